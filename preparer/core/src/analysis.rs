@@ -0,0 +1,368 @@
+//! Looking the source over before encoding it, for whatever the planner
+//! wants to know about its content rather than its streams: where the scenes
+//! cut, how black bars frame it, how loud it is, how hard it is to encode.
+//!
+//! Each of those is an [`AnalysisPass`], and all of them are fed from a
+//! single decode by [`analyze`], since decoding a feature film is the slow
+//! part and there's no call to do it once per question. Video reaches them
+//! as small grayscale frames and audio as interleaved float samples.
+
+use crate::cover;
+use anyhow::{Context, Result, anyhow};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Width video is scaled down to for analysis, keeping its aspect ratio.
+/// A multiple of 4, so rows of gray pixels aren't padded.
+pub const FRAME_WIDTH: u32 = 320;
+
+/// A decoded frame of video, as luma only.
+pub struct Frame<'a> {
+    pub pts: Duration,
+    pub width: u32,
+    pub height: u32,
+    /// `width` × `height` gray pixels, row by row
+    pub luma: &'a [u8],
+}
+
+/// A run of decoded audio.
+pub struct Samples<'a> {
+    pub pts: Duration,
+    pub rate: u32,
+    pub channels: u32,
+    /// Samples interleaved by channel
+    pub data: &'a [f32],
+}
+
+/// What the passes found, for the planner.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Findings {
+    /// Where one shot ends and the next begins
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub scene_cuts: Vec<Duration>,
+    /// How much the picture changes from frame to frame on average, from 0
+    /// for a still to 1; busier pictures need more bitrate to look as good
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub complexity: Option<f64>,
+}
+
+/// One question asked of the source as it's decoded.
+pub trait AnalysisPass: Send {
+    fn name(&self) -> &'static str;
+
+    fn video(&mut self, _frame: &Frame) {}
+
+    fn audio(&mut self, _samples: &Samples) {}
+
+    /// Record the answer, once the whole source has gone past.
+    fn finish(&mut self, findings: &mut Findings);
+}
+
+/// Mean difference between two frames' pixels, from 0 to 1.
+fn difference(a: &[u8], b: &[u8]) -> f64 {
+    let total: u64 = a.iter().zip(b).map(|(a, b)| a.abs_diff(*b) as u64).sum();
+    total as f64 / (a.len().max(1) as f64 * 255.0)
+}
+
+/// Finds cuts as frames that differ sharply from the one before.
+#[derive(Default)]
+pub struct SceneCuts {
+    previous: Vec<u8>,
+    cuts: Vec<Duration>,
+}
+
+impl SceneCuts {
+    /// How different a frame has to be from the last to start a new shot
+    const THRESHOLD: f64 = 0.3;
+}
+
+impl AnalysisPass for SceneCuts {
+    fn name(&self) -> &'static str {
+        "scene cuts"
+    }
+
+    fn video(&mut self, frame: &Frame) {
+        if self.previous.len() == frame.luma.len()
+            && difference(&self.previous, frame.luma) > Self::THRESHOLD
+        {
+            self.cuts.push(frame.pts);
+        }
+        self.previous = frame.luma.to_vec();
+    }
+
+    fn finish(&mut self, findings: &mut Findings) {
+        findings.scene_cuts = std::mem::take(&mut self.cuts);
+    }
+}
+
+/// Measures how much the picture moves, leaving scene cuts out.
+#[derive(Default)]
+pub struct Complexity {
+    previous: Vec<u8>,
+    total: f64,
+    frames: u32,
+}
+
+impl AnalysisPass for Complexity {
+    fn name(&self) -> &'static str {
+        "complexity"
+    }
+
+    fn video(&mut self, frame: &Frame) {
+        if self.previous.len() == frame.luma.len() {
+            let difference = difference(&self.previous, frame.luma);
+            if difference <= SceneCuts::THRESHOLD {
+                self.total += difference;
+                self.frames += 1;
+            }
+        }
+        self.previous = frame.luma.to_vec();
+    }
+
+    fn finish(&mut self, findings: &mut Findings) {
+        if self.frames > 0 {
+            findings.complexity = Some(self.total / self.frames as f64);
+        }
+    }
+}
+
+/// Every pass there is.
+pub fn all_passes() -> Vec<Box<dyn AnalysisPass>> {
+    vec![
+        Box::new(SceneCuts::default()),
+        Box::new(Complexity::default()),
+    ]
+}
+
+/// Decode `input` once, showing each pass all of it.
+pub fn analyze(input: &Path, passes: Vec<Box<dyn AnalysisPass>>) -> Result<Findings> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*input.to_string_lossy())
+        .build()?;
+    let decodebin = gst::ElementFactory::make("decodebin").build()?;
+    pipeline.add_many([&filesrc, &decodebin])?;
+    filesrc.link(&decodebin)?;
+    decodebin.connect("autoplug-continue", false, |values| {
+        let caps = values[2].get::<gst::Caps>().ok();
+        Some(
+            caps.is_none_or(|caps| !cover::caps_are_cover_art(&caps))
+                .to_value(),
+        )
+    });
+
+    let passes = Arc::new(Mutex::new(passes));
+    let taken = Arc::new(Mutex::new((false, false)));
+    let pipeline_weak = pipeline.downgrade();
+    let pad_passes = passes.clone();
+    decodebin.connect_pad_added(move |_, src_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        let name = src_pad
+            .current_caps()
+            .and_then(|caps| caps.structure(0).map(|s| s.name().to_string()))
+            .unwrap_or_default();
+        let mut taken = taken.lock().unwrap();
+        let chain = match name.as_str() {
+            name if name.starts_with("video/x-raw") && !taken.0 => {
+                taken.0 = true;
+                video_chain(&pipeline, pad_passes.clone())
+            }
+            name if name.starts_with("audio/x-raw") && !taken.1 => {
+                taken.1 = true;
+                audio_chain(&pipeline, pad_passes.clone())
+            }
+            _ => gst::ElementFactory::make("fakesink")
+                .build()
+                .map_err(Into::into)
+                .and_then(|sink| {
+                    pipeline.add(&sink)?;
+                    Ok(sink)
+                }),
+        };
+        let first = chain.expect("Failed to set up analysis");
+        first
+            .sync_state_with_parent()
+            .expect("Failed to start analysis");
+        src_pad
+            .link(&first.static_pad("sink").unwrap())
+            .expect("Failed to link decodebin to analysis");
+    });
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline.bus().unwrap();
+    let result = loop {
+        use gst::MessageView;
+
+        let Some(message) = bus.timed_pop(gst::ClockTime::NONE) else {
+            break Ok(());
+        };
+        match message.view() {
+            MessageView::Eos(..) => break Ok(()),
+            MessageView::Error(err) => {
+                break Err(anyhow!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ));
+            }
+            _ => (),
+        }
+    };
+    pipeline.set_state(gst::State::Null)?;
+    result.context(format!("Failed to analyze {}", input.display()))?;
+
+    let mut findings = Findings::default();
+    for pass in passes.lock().unwrap().iter_mut() {
+        pass.finish(&mut findings);
+    }
+    Ok(findings)
+}
+
+/// Add elements turning decoded video into gray frames for the passes,
+/// returning the first of them.
+fn video_chain(
+    pipeline: &gst::Pipeline,
+    passes: Arc<Mutex<Vec<Box<dyn AnalysisPass>>>>,
+) -> Result<gst::Element> {
+    let queue = gst::ElementFactory::make("queue").build()?;
+    let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+    let videoscale = gst::ElementFactory::make("videoscale").build()?;
+    let capsfilter = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("format", "GRAY8")
+                .field("width", FRAME_WIDTH as i32)
+                .build(),
+        )
+        .build()?;
+    let sink = gst::ElementFactory::make("fakesink").build()?;
+    let elements = [&queue, &videoconvert, &videoscale, &capsfilter, &sink];
+    pipeline.add_many(elements)?;
+    gst::Element::link_many(elements)?;
+    for element in &elements[1..] {
+        element.sync_state_with_parent()?;
+    }
+
+    sink.static_pad("sink")
+        .context("Failed to get sink pad from fakesink")?
+        .add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            if let Some(buffer) = info.buffer()
+                && let Some(caps) = pad.current_caps()
+                && let Some(structure) = caps.structure(0)
+                && let Ok(width) = structure.get::<i32>("width")
+                && let Ok(height) = structure.get::<i32>("height")
+                && let Ok(map) = buffer.map_readable()
+                && map.len() >= (width * height) as usize
+            {
+                let frame = Frame {
+                    pts: Duration::from_nanos(buffer.pts().unwrap_or_default().nseconds()),
+                    width: width as u32,
+                    height: height as u32,
+                    luma: &map[..(width * height) as usize],
+                };
+                for pass in passes.lock().unwrap().iter_mut() {
+                    pass.video(&frame);
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+    Ok(queue)
+}
+
+/// Add elements turning decoded audio into float samples for the passes,
+/// returning the first of them.
+fn audio_chain(
+    pipeline: &gst::Pipeline,
+    passes: Arc<Mutex<Vec<Box<dyn AnalysisPass>>>>,
+) -> Result<gst::Element> {
+    let queue = gst::ElementFactory::make("queue").build()?;
+    let audioconvert = gst::ElementFactory::make("audioconvert").build()?;
+    let capsfilter = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("audio/x-raw")
+                .field("format", "F32LE")
+                .field("layout", "interleaved")
+                .build(),
+        )
+        .build()?;
+    let sink = gst::ElementFactory::make("fakesink").build()?;
+    let elements = [&queue, &audioconvert, &capsfilter, &sink];
+    pipeline.add_many(elements)?;
+    gst::Element::link_many(elements)?;
+    for element in &elements[1..] {
+        element.sync_state_with_parent()?;
+    }
+
+    sink.static_pad("sink")
+        .context("Failed to get sink pad from fakesink")?
+        .add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            if let Some(buffer) = info.buffer()
+                && let Some(caps) = pad.current_caps()
+                && let Some(structure) = caps.structure(0)
+                && let Ok(rate) = structure.get::<i32>("rate")
+                && let Ok(channels) = structure.get::<i32>("channels")
+                && let Ok(map) = buffer.map_readable()
+            {
+                let data: Vec<f32> = map
+                    .chunks_exact(4)
+                    .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect();
+                let samples = Samples {
+                    pts: Duration::from_nanos(buffer.pts().unwrap_or_default().nseconds()),
+                    rate: rate as u32,
+                    channels: channels as u32,
+                    data: &data,
+                };
+                for pass in passes.lock().unwrap().iter_mut() {
+                    pass.audio(&samples);
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+    Ok(queue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_share_the_frames() {
+        let black = vec![0; 16];
+        let gray = vec![12; 16];
+        let white = vec![255; 16];
+        let mut passes = all_passes();
+        for (i, luma) in [&black, &gray, &black, &white, &white]
+            .into_iter()
+            .enumerate()
+        {
+            let frame = Frame {
+                pts: Duration::from_secs(i as u64),
+                width: 4,
+                height: 4,
+                luma,
+            };
+            for pass in &mut passes {
+                pass.video(&frame);
+            }
+        }
+        let mut findings = Findings::default();
+        for pass in &mut passes {
+            pass.finish(&mut findings);
+        }
+        assert_eq!(findings.scene_cuts, [Duration::from_secs(3)]);
+        // Two small changes and a still, with the cut left out
+        let expected = (12.0 / 255.0) * 2.0 / 3.0;
+        assert!((findings.complexity.unwrap() - expected).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,124 @@
+//! Checking the decoded audio and video line up, for sources whose broken
+//! timestamps would leave the prepared title out of sync.
+//!
+//! The first and last times of each stream are noted as buffers enter the
+//! branches, and compared once the run ends: both streams of a well-formed
+//! source start and end within a frame or so of each other.
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Where a stream starts and ends.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Span {
+    pub(crate) start: Option<gst::ClockTime>,
+    pub(crate) end: Option<gst::ClockTime>,
+}
+
+impl Span {
+    fn extend(&mut self, buffer: &gst::BufferRef) {
+        let Some(pts) = buffer.pts() else {
+            return;
+        };
+        let end = pts + buffer.duration().unwrap_or(gst::ClockTime::ZERO);
+        self.start = Some(self.start.map_or(pts, |start| start.min(pts)));
+        self.end = Some(self.end.map_or(end, |last| last.max(end)));
+    }
+}
+
+/// Signed seconds from `a` to `b`.
+fn offset(a: gst::ClockTime, b: gst::ClockTime) -> f64 {
+    b.nseconds() as f64 / 1e9 - a.nseconds() as f64 / 1e9
+}
+
+/// How far the audio starts and ends from the video, in seconds, past
+/// `threshold`, as problems to report.
+pub(crate) fn drift(video: Span, audio: Span, threshold: Duration) -> Vec<String> {
+    let mut problems = Vec::new();
+    let ends = [
+        ("starts", video.start, audio.start),
+        ("ends", video.end, audio.end),
+    ];
+    for (which, video, audio) in ends {
+        if let (Some(video), Some(audio)) = (video, audio) {
+            let secs = offset(video, audio);
+            if secs.abs() > threshold.as_secs_f64() {
+                problems.push(format!(
+                    "The audio {} {:.3}s {} the video; the output may be out of sync",
+                    which,
+                    secs.abs(),
+                    match secs > 0.0 {
+                        true => "after",
+                        false => "before",
+                    }
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Notes the span of the video and audio entering the branches.
+#[derive(Default)]
+pub(crate) struct SyncCheck {
+    video: Mutex<Span>,
+    audio: Mutex<Span>,
+}
+
+impl SyncCheck {
+    /// Note the buffers passing `pad`, which carries video or audio.
+    pub(crate) fn watch(self: &Arc<Self>, pad: &gst::Pad, video: bool) {
+        let check = self.clone();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(buffer) = info.buffer() {
+                match video {
+                    true => check.video.lock().unwrap().extend(buffer),
+                    false => check.audio.lock().unwrap().extend(buffer),
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    /// Drift past `threshold` between what was seen of both streams.
+    pub(crate) fn problems(&self, threshold: Duration) -> Vec<String> {
+        drift(
+            *self.video.lock().unwrap(),
+            *self.audio.lock().unwrap(),
+            threshold,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start_ms: u64, end_ms: u64) -> Span {
+        Span {
+            start: Some(gst::ClockTime::from_mseconds(start_ms)),
+            end: Some(gst::ClockTime::from_mseconds(end_ms)),
+        }
+    }
+
+    #[test]
+    fn flags_drift_past_the_threshold() {
+        let threshold = Duration::from_millis(100);
+        assert!(drift(span(0, 60_000), span(40, 60_020), threshold).is_empty());
+        let problems = drift(span(0, 60_000), span(0, 58_500), threshold);
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0].contains("ends 1.500s before"),
+            "{}",
+            problems[0]
+        );
+        assert_eq!(
+            drift(span(500, 60_000), span(0, 60_000), threshold).len(),
+            1
+        );
+        // Nothing to compare without audio
+        assert!(drift(span(0, 60_000), Span::default(), threshold).is_empty());
+    }
+}
@@ -0,0 +1,203 @@
+//! Finding which stage of the pipeline holds an encode back, for deciding
+//! what hardware would speed it up.
+//!
+//! Every queue sits in front of one stage, and fills up when that stage
+//! can't keep up with what reaches it. The queues are sampled as the run
+//! goes: the stage whose queues ran fullest is the bottleneck, and when
+//! they all ran nearly empty, it's decoding the source that can't feed them.
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::collections::BTreeMap;
+
+/// Queues fuller than this on average are backed up.
+const BACKED_UP: f64 = 0.2;
+
+/// A part of the pipeline that queues wait on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Demuxing and decoding the source, which no queue waits on directly
+    Decode,
+    /// Scaling and converting the decoded picture or sound
+    Convert,
+    Encode,
+    Parse,
+    /// Writing segments
+    Mux,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Convert => "convert",
+            Stage::Encode => "encode",
+            Stage::Parse => "parse",
+            Stage::Mux => "mux",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Stage::Decode,
+            Stage::Convert,
+            Stage::Encode,
+            Stage::Parse,
+            Stage::Mux,
+        ]
+        .into_iter()
+        .find(|stage| stage.name() == name)
+    }
+
+    /// The stage an element downstream of a queue belongs to, from its
+    /// factory's klass, like `Codec/Encoder/Video`.
+    fn of_klass(klass: &str) -> Option<Self> {
+        if klass.contains("Encoder") {
+            Some(Stage::Encode)
+        } else if klass.contains("Parser") {
+            Some(Stage::Parse)
+        } else if klass.contains("Sink") || klass.contains("Muxer") {
+            Some(Stage::Mux)
+        } else if klass.contains("Converter") || klass.contains("Filter") {
+            Some(Stage::Convert)
+        } else {
+            None
+        }
+    }
+}
+
+/// How full each stage's queues ran on average, and which stage that makes
+/// the bottleneck.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BottleneckReport {
+    /// Average fill of the queues in front of each stage, from 0.0 to 1.0
+    pub fills: BTreeMap<Stage, f64>,
+    pub bound: Stage,
+}
+
+impl BottleneckReport {
+    fn new(fills: BTreeMap<Stage, f64>) -> Self {
+        let fullest = fills
+            .iter()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .filter(|(_, fill)| **fill > BACKED_UP);
+        Self {
+            bound: fullest.map_or(Stage::Decode, |(stage, _)| *stage),
+            fills,
+        }
+    }
+}
+
+impl std::fmt::Display for BottleneckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The encode was {}-bound", self.bound.name())?;
+        let fills: Vec<String> = self
+            .fills
+            .iter()
+            .map(|(stage, fill)| format!("{} {:.0}%", stage.name(), fill * 100.0))
+            .collect();
+        if !fills.is_empty() {
+            write!(
+                f,
+                "; queues before each stage ran {} full",
+                fills.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How full `queue` is, by whichever of its limits it's nearest.
+fn fill(queue: &gst::Element) -> f64 {
+    let ratio = |level: f64, max: f64| match max > 0.0 {
+        true => level / max,
+        false => 0.0,
+    };
+    let buffers = |name| queue.property::<u32>(name) as f64;
+    let time = |name| queue.property::<u64>(name) as f64;
+    [
+        ratio(
+            buffers("current-level-buffers"),
+            buffers("max-size-buffers"),
+        ),
+        ratio(buffers("current-level-bytes"), buffers("max-size-bytes")),
+        ratio(time("current-level-time"), time("max-size-time")),
+    ]
+    .into_iter()
+    .fold(0.0, f64::max)
+}
+
+/// The stage `queue` feeds, looking past the capsfilters that filtered
+/// links put in between.
+fn stage_after(queue: &gst::Element) -> Option<Stage> {
+    let downstream = |element: &gst::Element| element.static_pad("src")?.peer()?.parent_element();
+    let mut next = downstream(queue)?;
+    while next.factory()?.name() == "capsfilter" {
+        next = downstream(&next)?;
+    }
+    Stage::of_klass(next.factory()?.metadata(gst::ELEMENT_METADATA_KLASS)?)
+}
+
+/// Samples of the pipeline's queues, taken while it runs.
+#[derive(Default)]
+pub(crate) struct QueueSampler {
+    /// Sum of the samples of each stage's queues, and how many there were
+    totals: BTreeMap<Stage, (f64, u64)>,
+}
+
+impl QueueSampler {
+    /// Note how full every queue in `pipeline` is now.
+    pub(crate) fn sample(&mut self, pipeline: &gst::Pipeline) {
+        for element in pipeline.iterate_recurse().into_iter().flatten() {
+            if element.factory().is_none_or(|f| f.name() != "queue") {
+                continue;
+            }
+            let stage = stage_after(&element);
+            if let Some(stage) = stage {
+                let total = self.totals.entry(stage).or_default();
+                total.0 += fill(&element);
+                total.1 += 1;
+            }
+        }
+    }
+
+    pub(crate) fn report(&self) -> BottleneckReport {
+        BottleneckReport::new(
+            self.totals
+                .iter()
+                .map(|(stage, (sum, count))| (*stage, sum / *count as f64))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blames_the_stage_with_the_fullest_queues() {
+        let report = BottleneckReport::new(BTreeMap::from([
+            (Stage::Convert, 0.1),
+            (Stage::Encode, 0.9),
+            (Stage::Mux, 0.0),
+        ]));
+        assert_eq!(report.bound, Stage::Encode);
+        assert_eq!(
+            report.to_string(),
+            "The encode was encode-bound; queues before each stage ran convert 10%, encode 90%, mux 0% full"
+        );
+        // Queues left waiting everywhere are waiting on the decoder
+        let starved = BottleneckReport::new(BTreeMap::from([
+            (Stage::Convert, 0.05),
+            (Stage::Encode, 0.1),
+        ]));
+        assert_eq!(starved.bound, Stage::Decode);
+        assert_eq!(Stage::of_klass("Codec/Encoder/Video"), Some(Stage::Encode));
+        assert_eq!(
+            Stage::of_klass("Filter/Converter/Video/Scaler"),
+            Some(Stage::Convert)
+        );
+        assert_eq!(Stage::from_name("mux"), Some(Stage::Mux));
+    }
+}
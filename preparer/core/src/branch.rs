@@ -0,0 +1,730 @@
+use crate::encoder::{Codec, VideoEncoder};
+use crate::factory::{ElementFactory, ElementSpec, GstFactory};
+use crate::job::BranchStats;
+use crate::loudness::LoudnessMeter;
+use crate::spec::AudioSpec;
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Which decoded stream a branch consumes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Video,
+    Audio,
+}
+
+/// One consumer of the decoded input, such as a representation of the
+/// encoding ladder.
+///
+/// Each branch is fed from a tee carrying raw frames of its
+/// [`MediaType`]. Branches that produce a DASH representation request a pad
+/// from the dashsink themselves; others (analysis, fingerprinting) are free
+/// to end in their own sink.
+pub trait PipelineBranch: Send {
+    /// Short identifier used in the journal and logs
+    fn name(&self) -> String;
+
+    fn media_type(&self) -> MediaType;
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()>;
+
+    /// Link the branch to its source tee and, if it produces output, the dashsink.
+    fn link(&self, tee: &gst::Element, dashsink: &gst::Element) -> Result<()>;
+
+    /// Output statistics once the pipeline has reached EOS.
+    fn stats(&self, _duration: Option<gst::ClockTime>) -> Option<BranchStats> {
+        None
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+}
+
+pub(crate) struct EncodingBranch<E = gst::Element> {
+    bitrate_mbps: u32,
+    video_encoder: VideoEncoder,
+    counters: Arc<Counters>,
+    queue1: E,
+    videoscale: E,
+    capsfilter: E,
+    /// Running timecode drawn over the picture, for review copies
+    timecode: Option<E>,
+    videoconvert: E,
+    /// Caps fields asked of the converted picture, like its chroma
+    /// subsampling or colorimetry
+    constraint: Option<E>,
+    queue2: E,
+    encoder: E,
+    queue3: E,
+    parser: E,
+    queue4: E,
+}
+
+impl<E: Clone> EncodingBranch<E> {
+    pub(crate) fn new(
+        factory: &mut impl ElementFactory<Element = E>,
+        encoder: VideoEncoder,
+        bitrate_mbps: u32,
+        preset: u32,
+        keyframe_interval: u32,
+    ) -> Result<Self> {
+        let bitrate_kbps = bitrate_mbps * 1000; // Convert MB/s to kbps
+
+        Ok(Self {
+            bitrate_mbps,
+            video_encoder: encoder,
+            counters: Arc::default(),
+            queue1: factory.make(&ElementSpec::new("queue"))?,
+            videoscale: factory
+                .make(&ElementSpec::new("videoscale").property_from_str("method", "lanczos"))?,
+            // Capsfilter to limit resolution to 1080p
+            capsfilter: factory.make(&ElementSpec::new("capsfilter").caps(
+                "caps",
+                "video/x-raw,width=(int)[1,1920],height=(int)[1,1080]",
+            ))?,
+            timecode: None,
+            videoconvert: factory.make(
+                &ElementSpec::new("videoconvert")
+                    .property_from_str("dither", "bayer")
+                    .property_from_str("chroma-mode", "full"),
+            )?,
+            constraint: None,
+            queue2: factory.make(&ElementSpec::new("queue"))?,
+            encoder: factory.make(&encoder.spec(bitrate_kbps, preset, keyframe_interval))?,
+            queue3: factory.make(&ElementSpec::new("queue"))?,
+            parser: factory.make(&ElementSpec::new(encoder.parser()))?,
+            queue4: factory.make(&ElementSpec::new("queue"))?,
+        })
+    }
+
+    /// Keep this representation's picture within `width`x`height`, such as
+    /// to fit a decoder level.
+    pub(crate) fn limit_size(
+        mut self,
+        factory: &mut impl ElementFactory<Element = E>,
+        width: u32,
+        height: u32,
+    ) -> Result<Self> {
+        self.capsfilter = factory.make(&ElementSpec::new("capsfilter").caps(
+            "caps",
+            &format!(
+                "video/x-raw,width=(int)[1,{}],height=(int)[1,{}]",
+                width, height
+            ),
+        ))?;
+        Ok(self)
+    }
+
+    /// Scale this representation's picture to `height`, keeping its aspect
+    /// ratio, instead of capping it at 1080p.
+    pub(crate) fn scale_to_height(
+        mut self,
+        factory: &mut impl ElementFactory<Element = E>,
+        height: u32,
+    ) -> Result<Self> {
+        self.capsfilter = factory.make(&ElementSpec::new("capsfilter").caps(
+            "caps",
+            &format!(
+                "video/x-raw,height=(int){},pixel-aspect-ratio=(fraction)1/1",
+                height
+            ),
+        ))?;
+        Ok(self)
+    }
+
+    /// Convert this representation's picture to satisfy `fields` too, given
+    /// as in a caps string, like `format=I420_10LE,colorimetry=bt709`.
+    pub(crate) fn constrain(
+        mut self,
+        factory: &mut impl ElementFactory<Element = E>,
+        fields: &str,
+    ) -> Result<Self> {
+        self.constraint = Some(factory.make(
+            &ElementSpec::new("capsfilter").caps("caps", &format!("video/x-raw,{}", fields)),
+        )?);
+        Ok(self)
+    }
+
+    /// Draw the running timecode over this representation's picture.
+    pub(crate) fn burn_timecode(
+        mut self,
+        factory: &mut impl ElementFactory<Element = E>,
+    ) -> Result<Self> {
+        self.timecode = Some(
+            factory.make(
+                &ElementSpec::new("timeoverlay")
+                    .property_from_str("time-mode", "buffer-time")
+                    .property_from_str("halignment", "center")
+                    .property_from_str("valignment", "bottom")
+                    .property("font-desc", "Monospace Bold 24")
+                    .property("shaded-background", true),
+            )?,
+        );
+        Ok(self)
+    }
+
+    fn elements(&self) -> Vec<&E> {
+        [&self.queue1, &self.videoscale, &self.capsfilter]
+            .into_iter()
+            .chain(&self.timecode)
+            .chain([&self.videoconvert])
+            .chain(&self.constraint)
+            .chain([
+                &self.queue2,
+                &self.encoder,
+                &self.queue3,
+                &self.parser,
+                &self.queue4,
+            ])
+            .collect()
+    }
+
+    /// Link the encoding chain with scaling and conversion
+    fn link_chain(&self, factory: &mut impl ElementFactory<Element = E>) -> Result<()> {
+        factory.link(&self.queue1, &self.videoscale, None)?;
+        factory.link(&self.videoscale, &self.capsfilter, None)?;
+        match &self.timecode {
+            Some(timecode) => {
+                factory.link(&self.capsfilter, timecode, None)?;
+                factory.link(timecode, &self.videoconvert, None)?;
+            }
+            None => factory.link(&self.capsfilter, &self.videoconvert, None)?,
+        }
+        match &self.constraint {
+            Some(constraint) => {
+                factory.link(&self.videoconvert, constraint, None)?;
+                factory.link(constraint, &self.queue2, None)?;
+            }
+            None => factory.link(&self.videoconvert, &self.queue2, None)?,
+        }
+        factory.link(&self.queue2, &self.encoder, None)?;
+        factory.link(&self.encoder, &self.queue3, None)?;
+        factory.link(&self.queue3, &self.parser, None)?;
+
+        // Link with caps filter
+        factory.link(&self.parser, &self.queue4, Some(self.video_encoder.caps()))?;
+        Ok(())
+    }
+}
+
+impl PipelineBranch for EncodingBranch {
+    fn name(&self) -> String {
+        format!(
+            "{}-{}mbps",
+            self.video_encoder.codec.name(),
+            self.bitrate_mbps
+        )
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::Video
+    }
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
+        pipeline.add_many(self.elements())?;
+        Ok(())
+    }
+
+    fn link(&self, tee: &gst::Element, dashsink: &gst::Element) -> Result<()> {
+        // Link from tee
+        tee.link(&self.queue1)?;
+        self.link_chain(&mut GstFactory)?;
+
+        // Link to dashsink
+        let video_sink_pad = dashsink
+            .request_pad_simple("video_%u")
+            .context("Failed to get video pad from dashsink")?;
+        let video_src_pad = self
+            .queue4
+            .static_pad("src")
+            .context("Failed to get src pad from queue4")?;
+        video_src_pad.link(&video_sink_pad)?;
+
+        // Count what this representation actually produced
+        let counters = self.counters.clone();
+        video_src_pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(buffer) = info.buffer() {
+                counters.frames.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .bytes
+                    .fetch_add(buffer.size() as u64, Ordering::Relaxed);
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        Ok(())
+    }
+
+    fn stats(&self, duration: Option<gst::ClockTime>) -> Option<BranchStats> {
+        // The ladder's stats are its AV1 rungs'
+        if self.video_encoder.codec != Codec::Av1 {
+            return None;
+        }
+        let bytes = self.counters.bytes.load(Ordering::Relaxed);
+        Some(BranchStats {
+            target_bitrate_mbps: self.bitrate_mbps,
+            frames: self.counters.frames.load(Ordering::Relaxed),
+            bytes,
+            average_bitrate_kbps: duration
+                .filter(|d| *d > gst::ClockTime::ZERO)
+                .map(|d| bytes as f64 * 8.0 / 1000.0 / d.seconds_f64()),
+        })
+    }
+}
+
+/// A stream of the source, already compressed, copied into its own
+/// representation.
+pub(crate) struct PassthroughBranch {
+    media_type: MediaType,
+    /// Rung of the ladder the source's video stands in for
+    bitrate_mbps: u32,
+    counters: Arc<Counters>,
+    queue: gst::Element,
+}
+
+impl PassthroughBranch {
+    pub(crate) fn new(media_type: MediaType, bitrate_mbps: u32) -> Result<Self> {
+        Ok(Self {
+            media_type,
+            bitrate_mbps,
+            counters: Arc::default(),
+            queue: gst::ElementFactory::make("queue").build()?,
+        })
+    }
+}
+
+impl PipelineBranch for PassthroughBranch {
+    fn name(&self) -> String {
+        match self.media_type {
+            MediaType::Video => format!("copy-{}mbps", self.bitrate_mbps),
+            MediaType::Audio => String::from("copy-audio"),
+        }
+    }
+
+    fn media_type(&self) -> MediaType {
+        self.media_type
+    }
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
+        pipeline.add(&self.queue)?;
+        Ok(())
+    }
+
+    fn link(&self, tee: &gst::Element, dashsink: &gst::Element) -> Result<()> {
+        tee.link(&self.queue)?;
+        let template = match self.media_type {
+            MediaType::Video => "video_%u",
+            MediaType::Audio => "audio_%u",
+        };
+        let sink_pad = dashsink
+            .request_pad_simple(template)
+            .context("Failed to get pad from dashsink")?;
+        let src_pad = self
+            .queue
+            .static_pad("src")
+            .context("Failed to get src pad from queue")?;
+        src_pad.link(&sink_pad)?;
+
+        let counters = self.counters.clone();
+        src_pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(buffer) = info.buffer() {
+                counters.frames.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .bytes
+                    .fetch_add(buffer.size() as u64, Ordering::Relaxed);
+            }
+            gst::PadProbeReturn::Ok
+        });
+        Ok(())
+    }
+
+    fn stats(&self, duration: Option<gst::ClockTime>) -> Option<BranchStats> {
+        if self.media_type == MediaType::Audio {
+            return None;
+        }
+        let bytes = self.counters.bytes.load(Ordering::Relaxed);
+        Some(BranchStats {
+            target_bitrate_mbps: self.bitrate_mbps,
+            frames: self.counters.frames.load(Ordering::Relaxed),
+            bytes,
+            average_bitrate_kbps: duration
+                .filter(|d| *d > gst::ClockTime::ZERO)
+                .map(|d| bytes as f64 * 8.0 / 1000.0 / d.seconds_f64()),
+        })
+    }
+}
+
+/// Opus encoding of the decoded audio into its own representation.
+pub(crate) struct AudioBranch<E = gst::Element> {
+    channels: u32,
+    sample_rate: u32,
+    queue1: E,
+    audioconvert: E,
+    /// Compressor and makeup gain, for the rendition with dialogue brought up
+    compression: Vec<E>,
+    audioresample: E,
+    queue2: E,
+    opusenc: E,
+    queue3: E,
+    loudness: LoudnessMeter,
+}
+
+impl<E: Clone> AudioBranch<E> {
+    pub(crate) fn new(
+        factory: &mut impl ElementFactory<Element = E>,
+        spec: &AudioSpec,
+    ) -> Result<Self> {
+        Ok(Self {
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+            queue1: factory.make(&ElementSpec::new("queue"))?,
+            audioconvert: factory.make(&ElementSpec::new("audioconvert"))?,
+            compression: Vec::new(),
+            audioresample: factory.make(
+                &ElementSpec::new("audioresample")
+                    .property("quality", spec.resample_quality as i32),
+            )?,
+            queue2: factory.make(&ElementSpec::new("queue"))?,
+            opusenc: factory.make(
+                &ElementSpec::new("opusenc")
+                    .property("bitrate", (spec.bitrate_kbps * 1000) as i32)
+                    .property_from_str("audio-type", spec.audio_type.nick())
+                    .property("inband-fec", spec.inband_fec)
+                    .property("dtx", spec.dtx)
+                    .property_from_str("frame-size", &format!("{}", spec.frame_duration_ms)),
+            )?,
+            queue3: factory.make(&ElementSpec::new("queue"))?,
+            loudness: LoudnessMeter::default(),
+        })
+    }
+
+    /// Meters the audio as it goes into the encoder.
+    pub(crate) fn loudness(&self) -> LoudnessMeter {
+        self.loudness.clone()
+    }
+
+    /// Narrow the dynamic range, so quiet dialogue holds up over loud
+    /// effects: peaks are squeezed a quarter of the way down to the
+    /// threshold, then everything is brought back up.
+    pub(crate) fn compress(
+        mut self,
+        factory: &mut impl ElementFactory<Element = E>,
+    ) -> Result<Self> {
+        self.compression = vec![
+            factory.make(
+                &ElementSpec::new("audiodynamic")
+                    .property_from_str("mode", "compressor")
+                    .property_from_str("characteristics", "soft-knee")
+                    .property_from_str("threshold", "0.125")
+                    .property_from_str("ratio", "0.25"),
+            )?,
+            factory.make(&ElementSpec::new("volume").property("volume", 2.0))?,
+        ];
+        Ok(self)
+    }
+
+    fn elements(&self) -> Vec<&E> {
+        [&self.queue1, &self.audioconvert]
+            .into_iter()
+            .chain(&self.compression)
+            .chain([
+                &self.audioresample,
+                &self.queue2,
+                &self.opusenc,
+                &self.queue3,
+            ])
+            .collect()
+    }
+
+    /// Link audio processing chain
+    fn link_chain(&self, factory: &mut impl ElementFactory<Element = E>) -> Result<()> {
+        factory.link(&self.queue1, &self.audioconvert, None)?;
+        let mut previous = &self.audioconvert;
+        for element in &self.compression {
+            factory.link(previous, element, None)?;
+            previous = element;
+        }
+        factory.link(previous, &self.audioresample, None)?;
+        factory.link(&self.audioresample, &self.queue2, None)?;
+
+        // Link audio with caps filter to fix the channel count and rate
+        factory.link(
+            &self.queue2,
+            &self.opusenc,
+            Some(&format!(
+                "audio/x-raw,channels={},rate={}",
+                self.channels, self.sample_rate
+            )),
+        )?;
+        factory.link(&self.opusenc, &self.queue3, None)?;
+        Ok(())
+    }
+}
+
+impl PipelineBranch for AudioBranch {
+    fn name(&self) -> String {
+        match (self.compression.is_empty(), self.channels) {
+            (false, _) => String::from("opus-drc"),
+            (true, 1) => String::from("opus-mono"),
+            (true, _) => String::from("opus"),
+        }
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::Audio
+    }
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
+        pipeline.add_many(self.elements())?;
+        Ok(())
+    }
+
+    fn link(&self, tee: &gst::Element, dashsink: &gst::Element) -> Result<()> {
+        tee.link(&self.queue1)?;
+        self.link_chain(&mut GstFactory)?;
+
+        let audio_sink_pad = dashsink
+            .request_pad_simple("audio_%u")
+            .context("Failed to get audio pad from dashsink")?;
+        let audio_src_pad = self
+            .queue3
+            .static_pad("src")
+            .context("Failed to get src pad from audio queue")?;
+        audio_src_pad.link(&audio_sink_pad)?;
+        let encoder_sink_pad = self
+            .opusenc
+            .static_pad("sink")
+            .context("Failed to get sink pad from opusenc")?;
+        self.loudness.watch(&encoder_sink_pad, &audio_sink_pad);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factory::PropertyValue;
+    use crate::factory::testing::RecordingFactory;
+    use crate::spec::AudioType;
+
+    #[test]
+    fn encoding_branch_element_order() {
+        let mut factory = RecordingFactory::default();
+        EncodingBranch::new(&mut factory, VideoEncoder::SVT_AV1, 6, 8, 120).unwrap();
+
+        assert_eq!(
+            factory.factories(),
+            [
+                "queue",
+                "videoscale",
+                "capsfilter",
+                "videoconvert",
+                "queue",
+                "svtav1enc",
+                "queue",
+                "av1parse",
+                "queue"
+            ]
+        );
+    }
+
+    #[test]
+    fn encoding_branch_maps_settings_to_encoder_properties() {
+        let mut factory = RecordingFactory::default();
+        EncodingBranch::new(&mut factory, VideoEncoder::SVT_AV1, 6, 8, 120).unwrap();
+
+        let encoder = factory.find("svtav1enc");
+        assert_eq!(
+            encoder.get("target-bitrate"),
+            Some(&PropertyValue::U32(6000))
+        );
+        assert_eq!(encoder.get("preset"), Some(&PropertyValue::U32(8)));
+        assert_eq!(
+            encoder.get("intra-period-length"),
+            Some(&PropertyValue::I32(120))
+        );
+        assert_eq!(
+            factory.find("capsfilter").get("caps"),
+            Some(&PropertyValue::Caps(String::from(
+                "video/x-raw,width=(int)[1,1920],height=(int)[1,1080]"
+            )))
+        );
+    }
+
+    #[test]
+    fn encoding_branch_links_chain_in_order() {
+        let mut factory = RecordingFactory::default();
+        let branch = EncodingBranch::new(&mut factory, VideoEncoder::SVT_AV1, 2, 8, 120).unwrap();
+        branch.link_chain(&mut factory).unwrap();
+
+        let links: Vec<_> = factory.links.iter().map(|(a, b, _)| (*a, *b)).collect();
+        assert_eq!(links, (0..8).map(|i| (i, i + 1)).collect::<Vec<_>>());
+
+        // Only the parser output is constrained
+        let (src, _, caps) = factory.links.iter().find(|l| l.2.is_some()).unwrap();
+        assert_eq!(factory.elements[*src].factory, "av1parse");
+        assert_eq!(
+            caps.as_deref(),
+            Some("video/x-av1,stream-format=obu-stream,alignment=tu")
+        );
+    }
+
+    #[test]
+    fn burns_timecode_after_scaling() {
+        let mut factory = RecordingFactory::default();
+        let branch = EncodingBranch::new(&mut factory, VideoEncoder::SVT_AV1, 1, 8, 120)
+            .unwrap()
+            .burn_timecode(&mut factory)
+            .unwrap();
+        branch.link_chain(&mut factory).unwrap();
+
+        let timecode = factory.factories().iter().position(|f| *f == "timeoverlay");
+        assert_eq!(timecode, Some(9));
+        assert!(factory.links.iter().any(|(src, sink, _)| {
+            factory.elements[*src].factory == "capsfilter" && *sink == 9
+        }));
+        assert!(factory.links.iter().any(|(src, sink, _)| {
+            *src == 9 && factory.elements[*sink].factory == "videoconvert"
+        }));
+        assert_eq!(branch.elements().len(), 10);
+    }
+
+    #[test]
+    fn constrains_the_converted_picture() {
+        let mut factory = RecordingFactory::default();
+        let branch = EncodingBranch::new(&mut factory, VideoEncoder::SVT_AV1, 2, 8, 120)
+            .unwrap()
+            .constrain(&mut factory, "format=I420_10LE")
+            .unwrap();
+        branch.link_chain(&mut factory).unwrap();
+
+        assert_eq!(
+            factory.elements[9].get("caps"),
+            Some(&PropertyValue::Caps(String::from(
+                "video/x-raw,format=I420_10LE"
+            )))
+        );
+        assert!(factory.links.iter().any(|(src, sink, _)| {
+            factory.elements[*src].factory == "videoconvert" && *sink == 9
+        }));
+        assert!(
+            factory
+                .links
+                .iter()
+                .any(|(src, sink, _)| { *src == 9 && factory.elements[*sink].factory == "queue" })
+        );
+        assert_eq!(branch.elements().len(), 10);
+    }
+
+    #[test]
+    fn audio_branch_fixes_channel_count() {
+        let mut factory = RecordingFactory::default();
+        let spec = AudioSpec {
+            bitrate_kbps: 96,
+            channels: 1,
+            audio_type: AudioType::Voice,
+            frame_duration_ms: 60.0,
+            sample_rate: 24000,
+            resample_quality: 7,
+            ..AudioSpec::default()
+        };
+        let branch = AudioBranch::new(&mut factory, &spec).unwrap();
+        branch.link_chain(&mut factory).unwrap();
+
+        let opusenc = factory.find("opusenc");
+        assert_eq!(opusenc.get("bitrate"), Some(&PropertyValue::I32(96000)));
+        assert_eq!(
+            opusenc.get("audio-type"),
+            Some(&PropertyValue::Parsed(String::from("voice")))
+        );
+        assert_eq!(
+            opusenc.get("frame-size"),
+            Some(&PropertyValue::Parsed(String::from("60")))
+        );
+        let (_, sink, caps) = factory.links.iter().find(|l| l.2.is_some()).unwrap();
+        assert_eq!(factory.elements[*sink].factory, "opusenc");
+        assert_eq!(caps.as_deref(), Some("audio/x-raw,channels=1,rate=24000"));
+        assert_eq!(
+            factory.find("audioresample").get("quality"),
+            Some(&PropertyValue::I32(7))
+        );
+    }
+
+    #[test]
+    fn compresses_before_resampling() {
+        let mut factory = RecordingFactory::default();
+        let branch = AudioBranch::new(&mut factory, &AudioSpec::default())
+            .unwrap()
+            .compress(&mut factory)
+            .unwrap();
+        branch.link_chain(&mut factory).unwrap();
+
+        let links: Vec<_> = factory
+            .links
+            .iter()
+            .map(|(src, sink, _)| {
+                (
+                    factory.elements[*src].factory.as_str(),
+                    factory.elements[*sink].factory.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(links[1], ("audioconvert", "audiodynamic"));
+        assert_eq!(links[2], ("audiodynamic", "volume"));
+        assert_eq!(links[3], ("volume", "audioresample"));
+        assert_eq!(
+            factory.find("audiodynamic").get("mode"),
+            Some(&PropertyValue::Parsed(String::from("compressor")))
+        );
+    }
+
+    /// Runs a real encode of a short test pattern.
+    #[test]
+    #[ignore = "needs GStreamer with svtav1enc and dashsink installed"]
+    fn encodes_test_source() {
+        gst::init().unwrap();
+        let output = std::env::temp_dir().join(format!("movieshare-branch-{}", std::process::id()));
+        std::fs::create_dir_all(&output).unwrap();
+
+        let pipeline = gst::Pipeline::new();
+        let src = gst::ElementFactory::make("videotestsrc")
+            .property("num-buffers", 60i32)
+            .build()
+            .unwrap();
+        let tee = gst::ElementFactory::make("tee").build().unwrap();
+        let dashsink = gst::ElementFactory::make("dashsink")
+            .property("mpd-root-path", output.to_str().unwrap())
+            .property_from_str("muxer", "dashmp4")
+            .build()
+            .unwrap();
+        pipeline.add_many([&src, &tee, &dashsink]).unwrap();
+        src.link(&tee).unwrap();
+
+        let branch =
+            EncodingBranch::new(&mut GstFactory, VideoEncoder::SVT_AV1, 1, 12, 30).unwrap();
+        branch.add_to_pipeline(&pipeline).unwrap();
+        branch.link(&tee, &dashsink).unwrap();
+
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let bus = pipeline.bus().unwrap();
+        let msg = bus
+            .timed_pop_filtered(
+                gst::ClockTime::from_seconds(60),
+                &[gst::MessageType::Eos, gst::MessageType::Error],
+            )
+            .expect("pipeline timed out");
+        pipeline.set_state(gst::State::Null).unwrap();
+
+        assert!(matches!(msg.view(), gst::MessageView::Eos(..)));
+        assert!(output.join("dash.mpd").exists());
+        assert!(branch.stats(None).unwrap().frames > 0);
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+//! Subtitles burned into the picture, for players and screens that can't
+//! show a separate subtitle track.
+//!
+//! A subtitle file is parsed alongside the video and drawn over the decoded
+//! frames before the tee, so every representation of the ladder carries
+//! it. How it looks comes from the profile's `[subtitles.burn_in]` table: a
+//! preset, such as large high-contrast text for an accessibility profile,
+//! with the font and colours optionally overridden.
+
+use crate::factory::{ElementFactory, ElementSpec, GstFactory};
+use anyhow::{Context, Result, bail};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Distance from the bottom of the picture, in pixels.
+const MARGIN: i32 = 32;
+
+/// Starting points for how burned-in subtitles look.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StylePreset {
+    /// White text with a thin black outline
+    #[default]
+    Standard,
+    /// Large bold yellow text on a shaded band, for low vision viewers
+    Accessible,
+}
+
+/// How burned-in subtitles look.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BurnInStyle {
+    pub preset: StylePreset,
+    /// Pango font description, like `Serif Bold 24`, instead of the preset's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub font: Option<String>,
+    /// Text colour as `#rrggbb` or `#rrggbbaa`, instead of the preset's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Outline colour as `#rrggbb` or `#rrggbbaa`, instead of the preset's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outline_color: Option<String>,
+}
+
+/// `#rrggbb` or `#rrggbbaa` as the ARGB `textoverlay` takes.
+fn parse_color(color: &str) -> Result<u32> {
+    let invalid = || format!("Invalid colour {:?}; expected #rrggbb or #rrggbbaa", color);
+    let hex = color
+        .strip_prefix('#')
+        .filter(|hex| matches!(hex.len(), 6 | 8))
+        .with_context(invalid)?;
+    let value = u32::from_str_radix(hex, 16).with_context(invalid)?;
+    Ok(match hex.len() {
+        6 => 0xff000000 | value,
+        _ => value.rotate_right(8),
+    })
+}
+
+impl BurnInStyle {
+    pub fn validate(&self) -> Result<()> {
+        for color in [&self.color, &self.outline_color].into_iter().flatten() {
+            parse_color(color)?;
+        }
+        if let Some(font) = &self.font
+            && font.trim().is_empty()
+        {
+            bail!("Burn-in font is empty");
+        }
+        Ok(())
+    }
+
+    /// The overlay element drawing subtitles in this style.
+    fn overlay(&self) -> Result<ElementSpec> {
+        let (font, color, outline_color, shaded) = match self.preset {
+            StylePreset::Standard => ("Sans 20", 0xffffffff, 0xff000000, false),
+            StylePreset::Accessible => ("Sans Bold 30", 0xffffff00, 0xff000000, true),
+        };
+        let color = match &self.color {
+            Some(color) => parse_color(color)?,
+            None => color,
+        };
+        let outline_color = match &self.outline_color {
+            Some(color) => parse_color(color)?,
+            None => outline_color,
+        };
+        let mut overlay = ElementSpec::new("textoverlay")
+            .property("font-desc", self.font.as_deref().unwrap_or(font))
+            .property("color", color)
+            .property("outline-color", outline_color)
+            .property("shaded-background", shaded)
+            .property_from_str("halignment", "center")
+            .property_from_str("valignment", "bottom")
+            .property("ypad", MARGIN)
+            .property("wait-text", false);
+        if shaded {
+            overlay = overlay.property("shading-value", 160u32);
+        }
+        Ok(overlay)
+    }
+}
+
+/// The elements burning subtitles from a file into the video, between the
+/// decoder and the video tee.
+pub(crate) struct BurnInStage<E = gst::Element> {
+    src: E,
+    parse: E,
+    convert_in: E,
+    overlay: E,
+    convert_out: E,
+}
+
+impl<E: Clone> BurnInStage<E> {
+    pub(crate) fn new(
+        factory: &mut impl ElementFactory<Element = E>,
+        subtitles: &Path,
+        style: &BurnInStyle,
+    ) -> Result<Self> {
+        Ok(Self {
+            src: factory.make(
+                &ElementSpec::new("filesrc").property("location", &*subtitles.to_string_lossy()),
+            )?,
+            parse: factory.make(&ElementSpec::new("subparse"))?,
+            convert_in: factory.make(&ElementSpec::new("videoconvert"))?,
+            overlay: factory.make(&style.overlay()?)?,
+            convert_out: factory.make(&ElementSpec::new("videoconvert"))?,
+        })
+    }
+
+    fn link_chain(&self, factory: &mut impl ElementFactory<Element = E>) -> Result<()> {
+        factory.link(&self.src, &self.parse, None)?;
+        factory.link(&self.convert_in, &self.overlay, None)?;
+        factory.link(&self.overlay, &self.convert_out, None)?;
+        Ok(())
+    }
+}
+
+impl BurnInStage {
+    /// Add the stage to the pipeline in front of `next`, returning the pad
+    /// decoded video should be linked to.
+    pub(crate) fn insert(&self, pipeline: &gst::Pipeline, next: &gst::Pad) -> Result<gst::Pad> {
+        pipeline.add_many([
+            &self.src,
+            &self.parse,
+            &self.convert_in,
+            &self.overlay,
+            &self.convert_out,
+        ])?;
+        self.link_chain(&mut GstFactory)?;
+        self.parse
+            .link_pads(None, &self.overlay, Some("text_sink"))
+            .context("Failed to feed the subtitles to the overlay")?;
+        self.convert_out
+            .static_pad("src")
+            .context("Failed to get src pad from the burn-in stage")?
+            .link(next)?;
+        self.convert_in
+            .static_pad("sink")
+            .context("Failed to get sink pad from the burn-in stage")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factory::PropertyValue;
+    use crate::factory::testing::RecordingFactory;
+
+    #[test]
+    fn styles_subtitles_from_the_profile() {
+        let mut factory = RecordingFactory::default();
+        let style = BurnInStyle {
+            preset: StylePreset::Accessible,
+            ..Default::default()
+        };
+        let stage = BurnInStage::new(&mut factory, Path::new("film.srt"), &style).unwrap();
+        stage.link_chain(&mut factory).unwrap();
+        assert_eq!(
+            factory.factories(),
+            [
+                "filesrc",
+                "subparse",
+                "videoconvert",
+                "textoverlay",
+                "videoconvert"
+            ]
+        );
+        assert_eq!(factory.links.len(), 3);
+        let overlay = factory.find("textoverlay");
+        assert_eq!(
+            overlay.get("font-desc"),
+            Some(&PropertyValue::Str(String::from("Sans Bold 30")))
+        );
+        assert_eq!(overlay.get("color"), Some(&PropertyValue::U32(0xffffff00)));
+        assert_eq!(
+            overlay.get("shaded-background"),
+            Some(&PropertyValue::Bool(true))
+        );
+
+        let mut factory = RecordingFactory::default();
+        let style = BurnInStyle {
+            color: Some(String::from("#00ff0080")),
+            ..Default::default()
+        };
+        BurnInStage::new(&mut factory, Path::new("film.srt"), &style).unwrap();
+        let overlay = factory.find("textoverlay");
+        assert_eq!(overlay.get("color"), Some(&PropertyValue::U32(0x8000ff00)));
+        assert_eq!(
+            overlay.get("shaded-background"),
+            Some(&PropertyValue::Bool(false))
+        );
+
+        let style = BurnInStyle {
+            outline_color: Some(String::from("black")),
+            ..Default::default()
+        };
+        assert!(style.validate().is_err());
+    }
+}
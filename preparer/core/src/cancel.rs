@@ -0,0 +1,71 @@
+//! Cancelling or pausing a running preparation from another thread, with
+//! a policy for whether what it wrote so far is kept or thrown away.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// What to do with the output of a cancelled run.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CancelPolicy {
+    /// Keep what was encoded so far as a shorter, playable presentation
+    Finalize,
+    /// Delete everything the run wrote
+    Discard,
+}
+
+const NOT_CANCELLED: u8 = 0;
+const FINALIZE: u8 = 1;
+const DISCARD: u8 = 2;
+
+/// Shared flag for cancelling a running preparation from another thread.
+///
+/// Cancelling sends EOS through the pipeline so the manifest is closed
+/// cleanly before the output is kept or discarded according to the policy.
+/// Only the first `cancel` call takes effect.
+///
+/// The token can also pause the run, holding the pipeline in `PAUSED`
+/// until it's resumed; cancelling a paused run resumes it so EOS can flow.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken {
+    state: Arc<AtomicU8>,
+    paused: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self, policy: CancelPolicy) {
+        let state = match policy {
+            CancelPolicy::Finalize => FINALIZE,
+            CancelPolicy::Discard => DISCARD,
+        };
+        let _ =
+            self.state
+                .compare_exchange(NOT_CANCELLED, state, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    pub fn policy(&self) -> Option<CancelPolicy> {
+        match self.state.load(Ordering::Relaxed) {
+            FINALIZE => Some(CancelPolicy::Finalize),
+            DISCARD => Some(CancelPolicy::Discard),
+            _ => None,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.policy().is_some()
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed) && !self.is_cancelled()
+    }
+}
@@ -0,0 +1,100 @@
+//! Signalling how many channels each audio representation carries, so
+//! players can pick the mono rendition for a phone speaker or a poor
+//! connection without fetching it first.
+
+use crate::roles;
+use anyhow::{Context, Result};
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+
+/// Scheme of channel counts as plain numbers.
+const CHANNELS_SCHEME: &str = "urn:mpeg:dash:23003:3:audio_channel_configuration:2011";
+
+/// Give the audio representations of a manifest, in order, the channel
+/// counts in `channels`, replacing whatever they said before. Any beyond the
+/// counts given are left alone.
+pub(crate) fn tag_manifest(xml: &str, channels: &[u32]) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let configuration = |count: u32| {
+        Event::Empty(
+            BytesStart::new("AudioChannelConfiguration").with_attributes([
+                ("schemeIdUri", CHANNELS_SCHEME),
+                ("value", count.to_string().as_str()),
+            ]),
+        )
+    };
+    let mut counts = channels.iter().copied();
+    let mut in_audio = false;
+    // Inside an audio representation we've tagged, and how deep in an old
+    // configuration being dropped
+    let mut tagged = false;
+    let mut dropping = 0;
+    loop {
+        let event = reader.read_event().context("Invalid MPD")?;
+        if dropping > 0 {
+            match event {
+                Event::Start(_) => dropping += 1,
+                Event::End(_) => dropping -= 1,
+                _ => (),
+            }
+            continue;
+        }
+        match event {
+            Event::Eof => break,
+            Event::Start(element) => match element.local_name().into_inner() {
+                "AdaptationSet" => {
+                    in_audio = roles::is_of(&element, "audio")?;
+                    writer.write_event(Event::Start(element))?;
+                }
+                "Representation"
+                    if (in_audio || roles::is_of(&element, "audio")?)
+                        && let Some(count) = counts.next() =>
+                {
+                    writer.write_event(Event::Start(element))?;
+                    writer.write_event(configuration(count))?;
+                    tagged = true;
+                }
+                "AudioChannelConfiguration" if tagged => dropping = 1,
+                _ => writer.write_event(Event::Start(element))?,
+            },
+            Event::Empty(element) => match element.local_name().into_inner() {
+                "Representation"
+                    if (in_audio || roles::is_of(&element, "audio")?)
+                        && let Some(count) = counts.next() =>
+                {
+                    let name = element.name().into_inner().to_string();
+                    writer.write_event(Event::Start(element))?;
+                    writer.write_event(configuration(count))?;
+                    writer.write_event(Event::End(BytesEnd::new(name)))?;
+                }
+                "AudioChannelConfiguration" if tagged => (),
+                _ => writer.write_event(Event::Empty(element))?,
+            },
+            Event::End(end) => {
+                match end.local_name().into_inner() {
+                    "AdaptationSet" => in_audio = false,
+                    "Representation" => tagged = false,
+                    _ => (),
+                }
+                writer.write_event(Event::End(end))?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_audio_representations_in_order() {
+        let xml = r#"<MPD><AdaptationSet contentType="video"><Representation id="0"/></AdaptationSet><AdaptationSet contentType="audio"><Representation id="1"><AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="6"/><BaseURL>a.mp4</BaseURL></Representation><Representation id="2"/></AdaptationSet></MPD>"#;
+        assert_eq!(
+            tag_manifest(xml, &[2, 1]).unwrap(),
+            r#"<MPD><AdaptationSet contentType="video"><Representation id="0"/></AdaptationSet><AdaptationSet contentType="audio"><Representation id="1"><AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="2"/><BaseURL>a.mp4</BaseURL></Representation><Representation id="2"><AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="1"/></Representation></AdaptationSet></MPD>"#
+        );
+    }
+}
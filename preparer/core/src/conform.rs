@@ -0,0 +1,163 @@
+//! Conforming video to one frame rate, for series meant to be watched back
+//! to back whose episodes don't share one, like 25 fps episodes from a
+//! European release among 23.976 fps ones. Players that switch the TV's
+//! refresh rate to match the video otherwise blank the screen between
+//! episodes, and again on every switch.
+//!
+//! Frames are dropped or repeated to reach the profile's rate, rather than
+//! the video sped up or slowed down, so the audio needs no changes.
+
+use anyhow::{Context, Result, bail};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A frame rate, as a fraction like `24000/1001`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct FrameRate {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl FrameRate {
+    pub fn fps(self) -> f64 {
+        self.numerator as f64 / self.denominator as f64
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+impl FromStr for FrameRate {
+    type Err = anyhow::Error;
+
+    /// `25`, `24000/1001`, or `23.976` and the like for NTSC rates.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || format!("Invalid frame rate {:?}; expected like 25 or 24000/1001", s);
+        let (numerator, denominator) = match s.split_once('/') {
+            Some((numerator, denominator)) => (
+                numerator.trim().parse().with_context(invalid)?,
+                denominator.trim().parse().with_context(invalid)?,
+            ),
+            None if s.contains('.') => {
+                let fps: f64 = s.trim().parse().with_context(invalid)?;
+                // NTSC rates are whole rates slowed by 1000/1001
+                let whole = (fps * 1001.0 / 1000.0).round();
+                match (whole * 1000.0 / 1001.0 - fps).abs() < 0.01 {
+                    true => (whole as u32 * 1000, 1001),
+                    false => ((fps * 1000.0).round() as u32, 1000),
+                }
+            }
+            None => (s.trim().parse().with_context(invalid)?, 1),
+        };
+        if numerator == 0 || denominator == 0 {
+            bail!("{}", invalid());
+        }
+        let gcd = gcd(numerator, denominator);
+        Ok(Self {
+            numerator: numerator / gcd,
+            denominator: denominator / gcd,
+        })
+    }
+}
+
+impl TryFrom<String> for FrameRate {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for FrameRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.denominator {
+            1 => write!(f, "{}", self.numerator),
+            _ => write!(f, "{}/{}", self.numerator, self.denominator),
+        }
+    }
+}
+
+impl From<FrameRate> for String {
+    fn from(rate: FrameRate) -> Self {
+        rate.to_string()
+    }
+}
+
+/// Frames per second of the video the rungs encode from a source at
+/// `source_fps`, taken as 30 without one: `rate` when conforming to it, or
+/// four frames of every five once pulldown is undone.
+pub fn encoded_fps(
+    source_fps: Option<f64>,
+    rate: Option<FrameRate>,
+    inverse_telecine: bool,
+) -> f64 {
+    let source_fps = source_fps.unwrap_or(30.0);
+    match (rate, inverse_telecine) {
+        (Some(rate), _) => rate.fps(),
+        (None, true) => source_fps * 4.0 / 5.0,
+        (None, false) => source_fps,
+    }
+}
+
+/// Add elements conforming video to `rate` in front of `downstream`,
+/// returning the pad decoded video should be linked to.
+pub(crate) fn insert(
+    pipeline: &gst::Pipeline,
+    downstream: &gst::Pad,
+    rate: FrameRate,
+) -> Result<gst::Pad> {
+    let videorate = gst::ElementFactory::make("videorate").build()?;
+    let capsfilter = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field(
+                    "framerate",
+                    gst::Fraction::new(rate.numerator as i32, rate.denominator as i32),
+                )
+                .build(),
+        )
+        .build()?;
+    pipeline.add_many([&videorate, &capsfilter])?;
+    videorate.link(&capsfilter)?;
+    capsfilter
+        .static_pad("src")
+        .context("Failed to get src pad from capsfilter")?
+        .link(downstream)?;
+    videorate
+        .static_pad("sink")
+        .context("Failed to get sink pad from videorate")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_whole_and_ntsc_rates() {
+        let rate = |s: &str| s.parse::<FrameRate>().unwrap().to_string();
+        assert_eq!(rate("25"), "25");
+        assert_eq!(rate("23.976"), "24000/1001");
+        assert_eq!(rate("29.97"), "30000/1001");
+        assert_eq!(rate("59.94"), "60000/1001");
+        assert_eq!(rate("24000/1001"), "24000/1001");
+        assert_eq!(rate("12.5"), "25/2");
+        assert_eq!(rate("50/2"), "25");
+        assert!("0".parse::<FrameRate>().is_err());
+        assert!("fast".parse::<FrameRate>().is_err());
+
+        let json = serde_json::to_string(&"23.976".parse::<FrameRate>().unwrap()).unwrap();
+        assert_eq!(json, "\"24000/1001\"");
+        let parsed: FrameRate = serde_json::from_str(&json).unwrap();
+        assert!((parsed.fps() - 23.976).abs() < 0.001);
+    }
+}
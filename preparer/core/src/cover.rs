@@ -0,0 +1,138 @@
+//! Cover art carried in the source, like a Matroska attachment or an
+//! embedded still that demuxers expose as a video stream.
+//!
+//! It isn't video to encode, so it's kept out of the video tee and saved
+//! next to the manifest instead, where the library picks it up as artwork.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Names the cover art can be saved under, by image type.
+pub const FILENAMES: &[&str] = &["cover.jpg", "cover.png"];
+
+/// Whether a stream of `caps_name` at `framerate` is a still rather than
+/// video; Motion JPEG is `image/jpeg` too, but has a frame rate.
+pub(crate) fn is_cover_art(caps_name: &str, framerate: Option<gst::Fraction>) -> bool {
+    caps_name.starts_with("image/") && framerate.is_none_or(|rate| rate.numer() == 0)
+}
+
+/// Whether `caps` are of a still that decoders should leave alone.
+pub(crate) fn caps_are_cover_art(caps: &gst::CapsRef) -> bool {
+    caps.structure(0).is_some_and(|structure| {
+        is_cover_art(
+            structure.name(),
+            structure.get::<gst::Fraction>("framerate").ok(),
+        )
+    })
+}
+
+/// Saves the first cover art found into the output directory.
+pub(crate) struct CoverArt {
+    dir: PathBuf,
+    saved: AtomicBool,
+}
+
+impl CoverArt {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            saved: AtomicBool::new(false),
+        }
+    }
+
+    /// Write an image of type `caps_name`, unless one was already written.
+    pub(crate) fn save(&self, caps_name: &str, data: &[u8]) -> Result<()> {
+        let name = match caps_name {
+            "image/jpeg" => FILENAMES[0],
+            "image/png" => FILENAMES[1],
+            _ => return Ok(()),
+        };
+        if self.saved.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let path = self.dir.join(name);
+        std::fs::write(&path, data).context(format!("Failed to write {}", path.display()))
+    }
+
+    /// Save the image in `sample`, if it is one.
+    pub(crate) fn save_sample(&self, sample: &gst::SampleRef) -> Result<()> {
+        let (Some(caps), Some(buffer)) = (sample.caps(), sample.buffer()) else {
+            return Ok(());
+        };
+        let Some(structure) = caps.structure(0) else {
+            return Ok(());
+        };
+        let map = buffer.map_readable()?;
+        self.save(structure.name(), map.as_slice())
+    }
+
+    /// Save cover art tagged as an image or attachment, as MP4, MP3 and
+    /// Matroska carry it.
+    pub(crate) fn save_from_tags(&self, tags: &gst::TagListRef) -> Result<()> {
+        if let Some(image) = tags.get::<gst::tags::Image>() {
+            return self.save_sample(&image.get());
+        }
+        for attachment in tags.iter_tag::<gst::tags::Attachment>() {
+            self.save_sample(&attachment.get())?;
+        }
+        Ok(())
+    }
+
+    /// Send the still on `pad` to a sink that saves its first frame.
+    pub(crate) fn capture(
+        self: &std::sync::Arc<Self>,
+        pipeline: &gst::Pipeline,
+        pad: &gst::Pad,
+    ) -> Result<()> {
+        let fakesink = gst::ElementFactory::make("fakesink")
+            .property("sync", false)
+            .property("async", false)
+            .build()?;
+        pipeline.add(&fakesink)?;
+        fakesink.sync_state_with_parent()?;
+        let sink_pad = fakesink
+            .static_pad("sink")
+            .context("Failed to get sink pad from fakesink")?;
+        let cover = self.clone();
+        sink_pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            if let Some(buffer) = info.buffer()
+                && let Some(caps) = pad.current_caps()
+                && let Some(structure) = caps.structure(0)
+                && let Ok(map) = buffer.map_readable()
+                && let Err(err) = cover.save(structure.name(), map.as_slice())
+            {
+                eprintln!("Warning: {:#}", err);
+            }
+            gst::PadProbeReturn::Ok
+        });
+        pad.link(&sink_pad)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_the_first_still() {
+        assert!(is_cover_art("image/jpeg", None));
+        assert!(is_cover_art("image/png", Some(gst::Fraction::new(0, 1))));
+        // Motion JPEG video
+        assert!(!is_cover_art("image/jpeg", Some(gst::Fraction::new(30, 1))));
+        assert!(!is_cover_art("video/x-h264", None));
+
+        let dir = std::env::temp_dir().join(format!("movieshare-cover-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cover = CoverArt::new(dir.clone());
+        cover.save("image/gif", b"gif").unwrap();
+        cover.save("image/png", b"png").unwrap();
+        cover.save("image/jpeg", b"jpeg").unwrap();
+        assert_eq!(std::fs::read(dir.join("cover.png")).unwrap(), b"png");
+        assert!(!dir.join("cover.jpg").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
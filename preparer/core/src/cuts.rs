@@ -0,0 +1,228 @@
+//! Ranges of the input to leave out, like commercials or recaps.
+//!
+//! Cut lists are read from EDL files as MPlayer and Kodi write them: one
+//! `start end [action]` line per range, with times in seconds or as
+//! `[HH:]MM:SS[.sss]`. Actions 0 (cut) and 3 (commercial break) remove the
+//! range; other actions, like mutes and scene markers, are ignored.
+
+use anyhow::{Context, Result, bail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+
+/// One range of the input to remove.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Cut {
+    pub start_secs: f64,
+    pub end_secs: f64,
+}
+
+/// Parse `SS[.sss]`, `MM:SS[.sss]` or `HH:MM:SS[.sss]`.
+pub fn parse_time(text: &str) -> Option<f64> {
+    let mut secs = 0.0;
+    for part in text.split(':') {
+        let value: f64 = part.parse().ok()?;
+        if !value.is_finite() || value < 0.0 {
+            return None;
+        }
+        secs = secs * 60.0 + value;
+    }
+    Some(secs)
+}
+
+/// Parse the text of an EDL file into sorted, non-overlapping cuts.
+pub fn parse_edl(text: &str) -> Result<Vec<Cut>> {
+    let mut cuts = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (start, end, action) = match fields.as_slice() {
+            [start, end] => (*start, *end, "0"),
+            [start, end, action, ..] => (*start, *end, *action),
+            _ => bail!("Line {}: expected a start and an end", number + 1),
+        };
+        if !matches!(action, "0" | "3") {
+            continue;
+        }
+        let (Some(start_secs), Some(end_secs)) = (parse_time(start), parse_time(end)) else {
+            bail!("Line {}: invalid time in {:?}", number + 1, line);
+        };
+        if end_secs <= start_secs {
+            bail!("Line {}: the cut ends before it starts", number + 1);
+        }
+        cuts.push(Cut {
+            start_secs,
+            end_secs,
+        });
+    }
+    Ok(normalize(cuts))
+}
+
+/// Read and parse an EDL file.
+pub fn read_edl(path: &Path) -> Result<Vec<Cut>> {
+    let text = std::fs::read_to_string(path)
+        .context(format!("Failed to read cut list {}", path.display()))?;
+    parse_edl(&text).context(format!("Invalid cut list {}", path.display()))
+}
+
+/// Sort cuts and merge the ones that overlap or touch.
+pub fn normalize(mut cuts: Vec<Cut>) -> Vec<Cut> {
+    cuts.sort_by(|a, b| a.start_secs.total_cmp(&b.start_secs));
+    let mut merged: Vec<Cut> = Vec::with_capacity(cuts.len());
+    for cut in cuts {
+        match merged.last_mut() {
+            Some(last) if cut.start_secs <= last.end_secs => {
+                last.end_secs = last.end_secs.max(cut.end_secs);
+            }
+            _ => merged.push(cut),
+        }
+    }
+    merged
+}
+
+/// What happens to a buffer at some input time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement {
+    /// Inside a cut
+    Dropped,
+    /// Kept, moved earlier by everything cut before it; `after_cut` is set
+    /// for the first time that follows a cut
+    Kept { time: Duration, after_cut: bool },
+}
+
+/// Maps input timestamps to output ones across a list of cuts.
+#[derive(Debug, Clone, Default)]
+pub struct Splice {
+    cuts: Vec<Cut>,
+}
+
+impl Splice {
+    pub fn new(cuts: Vec<Cut>) -> Self {
+        Self {
+            cuts: normalize(cuts),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cuts.is_empty()
+    }
+
+    /// Where a buffer starting at `time` ends up; `previous` is the input
+    /// time of the stream's previous buffer, to tell whether a cut lies
+    /// between them.
+    pub fn place(&self, time: Duration, previous: Option<Duration>) -> Placement {
+        let secs = time.as_secs_f64();
+        let mut removed = 0.0;
+        for cut in &self.cuts {
+            if secs >= cut.end_secs {
+                removed += cut.end_secs - cut.start_secs;
+            } else if secs >= cut.start_secs {
+                return Placement::Dropped;
+            } else {
+                break;
+            }
+        }
+        let after_cut = self.cuts.iter().any(|cut| {
+            secs >= cut.end_secs
+                && previous.is_none_or(|previous| previous.as_secs_f64() < cut.end_secs)
+        });
+        Placement::Kept {
+            time: time.saturating_sub(Duration::from_secs_f64(removed)),
+            after_cut,
+        }
+    }
+
+    /// How long the output is for an input of `duration`.
+    pub fn output_duration(&self, duration: Duration) -> Duration {
+        let total = duration.as_secs_f64();
+        let removed: f64 = self
+            .cuts
+            .iter()
+            .map(|cut| cut.end_secs.min(total) - cut.start_secs.min(total))
+            .sum();
+        duration.saturating_sub(Duration::from_secs_f64(removed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_edl_files() {
+        let cuts = parse_edl(
+            "# recap\n0 95.5 0\n\n1200.25\t1380 3\n10:00 10:05 1\n1:00:00 1:02:30\n1370 1400 0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            cuts,
+            [
+                Cut {
+                    start_secs: 0.0,
+                    end_secs: 95.5
+                },
+                Cut {
+                    start_secs: 1200.25,
+                    end_secs: 1400.0
+                },
+                Cut {
+                    start_secs: 3600.0,
+                    end_secs: 3750.0
+                },
+            ]
+        );
+        assert!(parse_edl("20 10 0").is_err());
+        assert!(parse_edl("abc 10").is_err());
+        assert!(parse_edl("10").is_err());
+    }
+
+    #[test]
+    fn shifts_times_past_cuts() {
+        let splice = Splice::new(vec![
+            Cut {
+                start_secs: 10.0,
+                end_secs: 20.0,
+            },
+            Cut {
+                start_secs: 30.0,
+                end_secs: 35.0,
+            },
+        ]);
+        let secs = Duration::from_secs;
+        assert_eq!(
+            splice.place(secs(5), None),
+            Placement::Kept {
+                time: secs(5),
+                after_cut: false
+            }
+        );
+        assert_eq!(splice.place(secs(15), Some(secs(5))), Placement::Dropped);
+        assert_eq!(
+            splice.place(secs(20), Some(secs(19))),
+            Placement::Kept {
+                time: secs(10),
+                after_cut: true
+            }
+        );
+        assert_eq!(
+            splice.place(secs(21), Some(secs(20))),
+            Placement::Kept {
+                time: secs(11),
+                after_cut: false
+            }
+        );
+        assert_eq!(
+            splice.place(secs(40), Some(secs(29))),
+            Placement::Kept {
+                time: secs(25),
+                after_cut: true
+            }
+        );
+        assert_eq!(splice.output_duration(secs(32)), secs(20));
+    }
+}
@@ -0,0 +1,155 @@
+//! Sources with damaged stretches, like old captures. GStreamer's decoders
+//! give up after a few corrupt packets, failing the whole run; they can be
+//! told to carry on instead, dropping what they can't decode, and the gap
+//! it leaves in the picture either kept or filled with the last good frame.
+
+use anyhow::{Context, Result, bail};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What to do when the source can't be decoded in places.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DecodeErrors {
+    /// Fail the run, as the decoders do by themselves
+    #[default]
+    Abort,
+    /// Leave the damaged stretch out, with a warning saying where it was
+    Skip,
+    /// Hold the last good frame over the damaged stretch
+    FreezeFrame,
+}
+
+impl FromStr for DecodeErrors {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "abort" => DecodeErrors::Abort,
+            "skip" => DecodeErrors::Skip,
+            "freeze-frame" => DecodeErrors::FreezeFrame,
+            _ => bail!(
+                "Unknown decode error handling {:?}; expected abort, skip or freeze-frame",
+                s
+            ),
+        })
+    }
+}
+
+impl DecodeErrors {
+    /// Have every decoder that turns up in `pipeline` carry on past
+    /// corrupt data, unless aborting.
+    pub(crate) fn apply(self, pipeline: &gst::Pipeline) {
+        if self == DecodeErrors::Abort {
+            return;
+        }
+        pipeline.connect_deep_element_added(|_, _, element| {
+            // Video and audio decoders alike, which count errors the same way
+            if element.find_property("max-errors").is_some() {
+                element.set_property("max-errors", -1i32);
+            }
+        });
+    }
+
+    /// Put what this needs ahead of `downstream`, the pad decoded video
+    /// goes to, returning the pad to send it to instead.
+    pub(crate) fn insert(self, pipeline: &gst::Pipeline, downstream: gst::Pad) -> Result<gst::Pad> {
+        let sink = match self {
+            DecodeErrors::Abort => return Ok(downstream),
+            DecodeErrors::Skip => downstream,
+            // videorate fills gaps in the timestamps by repeating the frame
+            // before them
+            DecodeErrors::FreezeFrame => {
+                let videorate = gst::ElementFactory::make("videorate").build()?;
+                pipeline.add(&videorate)?;
+                videorate
+                    .static_pad("src")
+                    .context("Failed to get src pad from videorate")?
+                    .link(&downstream)?;
+                videorate
+                    .static_pad("sink")
+                    .context("Failed to get sink pad from videorate")?
+            }
+        };
+        let what = match self {
+            DecodeErrors::FreezeFrame => "Held the last good frame over",
+            _ => "Left out",
+        };
+        let gaps = Mutex::new(Gaps::default());
+        sink.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            let Some(buffer) = info.buffer() else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let (Some(pts), Some(duration)) = (buffer.pts(), buffer.duration()) else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let gap = gaps.lock().unwrap().frame(
+                Duration::from_nanos(pts.nseconds()),
+                Duration::from_nanos(duration.nseconds()),
+            );
+            if let Some((from, to)) = gap
+                && let Some(element) = pad.parent_element()
+            {
+                gst::element_warning!(
+                    element,
+                    gst::StreamError::Decode,
+                    (
+                        "{} damaged source from {:.2}s to {:.2}s",
+                        what,
+                        from.as_secs_f64(),
+                        to.as_secs_f64()
+                    )
+                );
+            }
+            gst::PadProbeReturn::Ok
+        });
+        Ok(sink)
+    }
+}
+
+/// Follows decoded frames for stretches of the source that never arrived.
+#[derive(Debug, Default)]
+struct Gaps {
+    /// Where the last frame ended
+    end: Option<Duration>,
+}
+
+impl Gaps {
+    /// A frame at `pts` lasting `duration` went by; returns the gap before
+    /// it if at least a frame's worth is missing.
+    fn frame(&mut self, pts: Duration, duration: Duration) -> Option<(Duration, Duration)> {
+        let end = self.end.replace(pts + duration)?;
+        (pts >= end + duration && !duration.is_zero()).then_some((end, pts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notes_missing_stretches() {
+        assert_eq!(
+            "freeze-frame".parse::<DecodeErrors>().unwrap(),
+            DecodeErrors::FreezeFrame
+        );
+        assert!("repair".parse::<DecodeErrors>().is_err());
+
+        let frame = Duration::from_millis(40);
+        let at = |frames: u64| Duration::from_millis(40 * frames);
+        let mut gaps = Gaps::default();
+        assert_eq!(gaps.frame(at(0), frame), None);
+        assert_eq!(gaps.frame(at(1), frame), None);
+        // Timestamps a little off aren't a missing frame
+        assert_eq!(gaps.frame(at(2) + Duration::from_millis(5), frame), None);
+        assert_eq!(
+            gaps.frame(at(30), frame),
+            Some((at(3) + Duration::from_millis(5), at(30)))
+        );
+        assert_eq!(gaps.frame(at(31), frame), None);
+    }
+}
@@ -0,0 +1,130 @@
+//! Which decoders sources go through. GStreamer picks among decoders by
+//! rank, and software ones usually win, so a 4K HEVC source can keep every
+//! core busy decoding before the encoder gets any of them. Asking for a
+//! hardware family raises its decoders above the rest; asking for software
+//! drops hardware ones out of the running.
+
+use anyhow::{Result, bail};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Decoder {
+    /// Whichever GStreamer ranks highest
+    #[default]
+    Auto,
+    /// Never a hardware decoder
+    Software,
+    /// VA-API, on Intel and AMD GPUs
+    Vaapi,
+    /// NVDEC, on NVIDIA GPUs
+    Nvdec,
+}
+
+impl FromStr for Decoder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "auto" => Decoder::Auto,
+            "software" => Decoder::Software,
+            "vaapi" => Decoder::Vaapi,
+            "nvdec" => Decoder::Nvdec,
+            _ => bail!(
+                "Unknown decoder {:?}; expected auto, software, vaapi or nvdec",
+                s
+            ),
+        })
+    }
+}
+
+impl Decoder {
+    /// Whether this asks for a family of hardware decoders.
+    pub fn is_hardware(self) -> bool {
+        matches!(self, Decoder::Vaapi | Decoder::Nvdec)
+    }
+
+    /// The element decoding sources: decodebin3 for hardware, which hands
+    /// streams to the decoders ranked above without trying every one.
+    pub(crate) fn element(self) -> &'static str {
+        match self.is_hardware() {
+            true => "decodebin3",
+            false => "decodebin",
+        }
+    }
+
+    /// Whether a decoder factory is of the family this asks for.
+    fn is_family(self, name: &str) -> bool {
+        match self {
+            Decoder::Vaapi => name.starts_with("va"),
+            Decoder::Nvdec => name.starts_with("nv"),
+            Decoder::Auto | Decoder::Software => false,
+        }
+    }
+
+    /// The rank to give a decoder for this choice, or `None` to leave it.
+    fn rank(self, name: &str, hardware: bool) -> Option<gst::Rank> {
+        match self {
+            Decoder::Auto => None,
+            Decoder::Software => hardware.then_some(gst::Rank::NONE),
+            _ if hardware && self.is_family(name) => Some(gst::Rank::PRIMARY + 1),
+            _ => None,
+        }
+    }
+
+    /// Rerank the installed decoders for this choice, for the rest of the
+    /// process. Fails if no decoder of the family asked for is installed.
+    pub fn apply(self) -> Result<()> {
+        gst::init()?;
+        let mut found = false;
+        let decoders = gst::ElementFactory::factories_with_type(
+            gst::ElementFactoryType::DECODER,
+            gst::Rank::NONE,
+        );
+        for factory in decoders {
+            let hardware = factory
+                .metadata(gst::ELEMENT_METADATA_KLASS)
+                .is_some_and(|klass| klass.contains("Hardware"));
+            let name = factory.name();
+            found |= hardware && self.is_family(&name);
+            if let Some(rank) = self.rank(&name, hardware) {
+                factory.set_rank(rank);
+            }
+        }
+        if self.is_hardware() && !found {
+            bail!("No {:?} decoders are installed", self);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_the_family_asked_for() {
+        assert_eq!(Decoder::Auto.rank("vah265dec", true), None);
+        assert_eq!(
+            Decoder::Software.rank("nvh265dec", true),
+            Some(gst::Rank::NONE)
+        );
+        assert_eq!(Decoder::Software.rank("avdec_h265", false), None);
+        assert_eq!(
+            Decoder::Vaapi.rank("vah265dec", true),
+            Some(gst::Rank::PRIMARY + 1)
+        );
+        assert_eq!(Decoder::Vaapi.rank("nvh265dec", true), None);
+        assert_eq!(
+            Decoder::Nvdec.rank("nvh265dec", true),
+            Some(gst::Rank::PRIMARY + 1)
+        );
+        // A software decoder is left alone whatever it's called
+        assert_eq!(Decoder::Vaapi.rank("vorbisdec", false), None);
+        assert_eq!("nvdec".parse::<Decoder>().unwrap(), Decoder::Nvdec);
+        assert!("cuda".parse::<Decoder>().is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! Which encoders the ladder goes through. SVT-AV1 in software takes hours
+//! over a feature film on a small box, so GPU encoders found in the registry
+//! are preferred; asking for a family insists on it, and asking for
+//! software keeps to SVT-AV1 and x264.
+
+use crate::factory::ElementSpec;
+use anyhow::{Result, bail};
+use gstreamer as gst;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoder {
+    /// A hardware encoder if one is installed, otherwise software
+    #[default]
+    Auto,
+    /// SVT-AV1, and x264 for H.264
+    Software,
+    /// VA-API, on Intel and AMD GPUs
+    Vaapi,
+    /// NVENC, on NVIDIA GPUs
+    Nvenc,
+    /// Quick Sync, on Intel GPUs
+    Qsv,
+}
+
+impl FromStr for Encoder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "auto" => Encoder::Auto,
+            "software" => Encoder::Software,
+            "vaapi" => Encoder::Vaapi,
+            "nvenc" => Encoder::Nvenc,
+            "qsv" => Encoder::Qsv,
+            _ => bail!(
+                "Unknown encoder {:?}; expected auto, software, vaapi, nvenc or qsv",
+                s
+            ),
+        })
+    }
+}
+
+/// What a representation's video is compressed as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Av1,
+    /// For players without AV1 decoding, like older TVs and browsers
+    H264,
+}
+
+impl Codec {
+    /// Elements encoding this codec, hardware families first.
+    fn elements(self) -> [(Encoder, &'static str); 4] {
+        match self {
+            Codec::Av1 => [
+                (Encoder::Vaapi, "vaav1enc"),
+                (Encoder::Nvenc, "nvav1enc"),
+                (Encoder::Qsv, "qsvav1enc"),
+                (Encoder::Software, "svtav1enc"),
+            ],
+            Codec::H264 => [
+                (Encoder::Vaapi, "vah264enc"),
+                (Encoder::Nvenc, "nvh264enc"),
+                (Encoder::Qsv, "qsvh264enc"),
+                (Encoder::Software, "x264enc"),
+            ],
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Codec::Av1 => "av1",
+            Codec::H264 => "h264",
+        }
+    }
+}
+
+impl Encoder {
+    /// The element encoding `codec` for this choice, among those
+    /// `installed` says are.
+    fn pick(self, codec: Codec, installed: impl Fn(&str) -> bool) -> Result<VideoEncoder> {
+        let found = codec
+            .elements()
+            .into_iter()
+            .filter(|&(family, _)| self == Encoder::Auto || family == self)
+            .find(|&(_, element)| installed(element));
+        match found {
+            Some((_, element)) => Ok(VideoEncoder {
+                codec,
+                element,
+                scene_cuts_given: false,
+            }),
+            None => bail!("No {:?} encoder for {:?} is installed", self, codec),
+        }
+    }
+
+    pub fn is_auto(&self) -> bool {
+        *self == Encoder::Auto
+    }
+
+    /// Name of the implementation AV1 is encoded with under this choice,
+    /// like `nvenc`; for `auto`, the one the registry would give.
+    pub fn backend(self) -> &'static str {
+        let family = match self {
+            Encoder::Auto => Encoder::Auto
+                .find(Codec::Av1)
+                .ok()
+                .and_then(|encoder| {
+                    Codec::Av1
+                        .elements()
+                        .into_iter()
+                        .find(|&(_, element)| element == encoder.element)
+                })
+                .map_or(Encoder::Software, |(family, _)| family),
+            family => family,
+        };
+        match family {
+            Encoder::Auto | Encoder::Software => "svtav1",
+            Encoder::Vaapi => "vaapi",
+            Encoder::Nvenc => "nvenc",
+            Encoder::Qsv => "qsv",
+        }
+    }
+
+    /// The element encoding `codec` for this choice, from the registry.
+    pub(crate) fn find(self, codec: Codec) -> Result<VideoEncoder> {
+        gst::init()?;
+        self.pick(codec, |element| {
+            gst::ElementFactory::find(element).is_some()
+        })
+    }
+}
+
+/// An encoder element, and how to set it up like SVT-AV1 would be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct VideoEncoder {
+    pub(crate) codec: Codec,
+    pub(crate) element: &'static str,
+    /// Keyframes are forced at scene cuts found beforehand, so the encoder
+    /// needn't look for them itself
+    pub(crate) scene_cuts_given: bool,
+}
+
+impl VideoEncoder {
+    #[cfg(test)]
+    pub(crate) const SVT_AV1: Self = Self {
+        codec: Codec::Av1,
+        element: "svtav1enc",
+        scene_cuts_given: false,
+    };
+
+    /// This encoder for a ladder whose scene cuts were found once, before
+    /// encoding, rather than by each rung's encoder going over the same
+    /// frames.
+    pub(crate) fn given_scene_cuts(mut self) -> Self {
+        self.scene_cuts_given = true;
+        self
+    }
+
+    /// The encoder at `bitrate_kbps`, with a keyframe every
+    /// `keyframe_interval` frames. `preset` is SVT-AV1's, from 0 to 13,
+    /// carried over to the other encoders' speed settings.
+    pub(crate) fn spec(
+        self,
+        bitrate_kbps: u32,
+        preset: u32,
+        keyframe_interval: u32,
+    ) -> ElementSpec {
+        // Hardware encoders go from 1, best, to 7, fastest
+        let usage = 1 + preset.min(13) * 6 / 13;
+        let spec = ElementSpec::new(self.element);
+        let spec = match self.element {
+            "svtav1enc" => spec
+                .property("preset", preset)
+                .property("target-bitrate", bitrate_kbps)
+                .property("intra-period-length", keyframe_interval as i32),
+            "x264enc" => spec
+                .property("bitrate", bitrate_kbps)
+                .property("key-int-max", keyframe_interval)
+                .property_from_str(
+                    "speed-preset",
+                    [
+                        "slower",
+                        "slow",
+                        "medium",
+                        "fast",
+                        "faster",
+                        "veryfast",
+                        "superfast",
+                    ][usage as usize - 1],
+                ),
+            element if element.starts_with("nv") => spec
+                .property("bitrate", bitrate_kbps)
+                .property("gop-size", keyframe_interval as i32)
+                .property_from_str("rc-mode", "vbr")
+                .property_from_str("preset", &format!("p{}", 8 - usage)),
+            _ => spec
+                .property("bitrate", bitrate_kbps)
+                .property(
+                    match self.element.starts_with("qsv") {
+                        true => "gop-size",
+                        false => "key-int-max",
+                    },
+                    keyframe_interval,
+                )
+                .property("target-usage", usage)
+                .property_from_str("rate-control", "vbr"),
+        };
+        // NVENC's and QSV's own scene-cut and adaptive I-frame options are
+        // left alone, as their names vary between plugin versions; the cuts
+        // given still get keyframes, and at worst a few more come on top
+        match (self.scene_cuts_given, self.element) {
+            (true, "svtav1enc") => spec.property("parameters-string", "scd=0"),
+            (true, "x264enc") => spec.property("option-string", "scenecut=0"),
+            _ => spec,
+        }
+    }
+
+    /// The parser that frames this encoder's output for the muxer.
+    pub(crate) fn parser(self) -> &'static str {
+        match self.codec {
+            Codec::Av1 => "av1parse",
+            Codec::H264 => "h264parse",
+        }
+    }
+
+    /// Caps the parsed stream is muxed as.
+    pub(crate) fn caps(self) -> &'static str {
+        match self.codec {
+            Codec::Av1 => "video/x-av1,stream-format=obu-stream,alignment=tu",
+            Codec::H264 => "video/x-h264,stream-format=avc,alignment=au",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factory::PropertyValue;
+
+    #[test]
+    fn prefers_hardware_encoders() {
+        let installed = |names: &'static [&'static str]| move |name: &str| names.contains(&name);
+        let box_with_gpu = installed(&["svtav1enc", "x264enc", "vaav1enc"]);
+        let pick = |encoder: Encoder, codec| encoder.pick(codec, box_with_gpu).unwrap().element;
+        assert_eq!(pick(Encoder::Auto, Codec::Av1), "vaav1enc");
+        assert_eq!(pick(Encoder::Software, Codec::Av1), "svtav1enc");
+        // Without a hardware H.264 encoder, auto falls back to x264
+        assert_eq!(pick(Encoder::Auto, Codec::H264), "x264enc");
+        assert!(Encoder::Vaapi.pick(Codec::H264, box_with_gpu).is_err());
+        assert!(Encoder::Nvenc.pick(Codec::Av1, box_with_gpu).is_err());
+        assert_eq!("qsv".parse::<Encoder>().unwrap(), Encoder::Qsv);
+        assert!("amf".parse::<Encoder>().is_err());
+
+        let nvenc = VideoEncoder {
+            codec: Codec::H264,
+            element: "nvh264enc",
+            scene_cuts_given: false,
+        };
+        let spec = nvenc.spec(6000, 8, 120);
+        assert_eq!(spec.get("bitrate"), Some(&PropertyValue::U32(6000)));
+        assert_eq!(spec.get("gop-size"), Some(&PropertyValue::I32(120)));
+        assert_eq!(
+            spec.get("preset"),
+            Some(&PropertyValue::Parsed(String::from("p4")))
+        );
+        assert_eq!(nvenc.parser(), "h264parse");
+
+        let svt = VideoEncoder::SVT_AV1;
+        assert_eq!(svt.spec(6000, 8, 120).get("parameters-string"), None);
+        assert_eq!(
+            svt.given_scene_cuts()
+                .spec(6000, 8, 120)
+                .get("parameters-string"),
+            Some(&PropertyValue::Str(String::from("scd=0")))
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! The versioned JSON event format shared by `--json` output and the daemon APIs.
+//!
+//! These types are deliberately separate from the engine's own
+//! [`JobEvent`] and [`QueueEvent`] so internal refactors can't change what
+//! integrators see. Within one [`EVENT_SCHEMA_VERSION`], fields and event
+//! types are only ever added; renaming or removing anything requires a
+//! version bump. Consumers should ignore fields and event types they don't
+//! know. The golden files under `tests/golden` pin the current format.
+
+use crate::bottleneck::{BottleneckReport, Stage};
+use crate::cancel::CancelPolicy;
+use crate::job::{BranchStats, JobEvent};
+use crate::preparer::{Outcome, Progress, Summary};
+use crate::qc::{Defect, DefectRun, QcReport};
+use crate::queue::{JobId, JobState, QueueEvent};
+use anyhow::{Result, anyhow};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishedOutcome {
+    Prepared,
+    AlreadyPrepared,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStateName {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventBody {
+    /// Sent about twice a second while a job runs
+    Progress {
+        /// From 0.0 to 1.0
+        fraction: f64,
+        frames: u64,
+        fps: f64,
+        /// Seconds the rest of the job should take
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        eta_secs: Option<f64>,
+    },
+    /// A non-fatal problem
+    Warning { message: String },
+    /// What one representation produced; sent once each when encoding ends
+    BranchStats {
+        target_bitrate_mbps: u32,
+        frames: u64,
+        bytes: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        average_bitrate_kbps: Option<f64>,
+    },
+    /// Which stage of the pipeline held the encode back; sent once when
+    /// encoding ends, if asked for
+    Bottleneck {
+        /// `decode`, `convert`, `encode`, `parse` or `mux`
+        bound: String,
+        /// Average fill of the queues in front of each stage, from 0.0 to 1.0
+        fills: BTreeMap<String, f64>,
+    },
+    /// Long stretches of black or frozen frames in the video; sent once
+    /// when encoding ends, if asked for
+    Qc { runs: Vec<QcRun> },
+    /// Always the last event of a job
+    Finished {
+        outcome: FinishedOutcome,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cancel_policy: Option<CancelPolicy>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        frames: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        elapsed_secs: Option<f64>,
+    },
+    /// What a job is expected to produce; sent once before it starts
+    Estimate {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size_bytes: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration_secs: Option<f64>,
+    },
+    /// A daemon job moved to a new state
+    StateChanged {
+        state: JobStateName,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+impl EventBody {
+    /// The `type` field this body serializes with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EventBody::Progress { .. } => "progress",
+            EventBody::Warning { .. } => "warning",
+            EventBody::BranchStats { .. } => "branch_stats",
+            EventBody::Bottleneck { .. } => "bottleneck",
+            EventBody::Qc { .. } => "qc",
+            EventBody::Finished { .. } => "finished",
+            EventBody::Estimate { .. } => "estimate",
+            EventBody::StateChanged { .. } => "state_changed",
+        }
+    }
+}
+
+/// A stretch of video that looks wrong.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct QcRun {
+    /// `black` or `frozen`
+    pub defect: String,
+    pub start_secs: f64,
+    pub end_secs: f64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct Event {
+    /// The [`EVENT_SCHEMA_VERSION`] the event was written with
+    pub schema: u32,
+    /// The daemon job the event belongs to; absent in `--json` output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<JobId>,
+    #[serde(flatten)]
+    pub body: EventBody,
+}
+
+impl Event {
+    pub fn new(body: EventBody) -> Self {
+        Self {
+            schema: EVENT_SCHEMA_VERSION,
+            job_id: None,
+            body,
+        }
+    }
+
+    pub fn from_job_event(event: &JobEvent) -> Self {
+        Self::new(match event {
+            JobEvent::Progress(progress) => EventBody::Progress {
+                fraction: progress.fraction,
+                frames: progress.frames,
+                fps: progress.fps,
+                eta_secs: progress.eta_secs,
+            },
+            JobEvent::Warning(message) => EventBody::Warning {
+                message: message.clone(),
+            },
+            JobEvent::BranchStats(stats) => EventBody::BranchStats {
+                target_bitrate_mbps: stats.target_bitrate_mbps,
+                frames: stats.frames,
+                bytes: stats.bytes,
+                average_bitrate_kbps: stats.average_bitrate_kbps,
+            },
+            JobEvent::Bottleneck(report) => EventBody::Bottleneck {
+                bound: report.bound.name().to_string(),
+                fills: report
+                    .fills
+                    .iter()
+                    .map(|(stage, fill)| (stage.name().to_string(), *fill))
+                    .collect(),
+            },
+            JobEvent::Qc(report) => EventBody::Qc {
+                runs: report
+                    .runs
+                    .iter()
+                    .map(|run| QcRun {
+                        defect: run.defect.name().to_string(),
+                        start_secs: run.start.as_secs_f64(),
+                        end_secs: run.end.as_secs_f64(),
+                    })
+                    .collect(),
+            },
+            JobEvent::Finished(result) => {
+                let (outcome, error, cancel_policy, frames, elapsed_secs) = match result {
+                    Ok(Outcome::Prepared(summary)) => (
+                        FinishedOutcome::Prepared,
+                        None,
+                        None,
+                        Some(summary.frames),
+                        Some(summary.elapsed.as_secs_f64()),
+                    ),
+                    Ok(Outcome::AlreadyPrepared) => {
+                        (FinishedOutcome::AlreadyPrepared, None, None, None, None)
+                    }
+                    Ok(Outcome::Cancelled(policy)) => {
+                        (FinishedOutcome::Cancelled, None, Some(*policy), None, None)
+                    }
+                    Err(err) => (
+                        FinishedOutcome::Failed,
+                        Some(format!("{:#}", err)),
+                        None,
+                        None,
+                        None,
+                    ),
+                };
+                EventBody::Finished {
+                    outcome,
+                    error,
+                    cancel_policy,
+                    frames,
+                    elapsed_secs,
+                }
+            }
+        })
+    }
+
+    /// The job event this was made from by [`from_job_event`](Self::from_job_event),
+    /// or `None` for queue events.
+    pub fn into_job_event(self) -> Option<JobEvent> {
+        Some(match self.body {
+            EventBody::Progress {
+                fraction,
+                frames,
+                fps,
+                eta_secs,
+            } => JobEvent::Progress(Progress {
+                fraction,
+                frames,
+                fps,
+                eta_secs,
+            }),
+            EventBody::Warning { message } => JobEvent::Warning(message),
+            EventBody::BranchStats {
+                target_bitrate_mbps,
+                frames,
+                bytes,
+                average_bitrate_kbps,
+            } => JobEvent::BranchStats(BranchStats {
+                target_bitrate_mbps,
+                frames,
+                bytes,
+                average_bitrate_kbps,
+            }),
+            EventBody::Bottleneck { bound, fills } => JobEvent::Bottleneck(BottleneckReport {
+                bound: Stage::from_name(&bound)?,
+                fills: fills
+                    .iter()
+                    .filter_map(|(stage, fill)| Some((Stage::from_name(stage)?, *fill)))
+                    .collect(),
+            }),
+            EventBody::Qc { runs } => JobEvent::Qc(QcReport {
+                runs: runs
+                    .iter()
+                    .filter_map(|run| {
+                        Some(DefectRun {
+                            defect: Defect::from_name(&run.defect)?,
+                            start: std::time::Duration::from_secs_f64(run.start_secs),
+                            end: std::time::Duration::from_secs_f64(run.end_secs),
+                        })
+                    })
+                    .collect(),
+            }),
+            EventBody::Finished {
+                outcome,
+                error,
+                cancel_policy,
+                frames,
+                elapsed_secs,
+            } => JobEvent::Finished(match outcome {
+                FinishedOutcome::Prepared => Ok(Outcome::Prepared(Summary {
+                    frames: frames.unwrap_or(0),
+                    elapsed: std::time::Duration::from_secs_f64(elapsed_secs.unwrap_or(0.0)),
+                })),
+                FinishedOutcome::AlreadyPrepared => Ok(Outcome::AlreadyPrepared),
+                FinishedOutcome::Cancelled => Ok(Outcome::Cancelled(
+                    cancel_policy.unwrap_or(CancelPolicy::Discard),
+                )),
+                FinishedOutcome::Failed => {
+                    Err(anyhow!(error.unwrap_or_else(|| String::from("Job failed"))))
+                }
+            }),
+            EventBody::Estimate { .. } | EventBody::StateChanged { .. } => return None,
+        })
+    }
+
+    pub fn from_queue_event(event: &QueueEvent) -> Self {
+        let (id, body) = match event {
+            QueueEvent::StateChanged { id, state } => {
+                let (state, error) = match state {
+                    JobState::Queued => (JobStateName::Queued, None),
+                    JobState::Running => (JobStateName::Running, None),
+                    JobState::Completed => (JobStateName::Completed, None),
+                    JobState::Failed { error } => (JobStateName::Failed, Some(error.clone())),
+                    JobState::Cancelled => (JobStateName::Cancelled, None),
+                };
+                (*id, EventBody::StateChanged { state, error })
+            }
+            QueueEvent::Progress { id, progress } => (
+                *id,
+                EventBody::Progress {
+                    fraction: progress.fraction,
+                    frames: progress.frames,
+                    fps: progress.fps,
+                    eta_secs: progress.eta_secs,
+                },
+            ),
+            QueueEvent::Warning { id, message } => (
+                *id,
+                EventBody::Warning {
+                    message: message.clone(),
+                },
+            ),
+        };
+        Self {
+            job_id: Some(id),
+            ..Self::new(body)
+        }
+    }
+
+    /// One line of JSON, as written to `--json` output and event streams.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// The JSON Schema describing every event.
+    pub fn json_schema() -> Result<String> {
+        Ok(serde_json::to_string_pretty(&schemars::schema_for!(Event))?)
+    }
+}
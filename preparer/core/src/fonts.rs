@@ -0,0 +1,243 @@
+//! Fonts attached to the source, as Matroska carries them for styled ASS
+//! subtitles.
+//!
+//! The subtitles reach players as WebVTT, which names no fonts, so the
+//! attached fonts are packaged next to them in a directory of their own,
+//! with a stylesheet declaring each one and setting cues in the font the
+//! script's default style asks for.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use std::path::Path;
+
+/// Directory of the title the fonts are packaged into
+pub const DIR: &str = "fonts";
+/// Stylesheet in [`DIR`] the player loads
+pub const STYLESHEET: &str = "fonts.css";
+
+/// Types Matroska muxers give font attachments
+const FONT_TYPES: &[&str] = &[
+    "application/x-truetype-font",
+    "application/x-font-ttf",
+    "application/x-font-otf",
+    "application/x-font-opentype",
+    "application/vnd.ms-opentype",
+    "application/font-sfnt",
+    "font/ttf",
+    "font/otf",
+    "font/sfnt",
+    "font/collection",
+];
+/// Extensions of fonts attached with a generic type
+const FONT_EXTENSIONS: &[&str] = &["ttf", "otf", "ttc"];
+
+/// A font attached to the source.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Font {
+    /// Name it was attached under, without any directories
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+impl Font {
+    /// The family the font declares, or else its name without extension.
+    pub fn family(&self) -> String {
+        family_name(&self.data).unwrap_or_else(|| {
+            Path::new(&self.filename).file_stem().map_or_else(
+                || self.filename.clone(),
+                |stem| stem.to_string_lossy().into_owned(),
+            )
+        })
+    }
+}
+
+/// Whether an attachment of type `caps_name` named `filename` is a font.
+pub fn is_font(caps_name: &str, filename: &str) -> bool {
+    FONT_TYPES.contains(&caps_name)
+        || Path::new(filename).extension().is_some_and(|ext| {
+            FONT_EXTENSIONS
+                .iter()
+                .any(|font| ext.eq_ignore_ascii_case(font))
+        })
+}
+
+/// The fonts among the attachments in `tags`.
+pub fn from_tags(tags: &gst::TagListRef) -> Vec<Font> {
+    tags.iter_tag::<gst::tags::Attachment>()
+        .filter_map(|attachment| {
+            let sample = attachment.get();
+            let filename = sample
+                .info()
+                .and_then(|info| info.get::<&str>("filename").ok())
+                .and_then(|name| Path::new(name).file_name())?
+                .to_string_lossy()
+                .into_owned();
+            let caps_name = sample
+                .caps()
+                .and_then(|caps| caps.structure(0))
+                .map_or("", |structure| structure.name().as_str());
+            if !is_font(caps_name, &filename) {
+                return None;
+            }
+            let data = sample.buffer()?.map_readable().ok()?.to_vec();
+            Some(Font { filename, data })
+        })
+        .collect()
+}
+
+/// The family name in the `name` table of a TrueType or OpenType font, or
+/// of the first font of a collection.
+pub fn family_name(data: &[u8]) -> Option<String> {
+    let u16_at = |at: usize| Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?));
+    let u32_at = |at: usize| Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    let font = match data.get(..4)? {
+        b"ttcf" => u32_at(12)? as usize,
+        _ => 0,
+    };
+    let name = (0..u16_at(font + 4)? as usize)
+        .map(|table| font + 12 + table * 16)
+        .find(|&record| data.get(record..record + 4) == Some(b"name"))
+        .and_then(|record| u32_at(record + 8))? as usize;
+    let strings = name + u16_at(name + 4)? as usize;
+    let mut mac = None;
+    for record in (0..u16_at(name + 2)? as usize).map(|i| name + 6 + i * 12) {
+        // Font family, name 1
+        if u16_at(record + 6)? != 1 {
+            continue;
+        }
+        let start = strings + u16_at(record + 10)? as usize;
+        let bytes = data.get(start..start + u16_at(record + 8)? as usize)?;
+        match u16_at(record)? {
+            // Windows names are UTF-16, and the ones to go by
+            3 => {
+                let units: Vec<u16> = bytes
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect();
+                return Some(String::from_utf16_lossy(&units));
+            }
+            1 if mac.is_none() => mac = Some(bytes.iter().map(|&b| b as char).collect()),
+            _ => (),
+        }
+    }
+    mac
+}
+
+/// The fonts the styles in an ASS script's header are set in, the default
+/// style's first.
+pub fn style_fonts(header: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut styles = Vec::new();
+    for line in header.lines().map(str::trim) {
+        if let Some(format) = line.strip_prefix("Format:") {
+            fields = format.split(',').map(|f| f.trim().to_lowercase()).collect();
+        } else if let Some(style) = line.strip_prefix("Style:") {
+            let values: Vec<&str> = style
+                .splitn(fields.len().max(1), ',')
+                .map(str::trim)
+                .collect();
+            let field = |name: &str| {
+                let index = fields.iter().position(|f| f == name)?;
+                values.get(index).copied()
+            };
+            if let (Some(name), Some(font)) = (field("name"), field("fontname")) {
+                styles.push((name == "Default", font.to_string()));
+            }
+        }
+    }
+    styles.sort_by_key(|(default, _)| !default);
+    let mut fonts: Vec<String> = Vec::new();
+    for (_, font) in styles {
+        if !fonts.contains(&font) {
+            fonts.push(font);
+        }
+    }
+    fonts
+}
+
+/// Quote `text` as a CSS string.
+fn css_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Write `fonts` into the title in `dir` with a stylesheet declaring them,
+/// setting cues in `cue_font` if there is one.
+pub fn package(dir: &Path, fonts: &[Font], cue_font: Option<&str>) -> Result<()> {
+    let fonts_dir = dir.join(DIR);
+    std::fs::create_dir_all(&fonts_dir)
+        .context(format!("Failed to create {}", fonts_dir.display()))?;
+    let mut css = String::new();
+    for font in fonts {
+        let path = fonts_dir.join(&font.filename);
+        std::fs::write(&path, &font.data).context(format!("Failed to write {}", path.display()))?;
+        css.push_str(&format!(
+            "@font-face {{\n    font-family: {};\n    src: url({});\n}}\n\n",
+            css_string(&font.family()),
+            css_string(&font.filename)
+        ));
+    }
+    if let Some(font) = cue_font {
+        // Shaka draws cues itself rather than through the browser
+        css.push_str(&format!(
+            "::cue,\n.shaka-text-container {{\n    font-family: {}, sans-serif;\n}}\n",
+            css_string(font)
+        ));
+    }
+    let path = fonts_dir.join(STYLESHEET);
+    std::fs::write(&path, css).context(format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A font holding nothing but a `name` table with `family` in it.
+    fn font_named(family: &str) -> Vec<u8> {
+        let utf16: Vec<u8> = family.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        let mut data = Vec::new();
+        // One table, whose record says it starts right after the record
+        data.extend([0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        data.extend(b"name");
+        data.extend([0; 4]);
+        data.extend(28u32.to_be_bytes());
+        data.extend((6 + 12 + utf16.len() as u32).to_be_bytes());
+        // The name table: one Windows family name
+        data.extend([0, 0, 0, 1, 0, 18]);
+        data.extend([0, 3, 0, 1, 4, 9, 0, 1]);
+        data.extend((utf16.len() as u16).to_be_bytes());
+        data.extend([0, 0]);
+        data.extend(utf16);
+        data
+    }
+
+    #[test]
+    fn packages_the_fonts_styles_ask_for() {
+        assert!(is_font("application/x-truetype-font", "a.bin"));
+        assert!(is_font("application/octet-stream", "Title.OTF"));
+        assert!(!is_font("image/jpeg", "cover.jpg"));
+
+        let header = "[Script Info]\nScriptType: v4.00+\n\n[V4+ Styles]\nFormat: Name, Fontname, Fontsize, PrimaryColour\nStyle: Sign,Trajan Pro,40,&H00FFFFFF\nStyle: Default,Open Sans Semibold,52,&H00FFFFFF\nStyle: Alt,Open Sans Semibold,52,&H00FFFFFF\n";
+        assert_eq!(style_fonts(header), ["Open Sans Semibold", "Trajan Pro"]);
+
+        let font = Font {
+            filename: String::from("OpenSans-Semibold.ttf"),
+            data: font_named("Open Sans Semibold"),
+        };
+        assert_eq!(font.family(), "Open Sans Semibold");
+        let unreadable = Font {
+            filename: String::from("Trajan.otf"),
+            data: vec![0; 8],
+        };
+        assert_eq!(unreadable.family(), "Trajan");
+
+        let dir = std::env::temp_dir().join(format!("movieshare-fonts-{}", std::process::id()));
+        package(&dir, &[font, unreadable], Some("Open Sans Semibold")).unwrap();
+        assert!(dir.join(DIR).join("Trajan.otf").is_file());
+        let css = std::fs::read_to_string(dir.join(DIR).join(STYLESHEET)).unwrap();
+        assert!(css.contains(
+            "font-family: \"Open Sans Semibold\";\n    src: url(\"OpenSans-Semibold.ttf\");"
+        ));
+        assert!(css.contains("font-family: \"Open Sans Semibold\", sans-serif;"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+//! Signalling the encoder delay and padding of the audio, so playback
+//! doesn't start with the encoder's priming samples, heard as a click, or
+//! drift against the video by their length.
+//!
+//! Opus encoders put a pre-skip ahead of the audio, and AAC ones prime
+//! theirs too; the demuxer or encoder says how much through the stream's
+//! header or clipping metadata on the first and last buffers. dashsink's
+//! muxers ignore both, so each audio pad is watched while the run goes on,
+//! and afterwards the audio initialization segments get an edit list
+//! starting the track after the delay and ending it before the padding,
+//! with Opus's `dOps` pre-skip filled in where it was left out.
+
+use crate::sidx::{self, mp4_box};
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_audio as gst_audio;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Rate Opus timestamps and pre-skip are counted at, whatever it was
+/// encoded from
+const OPUS_RATE: u32 = 48000;
+
+/// Priming and padding of one audio representation.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct CodecDelay {
+    /// Rate `delay` and `padding` are counted at
+    pub(crate) rate: u32,
+    /// Samples to skip at the start
+    pub(crate) delay: u64,
+    /// Samples to leave out at the end
+    pub(crate) padding: u64,
+    /// Whether the track is Opus, whose `dOps` carries the pre-skip too
+    pub(crate) opus: bool,
+}
+
+/// What was seen on dashsink's audio pads, by pad name.
+#[derive(Clone, Default)]
+pub(crate) struct DelayWatch {
+    delays: Arc<Mutex<HashMap<String, CodecDelay>>>,
+}
+
+impl DelayWatch {
+    /// Watch the audio pads already requested from `dashsink`.
+    pub(crate) fn start(dashsink: &gst::Element) -> Self {
+        let watch = Self::default();
+        for pad in dashsink.sink_pads() {
+            let name = pad.name().to_string();
+            if !name.starts_with("audio_") {
+                continue;
+            }
+            let delays = watch.delays.clone();
+            pad.add_probe(
+                gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+                move |_, info| {
+                    let mut delays = delays.lock().unwrap();
+                    let delay = delays.entry(name.clone()).or_default();
+                    match &info.data {
+                        Some(gst::PadProbeData::Event(event)) => {
+                            if let gst::EventView::Caps(caps) = event.view() {
+                                read_caps(caps.caps(), delay);
+                            }
+                        }
+                        Some(gst::PadProbeData::Buffer(buffer)) => {
+                            if let Some(meta) = buffer.meta::<gst_audio::AudioClippingMeta>() {
+                                let samples = |value| match value {
+                                    gst::GenericFormattedValue::Default(Some(samples)) => *samples,
+                                    _ => 0,
+                                };
+                                // Opus's pre-skip is in its header already
+                                if !delay.opus {
+                                    delay.delay += samples(meta.start());
+                                }
+                                delay.padding += samples(meta.end());
+                            }
+                        }
+                        _ => (),
+                    }
+                    gst::PadProbeReturn::Ok
+                },
+            );
+        }
+        watch
+    }
+
+    /// The delays seen, by the name of the pad they were seen on.
+    pub(crate) fn delays(&self) -> HashMap<String, CodecDelay> {
+        self.delays.lock().unwrap().clone()
+    }
+}
+
+/// Take the rate, and for Opus the pre-skip, from the caps of an audio pad.
+fn read_caps(caps: &gst::CapsRef, delay: &mut CodecDelay) {
+    let Some(structure) = caps.structure(0) else {
+        return;
+    };
+    delay.opus = structure.name() == "audio/x-opus";
+    if !delay.opus {
+        delay.rate = structure.get::<i32>("rate").map_or(0, |rate| rate as u32);
+        return;
+    }
+    delay.rate = OPUS_RATE;
+    // The first stream header is the OpusHead, pre-skip at byte 10
+    if let Ok(headers) = structure.get::<gst::Array>("streamheader")
+        && let Some(Ok(header)) = headers
+            .as_slice()
+            .first()
+            .map(|value| value.get::<gst::Buffer>())
+        && let Ok(map) = header.map_readable()
+        && map.starts_with(b"OpusHead")
+        && let Some(pre_skip) = map.get(10..12)
+    {
+        delay.delay = u16::from_le_bytes([pre_skip[0], pre_skip[1]]) as u64;
+    }
+}
+
+/// An `edts` whose one edit plays `duration` of the movie's time from
+/// `media_time` into the track; a zero duration plays to the end.
+fn edit_list(duration: u64, media_time: u64) -> Vec<u8> {
+    let mut elst = Vec::with_capacity(28);
+    // Version 1, one entry
+    elst.extend(0x0100_0000u32.to_be_bytes());
+    elst.extend(1u32.to_be_bytes());
+    elst.extend(duration.to_be_bytes());
+    elst.extend(media_time.to_be_bytes());
+    // At normal rate
+    elst.extend([0, 1, 0, 0]);
+    mp4_box(b"edts", &mp4_box(b"elst", &elst))
+}
+
+/// Timescale of the `mvhd` or `mdhd` whose payload is `header`.
+fn timescale(header: &[u8]) -> Option<u32> {
+    let at = match header.first()? {
+        1 => 20,
+        _ => 12,
+    };
+    Some(u32::from_be_bytes(header.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// Write `pre_skip` into the `dOps` of an Opus sample description in
+/// `stbl` if it says there's none.
+fn fill_pre_skip(stbl: &mut [u8], pre_skip: u16) {
+    let Some(stsd) = sidx::find_range(stbl, &["stsd"]) else {
+        return;
+    };
+    // Version, flags and entry count, then the sample entry's header, its
+    // reserved bytes and data reference index, and the audio fields
+    let entry = stsd.start + 8;
+    if stbl.get(entry + 4..entry + 8) != Some(b"Opus") {
+        return;
+    }
+    let Some(dops) = stbl
+        .get(entry + 36..stsd.end)
+        .and_then(|children| sidx::find_range(children, &["dOps"]))
+    else {
+        return;
+    };
+    let at = entry + 36 + dops.start + 2;
+    if let Some(field) = stbl.get_mut(at..at + 2)
+        && field == [0, 0]
+    {
+        field.copy_from_slice(&pre_skip.to_be_bytes());
+    }
+}
+
+/// `init` with `delay` signalled on its audio track, which lasts
+/// `track_duration` in the track's timescale if that's known. `None` if
+/// there's no audio track, or it already has an edit list.
+fn signal_delay(init: &[u8], delay: &CodecDelay, track_duration: Option<u64>) -> Option<Vec<u8>> {
+    let moov = sidx::find(init, &["moov"])?;
+    let movie_timescale = timescale(sidx::find(moov, &["mvhd"])?)? as u64;
+    let mut rebuilt_moov = Vec::with_capacity(moov.len() + 44);
+    let mut signalled = false;
+    for (kind, payload) in sidx::boxes(moov) {
+        let is_audio = kind == b"trak"
+            && sidx::find(payload, &["mdia", "hdlr"]).and_then(|hdlr| hdlr.get(8..12))
+                == Some(b"soun");
+        if !is_audio || sidx::find(payload, &["edts"]).is_some() {
+            rebuilt_moov.extend(mp4_box(kind, payload));
+            continue;
+        }
+        let track_timescale = timescale(sidx::find(payload, &["mdia", "mdhd"])?)? as u64;
+        let in_track = |samples: u64| samples * track_timescale / delay.rate.max(1) as u64;
+        let media_time = in_track(delay.delay);
+        let duration = track_duration
+            .map(|total| total.saturating_sub(media_time + in_track(delay.padding)))
+            .map_or(0, |duration| {
+                duration * movie_timescale / track_timescale.max(1)
+            });
+
+        let mut trak = Vec::with_capacity(payload.len() + 44);
+        for (kind, payload) in sidx::boxes(payload) {
+            let mut payload = payload.to_vec();
+            if kind == b"mdia" && delay.opus {
+                let stbl = sidx::find_range(&payload, &["minf", "stbl"])?;
+                fill_pre_skip(&mut payload[stbl], delay.delay as u16);
+            }
+            trak.extend(mp4_box(kind, &payload));
+            // The edit list goes right after the track header
+            if kind == b"tkhd" {
+                trak.extend(edit_list(duration, media_time));
+            }
+        }
+        rebuilt_moov.extend(mp4_box(b"trak", &trak));
+        signalled = true;
+    }
+    if !signalled {
+        return None;
+    }
+    let mut rebuilt = Vec::with_capacity(init.len() + 44);
+    for (kind, payload) in sidx::boxes(init) {
+        match kind {
+            b"moov" => rebuilt.extend(mp4_box(kind, &rebuilt_moov)),
+            _ => rebuilt.extend(mp4_box(kind, payload)),
+        }
+    }
+    Some(rebuilt)
+}
+
+/// Length of the representation whose segments in `dir` start with
+/// `prefix`, in its track's timescale.
+fn track_duration(dir: &Path, prefix: &str, init: &[u8]) -> Result<Option<u64>> {
+    let Some((_, default_duration)) = sidx::track_defaults(init) else {
+        return Ok(None);
+    };
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !name.starts_with(prefix) || !name.ends_with(".m4s") {
+            continue;
+        }
+        let segment = std::fs::read(&path).context(format!("Failed to read {}", name))?;
+        match sidx::segment_timing(&segment, default_duration) {
+            Some((_, _, duration)) => total += duration,
+            None => return Ok(None),
+        }
+    }
+    Ok((total > 0).then_some(total))
+}
+
+/// Signal the delays seen on each audio pad in the initialization segments
+/// dashsink wrote for it in `dir`.
+pub(crate) fn signal(dir: &Path, delays: &HashMap<String, CodecDelay>) -> Result<()> {
+    for (pad, delay) in delays {
+        if delay.delay == 0 && delay.padding == 0 {
+            continue;
+        }
+        let prefix = format!("{}_", pad);
+        let path = dir.join(format!("{}{}", prefix, sidx::INIT_SUFFIX));
+        let Ok(init) = std::fs::read(&path) else {
+            continue;
+        };
+        let duration = track_duration(dir, &prefix, &init)?;
+        if let Some(signalled) = signal_delay(&init, delay, duration) {
+            std::fs::write(&path, signalled)
+                .context(format!("Failed to write {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn trims_the_priming_samples() {
+        // An Opus track with a dOps that says nothing of its pre-skip
+        let mut opus = vec![0; 6];
+        opus.extend([0, 1]);
+        opus.extend([0; 8]);
+        opus.extend([0, 2, 0, 16, 0, 0, 0, 0]);
+        opus.extend(words(&[48000 << 16]));
+        opus.extend(mp4_box(b"dOps", &[0, 2, 0, 0, 0, 0, 0xbb, 0x80, 0, 0, 0]));
+        let mut stsd = words(&[0, 1]);
+        stsd.extend(mp4_box(b"Opus", &opus));
+        let stbl = mp4_box(b"stbl", &mp4_box(b"stsd", &stsd));
+        let hdlr = mp4_box(b"hdlr", &[words(&[0, 0]), b"soun".to_vec()].concat());
+        let mdhd = mp4_box(b"mdhd", &words(&[0, 0, 0, 48000, 0, 0]));
+        let mdia = mp4_box(b"mdia", &[mdhd, hdlr, mp4_box(b"minf", &stbl)].concat());
+        let tkhd = mp4_box(b"tkhd", &words(&[0, 0, 0, 2]));
+        let trak = mp4_box(b"trak", &[tkhd, mdia].concat());
+        let mvhd = mp4_box(b"mvhd", &words(&[0, 0, 0, 1000, 0]));
+        let init = [
+            mp4_box(b"ftyp", b"iso6"),
+            mp4_box(b"moov", &[mvhd, trak].concat()),
+        ]
+        .concat();
+
+        let delay = CodecDelay {
+            rate: OPUS_RATE,
+            delay: 312,
+            padding: 648,
+            opus: true,
+        };
+        // Ten seconds of audio, as the segments add up to
+        let signalled = signal_delay(&init, &delay, Some(480_960)).unwrap();
+        let elst = sidx::find(&signalled, &["moov", "trak", "edts", "elst"]).unwrap();
+        // 480960 - 312 - 648 samples is ten seconds at the movie's 1000
+        assert_eq!(&elst[8..16], &10_000u64.to_be_bytes());
+        assert_eq!(&elst[16..24], &312u64.to_be_bytes());
+        let trak = sidx::find(&signalled, &["moov", "trak"]).unwrap();
+        let kinds: Vec<_> = sidx::boxes(trak).map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"tkhd", b"edts", b"mdia"]);
+        let stsd = sidx::find(trak, &["mdia", "minf", "stbl", "stsd"]).unwrap();
+        let dops = sidx::find(&stsd[8 + 36..], &["dOps"]).unwrap();
+        assert_eq!(&dops[2..4], &312u16.to_be_bytes());
+
+        // A track with an edit list already is left as it is
+        assert_eq!(signal_delay(&signalled, &delay, Some(480_960)), None);
+    }
+}
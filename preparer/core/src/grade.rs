@@ -0,0 +1,307 @@
+//! Colour fixes for off-coloured sources, like home video captures:
+//! brightness, contrast and saturation adjustments, then a 3D LUT from a
+//! `.cube` file.
+//!
+//! Like the watermark, the grade is applied to the decoded video before the
+//! tee, so every representation of the ladder carries it.
+
+use crate::factory::{ElementFactory, ElementSpec, GstFactory};
+use anyhow::{Context, Result, bail};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Format the LUT is applied in: 8-bit RGB, padded to four bytes a pixel
+const LUT_CAPS: &str = "video/x-raw,format=RGBx";
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Grade {
+    /// From -1 (black) to 1 (white); 0 leaves it alone
+    pub brightness: f64,
+    /// From 0 (flat grey) to 2; 1 leaves it alone
+    pub contrast: f64,
+    /// From 0 (greyscale) to 2; 1 leaves it alone
+    pub saturation: f64,
+    /// `.cube` 3D LUT applied after the adjustments
+    pub lut: Option<PathBuf>,
+}
+
+impl Default for Grade {
+    fn default() -> Self {
+        Self {
+            brightness: 0.0,
+            contrast: 1.0,
+            saturation: 1.0,
+            lut: None,
+        }
+    }
+}
+
+impl Grade {
+    /// Whether brightness, contrast or saturation are adjusted at all.
+    fn adjusts_balance(&self) -> bool {
+        (self.brightness, self.contrast, self.saturation) != (0.0, 1.0, 1.0)
+    }
+
+    /// Whether the grade changes the picture at all.
+    pub fn is_neutral(&self) -> bool {
+        !self.adjusts_balance() && self.lut.is_none()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(-1.0..=1.0).contains(&self.brightness) {
+            bail!("Brightness must be between -1 and 1");
+        }
+        if !(0.0..=2.0).contains(&self.contrast) {
+            bail!("Contrast must be between 0 and 2");
+        }
+        if !(0.0..=2.0).contains(&self.saturation) {
+            bail!("Saturation must be between 0 and 2");
+        }
+        if let Some(path) = &self.lut {
+            Lut::read(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// A 3D colour lookup table, as read from a `.cube` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lut {
+    size: usize,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+    /// Output colours, red changing fastest, then green, then blue
+    table: Vec<[f32; 3]>,
+}
+
+impl Lut {
+    pub fn read(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).context(format!("Invalid LUT {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let triple = |words: &[&str]| -> Result<[f32; 3]> {
+            match words {
+                [r, g, b] => Ok([r.parse()?, g.parse()?, b.parse()?]),
+                _ => bail!("Expected three numbers, not {:?}", words.join(" ")),
+            }
+        };
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
+        for line in text.lines().map(str::trim) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => (),
+                [first, ..] if first.starts_with('#') => (),
+                ["TITLE", ..] => (),
+                ["LUT_1D_SIZE", ..] => bail!("Only 3D LUTs are supported"),
+                ["LUT_3D_SIZE", n] => size = Some(n.parse::<usize>()?),
+                ["DOMAIN_MIN", rest @ ..] => domain_min = triple(rest)?,
+                ["DOMAIN_MAX", rest @ ..] => domain_max = triple(rest)?,
+                _ => table.push(triple(&words)?),
+            }
+        }
+        let Some(size) = size.filter(|&size| size >= 2) else {
+            bail!("No LUT_3D_SIZE of at least 2");
+        };
+        if table.len() != size.pow(3) {
+            bail!(
+                "Expected {} entries for a size of {}, not {}",
+                size.pow(3),
+                size,
+                table.len()
+            );
+        }
+        Ok(Self {
+            size,
+            domain_min,
+            domain_max,
+            table,
+        })
+    }
+
+    /// Look up an 8-bit colour, interpolating between the entries around it.
+    pub fn apply(&self, rgb: [u8; 3]) -> [u8; 3] {
+        let last = (self.size - 1) as f32;
+        // Where the colour falls in the table, and how far past each entry
+        let mut index = [0; 3];
+        let mut fraction = [0.0; 3];
+        for c in 0..3 {
+            let span = self.domain_max[c] - self.domain_min[c];
+            let value = (rgb[c] as f32 / 255.0 - self.domain_min[c]) / span;
+            let position = (value * last).clamp(0.0, last);
+            index[c] = (position as usize).min(self.size - 2);
+            fraction[c] = position - index[c] as f32;
+        }
+        let entry = |r: usize, g: usize, b: usize| {
+            self.table
+                [index[0] + r + (index[1] + g) * self.size + (index[2] + b) * self.size.pow(2)]
+        };
+        let mut out = [0u8; 3];
+        for (c, out) in out.iter_mut().enumerate() {
+            let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+            let along_r = |g, b| lerp(entry(0, g, b)[c], entry(1, g, b)[c], fraction[0]);
+            let along_g = |b| lerp(along_r(0, b), along_r(1, b), fraction[1]);
+            let value = lerp(along_g(0), along_g(1), fraction[2]);
+            *out = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+        out
+    }
+}
+
+/// The elements grading video between the decoder and the tee.
+pub(crate) struct GradeStage<E = gst::Element> {
+    elements: Vec<E>,
+    lut: Option<Arc<Lut>>,
+}
+
+impl<E: Clone> GradeStage<E> {
+    pub(crate) fn new(
+        factory: &mut impl ElementFactory<Element = E>,
+        grade: &Grade,
+    ) -> Result<Self> {
+        let mut elements = vec![factory.make(&ElementSpec::new("videoconvert"))?];
+        if grade.adjusts_balance() {
+            elements.push(
+                factory.make(
+                    &ElementSpec::new("videobalance")
+                        .property("brightness", grade.brightness)
+                        .property("contrast", grade.contrast)
+                        .property("saturation", grade.saturation),
+                )?,
+            );
+            elements.push(factory.make(&ElementSpec::new("videoconvert"))?);
+        }
+        let lut = match &grade.lut {
+            Some(path) => {
+                elements.push(factory.make(&ElementSpec::new("videoconvert"))?);
+                Some(Arc::new(Lut::read(path)?))
+            }
+            None => None,
+        };
+        Ok(Self { elements, lut })
+    }
+
+    /// Link the elements in order, handing the last one RGB to apply the
+    /// LUT to if there is one.
+    fn link_chain(&self, factory: &mut impl ElementFactory<Element = E>) -> Result<()> {
+        for (i, pair) in self.elements.windows(2).enumerate() {
+            let last = i + 2 == self.elements.len();
+            let caps = (last && self.lut.is_some()).then_some(LUT_CAPS);
+            factory.link(&pair[0], &pair[1], caps)?;
+        }
+        Ok(())
+    }
+}
+
+impl GradeStage {
+    /// Add the stage to the pipeline in front of `downstream`, returning the
+    /// pad decoded video should be linked to.
+    pub(crate) fn insert(
+        &self,
+        pipeline: &gst::Pipeline,
+        downstream: &gst::Pad,
+    ) -> Result<gst::Pad> {
+        pipeline.add_many(&self.elements)?;
+        self.link_chain(&mut GstFactory)?;
+        let (first, last) = (&self.elements[0], &self.elements[self.elements.len() - 1]);
+        last.static_pad("src")
+            .context("Failed to get src pad from the grade stage")?
+            .link(downstream)?;
+        if let Some(lut) = &self.lut {
+            let pad = last
+                .static_pad("sink")
+                .context("Failed to get sink pad from the grade stage")?;
+            let lut = lut.clone();
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                let Some(gst::PadProbeData::Buffer(buffer)) = info.data.as_mut() else {
+                    return gst::PadProbeReturn::Ok;
+                };
+                let Ok(mut map) = buffer.make_mut().map_writable() else {
+                    return gst::PadProbeReturn::Ok;
+                };
+                for pixel in map.as_mut_slice().chunks_exact_mut(4) {
+                    let graded = lut.apply([pixel[0], pixel[1], pixel[2]]);
+                    pixel[..3].copy_from_slice(&graded);
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+        first
+            .static_pad("sink")
+            .context("Failed to get sink pad from the grade stage")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factory::PropertyValue;
+    use crate::factory::testing::RecordingFactory;
+
+    #[test]
+    fn grades_before_the_tee() {
+        // Swaps red and blue, and leaves green alone
+        let mut cube = String::from("TITLE \"swap\"\n# comment\nLUT_3D_SIZE 2\n");
+        for b in 0..2 {
+            for g in 0..2 {
+                for r in 0..2 {
+                    cube.push_str(&format!("{} {} {}\n", b, g, r));
+                }
+            }
+        }
+        let lut = Lut::parse(&cube).unwrap();
+        assert_eq!(lut.apply([255, 0, 0]), [0, 0, 255]);
+        assert_eq!(lut.apply([51, 102, 204]), [204, 102, 51]);
+        assert!(Lut::parse("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+        assert!(Lut::parse("LUT_1D_SIZE 2\n0 0 0\n1 1 1\n").is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("movieshare-grade-{}.cube", std::process::id()));
+        std::fs::write(&path, &cube).unwrap();
+        let grade = Grade {
+            saturation: 1.3,
+            lut: Some(path.clone()),
+            ..Grade::default()
+        };
+        grade.validate().unwrap();
+        let mut factory = RecordingFactory::default();
+        let stage = GradeStage::new(&mut factory, &grade).unwrap();
+        stage.link_chain(&mut factory).unwrap();
+        assert_eq!(
+            factory.factories(),
+            [
+                "videoconvert",
+                "videobalance",
+                "videoconvert",
+                "videoconvert"
+            ]
+        );
+        let balance = factory.find("videobalance");
+        assert_eq!(balance.get("saturation"), Some(&PropertyValue::F64(1.3)));
+        assert_eq!(balance.get("brightness"), Some(&PropertyValue::F64(0.0)));
+        // Only the last link is forced to RGB, for the LUT
+        assert_eq!(factory.links[1].2, None);
+        assert_eq!(factory.links[2].2.as_deref(), Some(LUT_CAPS));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(Grade::default().is_neutral());
+        assert!(
+            Grade {
+                contrast: 2.5,
+                ..Grade::default()
+            }
+            .validate()
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Running jobs in worker processes, so a plugin crashing on a malformed
+//! file takes down only its own job rather than the whole queue.
+//!
+//! The host spawns a [`Worker`] per job and writes the [`JobSpec`] as one
+//! line of JSON to its stdin; the worker, which calls [`run_worker`], writes
+//! the job's [`Event`]s back to stdout, one per line. Further lines on
+//! stdin pause, resume or cancel the job, and closing stdin cancels it and
+//! discards its output, so a worker doesn't outlive its host.
+
+use crate::cancel::{CancelPolicy, CancellationToken};
+use crate::events::Event;
+use crate::job::{JobEvent, PrepareJob};
+use crate::preparer::{Outcome, Preparer};
+use crate::spec::JobSpec;
+use anyhow::{Context, Result, anyhow};
+use futures::StreamExt;
+use futures::channel::mpsc;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const PAUSE: &str = "pause";
+const RESUME: &str = "resume";
+const CANCEL_FINALIZE: &str = "cancel finalize";
+const CANCEL_DISCARD: &str = "cancel discard";
+
+/// How often the host passes pause and cancel requests on to the worker.
+const CONTROL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A program that runs one job and exits, by calling [`run_worker`].
+#[derive(Debug, Clone)]
+pub struct Worker {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+}
+
+/// Run `spec` in a new worker process, controlled through `token`.
+pub fn spawn(worker: &Worker, spec: &JobSpec, token: CancellationToken) -> PrepareJob {
+    let (sender, events) = mpsc::unbounded();
+    let worker = worker.clone();
+    let spec = spec.clone();
+    let control = token.clone();
+    thread::spawn(move || {
+        let result = supervise(&worker, &spec, control, &mut |event| {
+            let _ = sender.unbounded_send(event);
+        });
+        let _ = sender.unbounded_send(JobEvent::Finished(result));
+    });
+    PrepareJob::from_events(events, token)
+}
+
+fn supervise(
+    worker: &Worker,
+    spec: &JobSpec,
+    token: CancellationToken,
+    emit: &mut dyn FnMut(JobEvent),
+) -> Result<Outcome> {
+    let mut child = Command::new(&worker.program)
+        .args(&worker.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context(format!(
+            "Failed to start worker {}",
+            worker.program.display()
+        ))?;
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "{}", serde_json::to_string(spec)?)?;
+
+    // Pass pause and cancel requests along until the worker is done
+    let done = Arc::new(AtomicBool::new(false));
+    let control = {
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut paused = false;
+            let mut cancelled = false;
+            while !done.load(Ordering::Relaxed) {
+                let mut command = None;
+                if !cancelled && let Some(policy) = token.policy() {
+                    cancelled = true;
+                    command = Some(match policy {
+                        CancelPolicy::Finalize => CANCEL_FINALIZE,
+                        CancelPolicy::Discard => CANCEL_DISCARD,
+                    });
+                } else if token.is_paused() != paused {
+                    paused = !paused;
+                    command = Some(match paused {
+                        true => PAUSE,
+                        false => RESUME,
+                    });
+                }
+                if let Some(command) = command
+                    && writeln!(stdin, "{}", command).is_err()
+                {
+                    return;
+                }
+                thread::sleep(CONTROL_INTERVAL);
+            }
+        })
+    };
+
+    let mut finished = None;
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let event: Event = match serde_json::from_str(&line?) {
+            Ok(event) => event,
+            Err(err) => {
+                emit(JobEvent::Warning(format!(
+                    "Unreadable event from worker: {}",
+                    err
+                )));
+                continue;
+            }
+        };
+        match event.into_job_event() {
+            Some(JobEvent::Finished(result)) => finished = Some(result),
+            Some(event) => emit(event),
+            None => (),
+        }
+    }
+    let status = child.wait()?;
+    done.store(true, Ordering::Relaxed);
+    let _ = control.join();
+    finished.unwrap_or_else(|| Err(anyhow!("Worker stopped without finishing: {}", status)))
+}
+
+/// Run the job a host sends on `input`, reporting to `output`; see the
+/// module documentation for the protocol.
+pub fn run_worker(mut input: impl BufRead + Send + 'static, mut output: impl Write) -> Result<()> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let spec: JobSpec = serde_json::from_str(&line).context("Invalid job spec from host")?;
+
+    let token = CancellationToken::new();
+    let control = token.clone();
+    thread::spawn(move || {
+        for line in input.lines() {
+            match line.as_deref().map(str::trim) {
+                Ok(PAUSE) => control.set_paused(true),
+                Ok(RESUME) => control.set_paused(false),
+                Ok(CANCEL_FINALIZE) => control.cancel(CancelPolicy::Finalize),
+                Ok(CANCEL_DISCARD) => control.cancel(CancelPolicy::Discard),
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+        // The host is gone
+        control.cancel(CancelPolicy::Discard);
+    });
+
+    let mut job = PrepareJob::spawn(Preparer::from_spec(&spec).cancellation_token(token));
+    futures::executor::block_on(async {
+        while let Some(event) = job.next().await {
+            writeln!(output, "{}", Event::from_job_event(&event).to_json()?)?;
+            output.flush()?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(script: &str) -> (Vec<JobEvent>, Result<Outcome>) {
+        let worker = Worker {
+            program: PathBuf::from("sh"),
+            args: vec![String::from("-c"), String::from(script)],
+        };
+        let spec = JobSpec::new("movie.mkv", "out");
+        let mut events = Vec::new();
+        let result = supervise(&worker, &spec, CancellationToken::new(), &mut |event| {
+            events.push(event)
+        });
+        (events, result)
+    }
+
+    #[test]
+    fn follows_worker_events_and_crashes() {
+        let (events, result) = run(r#"read spec
+case "$spec" in *movie.mkv*) ;; *) exit 1 ;; esac
+echo '{"schema":1,"type":"progress","fraction":0.5,"frames":10,"fps":5.0}'
+echo '{"schema":1,"type":"finished","outcome":"already_prepared"}'"#);
+        assert!(matches!(events[..], [JobEvent::Progress(_)]));
+        assert!(matches!(result, Ok(Outcome::AlreadyPrepared)));
+
+        let (_, result) = run("read spec; kill -SEGV $$");
+        let error = result.unwrap_err().to_string();
+        assert!(
+            error.starts_with("Worker stopped without finishing"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Running a preparation on its own thread, with its progress, warnings
+//! and outcome as a stream of events.
+
+use crate::bottleneck::BottleneckReport;
+use crate::cancel::{CancelPolicy, CancellationToken};
+use crate::preparer::{Outcome, Preparer, Progress};
+use crate::qc::QcReport;
+use anyhow::Result;
+use futures::Stream;
+use futures::channel::mpsc;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+
+/// What one representation of the ladder produced.
+#[derive(Debug, Clone)]
+pub struct BranchStats {
+    pub target_bitrate_mbps: u32,
+    pub frames: u64,
+    pub bytes: u64,
+    /// `None` if the input duration couldn't be determined
+    pub average_bitrate_kbps: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum JobEvent {
+    Progress(Progress),
+    /// A non-fatal problem reported by the pipeline
+    Warning(String),
+    /// Sent once per representation after the pipeline reaches EOS
+    BranchStats(BranchStats),
+    /// Which stage held the encode back, when asked for; sent after EOS
+    Bottleneck(BottleneckReport),
+    /// Long stretches of black or frozen video, when asked for; sent after EOS
+    Qc(QcReport),
+    /// Always the last event
+    Finished(Result<Outcome>),
+}
+
+/// Handle to a preparation running on its own thread.
+///
+/// The job is a [`Stream`] of [`JobEvent`]s ending with
+/// [`JobEvent::Finished`]. Dropping the handle does not stop the job; call
+/// [`abort`](Self::abort) or the job's [`CancellationToken`] for that.
+pub struct PrepareJob {
+    events: mpsc::UnboundedReceiver<JobEvent>,
+    token: CancellationToken,
+}
+
+impl PrepareJob {
+    pub fn spawn(preparer: Preparer) -> Self {
+        let (sender, events) = mpsc::unbounded();
+        let token = preparer.token().clone();
+
+        thread::spawn(move || {
+            let result = preparer.execute(&mut |event| {
+                let _ = sender.unbounded_send(event);
+            });
+            let _ = sender.unbounded_send(JobEvent::Finished(result));
+        });
+
+        Self { events, token }
+    }
+
+    /// A job whose events come from elsewhere, like a worker process.
+    pub(crate) fn from_events(
+        events: mpsc::UnboundedReceiver<JobEvent>,
+        token: CancellationToken,
+    ) -> Self {
+        Self { events, token }
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Stop the job and delete its output.
+    pub fn abort(&self) {
+        self.token.cancel(CancelPolicy::Discard);
+    }
+}
+
+impl Stream for PrepareJob {
+    type Item = JobEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<JobEvent>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
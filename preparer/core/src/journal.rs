@@ -0,0 +1,122 @@
+//! The append-only journal of a run in its output directory: when it
+//! started, the branches it configured, each segment written, and how it
+//! ended. Each entry is synced to disk as it's written, so the journal
+//! survives a crash or power loss, for `--resume` and for working out what
+//! happened after.
+
+use crate::cancel::CancelPolicy;
+use crate::retention::SourcePolicy;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const JOURNAL_FILENAME: &str = "journal.jsonl";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JournalEvent {
+    Started {
+        input: String,
+    },
+    BranchConfigured {
+        name: String,
+    },
+    SegmentWritten {
+        location: String,
+    },
+    /// Writing the segment at `location` failed `failed` times before it
+    /// went through
+    SegmentRetried {
+        location: String,
+        failed: u32,
+    },
+    Finalized,
+    Cancelled {
+        policy: CancelPolicy,
+    },
+    Failed {
+        error: String,
+    },
+    /// The source policy was applied once the title checked out, leaving
+    /// the source at `location`, or nowhere when it was deleted
+    SourceHandled {
+        policy: SourcePolicy,
+        location: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    /// Seconds since the UNIX epoch
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: JournalEvent,
+}
+
+/// Append-only record of job state transitions, kept in the output directory.
+///
+/// Every entry is synced to disk before `record` returns, so after a crash
+/// or power loss the journal reflects everything the job had finished.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub fn open(output_dir: &Path) -> Result<Self> {
+        let path = output_dir.join(JOURNAL_FILENAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(format!("Failed to open journal: {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, event: JournalEvent) -> Result<()> {
+        let entry = JournalEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            event,
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Read back all entries. A torn final line from a crash mid-write is ignored.
+    pub fn read(output_dir: &Path) -> Result<Vec<JournalEntry>> {
+        let path = output_dir.join(JOURNAL_FILENAME);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err).context(format!("Failed to read journal: {}", path.display()));
+            }
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => break,
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Entries belonging to the most recent run.
+pub fn last_run(entries: &[JournalEntry]) -> &[JournalEntry] {
+    let start = entries
+        .iter()
+        .rposition(|entry| matches!(entry.event, JournalEvent::Started { .. }))
+        .unwrap_or(entries.len());
+    &entries[start..]
+}
@@ -0,0 +1,227 @@
+//! Language tags, normalized to BCP-47.
+//!
+//! Sources label their tracks every which way: ISO 639-2 codes in
+//! Matroska (`eng`, with `fre` and `fra` both meaning French), ISO 639-1
+//! from other muxers (`en`), English names typed into tagging tools
+//! (`English`), and `und` when nobody knew. DASH signals languages as
+//! BCP-47, so everything is mapped to the shortest form of that, like `en`
+//! or `pt-BR`.
+
+use anyhow::{Context, Result, bail};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer, XmlVersion};
+
+/// BCP-47 tag, ISO 639-2/T, ISO 639-2/B and English name of the languages
+/// we recognize.
+const LANGUAGES: &[(&str, &str, &str, &str)] = &[
+    ("ar", "ara", "ara", "Arabic"),
+    ("bg", "bul", "bul", "Bulgarian"),
+    ("bn", "ben", "ben", "Bengali"),
+    ("ca", "cat", "cat", "Catalan"),
+    ("cs", "ces", "cze", "Czech"),
+    ("cy", "cym", "wel", "Welsh"),
+    ("da", "dan", "dan", "Danish"),
+    ("de", "deu", "ger", "German"),
+    ("el", "ell", "gre", "Greek"),
+    ("en", "eng", "eng", "English"),
+    ("es", "spa", "spa", "Spanish"),
+    ("et", "est", "est", "Estonian"),
+    ("eu", "eus", "baq", "Basque"),
+    ("fa", "fas", "per", "Persian"),
+    ("fi", "fin", "fin", "Finnish"),
+    ("fr", "fra", "fre", "French"),
+    ("ga", "gle", "gle", "Irish"),
+    ("gl", "glg", "glg", "Galician"),
+    ("he", "heb", "heb", "Hebrew"),
+    ("hi", "hin", "hin", "Hindi"),
+    ("hr", "hrv", "hrv", "Croatian"),
+    ("hu", "hun", "hun", "Hungarian"),
+    ("id", "ind", "ind", "Indonesian"),
+    ("is", "isl", "ice", "Icelandic"),
+    ("it", "ita", "ita", "Italian"),
+    ("ja", "jpn", "jpn", "Japanese"),
+    ("ko", "kor", "kor", "Korean"),
+    ("lt", "lit", "lit", "Lithuanian"),
+    ("lv", "lav", "lav", "Latvian"),
+    ("ms", "msa", "may", "Malay"),
+    ("nb", "nob", "nob", "Norwegian Bokmål"),
+    ("nl", "nld", "dut", "Dutch"),
+    ("nn", "nno", "nno", "Norwegian Nynorsk"),
+    ("no", "nor", "nor", "Norwegian"),
+    ("pl", "pol", "pol", "Polish"),
+    ("pt", "por", "por", "Portuguese"),
+    ("ro", "ron", "rum", "Romanian"),
+    ("ru", "rus", "rus", "Russian"),
+    ("sk", "slk", "slo", "Slovak"),
+    ("sl", "slv", "slv", "Slovenian"),
+    ("sr", "srp", "srp", "Serbian"),
+    ("sv", "swe", "swe", "Swedish"),
+    ("ta", "tam", "tam", "Tamil"),
+    ("th", "tha", "tha", "Thai"),
+    ("tl", "tgl", "tgl", "Tagalog"),
+    ("tr", "tur", "tur", "Turkish"),
+    ("uk", "ukr", "ukr", "Ukrainian"),
+    ("ur", "urd", "urd", "Urdu"),
+    ("vi", "vie", "vie", "Vietnamese"),
+    ("zh", "zho", "chi", "Chinese"),
+];
+
+/// Codes meaning the language isn't known, rather than a language.
+const UNDETERMINED: &[&str] = &[
+    "und",
+    "mis",
+    "mul",
+    "zxx",
+    "unknown",
+    "undetermined",
+    "none",
+];
+
+/// The primary language subtag for a code or name, in any case.
+fn primary(language: &str) -> Option<&'static str> {
+    let lower = language.to_ascii_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|(bcp47, terminology, bibliographic, name)| {
+            lower == *bcp47
+                || lower == *terminology
+                || lower == *bibliographic
+                || language.eq_ignore_ascii_case(name)
+        })
+        .map(|(bcp47, ..)| *bcp47)
+}
+
+/// Normalize a language tag or name to BCP-47, or `None` if it's empty,
+/// undetermined or not one we recognize.
+///
+/// Region and script subtags are kept and cased as BCP-47 recommends, so
+/// `pt_br` becomes `pt-BR` and `zh-hant` becomes `zh-Hant`.
+pub fn normalize(tag: &str) -> Option<String> {
+    let tag = tag.trim();
+    if tag.is_empty()
+        || UNDETERMINED
+            .iter()
+            .any(|undetermined| tag.eq_ignore_ascii_case(undetermined))
+    {
+        return None;
+    }
+    if let Some(bcp47) = primary(tag) {
+        return Some(String::from(bcp47));
+    }
+
+    let mut subtags = tag.split(['-', '_']);
+    let mut normalized = String::from(primary(subtags.next()?)?);
+    for subtag in subtags {
+        let cased = match subtag.len() {
+            // Script, like Hant
+            4 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                let (first, rest) = subtag.split_at(1);
+                format!(
+                    "{}{}",
+                    first.to_ascii_uppercase(),
+                    rest.to_ascii_lowercase()
+                )
+            }
+            // Region, like BR or 419
+            2 | 3 if subtag.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                subtag.to_ascii_uppercase()
+            }
+            _ => return None,
+        };
+        normalized.push('-');
+        normalized.push_str(&cased);
+    }
+    Some(normalized)
+}
+
+/// Normalize a language given by the user, failing if it isn't recognized.
+pub fn parse(tag: &str) -> Result<String> {
+    match normalize(tag) {
+        Some(language) => Ok(language),
+        None => bail!(
+            "Unrecognized language {:?}; expected a code like en or eng",
+            tag
+        ),
+    }
+}
+
+/// Set `lang` on every adaptation set of `content_type` in a manifest.
+pub fn tag_manifest(xml: &str, content_type: &str, language: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let is_match = |element: &BytesStart| -> Result<bool> {
+        if element.local_name().into_inner() != "AdaptationSet" {
+            return Ok(false);
+        }
+        for attribute in element.attributes() {
+            let attribute = attribute?;
+            let value = attribute.normalized_value(XmlVersion::Implicit1_0)?;
+            match attribute.key.local_name().into_inner() {
+                "contentType" if value == content_type => return Ok(true),
+                "mimeType" if value.split('/').next() == Some(content_type) => return Ok(true),
+                _ => (),
+            }
+        }
+        Ok(false)
+    };
+    let tagged = |element: &BytesStart| -> Result<BytesStart<'static>> {
+        let mut set = BytesStart::new(element.name().into_inner().to_string());
+        for attribute in element.attributes() {
+            let attribute = attribute?;
+            if attribute.key.local_name().into_inner() != "lang" {
+                set.push_attribute(attribute);
+            }
+        }
+        set.push_attribute(("lang", language));
+        Ok(set)
+    };
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            Event::Start(element) if is_match(&element)? => {
+                writer.write_event(Event::Start(tagged(&element)?))?
+            }
+            Event::Empty(element) if is_match(&element)? => {
+                writer.write_event(Event::Empty(tagged(&element)?))?
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_codes_and_names() {
+        for tag in ["eng", "EN", "English", " en "] {
+            assert_eq!(normalize(tag).as_deref(), Some("en"), "{:?}", tag);
+        }
+        assert_eq!(normalize("fre").as_deref(), Some("fr"));
+        assert_eq!(normalize("fra").as_deref(), Some("fr"));
+        assert_eq!(normalize("ger").as_deref(), Some("de"));
+        assert_eq!(normalize("pt_br").as_deref(), Some("pt-BR"));
+        assert_eq!(normalize("spa-419").as_deref(), Some("es-419"));
+        assert_eq!(normalize("zh-hant-tw").as_deref(), Some("zh-Hant-TW"));
+        assert_eq!(normalize("und"), None);
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("klingon"), None);
+        assert!(parse("xx").is_err());
+    }
+
+    #[test]
+    fn tags_adaptation_sets_in_the_manifest() {
+        let xml = tag_manifest(
+            r#"<MPD><Period><AdaptationSet mimeType="video/mp4"/><AdaptationSet mimeType="audio/mp4" lang="eng"><Representation id="1"/></AdaptationSet></Period></MPD>"#,
+            "audio",
+            "en",
+        )
+        .unwrap();
+        assert_eq!(
+            xml,
+            r#"<MPD><Period><AdaptationSet mimeType="video/mp4"/><AdaptationSet mimeType="audio/mp4" lang="en"><Representation id="1"/></AdaptationSet></Period></MPD>"#
+        );
+    }
+}
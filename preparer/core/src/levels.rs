@@ -0,0 +1,372 @@
+//! AV1 decoder levels, so a ladder doesn't produce representations the
+//! household's hardware decoders can't play.
+//!
+//! A level caps the picture size, the rate pictures are shown at and the
+//! bitrate; the high tier of a level allows more bitrate than the main one.
+//! Limits are from Annex A of the AV1 specification.
+//!
+//! The level and tier each representation needs are also what its `codecs`
+//! string in the manifest tells players, so they can pick what they decode.
+
+use anyhow::{Context, Result, bail};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer, XmlVersion};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// What to do with a rung that exceeds the decoder level.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LevelPolicy {
+    /// Bring the rung's bitrate and picture size down to fit
+    #[default]
+    Adjust,
+    /// Encode it as asked, with a warning
+    Warn,
+}
+
+/// Limits of one AV1 level.
+struct Limits {
+    major: u32,
+    minor: u32,
+    max_picture_size: u64,
+    max_width: u32,
+    max_height: u32,
+    max_display_rate: u64,
+    /// Main and high tier bitrate, in Mb/s; levels below 4.0 have no high tier
+    main_mbps: f64,
+    high_mbps: Option<f64>,
+}
+
+#[rustfmt::skip]
+const LEVELS: &[Limits] = &[
+    Limits { major: 2, minor: 0, max_picture_size: 147_456, max_width: 2048, max_height: 1152, max_display_rate: 4_423_680, main_mbps: 1.5, high_mbps: None },
+    Limits { major: 2, minor: 1, max_picture_size: 278_784, max_width: 2816, max_height: 1584, max_display_rate: 8_363_520, main_mbps: 3.0, high_mbps: None },
+    Limits { major: 3, minor: 0, max_picture_size: 665_856, max_width: 4352, max_height: 2448, max_display_rate: 19_975_680, main_mbps: 6.0, high_mbps: None },
+    Limits { major: 3, minor: 1, max_picture_size: 1_065_024, max_width: 5504, max_height: 3096, max_display_rate: 31_950_720, main_mbps: 10.0, high_mbps: None },
+    Limits { major: 4, minor: 0, max_picture_size: 2_359_296, max_width: 6144, max_height: 3456, max_display_rate: 70_778_880, main_mbps: 12.0, high_mbps: Some(30.0) },
+    Limits { major: 4, minor: 1, max_picture_size: 2_359_296, max_width: 6144, max_height: 3456, max_display_rate: 141_557_760, main_mbps: 20.0, high_mbps: Some(50.0) },
+    Limits { major: 5, minor: 0, max_picture_size: 8_912_896, max_width: 8192, max_height: 4352, max_display_rate: 267_386_880, main_mbps: 30.0, high_mbps: Some(100.0) },
+    Limits { major: 5, minor: 1, max_picture_size: 8_912_896, max_width: 8192, max_height: 4352, max_display_rate: 534_773_760, main_mbps: 40.0, high_mbps: Some(160.0) },
+    Limits { major: 5, minor: 2, max_picture_size: 8_912_896, max_width: 8192, max_height: 4352, max_display_rate: 1_069_547_520, main_mbps: 60.0, high_mbps: Some(240.0) },
+    Limits { major: 5, minor: 3, max_picture_size: 8_912_896, max_width: 8192, max_height: 4352, max_display_rate: 1_069_547_520, main_mbps: 60.0, high_mbps: Some(240.0) },
+    Limits { major: 6, minor: 0, max_picture_size: 35_651_584, max_width: 16384, max_height: 8704, max_display_rate: 1_069_547_520, main_mbps: 60.0, high_mbps: Some(240.0) },
+    Limits { major: 6, minor: 1, max_picture_size: 35_651_584, max_width: 16384, max_height: 8704, max_display_rate: 2_139_095_040, main_mbps: 100.0, high_mbps: Some(480.0) },
+    Limits { major: 6, minor: 2, max_picture_size: 35_651_584, max_width: 16384, max_height: 8704, max_display_rate: 4_278_190_080, main_mbps: 160.0, high_mbps: Some(800.0) },
+    Limits { major: 6, minor: 3, max_picture_size: 35_651_584, max_width: 16384, max_height: 8704, max_display_rate: 4_278_190_080, main_mbps: 160.0, high_mbps: Some(800.0) },
+];
+
+/// Picture sizes rungs are brought down through, largest first.
+const SIZES: &[(u32, u32)] = &[
+    (1920, 1080),
+    (1280, 720),
+    (960, 540),
+    (854, 480),
+    (640, 360),
+    (426, 240),
+];
+
+/// An AV1 level and tier, like `4.0` or `5.1-high`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecoderLevel {
+    pub major: u32,
+    pub minor: u32,
+    pub high_tier: bool,
+}
+
+/// Parse a level like `4.0`, `4.1-main` or `5.1-high`.
+pub fn parse(level: &str) -> Result<DecoderLevel> {
+    let (number, high_tier) = match level.split_once('-') {
+        Some((number, "main")) => (number, false),
+        Some((number, "high")) => (number, true),
+        Some((_, tier)) => bail!("Unknown AV1 tier {:?}; expected main or high", tier),
+        None => (level, false),
+    };
+    let (major, minor) = number
+        .split_once('.')
+        .context(format!("Expected an AV1 level like 4.0, got {:?}", level))?;
+    let parsed = DecoderLevel {
+        major: major
+            .parse()
+            .context(format!("Invalid AV1 level {:?}", level))?,
+        minor: minor
+            .parse()
+            .context(format!("Invalid AV1 level {:?}", level))?,
+        high_tier,
+    };
+    let limits = parsed
+        .limits()
+        .context(format!("No such AV1 level: {}", level))?;
+    if high_tier && limits.high_mbps.is_none() {
+        bail!("AV1 level {} has no high tier", number);
+    }
+    Ok(parsed)
+}
+
+impl DecoderLevel {
+    fn limits(&self) -> Option<&'static Limits> {
+        LEVELS
+            .iter()
+            .find(|limits| limits.major == self.major && limits.minor == self.minor)
+    }
+
+    /// Highest bitrate the level and tier allow, in Mb/s.
+    pub fn max_mbps(&self) -> f64 {
+        let limits = self.limits().unwrap();
+        match self.high_tier {
+            true => limits.high_mbps.unwrap_or(limits.main_mbps),
+            false => limits.main_mbps,
+        }
+    }
+
+    /// `seq_level_idx`, as the bitstream and `codecs` strings number levels.
+    pub fn index(&self) -> u32 {
+        (self.major - 2) * 4 + self.minor
+    }
+
+    /// Whether a `width`x`height` picture at `fps` fits.
+    fn fits(&self, width: u32, height: u32, fps: u32) -> bool {
+        let limits = self.limits().unwrap();
+        let area = width as u64 * height as u64;
+        width <= limits.max_width
+            && height <= limits.max_height
+            && area <= limits.max_picture_size
+            && area * fps as u64 <= limits.max_display_rate
+    }
+}
+
+impl std::fmt::Display for DecoderLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.high_tier {
+            write!(f, "-high")?;
+        }
+        Ok(())
+    }
+}
+
+/// The lowest level and tier a `width`x`height` picture at `fps` and
+/// `bitrate_mbps` Mb/s fits, main tier first.
+pub fn lowest_fitting(
+    bitrate_mbps: f64,
+    width: u32,
+    height: u32,
+    fps: u32,
+) -> Option<DecoderLevel> {
+    LEVELS.iter().find_map(|limits| {
+        let level = DecoderLevel {
+            major: limits.major,
+            minor: limits.minor,
+            high_tier: false,
+        };
+        if !level.fits(width, height, fps) {
+            return None;
+        }
+        match limits.high_mbps {
+            _ if bitrate_mbps <= limits.main_mbps => Some(level),
+            Some(high_mbps) if bitrate_mbps <= high_mbps => Some(DecoderLevel {
+                high_tier: true,
+                ..level
+            }),
+            _ => None,
+        }
+    })
+}
+
+/// `codecs` with its level and tier replaced by `level`'s, keeping the
+/// profile and bit depth, like `av01.0.08M.08`.
+fn with_level(codecs: &str, level: DecoderLevel) -> String {
+    let mut fields: Vec<&str> = codecs.split('.').collect();
+    fields.resize(4, "");
+    let profile = match fields[1] {
+        "" => "0",
+        profile => profile,
+    };
+    let depth = match fields[3] {
+        "" => "08",
+        depth => depth,
+    };
+    let tier = match level.high_tier {
+        true => 'H',
+        false => 'M',
+    };
+    format!("av01.{}.{:02}{}.{}", profile, level.index(), tier, depth)
+}
+
+/// Signal each AV1 representation's level and tier in its `codecs` string:
+/// `level` if given, otherwise the lowest one its size and bandwidth fit.
+pub fn tag_manifest(xml: &str, level: Option<DecoderLevel>, fps: u32) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let tagged = |element: &BytesStart| -> Result<Option<BytesStart<'static>>> {
+        if element.local_name().into_inner() != "Representation" {
+            return Ok(None);
+        }
+        let mut codecs = None;
+        let (mut bandwidth, mut width, mut height) = (0, 1920, 1080);
+        for attribute in element.attributes() {
+            let attribute = attribute?;
+            let value = attribute.normalized_value(XmlVersion::Implicit1_0)?;
+            match attribute.key.local_name().into_inner() {
+                "codecs" if value.starts_with("av01") => codecs = Some(value.into_owned()),
+                "bandwidth" => bandwidth = value.parse().unwrap_or(0),
+                "width" => width = value.parse().unwrap_or(width),
+                "height" => height = value.parse().unwrap_or(height),
+                _ => (),
+            }
+        }
+        let Some(codecs) = codecs else {
+            return Ok(None);
+        };
+        let Some(level) =
+            level.or_else(|| lowest_fitting(bandwidth as f64 / 1_000_000.0, width, height, fps))
+        else {
+            return Ok(None);
+        };
+        let mut representation = BytesStart::new(element.name().into_inner().to_string());
+        for attribute in element.attributes() {
+            let attribute = attribute?;
+            if attribute.key.local_name().into_inner() != "codecs" {
+                representation.push_attribute(attribute);
+            }
+        }
+        representation.push_attribute(("codecs", with_level(&codecs, level).as_str()));
+        Ok(Some(representation))
+    };
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            Event::Start(element) => match tagged(&element)? {
+                Some(tagged) => writer.write_event(Event::Start(tagged))?,
+                None => writer.write_event(Event::Start(element))?,
+            },
+            Event::Empty(element) => match tagged(&element)? {
+                Some(tagged) => writer.write_event(Event::Empty(tagged))?,
+                None => writer.write_event(Event::Empty(element))?,
+            },
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// How a rung is encoded to stay within a level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fit {
+    pub bitrate_mbps: u32,
+    /// Largest picture, or `None` to leave the usual limit
+    pub max_size: Option<(u32, u32)>,
+    /// What didn't fit, for a warning
+    pub problem: Option<String>,
+}
+
+/// Fit a rung of `bitrate_mbps`, at most 1080p at `fps`, to `level`.
+pub fn fit(bitrate_mbps: u32, fps: u32, level: DecoderLevel, policy: LevelPolicy) -> Fit {
+    let mut problems = Vec::new();
+    let mut fitted = Fit {
+        bitrate_mbps,
+        max_size: None,
+        problem: None,
+    };
+
+    let max_mbps = level.max_mbps();
+    if bitrate_mbps as f64 > max_mbps {
+        problems.push(format!(
+            "{} MB/s is over its {} Mb/s",
+            bitrate_mbps, max_mbps
+        ));
+        if policy == LevelPolicy::Adjust {
+            fitted.bitrate_mbps = (max_mbps.floor() as u32).max(1);
+        }
+    }
+
+    let (width, height) = SIZES[0];
+    if !level.fits(width, height, fps) {
+        let size = SIZES
+            .iter()
+            .copied()
+            .find(|&(width, height)| level.fits(width, height, fps))
+            .unwrap_or(*SIZES.last().unwrap());
+        problems.push(format!("{}x{} at {} fps is too big", width, height, fps));
+        if policy == LevelPolicy::Adjust {
+            fitted.max_size = Some(size);
+        }
+    }
+
+    if !problems.is_empty() {
+        let outcome = match policy {
+            LevelPolicy::Adjust => format!(
+                "encoding at {} MB/s{}",
+                fitted.bitrate_mbps,
+                fitted
+                    .max_size
+                    .map_or(String::new(), |(w, h)| format!(" and up to {}x{}", w, h))
+            ),
+            LevelPolicy::Warn => String::from("encoding it anyway"),
+        };
+        fitted.problem = Some(format!(
+            "The {} MB/s rung doesn't fit AV1 level {}: {}; {}",
+            bitrate_mbps,
+            level,
+            problems.join(", "),
+            outcome
+        ));
+    }
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_rungs_to_levels() {
+        let level = parse("3.1").unwrap();
+        assert_eq!(level.max_mbps(), 10.0);
+        assert_eq!(parse("5.1-high").unwrap().max_mbps(), 160.0);
+        assert!(parse("3.1-high").is_err());
+        assert!(parse("4.4").is_err());
+        assert!(parse("four").is_err());
+
+        // 1080p30 is too big for 3.1, and 12 MB/s too much
+        let fitted = fit(12, 30, level, LevelPolicy::Adjust);
+        assert_eq!(fitted.bitrate_mbps, 10);
+        assert_eq!(fitted.max_size, Some((1280, 720)));
+        assert!(fitted.problem.is_some());
+
+        let warned = fit(12, 30, level, LevelPolicy::Warn);
+        assert_eq!((warned.bitrate_mbps, warned.max_size), (12, None));
+        assert!(warned.problem.is_some());
+
+        assert_eq!(
+            lowest_fitting(6.0, 1920, 1080, 30),
+            Some(parse("4.0").unwrap())
+        );
+        assert_eq!(
+            lowest_fitting(25.0, 1920, 1080, 30),
+            Some(parse("4.0-high").unwrap())
+        );
+
+        let xml = r#"<MPD><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="6000000" width="1920" height="1080" codecs="av01.0.05M.10"/><Representation id="1" bandwidth="1000000" width="640" height="360" codecs="av01"/></AdaptationSet><AdaptationSet contentType="audio"><Representation id="2" codecs="opus"/></AdaptationSet></Period></MPD>"#;
+        let tagged = tag_manifest(xml, None, 30).unwrap();
+        assert!(tagged.contains(r#"codecs="av01.0.08M.10""#), "{}", tagged);
+        assert!(tagged.contains(r#"codecs="av01.0.01M.08""#), "{}", tagged);
+        assert!(tagged.contains(r#"codecs="opus""#));
+        let explicit = tag_manifest(xml, Some(parse("5.1-high").unwrap()), 30).unwrap();
+        assert!(
+            explicit.contains(r#"codecs="av01.0.13H.10""#),
+            "{}",
+            explicit
+        );
+
+        let fine = fit(6, 30, parse("4.0").unwrap(), LevelPolicy::Adjust);
+        assert_eq!(
+            fine,
+            Fit {
+                bitrate_mbps: 6,
+                max_size: None,
+                problem: None
+            }
+        );
+    }
+}
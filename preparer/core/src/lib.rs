@@ -0,0 +1,63 @@
+//! Preparation engine for movieshare: transcodes a media file into a DASH
+//! presentation ready for streaming.
+//!
+//! ```no_run
+//! use movieshare_core::Preparer;
+//!
+//! Preparer::new("movie.mkv")
+//!     .ladder([6, 2])
+//!     .output("./output")
+//!     .run()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub use gstreamer as gst;
+
+pub mod analysis;
+mod avsync;
+pub mod bottleneck;
+mod branch;
+pub mod burnin;
+mod cancel;
+mod channels;
+pub mod conform;
+pub mod cover;
+pub mod cuts;
+pub mod damage;
+pub mod decoder;
+pub mod encoder;
+pub mod events;
+pub mod factory;
+pub mod fonts;
+mod gapless;
+pub mod grade;
+pub mod isolate;
+mod job;
+pub mod journal;
+pub mod language;
+pub mod levels;
+mod lock;
+mod loudness;
+mod packaging;
+mod pads;
+pub mod plan;
+mod preparer;
+mod preroll;
+pub mod qc;
+pub mod queue;
+mod reproducible;
+pub mod retention;
+mod roles;
+mod sidx;
+pub mod spec;
+mod staging;
+pub mod telecine;
+pub mod track;
+pub mod watermark;
+pub mod window;
+
+pub use branch::{MediaType, PipelineBranch};
+pub use cancel::{CancelPolicy, CancellationToken};
+pub use job::{BranchStats, JobEvent, PrepareJob};
+pub use preparer::{MissingAudio, Outcome, Preparer, Progress, Summary};
+pub use spec::{AudioSpec, AudioType, EncodingProfile, JobSpec, PackagingSpec, SubtitleSpec};
@@ -0,0 +1,59 @@
+//! Locking an output directory, so two runs writing into it at once, like
+//! overlapping cron jobs, can't interleave segments into one manifest.
+
+use anyhow::{Context, Result, bail};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const LOCK_FILENAME: &str = ".movieshare.lock";
+
+/// Exclusive advisory lock on an output directory, held until dropped.
+///
+/// The lock file records the owning PID and start time so a blocked
+/// invocation can say who it is waiting on. The file itself is left behind
+/// on exit; only the OS-level lock matters.
+pub struct OutputLock {
+    _file: File,
+}
+
+impl OutputLock {
+    pub fn acquire(output_dir: &Path) -> Result<Self> {
+        let path = output_dir.join(LOCK_FILENAME);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(format!("Failed to open lock file: {}", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut owner = String::new();
+                let _ = file.read_to_string(&mut owner);
+                bail!(
+                    "Output directory {} is in use by another preparer ({})",
+                    output_dir.display(),
+                    owner.trim()
+                );
+            }
+            Err(TryLockError::Error(err)) => {
+                return Err(err).context(format!("Failed to lock {}", path.display()));
+            }
+        }
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "pid={} started={}", std::process::id(), started)?;
+        file.flush()?;
+
+        Ok(Self { _file: file })
+    }
+}
@@ -0,0 +1,315 @@
+//! Measuring the loudness of each audio representation as it's encoded, so
+//! players with loudness management can normalize titles against each
+//! other without measuring them again.
+//!
+//! The audio going into each encoder is metered to EBU R 128, for its
+//! integrated loudness and true peak. Afterwards both are added to the
+//! representation in the manifest, and written into its initialization
+//! segment as the track's `ludt` box, where MP4 players look for them.
+
+use crate::roles;
+use crate::sidx::{self, mp4_box};
+use anyhow::{Context, Result};
+use ebur128::{EbuR128, Mode};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_audio as gst_audio;
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Scheme of the integrated loudness, in LUFS, in the manifest.
+const INTEGRATED_SCHEME: &str = "urn:movieshare:loudness:integrated";
+/// Scheme of the true peak, in dBTP, in the manifest.
+const TRUE_PEAK_SCHEME: &str = "urn:movieshare:loudness:true-peak";
+/// `ludt` measurement system numbers, from ISO/IEC 23003-4
+const EBU_R128: u8 = 1;
+const BS_1770_4: u8 = 2;
+/// `ludt` reliability of a measured value
+const ACCURATE: u8 = 3;
+/// `ludt` method of program, or integrated, loudness
+const PROGRAM_LOUDNESS: u8 = 1;
+
+/// How loud a representation's audio is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Loudness {
+    pub(crate) integrated_lufs: f64,
+    pub(crate) true_peak_dbtp: f64,
+    pub(crate) sample_peak_dbfs: f64,
+}
+
+#[derive(Default)]
+struct State {
+    ebur128: Option<EbuR128>,
+    format: Option<gst_audio::AudioFormat>,
+    /// dashsink pad the audio goes to, which its segments are named after
+    pad: Option<String>,
+}
+
+/// Meters the audio going into one encoder. Clones share what's measured.
+#[derive(Clone, Default)]
+pub(crate) struct LoudnessMeter {
+    state: Arc<Mutex<State>>,
+}
+
+impl LoudnessMeter {
+    /// Meter the raw audio going through `pad`, on its way to the dashsink
+    /// pad `sink`.
+    pub(crate) fn watch(&self, pad: &gst::Pad, sink: &gst::Pad) {
+        self.state.lock().unwrap().pad = Some(sink.name().to_string());
+        let state = self.state.clone();
+        pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_, info| {
+                let mut state = state.lock().unwrap();
+                match &info.data {
+                    Some(gst::PadProbeData::Event(event)) => {
+                        if let gst::EventView::Caps(caps) = event.view()
+                            && let Ok(audio) = gst_audio::AudioInfo::from_caps(caps.caps())
+                        {
+                            state.format = Some(audio.format());
+                            state.ebur128 = EbuR128::new(
+                                audio.channels(),
+                                audio.rate(),
+                                Mode::I | Mode::TRUE_PEAK,
+                            )
+                            .ok();
+                        }
+                    }
+                    Some(gst::PadProbeData::Buffer(buffer)) => {
+                        let format = state.format;
+                        if let Some(ebur128) = &mut state.ebur128
+                            && let Ok(map) = buffer.map_readable()
+                        {
+                            add_samples(ebur128, format, &map);
+                        }
+                    }
+                    _ => (),
+                }
+                gst::PadProbeReturn::Ok
+            },
+        );
+    }
+
+    /// What was measured, if the audio was in a format the meter reads
+    /// and wasn't silent.
+    pub(crate) fn loudness(&self) -> Option<Loudness> {
+        let state = self.state.lock().unwrap();
+        let ebur128 = state.ebur128.as_ref()?;
+        let integrated_lufs = ebur128.loudness_global().ok()?;
+        let peak = |peak: fn(&EbuR128, u32) -> Result<f64, ebur128::Error>| {
+            (0..ebur128.channels())
+                .filter_map(|channel| peak(ebur128, channel).ok())
+                .fold(0.0, f64::max)
+        };
+        let decibels = |linear: f64| 20.0 * linear.log10();
+        let loudness = Loudness {
+            integrated_lufs,
+            true_peak_dbtp: decibels(peak(EbuR128::true_peak)),
+            sample_peak_dbfs: decibels(peak(EbuR128::sample_peak)),
+        };
+        loudness.integrated_lufs.is_finite().then_some(loudness)
+    }
+
+    fn pad(&self) -> Option<String> {
+        self.state.lock().unwrap().pad.clone()
+    }
+}
+
+/// Feed a buffer of interleaved samples to the meter, if it's in a format
+/// opusenc takes, which is all that's metered.
+fn add_samples(ebur128: &mut EbuR128, format: Option<gst_audio::AudioFormat>, data: &[u8]) {
+    let _ = match format {
+        Some(gst_audio::AudioFormat::F32le) => {
+            let samples: Vec<f32> = data
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect();
+            ebur128.add_frames_f32(&samples)
+        }
+        Some(gst_audio::AudioFormat::S16le) => {
+            let samples: Vec<i16> = data
+                .chunks_exact(2)
+                .map(|bytes| i16::from_le_bytes(bytes.try_into().unwrap()))
+                .collect();
+            ebur128.add_frames_i16(&samples)
+        }
+        _ => return,
+    };
+}
+
+/// A `tlou` box saying how loud a track is.
+fn track_loudness(loudness: &Loudness) -> Vec<u8> {
+    // 20 dB down in 1/32 dB steps; 0 would mean it's not given
+    let peak = |db: f64| ((20.0 - db) * 32.0).round().clamp(1.0, 4095.0) as u32;
+    // From -57.75 LUFS up in quarters
+    let program = ((loudness.integrated_lufs + 57.75) * 4.0)
+        .round()
+        .clamp(0.0, 255.0) as u8;
+    // Version and flags, then no particular downmix or DRC set
+    let mut payload = vec![0; 6];
+    let peaks = peak(loudness.sample_peak_dbfs) << 12 | peak(loudness.true_peak_dbtp);
+    payload.extend(&peaks.to_be_bytes()[1..]);
+    payload.push(BS_1770_4 << 4 | ACCURATE);
+    // One measurement
+    payload.extend([1, PROGRAM_LOUDNESS, program, EBU_R128 << 4 | ACCURATE]);
+    mp4_box(b"tlou", &payload)
+}
+
+/// `init` with `loudness` in a `ludt` in its track's user data, or `None`
+/// if it has no track or already says how loud it is.
+fn with_loudness(init: &[u8], loudness: &Loudness) -> Option<Vec<u8>> {
+    let moov = sidx::find(init, &["moov"])?;
+    sidx::find(moov, &["trak"])?;
+    if sidx::find(moov, &["trak", "udta", "ludt"]).is_some() {
+        return None;
+    }
+    let ludt = mp4_box(b"ludt", &track_loudness(loudness));
+    let mut rebuilt_moov = Vec::with_capacity(moov.len() + ludt.len() + 8);
+    let mut added = false;
+    for (kind, payload) in sidx::boxes(moov) {
+        if kind != b"trak" || added {
+            rebuilt_moov.extend(mp4_box(kind, payload));
+            continue;
+        }
+        let mut trak = Vec::with_capacity(payload.len() + ludt.len() + 8);
+        let mut has_udta = false;
+        for (kind, payload) in sidx::boxes(payload) {
+            match kind {
+                b"udta" => {
+                    trak.extend(mp4_box(kind, &[payload, &ludt].concat()));
+                    has_udta = true;
+                }
+                _ => trak.extend(mp4_box(kind, payload)),
+            }
+        }
+        if !has_udta {
+            trak.extend(mp4_box(b"udta", &ludt));
+        }
+        rebuilt_moov.extend(mp4_box(b"trak", &trak));
+        added = true;
+    }
+    let mut rebuilt = Vec::with_capacity(init.len() + ludt.len() + 8);
+    for (kind, payload) in sidx::boxes(init) {
+        match kind {
+            b"moov" => rebuilt.extend(mp4_box(kind, &rebuilt_moov)),
+            _ => rebuilt.extend(mp4_box(kind, payload)),
+        }
+    }
+    Some(rebuilt)
+}
+
+/// Write what each of `meters` measured into the initialization segment
+/// dashsink wrote for it in `dir`, returning it for each, in order.
+pub(crate) fn signal(dir: &Path, meters: &[LoudnessMeter]) -> Result<Vec<Option<Loudness>>> {
+    let mut measured = Vec::new();
+    for meter in meters {
+        let loudness = meter.loudness();
+        measured.push(loudness);
+        let (Some(loudness), Some(pad)) = (loudness, meter.pad()) else {
+            continue;
+        };
+        let path = dir.join(format!("{}_{}", pad, sidx::INIT_SUFFIX));
+        let Ok(init) = std::fs::read(&path) else {
+            continue;
+        };
+        if let Some(signalled) = with_loudness(&init, &loudness) {
+            std::fs::write(&path, signalled)
+                .context(format!("Failed to write {}", path.display()))?;
+        }
+    }
+    Ok(measured)
+}
+
+/// Give the audio representations of a manifest, in order, the loudness
+/// in `measured`. Those measured as `None`, and any beyond, are left alone.
+pub(crate) fn tag_manifest(xml: &str, measured: &[Option<Loudness>]) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let properties = |writer: &mut Writer<Vec<u8>>, loudness: &Loudness| -> Result<()> {
+        for (scheme, value) in [
+            (INTEGRATED_SCHEME, loudness.integrated_lufs),
+            (TRUE_PEAK_SCHEME, loudness.true_peak_dbtp),
+        ] {
+            writer.write_event(Event::Empty(
+                BytesStart::new("SupplementalProperty").with_attributes([
+                    ("schemeIdUri", scheme),
+                    ("value", format!("{:.1}", value).as_str()),
+                ]),
+            ))?;
+        }
+        Ok(())
+    };
+    let mut measured = measured.iter();
+    let mut in_audio = false;
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            Event::Start(element) => match element.local_name().into_inner() {
+                "AdaptationSet" => {
+                    in_audio = roles::is_of(&element, "audio")?;
+                    writer.write_event(Event::Start(element))?;
+                }
+                "Representation" if in_audio || roles::is_of(&element, "audio")? => {
+                    writer.write_event(Event::Start(element))?;
+                    if let Some(Some(loudness)) = measured.next() {
+                        properties(&mut writer, loudness)?;
+                    }
+                }
+                _ => writer.write_event(Event::Start(element))?,
+            },
+            Event::Empty(element)
+                if element.local_name().into_inner() == "Representation"
+                    && (in_audio || roles::is_of(&element, "audio")?) =>
+            {
+                match measured.next() {
+                    Some(Some(loudness)) => {
+                        let name = element.name().into_inner().to_string();
+                        writer.write_event(Event::Start(element))?;
+                        properties(&mut writer, loudness)?;
+                        writer.write_event(Event::End(BytesEnd::new(name)))?;
+                    }
+                    _ => writer.write_event(Event::Empty(element))?,
+                }
+            }
+            Event::End(end) => {
+                if end.local_name().into_inner() == "AdaptationSet" {
+                    in_audio = false;
+                }
+                writer.write_event(Event::End(end))?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_measured_loudness() {
+        let loudness = Loudness {
+            integrated_lufs: -23.0,
+            true_peak_dbtp: -1.0,
+            sample_peak_dbfs: -1.5,
+        };
+        let xml = r#"<MPD><AdaptationSet contentType="video"><Representation id="0"/></AdaptationSet><AdaptationSet contentType="audio"><Representation id="1"><BaseURL>a.mp4</BaseURL></Representation><Representation id="2"/></AdaptationSet></MPD>"#;
+        assert_eq!(
+            tag_manifest(xml, &[None, Some(loudness)]).unwrap(),
+            r#"<MPD><AdaptationSet contentType="video"><Representation id="0"/></AdaptationSet><AdaptationSet contentType="audio"><Representation id="1"><BaseURL>a.mp4</BaseURL></Representation><Representation id="2"><SupplementalProperty schemeIdUri="urn:movieshare:loudness:integrated" value="-23.0"/><SupplementalProperty schemeIdUri="urn:movieshare:loudness:true-peak" value="-1.0"/></Representation></AdaptationSet></MPD>"#
+        );
+
+        let trak = mp4_box(b"trak", &mp4_box(b"tkhd", &[0; 4]));
+        let init = [mp4_box(b"ftyp", b"iso6"), mp4_box(b"moov", &trak)].concat();
+        let signalled = with_loudness(&init, &loudness).unwrap();
+        let tlou = sidx::find(&signalled, &["moov", "trak", "udta", "ludt", "tlou"]).unwrap();
+        // 21.5 and 21 dB below 20 dB, in 1/32 dB steps
+        assert_eq!(&tlou[6..9], &[0x2b, 0x02, 0xa0]);
+        // -23 LUFS is 34.75 dB up from -57.75, in quarters
+        assert_eq!(&tlou[10..], &[1, PROGRAM_LOUDNESS, 139, 0x13]);
+        assert_eq!(with_loudness(&signalled, &loudness), None);
+    }
+}
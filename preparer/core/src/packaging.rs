@@ -0,0 +1,119 @@
+//! Packaging knobs dashsink doesn't take itself: the fragment length of its
+//! muxer, segments starting at chapters, and the clock dynamic manifests
+//! point players at.
+
+use crate::spec::PackagingSpec;
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::time::Duration;
+
+/// Scheme of a `UTCTiming` whose URL answers with an ISO 8601 time.
+const UTC_TIMING_SCHEME: &str = "urn:mpeg:dash:utc:http-iso:2014";
+
+/// Apply `packaging` to the muxers dashsink creates inside itself.
+pub(crate) fn configure_muxers(dashsink: &gst::Element, packaging: &PackagingSpec) {
+    let Some(fragment_ms) = packaging.fragment_duration_ms else {
+        return;
+    };
+    let Some(bin) = dashsink.downcast_ref::<gst::Bin>() else {
+        return;
+    };
+    bin.connect_deep_element_added(move |_, _, element| {
+        let Some(property) = element.find_property("fragment-duration") else {
+            return;
+        };
+        // mp4mux counts in milliseconds, the fragmented MP4 muxers in nanoseconds
+        match property.value_type() == u64::static_type() {
+            true => element.set_property(
+                "fragment-duration",
+                gst::ClockTime::from_mseconds(fragment_ms as u64).nseconds(),
+            ),
+            false => element.set_property("fragment-duration", fragment_ms),
+        }
+    });
+}
+
+/// Have the segmenters dashsink creates also start a segment at each of
+/// `times`, in running time, besides at every target duration.
+pub(crate) fn split_at(dashsink: &gst::Element, times: &[Duration]) {
+    let Some(bin) = dashsink.downcast_ref::<gst::Bin>() else {
+        return;
+    };
+    if times.is_empty() {
+        return;
+    }
+    let times = times.to_vec();
+    bin.connect_deep_element_added(move |_, _, element| {
+        if element
+            .factory()
+            .is_none_or(|factory| factory.name() != "splitmuxsink")
+        {
+            return;
+        }
+        for time in &times {
+            element.emit_by_name::<()>("split-at-running-time", &[&(time.as_nanos() as u64)]);
+        }
+    });
+}
+
+/// Point players of a manifest at `url` to sync their clocks to, replacing
+/// any `UTCTiming` it had.
+pub(crate) fn set_utc_timing(xml: &str, url: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    // Depth inside an old UTCTiming being dropped
+    let mut skipping = 0;
+    loop {
+        let event = reader.read_event().context("Invalid MPD")?;
+        if skipping > 0 {
+            match event {
+                Event::Start(_) => skipping += 1,
+                Event::End(_) => skipping -= 1,
+                Event::Eof => break,
+                _ => (),
+            }
+            continue;
+        }
+        match event {
+            Event::Eof => break,
+            Event::Start(element) if element.local_name().into_inner() == "UTCTiming" => {
+                skipping = 1
+            }
+            Event::Empty(element) if element.local_name().into_inner() == "UTCTiming" => (),
+            // It comes last among the MPD's children
+            Event::End(element) if element.local_name().into_inner() == "MPD" => {
+                writer
+                    .write_event(Event::Empty(BytesStart::new("UTCTiming").with_attributes(
+                        [("schemeIdUri", UTC_TIMING_SCHEME), ("value", url)],
+                    )))?;
+                writer.write_event(Event::End(BytesEnd::new(
+                    element.name().into_inner().to_string(),
+                )))?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_one_utc_timing() {
+        let xml = r#"<MPD type="dynamic"><Period/></MPD>"#;
+        let timed = set_utc_timing(xml, "https://time.example/iso").unwrap();
+        assert_eq!(
+            timed,
+            r#"<MPD type="dynamic"><Period/><UTCTiming schemeIdUri="urn:mpeg:dash:utc:http-iso:2014" value="https://time.example/iso"/></MPD>"#
+        );
+        assert_eq!(
+            set_utc_timing(&timed, "https://time.example/iso").unwrap(),
+            timed
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Routing the pads decodebin and parsebin add as they find streams.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// Streams already given their place in the pipeline.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Taken {
+    pub video: bool,
+    pub audio: bool,
+}
+
+/// Where a stream the source offers goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Route {
+    CoverArt,
+    Video,
+    /// Another video stream, like a second camera angle, which is left out
+    ExtraVideo,
+    Audio,
+    Ignore,
+}
+
+/// Where a stream with caps named `name` goes, given those `taken` already.
+/// Audio beyond the first is carried separately, by extra tracks.
+pub(crate) fn route(name: &str, cover_art: bool, taken: Taken) -> Route {
+    match name {
+        _ if cover_art => Route::CoverArt,
+        name if name.starts_with("video/") && taken.video => Route::ExtraVideo,
+        name if name.starts_with("video/") => Route::Video,
+        name if name.starts_with("audio/") && !taken.audio => Route::Audio,
+        _ => Route::Ignore,
+    }
+}
+
+/// Pads still waiting to be routed, so that what the source lacks is only
+/// worked out once all of them have been.
+#[derive(Debug, Default)]
+pub(crate) struct Pending {
+    waiting: usize,
+    no_more_pads: bool,
+}
+
+impl Pending {
+    /// A pad was added.
+    pub fn add(&mut self) {
+        self.waiting += 1;
+    }
+
+    /// A pad was routed; returns whether it was the last of them.
+    pub fn routed(&mut self) -> bool {
+        self.waiting -= 1;
+        self.no_more_pads && self.waiting == 0
+    }
+
+    /// The source added its last pad; returns whether all are routed.
+    pub fn finish(&mut self) -> bool {
+        self.no_more_pads = true;
+        self.waiting == 0
+    }
+}
+
+/// Call `handle` with `pad`'s caps once it has them: right away if they've
+/// been negotiated, or when its caps event comes through if not.
+pub(crate) fn with_caps<F>(pad: &gst::Pad, handle: F)
+where
+    F: FnOnce(&gst::Pad, &gst::Caps) + Send + 'static,
+{
+    let handle = Arc::new(Mutex::new(Some(handle)));
+    // Watch for the event before looking, so caps set in between aren't missed
+    let on_event = handle.clone();
+    pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |pad, info| {
+        let Some(gst::PadProbeData::Event(event)) = &info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let gst::EventView::Caps(caps) = event.view() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        if let Some(handle) = on_event.lock().unwrap().take() {
+            handle(pad, &caps.caps_owned());
+        }
+        gst::PadProbeReturn::Remove
+    });
+    if let Some(caps) = pad.current_caps()
+        && let Some(handle) = handle.lock().unwrap().take()
+    {
+        handle(pad, &caps);
+    }
+}
+
+/// Read and drop whatever `pad` carries into a new sink in `pipeline`, so
+/// the demuxer it comes from keeps going.
+pub(crate) fn drain(pipeline: &gst::Pipeline, pad: &gst::Pad) -> Result<()> {
+    let fakesink = gst::ElementFactory::make("fakesink")
+        .property("sync", false)
+        .build()?;
+    pipeline.add(&fakesink)?;
+    fakesink.sync_state_with_parent()?;
+    pad.link(
+        &fakesink
+            .static_pad("sink")
+            .context("Failed to get sink pad from fakesink")?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_the_first_of_each_stream() {
+        let none = Taken::default();
+        assert_eq!(route("video/x-h264", false, none), Route::Video);
+        assert_eq!(route("image/jpeg", true, none), Route::CoverArt);
+        assert_eq!(route("audio/x-opus", false, none), Route::Audio);
+        assert_eq!(route("text/x-raw", false, none), Route::Ignore);
+
+        let both = Taken {
+            video: true,
+            audio: true,
+        };
+        assert_eq!(route("video/x-h264", false, both), Route::ExtraVideo);
+        assert_eq!(route("audio/x-opus", false, both), Route::Ignore);
+
+        // A pad still waiting for caps holds up the check after the last one
+        let mut pending = Pending::default();
+        pending.add();
+        pending.add();
+        assert!(!pending.routed());
+        assert!(!pending.finish());
+        assert!(pending.routed());
+        let mut pending = Pending::default();
+        assert!(pending.finish());
+    }
+
+    #[test]
+    #[ignore = "needs GStreamer installed"]
+    fn waits_for_caps() {
+        gst::init().unwrap();
+        let src = gst::Pad::builder(gst::PadDirection::Src).build();
+        let sink = gst::Pad::builder(gst::PadDirection::Sink)
+            .event_function(|_, _, _| true)
+            .build();
+        src.link(&sink).unwrap();
+        src.set_active(true).unwrap();
+        sink.set_active(true).unwrap();
+
+        let seen = Arc::new(Mutex::new(None));
+        let found = seen.clone();
+        with_caps(&src, move |_, caps| {
+            *found.lock().unwrap() = Some(caps.clone());
+        });
+        assert!(seen.lock().unwrap().is_none());
+
+        src.push_event(gst::event::StreamStart::new("test"));
+        let caps = gst::Caps::builder("video/x-raw").build();
+        src.push_event(gst::event::Caps::new(&caps));
+        assert_eq!(seen.lock().unwrap().as_ref(), Some(&caps));
+    }
+}
@@ -0,0 +1,610 @@
+//! Deciding stream by stream whether the source can go into the output as
+//! it is or has to be encoded, since copying is far faster than encoding.
+//!
+//! AV1 video within the top of the ladder is copied as the top rung, with
+//! only the lower rungs encoded from it, and Opus audio is copied verbatim.
+//! Audio that's Opus already is worth copying even when everything else is
+//! encoded, since encoding it again only loses quality.
+
+use crate::analysis::Findings;
+use crate::pads;
+use crate::spec::EncodingProfile;
+use anyhow::{Context, Result, anyhow};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::path::Path;
+use std::time::Duration;
+
+/// What to do with one stream of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Copy,
+    Encode,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Action::Copy => "copy",
+            Action::Encode => "encode",
+        })
+    }
+}
+
+/// The source's streams, as far as planning cares.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceInfo {
+    /// Caps name of the first video stream, like `video/x-av1`
+    pub video: Option<String>,
+    /// Picture size of the first video stream
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Frames per second of the first video stream, unless it's variable
+    pub framerate: Option<f64>,
+    /// Whether the first video stream carries HDR10+ dynamic metadata
+    pub dynamic_hdr: bool,
+    /// Caps name of the first audio stream, like `audio/x-opus`
+    pub audio: Option<String>,
+    /// Average bitrate of the whole file
+    pub bitrate_kbps: Option<f64>,
+    pub duration: Option<Duration>,
+    /// Bitrate of the first audio stream, when its tags give one
+    pub audio_kbps: Option<f64>,
+    pub audio_channels: Option<u32>,
+    /// How many audio streams there are in all
+    pub audio_streams: usize,
+    /// How many text subtitle streams there are
+    pub subtitle_streams: usize,
+}
+
+/// What to do with each representation of the output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    /// Each rung of the ladder, in MB/s, and whether it's copied
+    pub rungs: Vec<(u32, Action)>,
+    pub audio: Action,
+    /// What analyzing the source found, if it was
+    pub findings: Findings,
+}
+
+impl Plan {
+    /// Encode everything, as without a plan.
+    pub fn encode_all(profile: &EncodingProfile) -> Self {
+        Self {
+            rungs: profile
+                .ladder
+                .iter()
+                .map(|&bitrate| (bitrate, Action::Encode))
+                .collect(),
+            audio: Action::Encode,
+            findings: Findings::default(),
+        }
+    }
+
+    /// Copy the source's video as the only rung, for `--repackage`.
+    pub fn repackage(profile: &EncodingProfile) -> Self {
+        Self {
+            rungs: vec![(top_rung(profile), Action::Copy)],
+            audio: Action::Encode,
+            findings: Findings::default(),
+        }
+    }
+
+    /// Copy whatever of `source` already fits `profile`.
+    pub fn decide(source: &SourceInfo, profile: &EncodingProfile) -> Self {
+        let top = top_rung(profile);
+        let copy_video = source.video.as_deref() == Some("video/x-av1")
+            && source
+                .bitrate_kbps
+                .is_some_and(|kbps| kbps <= top as f64 * 1000.0);
+        let mut plan = Self::encode_all(profile);
+        if copy_video && let Some(rung) = plan.rungs.iter_mut().find(|(bitrate, _)| *bitrate == top)
+        {
+            rung.1 = Action::Copy;
+        }
+        plan.pass_audio_through(source, profile);
+        plan
+    }
+
+    /// Copy the source's audio if it's Opus that encoding again wouldn't
+    /// improve: no bigger than `profile` asks for, with no more channels, and
+    /// with no renditions that need it decoded. Returns whether it will be.
+    pub fn pass_audio_through(&mut self, source: &SourceInfo, profile: &EncodingProfile) -> bool {
+        let audio = &profile.audio;
+        let fits = source.audio.as_deref() == Some("audio/x-opus")
+            && source
+                .audio_kbps
+                .is_some_and(|kbps| kbps <= audio.bitrate_kbps as f64)
+            && source
+                .audio_channels
+                .is_some_and(|channels| channels <= audio.channels)
+            && !audio.drc
+            && audio.mono_kbps.is_none();
+        if fits {
+            self.audio = Action::Copy;
+        }
+        fits
+    }
+
+    /// Drop the encoded rungs `profile` scales taller than `source`, rather
+    /// than upscale it, keeping at least the shortest. Returns the bitrates
+    /// of those dropped.
+    pub fn skip_taller(&mut self, source: &SourceInfo, profile: &EncodingProfile) -> Vec<u32> {
+        let Some(source_height) = source.height else {
+            return Vec::new();
+        };
+        let height = |bitrate: u32| profile.rung(bitrate).and_then(|rung| rung.height);
+        let mut skipped: Vec<u32> = self
+            .rungs
+            .iter()
+            .filter(|&&(bitrate, action)| {
+                action == Action::Encode && height(bitrate).is_some_and(|h| h > source_height)
+            })
+            .map(|&(bitrate, _)| bitrate)
+            .collect();
+        if skipped.len() == self.rungs.len()
+            && let Some(shortest) = skipped.iter().copied().min_by_key(|&b| height(b))
+        {
+            skipped.retain(|&bitrate| bitrate != shortest);
+        }
+        self.rungs.retain(|(bitrate, _)| !skipped.contains(bitrate));
+        skipped
+    }
+
+    /// Whether any rung is the source's video.
+    pub fn copies_video(&self) -> bool {
+        self.rungs.iter().any(|(_, action)| *action == Action::Copy)
+    }
+
+    /// Rungs to encode, in MB/s.
+    pub fn encoded_rungs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.rungs
+            .iter()
+            .filter(|(_, action)| *action == Action::Encode)
+            .map(|(bitrate, _)| *bitrate)
+    }
+}
+
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (bitrate, action) in &self.rungs {
+            writeln!(f, "{} MB/s video: {}", bitrate, action)?;
+        }
+        write!(f, "Audio: {}", self.audio)?;
+        if !self.findings.scene_cuts.is_empty() {
+            write!(f, "\nScene cuts: {}", self.findings.scene_cuts.len())?;
+        }
+        if let Some(complexity) = self.findings.complexity {
+            write!(f, "\nComplexity: {:.3}", complexity)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything worth reading about a job before it starts.
+pub struct Overview<'a> {
+    pub source: &'a SourceInfo,
+    pub plan: &'a Plan,
+    pub profile: &'a EncodingProfile,
+    /// How the picture or timeline is altered on the way, like cuts
+    pub filters: Vec<String>,
+    /// Seconds of source past runs encoded per second, for each rung
+    pub speed: Option<f64>,
+    /// How past outputs' sizes came out against their estimates
+    pub size_ratio: Option<f64>,
+}
+
+impl Overview<'_> {
+    /// How big the output should come out, from the bitrates it's encoded
+    /// at and how far past outputs strayed from theirs, or `None` without
+    /// knowing how long the source is.
+    pub fn estimated_bytes(&self) -> Option<u64> {
+        let secs = self.source.duration?.as_secs_f64();
+        let video_kbps: f64 = self
+            .plan
+            .rungs
+            .iter()
+            .map(|&(bitrate, action)| match action {
+                Action::Copy => self.source.bitrate_kbps.unwrap_or(bitrate as f64 * 1000.0),
+                Action::Encode => bitrate as f64 * 1000.0,
+            })
+            .sum();
+        let audio = &self.profile.audio;
+        let audio_kbps =
+            audio.bitrate_kbps * (1 + audio.drc as u32) + audio.mono_kbps.unwrap_or_default();
+        let bytes = (video_kbps + audio_kbps as f64) * 1000.0 / 8.0 * secs;
+        Some((bytes * self.size_ratio.unwrap_or(1.0)) as u64)
+    }
+
+    /// How long encoding should take at the speed of past runs, or `None`
+    /// without any to go by.
+    pub fn estimated_time(&self) -> Option<Duration> {
+        let rungs = self.plan.encoded_rungs().count() as f64;
+        let speed = self.speed.filter(|speed| *speed > 0.0)?;
+        Some(Duration::from_secs_f64(
+            self.encoded_duration().as_secs_f64() * rungs / speed,
+        ))
+    }
+
+    /// How much video has to be encoded, which is what takes the time.
+    pub fn encoded_duration(&self) -> Duration {
+        match self.plan.encoded_rungs().next() {
+            Some(_) => self.source.duration.unwrap_or_default(),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+impl std::fmt::Display for Overview<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let streams: Vec<&str> = [&self.source.video, &self.source.audio]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        if !streams.is_empty() {
+            write!(f, "Source: {}", streams.join(", "))?;
+            if let Some(duration) = self.source.duration {
+                write!(f, ", {}", format_duration(duration))?;
+            }
+            writeln!(f)?;
+        }
+        for (bitrate, action) in &self.plan.rungs {
+            match action {
+                Action::Copy => writeln!(f, "{} MB/s video: copy", bitrate)?,
+                Action::Encode => writeln!(
+                    f,
+                    "{} MB/s video: encode to AV1 with {}, preset {}",
+                    bitrate,
+                    self.profile.encoder_backend(),
+                    self.profile.encoder_preset
+                )?,
+            }
+        }
+        let audio = &self.profile.audio;
+        match self.plan.audio {
+            Action::Copy => writeln!(f, "Audio: copy")?,
+            Action::Encode => {
+                write!(
+                    f,
+                    "Audio: encode to Opus at {} kb/s, {} channels",
+                    audio.bitrate_kbps, audio.channels
+                )?;
+                if let Some(kbps) = audio.mono_kbps {
+                    write!(f, ", and mono at {} kb/s", kbps)?;
+                }
+                if audio.drc {
+                    write!(f, ", and compressed for dialogue")?;
+                }
+                writeln!(f)?;
+            }
+        }
+        if !self.filters.is_empty() {
+            writeln!(f, "Filters: {}", self.filters.join(", "))?;
+        }
+        if !self.plan.findings.scene_cuts.is_empty() {
+            writeln!(f, "Scene cuts: {}", self.plan.findings.scene_cuts.len())?;
+        }
+        if let Some(complexity) = self.plan.findings.complexity {
+            writeln!(f, "Complexity: {:.3}", complexity)?;
+        }
+        match self.estimated_bytes() {
+            Some(bytes) => writeln!(f, "Estimated size: {:.1} GB", bytes as f64 / 1e9)?,
+            None => writeln!(f, "Estimated size: unknown")?,
+        }
+        match self.estimated_time() {
+            Some(time) => write!(f, "Estimated time: {}", format_duration(time)),
+            None => write!(
+                f,
+                "Estimated time: unknown until a first encode is recorded"
+            ),
+        }
+    }
+}
+
+/// A duration as hours, minutes and seconds, like `1:52:07`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Record the bitrate the stream's tags give, as they go past.
+fn watch_bitrate(pad: &gst::Pad, found: std::sync::Arc<std::sync::Mutex<SourceInfo>>) {
+    pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        if let Some(gst::EventView::Tag(tag)) = info.event().map(|event| event.view()) {
+            let tags = tag.tag();
+            let bitrate = tags
+                .get::<gst::tags::Bitrate>()
+                .or_else(|| tags.get::<gst::tags::NominalBitrate>());
+            if let Some(bitrate) = bitrate {
+                found.lock().unwrap().audio_kbps = Some(bitrate.get() as f64 / 1000.0);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// The ITU-T T.35 header HDR10+ metadata starts with, in HEVC SEI messages
+/// and AV1 metadata OBUs alike: the United States, Samsung, and application 4.
+const HDR10_PLUS: [u8; 6] = [0xb5, 0x00, 0x3c, 0x00, 0x01, 0x04];
+
+/// Whether a frame of compressed video holds HDR10+ metadata.
+fn carries_hdr10_plus(frame: &[u8]) -> bool {
+    frame.windows(HDR10_PLUS.len()).any(|w| w == HDR10_PLUS)
+}
+
+/// Note if the frames through `pad` before prerolling carry HDR10+ metadata.
+fn watch_dynamic_hdr(pad: &gst::Pad, found: std::sync::Arc<std::sync::Mutex<SourceInfo>>) {
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if let Some(buffer) = info.buffer()
+            && let Ok(map) = buffer.map_readable()
+            && carries_hdr10_plus(&map)
+        {
+            found.lock().unwrap().dynamic_hdr = true;
+            return gst::PadProbeReturn::Remove;
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+fn top_rung(profile: &EncodingProfile) -> u32 {
+    profile.ladder.iter().copied().max().unwrap_or_default()
+}
+
+/// Look at what streams `input` holds, without decoding any of them.
+pub fn probe(input: &Path) -> Result<SourceInfo> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*input.to_string_lossy())
+        .build()?;
+    let parsebin = gst::ElementFactory::make("parsebin").build()?;
+    pipeline.add_many([&filesrc, &parsebin])?;
+    filesrc.link(&parsebin)?;
+
+    let found = std::sync::Arc::new(std::sync::Mutex::new(SourceInfo::default()));
+    let found_pads = found.clone();
+    let pipeline_weak = pipeline.downgrade();
+    parsebin.connect_pad_added(move |parsebin, src_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        // Every stream is read and dropped, so the demuxer keeps going
+        if let Err(err) = pads::drain(&pipeline, src_pad) {
+            gst::element_error!(
+                parsebin,
+                gst::CoreError::Negotiation,
+                ("Failed to read a stream of the source: {:#}", err)
+            );
+            return;
+        }
+        let found = found_pads.clone();
+        pads::with_caps(src_pad, move |pad, caps| {
+            let Some(structure) = caps.structure(0) else {
+                return;
+            };
+            let name = structure.name().to_string();
+            let mut info = found.lock().unwrap();
+            if name.starts_with("video/") {
+                if info.video.is_none() {
+                    info.video = Some(name);
+                    info.width = structure.get::<i32>("width").ok().map(|w| w as u32);
+                    info.height = structure.get::<i32>("height").ok().map(|h| h as u32);
+                    info.framerate = structure
+                        .get::<gst::Fraction>("framerate")
+                        .ok()
+                        .filter(|rate| rate.numer() > 0)
+                        .map(|rate| rate.numer() as f64 / rate.denom() as f64);
+                    watch_dynamic_hdr(pad, found.clone());
+                }
+            } else if name.starts_with("audio/") {
+                info.audio_streams += 1;
+                if info.audio.is_none() {
+                    info.audio = Some(name);
+                    info.audio_channels = structure.get::<i32>("channels").ok().map(|c| c as u32);
+                    watch_bitrate(pad, found.clone());
+                }
+            } else if name == "text/x-raw" {
+                info.subtitle_streams += 1;
+            }
+        });
+    });
+
+    pipeline.set_state(gst::State::Paused)?;
+    let bus = pipeline.bus().unwrap();
+    let result = loop {
+        use gst::MessageView;
+
+        let Some(message) = bus.timed_pop(gst::ClockTime::NONE) else {
+            break Ok(());
+        };
+        match message.view() {
+            MessageView::AsyncDone(..) | MessageView::Eos(..) => break Ok(()),
+            MessageView::Error(err) => {
+                break Err(anyhow!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ));
+            }
+            _ => (),
+        }
+    };
+    let duration = pipeline.query_duration::<gst::ClockTime>();
+    pipeline.set_state(gst::State::Null)?;
+    result.context(format!("Failed to probe {}", input.display()))?;
+
+    let mut info = found.lock().unwrap().clone();
+    let bytes = std::fs::metadata(input)?.len();
+    info.duration = duration.map(|d| Duration::from_nanos(d.nseconds()));
+    info.bitrate_kbps = duration
+        .filter(|d| *d > gst::ClockTime::ZERO)
+        .map(|d| bytes as f64 * 8.0 / 1000.0 / d.seconds_f64());
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::RungSpec;
+
+    #[test]
+    fn copies_what_already_fits() {
+        let profile = EncodingProfile::default();
+        let top = *profile.ladder.iter().max().unwrap();
+        let av1 = SourceInfo {
+            video: Some(String::from("video/x-av1")),
+            audio: Some(String::from("audio/x-opus")),
+            audio_kbps: Some(128.0),
+            audio_channels: Some(2),
+            bitrate_kbps: Some(top as f64 * 1000.0 - 1.0),
+            ..SourceInfo::default()
+        };
+        let plan = Plan::decide(&av1, &profile);
+        assert!(plan.rungs.contains(&(top, Action::Copy)));
+        assert_eq!(plan.encoded_rungs().count(), profile.ladder.len() - 1);
+        assert_eq!(plan.audio, Action::Copy);
+
+        // Too big to be the top rung
+        let big = SourceInfo {
+            bitrate_kbps: Some(top as f64 * 1000.0 + 1.0),
+            ..av1.clone()
+        };
+        assert!(!Plan::decide(&big, &profile).copies_video());
+
+        let h264 = SourceInfo {
+            video: Some(String::from("video/x-h264")),
+            audio: Some(String::from("audio/mpeg")),
+            ..av1
+        };
+        assert_eq!(Plan::decide(&h264, &profile), Plan::encode_all(&profile));
+    }
+
+    #[test]
+    fn passes_through_audio_that_fits() {
+        let profile = EncodingProfile::default();
+        let opus = SourceInfo {
+            audio: Some(String::from("audio/x-opus")),
+            audio_kbps: Some(profile.audio.bitrate_kbps as f64),
+            audio_channels: Some(2),
+            ..SourceInfo::default()
+        };
+        let mut plan = Plan::encode_all(&profile);
+        assert!(plan.pass_audio_through(&opus, &profile));
+        assert_eq!(plan.audio, Action::Copy);
+        assert!(!plan.copies_video());
+
+        let rejects = |source: SourceInfo, profile: &EncodingProfile| {
+            !Plan::encode_all(profile).pass_audio_through(&source, profile)
+        };
+        let bigger = SourceInfo {
+            audio_kbps: Some(256.0),
+            ..opus.clone()
+        };
+        assert!(rejects(bigger, &profile));
+        let untagged = SourceInfo {
+            audio_kbps: None,
+            ..opus.clone()
+        };
+        assert!(rejects(untagged, &profile));
+        let surround = SourceInfo {
+            audio_channels: Some(6),
+            ..opus.clone()
+        };
+        assert!(rejects(surround, &profile));
+        let mut drc = profile.clone();
+        drc.audio.drc = true;
+        assert!(rejects(opus, &drc));
+    }
+
+    #[test]
+    fn finds_hdr10_plus_metadata() {
+        // An HEVC SEI NAL unit with a registered user data payload
+        let sei = [
+            0, 0, 1, 0x4e, 0x01, 0x04, 0x40, 0xb5, 0x00, 0x3c, 0x00, 0x01, 0x04,
+        ];
+        assert!(carries_hdr10_plus(&sei));
+        // Dolby Vision's provider code is 0x003b
+        let dolby = [
+            0, 0, 1, 0x4e, 0x01, 0x04, 0x40, 0xb5, 0x00, 0x3b, 0x00, 0x01, 0x04,
+        ];
+        assert!(!carries_hdr10_plus(&dolby));
+    }
+
+    #[test]
+    fn skips_rungs_taller_than_the_source() {
+        let rung = |bitrate, height| RungSpec {
+            bitrate,
+            height: Some(height),
+            preset: None,
+            keyframe_secs: None,
+            caps: None,
+        };
+        let profile = EncodingProfile {
+            ladder: vec![12, 6, 2],
+            rungs: vec![rung(12, 2160), rung(6, 1080), rung(2, 720)],
+            ..EncodingProfile::default()
+        };
+        let source = |height| SourceInfo {
+            height: Some(height),
+            ..SourceInfo::default()
+        };
+        let mut plan = Plan::encode_all(&profile);
+        assert_eq!(plan.skip_taller(&source(1080), &profile), vec![12]);
+        assert_eq!(plan.encoded_rungs().collect::<Vec<_>>(), vec![6, 2]);
+        // Something is still encoded from a source shorter than every rung
+        let mut plan = Plan::encode_all(&profile);
+        assert_eq!(plan.skip_taller(&source(480), &profile), vec![12, 6]);
+        assert_eq!(plan.encoded_rungs().collect::<Vec<_>>(), vec![2]);
+        // Rungs without a height follow the source's
+        let mut plan = Plan::encode_all(&EncodingProfile::default());
+        assert!(
+            plan.skip_taller(&source(480), &EncodingProfile::default())
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn estimates_the_output() {
+        let profile = EncodingProfile {
+            ladder: vec![6, 2],
+            ..EncodingProfile::default()
+        };
+        let source = SourceInfo {
+            video: Some(String::from("video/x-av1")),
+            audio: Some(String::from("audio/mpeg")),
+            bitrate_kbps: Some(4000.0),
+            duration: Some(Duration::from_secs(3723)),
+            ..SourceInfo::default()
+        };
+        let plan = Plan::decide(&source, &profile);
+        let overview = Overview {
+            source: &source,
+            plan: &plan,
+            profile: &profile,
+            filters: vec![String::from("watermark")],
+            speed: None,
+            size_ratio: None,
+        };
+        // 4 Mb/s copied, 2 Mb/s encoded and 192 kb/s of audio
+        assert_eq!(overview.estimated_bytes(), Some(2_881_602_000));
+        assert_eq!(overview.encoded_duration(), Duration::from_secs(3723));
+        assert_eq!(overview.estimated_time(), None);
+        let text = overview.to_string();
+        assert!(text.starts_with("Source: video/x-av1, audio/mpeg, 1:02:03\n"));
+        assert!(text.contains("6 MB/s video: copy\n"));
+        assert!(text.contains("Filters: watermark\n"));
+        assert!(text.contains("Estimated size: 2.9 GB\n"));
+
+        // Past runs encoding a rung at three times real time, a tenth smaller
+        let overview = Overview {
+            speed: Some(3.0),
+            size_ratio: Some(0.9),
+            ..overview
+        };
+        assert_eq!(overview.estimated_bytes(), Some(2_593_441_800));
+        assert_eq!(overview.estimated_time(), Some(Duration::from_secs(1241)));
+        assert!(overview.to_string().ends_with("Estimated time: 0:20:41"));
+    }
+}
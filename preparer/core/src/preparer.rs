@@ -0,0 +1,1931 @@
+use crate::avsync::SyncCheck;
+use crate::bottleneck::QueueSampler;
+use crate::branch::{AudioBranch, EncodingBranch, MediaType, PassthroughBranch, PipelineBranch};
+use crate::burnin::BurnInStage;
+use crate::cancel::{CancelPolicy, CancellationToken};
+use crate::channels;
+use crate::conform;
+use crate::cover::{self, CoverArt};
+use crate::cuts::{Cut, Placement, Splice};
+use crate::damage::DecodeErrors;
+use crate::decoder::Decoder;
+use crate::encoder::{Codec, Encoder};
+use crate::factory::GstFactory;
+use crate::gapless::{self, DelayWatch};
+use crate::grade::{Grade, GradeStage};
+use crate::job::JobEvent;
+use crate::journal::{self, JOURNAL_FILENAME, Journal, JournalEvent};
+use crate::language;
+use crate::levels;
+use crate::lock::{LOCK_FILENAME, OutputLock};
+use crate::loudness;
+use crate::packaging;
+use crate::pads::{self, Pending, Route, Taken};
+use crate::plan::{Action, Plan, SourceInfo};
+use crate::preroll;
+use crate::qc::QcBranch;
+use crate::reproducible;
+use crate::roles;
+use crate::sidx;
+use crate::spec::{AudioSpec, EncodingProfile, JobSpec};
+use crate::staging::{Retried, SegmentStaging};
+use crate::telecine;
+use crate::watermark::{Watermark, WatermarkStage};
+use anyhow::{Context, Result, bail};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Periodic progress sample taken while the pipeline runs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Progress {
+    /// Fraction of the input processed, from 0.0 to 1.0
+    pub fraction: f64,
+    /// Decoded video frames fed to the encoding branches so far
+    pub frames: u64,
+    /// Average frames per second since the pipeline started
+    pub fps: f64,
+    /// Seconds the rest should take at the pace so far, once there's a pace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub frames: u64,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Prepared(Summary),
+    /// `resume` was set and the journal shows this input was already prepared
+    AlreadyPrepared,
+    /// The cancellation token fired; the output was handled per the policy
+    Cancelled(CancelPolicy),
+}
+
+/// What to do with a source that turns out to have no audio.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingAudio {
+    /// Leave audio out of the presentation
+    #[default]
+    Skip,
+    /// Give the presentation a silent track, for players that insist on one
+    Silence,
+}
+
+impl std::str::FromStr for MissingAudio {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "skip" => MissingAudio::Skip,
+            "silence" => MissingAudio::Silence,
+            _ => bail!(
+                "Unknown missing audio handling {:?}; expected skip or silence",
+                s
+            ),
+        })
+    }
+}
+
+pub(crate) const MANIFEST_FILENAME: &str = "manifest.mpd";
+
+/// Video formats players can take as they are, so they can be copied
+/// rather than encoded.
+const REPACKAGEABLE: &[&str] = &["video/x-av1", "video/x-h264"];
+
+/// Where the decoded video and audio come from.
+enum Source {
+    File(PathBuf),
+    /// A container piped in on standard input
+    Stdin,
+    /// A live stream at this URI, like `srt://:9000`
+    Live(String),
+    /// The desktop and the default audio input
+    Screen,
+    /// Generated test pattern and tone of this length
+    TestPattern(Duration),
+    /// Still images shown in turn for `per_image` each, over an audio file
+    /// or silence
+    Slideshow {
+        images: Vec<PathBuf>,
+        audio: Option<PathBuf>,
+        per_image: Duration,
+    },
+}
+
+/// Builder for a single preparation run.
+pub struct Preparer {
+    source: Source,
+    output: Option<PathBuf>,
+    profile: EncodingProfile,
+    resume: bool,
+    dynamic_manifest: bool,
+    /// Where staged segments go before the output
+    scratch_dir: PathBuf,
+    cancellation: CancellationToken,
+    extra_branches: Vec<Box<dyn PipelineBranch>>,
+    cuts: Vec<Cut>,
+    chapter_starts: Vec<Duration>,
+    watermark: Option<Watermark>,
+    burn_subtitles: Option<PathBuf>,
+    grade: Option<Grade>,
+    inverse_telecine: bool,
+    repackage: bool,
+    plan: Option<Plan>,
+    probed: Option<SourceInfo>,
+    deterministic: bool,
+    sync_threshold: Option<Duration>,
+    report_bottleneck: bool,
+    qc: bool,
+    failure_graph: Option<PathBuf>,
+    decoder: Decoder,
+    encoder: Encoder,
+    missing_audio: MissingAudio,
+    allow_audio_only: bool,
+    decode_errors: DecodeErrors,
+}
+
+impl Preparer {
+    pub fn new(input: impl Into<PathBuf>) -> Self {
+        Self::with_source(Source::File(input.into()))
+    }
+
+    /// Prepare a container piped in on standard input, such as from
+    /// `makemkvcon`. It can't be seeked, so formats that keep their index at
+    /// the end, like most MP4 files, won't work.
+    pub fn stdin() -> Self {
+        Self::with_source(Source::Stdin)
+    }
+
+    /// Prepare a live stream, like `srt://:9000` or
+    /// `rtmp://host/app/stream`, for as long as it runs. The manifest is
+    /// dynamic so players can follow along.
+    pub fn live(uri: impl Into<String>) -> Self {
+        let mut preparer = Self::with_source(Source::Live(uri.into()));
+        preparer.dynamic_manifest = true;
+        preparer
+    }
+
+    /// Prepare a capture of the desktop and the default audio input, such as
+    /// for a demo or a lecture, until cancelled. Wayland desktops are
+    /// captured through PipeWire and X11 ones directly.
+    pub fn screen() -> Self {
+        let mut preparer = Self::with_source(Source::Screen);
+        preparer.dynamic_manifest = true;
+        preparer
+    }
+
+    /// Prepare a generated test pattern and tone instead of a file, to check
+    /// the encoding and packaging stack works.
+    pub fn test_pattern(duration: Duration) -> Self {
+        Self::with_source(Source::TestPattern(duration))
+    }
+
+    /// Prepare a slideshow of `images`, in order, each shown for
+    /// `per_image`, over `audio` if given and silence otherwise. Audio past
+    /// the last image is cut.
+    pub fn slideshow(images: Vec<PathBuf>, audio: Option<PathBuf>, per_image: Duration) -> Self {
+        Self::with_source(Source::Slideshow {
+            images,
+            audio,
+            per_image,
+        })
+    }
+
+    fn with_source(source: Source) -> Self {
+        Self {
+            source,
+            output: None,
+            profile: EncodingProfile::default(),
+            resume: false,
+            dynamic_manifest: false,
+            scratch_dir: std::env::temp_dir(),
+            cancellation: CancellationToken::new(),
+            extra_branches: Vec::new(),
+            cuts: Vec::new(),
+            chapter_starts: Vec::new(),
+            watermark: None,
+            burn_subtitles: None,
+            grade: None,
+            inverse_telecine: false,
+            repackage: false,
+            plan: None,
+            probed: None,
+            deterministic: false,
+            sync_threshold: None,
+            report_bottleneck: false,
+            qc: false,
+            failure_graph: None,
+            decoder: Decoder::default(),
+            encoder: Encoder::default(),
+            missing_audio: MissingAudio::default(),
+            allow_audio_only: false,
+            decode_errors: DecodeErrors::default(),
+        }
+    }
+
+    pub fn from_spec(spec: &JobSpec) -> Self {
+        Self::new(&spec.input)
+            .output(&spec.output)
+            .profile(spec.profile.clone())
+            .cuts(spec.cuts.clone())
+            .watermark(spec.watermark.clone())
+            .grade(spec.grade.clone())
+    }
+
+    /// Replace all encoding settings at once.
+    pub fn profile(mut self, profile: EncodingProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Video bitrates to encode, in MB/s, one representation each.
+    pub fn ladder(mut self, bitrates_mbps: impl Into<Vec<u32>>) -> Self {
+        self.profile.ladder = bitrates_mbps.into();
+        self
+    }
+
+    /// Directory to write the manifest and segments into.
+    pub fn output(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.output = Some(dir.into());
+        self
+    }
+
+    /// SVT-AV1 preset; lower is slower and better.
+    pub fn encoder_preset(mut self, preset: u32) -> Self {
+        self.profile.encoder_preset = preset;
+        self
+    }
+
+    /// Target DASH segment duration in seconds.
+    pub fn segment_duration(mut self, seconds: u32) -> Self {
+        self.profile.segment_duration = seconds;
+        self
+    }
+
+    /// Burn the running timecode into the representation at this ladder
+    /// bitrate, for collaborators to reference when giving notes.
+    pub fn timecode_rung(mut self, bitrate_mbps: Option<u32>) -> Self {
+        self.profile.timecode_rung = bitrate_mbps;
+        self
+    }
+
+    /// Skip the run if the output journal shows it already completed for this input.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Write a dynamic manifest that is updated after every segment, so
+    /// playback can start before the run finishes.
+    ///
+    /// dashsink leaves the finished manifest dynamic; rewrite it as static
+    /// afterwards if it will be served as a regular title.
+    pub fn dynamic_manifest(mut self, dynamic: bool) -> Self {
+        self.dynamic_manifest = dynamic;
+        self
+    }
+
+    /// Stage segments under `dir` instead of the system's temporary
+    /// directory, when they're retried into the output.
+    pub fn scratch_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.scratch_dir = dir.into();
+        self
+    }
+
+    /// Ranges of the input to leave out. Later timestamps move up to keep
+    /// the output continuous, and video restarts on a keyframe after each cut.
+    pub fn cuts(mut self, cuts: impl Into<Vec<Cut>>) -> Self {
+        self.cuts = cuts.into();
+        self
+    }
+
+    /// Where the source's chapters start. Each starts a segment, on a
+    /// keyframe, so seeking to a chapter starts playing at once.
+    pub fn chapter_starts(mut self, starts: impl Into<Vec<Duration>>) -> Self {
+        self.chapter_starts = starts.into();
+        self
+    }
+
+    /// Language to signal for the audio when the source doesn't tag it.
+    pub fn default_language(mut self, language: Option<String>) -> Self {
+        self.profile.default_language = language;
+        self
+    }
+
+    /// Image or text to composite over every video representation.
+    pub fn watermark(mut self, watermark: Option<Watermark>) -> Self {
+        self.watermark = watermark;
+        self
+    }
+
+    /// Subtitle file, such as SRT, to burn into every video representation
+    /// in the profile's burn-in style.
+    pub fn burn_subtitles(mut self, subtitles: Option<PathBuf>) -> Self {
+        self.burn_subtitles = subtitles;
+        self
+    }
+
+    /// Brightness, contrast and saturation adjustments and a LUT to apply
+    /// to every video representation.
+    pub fn grade(mut self, grade: Option<Grade>) -> Self {
+        self.grade = grade.filter(|grade| !grade.is_neutral());
+        self
+    }
+
+    /// Undo 3:2 pulldown, recovering 23.976 fps film from 29.97 fps video;
+    /// see [`telecine`](crate::telecine) to tell whether a source needs it.
+    pub fn inverse_telecine(mut self, inverse_telecine: bool) -> Self {
+        self.inverse_telecine = inverse_telecine;
+        self
+    }
+
+    /// Segment the source's AV1 or H.264 video as it is instead of
+    /// re-encoding it, as the only representation. Much faster, but the
+    /// source must already be within the top of the ladder; audio is still
+    /// encoded to Opus, which is cheap.
+    pub fn repackage(mut self, repackage: bool) -> Self {
+        self.repackage = repackage;
+        self
+    }
+
+    /// Copy or encode each stream as `plan` says, instead of encoding
+    /// everything; see [`plan`](crate::plan).
+    pub fn plan(mut self, plan: Plan) -> Self {
+        self.plan = Some(plan);
+        self
+    }
+
+    /// What probing the source found, for keyframes spaced by its real
+    /// framerate and rungs scaled no taller than it; see
+    /// [`probe`](crate::plan::probe).
+    pub fn probed(mut self, source: SourceInfo) -> Self {
+        self.probed = Some(source);
+        self
+    }
+
+    /// Strip wall-clock times from the segments and manifest, so runs over
+    /// the same source with the same profile write byte-identical files.
+    /// The journal still records when the run happened.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Once the run ends, warn if the audio starts or ends further than
+    /// `threshold` from the video, as sources with broken timestamps do.
+    pub fn check_sync(mut self, threshold: Option<Duration>) -> Self {
+        self.sync_threshold = threshold;
+        self
+    }
+
+    /// Sample how full the pipeline's queues run, and once the run ends
+    /// report which stage held it back.
+    pub fn report_bottleneck(mut self, report: bool) -> Self {
+        self.report_bottleneck = report;
+        self
+    }
+
+    /// Look for long stretches of black or frozen frames in the video
+    /// being encoded, and once the run ends report where they were.
+    pub fn qc(mut self, qc: bool) -> Self {
+        self.qc = qc;
+        self
+    }
+
+    /// If the run fails, write a Graphviz DOT graph of the pipeline as it
+    /// was to `path`, for bug reports.
+    pub fn failure_graph(mut self, path: Option<PathBuf>) -> Self {
+        self.failure_graph = path;
+        self
+    }
+
+    /// Which encoders to encode the ladder with, such as the GPU's; by
+    /// default, a hardware one if there is one.
+    pub fn encoder(mut self, encoder: Encoder) -> Self {
+        self.encoder = encoder;
+        self
+    }
+
+    /// Which decoders to decode the source with, such as the GPU's.
+    pub fn decoder(mut self, decoder: Decoder) -> Self {
+        self.decoder = decoder;
+        self
+    }
+
+    /// What to do if the source has no audio, which would otherwise leave
+    /// the audio branches waiting forever.
+    pub fn missing_audio(mut self, missing_audio: MissingAudio) -> Self {
+        self.missing_audio = missing_audio;
+        self
+    }
+
+    /// Prepare the audio alone if the source has no video, rather than
+    /// failing.
+    pub fn allow_audio_only(mut self, allow_audio_only: bool) -> Self {
+        self.allow_audio_only = allow_audio_only;
+        self
+    }
+
+    /// What to do where the source can't be decoded, rather than failing
+    /// on the first damaged stretch.
+    pub fn decode_errors(mut self, decode_errors: DecodeErrors) -> Self {
+        self.decode_errors = decode_errors;
+        self
+    }
+
+    /// Token the host can use to cancel the run from another thread.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Add a custom branch alongside the built-in audio and video ones.
+    pub fn branch(mut self, branch: impl PipelineBranch + 'static) -> Self {
+        self.extra_branches.push(Box::new(branch));
+        self
+    }
+
+    pub(crate) fn token(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Run the preparation to completion, blocking the calling thread.
+    ///
+    /// Use [`PrepareJob::spawn`](crate::PrepareJob::spawn) instead to follow
+    /// progress as it happens.
+    pub fn run(self) -> Result<Outcome> {
+        self.execute(&mut |_| ())
+    }
+
+    /// Build and drive the pipeline, reporting through `emit` until EOS or
+    /// an error.
+    pub(crate) fn execute(mut self, emit: &mut dyn FnMut(JobEvent)) -> Result<Outcome> {
+        gst::init()?;
+
+        let output_dir = self.output.take().context("No output directory set")?;
+        let extra_branches = std::mem::take(&mut self.extra_branches);
+        let plan = self.plan.take();
+        let profile = &self.profile;
+        profile.validate()?;
+        self.decoder.apply()?;
+        if let Some(watermark) = &self.watermark {
+            watermark.validate()?;
+        }
+        if let Some(grade) = &self.grade {
+            grade.validate()?;
+        }
+        if self.profile.packaging.sidx && self.dynamic_manifest {
+            bail!("Segment indexes are written once the run ends, too late for a dynamic manifest");
+        }
+        if self.deterministic && self.dynamic_manifest {
+            bail!("A dynamic manifest changes as it's served, so it can't be deterministic");
+        }
+        let plan = match (self.repackage, plan) {
+            (true, _) => Plan::repackage(profile),
+            (false, Some(plan)) => plan,
+            (false, None) => Plan::encode_all(profile),
+        };
+        let copy_video = plan.copies_video();
+        let decode_video = plan.encoded_rungs().next().is_some();
+        if decode_video
+            && self
+                .probed
+                .as_ref()
+                .is_some_and(|source| source.dynamic_hdr)
+        {
+            emit(JobEvent::Warning(String::from(
+                "The source's HDR10+ dynamic metadata can't be carried through the AV1 encode, so encoded rungs lose it",
+            )));
+        }
+        let copy_audio = plan.audio == Action::Copy;
+        if copy_video || copy_audio {
+            if !self.cuts.is_empty()
+                || (copy_video
+                    && (self.watermark.is_some()
+                        || self.burn_subtitles.is_some()
+                        || self.grade.is_some()
+                        || self.inverse_telecine
+                        || profile.frame_rate.is_some()))
+            {
+                bail!(
+                    "Can't cut, watermark, burn subtitles into, grade, inverse telecine or conform the frame rate of streams that are copied rather than re-encoded"
+                );
+            }
+            if !matches!(self.source, Source::File(_) | Source::Stdin) {
+                bail!("Only files can have their streams copied");
+            }
+            if extra_branches
+                .iter()
+                .any(|branch| match branch.media_type() {
+                    MediaType::Video => !decode_video,
+                    MediaType::Audio => copy_audio,
+                })
+            {
+                bail!("Extra branches need decoded streams, which copying skips");
+            }
+            if copy_audio && profile.audio.drc {
+                bail!("A compressed rendition needs decoded audio, which copying skips");
+            }
+            if copy_audio && profile.audio.mono_kbps.is_some() {
+                bail!("A mono rendition needs decoded audio, which copying skips");
+            }
+        }
+        let input = match &self.source {
+            Source::File(path) => path.display().to_string(),
+            Source::Stdin => String::from("-"),
+            Source::Live(uri) => uri.clone(),
+            Source::Screen => String::from("screen"),
+            Source::TestPattern(_) => String::from("videotestsrc"),
+            Source::Slideshow { images, .. } => {
+                match images.first().and_then(|image| image.parent()) {
+                    Some(dir) => dir.display().to_string(),
+                    None => bail!("A slideshow needs at least one image"),
+                }
+            }
+        };
+
+        // Ensure output directory exists
+        std::fs::create_dir_all(&output_dir).context(format!(
+            "Failed to create output directory: {}",
+            output_dir.display()
+        ))?;
+
+        // Keep concurrent runs from interleaving segments into the same manifest
+        let _lock = OutputLock::acquire(&output_dir)?;
+
+        // dashsink can't append to an existing presentation, so an interrupted
+        // run is re-encoded from the start; the journal tells us whether that's needed.
+        let previous = Journal::read(&output_dir)?;
+        let previous_run = journal::last_run(&previous);
+        // Nothing says a piped or live input is the same as last time
+        if self.resume
+            && matches!(self.source, Source::File(_))
+            && let Some(JournalEvent::Started {
+                input: previous_input,
+            }) = previous_run.first().map(|e| &e.event)
+            && *previous_input == input
+        {
+            if previous_run
+                .iter()
+                .any(|e| e.event == JournalEvent::Finalized)
+            {
+                return Ok(Outcome::AlreadyPrepared);
+            }
+            let segments = previous_run
+                .iter()
+                .filter(|e| matches!(e.event, JournalEvent::SegmentWritten { .. }))
+                .count();
+            emit(JobEvent::Warning(format!(
+                "Previous run was interrupted after {} segments, starting over",
+                segments
+            )));
+        }
+
+        let mut journal = Journal::open(&output_dir)?;
+        journal.record(JournalEvent::Started {
+            input: input.clone(),
+        })?;
+
+        // Space keyframes by the rate the rungs are encoded at
+        let probed = self.probed.clone().unwrap_or_default();
+        let exact_fps =
+            conform::encoded_fps(probed.framerate, profile.frame_rate, self.inverse_telecine);
+        let fps = exact_fps.round() as u32;
+
+        // Create the pipeline
+        let pipeline = gst::Pipeline::new();
+
+        let tee = gst::ElementFactory::make("tee").name("t").build()?;
+        let audio_tee = gst::ElementFactory::make("tee").name("at").build()?;
+
+        // DASH sink with output directory, or a local one segments are moved
+        // out of when writing them is to be retried
+        let mut staging = match profile.packaging.segment_retries {
+            0 => None,
+            retries => Some(SegmentStaging::new(
+                &self.scratch_dir,
+                &output_dir,
+                retries,
+            )?),
+        };
+        let mpd_root = staging.as_ref().map_or(output_dir.as_path(), |s| s.dir());
+        let dashsink = gst::ElementFactory::make("dashsink")
+            .property("mpd-filename", MANIFEST_FILENAME)
+            .property("mpd-root-path", &*mpd_root.to_string_lossy())
+            .property("target-duration", profile.segment_duration)
+            .property_from_str("muxer", "dashmp4")
+            .build()?;
+        if self.dynamic_manifest {
+            dashsink.set_property("dynamic", true);
+            dashsink.set_property("minimum-update-period", profile.segment_duration * 1000);
+        }
+        packaging::configure_muxers(&dashsink, &profile.packaging);
+        // Chapter times are the source's, which cuts would move
+        let mut chapter_starts: Vec<Duration> = match self.cuts.is_empty() {
+            true => self
+                .chapter_starts
+                .iter()
+                .copied()
+                .filter(|start| !start.is_zero())
+                .collect(),
+            false => Vec::new(),
+        };
+        chapter_starts.sort();
+        packaging::split_at(&dashsink, &chapter_starts);
+        // Scene cuts found by analysis are shared by every rung, forced as
+        // keyframes, rather than each encoder finding them again
+        let scene_cuts = match self.cuts.is_empty() {
+            true => plan.findings.scene_cuts.clone(),
+            false => Vec::new(),
+        };
+
+        // Add base elements to pipeline
+        pipeline.add_many([&tee, &audio_tee, &dashsink])?;
+
+        // Copied video is split off before decoding, for the encoded rungs
+        let copy_tee = match copy_video {
+            true => {
+                let copy_tee = gst::ElementFactory::make("tee").name("ct").build()?;
+                pipeline.add(&copy_tee)?;
+                preroll::drop_to_keyframe(
+                    &copy_tee
+                        .static_pad("sink")
+                        .context("Failed to get sink pad from copy tee")?,
+                );
+                Some(copy_tee)
+            }
+            false => None,
+        };
+
+        // Create and link the branches hanging off the audio and video tees
+        // Each encoded audio representation's loudness, in manifest order
+        let mut audio_loudness = Vec::new();
+        let mut branches: Vec<Box<dyn PipelineBranch>> = vec![match plan.audio {
+            Action::Copy => Box::new(PassthroughBranch::new(MediaType::Audio, 0)?),
+            Action::Encode => {
+                let branch = AudioBranch::new(&mut GstFactory, &profile.audio)?;
+                audio_loudness.push(branch.loudness());
+                Box::new(branch)
+            }
+        }];
+        // The compressed rendition goes last, where the manifest's roles look for it
+        let mut audio_channels = vec![profile.audio.channels];
+        if let Some(kbps) = profile.audio.mono_kbps {
+            let mono = AudioSpec {
+                bitrate_kbps: kbps,
+                channels: 1,
+                ..profile.audio.clone()
+            };
+            let branch = AudioBranch::new(&mut GstFactory, &mono)?;
+            audio_loudness.push(branch.loudness());
+            branches.push(Box::new(branch));
+            audio_channels.push(1);
+        }
+        if profile.audio.drc {
+            audio_channels.push(profile.audio.channels);
+            let branch =
+                AudioBranch::new(&mut GstFactory, &profile.audio)?.compress(&mut GstFactory)?;
+            audio_loudness.push(branch.loudness());
+            branches.push(Box::new(branch));
+        }
+        let decoder_level = profile
+            .decoder_level
+            .as_deref()
+            .map(levels::parse)
+            .transpose()?;
+        // Found up front, so a missing encoder fails before anything runs
+        let find = |codec| {
+            // The run's choice of encoders, or else the profile's
+            let encoder = match self.encoder {
+                Encoder::Auto => self.profile.encoder,
+                encoder => encoder,
+            }
+            .find(codec)?;
+            anyhow::Ok(match scene_cuts.is_empty() {
+                true => encoder,
+                false => encoder.given_scene_cuts(),
+            })
+        };
+        let av1_encoder = match decode_video {
+            true => Some(find(Codec::Av1)?),
+            false => None,
+        };
+        let h264_encoder = match profile.h264_rung {
+            Some(rung) if plan.encoded_rungs().any(|bitrate| bitrate == rung) => {
+                Some(find(Codec::H264)?)
+            }
+            _ => None,
+        };
+        for bitrate in plan.encoded_rungs() {
+            let av1_encoder = av1_encoder.context("Encoding video without decoding it")?;
+            let rung = profile.rung(bitrate);
+            let preset = rung
+                .and_then(|rung| rung.preset)
+                .unwrap_or(profile.encoder_preset);
+            let keyframe_interval = (exact_fps * profile.keyframe_secs(bitrate))
+                .round()
+                .max(1.0) as u32;
+            // Keep within what the household's decoders can play
+            let fitted =
+                decoder_level.map(|level| levels::fit(bitrate, fps, level, profile.level_policy));
+            if let Some(problem) = fitted.as_ref().and_then(|fitted| fitted.problem.clone()) {
+                emit(JobEvent::Warning(problem));
+            }
+            let mut branch = EncodingBranch::new(
+                &mut GstFactory,
+                av1_encoder,
+                fitted
+                    .as_ref()
+                    .map_or(bitrate, |fitted| fitted.bitrate_mbps),
+                preset,
+                keyframe_interval,
+            )?;
+            // Never taller than the source, even when every rung is
+            let height = rung
+                .and_then(|rung| rung.height)
+                .map(|height| probed.height.map_or(height, |source| height.min(source)));
+            let max_size = fitted.and_then(|fitted| fitted.max_size);
+            match (height, max_size) {
+                (Some(height), max_size)
+                    if max_size.is_none_or(|(_, max_height)| height <= max_height) =>
+                {
+                    branch = branch.scale_to_height(&mut GstFactory, height)?;
+                }
+                (_, Some((width, height))) => {
+                    branch = branch.limit_size(&mut GstFactory, width, height)?;
+                }
+                _ => {}
+            }
+            let caps = rung.and_then(|rung| rung.caps.as_deref());
+            if let Some(caps) = caps {
+                branch = branch.constrain(&mut GstFactory, caps)?;
+            }
+            if profile.timecode_rung == Some(bitrate) {
+                branch = branch.burn_timecode(&mut GstFactory)?;
+            }
+            branches.push(Box::new(branch));
+            // AV1's decoder levels don't apply to the compatibility rendition
+            if let Some(h264_encoder) = h264_encoder
+                && profile.h264_rung == Some(bitrate)
+            {
+                let mut branch = EncodingBranch::new(
+                    &mut GstFactory,
+                    h264_encoder,
+                    bitrate,
+                    preset,
+                    keyframe_interval,
+                )?;
+                if let Some(height) = height {
+                    branch = branch.scale_to_height(&mut GstFactory, height)?;
+                }
+                if let Some(caps) = caps {
+                    branch = branch.constrain(&mut GstFactory, caps)?;
+                }
+                branches.push(Box::new(branch));
+            }
+        }
+        branches.extend(extra_branches);
+        let qc = match (self.qc, decode_video) {
+            (true, true) => Some(QcBranch::new()?),
+            (true, false) => {
+                emit(JobEvent::Warning(String::from(
+                    "Copied video isn't decoded, so it can't be checked for black or frozen frames",
+                )));
+                None
+            }
+            (false, _) => None,
+        };
+        if let Some(qc) = &qc {
+            branches.push(Box::new(qc.clone()));
+        }
+
+        for branch in &branches {
+            let source = match branch.media_type() {
+                MediaType::Video => &tee,
+                MediaType::Audio => &audio_tee,
+            };
+            branch.add_to_pipeline(&pipeline)?;
+            branch.link(source, &dashsink)?;
+            journal.record(JournalEvent::BranchConfigured {
+                name: branch.name(),
+            })?;
+        }
+        if let Some(copy_tee) = &copy_tee {
+            for &(bitrate, action) in &plan.rungs {
+                if action == Action::Copy {
+                    let branch = PassthroughBranch::new(MediaType::Video, bitrate)?;
+                    branch.add_to_pipeline(&pipeline)?;
+                    branch.link(copy_tee, &dashsink)?;
+                    journal.record(JournalEvent::BranchConfigured {
+                        name: branch.name(),
+                    })?;
+                    branches.push(Box::new(branch));
+                }
+            }
+        }
+        // The muxers drop the audio's priming, so note it for afterwards
+        let delay_watch = DelayWatch::start(&dashsink);
+
+        // Set once the demuxer has offered every stream and none was audio,
+        // or none video
+        let audio_missing = Arc::new(AtomicBool::new(false));
+        let video_missing = Arc::new(AtomicBool::new(false));
+
+        // Cover art found in the source is saved alongside the manifest
+        let cover = Arc::new(CoverArt::new(output_dir.clone()));
+
+        // Decoded video goes through the watermark, if any, on its way to the tee
+        let video_sink = match &self.watermark {
+            Some(watermark) => {
+                WatermarkStage::new(&mut GstFactory, watermark)?.insert(&pipeline, &tee)?
+            }
+            None => tee
+                .static_pad("sink")
+                .context("Failed to get sink pad from tee")?,
+        };
+        // Subtitles are burned in under the watermark
+        let video_sink = match &self.burn_subtitles {
+            Some(subtitles) => {
+                BurnInStage::new(&mut GstFactory, subtitles, &profile.subtitles.burn_in)?
+                    .insert(&pipeline, &video_sink)?
+            }
+            None => video_sink,
+        };
+        // Before the watermark, it goes through the grade so the watermark keeps its colours
+        let video_sink = match &self.grade {
+            Some(grade) => {
+                GradeStage::new(&mut GstFactory, grade)?.insert(&pipeline, &video_sink)?
+            }
+            None => video_sink,
+        };
+        // Ahead of them, frames are dropped or repeated to the profile's rate
+        let video_sink = match profile.frame_rate {
+            Some(rate) => conform::insert(&pipeline, &video_sink, rate)?,
+            None => video_sink,
+        };
+        // Ahead of all that, the pulldown is undone so they see whole frames
+        let video_sink = match self.inverse_telecine {
+            true => telecine::insert(&pipeline, &video_sink)?,
+            false => video_sink,
+        };
+        // First of all, stretches the decoders had to drop are noted or filled
+        self.decode_errors.apply(&pipeline);
+        let video_sink = self.decode_errors.insert(&pipeline, video_sink)?;
+
+        match &self.source {
+            Source::File(_) | Source::Stdin | Source::Live(_) => {
+                // Create source and decoder elements
+                let src = match &self.source {
+                    Source::File(path) => gst::ElementFactory::make("filesrc")
+                        .name("filesrc")
+                        .property("location", &*path.to_string_lossy())
+                        .build()?,
+                    Source::Live(uri) => {
+                        gst::Element::make_from_uri(gst::URIType::Src, uri, Some("livesrc"))
+                            .context(format!("Can't receive {}", uri))?
+                    }
+                    _ => gst::ElementFactory::make("fdsrc")
+                        .name("fdsrc")
+                        .property("fd", 0i32)
+                        .build()?,
+                };
+                // Copying streams needs them demuxed and parsed, but not decoded
+                let parsed = copy_video || copy_audio;
+                let decoder = match parsed {
+                    true => "parsebin",
+                    false => self.decoder.element(),
+                };
+                let decodebin = gst::ElementFactory::make(decoder).name("d").build()?;
+                pipeline.add_many([&src, &decodebin])?;
+                src.link(&decodebin)?;
+                // Leave cover art as an image rather than a frame of video;
+                // decodebin3 only offers the streams it selects, which
+                // leaves out cover art already
+                if decoder != "decodebin3" {
+                    decodebin.connect("autoplug-continue", false, |values| {
+                        let caps = values[2].get::<gst::Caps>().ok();
+                        Some(
+                            caps.is_none_or(|caps| !cover::caps_are_cover_art(&caps))
+                                .to_value(),
+                        )
+                    });
+                }
+
+                // Handle dynamic pads from decodebin, once their caps are
+                // known. They're routed one at a time, so with several
+                // video streams, like camera angles, only one is used.
+                let decodebin_weak = decodebin.downgrade();
+                let video_sink_weak = video_sink.downgrade();
+                let audio_tee_weak = audio_tee.downgrade();
+                let pipeline_weak = pipeline.downgrade();
+                let copy_tee_weak = copy_tee.as_ref().map(|tee| tee.downgrade());
+                let cover_pads = cover.clone();
+                let has_audio = Arc::new(AtomicBool::new(false));
+                let has_audio_pads = has_audio.clone();
+                let has_video = Arc::new(AtomicBool::new(false));
+                let has_video_pads = has_video.clone();
+                let taken = Mutex::new(Taken::default());
+
+                let route_pad = Arc::new(move |src_pad: &gst::Pad, caps: &gst::Caps| {
+                    let (Some(dbin), Some(video_sink), Some(audio_tee), Some(pipeline)) = (
+                        decodebin_weak.upgrade(),
+                        video_sink_weak.upgrade(),
+                        audio_tee_weak.upgrade(),
+                        pipeline_weak.upgrade(),
+                    ) else {
+                        return;
+                    };
+                    let Some(structure) = caps.structure(0) else {
+                        gst::element_error!(
+                            dbin,
+                            gst::StreamError::Format,
+                            ("The source offered a stream with empty caps")
+                        );
+                        return;
+                    };
+                    let name = structure.name().as_str();
+                    let audio_sink = audio_tee.static_pad("sink").unwrap();
+
+                    let mut taken = taken.lock().unwrap();
+                    let linked = match pads::route(name, cover::caps_are_cover_art(caps), *taken) {
+                        Route::CoverArt => {
+                            if let Err(err) = cover_pads.capture(&pipeline, src_pad) {
+                                eprintln!("Warning: failed to save the cover art: {:#}", err);
+                            }
+                            Ok(())
+                        }
+                        Route::ExtraVideo => {
+                            gst::element_warning!(
+                                dbin,
+                                gst::StreamError::Demux,
+                                (
+                                    "Leaving out another video stream ({}), like a second camera angle",
+                                    src_pad.name()
+                                )
+                            );
+                            Ok(())
+                        }
+                        Route::Ignore => Ok(()),
+                        Route::Video => {
+                            taken.video = true;
+                            has_video_pads.store(true, Ordering::Relaxed);
+                            let copy_tee = copy_tee_weak.as_ref().and_then(|tee| tee.upgrade());
+                            match copy_tee {
+                                _ if !parsed => src_pad
+                                    .link(&video_sink)
+                                    .map(|_| ())
+                                    .context("Failed to link decodebin video to tee"),
+                                Some(_) if !REPACKAGEABLE.contains(&name) => Err(anyhow::anyhow!(
+                                    "Can't copy {} video; encode it instead",
+                                    name
+                                )),
+                                Some(copy_tee) => src_pad
+                                    .link(&copy_tee.static_pad("sink").unwrap())
+                                    .context("Failed to link parsebin video to tee")
+                                    .and_then(|_| match decode_video {
+                                        true => {
+                                            let src_pad = copy_tee
+                                                .request_pad_simple("src_%u")
+                                                .context("Failed to get a pad from the copy tee")?;
+                                            decode_into(&pipeline, &src_pad, video_sink)
+                                        }
+                                        false => Ok(()),
+                                    }),
+                                None => decode_into(&pipeline, src_pad, video_sink),
+                            }
+                        }
+                        Route::Audio => {
+                            taken.audio = true;
+                            has_audio_pads.store(true, Ordering::Relaxed);
+                            match parsed && !copy_audio {
+                                true => decode_into(&pipeline, src_pad, audio_sink),
+                                false => src_pad
+                                    .link(&audio_sink)
+                                    .map(|_| ())
+                                    .context("Failed to link decodebin audio to tee"),
+                            }
+                        }
+                    };
+                    if let Err(err) = linked {
+                        gst::element_error!(dbin, gst::StreamError::Format, ("{:#}", err));
+                    }
+                });
+                // A source without audio or video would leave the branches
+                // for it waiting. What it has is known once it's added its
+                // last pad and every pad has its caps.
+                let audio_tee_weak = audio_tee.downgrade();
+                let pipeline_weak = pipeline.downgrade();
+                let dashsink_weak = dashsink.downgrade();
+                let video_pads = [Some(&tee), copy_tee.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|tee| tee.static_pad("sink"))
+                    .collect::<Vec<_>>();
+                // The video's branches start at the watermark, if there is one
+                let video_starts = [video_sink.parent_element(), copy_tee.clone()]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>();
+                let missing_audio = self.missing_audio;
+                let allow_audio_only = self.allow_audio_only;
+                let audio_missing = audio_missing.clone();
+                let video_missing = video_missing.clone();
+                let check_streams = Arc::new(move |dbin: &gst::Element| {
+                    let (Some(pipeline), Some(audio_tee), Some(dashsink)) = (
+                        pipeline_weak.upgrade(),
+                        audio_tee_weak.upgrade(),
+                        dashsink_weak.upgrade(),
+                    ) else {
+                        return;
+                    };
+                    let has_audio = has_audio.load(Ordering::Relaxed);
+                    let has_video = has_video.load(Ordering::Relaxed);
+                    if !has_video {
+                        video_missing.store(true, Ordering::Relaxed);
+                        if !allow_audio_only || !has_audio {
+                            gst::element_error!(
+                                dbin,
+                                gst::StreamError::WrongType,
+                                ("The source has no video; allow audio-only output to prepare its audio alone")
+                            );
+                            return;
+                        }
+                        for start in &video_starts {
+                            if let Err(err) = remove_downstream(&pipeline, start, &dashsink) {
+                                gst::element_error!(
+                                    dbin,
+                                    gst::CoreError::Negotiation,
+                                    ("Failed to leave out the video: {:#}", err)
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    if has_audio {
+                        return;
+                    }
+                    audio_missing.store(true, Ordering::Relaxed);
+                    let handled = match missing_audio {
+                        MissingAudio::Skip => remove_downstream(&pipeline, &audio_tee, &dashsink),
+                        MissingAudio::Silence => add_silence(&pipeline, &audio_tee, &video_pads),
+                    };
+                    if let Err(err) = handled {
+                        gst::element_error!(
+                            dbin,
+                            gst::CoreError::Negotiation,
+                            ("Failed to handle the missing audio: {:#}", err)
+                        );
+                    }
+                });
+                let pending = Arc::new(Mutex::new(Pending::default()));
+                let (pad_pending, pad_check) = (pending.clone(), check_streams.clone());
+                decodebin.connect_pad_added(move |dbin, src_pad| {
+                    pad_pending.lock().unwrap().add();
+                    let (route_pad, pending, check_streams) =
+                        (route_pad.clone(), pad_pending.clone(), pad_check.clone());
+                    let dbin = dbin.downgrade();
+                    pads::with_caps(src_pad, move |pad, caps| {
+                        route_pad(pad, caps);
+                        if pending.lock().unwrap().routed()
+                            && let Some(dbin) = dbin.upgrade()
+                        {
+                            check_streams(&dbin);
+                        }
+                    });
+                });
+                decodebin.connect_no_more_pads(move |dbin| {
+                    if pending.lock().unwrap().finish() {
+                        check_streams(dbin);
+                    }
+                });
+            }
+            Source::TestPattern(duration) => {
+                add_test_sources(&pipeline, *duration, &video_sink, &audio_tee)?
+            }
+            Source::Screen => add_screen_sources(&pipeline, &video_sink, &audio_tee)?,
+            Source::Slideshow {
+                images,
+                audio,
+                per_image,
+            } => add_slideshow_sources(
+                &pipeline,
+                images,
+                audio.as_deref(),
+                *per_image,
+                &video_sink,
+                &audio_tee,
+            )?,
+        }
+
+        if !self.cuts.is_empty() {
+            let splice = Arc::new(Splice::new(self.cuts.clone()));
+            for (tee, video) in [(&tee, true), (&audio_tee, false)] {
+                let pad = tee
+                    .static_pad("sink")
+                    .context("Failed to get sink pad from tee")?;
+                add_splice_probe(&pad, splice.clone(), video);
+            }
+        }
+        // The first frame too, so the first segment starts playing at once
+        let mut keyframes: Vec<Duration> = std::iter::once(Duration::ZERO)
+            .chain(chapter_starts)
+            .chain(scene_cuts)
+            .collect();
+        keyframes.sort();
+        keyframes.dedup();
+        let pad = tee
+            .static_pad("sink")
+            .context("Failed to get sink pad from tee")?;
+        add_keyframe_probe(&pad, keyframes);
+
+        // Remember the language the source tags its audio with
+        let audio_language = Arc::new(Mutex::new(None));
+        let audio_language_probe = audio_language.clone();
+        audio_tee
+            .static_pad("sink")
+            .context("Failed to get sink pad from tee")?
+            .add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+                if let Some(gst::EventView::Tag(tag)) = info.event().map(|e| e.view())
+                    && let Some(code) = tag.tag().get::<gst::tags::LanguageCode>()
+                    && let Some(language) = language::normalize(code.get())
+                {
+                    *audio_language_probe.lock().unwrap() = Some(language);
+                }
+                gst::PadProbeReturn::Ok
+            });
+
+        // Count decoded video frames entering the encoding branches, or the
+        // copied ones when nothing is encoded
+        let frame_count = Arc::new(AtomicU64::new(0));
+        let frame_count_probe = frame_count.clone();
+        let counted = match &copy_tee {
+            Some(copy_tee) if !decode_video => copy_tee,
+            _ => &tee,
+        };
+        counted
+            .static_pad("sink")
+            .context("Failed to get sink pad from tee")?
+            .add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                frame_count_probe.fetch_add(1, Ordering::Relaxed);
+                gst::PadProbeReturn::Ok
+            });
+
+        let sync_check = Arc::new(SyncCheck::default());
+        if self.sync_threshold.is_some() {
+            for (tee, video) in [(counted, true), (&audio_tee, false)] {
+                let pad = tee
+                    .static_pad("sink")
+                    .context("Failed to get sink pad from tee")?;
+                sync_check.watch(&pad, video);
+            }
+        }
+
+        pipeline.set_state(gst::State::Playing)?;
+        let started = Instant::now();
+        let started_at = SystemTime::now();
+        let mut eos_sent = false;
+        let mut paused = false;
+        let mut bitrate_checked = false;
+        let mut queues = self.report_bottleneck.then(QueueSampler::default);
+
+        // Wait until error or EOS, sampling progress while we wait
+        let bus = pipeline.bus().unwrap();
+        let result = loop {
+            use gst::MessageView;
+
+            if self.cancellation.is_paused() != paused {
+                paused = !paused;
+                pipeline.set_state(match paused {
+                    true => gst::State::Paused,
+                    false => gst::State::Playing,
+                })?;
+            }
+
+            // Let EOS flow through so dashsink closes the manifest cleanly
+            if !eos_sent && self.cancellation.is_cancelled() {
+                pipeline.send_event(gst::event::Eos::new());
+                eos_sent = true;
+            }
+
+            let Some(msg) = bus.timed_pop(gst::ClockTime::from_mseconds(500)) else {
+                let position = pipeline.query_position::<gst::ClockTime>();
+                let duration = pipeline.query_duration::<gst::ClockTime>();
+                let fraction = match (position, duration) {
+                    (Some(position), Some(duration)) if duration > gst::ClockTime::ZERO => {
+                        position.nseconds() as f64 / duration.nseconds() as f64
+                    }
+                    _ => 0.0,
+                };
+                if let Some(queues) = &mut queues {
+                    queues.sample(&pipeline);
+                }
+                let frames = frame_count.load(Ordering::Relaxed);
+                let elapsed = started.elapsed().as_secs_f64();
+                emit(JobEvent::Progress(Progress {
+                    fraction,
+                    frames,
+                    fps: frames as f64 / elapsed,
+                    // The first percent is mostly startup
+                    eta_secs: (fraction > 0.01).then(|| elapsed * (1.0 - fraction) / fraction),
+                }));
+                continue;
+            };
+
+            match msg.view() {
+                MessageView::Eos(..) => {
+                    if let Some(policy) = self.cancellation.policy() {
+                        journal.record(JournalEvent::Cancelled { policy })?;
+                        break Ok(Some(policy));
+                    }
+                    journal.record(JournalEvent::Finalized)?;
+                    let dropped_audio = audio_missing.load(Ordering::Relaxed)
+                        && self.missing_audio == MissingAudio::Skip;
+                    let dropped_video = video_missing.load(Ordering::Relaxed);
+                    if let Some(threshold) = self.sync_threshold
+                        && !dropped_audio
+                        && !dropped_video
+                    {
+                        for problem in sync_check.problems(threshold) {
+                            emit(JobEvent::Warning(problem));
+                        }
+                    }
+                    let duration = pipeline.query_duration::<gst::ClockTime>();
+                    for stats in branches
+                        .iter()
+                        .filter(|b| match b.media_type() {
+                            MediaType::Audio => !dropped_audio,
+                            MediaType::Video => !dropped_video,
+                        })
+                        .filter_map(|b| b.stats(duration))
+                    {
+                        emit(JobEvent::BranchStats(stats));
+                    }
+                    if let Some(queues) = &queues {
+                        emit(JobEvent::Bottleneck(queues.report()));
+                    }
+                    if let Some(qc) = &qc {
+                        emit(JobEvent::Qc(qc.report()));
+                    }
+                    break Ok(None);
+                }
+                MessageView::AsyncDone(..) if copy_video && !bitrate_checked => {
+                    bitrate_checked = true;
+                    // Players expect no representation above the top rung
+                    if let Source::File(path) = &self.source
+                        && let Some(duration) = pipeline.query_duration::<gst::ClockTime>()
+                        && duration > gst::ClockTime::ZERO
+                    {
+                        let bytes = std::fs::metadata(path)?.len();
+                        let kbps = bytes as f64 * 8.0 / 1000.0 / duration.seconds_f64();
+                        let top_rung = profile.ladder.iter().copied().max().unwrap_or_default();
+                        if kbps > top_rung as f64 * 1000.0 {
+                            let error = format!(
+                                "{} averages {:.0} kb/s, above the top of the ladder; encode it instead",
+                                path.display(),
+                                kbps
+                            );
+                            journal.record(JournalEvent::Failed {
+                                error: error.clone(),
+                            })?;
+                            break Err(anyhow::anyhow!(error));
+                        }
+                    }
+                }
+                MessageView::Tag(tag) => {
+                    if let Err(err) = cover.save_from_tags(&tag.tags()) {
+                        emit(JobEvent::Warning(format!(
+                            "Failed to save the cover art: {:#}",
+                            err
+                        )));
+                    }
+                }
+                MessageView::Warning(warning) => {
+                    emit(JobEvent::Warning(format!(
+                        "{} ({:?})",
+                        warning.error(),
+                        warning.debug()
+                    )));
+                }
+                MessageView::Error(err) => {
+                    if let Some(path) = &self.failure_graph {
+                        let graph = pipeline.debug_to_dot_data(gst::DebugGraphDetails::all());
+                        std::fs::write(path, graph.as_str())
+                            .context(format!("Failed to write {}", path.display()))?;
+                    }
+                    journal.record(JournalEvent::Failed {
+                        error: err.error().to_string(),
+                    })?;
+                    break Err(anyhow::anyhow!(
+                        "Error from {:?}: {} ({:?})",
+                        err.src().map(|s| s.path_string()),
+                        err.error(),
+                        err.debug()
+                    ));
+                }
+                MessageView::Element(element) => {
+                    if let Some(s) = element.structure()
+                        && s.name() == "splitmuxsink-fragment-closed"
+                        && let Ok(location) = s.get::<String>("location")
+                    {
+                        let location = match staging.as_mut() {
+                            Some(staging) => match staging.segment_closed(Path::new(&location)) {
+                                Ok((location, retried)) => {
+                                    record_retries(&mut journal, &retried)?;
+                                    location.to_string_lossy().into_owned()
+                                }
+                                Err(err) => {
+                                    journal.record(JournalEvent::Failed {
+                                        error: format!("{:#}", err),
+                                    })?;
+                                    break Err(err);
+                                }
+                            },
+                            None => location,
+                        };
+                        journal.record(JournalEvent::SegmentWritten { location })?;
+                        // dashsink has just rewritten the manifest for the new segment
+                        if self.dynamic_manifest
+                            && let Some(url) = &profile.packaging.utc_timing
+                        {
+                            set_manifest_utc_timing(&output_dir, url)?;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        };
+
+        // Clean up
+        pipeline.set_state(gst::State::Null)?;
+        let result = result?;
+        if let Some(staging) = staging.take() {
+            match staging.finish() {
+                Ok(retried) => record_retries(&mut journal, &retried)?,
+                Err(err) => {
+                    journal.record(JournalEvent::Failed {
+                        error: format!("{:#}", err),
+                    })?;
+                    return Err(err);
+                }
+            }
+        }
+        match result {
+            Some(CancelPolicy::Discard) => {
+                discard_output(&output_dir, started_at)?;
+                return Ok(Outcome::Cancelled(CancelPolicy::Discard));
+            }
+            Some(policy) => return Ok(Outcome::Cancelled(policy)),
+            None => (),
+        }
+
+        if video_missing.load(Ordering::Relaxed) {
+            emit(JobEvent::Warning(String::from(
+                "The source has no video, so only its audio was prepared",
+            )));
+        }
+        let has_audio = match (audio_missing.load(Ordering::Relaxed), self.missing_audio) {
+            (false, _) => true,
+            (true, MissingAudio::Skip) => {
+                emit(JobEvent::Warning(String::from(
+                    "The source has no audio, so neither does the presentation",
+                )));
+                false
+            }
+            (true, MissingAudio::Silence) => {
+                emit(JobEvent::Warning(String::from(
+                    "The source has no audio, so the presentation's is silent",
+                )));
+                true
+            }
+        };
+
+        // dashsink doesn't signal languages, so add them to the manifest
+        let audio_language = audio_language.lock().unwrap().take().or_else(|| {
+            profile
+                .default_language
+                .as_deref()
+                .and_then(language::normalize)
+        });
+        if has_audio && let Some(audio_language) = audio_language {
+            let manifest = output_dir.join(MANIFEST_FILENAME);
+            let xml = std::fs::read_to_string(&manifest)
+                .context(format!("Failed to read {}", manifest.display()))?;
+            std::fs::write(
+                &manifest,
+                language::tag_manifest(&xml, "audio", &audio_language)?,
+            )
+            .context(format!("Failed to write {}", manifest.display()))?;
+        }
+        // Nor loudness, which is written into the audio's init segments too
+        let measured = loudness::signal(&output_dir, &audio_loudness)?;
+        if has_audio && measured.iter().any(Option::is_some) {
+            let manifest = output_dir.join(MANIFEST_FILENAME);
+            let xml = std::fs::read_to_string(&manifest)
+                .context(format!("Failed to read {}", manifest.display()))?;
+            std::fs::write(&manifest, loudness::tag_manifest(&xml, &measured)?)
+                .context(format!("Failed to write {}", manifest.display()))?;
+        }
+        // Nor channel counts, when copied audio's aren't known
+        if has_audio && plan.audio == Action::Encode {
+            let manifest = output_dir.join(MANIFEST_FILENAME);
+            let xml = std::fs::read_to_string(&manifest)
+                .context(format!("Failed to read {}", manifest.display()))?;
+            std::fs::write(&manifest, channels::tag_manifest(&xml, &audio_channels)?)
+                .context(format!("Failed to write {}", manifest.display()))?;
+        }
+        // Nor roles, which players offer the compressed rendition by
+        if has_audio && profile.audio.drc {
+            let manifest = output_dir.join(MANIFEST_FILENAME);
+            let xml = std::fs::read_to_string(&manifest)
+                .context(format!("Failed to read {}", manifest.display()))?;
+            std::fs::write(
+                &manifest,
+                roles::set_role_of_last(&xml, "audio", roles::ENHANCED_INTELLIGIBILITY)?,
+            )
+            .context(format!("Failed to write {}", manifest.display()))?;
+        }
+
+        // Signal the level each rung needs rather than what the parser guessed
+        let codec_level = profile
+            .codec_level
+            .as_deref()
+            .map(levels::parse)
+            .transpose()?;
+        let manifest = output_dir.join(MANIFEST_FILENAME);
+        let xml = std::fs::read_to_string(&manifest)
+            .context(format!("Failed to read {}", manifest.display()))?;
+        std::fs::write(&manifest, levels::tag_manifest(&xml, codec_level, fps)?)
+            .context(format!("Failed to write {}", manifest.display()))?;
+        if let Some(url) = &profile.packaging.utc_timing {
+            set_manifest_utc_timing(&output_dir, url)?;
+        }
+        gapless::signal(&output_dir, &delay_watch.delays())?;
+        for problem in preroll::check(&output_dir)? {
+            emit(JobEvent::Warning(problem));
+        }
+        if profile.packaging.sidx {
+            sidx::index_segments(&output_dir)?;
+        }
+        if self.deterministic {
+            reproducible::scrub(&output_dir, MANIFEST_FILENAME)?;
+        }
+
+        Ok(Outcome::Prepared(Summary {
+            frames: frame_count.load(Ordering::Relaxed),
+            elapsed: started.elapsed(),
+        }))
+    }
+}
+
+/// Record the segment writes that had to be retried.
+fn record_retries(journal: &mut Journal, retried: &[Retried]) -> Result<()> {
+    for retried in retried {
+        journal.record(JournalEvent::SegmentRetried {
+            location: retried.location.to_string_lossy().into_owned(),
+            failed: retried.failed,
+        })?;
+    }
+    Ok(())
+}
+
+fn set_manifest_utc_timing(output_dir: &Path, url: &str) -> Result<()> {
+    let manifest = output_dir.join(MANIFEST_FILENAME);
+    let xml = std::fs::read_to_string(&manifest)
+        .context(format!("Failed to read {}", manifest.display()))?;
+    std::fs::write(&manifest, packaging::set_utc_timing(&xml, url)?)
+        .context(format!("Failed to write {}", manifest.display()))
+}
+
+/// Take out `start` and every element downstream of it up to `dashsink`,
+/// releasing the dashsink pads they fed, for a stream the source turns out
+/// not to have.
+fn remove_downstream(
+    pipeline: &gst::Pipeline,
+    start: &gst::Element,
+    dashsink: &gst::Element,
+) -> Result<()> {
+    let mut elements = vec![start.clone()];
+    let mut pending = start.src_pads();
+    while let Some(pad) = pending.pop() {
+        let Some(peer) = pad.peer() else {
+            continue;
+        };
+        let Some(element) = peer.parent_element() else {
+            continue;
+        };
+        if element == *dashsink {
+            pad.unlink(&peer)?;
+            dashsink.release_request_pad(&peer);
+        } else if !elements.contains(&element) {
+            pending.extend(element.src_pads());
+            elements.push(element);
+        }
+    }
+    for element in &elements {
+        element.set_state(gst::State::Null)?;
+        pipeline.remove(element)?;
+    }
+    Ok(())
+}
+
+/// Feed silence into `audio_tee` until EOS reaches any of `video_pads`.
+fn add_silence(
+    pipeline: &gst::Pipeline,
+    audio_tee: &gst::Element,
+    video_pads: &[gst::Pad],
+) -> Result<()> {
+    let silence = gst::ElementFactory::make("audiotestsrc")
+        .property_from_str("wave", "silence")
+        .build()?;
+    let audiocaps = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("audio/x-raw")
+                .field("rate", 48000)
+                .field("channels", 2)
+                .build(),
+        )
+        .build()?;
+    pipeline.add_many([&silence, &audiocaps])?;
+    gst::Element::link_many([&silence, &audiocaps, audio_tee])?;
+    audiocaps.sync_state_with_parent()?;
+    silence.sync_state_with_parent()?;
+
+    // The silence ends with the video
+    let ended = Arc::new(AtomicBool::new(false));
+    for pad in video_pads {
+        let silence = silence.downgrade();
+        let ended = ended.clone();
+        pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            if let Some(gst::EventView::Eos(..)) = info.event().map(|event| event.view())
+                && !ended.swap(true, Ordering::Relaxed)
+                && let Some(silence) = silence.upgrade()
+            {
+                silence.send_event(gst::event::Eos::new());
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+    Ok(())
+}
+
+/// Feed `videotestsrc` and `audiotestsrc` into the video and audio tees in
+/// place of a decoded file.
+/// Decode the parsed stream on `src_pad` into `sink_pad`, for streams that
+/// are encoded when others are copied.
+fn decode_into(pipeline: &gst::Pipeline, src_pad: &gst::Pad, sink_pad: gst::Pad) -> Result<()> {
+    let decodebin = gst::ElementFactory::make("decodebin").build()?;
+    pipeline.add(&decodebin)?;
+    decodebin.sync_state_with_parent()?;
+    src_pad
+        .link(&decodebin.static_pad("sink").unwrap())
+        .context("Failed to link parsed stream to decodebin")?;
+    decodebin.connect_pad_added(move |dbin, pad| {
+        if !sink_pad.is_linked()
+            && let Err(err) = pad.link(&sink_pad)
+        {
+            gst::element_error!(
+                dbin,
+                gst::CoreError::Negotiation,
+                ("Failed to link decoded stream: {}", err)
+            );
+        }
+    });
+    Ok(())
+}
+
+/// Capture the desktop and the default audio input.
+fn add_screen_sources(
+    pipeline: &gst::Pipeline,
+    video_sink: &gst::Pad,
+    audio_tee: &gst::Element,
+) -> Result<()> {
+    const FPS: i32 = 30;
+
+    let screen = match std::env::var_os("WAYLAND_DISPLAY") {
+        Some(_) => gst::ElementFactory::make("pipewiresrc").build()?,
+        None => gst::ElementFactory::make("ximagesrc")
+            .property("use-damage", false)
+            .build()?,
+    };
+    let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+    let videorate = gst::ElementFactory::make("videorate").build()?;
+    let videocaps = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("framerate", gst::Fraction::new(FPS, 1))
+                .build(),
+        )
+        .build()?;
+    let pulsesrc = gst::ElementFactory::make("pulsesrc").build()?;
+    let audioconvert = gst::ElementFactory::make("audioconvert").build()?;
+    let audioresample = gst::ElementFactory::make("audioresample").build()?;
+
+    pipeline.add_many([
+        &screen,
+        &videoconvert,
+        &videorate,
+        &videocaps,
+        &pulsesrc,
+        &audioconvert,
+        &audioresample,
+    ])?;
+    gst::Element::link_many([&screen, &videoconvert, &videorate, &videocaps])?;
+    videocaps
+        .static_pad("src")
+        .context("Failed to get src pad from capsfilter")?
+        .link(video_sink)?;
+    gst::Element::link_many([&pulsesrc, &audioconvert, &audioresample, audio_tee])?;
+    Ok(())
+}
+
+fn add_test_sources(
+    pipeline: &gst::Pipeline,
+    duration: Duration,
+    video_sink: &gst::Pad,
+    audio_tee: &gst::Element,
+) -> Result<()> {
+    const FPS: u64 = 30;
+    const RATE: u64 = 48000;
+    const SAMPLES_PER_BUFFER: u64 = 1024;
+    let millis = duration.as_millis() as u64;
+
+    let videotestsrc = gst::ElementFactory::make("videotestsrc")
+        .property("num-buffers", (millis * FPS / 1000) as i32)
+        .property_from_str("pattern", "smpte")
+        .build()?;
+    let videocaps = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("width", 1280)
+                .field("height", 720)
+                .field("framerate", gst::Fraction::new(FPS as i32, 1))
+                .build(),
+        )
+        .build()?;
+    let audiotestsrc = gst::ElementFactory::make("audiotestsrc")
+        .property(
+            "num-buffers",
+            (millis * RATE).div_ceil(1000 * SAMPLES_PER_BUFFER) as i32,
+        )
+        .property("samplesperbuffer", SAMPLES_PER_BUFFER as i32)
+        .build()?;
+    let audiocaps = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("audio/x-raw")
+                .field("rate", RATE as i32)
+                .field("channels", 2)
+                .build(),
+        )
+        .build()?;
+
+    pipeline.add_many([&videotestsrc, &videocaps, &audiotestsrc, &audiocaps])?;
+    videotestsrc.link(&videocaps)?;
+    videocaps
+        .static_pad("src")
+        .context("Failed to get src pad from capsfilter")?
+        .link(video_sink)?;
+    gst::Element::link_many([&audiotestsrc, &audiocaps, audio_tee])?;
+    Ok(())
+}
+
+/// Show each of `images` for `per_image`, scaled into a 1080p frame, over
+/// `audio` or silence.
+fn add_slideshow_sources(
+    pipeline: &gst::Pipeline,
+    images: &[PathBuf],
+    audio: Option<&Path>,
+    per_image: Duration,
+    video_sink: &gst::Pad,
+    audio_tee: &gst::Element,
+) -> Result<()> {
+    const FPS: u64 = 30;
+    let frames = (per_image.as_millis() as u64 * FPS / 1000).max(1);
+    let end = gst::ClockTime::from_nseconds(frames * images.len() as u64 * 1_000_000_000 / FPS);
+    let frame_caps = gst::Caps::builder("video/x-raw")
+        .field("width", 1920)
+        .field("height", 1080)
+        .field("pixel-aspect-ratio", gst::Fraction::new(1, 1))
+        .field("framerate", gst::Fraction::new(FPS as i32, 1))
+        .build();
+
+    // Each image is frozen into a clip of its own, and the clips played in turn
+    let concat = gst::ElementFactory::make("concat").build()?;
+    pipeline.add(&concat)?;
+    concat
+        .static_pad("src")
+        .context("Failed to get src pad from concat")?
+        .link(video_sink)?;
+    for image in images {
+        let filesrc = gst::ElementFactory::make("filesrc")
+            .property("location", image)
+            .build()?;
+        let decodebin = gst::ElementFactory::make("decodebin").build()?;
+        let imagefreeze = gst::ElementFactory::make("imagefreeze")
+            .property("num-buffers", frames as i32)
+            .build()?;
+        let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+        let videoscale = gst::ElementFactory::make("videoscale")
+            .property("add-borders", true)
+            .build()?;
+        let videocaps = gst::ElementFactory::make("capsfilter")
+            .property("caps", &frame_caps)
+            .build()?;
+        pipeline.add_many([
+            &filesrc,
+            &decodebin,
+            &imagefreeze,
+            &videoconvert,
+            &videoscale,
+            &videocaps,
+        ])?;
+        filesrc.link(&decodebin)?;
+        gst::Element::link_many([
+            &imagefreeze,
+            &videoconvert,
+            &videoscale,
+            &videocaps,
+            &concat,
+        ])?;
+
+        let freeze_sink = imagefreeze
+            .static_pad("sink")
+            .context("Failed to get sink pad from imagefreeze")?;
+        decodebin.connect_pad_added(move |dbin, src_pad| {
+            if !freeze_sink.is_linked()
+                && let Err(err) = src_pad.link(&freeze_sink)
+            {
+                gst::element_error!(
+                    dbin,
+                    gst::CoreError::Negotiation,
+                    ("Failed to link the decoded image: {}", err)
+                );
+            }
+        });
+    }
+
+    match audio {
+        Some(path) => {
+            let filesrc = gst::ElementFactory::make("filesrc")
+                .property("location", path)
+                .build()?;
+            let decodebin = gst::ElementFactory::make("decodebin").build()?;
+            pipeline.add_many([&filesrc, &decodebin])?;
+            filesrc.link(&decodebin)?;
+            let audio_sink = audio_tee
+                .static_pad("sink")
+                .context("Failed to get sink pad from tee")?;
+            decodebin.connect_pad_added(move |dbin, src_pad| {
+                let (audio_sink, dbin) = (audio_sink.clone(), dbin.downgrade());
+                pads::with_caps(src_pad, move |pad, caps| {
+                    let is_audio = caps
+                        .structure(0)
+                        .is_some_and(|s| s.name().starts_with("audio/"));
+                    if is_audio
+                        && !audio_sink.is_linked()
+                        && let Err(err) = pad.link(&audio_sink)
+                        && let Some(dbin) = dbin.upgrade()
+                    {
+                        gst::element_error!(
+                            dbin,
+                            gst::CoreError::Negotiation,
+                            ("Failed to link the decoded audio: {}", err)
+                        );
+                    }
+                });
+            });
+        }
+        None => {
+            const RATE: u64 = 48000;
+            const SAMPLES_PER_BUFFER: u64 = 1024;
+            let silence = gst::ElementFactory::make("audiotestsrc")
+                .property_from_str("wave", "silence")
+                .property(
+                    "num-buffers",
+                    (end.mseconds() * RATE).div_ceil(1000 * SAMPLES_PER_BUFFER) as i32,
+                )
+                .property("samplesperbuffer", SAMPLES_PER_BUFFER as i32)
+                .build()?;
+            let audiocaps = gst::ElementFactory::make("capsfilter")
+                .property(
+                    "caps",
+                    gst::Caps::builder("audio/x-raw")
+                        .field("rate", RATE as i32)
+                        .field("channels", 2)
+                        .build(),
+                )
+                .build()?;
+            pipeline.add_many([&silence, &audiocaps])?;
+            gst::Element::link_many([&silence, &audiocaps, audio_tee])?;
+        }
+    }
+
+    // Nothing plays past the last image
+    audio_tee
+        .static_pad("sink")
+        .context("Failed to get sink pad from tee")?
+        .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            match info.buffer().and_then(|buffer| buffer.pts()) {
+                Some(pts) if pts >= end => gst::PadProbeReturn::Drop,
+                _ => gst::PadProbeReturn::Ok,
+            }
+        });
+    Ok(())
+}
+
+/// Drop buffers inside cuts and move later ones up to close the gaps. On
+/// video, ask the encoders for a keyframe where each cut closes so a new
+/// segment can start there.
+fn add_splice_probe(pad: &gst::Pad, splice: Arc<Splice>, video: bool) {
+    let previous = Mutex::new(None);
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let Some(gst::PadProbeData::Buffer(buffer)) = info.data.as_mut() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let Some(pts) = buffer.pts() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let input_time = Duration::from_nanos(pts.nseconds());
+        let placement = splice.place(input_time, previous.lock().unwrap().replace(input_time));
+        let Placement::Kept { time, after_cut } = placement else {
+            return gst::PadProbeReturn::Drop;
+        };
+
+        let shift = input_time - time;
+        let buffer = buffer.make_mut();
+        buffer.set_pts(gst::ClockTime::from_nseconds(time.as_nanos() as u64));
+        if let Some(dts) = buffer.dts() {
+            buffer.set_dts(
+                dts.saturating_sub(gst::ClockTime::from_nseconds(shift.as_nanos() as u64)),
+            );
+        }
+        if video && after_cut {
+            force_key_unit(pad, time);
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Ask the encoders downstream of `pad` for a keyframe at `time`.
+fn force_key_unit(pad: &gst::Pad, time: Duration) {
+    let timestamp = gst::ClockTime::from_nseconds(time.as_nanos() as u64);
+    let structure = gst::Structure::builder("GstForceKeyUnit")
+        .field("timestamp", timestamp)
+        .field("stream-time", timestamp)
+        .field("running-time", timestamp)
+        .field("all-headers", true)
+        .field("count", 0u32)
+        .build();
+    pad.push_event(gst::event::CustomDownstream::new(structure));
+}
+
+/// Ask the encoders for a keyframe on the first frame at or after each of
+/// `times`, in order, so segments can start there.
+fn add_keyframe_probe(pad: &gst::Pad, times: Vec<Duration>) {
+    let next = Mutex::new(0);
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let Some(pts) = info.buffer().and_then(|buffer| buffer.pts()) else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let time = Duration::from_nanos(pts.nseconds());
+        let mut next = next.lock().unwrap();
+        let passed = times[*next..]
+            .iter()
+            .take_while(|start| **start <= time)
+            .count();
+        if passed > 0 {
+            *next += passed;
+            force_key_unit(pad, time);
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Remove everything the run wrote, keeping the journal and lock file.
+fn discard_output(output_dir: &Path, since: SystemTime) -> Result<()> {
+    for entry in std::fs::read_dir(output_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == JOURNAL_FILENAME || name == LOCK_FILENAME {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && metadata.modified()? >= since {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
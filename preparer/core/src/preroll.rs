@@ -0,0 +1,272 @@
+//! Making sure a prepared title starts playing at once. TVs tend to show
+//! black for seconds when the first segment of the video doesn't start
+//! with a keyframe, as they can't decode anything before the next one, or
+//! when the video and audio don't start together, as they wait for both.
+//!
+//! Encoders are asked for a keyframe on the very first frame, and copied
+//! video is dropped up to its first one. Once the run ends, the first
+//! segment of each representation is read back to check, with the audio
+//! taken to start where its edit list skips the priming to.
+
+use crate::sidx::{self, INIT_SUFFIX, u32_at, u64_at};
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How far apart, in seconds, the representations may start, and how late
+/// the earliest may, before players notice.
+const ALIGNMENT_SECS: f64 = 0.1;
+
+/// Flag of sample flags marking a sample that isn't a sync sample.
+const NON_SYNC: u32 = 0x0001_0000;
+
+/// Where the first segment of a representation starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Start {
+    video: bool,
+    /// When its first sample is presented, in seconds
+    secs: f64,
+    /// Whether that sample is a keyframe
+    keyframe: bool,
+}
+
+/// Where `segment` starts, given the initialization segment `init` of its
+/// representation.
+fn start(init: &[u8], segment: &[u8]) -> Option<Start> {
+    let trak = sidx::find(init, &["moov", "trak"])?;
+    let video = sidx::find(trak, &["mdia", "hdlr"])?.get(8..12)? == b"vide";
+    let (timescale, _) = sidx::track_defaults(init)?;
+    let trex_flags = sidx::find(init, &["moov", "mvex", "trex"]).and_then(|trex| u32_at(trex, 20));
+    // Priming skipped by the edit list
+    let media_time = sidx::find(trak, &["edts", "elst"]).and_then(|elst| match elst.first()? {
+        1 => u64_at(elst, 16).map(|time| time as i64),
+        _ => u32_at(elst, 12).map(|time| time as i32 as i64),
+    });
+
+    let traf = sidx::find(segment, &["moof", "traf"])?;
+    let tfhd = sidx::find(traf, &["tfhd"])?;
+    let tfhd_flags = u32_at(tfhd, 0)? & 0xffffff;
+    // base-data-offset, sample-description-index, default duration and
+    // size come before the default flags
+    let offset = 8 + [(0x01, 8), (0x02, 4), (0x08, 4), (0x10, 4)]
+        .iter()
+        .filter(|(flag, _)| tfhd_flags & flag != 0)
+        .map(|(_, size)| size)
+        .sum::<usize>();
+    let default_flags = match tfhd_flags & 0x20 != 0 {
+        true => u32_at(tfhd, offset),
+        false => trex_flags,
+    };
+    let tfdt = sidx::find(traf, &["tfdt"])?;
+    let decode_time = match tfdt.first()? {
+        1 => u64_at(tfdt, 4)?,
+        _ => u32_at(tfdt, 4)? as u64,
+    };
+
+    let trun = sidx::find(traf, &["trun"])?;
+    let version = *trun.first()?;
+    let trun_flags = u32_at(trun, 0)? & 0xffffff;
+    let mut offset = 8 + if trun_flags & 0x01 != 0 { 4 } else { 0 };
+    let first_flags = match trun_flags & 0x04 != 0 {
+        true => {
+            offset += 4;
+            u32_at(trun, offset - 4)
+        }
+        false => None,
+    };
+    // The first sample's own fields, in order
+    let mut sample_flags = None;
+    let mut composition_offset = 0;
+    for field in [0x100, 0x200, 0x400, 0x800] {
+        if trun_flags & field == 0 {
+            continue;
+        }
+        let value = u32_at(trun, offset)?;
+        match field {
+            0x400 => sample_flags = Some(value),
+            0x800 if version == 1 => composition_offset = value as i32 as i64,
+            0x800 => composition_offset = value as i64,
+            _ => {}
+        }
+        offset += 4;
+    }
+    let flags = first_flags.or(sample_flags).or(default_flags).unwrap_or(0);
+
+    let presented = decode_time as i64 + composition_offset - media_time.unwrap_or(0);
+    Some(Start {
+        video,
+        secs: presented as f64 / timescale.max(1) as f64,
+        keyframe: flags & NON_SYNC == 0,
+    })
+}
+
+/// What would keep the title in `dir` from starting at once, one line each.
+pub(crate) fn check(dir: &Path) -> Result<Vec<String>> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    let mut starts = Vec::new();
+    for init_name in names.iter().filter(|name| name.ends_with(INIT_SUFFIX)) {
+        let prefix = init_name.trim_end_matches(INIT_SUFFIX);
+        let Some(first) = names
+            .iter()
+            .find(|name| name.starts_with(prefix) && name.ends_with(".m4s"))
+        else {
+            continue;
+        };
+        let init =
+            std::fs::read(dir.join(init_name)).context(format!("Failed to read {}", init_name))?;
+        let segment =
+            std::fs::read(dir.join(first)).context(format!("Failed to read {}", first))?;
+        if let Some(start) = start(&init, &segment) {
+            starts.push((prefix.trim_end_matches('_').to_string(), start));
+        }
+    }
+
+    let mut problems = Vec::new();
+    for (name, start) in &starts {
+        if start.video && !start.keyframe {
+            problems.push(format!(
+                "The first segment of {} doesn't start with a keyframe, so players show nothing until the next one",
+                name
+            ));
+        }
+    }
+    let video = starts
+        .iter()
+        .filter(|(_, start)| start.video)
+        .map(|(_, start)| start.secs)
+        .reduce(f64::min);
+    if let Some(video) = video {
+        if video > ALIGNMENT_SECS {
+            problems.push(format!(
+                "The video starts {:.2}s in, shown as black until then",
+                video
+            ));
+        }
+        for (name, start) in starts.iter().filter(|(_, start)| !start.video) {
+            let apart = start.secs - video;
+            if apart.abs() > ALIGNMENT_SECS {
+                problems.push(format!(
+                    "{} starts {:.2}s {} the video, which players may wait on",
+                    name,
+                    apart.abs(),
+                    if apart > 0.0 { "after" } else { "before" }
+                ));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Drop copied video arriving at `pad` up to its first keyframe, which
+/// nothing before could be decoded without.
+pub(crate) fn drop_to_keyframe(pad: &gst::Pad) {
+    let started = AtomicBool::new(false);
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if started.load(Ordering::Relaxed) {
+            return gst::PadProbeReturn::Ok;
+        }
+        match info.buffer() {
+            Some(buffer) if buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) => {
+                gst::PadProbeReturn::Drop
+            }
+            _ => {
+                started.store(true, Ordering::Relaxed);
+                gst::PadProbeReturn::Ok
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sidx::mp4_box;
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    fn init(handler: &[u8], media_time: Option<u32>) -> Vec<u8> {
+        let mut hdlr = words(&[0, 0]);
+        hdlr.extend(handler);
+        let mdia = [
+            mp4_box(b"mdhd", &words(&[0, 0, 0, 1000, 0])),
+            mp4_box(b"hdlr", &hdlr),
+        ]
+        .concat();
+        let mut trak = mp4_box(b"tkhd", &words(&[0]));
+        if let Some(media_time) = media_time {
+            trak.extend(mp4_box(
+                b"edts",
+                &mp4_box(b"elst", &words(&[0, 1, 0, media_time, 0x10000])),
+            ));
+        }
+        trak.extend(mp4_box(b"mdia", &mdia));
+        let trex = words(&[0, 1, 1, 40, 0, NON_SYNC]);
+        let moov = [
+            mp4_box(b"trak", &trak),
+            mp4_box(b"mvex", &mp4_box(b"trex", &trex)),
+        ]
+        .concat();
+        mp4_box(b"moov", &moov)
+    }
+
+    fn segment(decode_time: u32, first_flags: Option<u32>) -> Vec<u8> {
+        let trun = match first_flags {
+            Some(flags) => words(&[0x04, 1, flags]),
+            None => words(&[0, 1]),
+        };
+        let traf = [
+            mp4_box(b"tfhd", &words(&[0, 1])),
+            mp4_box(b"tfdt", &words(&[0, decode_time])),
+            mp4_box(b"trun", &trun),
+        ]
+        .concat();
+        mp4_box(b"moof", &mp4_box(b"traf", &traf))
+    }
+
+    #[test]
+    fn finds_late_starts_and_missing_keyframes() {
+        let video = start(&init(b"vide", None), &segment(0, Some(0))).unwrap();
+        assert_eq!(
+            video,
+            Start {
+                video: true,
+                secs: 0.0,
+                keyframe: true
+            }
+        );
+        // Without flags of its own, the first sample takes the track's
+        let delta = start(&init(b"vide", None), &segment(0, None)).unwrap();
+        assert!(!delta.keyframe);
+        // The audio's priming is skipped by the edit list
+        let audio = start(&init(b"soun", Some(312)), &segment(312, None)).unwrap();
+        assert_eq!(audio.secs, 0.0);
+
+        let dir = std::env::temp_dir().join(format!("movieshare-preroll-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, data: Vec<u8>| std::fs::write(dir.join(name), data).unwrap();
+        write("video_0_init.mp4", init(b"vide", None));
+        write("video_0_00001.m4s", segment(0, Some(0)));
+        write("video_0_00002.m4s", segment(4000, None));
+        write("audio_0_init.mp4", init(b"soun", Some(312)));
+        write("audio_0_00001.m4s", segment(312, None));
+        assert!(check(&dir).unwrap().is_empty());
+
+        write("video_0_00001.m4s", segment(2500, None));
+        assert_eq!(
+            check(&dir).unwrap(),
+            [
+                "The first segment of video_0 doesn't start with a keyframe, so players show nothing until the next one",
+                "The video starts 2.50s in, shown as black until then",
+                "audio_0 starts 2.50s before the video, which players may wait on",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+//! Looking for long stretches of black or frozen frames in the video being
+//! encoded, the usual signs of a source that failed to decode in places,
+//! so they can be spot checked before the title is shared.
+//!
+//! A branch off the video tee shrinks each frame to a small grey picture
+//! and a pad probe compares it with the one before. Runs of black or
+//! unchanging frames long enough not to be a fade or a held shot are
+//! reported once the run ends.
+
+use crate::branch::{MediaType, PipelineBranch};
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Size frames are shrunk to before being looked at.
+const WIDTH: usize = 64;
+const HEIGHT: usize = 36;
+/// Grey level at or below which a pixel counts as black.
+const BLACK_LEVEL: u8 = 24;
+/// Share of a frame's pixels that must be black for the frame to be.
+const BLACK_SHARE: f64 = 0.98;
+/// Mean difference in grey level from the frame before below which a frame
+/// counts as frozen.
+const FROZEN_DIFFERENCE: f64 = 0.25;
+/// Shortest run reported.
+const LONG_RUN: Duration = Duration::from_secs(3);
+
+/// What's wrong with a stretch of frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Defect {
+    Black,
+    Frozen,
+}
+
+impl Defect {
+    pub fn name(self) -> &'static str {
+        match self {
+            Defect::Black => "black",
+            Defect::Frozen => "frozen",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Defect::Black, Defect::Frozen]
+            .into_iter()
+            .find(|defect| defect.name() == name)
+    }
+}
+
+/// A long stretch of black or frozen frames.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DefectRun {
+    pub defect: Defect,
+    pub start: Duration,
+    pub end: Duration,
+}
+
+/// The long stretches of black or frozen frames found in a run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QcReport {
+    pub runs: Vec<DefectRun>,
+}
+
+impl fmt::Display for QcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.runs.is_empty() {
+            return write!(f, "No long stretches of black or frozen frames");
+        }
+        let timestamp = |at: Duration| {
+            let secs = at.as_secs_f64();
+            format!("{}:{:04.1}", (secs / 60.0) as u64, secs % 60.0)
+        };
+        let runs: Vec<String> = self
+            .runs
+            .iter()
+            .map(|run| {
+                format!(
+                    "{} from {} to {}",
+                    run.defect.name(),
+                    timestamp(run.start),
+                    timestamp(run.end)
+                )
+            })
+            .collect();
+        write!(f, "Check the video where it's {}", runs.join(", "))
+    }
+}
+
+/// Which defect, if any, `frame` shows, following `previous`.
+fn classify(frame: &[u8], previous: Option<&[u8]>) -> Option<Defect> {
+    let black = frame.iter().filter(|&&level| level <= BLACK_LEVEL).count();
+    if black as f64 >= frame.len() as f64 * BLACK_SHARE {
+        return Some(Defect::Black);
+    }
+    let previous = previous.filter(|previous| previous.len() == frame.len())?;
+    let difference: u64 = frame
+        .iter()
+        .zip(previous)
+        .map(|(&a, &b)| a.abs_diff(b) as u64)
+        .sum();
+    (difference as f64 / (frame.len() as f64) < FROZEN_DIFFERENCE).then_some(Defect::Frozen)
+}
+
+/// Follows frames for runs of one defect.
+#[derive(Debug, Default)]
+struct Runs {
+    current: Option<DefectRun>,
+    previous: Option<Vec<u8>>,
+    found: Vec<DefectRun>,
+}
+
+impl Runs {
+    /// Take in a frame at `pts` lasting `duration`.
+    fn frame(&mut self, frame: &[u8], pts: Duration, duration: Duration) {
+        let defect = classify(frame, self.previous.as_deref());
+        self.previous = Some(frame.to_vec());
+        match (&mut self.current, defect) {
+            (Some(run), Some(defect)) if run.defect == defect => run.end = pts + duration,
+            _ => {
+                self.end_run();
+                self.current = defect.map(|defect| DefectRun {
+                    defect,
+                    start: pts,
+                    end: pts + duration,
+                });
+            }
+        }
+    }
+
+    fn end_run(&mut self) {
+        if let Some(run) = self.current.take()
+            && run.end.saturating_sub(run.start) >= LONG_RUN
+        {
+            self.found.push(run);
+        }
+    }
+}
+
+/// Looks at the decoded video alongside the encoding branches. Clones share
+/// what's found.
+#[derive(Clone)]
+pub(crate) struct QcBranch {
+    queue: gst::Element,
+    videoconvert: gst::Element,
+    videoscale: gst::Element,
+    capsfilter: gst::Element,
+    sink: gst::Element,
+    runs: Arc<Mutex<Runs>>,
+}
+
+impl QcBranch {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(Self {
+            queue: gst::ElementFactory::make("queue").build()?,
+            videoconvert: gst::ElementFactory::make("videoconvert").build()?,
+            videoscale: gst::ElementFactory::make("videoscale").build()?,
+            capsfilter: gst::ElementFactory::make("capsfilter")
+                .property(
+                    "caps",
+                    gst::Caps::builder("video/x-raw")
+                        .field("format", "GRAY8")
+                        .field("width", WIDTH as i32)
+                        .field("height", HEIGHT as i32)
+                        .field("pixel-aspect-ratio", gst::Fraction::new(1, 1))
+                        .build(),
+                )
+                .build()?,
+            sink: gst::ElementFactory::make("fakesink").build()?,
+            runs: Arc::default(),
+        })
+    }
+
+    /// What was found, once the video has ended.
+    pub(crate) fn report(&self) -> QcReport {
+        let mut runs = self.runs.lock().unwrap();
+        runs.end_run();
+        QcReport {
+            runs: runs.found.clone(),
+        }
+    }
+
+    fn elements(&self) -> [&gst::Element; 5] {
+        [
+            &self.queue,
+            &self.videoconvert,
+            &self.videoscale,
+            &self.capsfilter,
+            &self.sink,
+        ]
+    }
+}
+
+impl PipelineBranch for QcBranch {
+    fn name(&self) -> String {
+        String::from("qc")
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::Video
+    }
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
+        pipeline.add_many(self.elements())?;
+        Ok(())
+    }
+
+    fn link(&self, tee: &gst::Element, _dashsink: &gst::Element) -> Result<()> {
+        tee.link(&self.queue)?;
+        gst::Element::link_many(self.elements())?;
+
+        let runs = self.runs.clone();
+        self.sink
+            .static_pad("sink")
+            .context("Failed to get sink pad from fakesink")?
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if let Some(buffer) = info.buffer()
+                    && let Some(pts) = buffer.pts()
+                    && let Ok(map) = buffer.map_readable()
+                    && let Some(frame) = map.get(..WIDTH * HEIGHT)
+                {
+                    let duration = buffer.duration().unwrap_or(gst::ClockTime::ZERO);
+                    runs.lock().unwrap().frame(
+                        frame,
+                        Duration::from_nanos(pts.nseconds()),
+                        Duration::from_nanos(duration.nseconds()),
+                    );
+                }
+                gst::PadProbeReturn::Ok
+            });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_long_black_and_frozen_runs() {
+        let picture = |seed: u64| -> Vec<u8> {
+            (0..WIDTH * HEIGHT)
+                .map(|i| ((i as u64 * 31 + seed * 17) % 200 + 40) as u8)
+                .collect()
+        };
+        let black = vec![16; WIDTH * HEIGHT];
+        // Two seconds of moving picture, a one second fade to black, four
+        // seconds of one frame, then four seconds of black
+        let frames = (0..50)
+            .map(picture)
+            .chain(std::iter::repeat_n(black.clone(), 25))
+            .chain(std::iter::repeat_n(picture(999), 100))
+            .chain(std::iter::repeat_n(black, 100));
+        let at = |frames: u64| Duration::from_millis(40 * frames);
+        let mut runs = Runs::default();
+        for (n, frame) in frames.enumerate() {
+            runs.frame(&frame, at(n as u64), at(1));
+        }
+        let report = QcReport {
+            runs: {
+                runs.end_run();
+                runs.found.clone()
+            },
+        };
+        // The freeze starts with the first repeat of the frame
+        let found: Vec<_> = report
+            .runs
+            .iter()
+            .map(|run| (run.defect, run.start, run.end))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Defect::Frozen, at(76), at(175)),
+                (Defect::Black, at(175), at(275)),
+            ]
+        );
+        assert_eq!(
+            report.to_string(),
+            "Check the video where it's frozen from 0:03.0 to 0:07.0, black from 0:07.0 to 0:11.0"
+        );
+    }
+}
@@ -0,0 +1,474 @@
+//! A persistent queue that runs several [`JobSpec`]s with bounded concurrency.
+
+use crate::cancel::{CancelPolicy, CancellationToken};
+use crate::isolate::{self, Worker};
+use crate::job::{JobEvent, PrepareJob};
+use crate::preparer::{Outcome, Preparer, Progress};
+use crate::spec::JobSpec;
+use crate::window::EncodeWindow;
+use anyhow::{Context, Result, bail};
+use futures::StreamExt;
+use futures::channel::mpsc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often to check whether the encode window opened or closed.
+const WINDOW_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub type JobId = u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Failed { error: String },
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedJob {
+    pub id: JobId,
+    /// Higher runs first; ties run in submission order
+    pub priority: i32,
+    pub spec: JobSpec,
+    #[serde(flatten)]
+    pub state: JobState,
+    #[serde(skip)]
+    pub progress: Option<Progress>,
+}
+
+#[derive(Debug, Clone)]
+pub enum QueueEvent {
+    StateChanged { id: JobId, state: JobState },
+    Progress { id: JobId, progress: Progress },
+    Warning { id: JobId, message: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    /// Jobs allowed to run at once
+    pub max_concurrent: usize,
+    /// Further limits per encoder backend, e.g. `"nvenc" => 1`
+    pub backend_limits: HashMap<String, usize>,
+    /// Where to persist the queue; `None` keeps it in memory only
+    pub state_file: Option<PathBuf>,
+    /// Only encode during this daily window, pausing running jobs outside it
+    pub window: Option<EncodeWindow>,
+    /// Run each job in its own process of this worker instead of a thread
+    pub worker: Option<Worker>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 1,
+            backend_limits: HashMap::new(),
+            state_file: None,
+            window: None,
+            worker: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PersistedQueue {
+    next_id: JobId,
+    jobs: Vec<QueuedJob>,
+}
+
+struct Inner {
+    config: QueueConfig,
+    next_id: JobId,
+    jobs: Vec<QueuedJob>,
+    tokens: HashMap<JobId, CancellationToken>,
+    subscribers: Vec<mpsc::UnboundedSender<QueueEvent>>,
+    /// Outside the encode window, so running jobs are paused and no more start
+    closed: bool,
+}
+
+/// Runs submitted jobs in priority order, up to the configured limits.
+///
+/// The queue is persisted after every state change. Jobs that were running
+/// when the process stopped are queued again on [`open`](Self::open).
+///
+/// With an encode window configured, running jobs are paused when it closes
+/// and resumed when it opens again; they still count as running.
+#[derive(Clone)]
+pub struct JobQueue {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl JobQueue {
+    pub fn open(config: QueueConfig) -> Result<Self> {
+        let mut persisted = PersistedQueue::default();
+        if let Some(path) = &config.state_file
+            && path.exists()
+        {
+            let json = std::fs::read_to_string(path)
+                .context(format!("Failed to read queue state: {}", path.display()))?;
+            persisted = serde_json::from_str(&json)
+                .context(format!("Invalid queue state: {}", path.display()))?;
+        }
+
+        for job in &mut persisted.jobs {
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+            }
+        }
+
+        let window = config.window;
+        let queue = Self {
+            inner: Arc::new(Mutex::new(Inner {
+                closed: window.is_some_and(|window| !window.is_open()),
+                config,
+                next_id: persisted.next_id,
+                jobs: persisted.jobs,
+                tokens: HashMap::new(),
+                subscribers: Vec::new(),
+            })),
+        };
+        queue.schedule();
+
+        if let Some(window) = window {
+            let inner = Arc::downgrade(&queue.inner);
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(WINDOW_CHECK_INTERVAL);
+                    let Some(inner) = inner.upgrade() else {
+                        return;
+                    };
+                    JobQueue { inner }.follow_window(window.is_open());
+                }
+            });
+        }
+        Ok(queue)
+    }
+
+    /// Pause running jobs when the encode window closes, and resume them
+    /// and start more when it opens.
+    fn follow_window(&self, open: bool) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.closed != open {
+                return;
+            }
+            inner.closed = !open;
+            let window = inner.config.window.unwrap();
+            let ids: Vec<JobId> = inner.tokens.keys().copied().collect();
+            for id in ids {
+                inner.tokens[&id].set_paused(!open);
+                let message = match open {
+                    true => String::from("Resumed in the encode window"),
+                    false => format!("Paused until the encode window {} opens", window),
+                };
+                inner.broadcast(QueueEvent::Warning { id, message });
+            }
+        }
+        if open {
+            self.schedule();
+        }
+    }
+
+    pub fn submit(&self, spec: JobSpec, priority: i32) -> Result<JobId> {
+        spec.profile.validate()?;
+
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.jobs.push(QueuedJob {
+                id,
+                priority,
+                spec,
+                state: JobState::Queued,
+                progress: None,
+            });
+            inner.broadcast(QueueEvent::StateChanged {
+                id,
+                state: JobState::Queued,
+            });
+            inner.persist()?;
+            id
+        };
+
+        self.schedule();
+        Ok(id)
+    }
+
+    /// Change a queued job's priority, moving it ahead of or behind others.
+    pub fn set_priority(&self, id: JobId, priority: i32) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let job = inner.job_mut(id)?;
+        if job.state != JobState::Queued {
+            bail!("Job {} is no longer queued", id);
+        }
+        job.priority = priority;
+        inner.persist()
+    }
+
+    /// Cancel a job. Running jobs are stopped and their output discarded.
+    pub fn cancel(&self, id: JobId) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let state = inner.job_mut(id)?.state.clone();
+        match state {
+            JobState::Queued => {
+                inner.set_state(id, JobState::Cancelled);
+                inner.persist()
+            }
+            JobState::Running => {
+                if let Some(token) = inner.tokens.get(&id) {
+                    token.cancel(CancelPolicy::Discard);
+                }
+                Ok(())
+            }
+            _ => bail!("Job {} has already finished", id),
+        }
+    }
+
+    pub fn get(&self, id: JobId) -> Option<QueuedJob> {
+        let inner = self.inner.lock().unwrap();
+        inner.jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    pub fn jobs(&self) -> Vec<QueuedJob> {
+        self.inner.lock().unwrap().jobs.clone()
+    }
+
+    /// Receive state changes and progress for every job from now on.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<QueueEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.inner.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    /// Start as many queued jobs as the limits allow.
+    fn schedule(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed {
+            return;
+        }
+
+        for id in startable(&inner.jobs, &inner.config) {
+            let spec = inner.job_mut(id).unwrap().spec.clone();
+            let token = CancellationToken::new();
+            inner.tokens.insert(id, token.clone());
+            inner.set_state(id, JobState::Running);
+            let job = match &inner.config.worker {
+                Some(worker) => isolate::spawn(worker, &spec, token),
+                None => PrepareJob::spawn(Preparer::from_spec(&spec).cancellation_token(token)),
+            };
+            self.watch(id, job);
+        }
+
+        if let Err(err) = inner.persist() {
+            eprintln!("Failed to persist job queue: {:#}", err);
+        }
+    }
+
+    /// Follow a running job's events on a helper thread.
+    fn watch(&self, id: JobId, mut job: PrepareJob) {
+        let queue = self.clone();
+        thread::spawn(move || {
+            let state = futures::executor::block_on(async {
+                while let Some(event) = job.next().await {
+                    let mut inner = queue.inner.lock().unwrap();
+                    match event {
+                        JobEvent::Progress(progress) => {
+                            if let Ok(job) = inner.job_mut(id) {
+                                job.progress = Some(progress.clone());
+                            }
+                            inner.broadcast(QueueEvent::Progress { id, progress });
+                        }
+                        JobEvent::Warning(message) => {
+                            inner.broadcast(QueueEvent::Warning { id, message });
+                        }
+                        JobEvent::BranchStats(_) | JobEvent::Bottleneck(_) | JobEvent::Qc(_) => (),
+                        JobEvent::Finished(Ok(Outcome::Cancelled(_))) => {
+                            return JobState::Cancelled;
+                        }
+                        JobEvent::Finished(Ok(_)) => return JobState::Completed,
+                        JobEvent::Finished(Err(err)) => {
+                            return JobState::Failed {
+                                error: format!("{:#}", err),
+                            };
+                        }
+                    }
+                }
+                JobState::Failed {
+                    error: String::from("Job ended without finishing"),
+                }
+            });
+
+            {
+                let mut inner = queue.inner.lock().unwrap();
+                inner.tokens.remove(&id);
+                inner.set_state(id, state);
+            }
+            queue.schedule();
+        });
+    }
+}
+
+/// The queued jobs in `jobs` to start now, highest priority first, within
+/// the limits of `config`.
+fn startable(jobs: &[QueuedJob], config: &QueueConfig) -> Vec<JobId> {
+    let mut candidates: Vec<_> = jobs
+        .iter()
+        .filter(|job| job.state == JobState::Queued)
+        .collect();
+    candidates.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.id.cmp(&b.id)));
+
+    let mut running: Vec<_> = jobs
+        .iter()
+        .filter(|job| job.state == JobState::Running)
+        .map(|job| job.spec.profile.encoder_backend())
+        .collect();
+    let mut starting = Vec::new();
+    for job in candidates {
+        if running.len() >= config.max_concurrent {
+            break;
+        }
+        let backend = job.spec.profile.encoder_backend();
+        if let Some(&limit) = config.backend_limits.get(backend)
+            && running.iter().filter(|&&b| b == backend).count() >= limit
+        {
+            continue;
+        }
+        running.push(backend);
+        starting.push(job.id);
+    }
+    starting
+}
+
+impl Inner {
+    fn job_mut(&mut self, id: JobId) -> Result<&mut QueuedJob> {
+        self.jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .context(format!("No such job: {}", id))
+    }
+
+    fn set_state(&mut self, id: JobId, state: JobState) {
+        if let Ok(job) = self.job_mut(id) {
+            job.state = state.clone();
+            self.broadcast(QueueEvent::StateChanged { id, state });
+        }
+    }
+
+    fn broadcast(&mut self, event: QueueEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    fn persist(&self) -> Result<()> {
+        let Some(path) = &self.config.state_file else {
+            return Ok(());
+        };
+
+        let json = serde_json::to_string_pretty(&PersistedQueue {
+            next_id: self.next_id,
+            jobs: self.jobs.clone(),
+        })?;
+
+        // Write-then-rename so a crash never leaves a truncated queue behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)
+            .context(format!("Failed to write queue state: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::Encoder;
+
+    fn job(id: JobId, priority: i32, encoder: Encoder, state: JobState) -> QueuedJob {
+        let mut spec = JobSpec::new(format!("{}.mkv", id), format!("out-{}", id));
+        spec.profile.encoder = encoder;
+        QueuedJob {
+            id,
+            priority,
+            spec,
+            state,
+            progress: None,
+        }
+    }
+
+    #[test]
+    fn starts_by_priority_within_backend_limits() {
+        let config = QueueConfig {
+            max_concurrent: 2,
+            ..QueueConfig::default()
+        };
+        let jobs = [
+            job(1, 0, Encoder::Software, JobState::Queued),
+            job(2, 5, Encoder::Software, JobState::Queued),
+            job(3, 5, Encoder::Software, JobState::Queued),
+        ];
+        // Ties run in submission order
+        assert_eq!(startable(&jobs, &config), [2, 3]);
+
+        let config = QueueConfig {
+            max_concurrent: 3,
+            backend_limits: HashMap::from([(String::from("nvenc"), 1)]),
+            ..QueueConfig::default()
+        };
+        let jobs = [
+            job(1, 0, Encoder::Nvenc, JobState::Running),
+            job(2, 9, Encoder::Nvenc, JobState::Queued),
+            job(3, 0, Encoder::Software, JobState::Queued),
+            job(4, 0, Encoder::Vaapi, JobState::Queued),
+        ];
+        assert_eq!(Encoder::Nvenc.backend(), "nvenc");
+        // The GPU's one session is taken, so others go ahead of job 2
+        assert_eq!(startable(&jobs, &config), [3, 4]);
+    }
+
+    #[test]
+    fn requeues_interrupted_jobs_on_open() {
+        let path =
+            std::env::temp_dir().join(format!("movieshare-queue-{}.json", std::process::id()));
+        let persisted = PersistedQueue {
+            next_id: 3,
+            jobs: vec![
+                job(1, 0, Encoder::Auto, JobState::Completed),
+                job(2, 0, Encoder::Auto, JobState::Running),
+            ],
+        };
+        std::fs::write(&path, serde_json::to_string(&persisted).unwrap()).unwrap();
+
+        // Nothing may start, so the requeued job stays put
+        let config = QueueConfig {
+            max_concurrent: 0,
+            state_file: Some(path.clone()),
+            ..QueueConfig::default()
+        };
+        let queue = JobQueue::open(config.clone()).unwrap();
+        assert_eq!(queue.get(1).unwrap().state, JobState::Completed);
+        assert_eq!(queue.get(2).unwrap().state, JobState::Queued);
+        let id = queue.submit(JobSpec::new("3.mkv", "out-3"), 0).unwrap();
+        assert_eq!(id, 3);
+
+        let reopened = JobQueue::open(config).unwrap();
+        let states: Vec<_> = reopened.jobs().into_iter().map(|job| job.state).collect();
+        assert_eq!(
+            states,
+            [JobState::Completed, JobState::Queued, JobState::Queued]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
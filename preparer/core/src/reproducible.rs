@@ -0,0 +1,127 @@
+//! Making a finished presentation byte-identical from run to run, so mirrors
+//! can check their copies by checksum.
+//!
+//! The muxer stamps each initialization segment's `mvhd`, `tkhd` and `mdhd`
+//! with the time it was written, and the manifest can carry publish times
+//! and comments; these are zeroed or dropped. Media segments hold no clock
+//! times and are left alone.
+
+use anyhow::{Context, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use std::path::Path;
+
+/// Boxes that only hold other boxes, down to the ones with times.
+const CONTAINERS: &[&[u8]] = &[b"moov", b"trak", b"mdia"];
+/// Boxes starting with a version, flags, creation and modification time.
+const TIMED: &[&[u8]] = &[b"mvhd", b"tkhd", b"mdhd"];
+
+/// Zero the creation and modification times of the boxes in `data`.
+fn scrub_boxes(data: &mut [u8]) {
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+        // Large and open-ended boxes are only ever media data here
+        if size < 8 || offset + size > data.len() {
+            break;
+        }
+        let payload = &mut data[offset + 8..offset + size];
+        if CONTAINERS.contains(&&kind[..]) {
+            scrub_boxes(payload);
+        } else if TIMED.contains(&&kind[..]) && !payload.is_empty() {
+            let times = match payload[0] {
+                1 => 4..20,
+                _ => 4..12,
+            };
+            if let Some(times) = payload.get_mut(times) {
+                times.fill(0);
+            }
+        }
+        offset += size;
+    }
+}
+
+/// Drop comments and wall-clock times from a static manifest.
+fn scrub_manifest(xml: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            Event::Comment(_) => (),
+            Event::Start(element) if element.local_name().into_inner() == "MPD" => {
+                let mut root = BytesStart::new(element.name().into_inner().to_string());
+                for attribute in element.attributes() {
+                    let attribute = attribute?;
+                    if !matches!(
+                        attribute.key.local_name().into_inner(),
+                        "publishTime" | "availabilityStartTime"
+                    ) {
+                        root.push_attribute(attribute);
+                    }
+                }
+                writer.write_event(Event::Start(root))?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Scrub the presentation in `dir`, whose manifest is `manifest`.
+pub(crate) fn scrub(dir: &Path, manifest: &str) -> Result<()> {
+    let path = dir.join(manifest);
+    let xml =
+        std::fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+    std::fs::write(&path, scrub_manifest(&xml)?)
+        .context(format!("Failed to write {}", path.display()))?;
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "mp4") {
+            let mut data =
+                std::fs::read(&path).context(format!("Failed to read {}", path.display()))?;
+            scrub_boxes(&mut data);
+            std::fs::write(&path, data).context(format!("Failed to write {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &str, payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend(kind.as_bytes());
+        data.extend(payload);
+        data
+    }
+
+    #[test]
+    fn zeroes_clock_times() {
+        let times = [0xaa; 8];
+        let mvhd = mp4_box("mvhd", &[&[0, 0, 0, 0], &times[..], &[1, 2, 3, 4]].concat());
+        let mdhd = mp4_box("mdhd", &[&[1, 0, 0, 0], &[0xbb; 16][..], &[5, 6]].concat());
+        let trak = mp4_box("trak", &mp4_box("mdia", &mdhd));
+        let mut init = mp4_box("moov", &[mvhd, trak].concat());
+        let original = init.clone();
+        scrub_boxes(&mut init);
+        assert_eq!(init.len(), original.len());
+        assert!(!init.contains(&0xaa) && !init.contains(&0xbb));
+        // Everything after the times is kept
+        assert_eq!(init[28..32], [1, 2, 3, 4]);
+        assert_eq!(init[init.len() - 2..], [5, 6]);
+
+        let xml = scrub_manifest(
+            r#"<?xml version="1.0"?><!-- Generated at 12:00 --><MPD type="static" publishTime="2026-01-01T12:00:00Z"><Period/></MPD>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0"?><MPD type="static"><Period/></MPD>"#
+        );
+    }
+}
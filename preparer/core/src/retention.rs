@@ -0,0 +1,136 @@
+//! What becomes of a source once the title prepared from it checks out, so
+//! the disk doesn't fill up with both raw rips and prepared titles.
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum SourcePolicy {
+    /// Leave the source where it is
+    #[default]
+    Keep,
+    /// Move the source into a directory, like an archive disk
+    Move { dir: PathBuf },
+    /// Hardlink the source into a directory, to be cleaned up from there
+    Link { dir: PathBuf },
+    /// Delete the source
+    Delete,
+}
+
+impl FromStr for SourcePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.split_once(':') {
+            None if s == "keep" => SourcePolicy::Keep,
+            None if s == "delete" => SourcePolicy::Delete,
+            Some(("move", dir)) if !dir.is_empty() => SourcePolicy::Move { dir: dir.into() },
+            Some(("link", dir)) if !dir.is_empty() => SourcePolicy::Link { dir: dir.into() },
+            _ => bail!(
+                "Unknown source policy {:?}; expected keep, move:<dir>, link:<dir> or delete",
+                s
+            ),
+        })
+    }
+}
+
+impl SourcePolicy {
+    /// Apply the policy to `source`, returning where it can be found now,
+    /// if anywhere.
+    pub fn apply(&self, source: &Path) -> Result<Option<PathBuf>> {
+        let destination = |dir: &Path| -> Result<PathBuf> {
+            let name = source
+                .file_name()
+                .context(format!("{} isn't a file", source.display()))?;
+            std::fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+            let destination = dir.join(name);
+            if destination.exists() {
+                bail!("{} is already there", destination.display());
+            }
+            Ok(destination)
+        };
+        match self {
+            SourcePolicy::Keep => Ok(Some(source.to_path_buf())),
+            SourcePolicy::Move { dir } => {
+                let destination = destination(dir)?;
+                match std::fs::rename(source, &destination) {
+                    Ok(()) => (),
+                    // Another filesystem, so it has to be copied over
+                    Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                        std::fs::copy(source, &destination).context(format!(
+                            "Failed to copy {} to {}",
+                            source.display(),
+                            destination.display()
+                        ))?;
+                        std::fs::remove_file(source)
+                            .context(format!("Failed to delete {}", source.display()))?;
+                    }
+                    Err(err) => {
+                        return Err(err).context(format!(
+                            "Failed to move {} to {}",
+                            source.display(),
+                            destination.display()
+                        ));
+                    }
+                }
+                Ok(Some(destination))
+            }
+            SourcePolicy::Link { dir } => {
+                let destination = destination(dir)?;
+                std::fs::hard_link(source, &destination).context(format!(
+                    "Failed to link {} into {}",
+                    source.display(),
+                    dir.display()
+                ))?;
+                Ok(Some(destination))
+            }
+            SourcePolicy::Delete => {
+                std::fs::remove_file(source)
+                    .context(format!("Failed to delete {}", source.display()))?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_links_and_deletes() {
+        let dir = std::env::temp_dir().join(format!("movieshare-retention-{}", std::process::id()));
+        let archive = dir.join("archive");
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("movie.mkv");
+
+        std::fs::write(&source, "rip").unwrap();
+        let policy: SourcePolicy = format!("link:{}", archive.display()).parse().unwrap();
+        let linked = policy.apply(&source).unwrap().unwrap();
+        assert!(source.is_file());
+        assert_eq!(std::fs::read_to_string(&linked).unwrap(), "rip");
+        // Never over something already there
+        assert!(policy.apply(&source).is_err());
+        std::fs::remove_file(&linked).unwrap();
+
+        let policy: SourcePolicy = format!("move:{}", archive.display()).parse().unwrap();
+        assert_eq!(policy.apply(&source).unwrap(), Some(linked.clone()));
+        assert!(!source.exists());
+
+        assert_eq!(
+            "delete"
+                .parse::<SourcePolicy>()
+                .unwrap()
+                .apply(&linked)
+                .unwrap(),
+            None
+        );
+        assert!(!linked.exists());
+        assert!("move:".parse::<SourcePolicy>().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
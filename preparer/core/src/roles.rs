@@ -0,0 +1,167 @@
+//! Signalling what a representation is for, like the dialogue-boosted
+//! audio, with a DASH `Role`.
+//!
+//! dashsink puts every audio representation in one adaptation set, and
+//! players pick roles per set, so a representation with a role of its own is
+//! moved into a set of its own first.
+
+use anyhow::{Context, Result};
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer, XmlVersion};
+
+/// Scheme of DASH's `Role` values.
+const ROLE_SCHEME: &str = "urn:mpeg:dash:role:2011";
+/// Role of audio made easier to follow, such as by compressing its range.
+pub(crate) const ENHANCED_INTELLIGIBILITY: &str = "enhanced-audio-intelligibility";
+
+/// Give the last representation of `content_type` in a manifest `role`, in
+/// an adaptation set of its own.
+pub(crate) fn set_role_of_last(xml: &str, content_type: &str, role: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut events = Vec::new();
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            event => events.push(event.into_owned()),
+        }
+    }
+
+    let name = |event: &Event| match event {
+        Event::Start(element) | Event::Empty(element) => {
+            Some(element.local_name().into_inner().to_string())
+        }
+        _ => None,
+    };
+    // Each matching set's start and end, and its representations' spans
+    let mut sets = Vec::new();
+    let mut depth = 0;
+    let mut set: Option<(usize, Vec<(usize, usize)>)> = None;
+    let mut representation = None;
+    for (i, event) in events.iter().enumerate() {
+        match event {
+            Event::Start(element) => {
+                depth += 1;
+                match name(event).as_deref() {
+                    Some("AdaptationSet") if is_of(element, content_type)? => {
+                        set = Some((i, Vec::new()))
+                    }
+                    Some("Representation") if set.is_some() => representation = Some((i, depth)),
+                    _ => (),
+                }
+            }
+            Event::Empty(_) if name(event).as_deref() == Some("Representation") => {
+                if let Some((_, spans)) = &mut set {
+                    spans.push((i, i));
+                }
+            }
+            Event::End(end) => {
+                match end.local_name().into_inner() {
+                    "Representation" => {
+                        if let (Some((start, at)), Some((_, spans))) = (representation, &mut set)
+                            && at == depth
+                        {
+                            spans.push((start, i));
+                            representation = None;
+                        }
+                    }
+                    "AdaptationSet" => {
+                        if let Some((start, spans)) = set.take() {
+                            sets.push((start, i, spans));
+                        }
+                    }
+                    _ => (),
+                }
+                depth -= 1;
+            }
+            _ => (),
+        }
+    }
+    let (set_start, set_end, spans) = sets
+        .into_iter()
+        .rev()
+        .find(|(_, _, spans)| !spans.is_empty())
+        .context(format!(
+            "The manifest has no {} representation",
+            content_type
+        ))?;
+    let (first, last) = *spans.last().unwrap();
+
+    let role_element = || {
+        Event::Empty(
+            BytesStart::new("Role")
+                .with_attributes([("schemeIdUri", ROLE_SCHEME), ("value", role)]),
+        )
+    };
+    let mut writer = Writer::new(Vec::new());
+    for (i, event) in events.iter().enumerate() {
+        if spans.len() == 1 {
+            writer.write_event(event.clone())?;
+            if i == set_start {
+                writer.write_event(role_element())?;
+            }
+            continue;
+        }
+        if (first..=last).contains(&i) {
+            continue;
+        }
+        writer.write_event(event.clone())?;
+        if i == set_end {
+            let Event::Start(element) = &events[set_start] else {
+                unreachable!();
+            };
+            let mut own = BytesStart::new(element.name().into_inner().to_string());
+            for attribute in element.attributes() {
+                let attribute = attribute?;
+                if attribute.key.local_name().into_inner() != "id" {
+                    own.push_attribute(attribute);
+                }
+            }
+            writer.write_event(Event::Start(own))?;
+            writer.write_event(role_element())?;
+            for event in &events[first..=last] {
+                writer.write_event(event.clone())?;
+            }
+            writer.write_event(Event::End(BytesEnd::new(
+                element.name().into_inner().to_string(),
+            )))?;
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Whether an adaptation set holds `content_type`, by its content or MIME type.
+pub(crate) fn is_of(element: &BytesStart, content_type: &str) -> Result<bool> {
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let value = attribute.normalized_value(XmlVersion::Implicit1_0)?;
+        match attribute.key.local_name().into_inner() {
+            "contentType" if value == content_type => return Ok(true),
+            "mimeType" if value.split('/').next() == Some(content_type) => return Ok(true),
+            _ => (),
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_the_last_audio_into_its_own_set() {
+        let xml = r#"<MPD><Period><AdaptationSet id="1" contentType="video"><Representation id="video_0"/></AdaptationSet><AdaptationSet id="2" contentType="audio" lang="fr"><Representation id="audio_0"><SegmentList/></Representation><Representation id="audio_1"><SegmentList/></Representation></AdaptationSet></Period></MPD>"#;
+        let xml = set_role_of_last(xml, "audio", ENHANCED_INTELLIGIBILITY).unwrap();
+        assert_eq!(
+            xml,
+            r#"<MPD><Period><AdaptationSet id="1" contentType="video"><Representation id="video_0"/></AdaptationSet><AdaptationSet id="2" contentType="audio" lang="fr"><Representation id="audio_0"><SegmentList/></Representation></AdaptationSet><AdaptationSet contentType="audio" lang="fr"><Role schemeIdUri="urn:mpeg:dash:role:2011" value="enhanced-audio-intelligibility"/><Representation id="audio_1"><SegmentList/></Representation></AdaptationSet></Period></MPD>"#
+        );
+
+        // Alone in its set, it stays there
+        let alone = r#"<MPD><Period><AdaptationSet mimeType="audio/mp4"><Representation id="0"/></AdaptationSet></Period></MPD>"#;
+        assert_eq!(
+            set_role_of_last(alone, "audio", "main").unwrap(),
+            r#"<MPD><Period><AdaptationSet mimeType="audio/mp4"><Role schemeIdUri="urn:mpeg:dash:role:2011" value="main"/><Representation id="0"/></AdaptationSet></Period></MPD>"#
+        );
+        assert!(set_role_of_last(alone, "text", "main").is_err());
+    }
+}
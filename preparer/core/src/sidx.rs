@@ -0,0 +1,270 @@
+//! Segment index (`sidx`) boxes for media segments, for players and caches
+//! that look for one to learn a segment's timing before fetching it.
+//!
+//! dashsink's muxers don't write them, so once the run ends each media
+//! segment gets one at its start, with a single reference spanning all of
+//! its fragments. The timescale and default sample duration come from the
+//! initialization segment the media segment's name shares a prefix with.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+
+/// Suffix dashsink gives initialization segments, after the
+/// representation's prefix.
+pub(crate) const INIT_SUFFIX: &str = "init.mp4";
+
+/// A box of type `kind` around `payload`.
+pub(crate) fn mp4_box(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+    data.extend(kind);
+    data.extend(payload);
+    data
+}
+
+pub(crate) fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let kind = data.get(4..8)?;
+        let (header, size) = match size {
+            0 => (8, data.len()),
+            1 => (
+                16,
+                u64::from_be_bytes(data.get(8..16)?.try_into().ok()?) as usize,
+            ),
+            size => (8, size),
+        };
+        let payload = data.get(header..size)?;
+        data = &data[size..];
+        Some((kind, payload))
+    })
+}
+
+/// Payload of the first box of type `path[0]`, inside the first `path[1]`,
+/// and so on.
+pub(crate) fn find<'a>(data: &'a [u8], path: &[&str]) -> Option<&'a [u8]> {
+    find_range(data, path).map(|range| &data[range])
+}
+
+/// Where in `data` the payload [`find`] would give is, for changing it in
+/// place.
+pub(crate) fn find_range(data: &[u8], path: &[&str]) -> Option<Range<usize>> {
+    path.iter().try_fold(0..data.len(), |range, name| {
+        let start = data.as_ptr() as usize;
+        boxes(&data[range])
+            .find(|(kind, _)| *kind == name.as_bytes())
+            .map(|(_, payload)| {
+                let offset = payload.as_ptr() as usize - start;
+                offset..offset + payload.len()
+            })
+    })
+}
+
+pub(crate) fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Timescale and default sample duration of the track in `init`.
+pub(crate) fn track_defaults(init: &[u8]) -> Option<(u32, u32)> {
+    let mdhd = find(init, &["moov", "trak", "mdia", "mdhd"])?;
+    let timescale = match mdhd.first()? {
+        1 => u32_at(mdhd, 20)?,
+        _ => u32_at(mdhd, 12)?,
+    };
+    let default_duration = find(init, &["moov", "mvex", "trex"])
+        .and_then(|trex| u32_at(trex, 12))
+        .unwrap_or(0);
+    Some((timescale, default_duration))
+}
+
+/// Track id, earliest decode time and duration of the fragments in
+/// `segment`.
+pub(crate) fn segment_timing(segment: &[u8], default_duration: u32) -> Option<(u32, u64, u64)> {
+    let mut timing: Option<(u32, u64)> = None;
+    let mut duration = 0;
+    for (_, moof) in boxes(segment).filter(|(kind, _)| *kind == b"moof") {
+        for (_, traf) in boxes(moof).filter(|(kind, _)| *kind == b"traf") {
+            let tfhd = find(traf, &["tfhd"])?;
+            let flags = u32_at(tfhd, 0)? & 0xffffff;
+            let track_id = u32_at(tfhd, 4)?;
+            // base-data-offset and sample-description-index come first
+            let offset =
+                8 + if flags & 0x01 != 0 { 8 } else { 0 } + if flags & 0x02 != 0 { 4 } else { 0 };
+            let default_duration = match flags & 0x08 != 0 {
+                true => u32_at(tfhd, offset)?,
+                false => default_duration,
+            };
+            if timing.is_none() {
+                let tfdt = find(traf, &["tfdt"])?;
+                let start = match tfdt.first()? {
+                    1 => u64_at(tfdt, 4)?,
+                    _ => u32_at(tfdt, 4)? as u64,
+                };
+                timing = Some((track_id, start));
+            }
+            for (_, trun) in boxes(traf).filter(|(kind, _)| *kind == b"trun") {
+                let flags = u32_at(trun, 0)? & 0xffffff;
+                let count = u32_at(trun, 4)? as usize;
+                if flags & 0x100 == 0 {
+                    duration += count as u64 * default_duration as u64;
+                    continue;
+                }
+                let mut offset = 8
+                    + if flags & 0x01 != 0 { 4 } else { 0 }
+                    + if flags & 0x04 != 0 { 4 } else { 0 };
+                let stride = [0x100, 0x200, 0x400, 0x800]
+                    .iter()
+                    .filter(|flag| flags & **flag != 0)
+                    .count()
+                    * 4;
+                for _ in 0..count {
+                    duration += u32_at(trun, offset)? as u64;
+                    offset += stride;
+                }
+            }
+        }
+    }
+    let (track_id, start) = timing?;
+    Some((track_id, start, duration))
+}
+
+/// A version 1 `sidx` with one reference to `size` bytes of media starting
+/// with a keyframe.
+fn sidx(track_id: u32, timescale: u32, start: u64, duration: u64, size: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(52);
+    data.extend(52u32.to_be_bytes());
+    data.extend(b"sidx");
+    data.extend(0x0100_0000u32.to_be_bytes());
+    data.extend(track_id.to_be_bytes());
+    data.extend(timescale.to_be_bytes());
+    data.extend(start.to_be_bytes());
+    // First offset, then reserved bits and one reference
+    data.extend(0u64.to_be_bytes());
+    data.extend([0, 0, 0, 1]);
+    data.extend((size as u32 & 0x7fff_ffff).to_be_bytes());
+    data.extend((duration as u32).to_be_bytes());
+    // Starts with a stream access point of type 1
+    data.extend(0x9000_0000u32.to_be_bytes());
+    data
+}
+
+/// Put a `sidx` at the start of every media segment in `dir` that lacks one.
+pub(crate) fn index_segments(dir: &Path) -> Result<()> {
+    let mut inits = HashMap::new();
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if let Some(prefix) = name.strip_suffix(INIT_SUFFIX) {
+            let init = std::fs::read(&path).context(format!("Failed to read {}", name))?;
+            if let Some(defaults) = track_defaults(&init) {
+                inits.insert(prefix.to_owned(), defaults);
+            }
+        } else if name.ends_with(".m4s") {
+            segments.push(path);
+        }
+    }
+
+    for path in segments {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let Some((timescale, default_duration)) = inits
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .map(|(_, defaults)| *defaults)
+        else {
+            continue;
+        };
+        let data = std::fs::read(&path).context(format!("Failed to read {}", name))?;
+        if boxes(&data).next().is_some_and(|(kind, _)| kind == b"sidx") {
+            continue;
+        }
+        let (track_id, start, duration) = segment_timing(&data, default_duration)
+            .context(format!("{} has no fragments to index", name))?;
+        let mut indexed = sidx(track_id, timescale, start, duration, data.len() as u64);
+        indexed.extend(data);
+        std::fs::write(&path, indexed).context(format!("Failed to write {}", name))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &str, payload: &[&[u8]]) -> Vec<u8> {
+        let payload = payload.concat();
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend(kind.as_bytes());
+        data.extend(payload);
+        data
+    }
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn indexes_each_segment() {
+        let dir = std::env::temp_dir().join(format!("movieshare-sidx-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mdhd = mp4_box("mdhd", &[&words(&[0, 0, 0, 90000, 0, 0])]);
+        let trex = mp4_box("trex", &[&words(&[0, 1, 1, 3000, 0, 0])]);
+        let init = mp4_box(
+            "moov",
+            &[
+                &mp4_box("trak", &[&mp4_box("mdia", &[&mdhd])]),
+                &mp4_box("mvex", &[&trex]),
+            ],
+        );
+        std::fs::write(dir.join("video_0_init.mp4"), init).unwrap();
+
+        // Two fragments: four samples at the default duration, then two
+        // with their own
+        let fragment = |tfdt: u32, trun: &[u8]| {
+            let traf = mp4_box(
+                "traf",
+                &[
+                    &mp4_box("tfhd", &[&words(&[0, 1])]),
+                    &mp4_box("tfdt", &[&words(&[0, tfdt])]),
+                    &mp4_box("trun", &[trun]),
+                ],
+            );
+            [mp4_box("moof", &[&traf]), mp4_box("mdat", &[b"av1"])].concat()
+        };
+        let segment = [
+            fragment(360000, &words(&[0, 4])),
+            fragment(372000, &words(&[0x100, 2, 1500, 4500])),
+        ]
+        .concat();
+        std::fs::write(dir.join("video_0_00001.m4s"), &segment).unwrap();
+
+        index_segments(&dir).unwrap();
+        let indexed = std::fs::read(dir.join("video_0_00001.m4s")).unwrap();
+        let sidx = find(&indexed, &["sidx"]).unwrap();
+        assert_eq!(u32_at(sidx, 4), Some(1));
+        assert_eq!(u32_at(sidx, 8), Some(90000));
+        assert_eq!(u64_at(sidx, 12), Some(360000));
+        assert_eq!(u32_at(sidx, 32), Some(segment.len() as u32));
+        assert_eq!(u32_at(sidx, 36), Some(18000));
+        assert_eq!(&indexed[52..], &segment[..]);
+
+        // Running again leaves the index alone
+        index_segments(&dir).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("video_0_00001.m4s")).unwrap(),
+            indexed
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+//! Serializable descriptions of what to prepare and how.
+//!
+//! These are the types shared by the CLI, profile files, and job submission
+//! APIs. Every [`JobSpec`] carries a `version`; bump [`SPEC_VERSION`] whenever
+//! a change would make older readers misinterpret a spec. `preparer
+//! spec-schema` prints the JSON Schema of the current version.
+
+use crate::burnin::BurnInStyle;
+use crate::conform::FrameRate;
+use crate::cuts::Cut;
+use crate::encoder::Encoder;
+use crate::grade::Grade;
+use crate::language;
+use crate::levels::{self, LevelPolicy};
+use crate::watermark::Watermark;
+use anyhow::{Result, bail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+pub const SPEC_VERSION: u32 = 1;
+
+/// What Opus tunes its coding for.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AudioType {
+    #[default]
+    Generic,
+    /// Speech, like lectures and talks, which holds up at far lower bitrates
+    Voice,
+    RestrictedLowdelay,
+}
+
+impl AudioType {
+    /// Nick of opusenc's `audio-type` value.
+    pub fn nick(&self) -> &'static str {
+        match self {
+            AudioType::Generic => "generic",
+            AudioType::Voice => "voice",
+            AudioType::RestrictedLowdelay => "restricted-lowdelay",
+        }
+    }
+}
+
+/// Frame durations Opus supports, in milliseconds.
+const OPUS_FRAME_DURATIONS: &[f64] = &[2.5, 5.0, 10.0, 20.0, 40.0, 60.0];
+
+/// Sample rates Opus encodes at, in Hz.
+const OPUS_SAMPLE_RATES: &[u32] = &[8000, 12000, 16000, 24000, 48000];
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioSpec {
+    pub bitrate_kbps: u32,
+    pub channels: u32,
+    pub audio_type: AudioType,
+    /// Carry redundancy to recover lost packets from, at some cost in bitrate
+    pub inband_fec: bool,
+    /// Send next to nothing during silence
+    pub dtx: bool,
+    /// Length of each Opus frame: 2.5, 5, 10, 20, 40 or 60 ms; longer frames
+    /// code speech more cheaply
+    pub frame_duration_ms: f64,
+    /// Also encode a rendition with its dynamic range narrowed, so dialogue
+    /// carries over noise or for viewers who are hard of hearing
+    pub drc: bool,
+    /// Also encode a mono rendition at this bitrate, for viewers on the
+    /// thinnest connections
+    pub mono_kbps: Option<u32>,
+    /// Rate the audio is resampled to before encoding: 8000, 12000, 16000,
+    /// 24000 or 48000 Hz
+    pub sample_rate: u32,
+    /// `audioresample` quality, from 0 (fastest) to 10 (best); audio is
+    /// cheap enough to resample at the best, which keeps 44.1 kHz captures
+    /// free of artifacts
+    pub resample_quality: u32,
+}
+
+impl Default for AudioSpec {
+    fn default() -> Self {
+        Self {
+            bitrate_kbps: 192,
+            channels: 2,
+            audio_type: AudioType::default(),
+            inband_fec: false,
+            dtx: false,
+            frame_duration_ms: 20.0,
+            drc: false,
+            mono_kbps: None,
+            sample_rate: 48000,
+            resample_quality: 10,
+        }
+    }
+}
+
+/// Subtitle tracks to carry into the presentation, when the source's other
+/// tracks are carried along with its main ones.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubtitleSpec {
+    /// Languages to keep, as BCP-47 or ISO 639 codes; empty keeps every track
+    pub languages: Vec<String>,
+    /// How subtitles burned into the picture look
+    pub burn_in: BurnInStyle,
+}
+
+impl SubtitleSpec {
+    /// Whether to keep a track tagged with `language`, compared after
+    /// normalizing both sides so `eng` matches `en`.
+    pub fn keeps(&self, language: Option<&str>) -> bool {
+        if self.languages.is_empty() {
+            return true;
+        }
+        let Some(language) = language.and_then(language::normalize) else {
+            return false;
+        };
+        self.languages
+            .iter()
+            .any(|kept| language::normalize(kept).as_ref() == Some(&language))
+    }
+}
+
+/// How the encoded streams are packaged into segments.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PackagingSpec {
+    /// Split each segment into fragments of this many milliseconds, so
+    /// players can start on part of one; unset keeps a fragment a segment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fragment_duration_ms: Option<u32>,
+    /// Begin each media segment with a segment index (`sidx`)
+    pub sidx: bool,
+    /// URL answering with the time in ISO 8601, for players of dynamic
+    /// manifests to sync their clocks to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utc_timing: Option<String>,
+    /// Times to retry writing a segment into the output before failing the
+    /// job; with any, segments are staged locally first
+    pub segment_retries: u32,
+}
+
+/// Settings for one rung of the ladder that differ from the profile's.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RungSpec {
+    /// Ladder bitrate, in MB/s, of the rung these apply to
+    pub bitrate: u32,
+    /// Picture height to scale to, keeping the aspect ratio; sources no
+    /// taller skip the rung rather than be upscaled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// SVT-AV1 preset, instead of the profile's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<u32>,
+    /// Seconds between keyframes, instead of one per segment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyframe_secs: Option<f64>,
+    /// Caps fields the picture is converted to before encoding, like
+    /// `format=I420_10LE` or `colorimetry=bt709`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caps: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncodingProfile {
+    /// Video bitrates in MB/s, one representation each
+    pub ladder: Vec<u32>,
+    /// Height, preset or keyframe interval for particular rungs
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rungs: Vec<RungSpec>,
+    /// SVT-AV1 preset; lower is slower and better
+    pub encoder_preset: u32,
+    /// Which encoders to run on, unless the run asks for others
+    #[serde(skip_serializing_if = "Encoder::is_auto")]
+    pub encoder: Encoder,
+    /// Target DASH segment duration in seconds
+    pub segment_duration: u32,
+    pub packaging: PackagingSpec,
+    pub audio: AudioSpec,
+    pub subtitles: SubtitleSpec,
+    /// Ladder bitrate of the review representation to burn the running
+    /// timecode into
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timecode_rung: Option<u32>,
+    /// Ladder bitrate to also encode as H.264, in an adaptation set of its
+    /// own, for players that can't decode AV1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub h264_rung: Option<u32>,
+    /// Language to signal for tracks the source leaves untagged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_language: Option<String>,
+    /// Highest AV1 level the household's decoders handle, like `4.0` or
+    /// `5.1-high`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoder_level: Option<String>,
+    /// What to do with rungs beyond `decoder_level`
+    pub level_policy: LevelPolicy,
+    /// AV1 level and tier to signal for every rung, like `4.0` or
+    /// `5.1-high`, instead of the lowest each one fits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec_level: Option<String>,
+    /// Frame rate to conform every title to, like `24000/1001` or `25`, so a
+    /// series whose episodes differ plays back to back at one rate; unset
+    /// keeps the source's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<FrameRate>,
+}
+
+impl Default for EncodingProfile {
+    fn default() -> Self {
+        Self {
+            ladder: vec![6, 2],
+            rungs: Vec::new(),
+            encoder_preset: 8,
+            encoder: Encoder::Auto,
+            segment_duration: 4,
+            packaging: PackagingSpec::default(),
+            audio: AudioSpec::default(),
+            subtitles: SubtitleSpec::default(),
+            timecode_rung: None,
+            h264_rung: None,
+            default_language: None,
+            decoder_level: None,
+            level_policy: LevelPolicy::default(),
+            codec_level: None,
+            frame_rate: None,
+        }
+    }
+}
+
+impl EncodingProfile {
+    pub fn from_json(json: &str) -> Result<Self> {
+        let profile: Self = serde_json::from_str(json)?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        let profile: Self = toml::from_str(toml)?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    /// Settings particular to the rung of `bitrate`, if there are any.
+    pub fn rung(&self, bitrate: u32) -> Option<&RungSpec> {
+        self.rungs.iter().find(|rung| rung.bitrate == bitrate)
+    }
+
+    /// Seconds between keyframes in the rung of `bitrate`.
+    pub fn keyframe_secs(&self, bitrate: u32) -> f64 {
+        self.rung(bitrate)
+            .and_then(|rung| rung.keyframe_secs)
+            .unwrap_or(self.segment_duration as f64)
+    }
+
+    /// Name of the encoder implementation this profile runs on, used to
+    /// apply per-backend concurrency limits.
+    pub fn encoder_backend(&self) -> &'static str {
+        self.encoder.backend()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.ladder.is_empty() {
+            bail!("The encoding ladder needs at least one bitrate");
+        }
+        if self.segment_duration == 0 {
+            bail!("Segment duration must be at least one second");
+        }
+        for (i, rung) in self.rungs.iter().enumerate() {
+            if !self.ladder.contains(&rung.bitrate) {
+                bail!(
+                    "Settings for rung {} MB/s, which isn't in the ladder",
+                    rung.bitrate
+                );
+            }
+            if self.rungs[..i]
+                .iter()
+                .any(|other| other.bitrate == rung.bitrate)
+            {
+                bail!("Rung {} MB/s has settings twice", rung.bitrate);
+            }
+            if let Some(height) = rung.height
+                && (height == 0 || height % 2 != 0)
+            {
+                bail!("Rung heights must be even and more than 0, not {}", height);
+            }
+            if let Some(preset) = rung.preset
+                && preset > 13
+            {
+                bail!("SVT-AV1 presets go from 0 to 13, not {}", preset);
+            }
+            if let Some(secs) = rung.keyframe_secs
+                && !(secs > 0.0 && secs <= self.segment_duration as f64)
+            {
+                bail!(
+                    "Keyframes must come at least once a segment, not every {} seconds",
+                    secs
+                );
+            }
+            // Fields of the one structure, which the caps' media type leads
+            if let Some(caps) = &rung.caps
+                && (caps.is_empty()
+                    || caps.contains(';')
+                    || !caps.split(',').all(|f| f.contains('=')))
+            {
+                bail!("Rung caps are fields like format=I420_10LE, not {:?}", caps);
+            }
+        }
+        self.subtitles.burn_in.validate()?;
+        if let Some(fragment_ms) = self.packaging.fragment_duration_ms
+            && (fragment_ms == 0 || fragment_ms > self.segment_duration * 1000)
+        {
+            bail!(
+                "Fragments must last between 1 ms and the segment duration, not {} ms",
+                fragment_ms
+            );
+        }
+        if let Some(url) = &self.packaging.utc_timing
+            && !url.starts_with("http://")
+            && !url.starts_with("https://")
+        {
+            bail!("The UTC timing source must be an HTTP URL, not {:?}", url);
+        }
+        if let Some(rung) = self.timecode_rung
+            && !self.ladder.contains(&rung)
+        {
+            bail!("The timecode rung {} MB/s is not in the ladder", rung);
+        }
+        if let Some(rung) = self.h264_rung
+            && !self.ladder.contains(&rung)
+        {
+            bail!("The H.264 rung {} MB/s is not in the ladder", rung);
+        }
+        if let Some(default) = &self.default_language {
+            language::parse(default)?;
+        }
+        for kept in &self.subtitles.languages {
+            language::parse(kept)?;
+        }
+        for level in self.decoder_level.iter().chain(&self.codec_level) {
+            levels::parse(level)?;
+        }
+        if !OPUS_FRAME_DURATIONS.contains(&self.audio.frame_duration_ms) {
+            bail!(
+                "Opus frames last 2.5, 5, 10, 20, 40 or 60 ms, not {}",
+                self.audio.frame_duration_ms
+            );
+        }
+        if !OPUS_SAMPLE_RATES.contains(&self.audio.sample_rate) {
+            bail!(
+                "Opus encodes at 8000, 12000, 16000, 24000 or 48000 Hz, not {}",
+                self.audio.sample_rate
+            );
+        }
+        if self.audio.resample_quality > 10 {
+            bail!(
+                "Resample quality goes from 0 to 10, not {}",
+                self.audio.resample_quality
+            );
+        }
+        if let Some(kbps) = self.audio.mono_kbps
+            && !(6..self.audio.bitrate_kbps).contains(&kbps)
+        {
+            bail!(
+                "The mono rendition needs at least 6 kb/s and less than the main audio's {}, not {}",
+                self.audio.bitrate_kbps,
+                kbps
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A complete, self-contained preparation request.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct JobSpec {
+    /// The [`SPEC_VERSION`] the spec was written for
+    pub version: u32,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    #[serde(default)]
+    pub profile: EncodingProfile,
+    /// Ranges of the input to leave out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cuts: Vec<Cut>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<Watermark>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grade: Option<Grade>,
+}
+
+impl JobSpec {
+    pub fn new(input: impl Into<PathBuf>, output: impl Into<PathBuf>) -> Self {
+        Self {
+            version: SPEC_VERSION,
+            input: input.into(),
+            output: output.into(),
+            profile: EncodingProfile::default(),
+            cuts: Vec::new(),
+            watermark: None,
+            grade: None,
+        }
+    }
+
+    /// The JSON Schema describing a spec.
+    pub fn json_schema() -> Result<String> {
+        Ok(serde_json::to_string_pretty(&schemars::schema_for!(
+            JobSpec
+        ))?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let spec: Self = serde_json::from_str(json)?;
+        if spec.version > SPEC_VERSION {
+            bail!(
+                "Job spec version {} is newer than the supported version {}",
+                spec.version,
+                SPEC_VERSION
+            );
+        }
+        spec.profile.validate()?;
+        if spec
+            .cuts
+            .iter()
+            .any(|cut| cut.start_secs < 0.0 || cut.end_secs <= cut.start_secs)
+        {
+            bail!("Every cut must end after it starts");
+        }
+        if let Some(watermark) = &spec.watermark {
+            watermark.validate()?;
+        }
+        Ok(spec)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
@@ -0,0 +1,246 @@
+//! Writing segments through a local staging directory, so a write to the
+//! output that fails, like on an NFS blip, can be retried for that segment
+//! alone instead of failing the job.
+//!
+//! dashsink writes into the staging directory. As each fragment closes, its
+//! segment is moved into the output along with the manifest and the
+//! initialization segments the manifest names; whatever is left follows
+//! once the pipeline has finished.
+
+use crate::preparer::MANIFEST_FILENAME;
+use anyhow::{Context, Result, anyhow};
+use quick_xml::Reader;
+use quick_xml::XmlVersion;
+use quick_xml::events::Event;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Wait before the first retry, doubled for each one after it
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// Run `write` until it succeeds, trying again up to `retries` times with
+/// backoff. Returns how many attempts failed.
+fn with_retries(
+    retries: u32,
+    backoff: Duration,
+    mut write: impl FnMut() -> std::io::Result<()>,
+) -> Result<u32> {
+    let mut failed = 0;
+    loop {
+        match write() {
+            Ok(()) => return Ok(failed),
+            Err(_) if failed < retries => {
+                std::thread::sleep(backoff * 2u32.pow(failed));
+                failed += 1;
+            }
+            Err(err) => return Err(anyhow!("{} (after {} attempts)", err, failed + 1)),
+        }
+    }
+}
+
+/// Put `from` at `to`, leaving `from` alone when `keep` is set. Copies go to
+/// a temporary name first so a failed one never looks like the real file.
+fn transfer(from: &Path, to: &Path, keep: bool) -> std::io::Result<()> {
+    if !keep {
+        match std::fs::rename(from, to) {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == ErrorKind::CrossesDevices => (),
+            Err(err) => return Err(err),
+        }
+    }
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(".part");
+    std::fs::copy(from, &partial)?;
+    std::fs::rename(&partial, to)?;
+    if !keep {
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// Initialization segments `xml` names.
+fn initializations(xml: &str) -> Vec<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut names = Vec::new();
+    while let Ok(event) = reader.read_event() {
+        match event {
+            Event::Eof => break,
+            Event::Start(element) | Event::Empty(element) => {
+                let attribute = match element.local_name().into_inner() {
+                    "Initialization" => "sourceURL",
+                    "SegmentTemplate" => "initialization",
+                    _ => continue,
+                };
+                if let Ok(Some(value)) = element.try_get_attribute(attribute)
+                    && let Ok(value) = value.normalized_value(XmlVersion::Implicit1_0)
+                {
+                    names.push(value.into_owned());
+                }
+            }
+            _ => (),
+        }
+    }
+    names
+}
+
+/// A segment whose write into the output had to be retried.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Retried {
+    pub(crate) location: PathBuf,
+    /// How many attempts failed before one went through
+    pub(crate) failed: u32,
+}
+
+/// Segments on their way from the staging directory into the output.
+pub(crate) struct SegmentStaging {
+    dir: PathBuf,
+    output: PathBuf,
+    retries: u32,
+    backoff: Duration,
+    /// Initialization segments already in the output
+    moved: HashSet<String>,
+}
+
+impl SegmentStaging {
+    /// Stage segments bound for `output` under `scratch`, retrying each
+    /// write up to `retries` times.
+    pub(crate) fn new(scratch: &Path, output: &Path, retries: u32) -> Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let dir = scratch.join(format!(
+            "movieshare-staging-{}-{}",
+            std::process::id(),
+            nanos
+        ));
+        std::fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            output: output.to_path_buf(),
+            retries,
+            backoff: BACKOFF,
+            moved: HashSet::new(),
+        })
+    }
+
+    /// Where dashsink should write.
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Put `name` from the staging directory into the output, copying it
+    /// when dashsink will write it again.
+    fn place(&self, name: &str, keep: bool) -> Result<Option<Retried>> {
+        let (from, to) = (self.dir.join(name), self.output.join(name));
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create {}", parent.display()))?;
+        }
+        let failed = with_retries(self.retries, self.backoff, || transfer(&from, &to, keep))
+            .context(format!("Failed to write {}", to.display()))?;
+        Ok((failed > 0).then_some(Retried {
+            location: to,
+            failed,
+        }))
+    }
+
+    /// Move the segment dashsink just closed at `location` into the output,
+    /// with the manifest and any initialization segment it newly names.
+    /// Returns where the segment is now, and the writes that were retried.
+    pub(crate) fn segment_closed(&mut self, location: &Path) -> Result<(PathBuf, Vec<Retried>)> {
+        let name = location
+            .strip_prefix(&self.dir)
+            .unwrap_or(location)
+            .to_string_lossy()
+            .into_owned();
+        let mut retried = Vec::new();
+        retried.extend(self.place(&name, false)?);
+        let manifest = self.dir.join(MANIFEST_FILENAME);
+        if let Ok(xml) = std::fs::read_to_string(&manifest) {
+            for init in initializations(&xml) {
+                if !self.moved.contains(&init) && self.dir.join(&init).is_file() {
+                    retried.extend(self.place(&init, false)?);
+                    self.moved.insert(init);
+                }
+            }
+            // Last, so it never points at a segment that isn't there yet
+            retried.extend(self.place(MANIFEST_FILENAME, true)?);
+        }
+        Ok((self.output.join(name), retried))
+    }
+
+    /// Move everything left once the pipeline has finished, the manifest
+    /// last. Returns the writes that were retried.
+    pub(crate) fn finish(self) -> Result<Vec<Retried>> {
+        let mut names: Vec<String> = std::fs::read_dir(&self.dir)
+            .context(format!("Failed to read {}", self.dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort_by_key(|name| name == MANIFEST_FILENAME);
+        let mut retried = Vec::new();
+        for name in names {
+            retried.extend(self.place(&name, false)?);
+        }
+        Ok(retried)
+    }
+}
+
+impl Drop for SegmentStaging {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_each_segment_on_its_own() {
+        let mut failures = 2;
+        let flaky = || match failures {
+            0 => Ok(()),
+            _ => {
+                failures -= 1;
+                Err(std::io::Error::other("stale file handle"))
+            }
+        };
+        assert_eq!(with_retries(3, Duration::ZERO, flaky).unwrap(), 2);
+        let broken = || Err(std::io::Error::other("stale file handle"));
+        let err = with_retries(1, Duration::ZERO, broken).unwrap_err();
+        assert_eq!(err.to_string(), "stale file handle (after 2 attempts)");
+
+        let output = std::env::temp_dir().join(format!("movieshare-staged-{}", std::process::id()));
+        std::fs::create_dir_all(&output).unwrap();
+        let mut staging = SegmentStaging::new(&std::env::temp_dir(), &output, 3).unwrap();
+        let staged = staging.dir().to_path_buf();
+        std::fs::write(
+            staged.join(MANIFEST_FILENAME),
+            r#"<MPD><Period><AdaptationSet><Representation id="0"><SegmentList><Initialization sourceURL="video_0_init.mp4"/><SegmentURL media="video_0_00001.m4s"/></SegmentList></Representation></AdaptationSet></Period></MPD>"#,
+        )
+        .unwrap();
+        std::fs::write(staged.join("video_0_init.mp4"), "init").unwrap();
+        std::fs::write(staged.join("video_0_00001.m4s"), "one").unwrap();
+        // Still being written, so it stays until its fragment closes
+        std::fs::write(staged.join("video_0_00002.m4s"), "tw").unwrap();
+
+        let (location, retried) = staging
+            .segment_closed(&staged.join("video_0_00001.m4s"))
+            .unwrap();
+        assert_eq!(location, output.join("video_0_00001.m4s"));
+        assert!(retried.is_empty());
+        assert!(output.join("video_0_init.mp4").is_file());
+        assert!(output.join(MANIFEST_FILENAME).is_file());
+        assert!(staged.join(MANIFEST_FILENAME).is_file());
+        assert!(!output.join("video_0_00002.m4s").exists());
+
+        staging.finish().unwrap();
+        assert!(output.join("video_0_00002.m4s").is_file());
+        assert!(!staged.exists());
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+}
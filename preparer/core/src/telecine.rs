@@ -0,0 +1,282 @@
+//! Undoing 3:2 pulldown. Film shot at 24 frames a second reaches NTSC video
+//! at 29.97 by spreading every four frames over five, two of them woven
+//! from the fields of neighbouring frames. Encoding that as it is spends
+//! bitrate on the repeated fields and keeps the judder; GStreamer's `ivtc`
+//! weaves the fields back into the original frames.
+//!
+//! Telecined sources are told apart from interlaced and progressive ones by
+//! the cadence of their combed frames: two in every five, always in the
+//! same place.
+
+use anyhow::{Context, Result, anyhow, bail};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// How many frames [`detect`] looks at: half a minute's worth
+const SAMPLE_FRAMES: usize = 900;
+/// How far apart, in luma levels, a line has to be from both of its
+/// neighbours for a pixel to be combed
+const COMB_DELTA: i32 = 24;
+/// Share of combed pixels past which a frame counts as combed
+const COMBED_FRAME: f64 = 0.02;
+
+/// Whether to undo pulldown.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InverseTelecine {
+    /// When the source looks telecined
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for InverseTelecine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "auto" => InverseTelecine::Auto,
+            "always" => InverseTelecine::Always,
+            "never" => InverseTelecine::Never,
+            _ => bail!(
+                "Unknown inverse telecine setting {:?}; expected auto, always or never",
+                s
+            ),
+        })
+    }
+}
+
+/// Share of pixels in a gray frame whose line stands out from the lines
+/// above and below in the same direction, as where fields from two moments
+/// are woven together.
+pub fn combing(luma: &[u8], width: usize, height: usize) -> f64 {
+    if height < 3 || luma.len() < width * height {
+        return 0.0;
+    }
+    let mut combed = 0;
+    for y in 1..height - 1 {
+        let rows = [y - 1, y, y + 1].map(|row| &luma[row * width..(row + 1) * width]);
+        combed += (0..width)
+            .filter(|&x| {
+                let [above, line, below] = rows.map(|row| row[x] as i32);
+                (line - above) * (line - below) > COMB_DELTA * COMB_DELTA
+            })
+            .count();
+    }
+    combed as f64 / (width * (height - 2)) as f64
+}
+
+/// Whether frames combed or not, in order, follow the pulldown cadence:
+/// enough of them combed, nearly all in two neighbouring places of five.
+pub fn is_telecined(combed: &[bool]) -> bool {
+    let mut phases = [0; 5];
+    for (i, _) in combed.iter().enumerate().filter(|(_, combed)| **combed) {
+        phases[i % 5] += 1;
+    }
+    let total: usize = phases.iter().sum();
+    // Stills and fades don't comb, so there's no telling from those
+    if total < combed.len() / 10 || total < 10 {
+        return false;
+    }
+    let pair = (0..5)
+        .map(|phase| phases[phase] + phases[(phase + 1) % 5])
+        .max()
+        .unwrap_or(0);
+    pair as f64 >= total as f64 * 0.8
+}
+
+/// Decode a stretch from a third of the way into `input` and tell whether
+/// its video is telecined film. Only 29.97 fps video can be.
+pub fn detect(input: &Path) -> Result<bool> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*input.to_string_lossy())
+        .build()?;
+    let decodebin = gst::ElementFactory::make("decodebin").build()?;
+    let queue = gst::ElementFactory::make("queue").build()?;
+    let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+    let capsfilter = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("format", "GRAY8")
+                .build(),
+        )
+        .build()?;
+    let sink = gst::ElementFactory::make("fakesink").build()?;
+    pipeline.add_many([
+        &filesrc,
+        &decodebin,
+        &queue,
+        &videoconvert,
+        &capsfilter,
+        &sink,
+    ])?;
+    filesrc.link(&decodebin)?;
+    gst::Element::link_many([&queue, &videoconvert, &capsfilter, &sink])?;
+
+    // Only the first video stream is looked at, and everything else dropped
+    let queue_weak = queue.downgrade();
+    let pipeline_weak = pipeline.downgrade();
+    let ntsc = Arc::new(Mutex::new(None));
+    let pad_ntsc = ntsc.clone();
+    decodebin.connect_pad_added(move |_, src_pad| {
+        let (Some(queue), Some(pipeline)) = (queue_weak.upgrade(), pipeline_weak.upgrade()) else {
+            return;
+        };
+        let caps = src_pad.current_caps();
+        let structure = caps.as_ref().and_then(|caps| caps.structure(0));
+        let sink_pad = queue.static_pad("sink").unwrap();
+        if let Some(structure) = structure
+            && structure.name().starts_with("video/x-raw")
+            && !sink_pad.is_linked()
+        {
+            *pad_ntsc.lock().unwrap() = Some(
+                structure
+                    .get::<gst::Fraction>("framerate")
+                    .is_ok_and(|rate| rate == gst::Fraction::new(30000, 1001)),
+            );
+            src_pad
+                .link(&sink_pad)
+                .expect("Failed to link decodebin to telecine detection");
+            return;
+        }
+        let fakesink = gst::ElementFactory::make("fakesink")
+            .build()
+            .expect("Failed to create fakesink");
+        pipeline.add(&fakesink).expect("Failed to add fakesink");
+        fakesink
+            .sync_state_with_parent()
+            .expect("Failed to start fakesink");
+        src_pad
+            .link(&fakesink.static_pad("sink").unwrap())
+            .expect("Failed to link decodebin to fakesink");
+    });
+
+    let combed = Arc::new(Mutex::new(Vec::new()));
+    let frames = Arc::new(AtomicUsize::new(0));
+    let (probe_combed, probe_frames) = (combed.clone(), frames.clone());
+    sink.static_pad("sink")
+        .context("Failed to get sink pad from fakesink")?
+        .add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            if let Some(buffer) = info.buffer()
+                && let Some(caps) = pad.current_caps()
+                && let Some(structure) = caps.structure(0)
+                && let Ok(width) = structure.get::<i32>("width")
+                && let Ok(height) = structure.get::<i32>("height")
+                && let Ok(map) = buffer.map_readable()
+                && probe_frames.fetch_add(1, Ordering::Relaxed) < SAMPLE_FRAMES
+            {
+                // Gray rows are padded to a multiple of 4 bytes
+                let stride = (width as usize).next_multiple_of(4);
+                let combing = combing(&map, stride, height as usize);
+                probe_combed.lock().unwrap().push(combing > COMBED_FRAME);
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+    let bus = pipeline.bus().unwrap();
+    let wait = |done: &dyn Fn(&gst::MessageView) -> bool| -> Result<()> {
+        loop {
+            use gst::MessageView;
+
+            let Some(message) = bus.timed_pop(gst::ClockTime::from_mseconds(100)) else {
+                if frames.load(Ordering::Relaxed) >= SAMPLE_FRAMES {
+                    return Ok(());
+                }
+                continue;
+            };
+            match message.view() {
+                MessageView::Error(err) => {
+                    return Err(anyhow!(
+                        "Error from {:?}: {} ({:?})",
+                        err.src().map(|s| s.path_string()),
+                        err.error(),
+                        err.debug()
+                    ));
+                }
+                MessageView::Eos(..) => return Ok(()),
+                view if done(&view) => return Ok(()),
+                _ => (),
+            }
+        }
+    };
+    // Openings are often titles on black, which don't comb
+    pipeline.set_state(gst::State::Paused)?;
+    let result = wait(&|view| matches!(view, gst::MessageView::AsyncDone(..))).and_then(|_| {
+        if let Some(duration) = pipeline.query_duration::<gst::ClockTime>() {
+            pipeline.seek_simple(
+                gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+                duration / 3,
+            )?;
+        }
+        combed.lock().unwrap().clear();
+        frames.store(0, Ordering::Relaxed);
+        pipeline.set_state(gst::State::Playing)?;
+        wait(&|_| false)
+    });
+    pipeline.set_state(gst::State::Null)?;
+    result.context(format!(
+        "Failed to look for pulldown in {}",
+        input.display()
+    ))?;
+
+    let ntsc = ntsc.lock().unwrap().unwrap_or(false);
+    Ok(ntsc && is_telecined(&combed.lock().unwrap()))
+}
+
+/// Add `ivtc` to the pipeline in front of `downstream`, returning the pad
+/// decoded video should be linked to.
+pub(crate) fn insert(pipeline: &gst::Pipeline, downstream: &gst::Pad) -> Result<gst::Pad> {
+    let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+    let ivtc = gst::ElementFactory::make("ivtc")
+        .build()
+        .context("Inverse telecine needs the ivtc element from gst-plugins-bad")?;
+    pipeline.add_many([&videoconvert, &ivtc])?;
+    videoconvert.link(&ivtc)?;
+    ivtc.static_pad("src")
+        .context("Failed to get src pad from ivtc")?
+        .link(downstream)?;
+    videoconvert
+        .static_pad("sink")
+        .context("Failed to get sink pad from videoconvert")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_pulldown_cadence() {
+        // Alternating lines of a woven frame comb, a smooth gradient doesn't
+        let (width, height) = (8, 6);
+        let woven: Vec<u8> = (0..width * height)
+            .map(|i| match (i / width) % 2 {
+                0 => 40,
+                _ => 200,
+            })
+            .collect();
+        let smooth: Vec<u8> = (0..width * height)
+            .map(|i| (i / width * 10) as u8)
+            .collect();
+        assert_eq!(combing(&woven, width, height), 1.0);
+        assert_eq!(combing(&smooth, width, height), 0.0);
+
+        // AA BB BC CD DD: the third and fourth frames of every five are woven
+        let pulldown: Vec<bool> = (0..100).map(|i| matches!(i % 5, 2 | 3)).collect();
+        assert!(is_telecined(&pulldown));
+        // Interlaced video combs whenever it moves, with no cadence
+        assert!(!is_telecined(&[true; 100]));
+        assert!(!is_telecined(&[false; 100]));
+        // A still stretch leaves too little to go on
+        let mostly_still: Vec<bool> = (0..100).map(|i| i < 10 && i % 5 == 2).collect();
+        assert!(!is_telecined(&mostly_still));
+    }
+}
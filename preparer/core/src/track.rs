@@ -0,0 +1,467 @@
+//! Encoding one track on its own, like a dub delivered after the title was
+//! prepared or a rung that came out too soft, into a presentation of its
+//! own that can then be merged into the title's manifest.
+
+use crate::branch::{AudioBranch, EncodingBranch, MediaType, PipelineBranch};
+use crate::encoder::{Codec, Encoder};
+use crate::factory::GstFactory;
+use crate::fonts::{self, Font};
+use crate::language;
+use crate::levels;
+use crate::loudness;
+use crate::packaging;
+use crate::pads;
+use crate::plan;
+use crate::preparer::MANIFEST_FILENAME;
+use crate::sidx;
+use crate::spec::EncodingProfile;
+use anyhow::{Context, Result, anyhow, bail};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Encode the first audio stream of `input` as the profile's audio, into a
+/// DASH presentation in `output_dir` holding only that stream.
+pub fn encode_audio(input: &Path, output_dir: &Path, profile: &EncodingProfile) -> Result<()> {
+    encode_audio_stream(input, output_dir, profile, 0).map(|_| ())
+}
+
+/// Encode audio stream `index` of `input`, counting from 0, like
+/// [`encode_audio`]. Returns the language it was tagged with.
+pub fn encode_audio_stream(
+    input: &Path,
+    output_dir: &Path,
+    profile: &EncodingProfile,
+    index: usize,
+) -> Result<Option<String>> {
+    let branch = AudioBranch::new(&mut GstFactory, &profile.audio)?;
+    let language = encode(input, output_dir, profile, &branch, index).context(format!(
+        "Failed to encode audio stream {} of {}",
+        index,
+        input.display()
+    ))?;
+    let measured = loudness::signal(output_dir, &[branch.loudness()])?;
+    if measured.iter().any(Option::is_some) {
+        let manifest = output_dir.join(MANIFEST_FILENAME);
+        let xml = std::fs::read_to_string(&manifest)
+            .context(format!("Failed to read {}", manifest.display()))?;
+        std::fs::write(&manifest, loudness::tag_manifest(&xml, &measured)?)
+            .context(format!("Failed to write {}", manifest.display()))?;
+    }
+    Ok(language)
+}
+
+/// Encode the first video stream of `input` at `bitrate_mbps`, within
+/// `width`x`height`, into a DASH presentation in `output_dir` holding only
+/// that representation.
+pub fn encode_video(
+    input: &Path,
+    output_dir: &Path,
+    profile: &EncodingProfile,
+    bitrate_mbps: u32,
+    (width, height): (u32, u32),
+) -> Result<()> {
+    // As the preparer would, from the source's framerate if it gives one
+    let fps = plan::probe(input)
+        .ok()
+        .and_then(|source| source.framerate)
+        .unwrap_or(30.0);
+    let branch = EncodingBranch::new(
+        &mut GstFactory,
+        Encoder::default().find(Codec::Av1)?,
+        bitrate_mbps,
+        profile.encoder_preset,
+        (fps * profile.segment_duration as f64).round() as u32,
+    )?
+    .limit_size(&mut GstFactory, width, height)?;
+    encode(input, output_dir, profile, &branch, 0)
+        .context(format!("Failed to encode the video of {}", input.display()))?;
+
+    let codec_level = profile
+        .codec_level
+        .as_deref()
+        .map(levels::parse)
+        .transpose()?;
+    let manifest = output_dir.join(MANIFEST_FILENAME);
+    let xml = std::fs::read_to_string(&manifest)
+        .context(format!("Failed to read {}", manifest.display()))?;
+    std::fs::write(
+        &manifest,
+        levels::tag_manifest(&xml, codec_level, fps.round() as u32)?,
+    )
+    .context(format!("Failed to write {}", manifest.display()))
+}
+
+/// Run `branch` over stream `index` of its type in `input`, returning the
+/// language the stream was tagged with.
+fn encode(
+    input: &Path,
+    output_dir: &Path,
+    profile: &EncodingProfile,
+    branch: &dyn PipelineBranch,
+    index: usize,
+) -> Result<Option<String>> {
+    gst::init()?;
+    std::fs::create_dir_all(output_dir).context(format!(
+        "Failed to create output directory: {}",
+        output_dir.display()
+    ))?;
+
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*input.to_string_lossy())
+        .build()?;
+    let decodebin = gst::ElementFactory::make("decodebin").build()?;
+    let tee = gst::ElementFactory::make("tee").build()?;
+    let dashsink = gst::ElementFactory::make("dashsink")
+        .property("mpd-filename", MANIFEST_FILENAME)
+        .property("mpd-root-path", &*output_dir.to_string_lossy())
+        .property("target-duration", profile.segment_duration)
+        .property_from_str("muxer", "dashmp4")
+        .build()?;
+    packaging::configure_muxers(&dashsink, &profile.packaging);
+    pipeline.add_many([&filesrc, &decodebin, &tee, &dashsink])?;
+    filesrc.link(&decodebin)?;
+    branch.add_to_pipeline(&pipeline)?;
+    branch.link(&tee, &dashsink)?;
+
+    let (wanted, unwanted) = match branch.media_type() {
+        MediaType::Video => ("video/", "audio/"),
+        MediaType::Audio => ("audio/", "video/"),
+    };
+    // Leave the other kind of stream undecoded, since nothing plays it
+    decodebin.connect("autoplug-continue", false, move |values| {
+        let caps = values[2].get::<gst::Caps>().ok();
+        let unwanted = caps
+            .as_ref()
+            .and_then(|caps| caps.structure(0))
+            .is_some_and(|s| s.name().starts_with(unwanted));
+        Some((!unwanted).to_value())
+    });
+    let tee_sink = tee
+        .static_pad("sink")
+        .context("Failed to get sink pad from tee")?;
+    let linked = tee_sink.clone();
+    let language = Arc::new(Mutex::new(None));
+    let stream_language = language.clone();
+    let seen = Arc::new(Mutex::new(0));
+    decodebin.connect_pad_added(move |dbin, src_pad| {
+        let (tee_sink, language, seen) = (tee_sink.clone(), stream_language.clone(), seen.clone());
+        let dbin = dbin.downgrade();
+        pads::with_caps(src_pad, move |pad, caps| {
+            if !caps
+                .structure(0)
+                .is_some_and(|s| s.name().starts_with(wanted))
+            {
+                return;
+            }
+            let mut seen = seen.lock().unwrap();
+            *seen += 1;
+            if *seen != index + 1 || tee_sink.is_linked() {
+                return;
+            }
+            watch_language(pad, language);
+            if let Err(err) = pad.link(&tee_sink)
+                && let Some(dbin) = dbin.upgrade()
+            {
+                gst::element_error!(
+                    dbin,
+                    gst::CoreError::Negotiation,
+                    ("Failed to link the decoded stream: {}", err)
+                );
+            }
+        });
+    });
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline.bus().unwrap();
+    let result = loop {
+        use gst::MessageView;
+
+        let Some(message) = bus.timed_pop(gst::ClockTime::NONE) else {
+            break Ok(());
+        };
+        match message.view() {
+            MessageView::Eos(..) => break Ok(()),
+            MessageView::Error(err) => {
+                break Err(anyhow!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ));
+            }
+            _ => (),
+        }
+    };
+    pipeline.set_state(gst::State::Null)?;
+    result?;
+    if !linked.is_linked() {
+        bail!("{} has no stream {} of that kind", input.display(), index);
+    }
+    if profile.packaging.sidx {
+        sidx::index_segments(output_dir)?;
+    }
+    Ok(language.lock().unwrap().take())
+}
+
+/// Keep the language `pad`'s stream is tagged with in `language`.
+fn watch_language(pad: &gst::Pad, language: Arc<Mutex<Option<String>>>) {
+    pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        if let Some(gst::EventView::Tag(tag)) = info.event().map(|e| e.view())
+            && let Some(code) = tag.tag().get::<gst::tags::LanguageCode>()
+            && let Some(normalized) = language::normalize(code.get())
+        {
+            *language.lock().unwrap() = Some(normalized);
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// A subtitle stream, read out of its container.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Subtitles {
+    /// The cues as a WebVTT file
+    pub vtt: String,
+    pub language: Option<String>,
+    /// Fonts attached to the source, which styled subtitles are set in
+    pub fonts: Vec<Font>,
+    /// Fonts the styles of an ASS script ask for, its default style's first
+    pub style_fonts: Vec<String>,
+}
+
+/// The header of the ASS script parsed into `pad`, if it's one: its script
+/// info and styles, which the parser drops.
+fn ass_header(pad: &gst::Pad) -> Option<String> {
+    let parser = pad
+        .downcast_ref::<gst::GhostPad>()?
+        .target()?
+        .parent_element()?;
+    let caps = parser
+        .static_pad("sink")?
+        .sticky_event::<gst::event::Caps>(0)?;
+    let structure = caps.caps().structure(0)?;
+    if !matches!(
+        structure.name().as_str(),
+        "application/x-ass" | "application/x-ssa"
+    ) {
+        return None;
+    }
+    let header = structure.get::<gst::Buffer>("codec_data").ok()?;
+    let map = header.map_readable().ok()?;
+    Some(String::from_utf8_lossy(&map).into_owned())
+}
+
+/// Read text subtitle stream `index` of `input`, counting from 0, as WebVTT.
+///
+/// Nothing is decoded, so this takes about as long as reading the file.
+pub fn extract_subtitles(input: &Path, index: usize) -> Result<Subtitles> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*input.to_string_lossy())
+        .build()?;
+    let parsebin = gst::ElementFactory::make("parsebin").build()?;
+    pipeline.add_many([&filesrc, &parsebin])?;
+    filesrc.link(&parsebin)?;
+
+    let cues = Arc::new(Mutex::new(Vec::new()));
+    let language = Arc::new(Mutex::new(None));
+    let found = Arc::new(Mutex::new(false));
+    let attached = Arc::new(Mutex::new(Vec::new()));
+    let style_fonts = Arc::new(Mutex::new(Vec::new()));
+    let (pad_cues, pad_language, pad_found) = (cues.clone(), language.clone(), found.clone());
+    let (pad_attached, pad_style_fonts) = (attached.clone(), style_fonts.clone());
+    let pipeline_weak = pipeline.downgrade();
+    let seen = Arc::new(Mutex::new(0));
+    parsebin.connect_pad_added(move |parsebin, src_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        // Every stream is read and dropped, so the demuxer keeps going
+        if let Err(err) = pads::drain(&pipeline, src_pad) {
+            gst::element_error!(
+                parsebin,
+                gst::CoreError::Negotiation,
+                ("Failed to read a stream of the source: {:#}", err)
+            );
+            return;
+        }
+        let (pad_cues, pad_language, pad_found, seen) = (
+            pad_cues.clone(),
+            pad_language.clone(),
+            pad_found.clone(),
+            seen.clone(),
+        );
+        let (pad_attached, pad_style_fonts) = (pad_attached.clone(), pad_style_fonts.clone());
+        pads::with_caps(src_pad, move |src_pad, caps| {
+            if caps.structure(0).is_none_or(|s| s.name() != "text/x-raw") {
+                return;
+            }
+            let mut seen = seen.lock().unwrap();
+            *seen += 1;
+            if *seen != index + 1 {
+                return;
+            }
+            *pad_found.lock().unwrap() = true;
+            watch_language(src_pad, pad_language);
+            if let Some(header) = ass_header(src_pad) {
+                *pad_style_fonts.lock().unwrap() = fonts::style_fonts(&header);
+            }
+            let attached = pad_attached;
+            src_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+                if let Some(gst::EventView::Tag(tag)) = info.event().map(|e| e.view()) {
+                    let mut attached = attached.lock().unwrap();
+                    for font in fonts::from_tags(tag.tag()) {
+                        if !attached.iter().any(|f: &Font| f.filename == font.filename) {
+                            attached.push(font);
+                        }
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+            let cues = pad_cues;
+            src_pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if let Some(buffer) = info.buffer()
+                    && let Some(pts) = buffer.pts()
+                    && let Ok(map) = buffer.map_readable()
+                {
+                    let start = Duration::from_nanos(pts.nseconds());
+                    let end = start
+                        + buffer.duration().map_or(Duration::from_secs(2), |d| {
+                            Duration::from_nanos(d.nseconds())
+                        });
+                    let text = String::from_utf8_lossy(&map).into_owned();
+                    cues.lock().unwrap().push((start, end, text));
+                }
+                gst::PadProbeReturn::Ok
+            });
+        });
+    });
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline.bus().unwrap();
+    let result = loop {
+        use gst::MessageView;
+
+        let Some(message) = bus.timed_pop(gst::ClockTime::NONE) else {
+            break Ok(());
+        };
+        match message.view() {
+            MessageView::Eos(..) => break Ok(()),
+            MessageView::Error(err) => {
+                break Err(anyhow!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ));
+            }
+            _ => (),
+        }
+    };
+    pipeline.set_state(gst::State::Null)?;
+    result.context(format!(
+        "Failed to read subtitle stream {} of {}",
+        index,
+        input.display()
+    ))?;
+    if !*found.lock().unwrap() {
+        bail!("{} has no subtitle stream {}", input.display(), index);
+    }
+
+    let cues = cues.lock().unwrap();
+    let language = language.lock().unwrap().take();
+    let fonts = std::mem::take(&mut *attached.lock().unwrap());
+    let style_fonts = std::mem::take(&mut *style_fonts.lock().unwrap());
+    Ok(Subtitles {
+        vtt: cues_to_vtt(&cues),
+        language,
+        fonts,
+        style_fonts,
+    })
+}
+
+/// Write cues, whose text may carry Pango markup, as a WebVTT file.
+pub fn cues_to_vtt(cues: &[(Duration, Duration, String)]) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for (start, end, text) in cues {
+        let text = vtt_markup(text.trim_end());
+        if text.trim().is_empty() {
+            continue;
+        }
+        vtt.push_str(&format!(
+            "\n{} --> {}\n{}\n",
+            vtt_time(*start),
+            vtt_time(*end),
+            text
+        ));
+    }
+    vtt
+}
+
+/// Keep the italics, bold and underlines WebVTT shares with Pango, dropping
+/// any other tags.
+fn vtt_markup(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        out.push_str(&rest[..open]);
+        let Some(close) = rest[open..].find('>') else {
+            rest = &rest[open..];
+            break;
+        };
+        let tag = &rest[open..open + close + 1];
+        if matches!(tag, "<i>" | "</i>" | "<b>" | "</b>" | "<u>" | "</u>") {
+            out.push_str(tag);
+        }
+        rest = &rest[open + close + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A WebVTT timestamp, like `01:02:03.450`.
+fn vtt_time(time: Duration) -> String {
+    let millis = time.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_cues_as_webvtt() {
+        let cues = [
+            (
+                Duration::from_millis(1_500),
+                Duration::from_millis(3_250),
+                String::from("<span foreground=\"yellow\"><i>Hello</i></span> there\n"),
+            ),
+            (
+                Duration::from_secs(4),
+                Duration::from_secs(5),
+                String::from(" "),
+            ),
+            (
+                Duration::from_secs(3723),
+                Duration::from_millis(3_725_040),
+                String::from("Two\nlines"),
+            ),
+        ];
+        assert_eq!(
+            cues_to_vtt(&cues),
+            "WEBVTT\n\n00:00:01.500 --> 00:00:03.250\n<i>Hello</i> there\n\n\
+             01:02:03.000 --> 01:02:05.040\nTwo\nlines\n"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+//! An image or line of text composited over the video, for traceable
+//! review copies.
+//!
+//! The overlay is applied to the decoded video before the tee, so every
+//! representation of the ladder carries it.
+
+use crate::factory::{ElementFactory, ElementSpec, GstFactory};
+use anyhow::{Context, Result, bail};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Distance from the edges of the picture, in pixels.
+const MARGIN: i32 = 24;
+
+/// What to draw.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mark {
+    /// An image file, such as a PNG logo
+    Image(PathBuf),
+    Text(String),
+}
+
+/// Which corner of the picture the watermark sits in.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Position {
+    TopLeft,
+    #[default]
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Position {
+    fn is_right(self) -> bool {
+        matches!(self, Position::TopRight | Position::BottomRight)
+    }
+
+    fn is_bottom(self) -> bool {
+        matches!(self, Position::BottomLeft | Position::BottomRight)
+    }
+}
+
+impl FromStr for Position {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "top-left" => Position::TopLeft,
+            "top-right" => Position::TopRight,
+            "bottom-left" => Position::BottomLeft,
+            "bottom-right" => Position::BottomRight,
+            _ => bail!(
+                "Unknown position {:?}; expected top-left, top-right, bottom-left or bottom-right",
+                s
+            ),
+        })
+    }
+}
+
+fn default_opacity() -> f64 {
+    0.4
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Watermark {
+    pub mark: Mark,
+    #[serde(default)]
+    pub position: Position,
+    /// From 0.0 (invisible) to 1.0 (opaque)
+    #[serde(default = "default_opacity")]
+    pub opacity: f64,
+}
+
+impl Watermark {
+    pub fn new(mark: Mark) -> Self {
+        Self {
+            mark,
+            position: Position::default(),
+            opacity: default_opacity(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.opacity) {
+            bail!("Watermark opacity must be between 0 and 1");
+        }
+        if let Mark::Text(text) = &self.mark
+            && text.trim().is_empty()
+        {
+            bail!("Watermark text is empty");
+        }
+        Ok(())
+    }
+
+    /// The overlay element for this watermark.
+    fn overlay(&self) -> ElementSpec {
+        match &self.mark {
+            Mark::Image(path) => {
+                // Negative offsets count from the right and bottom edges
+                let offset = |far: bool| match far {
+                    true => -MARGIN,
+                    false => MARGIN,
+                };
+                ElementSpec::new("gdkpixbufoverlay")
+                    .property("location", &*path.to_string_lossy())
+                    .property("alpha", self.opacity)
+                    .property("offset-x", offset(self.position.is_right()))
+                    .property("offset-y", offset(self.position.is_bottom()))
+            }
+            Mark::Text(text) => {
+                let alpha = ((self.opacity * 255.0).round() as u32) << 24;
+                let halignment = match self.position.is_right() {
+                    true => "right",
+                    false => "left",
+                };
+                let valignment = match self.position.is_bottom() {
+                    true => "bottom",
+                    false => "top",
+                };
+                ElementSpec::new("textoverlay")
+                    .property("text", text.as_str())
+                    .property("font-desc", "Sans Bold 20")
+                    .property("color", alpha | 0xffffff)
+                    .property("outline-color", alpha)
+                    .property("shaded-background", false)
+                    .property_from_str("halignment", halignment)
+                    .property_from_str("valignment", valignment)
+                    .property("xpad", MARGIN)
+                    .property("ypad", MARGIN)
+            }
+        }
+    }
+}
+
+/// The elements drawing a watermark between the decoder and the video tee.
+pub(crate) struct WatermarkStage<E = gst::Element> {
+    convert_in: E,
+    overlay: E,
+    convert_out: E,
+}
+
+impl<E: Clone> WatermarkStage<E> {
+    pub(crate) fn new(
+        factory: &mut impl ElementFactory<Element = E>,
+        watermark: &Watermark,
+    ) -> Result<Self> {
+        Ok(Self {
+            convert_in: factory.make(&ElementSpec::new("videoconvert"))?,
+            overlay: factory.make(&watermark.overlay())?,
+            convert_out: factory.make(&ElementSpec::new("videoconvert"))?,
+        })
+    }
+
+    fn link_chain(&self, factory: &mut impl ElementFactory<Element = E>) -> Result<()> {
+        factory.link(&self.convert_in, &self.overlay, None)?;
+        factory.link(&self.overlay, &self.convert_out, None)?;
+        Ok(())
+    }
+}
+
+impl WatermarkStage {
+    /// Add the stage to the pipeline in front of `tee`, returning the pad
+    /// decoded video should be linked to.
+    pub(crate) fn insert(&self, pipeline: &gst::Pipeline, tee: &gst::Element) -> Result<gst::Pad> {
+        pipeline.add_many([&self.convert_in, &self.overlay, &self.convert_out])?;
+        self.link_chain(&mut GstFactory)?;
+        self.convert_out.link(tee)?;
+        self.convert_in
+            .static_pad("sink")
+            .context("Failed to get sink pad from the watermark stage")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::factory::PropertyValue;
+    use crate::factory::testing::RecordingFactory;
+
+    #[test]
+    fn places_marks_in_the_chosen_corner() {
+        let mut factory = RecordingFactory::default();
+        let watermark = Watermark {
+            mark: Mark::Image(PathBuf::from("logo.png")),
+            position: Position::BottomRight,
+            opacity: 0.4,
+        };
+        let stage = WatermarkStage::new(&mut factory, &watermark).unwrap();
+        stage.link_chain(&mut factory).unwrap();
+        assert_eq!(
+            factory.factories(),
+            ["videoconvert", "gdkpixbufoverlay", "videoconvert"]
+        );
+        assert_eq!(factory.links.len(), 2);
+        let overlay = factory.find("gdkpixbufoverlay");
+        assert_eq!(overlay.get("alpha"), Some(&PropertyValue::F64(0.4)));
+        assert_eq!(overlay.get("offset-x"), Some(&PropertyValue::I32(-MARGIN)));
+        assert_eq!(overlay.get("offset-y"), Some(&PropertyValue::I32(-MARGIN)));
+
+        let mut factory = RecordingFactory::default();
+        let watermark = Watermark::new(Mark::Text(String::from("screener for Alice")));
+        WatermarkStage::new(&mut factory, &watermark).unwrap();
+        let overlay = factory.find("textoverlay");
+        assert_eq!(overlay.get("color"), Some(&PropertyValue::U32(0x66ffffff)));
+        assert_eq!(
+            overlay.get("halignment"),
+            Some(&PropertyValue::Parsed(String::from("right")))
+        );
+        assert_eq!(
+            overlay.get("valignment"),
+            Some(&PropertyValue::Parsed(String::from("top")))
+        );
+    }
+}
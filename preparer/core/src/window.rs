@@ -0,0 +1,115 @@
+//! Daily time windows, for keeping heavy encodes to hours when the machine
+//! is otherwise idle.
+
+use anyhow::{Context, Result, bail};
+
+/// A daily window in local time, like `23:00-07:00`; it may run past
+/// midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeWindow {
+    /// Minutes after midnight the window opens
+    pub start: u32,
+    /// Minutes after midnight the window closes
+    pub end: u32,
+}
+
+fn parse_clock(time: &str) -> Result<u32> {
+    let (hours, minutes) = time
+        .trim()
+        .split_once(':')
+        .context(format!("Expected HH:MM, got {:?}", time))?;
+    let hours: u32 = hours
+        .parse()
+        .context(format!("Invalid hour in {:?}", time))?;
+    let minutes: u32 = minutes
+        .parse()
+        .context(format!("Invalid minute in {:?}", time))?;
+    if hours > 23 || minutes > 59 {
+        bail!("No such time of day: {}", time);
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// Parse a window like `23:00-07:00`.
+pub fn parse(window: &str) -> Result<EncodeWindow> {
+    let (start, end) = window
+        .split_once('-')
+        .context(format!("Expected HH:MM-HH:MM, got {:?}", window))?;
+    let window = EncodeWindow {
+        start: parse_clock(start)?,
+        end: parse_clock(end)?,
+    };
+    if window.start == window.end {
+        bail!("Encode window {} is empty", window);
+    }
+    Ok(window)
+}
+
+impl EncodeWindow {
+    /// Whether the window is open `minute` minutes after midnight.
+    pub fn contains(&self, minute: u32) -> bool {
+        match self.start < self.end {
+            true => (self.start..self.end).contains(&minute),
+            false => minute >= self.start || minute < self.end,
+        }
+    }
+
+    /// Whether the window is open now, in local time.
+    pub fn is_open(&self) -> bool {
+        self.contains(local_minute())
+    }
+}
+
+impl std::fmt::Display for EncodeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Minutes since local midnight.
+fn local_minute() -> u32 {
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_may_wrap_past_midnight() {
+        let night = parse("23:00-07:00").unwrap();
+        assert_eq!(
+            night,
+            EncodeWindow {
+                start: 1380,
+                end: 420
+            }
+        );
+        assert!(night.contains(23 * 60 + 30));
+        assert!(night.contains(3 * 60));
+        assert!(!night.contains(7 * 60));
+        assert!(!night.contains(12 * 60));
+
+        let lunch = parse("12:00-13:30").unwrap();
+        assert!(lunch.contains(12 * 60));
+        assert!(!lunch.contains(13 * 60 + 30));
+        assert_eq!(lunch.to_string(), "12:00-13:30");
+
+        assert!(parse("24:00-07:00").is_err());
+        assert!(parse("07:00-07:00").is_err());
+        assert!(parse("7pm").is_err());
+    }
+}
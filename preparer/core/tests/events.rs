@@ -0,0 +1,155 @@
+//! Golden tests pinning the JSON event and job spec formats. If one fails,
+//! the change is visible to integrators: either keep the old shape or bump
+//! `EVENT_SCHEMA_VERSION` (`SPEC_VERSION` for specs). Run with `UPDATE_GOLDEN=1` to rewrite the files
+//! after an intentional change.
+
+use anyhow::anyhow;
+use movieshare_core::bottleneck::{BottleneckReport, Stage};
+use movieshare_core::events::{Event, EventBody};
+use movieshare_core::qc::{Defect, DefectRun, QcReport};
+use movieshare_core::queue::{JobState, QueueEvent};
+use movieshare_core::{BranchStats, CancelPolicy, JobEvent, JobSpec, Outcome, Progress, Summary};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn golden(name: &str, actual: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        actual,
+        expected,
+        "{} changed; see tests/events.rs",
+        path.display()
+    );
+}
+
+fn examples() -> Vec<Event> {
+    let progress = Progress {
+        fraction: 0.25,
+        frames: 1440,
+        fps: 48.5,
+        eta_secs: Some(356.25),
+    };
+    let job_events = [
+        JobEvent::Progress(progress.clone()),
+        JobEvent::Warning(String::from("Dropped a late frame")),
+        JobEvent::BranchStats(BranchStats {
+            target_bitrate_mbps: 6,
+            frames: 5760,
+            bytes: 180_000_000,
+            average_bitrate_kbps: Some(6000.0),
+        }),
+        JobEvent::BranchStats(BranchStats {
+            target_bitrate_mbps: 2,
+            frames: 5760,
+            bytes: 60_000_000,
+            average_bitrate_kbps: None,
+        }),
+        JobEvent::Bottleneck(BottleneckReport {
+            fills: BTreeMap::from([(Stage::Convert, 0.05), (Stage::Encode, 0.875)]),
+            bound: Stage::Encode,
+        }),
+        JobEvent::Qc(QcReport {
+            runs: vec![DefectRun {
+                defect: Defect::Frozen,
+                start: Duration::from_millis(3_040),
+                end: Duration::from_millis(7_000),
+            }],
+        }),
+        JobEvent::Finished(Ok(Outcome::Prepared(Summary {
+            frames: 5760,
+            elapsed: Duration::from_millis(118_750),
+        }))),
+        JobEvent::Finished(Ok(Outcome::AlreadyPrepared)),
+        JobEvent::Finished(Ok(Outcome::Cancelled(CancelPolicy::Finalize))),
+        JobEvent::Finished(Err(
+            anyhow!("No such file").context("Failed to open movie.mkv")
+        )),
+    ];
+    let queue_events = [
+        QueueEvent::StateChanged {
+            id: 7,
+            state: JobState::Running,
+        },
+        QueueEvent::StateChanged {
+            id: 7,
+            state: JobState::Failed {
+                error: String::from("Encoder crashed"),
+            },
+        },
+        QueueEvent::Progress { id: 7, progress },
+        QueueEvent::Warning {
+            id: 7,
+            message: String::from("Dropped a late frame"),
+        },
+    ];
+
+    let estimate = Event::new(EventBody::Estimate {
+        size_bytes: Some(2_400_000_000),
+        duration_secs: Some(1241.0),
+    });
+
+    job_events
+        .iter()
+        .map(Event::from_job_event)
+        .chain([estimate])
+        .chain(queue_events.iter().map(Event::from_queue_event))
+        .collect()
+}
+
+#[test]
+fn events_match_golden() {
+    let lines: Vec<_> = examples().iter().map(|e| e.to_json().unwrap()).collect();
+    golden("events.jsonl", &(lines.join("\n") + "\n"));
+}
+
+#[test]
+fn schema_matches_golden() {
+    golden("event-schema.json", &(Event::json_schema().unwrap() + "\n"));
+}
+
+#[test]
+fn spec_schema_matches_golden() {
+    golden(
+        "spec-schema.json",
+        &(JobSpec::json_schema().unwrap() + "\n"),
+    );
+}
+
+#[test]
+fn golden_events_parse() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/events.jsonl");
+    let parsed: Vec<Event> = std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(parsed, examples());
+}
+
+#[test]
+fn kind_matches_type_field() {
+    for event in examples() {
+        let json: serde_json::Value = serde_json::from_str(&event.to_json().unwrap()).unwrap();
+        assert_eq!(json["type"], event.body.kind());
+    }
+}
+
+#[test]
+fn job_events_round_trip() {
+    // Estimates come from the plan rather than the job
+    for event in examples()
+        .into_iter()
+        .filter(|e| e.job_id.is_none() && e.body.kind() != "estimate")
+    {
+        let job_event = event.clone().into_job_event().unwrap();
+        assert_eq!(Event::from_job_event(&job_event), event);
+    }
+}
@@ -0,0 +1,59 @@
+/*
+ * C interface to the movieshare preparation engine.
+ *
+ * Link against libmovieshare_ffi. All strings are UTF-8 and NUL-terminated.
+ */
+
+#ifndef MOVIESHARE_H
+#define MOVIESHARE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return values of movieshare_prepare() */
+#define MOVIESHARE_OK 0
+#define MOVIESHARE_ALREADY_PREPARED 1
+#define MOVIESHARE_CANCELLED 2
+#define MOVIESHARE_ERROR -1
+
+/* Lets another thread cancel a running movieshare_prepare() call. */
+typedef struct movieshare_token movieshare_token;
+
+/* Called on the thread running movieshare_prepare() about twice a second. */
+typedef void (*movieshare_progress_cb)(double fraction, uint64_t frames, double fps,
+                                       void *user_data);
+
+movieshare_token *movieshare_token_new(void);
+void movieshare_token_free(movieshare_token *token);
+
+/*
+ * Stop the preparation using this token. With discard set, everything it
+ * wrote is deleted; otherwise what was encoded so far is kept playable.
+ * Safe to call from any thread.
+ */
+void movieshare_cancel(const movieshare_token *token, int discard);
+
+/*
+ * Prepare input into a DASH presentation in output and block until done.
+ *
+ * profile_json, progress and token may be NULL. On MOVIESHARE_ERROR,
+ * movieshare_last_error() describes what went wrong.
+ */
+int movieshare_prepare(const char *input, const char *output, const char *profile_json,
+                       movieshare_progress_cb progress, void *user_data,
+                       const movieshare_token *token);
+
+/*
+ * The last error on this thread, or NULL. Valid until the next
+ * movieshare_prepare() call on the same thread.
+ */
+const char *movieshare_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MOVIESHARE_H */
@@ -0,0 +1,207 @@
+//! C ABI for embedding the preparation engine; see `include/movieshare.h`.
+
+use anyhow::{Context, Result, anyhow};
+use futures::StreamExt;
+use movieshare_core::{
+    CancelPolicy, CancellationToken, EncodingProfile, JobEvent, Outcome, PrepareJob, Preparer,
+};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+
+pub const MOVIESHARE_OK: c_int = 0;
+pub const MOVIESHARE_ALREADY_PREPARED: c_int = 1;
+pub const MOVIESHARE_CANCELLED: c_int = 2;
+pub const MOVIESHARE_ERROR: c_int = -1;
+
+pub type ProgressCallback =
+    Option<unsafe extern "C" fn(fraction: f64, frames: u64, fps: f64, user_data: *mut c_void)>;
+
+#[allow(non_camel_case_types)]
+pub struct movieshare_token {
+    inner: CancellationToken,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // Interior NULs would truncate the message in C anyway
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn movieshare_token_new() -> *mut movieshare_token {
+    Box::into_raw(Box::new(movieshare_token {
+        inner: CancellationToken::new(),
+    }))
+}
+
+/// # Safety
+///
+/// `token` must be NULL or come from `movieshare_token_new` and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn movieshare_token_free(token: *mut movieshare_token) {
+    if !token.is_null() {
+        drop(unsafe { Box::from_raw(token) });
+    }
+}
+
+/// # Safety
+///
+/// `token` must be NULL or a live token from `movieshare_token_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn movieshare_cancel(token: *const movieshare_token, discard: c_int) {
+    if let Some(token) = unsafe { token.as_ref() } {
+        token.inner.cancel(if discard != 0 {
+            CancelPolicy::Discard
+        } else {
+            CancelPolicy::Finalize
+        });
+    }
+}
+
+unsafe fn string_arg(ptr: *const c_char, name: &str) -> Result<String> {
+    if ptr.is_null() {
+        return Err(anyhow!("{} must not be NULL", name));
+    }
+    let value = unsafe { CStr::from_ptr(ptr) };
+    Ok(value
+        .to_str()
+        .context(format!("{} is not valid UTF-8", name))?
+        .to_string())
+}
+
+/// # Safety
+///
+/// `input` and `output` must be valid C strings; `profile_json` must be NULL
+/// or a valid C string; `token` must be NULL or a live token. `progress` is
+/// called with `user_data` on this thread.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn movieshare_prepare(
+    input: *const c_char,
+    output: *const c_char,
+    profile_json: *const c_char,
+    progress: ProgressCallback,
+    user_data: *mut c_void,
+    token: *const movieshare_token,
+) -> c_int {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+
+    let run = || -> Result<Outcome> {
+        let input = unsafe { string_arg(input, "input")? };
+        let output = unsafe { string_arg(output, "output")? };
+        let profile = match profile_json.is_null() {
+            true => EncodingProfile::default(),
+            false => EncodingProfile::from_json(&unsafe { string_arg(profile_json, "profile")? })
+                .context("Invalid profile")?,
+        };
+
+        let mut preparer = Preparer::new(input).output(output).profile(profile);
+        if let Some(token) = unsafe { token.as_ref() } {
+            preparer = preparer.cancellation_token(token.inner.clone());
+        }
+
+        let mut job = PrepareJob::spawn(preparer);
+        futures::executor::block_on(async {
+            while let Some(event) = job.next().await {
+                match event {
+                    JobEvent::Progress(sample) => {
+                        if let Some(callback) = progress {
+                            unsafe {
+                                callback(sample.fraction, sample.frames, sample.fps, user_data)
+                            };
+                        }
+                    }
+                    JobEvent::Finished(result) => return result,
+                    _ => (),
+                }
+            }
+            Err(anyhow!("Preparation ended without finishing"))
+        })
+    };
+
+    // Unwinding into C is undefined behaviour
+    match panic::catch_unwind(AssertUnwindSafe(run)) {
+        Ok(Ok(Outcome::Prepared(_))) => MOVIESHARE_OK,
+        Ok(Ok(Outcome::AlreadyPrepared)) => MOVIESHARE_ALREADY_PREPARED,
+        Ok(Ok(Outcome::Cancelled(_))) => MOVIESHARE_CANCELLED,
+        Ok(Err(err)) => {
+            set_last_error(format!("{:#}", err));
+            MOVIESHARE_ERROR
+        }
+        Err(_) => {
+            set_last_error(String::from("Internal error: the preparation panicked"));
+            MOVIESHARE_ERROR
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn movieshare_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        let ptr = movieshare_last_error();
+        assert!(!ptr.is_null());
+        unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn rejects_null_input() {
+        let output = CString::new("out").unwrap();
+        let status = unsafe {
+            movieshare_prepare(
+                std::ptr::null(),
+                output.as_ptr(),
+                std::ptr::null(),
+                None,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(status, MOVIESHARE_ERROR);
+        assert_eq!(last_error(), "input must not be NULL");
+    }
+
+    #[test]
+    fn reports_invalid_profile() {
+        let input = CString::new("movie.mkv").unwrap();
+        let output = CString::new("out").unwrap();
+        let profile = CString::new(r#"{"ladder": []}"#).unwrap();
+        let status = unsafe {
+            movieshare_prepare(
+                input.as_ptr(),
+                output.as_ptr(),
+                profile.as_ptr(),
+                None,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(status, MOVIESHARE_ERROR);
+        assert!(last_error().contains("at least one bitrate"));
+    }
+
+    #[test]
+    fn cancel_tolerates_null() {
+        unsafe { movieshare_cancel(std::ptr::null(), 1) };
+        let token = movieshare_token_new();
+        unsafe {
+            movieshare_cancel(token, 1);
+            assert_eq!((*token).inner.policy(), Some(CancelPolicy::Discard));
+            movieshare_token_free(token);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! Python bindings for the preparation engine.
+//!
+//! ```python
+//! import movieshare
+//!
+//! spec = movieshare.JobSpec("movie.mkv", "output")
+//! spec.ladder = [6, 2]
+//! movieshare.prepare(spec, progress=lambda fraction, frames, fps: print(fraction))
+//! ```
+
+use futures::StreamExt;
+use movieshare_core::queue::{self, JobState, QueueConfig, QueueEvent};
+use movieshare_core::{CancelPolicy, JobEvent, Outcome, PrepareJob, Preparer};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::path::PathBuf;
+
+fn runtime_error(err: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{:#}", err))
+}
+
+/// What to prepare and how; mirrors the JSON job spec.
+#[pyclass(module = "movieshare", skip_from_py_object)]
+#[derive(Clone)]
+struct JobSpec {
+    inner: movieshare_core::JobSpec,
+}
+
+#[pymethods]
+impl JobSpec {
+    #[new]
+    fn new(input: PathBuf, output: PathBuf) -> Self {
+        Self {
+            inner: movieshare_core::JobSpec::new(input, output),
+        }
+    }
+
+    #[staticmethod]
+    fn from_json(json: &str) -> PyResult<Self> {
+        let inner = movieshare_core::JobSpec::from_json(json)
+            .map_err(|err| PyValueError::new_err(format!("{:#}", err)))?;
+        Ok(Self { inner })
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        self.inner.to_json().map_err(runtime_error)
+    }
+
+    #[getter]
+    fn input(&self) -> PathBuf {
+        self.inner.input.clone()
+    }
+
+    #[setter]
+    fn set_input(&mut self, input: PathBuf) {
+        self.inner.input = input;
+    }
+
+    #[getter]
+    fn output(&self) -> PathBuf {
+        self.inner.output.clone()
+    }
+
+    #[setter]
+    fn set_output(&mut self, output: PathBuf) {
+        self.inner.output = output;
+    }
+
+    /// Video bitrates in MB/s, one representation each
+    #[getter]
+    fn ladder(&self) -> Vec<u32> {
+        self.inner.profile.ladder.clone()
+    }
+
+    #[setter]
+    fn set_ladder(&mut self, ladder: Vec<u32>) {
+        self.inner.profile.ladder = ladder;
+    }
+
+    #[getter]
+    fn encoder_preset(&self) -> u32 {
+        self.inner.profile.encoder_preset
+    }
+
+    #[setter]
+    fn set_encoder_preset(&mut self, preset: u32) {
+        self.inner.profile.encoder_preset = preset;
+    }
+
+    #[getter]
+    fn segment_duration(&self) -> u32 {
+        self.inner.profile.segment_duration
+    }
+
+    #[setter]
+    fn set_segment_duration(&mut self, seconds: u32) {
+        self.inner.profile.segment_duration = seconds;
+    }
+
+    #[getter]
+    fn audio_bitrate_kbps(&self) -> u32 {
+        self.inner.profile.audio.bitrate_kbps
+    }
+
+    #[setter]
+    fn set_audio_bitrate_kbps(&mut self, kbps: u32) {
+        self.inner.profile.audio.bitrate_kbps = kbps;
+    }
+
+    #[getter]
+    fn audio_channels(&self) -> u32 {
+        self.inner.profile.audio.channels
+    }
+
+    #[setter]
+    fn set_audio_channels(&mut self, channels: u32) {
+        self.inner.profile.audio.channels = channels;
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "JobSpec({:?}, {:?}, ladder={:?})",
+            self.inner.input, self.inner.output, self.inner.profile.ladder
+        )
+    }
+}
+
+/// Cancels a running `prepare` call from another thread.
+#[pyclass(module = "movieshare", from_py_object)]
+#[derive(Clone, Default)]
+struct CancellationToken {
+    inner: movieshare_core::CancellationToken,
+}
+
+#[pymethods]
+impl CancellationToken {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop the job; keep what was encoded so far unless `discard` is set.
+    #[pyo3(signature = (discard=false))]
+    fn cancel(&self, discard: bool) {
+        self.inner.cancel(if discard {
+            CancelPolicy::Discard
+        } else {
+            CancelPolicy::Finalize
+        });
+    }
+
+    #[getter]
+    fn is_cancelled(&self) -> bool {
+        self.inner.is_cancelled()
+    }
+}
+
+/// Prepare `spec` and block until done.
+///
+/// `progress` is called as `progress(fraction, frames, fps)`. Returns
+/// `"prepared"`, `"already_prepared"` or `"cancelled"`; raises
+/// `RuntimeError` if the preparation fails. If the callback raises, the job
+/// is aborted and the exception propagates.
+#[pyfunction]
+#[pyo3(signature = (spec, progress=None, cancellation=None, resume=false))]
+fn prepare(
+    py: Python<'_>,
+    spec: &JobSpec,
+    progress: Option<Py<PyAny>>,
+    cancellation: Option<CancellationToken>,
+    resume: bool,
+) -> PyResult<&'static str> {
+    let mut preparer = Preparer::from_spec(&spec.inner).resume(resume);
+    if let Some(token) = cancellation {
+        preparer = preparer.cancellation_token(token.inner);
+    }
+    let mut job = PrepareJob::spawn(preparer);
+
+    // Wait without the GIL so other Python threads keep running
+    let result = py.detach(|| {
+        futures::executor::block_on(async {
+            while let Some(event) = job.next().await {
+                match event {
+                    JobEvent::Progress(sample) => {
+                        if let Some(callback) = &progress
+                            && let Err(err) = Python::attach(|py| {
+                                callback.call1(py, (sample.fraction, sample.frames, sample.fps))
+                            })
+                        {
+                            job.abort();
+                            return Err(err);
+                        }
+                    }
+                    JobEvent::Finished(result) => return Ok(result),
+                    _ => (),
+                }
+            }
+            Ok(Err(anyhow::anyhow!("Preparation ended without finishing")))
+        })
+    })?;
+
+    match result.map_err(runtime_error)? {
+        Outcome::Prepared(_) => Ok("prepared"),
+        Outcome::AlreadyPrepared => Ok("already_prepared"),
+        Outcome::Cancelled(_) => Ok("cancelled"),
+    }
+}
+
+fn state_name(state: &JobState) -> &'static str {
+    match state {
+        JobState::Queued => "queued",
+        JobState::Running => "running",
+        JobState::Completed => "completed",
+        JobState::Failed { .. } => "failed",
+        JobState::Cancelled => "cancelled",
+    }
+}
+
+/// Runs submitted jobs in the background, in priority order.
+#[pyclass(module = "movieshare")]
+struct JobQueue {
+    inner: queue::JobQueue,
+}
+
+#[pymethods]
+impl JobQueue {
+    #[new]
+    #[pyo3(signature = (state_file=None, max_concurrent=1))]
+    fn new(state_file: Option<PathBuf>, max_concurrent: usize) -> PyResult<Self> {
+        let inner = queue::JobQueue::open(QueueConfig {
+            max_concurrent,
+            state_file,
+            ..QueueConfig::default()
+        })
+        .map_err(runtime_error)?;
+        Ok(Self { inner })
+    }
+
+    /// Queue a job and return its id. Higher priorities run first.
+    #[pyo3(signature = (spec, priority=0))]
+    fn submit(&self, spec: &JobSpec, priority: i32) -> PyResult<u64> {
+        self.inner
+            .submit(spec.inner.clone(), priority)
+            .map_err(|err| PyValueError::new_err(format!("{:#}", err)))
+    }
+
+    fn cancel(&self, id: u64) -> PyResult<()> {
+        self.inner.cancel(id).map_err(runtime_error)
+    }
+
+    /// A dict with the job's `id`, `priority`, `state`, `error` and `progress`.
+    fn status<'py>(&self, py: Python<'py>, id: u64) -> PyResult<Bound<'py, PyDict>> {
+        let job = self
+            .inner
+            .get(id)
+            .ok_or_else(|| PyValueError::new_err(format!("No such job: {}", id)))?;
+
+        let status = PyDict::new(py);
+        status.set_item("id", job.id)?;
+        status.set_item("priority", job.priority)?;
+        status.set_item("state", state_name(&job.state))?;
+        let error = match &job.state {
+            JobState::Failed { error } => Some(error.clone()),
+            _ => None,
+        };
+        status.set_item("error", error)?;
+        status.set_item("progress", job.progress.map(|p| p.fraction))?;
+        Ok(status)
+    }
+
+    /// Call `callback(id, kind, value)` from a background thread for every
+    /// job event: `"state"` with the state name, `"progress"` with the
+    /// fraction done, or `"warning"` with the message.
+    fn subscribe(&self, callback: Py<PyAny>) {
+        let mut events = self.inner.subscribe();
+        std::thread::spawn(move || {
+            futures::executor::block_on(async {
+                while let Some(event) = events.next().await {
+                    let result = Python::attach(|py| match event {
+                        QueueEvent::StateChanged { id, state } => {
+                            callback.call1(py, (id, "state", state_name(&state)))
+                        }
+                        QueueEvent::Progress { id, progress } => {
+                            callback.call1(py, (id, "progress", progress.fraction))
+                        }
+                        QueueEvent::Warning { id, message } => {
+                            callback.call1(py, (id, "warning", message))
+                        }
+                    });
+                    if let Err(err) = result {
+                        Python::attach(|py| err.print(py));
+                    }
+                }
+            })
+        });
+    }
+}
+
+#[pymodule]
+fn movieshare(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<JobSpec>()?;
+    m.add_class::<CancellationToken>()?;
+    m.add_class::<JobQueue>()?;
+    m.add_function(wrap_pyfunction!(prepare, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,222 @@
+//! `preparer add-track`: add a subtitle or audio track to a title that was
+//! already prepared, like a dub or fan subtitles found later.
+//!
+//! Only the new track is converted or encoded, then patched into the
+//! manifest as an adaptation set of its own, so the video is left as it is.
+//! Preparing with `--all-tracks` adds the source's other tracks the same way,
+//! along with the fonts styled subtitles among them are set in.
+
+use crate::library::MANIFEST;
+use crate::mpd;
+use anyhow::{Context, Result};
+use movieshare_core::fonts::{self, Font};
+use movieshare_core::language;
+use movieshare_core::track;
+use movieshare_core::{EncodingProfile, SubtitleSpec};
+use std::path::Path;
+use std::thread::JoinHandle;
+
+/// Turn SubRip subtitles into WebVTT, which players take as a sidecar.
+pub fn srt_to_vtt(srt: &str) -> String {
+    let mut vtt = String::from("WEBVTT\n\n");
+    for line in srt.trim_start_matches('\u{feff}').lines() {
+        // Only the timings differ: WebVTT takes a dot before the milliseconds
+        match line.contains("-->") {
+            true => vtt.push_str(&line.replace(',', ".")),
+            false => vtt.push_str(line),
+        }
+        vtt.push('\n');
+    }
+    vtt
+}
+
+/// Add `file`, SubRip or WebVTT, to the title in `dir` as subtitles in `lang`.
+/// Returns the new representation's id.
+pub fn add_subtitles(dir: &Path, file: &Path, lang: Option<&str>) -> Result<String> {
+    let text = std::fs::read_to_string(file)
+        .context(format!("Failed to read subtitles: {}", file.display()))?;
+    let vtt = match file.extension().is_some_and(|ext| ext == "vtt") {
+        true => text,
+        false => srt_to_vtt(&text),
+    };
+    add_vtt(dir, &vtt, lang)
+}
+
+/// Add WebVTT subtitles to the title in `dir`, returning the new
+/// representation's id.
+fn add_vtt(dir: &Path, vtt: &str, lang: Option<&str>) -> Result<String> {
+    let xml = read_manifest(dir)?;
+    let manifest = mpd::parse(&xml)?;
+    let id = mpd::next_representation_id(&manifest);
+    let name = format!("subtitles_{}.vtt", id);
+    std::fs::write(dir.join(&name), vtt).context(format!("Failed to write {}", name))?;
+
+    // The manifest wants a bandwidth even for a file fetched whole
+    let bandwidth = manifest
+        .duration_secs
+        .filter(|duration| *duration > 0.0)
+        .map_or(0, |duration| (vtt.len() as f64 * 8.0 / duration) as u64)
+        .max(1);
+    let set = mpd::subtitle_adaptation_set(&name, lang, &id, bandwidth)?;
+    write_manifest(dir, &mpd::add_adaptation_set(&xml, &set)?)?;
+    Ok(id)
+}
+
+/// Encode the audio of `file` as the profile's audio and add it to the title
+/// in `dir` as a track in `lang`. Returns the new representation's id.
+pub fn add_audio(
+    dir: &Path,
+    file: &Path,
+    lang: Option<&str>,
+    profile: &EncodingProfile,
+) -> Result<String> {
+    let xml = read_manifest(dir)?;
+    let id = mpd::next_representation_id(&mpd::parse(&xml)?);
+    // Segments go in a directory of their own, so their names can't clash
+    let subdir = format!("audio_{}", id);
+    track::encode_audio(file, &dir.join(&subdir), profile)?;
+    add_encoded_audio(dir, &subdir, lang)
+}
+
+/// Add the audio encoded into `subdir` of the title in `dir` as a track in
+/// `lang`, returning the new representation's id.
+fn add_encoded_audio(dir: &Path, subdir: &str, lang: Option<&str>) -> Result<String> {
+    let xml = read_manifest(dir)?;
+    let id = mpd::next_representation_id(&mpd::parse(&xml)?);
+    let track_manifest = dir.join(subdir).join(MANIFEST);
+    let track_xml = std::fs::read_to_string(&track_manifest)
+        .context(format!("Failed to read {}", track_manifest.display()))?;
+    let mut set = mpd::extract_adaptation_set(&track_xml, "audio", &format!("{}/", subdir), &id)?;
+    if let Some(lang) = lang {
+        set = language::tag_manifest(&set, "audio", lang)?;
+    }
+    write_manifest(dir, &mpd::add_adaptation_set(&xml, &set)?)?;
+    std::fs::remove_file(&track_manifest)?;
+    Ok(id)
+}
+
+/// The source's audio streams past the first and its text subtitles, each
+/// converted in a pipeline of its own while the main one encodes the video,
+/// so they're ready by the time it is instead of waiting on it.
+pub struct ExtraTracks {
+    /// Directory each audio stream is encoded into, and its encode
+    audio: Vec<(String, JoinHandle<Result<Option<String>>>)>,
+    subtitles: Vec<JoinHandle<Result<track::Subtitles>>>,
+    kept_subtitles: SubtitleSpec,
+}
+
+impl ExtraTracks {
+    pub fn start(
+        input: &Path,
+        dir: &Path,
+        profile: &EncodingProfile,
+        audio_streams: usize,
+        subtitle_streams: usize,
+    ) -> Self {
+        let audio = (1..audio_streams)
+            .map(|index| {
+                let subdir = format!("audio_stream_{}", index);
+                let (input, output, profile) =
+                    (input.to_owned(), dir.join(&subdir), profile.clone());
+                let encode = std::thread::spawn(move || {
+                    track::encode_audio_stream(&input, &output, &profile, index)
+                });
+                (subdir, encode)
+            })
+            .collect();
+        let subtitles = (0..subtitle_streams)
+            .map(|index| {
+                let input = input.to_owned();
+                std::thread::spawn(move || track::extract_subtitles(&input, index))
+            })
+            .collect();
+        Self {
+            audio,
+            subtitles,
+            kept_subtitles: profile.subtitles.clone(),
+        }
+    }
+
+    /// Wait for every track and add those that came out, and that the
+    /// profile keeps, to the title in `dir`. Returns how many were added.
+    pub fn finish(self, dir: &Path) -> Result<usize> {
+        let mut added = 0;
+        for (subdir, encode) in self.audio {
+            match encode.join().expect("Audio encode panicked") {
+                Ok(lang) => {
+                    add_encoded_audio(dir, &subdir, lang.as_deref())?;
+                    added += 1;
+                }
+                Err(err) => eprintln!("Warning: {:#}", err),
+            }
+        }
+        let mut attached: Vec<Font> = Vec::new();
+        let mut cue_font = None;
+        for extract in self.subtitles {
+            match extract.join().expect("Subtitle extraction panicked") {
+                Ok(subtitles) if self.kept_subtitles.keeps(subtitles.language.as_deref()) => {
+                    add_vtt(dir, &subtitles.vtt, subtitles.language.as_deref())?;
+                    added += 1;
+                    for font in subtitles.fonts {
+                        if !attached.iter().any(|f| f.filename == font.filename) {
+                            attached.push(font);
+                        }
+                    }
+                    cue_font = cue_font.or(subtitles.style_fonts.into_iter().next());
+                }
+                Ok(_) => (),
+                Err(err) => eprintln!("Warning: {:#}", err),
+            }
+        }
+        // Styled subtitles fall back to the player's font without these
+        if !attached.is_empty() {
+            fonts::package(dir, &attached, cue_font.as_deref())?;
+        }
+        Ok(added)
+    }
+}
+
+fn read_manifest(dir: &Path) -> Result<String> {
+    std::fs::read_to_string(dir.join(MANIFEST))
+        .context(format!("No prepared title in {}", dir.display()))
+}
+
+fn write_manifest(dir: &Path, xml: &str) -> Result<()> {
+    let path = dir.join(MANIFEST);
+    std::fs::write(&path, xml).context(format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_srt_and_adds_it() {
+        let srt = "\u{feff}1\r\n00:00:01,000 --> 00:00:02,500\r\nBonjour, monde\r\n\r\n2\r\n00:00:03,000 --> 00:00:04,000\r\nAu revoir\r\n";
+        assert_eq!(
+            srt_to_vtt(srt),
+            "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.500\nBonjour, monde\n\n2\n00:00:03.000 --> 00:00:04.000\nAu revoir\n"
+        );
+
+        let dir = std::env::temp_dir().join(format!("movieshare-addtrack-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD mediaPresentationDuration="PT10S"><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+        )
+        .unwrap();
+        let file = dir.join("new.srt");
+        std::fs::write(&file, srt).unwrap();
+
+        assert_eq!(add_subtitles(&dir, &file, Some("fr")).unwrap(), "1");
+        let manifest = mpd::parse(&read_manifest(&dir).unwrap()).unwrap();
+        assert_eq!(manifest.representations[1].content_type, "text");
+        assert_eq!(manifest.representations[1].lang.as_deref(), Some("fr"));
+        assert!(
+            std::fs::read_to_string(dir.join("subtitles_1.vtt"))
+                .unwrap()
+                .starts_with("WEBVTT")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+//! A small web UI for running the daemon without SSH.
+//!
+//! `GET /admin/` is a page showing the job queue with live progress and the
+//! library's catalog, with buttons to re-encode, delete or share titles. It
+//! talks to the JSON routes below it:
+//!
+//! - `GET /admin/api/jobs` lists every job in the queue
+//! - `DELETE /admin/api/jobs/{id}` cancels a job
+//! - `GET /admin/api/events` streams every job's events as server-sent events
+//! - `GET /admin/api/titles` lists the catalog's titles
+//! - `POST /admin/api/titles/{title}/reencode` prepares a title again from
+//!   the source its journal recorded
+//! - `DELETE /admin/api/titles/{title}` deletes a title and its files
+//! - `POST /admin/api/titles/{title}/share` makes a share link
+//!
+//! Everything needs HTTP Basic auth as an unrestricted user from the
+//! library's catalog.
+
+use crate::library::{Catalog, MANIFEST, Title, recorded_source};
+use crate::rest::JobView;
+use crate::share::{self, ShareKey};
+use crate::upload;
+use anyhow::{Context, Result};
+use axum::extract::{Path, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, Sse};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use futures::{Stream, StreamExt, future};
+use movieshare_core::JobSpec;
+use movieshare_core::events;
+use movieshare_core::queue::{JobId, JobQueue, JobState, QueueEvent};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone)]
+struct AdminState {
+    queue: JobQueue,
+    library: PathBuf,
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(serde_json::json!({ "error": message.into() }))).into_response()
+}
+
+/// Let through only unrestricted users of the catalog of `library`.
+async fn require_admin(State(library): State<PathBuf>, request: Request, next: Next) -> Response {
+    match upload::authenticate(&library, request.headers()).await {
+        Ok(Some(user)) if !user.restricted => next.run(request).await,
+        Ok(Some(_)) => error(StatusCode::FORBIDDEN, "Restricted users can't administer"),
+        Ok(None) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"movieshare admin\"")],
+        )
+            .into_response(),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)),
+    }
+}
+
+async fn page() -> Html<&'static str> {
+    Html(include_str!("admin.html"))
+}
+
+async fn jobs(State(state): State<AdminState>) -> Json<Vec<JobView>> {
+    Json(state.queue.jobs().into_iter().map(JobView::from).collect())
+}
+
+async fn cancel_job(State(state): State<AdminState>, Path(id): Path<JobId>) -> Response {
+    if state.queue.get(id).is_none() {
+        return error(StatusCode::NOT_FOUND, format!("No such job: {}", id));
+    }
+    match state.queue.cancel(id) {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(err) => error(StatusCode::CONFLICT, format!("{:#}", err)),
+    }
+}
+
+async fn job_events(
+    State(state): State<AdminState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = state.queue.subscribe().filter_map(|event| {
+        let event = events::Event::from_queue_event(&event);
+        let sse = Event::default()
+            .event(event.body.kind())
+            .json_data(&event)
+            .ok();
+        future::ready(sse.map(Ok))
+    });
+    Sse::new(events).keep_alive(Default::default())
+}
+
+/// A catalog title, and whether the admin can prepare it again.
+#[derive(Serialize)]
+struct TitleView {
+    #[serde(flatten)]
+    title: Title,
+    /// The source recorded by the last run, when it's still there
+    source: Option<PathBuf>,
+}
+
+fn catalog_titles(state: &AdminState) -> Result<Vec<TitleView>> {
+    let Some(catalog) = Catalog::open_existing(&state.library)? else {
+        return Ok(Vec::new());
+    };
+    Ok(catalog
+        .titles()?
+        .into_iter()
+        .map(|title| TitleView {
+            source: recorded_source(&state.library.join(&title.name)).ok(),
+            title,
+        })
+        .collect())
+}
+
+async fn titles(State(state): State<AdminState>) -> Response {
+    match tokio::task::spawn_blocking(move || catalog_titles(&state)).await {
+        Ok(Ok(titles)) => Json(titles).into_response(),
+        Ok(Err(err)) => error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+/// The title's directory, if it's a prepared title of the library.
+fn title_dir(state: &AdminState, title: &str) -> Option<PathBuf> {
+    let dir = state.library.join(title);
+    (upload::valid_title(title) && dir.join(MANIFEST).is_file()).then_some(dir)
+}
+
+fn no_such_title(title: &str) -> Response {
+    error(StatusCode::NOT_FOUND, format!("No such title: {}", title))
+}
+
+/// Whether a job that hasn't finished writes into `dir`.
+fn is_busy(queue: &JobQueue, dir: &std::path::Path) -> bool {
+    queue
+        .jobs()
+        .iter()
+        .any(|job| !job.state.is_finished() && job.spec.output == dir)
+}
+
+#[derive(Serialize)]
+struct ReencodeResponse {
+    id: JobId,
+}
+
+async fn reencode(State(state): State<AdminState>, Path(title): Path<String>) -> Response {
+    let Some(dir) = title_dir(&state, &title) else {
+        return no_such_title(&title);
+    };
+    if is_busy(&state.queue, &dir) {
+        return error(
+            StatusCode::CONFLICT,
+            format!("{} is already being prepared", title),
+        );
+    }
+    let input = match recorded_source(&dir) {
+        Ok(input) => input,
+        Err(err) => return error(StatusCode::CONFLICT, format!("{:#}", err)),
+    };
+
+    let events = state.queue.subscribe();
+    let id = match state.queue.submit(JobSpec::new(input, &dir), 0) {
+        Ok(id) => id,
+        Err(err) => return error(StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", err)),
+    };
+    println!("Re-encoding {} as job {}", title, id);
+    tokio::spawn(rescan_when_done(events, id, state.library.clone()));
+    (StatusCode::CREATED, Json(ReencodeResponse { id })).into_response()
+}
+
+/// Once the job is done, rescan the catalog so it reflects the new ladder.
+async fn rescan_when_done(
+    mut events: futures::channel::mpsc::UnboundedReceiver<QueueEvent>,
+    id: JobId,
+    library: PathBuf,
+) {
+    while let Some(event) = events.next().await {
+        if let QueueEvent::StateChanged { id: job, state } = event
+            && job == id
+            && state.is_finished()
+        {
+            if state != JobState::Completed {
+                return;
+            }
+            break;
+        }
+    }
+    drop(events);
+    let result = tokio::task::spawn_blocking(move || -> Result<()> {
+        Catalog::open(&Catalog::default_path(&library))?.scan(&library, false)?;
+        Ok(())
+    })
+    .await;
+    if let Ok(Err(err)) = result {
+        eprintln!("Failed to rescan the catalog: {:#}", err);
+    }
+}
+
+async fn delete_title(State(state): State<AdminState>, Path(title): Path<String>) -> Response {
+    let Some(dir) = title_dir(&state, &title) else {
+        return no_such_title(&title);
+    };
+    if is_busy(&state.queue, &dir) {
+        return error(
+            StatusCode::CONFLICT,
+            format!("{} is being prepared; cancel its job first", title),
+        );
+    }
+    let library = state.library.clone();
+    let name = title.clone();
+    let result = tokio::task::spawn_blocking(move || -> Result<()> {
+        if let Some(mut catalog) = Catalog::open_existing(&library)? {
+            catalog.remove(&name)?;
+        }
+        std::fs::remove_dir_all(&dir).context(format!("Failed to delete {}", dir.display()))
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => {
+            println!("Deleted {}", title);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(Err(err)) => error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ShareRequest {
+    /// Where viewers reach `preparer serve`, like `https://movies.example.com`
+    base_url: String,
+    /// How long the link works, like `48h`
+    #[serde(default = "default_expiry")]
+    expires: String,
+    /// Tallest video rung the link may fetch, like `720p`
+    max_rung: Option<String>,
+}
+
+fn default_expiry() -> String {
+    String::from("48h")
+}
+
+#[derive(Serialize)]
+struct ShareResponse {
+    link: String,
+}
+
+async fn share(
+    State(state): State<AdminState>,
+    Path(title): Path<String>,
+    Json(request): Json<ShareRequest>,
+) -> Response {
+    if title_dir(&state, &title).is_none() {
+        return no_such_title(&title);
+    }
+    let expires: Duration = match humantime::parse_duration(&request.expires) {
+        Ok(expires) => expires,
+        Err(err) => return error(StatusCode::UNPROCESSABLE_ENTITY, err.to_string()),
+    };
+    let key = match ShareKey::load_or_create(&state.library) {
+        Ok(key) => key,
+        Err(err) => return error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)),
+    };
+    let expires = SystemTime::now() + expires;
+    let token = match request.max_rung.as_deref().map(share::parse_rung) {
+        Some(Ok(max_height)) => key.sign_capped(&title, max_height, expires),
+        Some(Err(err)) => return error(StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", err)),
+        None => key.sign(&title, expires),
+    };
+    let link = format!("{}/s/{}/", request.base_url.trim_end_matches('/'), token);
+    Json(ShareResponse { link }).into_response()
+}
+
+/// Routes of the admin UI for the queue and `library`.
+pub fn router(queue: JobQueue, library: PathBuf) -> Router {
+    let state = AdminState { queue, library };
+    Router::new()
+        .route("/admin/", get(page))
+        .route("/admin/api/jobs", get(jobs))
+        .route("/admin/api/jobs/{id}", delete(cancel_job))
+        .route("/admin/api/events", get(job_events))
+        .route("/admin/api/titles", get(titles))
+        .route("/admin/api/titles/{title}", delete(delete_title))
+        .route("/admin/api/titles/{title}/reencode", post(reencode))
+        .route("/admin/api/titles/{title}/share", post(share))
+        .layer(middleware::from_fn_with_state(
+            state.library.clone(),
+            require_admin,
+        ))
+        .with_state(state)
+}
+
+/// `router` with every route needing an unrestricted user of the catalog
+/// of `library`, like the admin UI's.
+pub(crate) fn admins_only(router: Router, library: PathBuf) -> Router {
+    router.layer(middleware::from_fn_with_state(library, require_admin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use movieshare_core::queue::QueueConfig;
+    use tower::ServiceExt;
+
+    async fn get_titles(app: &Router, credentials: Option<&str>) -> (StatusCode, String) {
+        let mut request = axum::http::Request::get("/admin/api/titles");
+        if let Some(credentials) = credentials {
+            request = request.header(
+                header::AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode(credentials)),
+            );
+        }
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn only_unrestricted_users_get_in() {
+        let library = std::env::temp_dir().join(format!("movieshare-admin-{}", std::process::id()));
+        let title = library.join("Heat");
+        std::fs::create_dir_all(&title).unwrap();
+        std::fs::write(title.join(MANIFEST), "<MPD/>").unwrap();
+        {
+            let mut catalog = Catalog::open(&Catalog::default_path(&library)).unwrap();
+            catalog.add_user("alice", Some("secret"), false).unwrap();
+            catalog.add_user("kid", Some("secret"), true).unwrap();
+            catalog.scan(&library, false).unwrap();
+        }
+        let queue = JobQueue::open(QueueConfig::default()).unwrap();
+        let app = router(queue, library.clone());
+
+        assert_eq!(get_titles(&app, None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            get_titles(&app, Some("alice:wrong")).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            get_titles(&app, Some("kid:secret")).await.0,
+            StatusCode::FORBIDDEN
+        );
+        let (status, body) = get_titles(&app, Some("alice:secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        let titles: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(titles[0]["name"], "Heat");
+        // Nothing recorded where it came from, so it can't be re-encoded
+        assert_eq!(titles[0]["source"], serde_json::Value::Null);
+
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+//! Anonymous playback sessions, to see how the ladder holds up for viewers.
+//!
+//! The player page reports each session's totals to `POST /analytics`
+//! (or `POST /s/<token>/analytics` for share links) every so often and
+//! when it's closed. Sessions are identified only by a random id the page
+//! makes up; nothing ties them to a user. `preparer stats` sums them up.
+//!
+//! `preparer stats --recommend-ladder` closes the loop: rungs viewers
+//! hardly ever sustain are dropped from the ladder future titles are
+//! prepared with, and a lower one added when many viewers only manage the
+//! lowest.
+
+use crate::auth;
+use crate::library::Catalog;
+use crate::serve::{AppState, is_title, shared_title};
+use crate::users::User;
+use anyhow::{Context, Result, bail};
+use axum::Extension;
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use rusqlite::{OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Share of watching time a rung needs to be worth encoding.
+const MIN_SHARE: f64 = 0.05;
+/// Share of watching time at the lowest rung past which viewers are taken
+/// to need a lower one.
+const CROWDED_SHARE: f64 = 0.3;
+/// Watching time below which there's too little to go by.
+const MIN_WATCHED_SECS: f64 = 3600.0;
+
+/// Time spent playing one rung.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RungTime {
+    pub height: u32,
+    pub bandwidth: u64,
+    pub secs: f64,
+}
+
+/// A session's totals so far, as the player reports them.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Made up by the player page for each playback
+    pub session: String,
+    pub startup_secs: Option<f64>,
+    pub watched_secs: f64,
+    pub rebuffer_count: u32,
+    pub rebuffer_secs: f64,
+    /// Switches the adaptive bitrate logic made between rungs
+    pub rung_switches: u32,
+    #[serde(default)]
+    pub rungs: Vec<RungTime>,
+}
+
+impl Report {
+    fn is_valid(&self) -> bool {
+        let valid = |secs: f64| secs.is_finite() && secs >= 0.0;
+        !self.session.is_empty()
+            && self.session.len() <= 64
+            && self.startup_secs.is_none_or(valid)
+            && valid(self.watched_secs)
+            && valid(self.rebuffer_secs)
+            && self.rungs.len() <= 32
+            && self.rungs.iter().all(|rung| valid(rung.secs))
+    }
+}
+
+/// Every session of one title, summed up.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TitleStats {
+    pub title: String,
+    pub sessions: u32,
+    pub watched_secs: f64,
+    /// Mean over sessions that got as far as playing
+    pub startup_secs: Option<f64>,
+    pub rebuffer_count: u32,
+    pub rebuffer_secs: f64,
+    pub rung_switches: u32,
+    /// Seconds watched at each rung height
+    pub rungs: BTreeMap<u32, f64>,
+}
+
+impl TitleStats {
+    /// Rebuffering events per hour watched.
+    pub fn rebuffers_per_hour(&self) -> f64 {
+        match self.watched_secs > 0.0 {
+            true => self.rebuffer_count as f64 * 3600.0 / self.watched_secs,
+            false => 0.0,
+        }
+    }
+}
+
+impl Catalog {
+    pub fn record_playback(&mut self, title: &str, report: &Report) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let transaction = self.conn.transaction()?;
+        let existing: Option<String> = transaction
+            .query_row(
+                "SELECT title FROM playback_sessions WHERE id = ?1",
+                params![report.session],
+                |row| row.get(0),
+            )
+            .optional()?;
+        // A session can't be moved to another title
+        if existing.is_some_and(|existing| existing != title) {
+            return Ok(());
+        }
+        transaction.execute(
+            "INSERT INTO playback_sessions (id, title, started_at, updated_at, startup_secs,
+                watched_secs, rebuffer_count, rebuffer_secs, rung_switches)
+             VALUES (?1, ?2, ?3, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (id) DO UPDATE SET updated_at = ?3, startup_secs = ?4,
+                watched_secs = ?5, rebuffer_count = ?6, rebuffer_secs = ?7, rung_switches = ?8",
+            params![
+                report.session,
+                title,
+                now,
+                report.startup_secs,
+                report.watched_secs,
+                report.rebuffer_count,
+                report.rebuffer_secs,
+                report.rung_switches
+            ],
+        )?;
+        transaction.execute(
+            "DELETE FROM playback_rungs WHERE session_id = ?1",
+            params![report.session],
+        )?;
+        for rung in &report.rungs {
+            transaction.execute(
+                "INSERT INTO playback_rungs (session_id, height, bandwidth, secs)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (session_id, height, bandwidth) DO UPDATE SET secs = secs + ?4",
+                params![
+                    report.session,
+                    rung.height,
+                    rung.bandwidth as i64,
+                    rung.secs
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Totals for each title with recorded sessions, optionally only since a time.
+    pub fn playback_stats(&self, since: Option<SystemTime>) -> Result<Vec<TitleStats>> {
+        let since = since.map_or(0, |since| {
+            since
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs() as i64)
+        });
+        let mut statement = self.conn.prepare(
+            "SELECT title, COUNT(*), SUM(watched_secs), AVG(startup_secs), SUM(rebuffer_count),
+                SUM(rebuffer_secs), SUM(rung_switches)
+             FROM playback_sessions WHERE updated_at >= ?1 GROUP BY title ORDER BY title",
+        )?;
+        let mut stats: Vec<TitleStats> = statement
+            .query_map(params![since], |row| {
+                Ok(TitleStats {
+                    title: row.get(0)?,
+                    sessions: row.get(1)?,
+                    watched_secs: row.get(2)?,
+                    startup_secs: row.get(3)?,
+                    rebuffer_count: row.get(4)?,
+                    rebuffer_secs: row.get(5)?,
+                    rung_switches: row.get(6)?,
+                    rungs: BTreeMap::new(),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut statement = self.conn.prepare(
+            "SELECT s.title, r.height, SUM(r.secs) FROM playback_rungs r
+             JOIN playback_sessions s ON s.id = r.session_id
+             WHERE s.updated_at >= ?1 GROUP BY s.title, r.height",
+        )?;
+        let rows = statement.query_map(params![since], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u32>(1)?,
+                row.get::<_, f64>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (title, height, secs) = row?;
+            if let Some(entry) = stats.iter_mut().find(|entry| entry.title == title) {
+                entry.rungs.insert(height, secs);
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// A ladder fitted to what viewers sustain.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LadderAdvice {
+    /// Bitrates in MB/s, highest first
+    pub ladder: Vec<u32>,
+    /// Share of watching time at each rung of the current ladder
+    pub shares: BTreeMap<u32, f64>,
+    /// What changed and why, one line each
+    pub changes: Vec<String>,
+}
+
+impl Catalog {
+    /// Seconds watched at each representation bandwidth, in bits per
+    /// second, optionally only since a time.
+    pub fn bandwidth_watched(&self, since: Option<SystemTime>) -> Result<BTreeMap<u64, f64>> {
+        let since = since.map_or(0, |since| {
+            since
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs() as i64)
+        });
+        let mut statement = self.conn.prepare(
+            "SELECT r.bandwidth, SUM(r.secs) FROM playback_rungs r
+             JOIN playback_sessions s ON s.id = r.session_id
+             WHERE s.updated_at >= ?1 GROUP BY r.bandwidth",
+        )?;
+        let watched = statement
+            .query_map(params![since], |row| {
+                Ok((row.get::<_, i64>(0)? as u64, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(watched)
+    }
+}
+
+/// Fit `ladder`, in MB/s, to the time viewers spent at each bandwidth,
+/// keeping the rungs in `pinned`, which other settings refer to. Titles
+/// prepared with other ladders count towards the nearest rung.
+pub fn recommend_ladder(
+    ladder: &[u32],
+    pinned: &[u32],
+    watched: &BTreeMap<u64, f64>,
+) -> Result<LadderAdvice> {
+    let total: f64 = watched.values().sum();
+    if total < MIN_WATCHED_SECS {
+        bail!(
+            "Only {:.0} minutes of playback recorded, too little to go by",
+            total / 60.0
+        );
+    }
+    let mut ladder = ladder.to_vec();
+    ladder.sort_unstable_by(|a, b| b.cmp(a));
+    ladder.dedup();
+    let mut shares: BTreeMap<u32, f64> = ladder.iter().map(|rung| (*rung, 0.0)).collect();
+    for (bandwidth, secs) in watched {
+        let mbps = *bandwidth as f64 / 1_000_000.0;
+        let nearest = ladder.iter().min_by(|a, b| {
+            let distance = |rung: u32| (mbps / rung as f64).ln().abs();
+            distance(**a).total_cmp(&distance(**b))
+        });
+        if let Some(rung) = nearest {
+            *shares.entry(*rung).or_default() += secs / total;
+        }
+    }
+
+    let lowest = *ladder.last().context("The ladder has no rungs")?;
+    let mut changes = Vec::new();
+    ladder.retain(|rung| {
+        let share = shares[rung];
+        let keep = *rung == lowest || share >= MIN_SHARE || pinned.contains(rung);
+        if !keep {
+            changes.push(format!(
+                "Drop {} MB/s: only {:.1}% of watching time",
+                rung,
+                share * 100.0
+            ));
+        }
+        keep
+    });
+    if shares[&lowest] >= CROWDED_SHARE && lowest > 1 {
+        let lower = lowest / 2;
+        ladder.push(lower);
+        changes.push(format!(
+            "Add {} MB/s: {:.0}% of watching time is at the lowest rung",
+            lower,
+            shares[&lowest] * 100.0
+        ));
+    }
+    Ok(LadderAdvice {
+        ladder,
+        shares,
+        changes,
+    })
+}
+
+fn record(state: &AppState, title: &str, report: &Report) -> StatusCode {
+    let recorded = Catalog::open(&Catalog::default_path(&state.library))
+        .and_then(|mut catalog| catalog.record_playback(title, report));
+    match recorded {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            eprintln!("Failed to record playback: {:#}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TitleReport {
+    title: String,
+    #[serde(flatten)]
+    report: Report,
+}
+
+pub async fn report(
+    State(state): State<AppState>,
+    user: Option<Extension<User>>,
+    Json(request): Json<TitleReport>,
+) -> StatusCode {
+    if !request.report.is_valid() {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    if !is_title(&state.library, &request.title)
+        || !auth::may_watch(&state, user.as_deref(), &request.title)
+    {
+        return StatusCode::NOT_FOUND;
+    }
+    record(&state, &request.title, &request.report)
+}
+
+pub async fn shared_report(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Json(report): Json<Report>,
+) -> StatusCode {
+    if !report.is_valid() {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    match shared_title(&state, &token) {
+        Ok(title) => record(&state, &title, &report),
+        Err(status) => status,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(session: &str, watched_secs: f64, rebuffer_count: u32) -> Report {
+        Report {
+            session: session.to_string(),
+            startup_secs: Some(1.0),
+            watched_secs,
+            rebuffer_count,
+            rebuffer_secs: rebuffer_count as f64 * 2.0,
+            rung_switches: 1,
+            rungs: vec![RungTime {
+                height: 1080,
+                bandwidth: 6_000_000,
+                secs: watched_secs,
+            }],
+        }
+    }
+
+    #[test]
+    fn sums_sessions_per_title() {
+        let mut catalog = Catalog::open(std::path::Path::new(":memory:")).unwrap();
+        catalog
+            .record_playback("Heat", &report("a", 60.0, 0))
+            .unwrap();
+        // Later reports replace a session's totals
+        catalog
+            .record_playback("Heat", &report("a", 1800.0, 1))
+            .unwrap();
+        catalog
+            .record_playback("Heat", &report("b", 1800.0, 2))
+            .unwrap();
+        catalog
+            .record_playback("Alien", &report("c", 10.0, 0))
+            .unwrap();
+        catalog
+            .record_playback("Alien", &report("b", 1.0, 9))
+            .unwrap();
+
+        let stats = catalog.playback_stats(None).unwrap();
+        assert_eq!(stats.len(), 2);
+        let heat = &stats[1];
+        assert_eq!(heat.sessions, 2);
+        assert_eq!(heat.watched_secs, 3600.0);
+        assert_eq!(heat.rebuffer_count, 3);
+        assert_eq!(heat.rebuffers_per_hour(), 3.0);
+        assert_eq!(heat.startup_secs, Some(1.0));
+        assert_eq!(heat.rungs[&1080], 3600.0);
+
+        assert!(!report("", 1.0, 0).is_valid());
+        assert!(!report("x", f64::NAN, 0).is_valid());
+    }
+
+    #[test]
+    fn fits_the_ladder_to_what_viewers_sustain() {
+        let mut catalog = Catalog::open(std::path::Path::new(":memory:")).unwrap();
+        let mut session = report("a", 3600.0, 0);
+        session.rungs = [(1080, 12_000_000, 60.0), (720, 6_100_000, 1540.0)]
+            .into_iter()
+            .chain([(480, 2_000_000, 1000.0), (480, 1_900_000, 1000.0)])
+            .map(|(height, bandwidth, secs)| RungTime {
+                height,
+                bandwidth,
+                secs,
+            })
+            .collect();
+        catalog.record_playback("Heat", &session).unwrap();
+        let watched = catalog.bandwidth_watched(None).unwrap();
+        assert_eq!(watched[&2_000_000], 1000.0);
+
+        let advice = recommend_ladder(&[6, 12, 2], &[], &watched).unwrap();
+        assert_eq!(advice.ladder, [6, 2, 1]);
+        assert!((advice.shares[&2] - 2000.0 / 3600.0).abs() < 1e-9);
+        assert_eq!(
+            advice.changes,
+            [
+                "Drop 12 MB/s: only 1.7% of watching time",
+                "Add 1 MB/s: 56% of watching time is at the lowest rung",
+            ]
+        );
+
+        let little = BTreeMap::from([(2_000_000, 600.0)]);
+        assert_eq!(
+            recommend_ladder(&[6, 12, 2], &[12], &watched)
+                .unwrap()
+                .ladder,
+            [12, 6, 2, 1]
+        );
+        assert!(recommend_ladder(&[6, 2], &[], &little).is_err());
+    }
+}
@@ -0,0 +1,364 @@
+//! JSON API over the library, for third-party frontends.
+//!
+//! `GET /api/titles` lists the titles the viewer may watch and
+//! `GET /api/titles/{id}` describes one; a title's id is its directory name.
+//! Entries come from the catalog when `preparer library scan` has built one,
+//! and from the title directories otherwise. URLs are absolute, as seen by
+//! the client, so they work from other origins. `?attention=true` lists
+//! only the titles flagged for review; see [`crate::attention`].
+//!
+//! `GET /api/search?q=<words>` finds the subtitle cues the words are in,
+//! each with a player link that starts at the cue; see [`crate::search`].
+//!
+//! `GET /api/titles/{id}/thumbnail?t=<secs>` gives the trick-play tile
+//! showing that moment and where in it the thumbnail is, for titles
+//! prepared with them; see [`crate::trickplay`].
+//!
+//! `GET /api/titles/{id}/files` lists a title's files with their sizes and
+//! SHA-256, for `preparer sync` to tell what another host is missing; see
+//! [`crate::mirror`].
+
+use crate::auth;
+use crate::feed;
+use crate::library::{self, Catalog, FileChecksum, MANIFEST, Metadata, POSTERS, Title};
+use crate::mpd::{self, Representation};
+use crate::search::Hit;
+use crate::serve::{AppState, encode_segment, is_title};
+use crate::trickplay::{self, Thumbnail};
+use crate::users::User;
+use anyhow::Result;
+use axum::Extension;
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::path::Path as FsPath;
+
+/// One title, as the API describes it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub id: String,
+    pub title: String,
+    pub year: Option<u32>,
+    pub overview: Option<String>,
+    pub genres: Vec<String>,
+    /// Content rating, like `PG-13`
+    pub rating: Option<String>,
+    pub duration_secs: Option<f64>,
+    /// Every video, audio, text and image representation in the manifest
+    pub tracks: Vec<Representation>,
+    pub manifest_url: String,
+    pub player_url: String,
+    pub poster_url: Option<String>,
+    pub backdrop_url: Option<String>,
+    /// What's wrong with the title, if the server found it broken on start
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unavailable: Option<String>,
+    /// What the run that prepared the title warned about, until reviewed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attention: Vec<String>,
+}
+
+impl Entry {
+    fn new(title: Title, base: &str) -> Self {
+        let media = format!("{}/media/{}", base, encode_segment(&title.name));
+        let metadata = title.metadata.unwrap_or_else(|| Metadata {
+            title: title.name.clone(),
+            ..Metadata::default()
+        });
+        Self {
+            manifest_url: format!("{}/{}", media, MANIFEST),
+            player_url: format!("{}/watch/{}", base, encode_segment(&title.name)),
+            poster_url: title
+                .poster
+                .map(|poster| format!("{}/{}", media, encode_segment(&poster))),
+            backdrop_url: title
+                .backdrop
+                .map(|backdrop| format!("{}/{}", media, encode_segment(&backdrop))),
+            id: title.name,
+            title: metadata.title,
+            year: metadata.year,
+            overview: metadata.overview,
+            genres: metadata.genres,
+            rating: metadata.rating,
+            duration_secs: title.duration_secs,
+            tracks: title.ladder,
+            unavailable: None,
+            attention: metadata.attention,
+        }
+    }
+
+    fn may_watch(&self, user: Option<&User>) -> bool {
+        user.is_none_or(|user| user.can_watch(&self.id, &self.genres, self.rating.as_deref()))
+    }
+}
+
+/// A title read straight from its directory, for libraries never scanned.
+fn read_title(library: &FsPath, name: &str) -> Result<Title> {
+    let dir = library.join(name);
+    let manifest = mpd::parse(&std::fs::read_to_string(dir.join(MANIFEST))?)?;
+    Ok(Title {
+        name: name.to_string(),
+        duration_secs: manifest.duration_secs,
+        ladder: manifest.representations,
+        metadata: Metadata::read(&dir)?,
+        poster: POSTERS
+            .iter()
+            .find(|poster| dir.join(poster).is_file())
+            .map(|poster| poster.to_string()),
+        backdrop: None,
+        size_bytes: 0,
+        manifest_sha256: String::new(),
+        scanned_at: 0,
+        path: dir,
+    })
+}
+
+/// Every title in the library, from the catalog if it lists any.
+fn library_titles(library: &FsPath) -> Result<Vec<Title>> {
+    if let Some(catalog) = Catalog::open_existing(library)? {
+        let titles = catalog.titles()?;
+        if !titles.is_empty() {
+            return Ok(titles);
+        }
+    }
+    let mut names: Vec<String> = std::fs::read_dir(library)?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_title(library, name))
+        .collect();
+    names.sort();
+    // A title being written may not have a readable manifest yet
+    Ok(names
+        .iter()
+        .filter_map(|name| read_title(library, name).ok())
+        .collect())
+}
+
+fn internal_error(err: anyhow::Error) -> StatusCode {
+    eprintln!("Failed to read the library: {:#}", err);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// Only the titles flagged for review, or only those that aren't
+    attention: Option<bool>,
+}
+
+pub async fn list(
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Entry>>, StatusCode> {
+    let base = feed::base_url(&headers);
+    let library = state.library.clone();
+    let titles = tokio::task::spawn_blocking(move || library_titles(&library))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(internal_error)?;
+    Ok(Json(
+        titles
+            .into_iter()
+            .map(|title| Entry {
+                unavailable: state.unavailable.get(&title.name).cloned(),
+                ..Entry::new(title, &base)
+            })
+            .filter(|entry| entry.may_watch(user.as_deref()))
+            .filter(|entry| {
+                let flagged = !entry.attention.is_empty();
+                query.attention.is_none_or(|attention| attention == flagged)
+            })
+            .collect(),
+    ))
+}
+
+pub async fn get(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
+) -> Result<Json<Entry>, StatusCode> {
+    if id.contains('/') || !is_title(&state.library, &id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let library = state.library.clone();
+    let title = tokio::task::spawn_blocking(move || -> Result<Title> {
+        if let Some(catalog) = Catalog::open_existing(&library)?
+            && let Some(title) = catalog.title(&id)?
+        {
+            return Ok(title);
+        }
+        read_title(&library, &id)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(internal_error)?;
+    let entry = Entry {
+        unavailable: state.unavailable.get(&title.name).cloned(),
+        ..Entry::new(title, &feed::base_url(&headers))
+    };
+    // Titles a user may not watch don't exist as far as they can tell
+    match entry.may_watch(user.as_deref()) {
+        true => Ok(Json(entry)),
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailQuery {
+    t: f64,
+}
+
+/// The trick-play thumbnail of the title `name` in `library` at `secs`.
+fn read_thumbnail(library: &FsPath, name: &str, secs: f64) -> Result<Option<Thumbnail>> {
+    let xml = std::fs::read_to_string(library.join(name).join(MANIFEST))?;
+    let Some(track) = mpd::thumbnail_track(&xml)? else {
+        return Ok(None);
+    };
+    let duration_secs = mpd::parse(&xml)?.duration_secs;
+    Ok(trickplay::thumbnail_at(&track, duration_secs, secs))
+}
+
+pub async fn thumbnail(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
+    Query(query): Query<ThumbnailQuery>,
+) -> Result<Json<Thumbnail>, StatusCode> {
+    if id.contains('/')
+        || !is_title(&state.library, &id)
+        || !auth::may_watch(&state, user.as_deref(), &id)
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let library = state.library.clone();
+    let name = id.clone();
+    let thumbnail = tokio::task::spawn_blocking(move || read_thumbnail(&library, &name, query.t))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(Thumbnail {
+        url: format!(
+            "{}/media/{}/{}",
+            feed::base_url(&headers),
+            encode_segment(&id),
+            thumbnail.url
+        ),
+        ..thumbnail
+    }))
+}
+
+pub async fn files(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    user: Option<Extension<User>>,
+) -> Result<Json<Vec<FileChecksum>>, StatusCode> {
+    // Encrypted titles are served decrypted, so they wouldn't match these
+    if state.library_key.is_some() {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+    if id.contains('/')
+        || !is_title(&state.library, &id)
+        || !auth::may_watch(&state, user.as_deref(), &id)
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let library = state.library.clone();
+    let checksums = tokio::task::spawn_blocking(move || library::checksums(&library, &id))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(internal_error)?;
+    Ok(Json(checksums))
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+/// A cue that matched a search, and where to watch it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub hit: Hit,
+    /// Player page starting at the cue
+    pub player_url: String,
+}
+
+pub async fn search(
+    State(state): State<AppState>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchHit>>, StatusCode> {
+    let library = state.library.clone();
+    let limit = query.limit.unwrap_or(20).min(100);
+    let hits = tokio::task::spawn_blocking(move || -> Result<Vec<Hit>> {
+        match Catalog::open_existing(&library)? {
+            Some(catalog) => catalog.search(&query.q, limit),
+            None => Ok(Vec::new()),
+        }
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(internal_error)?;
+    let base = feed::base_url(&headers);
+    Ok(Json(
+        hits.into_iter()
+            .filter(|hit| auth::may_watch(&state, user.as_deref(), &hit.title))
+            .map(|hit| SearchHit {
+                player_url: format!(
+                    "{}/watch/{}?t={}",
+                    base,
+                    encode_segment(&hit.title),
+                    hit.start_secs
+                ),
+                hit,
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_titles_with_absolute_urls() {
+        let library = std::env::temp_dir().join(format!("movieshare-api-{}", std::process::id()));
+        let dir = library.join("Heat 1995");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD mediaPresentationDuration="PT2H50M"><Period>
+                <AdaptationSet contentType="video"><Representation id="0" bandwidth="6000000" height="1080"/></AdaptationSet>
+                <AdaptationSet contentType="audio" lang="en"><Representation id="1" bandwidth="128000"/></AdaptationSet>
+            </Period></MPD>"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("poster.png"), b"png").unwrap();
+
+        let titles = library_titles(&library).unwrap();
+        let entry = Entry::new(titles[0].clone(), "http://tv.local:8080");
+        assert_eq!(entry.id, "Heat 1995");
+        assert_eq!(entry.title, "Heat 1995");
+        assert_eq!(entry.duration_secs, Some(10200.0));
+        assert_eq!(entry.tracks.len(), 2);
+        assert_eq!(entry.tracks[1].lang.as_deref(), Some("en"));
+        assert_eq!(
+            entry.manifest_url,
+            "http://tv.local:8080/media/Heat%201995/manifest.mpd"
+        );
+        assert_eq!(
+            entry.poster_url.as_deref(),
+            Some("http://tv.local:8080/media/Heat%201995/poster.png")
+        );
+
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
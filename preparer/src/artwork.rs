@@ -0,0 +1,88 @@
+//! Poster and backdrop images cached in a title's output directory.
+//!
+//! Each image is kept at its original size plus a few narrower copies, named
+//! like `poster-w342.jpg`, so pages can pick one to fit.
+
+use crate::library::Metadata;
+use anyhow::{Context, Result};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use std::path::Path;
+
+pub const POSTER: &str = "poster.jpg";
+pub const BACKDROP: &str = "backdrop.jpg";
+const POSTER_WIDTHS: &[u32] = &[342, 780];
+const BACKDROP_WIDTHS: &[u32] = &[780, 1280];
+
+/// File name of the copy of `name` resized to `width` pixels wide.
+pub fn sized_name(name: &str, width: u32) -> String {
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, "jpg"));
+    format!("{}-w{}.{}", stem, width, extension)
+}
+
+/// Download the metadata's poster and backdrop into `dir`, unless already there.
+pub fn cache(metadata: &Metadata, dir: &Path) -> Result<()> {
+    let images = [
+        (&metadata.poster_url, POSTER, POSTER_WIDTHS),
+        (&metadata.backdrop_url, BACKDROP, BACKDROP_WIDTHS),
+    ];
+    for (url, name, widths) in images {
+        let Some(url) = url else {
+            continue;
+        };
+        if dir.join(name).is_file() {
+            continue;
+        }
+
+        let bytes = ureq::get(url)
+            .call()
+            .context(format!("Failed to download {}", url))?
+            .body_mut()
+            .read_to_vec()?;
+        let image = image::load_from_memory(&bytes).context(format!("Invalid image: {}", url))?;
+        save_sized(&image, dir, name, widths)?;
+    }
+    Ok(())
+}
+
+fn save_sized(image: &DynamicImage, dir: &Path, name: &str, widths: &[u32]) -> Result<()> {
+    let save = |image: &DynamicImage, name: &str| {
+        let path = dir.join(name);
+        DynamicImage::from(image.to_rgb8())
+            .save_with_format(&path, ImageFormat::Jpeg)
+            .context(format!("Failed to write {}", path.display()))
+    };
+
+    for &width in widths {
+        // Never scale up; a small original is just copied
+        let sized = match width < image.width() {
+            true => image.resize(width, u32::MAX, FilterType::Lanczos3),
+            false => image.clone(),
+        };
+        save(&sized, &sized_name(name, width))?;
+    }
+    // Written last so its presence means the whole set is cached
+    save(image, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_every_size() {
+        let dir = std::env::temp_dir().join(format!("movieshare-artwork-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let image = DynamicImage::new_rgb8(500, 750);
+        save_sized(&image, &dir, POSTER, POSTER_WIDTHS).unwrap();
+
+        let small = image::open(dir.join("poster-w342.jpg")).unwrap();
+        assert_eq!((small.width(), small.height()), (342, 513));
+        let large = image::open(dir.join("poster-w780.jpg")).unwrap();
+        assert_eq!(large.width(), 500);
+        assert!(dir.join(POSTER).is_file());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+//! Titles that need someone to look at them, so that after a big batch run
+//! only the suspect ones get reviewed.
+//!
+//! Everything a run warns about, like audio drifting out of sync, long
+//! stretches of black or frozen frames, a loudness that couldn't be
+//! matched or a first segment that won't start at once, is kept in the
+//! title's `metadata.json` and flags it in the catalog. `preparer qc list`
+//! lists the flagged titles, `GET /api/titles?attention=true` does the same
+//! for frontends, and `preparer qc clear` drops the flag once a title has
+//! been checked.
+
+use crate::library::{Catalog, Metadata};
+use anyhow::{Result, bail};
+use movieshare_core::JobEvent;
+use serde::Serialize;
+use std::path::Path;
+
+/// What `event` gives a reviewer to look at, if anything.
+pub fn reason(event: &JobEvent) -> Option<String> {
+    match event {
+        JobEvent::Warning(warning) => Some(warning.clone()),
+        JobEvent::Qc(report) if !report.runs.is_empty() => Some(report.to_string()),
+        _ => None,
+    }
+}
+
+/// A title flagged for review.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Flagged {
+    pub name: String,
+    pub reasons: Vec<String>,
+}
+
+impl Catalog {
+    /// The titles flagged for review, by name.
+    pub fn needing_attention(&self) -> Result<Vec<Flagged>> {
+        let mut statement = self.conn.prepare(
+            "SELECT name, attention FROM titles WHERE attention IS NOT NULL ORDER BY name",
+        )?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+        rows.into_iter()
+            .map(|(name, reasons)| {
+                Ok(Flagged {
+                    name,
+                    reasons: serde_json::from_str(&reasons)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Drop the flag on the title `name` in `library`, once it's been checked.
+pub fn clear(catalog: &mut Catalog, library: &Path, name: &str) -> Result<()> {
+    let dir = library.join(name);
+    let Some(mut metadata) = Metadata::read(&dir)?.filter(|m| !m.attention.is_empty()) else {
+        bail!("{} isn't flagged for review", name);
+    };
+    metadata.attention.clear();
+    metadata.write(&dir)?;
+    catalog.refresh(name, &dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::MANIFEST;
+    use movieshare_core::qc::{Defect, DefectRun, QcReport};
+    use std::time::Duration;
+
+    #[test]
+    fn flags_titles_until_cleared() {
+        let library = std::env::temp_dir().join(format!("movieshare-qc-{}", std::process::id()));
+        let mpd = r#"<MPD mediaPresentationDuration="PT10S"><Period></Period></MPD>"#;
+        for name in ["Clean", "Suspect"] {
+            std::fs::create_dir_all(library.join(name)).unwrap();
+            std::fs::write(library.join(name).join(MANIFEST), mpd).unwrap();
+        }
+        let events = [
+            JobEvent::Qc(QcReport { runs: Vec::new() }),
+            JobEvent::Warning(String::from("Audio drifts 0.30s from the video")),
+            JobEvent::Qc(QcReport {
+                runs: vec![DefectRun {
+                    defect: Defect::Black,
+                    start: Duration::from_secs(60),
+                    end: Duration::from_secs(65),
+                }],
+            }),
+        ];
+        let metadata = Metadata {
+            title: String::from("Suspect"),
+            attention: events.iter().filter_map(reason).collect(),
+            ..Default::default()
+        };
+        metadata.write(&library.join("Suspect")).unwrap();
+
+        let mut catalog = Catalog::open(&Catalog::default_path(&library)).unwrap();
+        catalog.scan(&library, false).unwrap();
+        assert_eq!(
+            catalog.needing_attention().unwrap(),
+            [Flagged {
+                name: String::from("Suspect"),
+                reasons: vec![
+                    String::from("Audio drifts 0.30s from the video"),
+                    String::from("Check the video where it's black from 1:00.0 to 1:05.0"),
+                ],
+            }]
+        );
+
+        assert!(clear(&mut catalog, &library, "Clean").is_err());
+        clear(&mut catalog, &library, "Suspect").unwrap();
+        assert!(catalog.needing_attention().unwrap().is_empty());
+        assert_eq!(catalog.scan(&library, false).unwrap().unchanged, 2);
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
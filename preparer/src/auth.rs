@@ -0,0 +1,221 @@
+//! Signing in to the library server.
+//!
+//! With password auth, `/login` checks a user's password from the catalog
+//! and sets a signed session cookie. With a trusted header, an
+//! authenticating proxy in front of the server (oauth2-proxy or similar,
+//! for OIDC) names the user and the server takes its word for it. Either
+//! way the user must exist in the catalog, which decides what they may watch.
+
+use crate::library::Catalog;
+use crate::serve::AppState;
+use crate::users::User;
+use anyhow::Result;
+use axum::Form;
+use axum::extract::{Query, Request, State};
+use axum::http::{HeaderMap, HeaderName, Method, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use serde::Deserialize;
+use std::time::{Duration, SystemTime};
+
+const SESSION_COOKIE: &str = "movieshare_session";
+const SESSION_LENGTH: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Clone, Debug)]
+pub enum AuthMode {
+    Password,
+    /// Trust the user name in this header, set by an authenticating proxy
+    Header(HeaderName),
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|cookie| {
+            cookie
+                .trim()
+                .strip_prefix(SESSION_COOKIE)?
+                .strip_prefix('=')
+        })
+}
+
+fn current_user(state: &AppState, mode: &AuthMode, headers: &HeaderMap) -> Result<Option<User>> {
+    let name = match mode {
+        AuthMode::Password => session_cookie(headers)
+            .and_then(|token| state.key.verify_session(token, SystemTime::now()).ok()),
+        AuthMode::Header(header) => headers
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+    };
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    match Catalog::open_existing(&state.library)? {
+        Some(catalog) => catalog.user(&name),
+        None => Ok(None),
+    }
+}
+
+/// Decode a percent-encoded URL path segment.
+fn decode_segment(segment: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = segment.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Whether `user` (if auth is on) may watch `title`.
+pub fn may_watch(state: &AppState, user: Option<&User>, title: &str) -> bool {
+    let Some(user) = user else {
+        return state.auth.is_none();
+    };
+    match Catalog::open_existing(&state.library) {
+        Ok(Some(catalog)) => catalog.can_watch(user, title).unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Require a signed-in user for everything behind this layer.
+///
+/// The user is added to the request's extensions for handlers to check
+/// titles against. Files under `/media/<title>/` are checked here, since
+/// they're served straight from disk.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(mode) = &state.auth else {
+        return next.run(request).await;
+    };
+    let user = match current_user(&state, mode, request.headers()) {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            let page =
+                request.method() == Method::GET && !request.uri().path().starts_with("/media/");
+            return match mode {
+                AuthMode::Password if page => Redirect::to("/login").into_response(),
+                _ => StatusCode::UNAUTHORIZED.into_response(),
+            };
+        }
+        Err(err) => {
+            eprintln!("Failed to look up user: {:#}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if let Some(rest) = request.uri().path().strip_prefix("/media/") {
+        let title = rest.split('/').next().and_then(decode_segment);
+        if !title.is_some_and(|title| may_watch(&state, Some(&user), &title)) {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    }
+    request.extensions_mut().insert(user);
+    next.run(request).await
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    #[serde(default)]
+    failed: bool,
+}
+
+pub async fn login_page(Query(query): Query<LoginQuery>) -> Html<String> {
+    let error = match query.failed {
+        true => "        <p>Wrong name or password.</p>\n",
+        false => "",
+    };
+    Html(format!(
+        r#"<!doctype html>
+<html lang="en">
+    <head>
+        <meta charset="UTF-8" />
+        <title>Sign in</title>
+    </head>
+    <body>
+        <h1>Sign in</h1>
+{}        <form method="post" action="/login">
+            <label>Name <input name="name" autocomplete="username" required /></label>
+            <label>Password <input name="password" type="password" autocomplete="current-password" required /></label>
+            <button>Sign in</button>
+        </form>
+    </body>
+</html>
+"#,
+        error
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct Login {
+    name: String,
+    password: String,
+}
+
+pub async fn login(State(state): State<AppState>, Form(login): Form<Login>) -> Response {
+    // Password hashing is slow on purpose; keep it off the async workers
+    let library = state.library.clone();
+    let name = login.name.clone();
+    let user = tokio::task::spawn_blocking(move || match Catalog::open_existing(&library)? {
+        Some(catalog) => catalog.authenticate(&login.name, &login.password),
+        None => Ok(None),
+    })
+    .await;
+
+    match user {
+        Ok(Ok(Some(_))) => {
+            let token = state
+                .key
+                .sign_session(&name, SystemTime::now() + SESSION_LENGTH);
+            let cookie = format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                SESSION_COOKIE,
+                token,
+                SESSION_LENGTH.as_secs()
+            );
+            ([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response()
+        }
+        Ok(Ok(None)) => Redirect::to("/login?failed=true").into_response(),
+        Ok(Err(err)) => {
+            eprintln!("Failed to sign in {}: {:#}", name, err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn logout() -> Response {
+    let cookie = format!(
+        "{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax",
+        SESSION_COOKIE
+    );
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/login")).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_segments() {
+        assert_eq!(
+            decode_segment("Alien%20(1979)").as_deref(),
+            Some("Alien (1979)")
+        );
+        assert_eq!(decode_segment("%C3%A9t%C3%A9").as_deref(), Some("été"));
+        assert_eq!(decode_segment("%2"), None);
+    }
+}
@@ -0,0 +1,166 @@
+//! Bencoding, the serialization format of .torrent files and tracker responses.
+
+use anyhow::{Result, bail};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// Keys are kept sorted, as the format requires
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn str(text: &str) -> Self {
+        Self::Bytes(text.as_bytes().to_vec())
+    }
+
+    pub fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Self::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Dict(entries) => entries.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Int(value) => out.extend(format!("i{}e", value).as_bytes()),
+            Self::Bytes(bytes) => {
+                out.extend(format!("{}:", bytes.len()).as_bytes());
+                out.extend(bytes);
+            }
+            Self::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Self::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    out.extend(format!("{}:", key.len()).as_bytes());
+                    out.extend(key);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let (value, rest) = Self::decode_prefix(data)?;
+        if !rest.is_empty() {
+            bail!("Trailing data after bencoded value");
+        }
+        Ok(value)
+    }
+
+    fn decode_prefix(data: &[u8]) -> Result<(Self, &[u8])> {
+        match data.first() {
+            Some(b'i') => {
+                let end = find(data, b'e')?;
+                let value = std::str::from_utf8(&data[1..end])?.parse()?;
+                Ok((Self::Int(value), &data[end + 1..]))
+            }
+            Some(b'l') => {
+                let mut items = Vec::new();
+                let mut rest = &data[1..];
+                while rest.first() != Some(&b'e') {
+                    let (item, next) = Self::decode_prefix(rest)?;
+                    items.push(item);
+                    rest = next;
+                }
+                Ok((Self::List(items), &rest[1..]))
+            }
+            Some(b'd') => {
+                let mut entries = BTreeMap::new();
+                let mut rest = &data[1..];
+                while rest.first() != Some(&b'e') {
+                    let (key, next) = Self::decode_prefix(rest)?;
+                    let Self::Bytes(key) = key else {
+                        bail!("Dictionary key isn't a string");
+                    };
+                    let (value, next) = Self::decode_prefix(next)?;
+                    entries.insert(key, value);
+                    rest = next;
+                }
+                Ok((Self::Dict(entries), &rest[1..]))
+            }
+            Some(b'0'..=b'9') => {
+                let colon = find(data, b':')?;
+                let length: usize = std::str::from_utf8(&data[..colon])?.parse()?;
+                let start = colon + 1;
+                if data.len() < start + length {
+                    bail!("Truncated bencoded string");
+                }
+                Ok((
+                    Self::Bytes(data[start..start + length].to_vec()),
+                    &data[start + length..],
+                ))
+            }
+            _ => bail!("Invalid bencoded value"),
+        }
+    }
+}
+
+fn find(data: &[u8], byte: u8) -> Result<usize> {
+    match data.iter().position(|&b| b == byte) {
+        Some(position) => Ok(position),
+        None => bail!("Truncated bencoded value"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let value = Value::dict([
+            ("name", Value::str("Heat")),
+            ("length", Value::Int(-42)),
+            ("list", Value::List(vec![Value::Int(1), Value::str("")])),
+        ]);
+        let encoded = value.encode();
+        assert_eq!(encoded, b"d6:lengthi-42e4:listli1e0:e4:name4:Heate");
+        assert_eq!(Value::decode(&encoded).unwrap(), value);
+        assert!(Value::decode(b"5:abc").is_err());
+        assert!(Value::decode(b"i1ei2e").is_err());
+    }
+}
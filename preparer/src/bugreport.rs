@@ -0,0 +1,298 @@
+//! `preparer report-bug`: bundle what it takes to look into a failed
+//! preparation into one tar archive to attach to a report.
+//!
+//! The bundle holds what probing the input found, a short stretch of it
+//! around where things went wrong, re-encoded small enough to attach, and
+//! the events, GStreamer warnings and pipeline graph from preparing that
+//! stretch, which usually fails the same way the whole input did, and what
+//! features the machine's GStreamer supports.
+
+use crate::clip::link_when_added;
+use crate::doctor;
+use anyhow::{Context, Result, bail};
+use futures::StreamExt;
+use movieshare_core::events::Event;
+use movieshare_core::gst;
+use movieshare_core::gst::prelude::*;
+use movieshare_core::journal::JOURNAL_FILENAME;
+use movieshare_core::plan;
+use movieshare_core::{EncodingProfile, JobEvent, PrepareJob, Preparer};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Height the sample is scaled down to, so it's small enough to attach.
+const SAMPLE_HEIGHT: i32 = 360;
+const SAMPLE_KBPS: u32 = 1000;
+/// Size of a tar header and of the blocks file contents are padded to.
+const BLOCK: usize = 512;
+
+/// Re-encode `length` seconds of `input` from `start` into a small
+/// Matroska file at `output`, with its first video and audio streams.
+fn cut_sample(input: &Path, start: f64, length: f64, output: &Path) -> Result<()> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*input.to_string_lossy())
+        .build()?;
+    let decodebin = gst::ElementFactory::make("decodebin").build()?;
+    let mux = gst::ElementFactory::make("matroskamux").build()?;
+    let filesink = gst::ElementFactory::make("filesink")
+        .property("location", &*output.to_string_lossy())
+        .build()?;
+    pipeline.add_many([&filesrc, &decodebin, &mux, &filesink])?;
+    filesrc.link(&decodebin)?;
+    mux.link(&filesink)?;
+
+    let video = [
+        gst::ElementFactory::make("queue").build()?,
+        gst::ElementFactory::make("videoconvert").build()?,
+        gst::ElementFactory::make("videoscale").build()?,
+        gst::ElementFactory::make("capsfilter")
+            .property(
+                "caps",
+                gst::Caps::builder("video/x-raw")
+                    .field("height", SAMPLE_HEIGHT)
+                    .build(),
+            )
+            .build()?,
+        gst::ElementFactory::make("x264enc")
+            .property("bitrate", SAMPLE_KBPS)
+            .property_from_str("speed-preset", "veryfast")
+            .build()?,
+        gst::ElementFactory::make("h264parse").build()?,
+    ];
+    let audio = [
+        gst::ElementFactory::make("queue").build()?,
+        gst::ElementFactory::make("audioconvert").build()?,
+        gst::ElementFactory::make("audioresample").build()?,
+        gst::ElementFactory::make("opusenc").build()?,
+    ];
+    let source = plan::probe(input)?;
+    for (chain, present) in [
+        (&video[..], source.video.is_some()),
+        (&audio[..], source.audio.is_some()),
+    ] {
+        if !present {
+            continue;
+        }
+        pipeline.add_many(chain)?;
+        gst::Element::link_many(chain)?;
+        chain.last().unwrap().link(&mux)?;
+    }
+    if source.video.is_some() {
+        link_when_added(&decodebin, "video/", &video[0].static_pad("sink").unwrap());
+    }
+    if source.audio.is_some() {
+        link_when_added(&decodebin, "audio/", &audio[0].static_pad("sink").unwrap());
+    }
+
+    let bus = pipeline.bus().unwrap();
+    let run = || -> Result<()> {
+        pipeline.set_state(gst::State::Paused)?;
+        wait_for(&bus, gst::MessageType::AsyncDone)?;
+        pipeline.seek(
+            1.0,
+            gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+            gst::SeekType::Set,
+            gst::ClockTime::from_nseconds((start * 1e9) as u64),
+            gst::SeekType::Set,
+            gst::ClockTime::from_nseconds(((start + length) * 1e9) as u64),
+        )?;
+        pipeline.set_state(gst::State::Playing)?;
+        wait_for(&bus, gst::MessageType::Eos)
+    };
+    let result = run();
+    pipeline.set_state(gst::State::Null)?;
+    result.context("Failed to cut a sample out of the input")
+}
+
+/// Wait for a message of type `wanted` on `bus`, failing on an error.
+fn wait_for(bus: &gst::Bus, wanted: gst::MessageType) -> Result<()> {
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        match msg.view() {
+            gst::MessageView::Error(err) => bail!(
+                "Error from {:?}: {} ({:?})",
+                err.src().map(|s| s.path_string()),
+                err.error(),
+                err.debug()
+            ),
+            _ if msg.type_() == wanted => return Ok(()),
+            _ => (),
+        }
+    }
+    bail!("The pipeline stopped before {:?}", wanted)
+}
+
+/// Prepare `sample` into `scratch`, returning its events as JSON lines and
+/// what GStreamer logged at warning level or worse. The pipeline's graph
+/// goes to `graph` if it fails.
+fn prepare_sample(
+    sample: &Path,
+    scratch: &Path,
+    profile: &EncodingProfile,
+    graph: &Path,
+) -> Result<(String, String)> {
+    gst::init()?;
+    let logged = Arc::new(Mutex::new(String::new()));
+    let log = logged.clone();
+    gst::log::remove_default_log_function();
+    gst::log::set_active(true);
+    gst::log::set_default_threshold(gst::DebugLevel::Warning);
+    let log_function = gst::log::add_log_function(
+        move |category, level, file, function, line, object, message| {
+            let object = object.map_or(String::new(), |object| format!(" {}", object));
+            let message = message.get().map_or(String::new(), |m| m.to_string());
+            log.lock().unwrap().push_str(&format!(
+                "{:?} {} {}:{}:{}{}: {}\n",
+                level,
+                category.name(),
+                file,
+                line,
+                function,
+                object,
+                message
+            ));
+        },
+    );
+
+    let preparer = Preparer::new(sample)
+        .output(scratch.join("prepared"))
+        .profile(profile.clone())
+        .probed(plan::probe(sample)?)
+        .failure_graph(Some(graph.to_path_buf()));
+    let mut job = PrepareJob::spawn(preparer);
+    let mut events = String::new();
+    futures::executor::block_on(async {
+        while let Some(event) = job.next().await {
+            events.push_str(&Event::from_job_event(&event).to_json()?);
+            events.push('\n');
+            if let JobEvent::Finished(result) = event {
+                return result.map(|_| ());
+            }
+        }
+        Ok(())
+    })
+    .unwrap_or_else(|err| events.push_str(&format!("# The run failed: {:#}\n", err)));
+
+    gst::log::remove_log_function(log_function);
+    gst::log::set_active(false);
+    let logged = logged.lock().unwrap().clone();
+    Ok((events, logged))
+}
+
+/// A ustar header for a regular file `name` of `size` bytes.
+fn tar_header(name: &str, size: u64, mtime: u64) -> Result<[u8; BLOCK]> {
+    if name.len() > 100 {
+        bail!("{} is too long a name for a tar archive", name);
+    }
+    let mut header = [0u8; BLOCK];
+    let mut put = |offset: usize, field: &[u8]| {
+        header[offset..offset + field.len()].copy_from_slice(field);
+    };
+    put(0, name.as_bytes());
+    put(100, b"0000644\0");
+    put(108, b"0000000\0");
+    put(116, b"0000000\0");
+    put(124, format!("{:011o}\0", size).as_bytes());
+    put(136, format!("{:011o}\0", mtime).as_bytes());
+    put(148, b"        ");
+    put(156, b"0");
+    put(257, b"ustar\x0000");
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+/// Write `files`, by name and contents, to a tar archive at `path`.
+fn write_tar(path: &Path, files: &[(&str, Vec<u8>)]) -> Result<()> {
+    let mut archive =
+        std::fs::File::create(path).context(format!("Failed to create {}", path.display()))?;
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    for (name, contents) in files {
+        archive.write_all(&tar_header(name, contents.len() as u64, mtime)?)?;
+        archive.write_all(contents)?;
+        let padding = (BLOCK - contents.len() % BLOCK) % BLOCK;
+        archive.write_all(&vec![0; padding])?;
+    }
+    // Two empty blocks end the archive
+    archive.write_all(&[0; 2 * BLOCK])?;
+    Ok(())
+}
+
+/// Bundle a report on `input` failing around `start` into a tar archive at
+/// `output`, with `length` seconds of it as the sample. `output_dir` is
+/// where the failed run wrote, for its journal. Returns whether preparing
+/// the sample failed too, as it's less use if it didn't. The sample is cut
+/// and prepared under `scratch`.
+pub fn report(
+    input: &Path,
+    start: f64,
+    length: f64,
+    profile: &EncodingProfile,
+    output_dir: Option<&Path>,
+    output: &Path,
+    scratch: &Path,
+) -> Result<bool> {
+    let scratch = scratch.join(format!("movieshare-bug-{}", std::process::id()));
+    std::fs::create_dir_all(&scratch)?;
+    let result = (|| {
+        let mut files = Vec::new();
+        let probe = match plan::probe(input) {
+            Ok(source) => format!("{:#?}\n", source),
+            Err(err) => format!("Probing failed: {:#}\n", err),
+        };
+        files.push(("probe.txt", probe.into_bytes()));
+        // What this machine's GStreamer can do, as `preparer doctor` reports it
+        files.push(("doctor.txt", doctor::report()?.to_string().into_bytes()));
+
+        let sample = scratch.join("sample.mkv");
+        cut_sample(input, start, length, &sample)?;
+        let graph = scratch.join("pipeline.dot");
+        let (events, logged) = prepare_sample(&sample, &scratch, profile, &graph)?;
+        files.push(("sample.mkv", std::fs::read(&sample)?));
+        files.push(("events.jsonl", events.into_bytes()));
+        files.push(("gstreamer.log", logged.into_bytes()));
+        let failed = graph.exists();
+        if failed {
+            files.push(("pipeline.dot", std::fs::read(&graph)?));
+        }
+        if let Some(journal) = output_dir
+            .map(|dir| dir.join(JOURNAL_FILENAME))
+            .filter(|journal| journal.exists())
+        {
+            files.push((JOURNAL_FILENAME, std::fs::read(&journal)?));
+        }
+        write_tar(output, &files)?;
+        Ok(failed)
+    })();
+    let _ = std::fs::remove_dir_all(&scratch);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_ustar_headers() {
+        let header = tar_header("probe.txt", 1234, 0).unwrap();
+        assert_eq!(&header[..9], b"probe.txt");
+        assert_eq!(&header[124..136], b"00000002322\0");
+        assert_eq!(&header[257..265], b"ustar\x0000");
+        // The checksum counts its own field as spaces
+        let mut blanked = header;
+        blanked[148..156].copy_from_slice(b"        ");
+        let sum: u32 = blanked.iter().map(|&byte| byte as u32).sum();
+        let field = std::str::from_utf8(&header[148..154]).unwrap();
+        assert_eq!(u32::from_str_radix(field, 8).unwrap(), sum);
+        assert!(tar_header(&"x".repeat(101), 0, 0).is_err());
+
+        let path = std::env::temp_dir().join(format!("bug-test-{}.tar", std::process::id()));
+        write_tar(&path, &[("a.txt", b"hello".to_vec())]).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * BLOCK as u64);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
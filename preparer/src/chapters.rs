@@ -0,0 +1,210 @@
+//! Stills for chapter menus.
+//!
+//! When the source has chapters, a frame a little into each one is saved
+//! next to the manifest while preparing, and the chapters are listed with
+//! their stills in `metadata.json`.
+
+use crate::library::Chapter;
+use crate::split::TocChapter;
+use anyhow::{Context, Result};
+use gstreamer_video as gst_video;
+use gstreamer_video::prelude::*;
+use image::{ImageFormat, RgbImage};
+use movieshare_core::gst;
+use movieshare_core::{MediaType, PipelineBranch};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How far into a chapter its still is taken, past any fade in.
+const OFFSET: Duration = Duration::from_secs(3);
+/// Width of the stills; the height follows the picture's aspect ratio.
+const WIDTH: i32 = 320;
+
+/// When to take each chapter's still: [`OFFSET`] into it, or halfway
+/// through chapters shorter than twice that.
+pub fn sample_times(starts: &[f64]) -> Vec<Duration> {
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| {
+            let offset = match starts.get(i + 1) {
+                Some(next) => OFFSET.min(Duration::from_secs_f64((next - start).max(0.0) / 2.0)),
+                None => OFFSET,
+            };
+            Duration::from_secs_f64(start.max(0.0)) + offset
+        })
+        .collect()
+}
+
+/// The chapters whose stills are due at `pts`, given the first `done` are
+/// taken: a frame can be the still of several chapters close together.
+fn due(times: &[Duration], done: usize, pts: Duration) -> Range<usize> {
+    done..done.max(times.partition_point(|time| *time <= pts))
+}
+
+/// File name of the still of the chapter at `index`.
+fn filename(index: usize) -> String {
+    format!("chapter-{:02}.jpg", index + 1)
+}
+
+/// Saves a still of each chapter into the output directory.
+#[derive(Clone)]
+pub struct ChapterThumbnailBranch {
+    queue: gst::Element,
+    videoconvert: gst::Element,
+    videoscale: gst::Element,
+    capsfilter: gst::Element,
+    sink: gst::Element,
+    dir: PathBuf,
+    times: Arc<Vec<Duration>>,
+    /// How many stills were taken
+    done: Arc<Mutex<usize>>,
+}
+
+impl ChapterThumbnailBranch {
+    /// Take stills of chapters starting at `starts`, in seconds, into `dir`.
+    pub fn new(dir: &Path, starts: &[f64]) -> Result<Self> {
+        Ok(Self {
+            queue: gst::ElementFactory::make("queue").build()?,
+            videoconvert: gst::ElementFactory::make("videoconvert").build()?,
+            videoscale: gst::ElementFactory::make("videoscale").build()?,
+            capsfilter: gst::ElementFactory::make("capsfilter")
+                .property(
+                    "caps",
+                    gst::Caps::builder("video/x-raw")
+                        .field("format", "RGB")
+                        .field("width", WIDTH)
+                        .field("pixel-aspect-ratio", gst::Fraction::new(1, 1))
+                        .build(),
+                )
+                .build()?,
+            sink: gst::ElementFactory::make("fakesink").build()?,
+            dir: dir.to_owned(),
+            times: Arc::new(sample_times(starts)),
+            done: Arc::default(),
+        })
+    }
+
+    /// `chapters` as listed in the metadata, with the stills taken of them.
+    pub fn chapters(&self, chapters: &[TocChapter]) -> Vec<Chapter> {
+        let done = *self.done.lock().unwrap();
+        chapters
+            .iter()
+            .enumerate()
+            .map(|(i, chapter)| Chapter {
+                title: chapter.title.clone(),
+                start_secs: chapter.start_secs,
+                thumbnail: (i < done).then(|| filename(i)),
+            })
+            .collect()
+    }
+
+    fn elements(&self) -> [&gst::Element; 5] {
+        [
+            &self.queue,
+            &self.videoconvert,
+            &self.videoscale,
+            &self.capsfilter,
+            &self.sink,
+        ]
+    }
+}
+
+impl PipelineBranch for ChapterThumbnailBranch {
+    fn name(&self) -> String {
+        String::from("chapters")
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::Video
+    }
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
+        pipeline.add_many(self.elements())?;
+        Ok(())
+    }
+
+    fn link(&self, tee: &gst::Element, _dashsink: &gst::Element) -> Result<()> {
+        tee.link(&self.queue)?;
+        gst::Element::link_many(self.elements())?;
+
+        // Only frames due as a still go on to be converted and scaled
+        let times = self.times.clone();
+        let passed = Mutex::new(0);
+        self.queue
+            .static_pad("src")
+            .context("Failed to get src pad from queue")?
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                let Some(pts) = info.buffer().and_then(|buffer| buffer.pts()) else {
+                    return gst::PadProbeReturn::Drop;
+                };
+                let mut passed = passed.lock().unwrap();
+                let chapters = due(&times, *passed, Duration::from_nanos(pts.nseconds()));
+                if chapters.is_empty() {
+                    return gst::PadProbeReturn::Drop;
+                }
+                *passed = chapters.end;
+                gst::PadProbeReturn::Ok
+            });
+
+        let times = self.times.clone();
+        let done = self.done.clone();
+        let dir = self.dir.clone();
+        self.sink
+            .static_pad("sink")
+            .context("Failed to get sink pad from fakesink")?
+            .add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+                if let Some(buffer) = info.buffer()
+                    && let Some(pts) = buffer.pts()
+                    && let Some(caps) = pad.current_caps()
+                    && let Ok(video) = gst_video::VideoInfo::from_caps(&caps)
+                    && let Ok(frame) =
+                        gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, &video)
+                    && let Ok(pixels) = frame.plane_data(0)
+                {
+                    let mut done = done.lock().unwrap();
+                    let chapters = due(&times, *done, Duration::from_nanos(pts.nseconds()));
+                    let stride = frame.plane_stride()[0] as usize;
+                    let row = frame.width() as usize * 3;
+                    let rgb = (0..frame.height() as usize)
+                        .flat_map(|y| &pixels[y * stride..y * stride + row])
+                        .copied()
+                        .collect();
+                    let Some(still) = RgbImage::from_raw(frame.width(), frame.height(), rgb) else {
+                        return gst::PadProbeReturn::Ok;
+                    };
+                    for i in chapters {
+                        let path = dir.join(filename(i));
+                        if let Err(err) = still.save_with_format(&path, ImageFormat::Jpeg) {
+                            eprintln!("Warning: failed to write {}: {}", path.display(), err);
+                            return gst::PadProbeReturn::Ok;
+                        }
+                        *done = i + 1;
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_a_frame_into_each_chapter() {
+        let times = sample_times(&[0.0, 300.0, 302.0, 900.0]);
+        assert_eq!(
+            times,
+            [3.0, 301.0, 305.0, 903.0].map(Duration::from_secs_f64)
+        );
+        assert!(due(&times, 0, Duration::from_secs(2)).is_empty());
+        assert_eq!(due(&times, 0, Duration::from_secs(3)), 0..1);
+        // A frame late enough stands in for every chapter it passed
+        assert_eq!(due(&times, 1, Duration::from_secs(310)), 1..3);
+        assert!(due(&times, 3, Duration::from_secs(310)).is_empty());
+    }
+}
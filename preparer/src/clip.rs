@@ -0,0 +1,404 @@
+//! `preparer clip`: cut a shareable MP4 out of a prepared title.
+//!
+//! Segments that fall wholly inside the clip are copied as they were
+//! encoded. Only the segments the clip starts and ends in are decoded,
+//! trimmed and encoded again, so the cut lands on the exact frame without
+//! re-encoding the whole clip, and without needing the source.
+
+use crate::library::MANIFEST;
+use crate::mpd::{self, Segment, SegmentList};
+use crate::seekindex::SeekIndex;
+use anyhow::{Context, Result, anyhow, bail};
+use movieshare_core::gst;
+use movieshare_core::gst::prelude::*;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Slack when comparing times from the manifest, in seconds.
+const EPSILON: f64 = 0.001;
+/// SVT-AV1 preset for the re-encoded boundary segments.
+const ENCODER_PRESET: u32 = 8;
+
+/// Part of the clip's video, in playback order.
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    /// These segments, copied as they are
+    Copy(Range<usize>),
+    /// This segment, decoded, trimmed to the clip and encoded again
+    Reencode(usize),
+}
+
+fn segment_end(segment: &Segment) -> f64 {
+    segment.start_secs + segment.duration_secs
+}
+
+/// Indices of the first and last segments holding part of `start..end`.
+pub(crate) fn covering(segments: &[Segment], start: f64, end: f64) -> Result<(usize, usize)> {
+    if end <= start {
+        bail!("The clip must end after it starts");
+    }
+    let first = segments
+        .iter()
+        .position(|segment| start < segment_end(segment) - EPSILON)
+        .context("The clip starts after the end of the title")?;
+    let last = segments
+        .iter()
+        .position(|segment| end <= segment_end(segment) + EPSILON)
+        .context("The clip ends after the end of the title")?;
+    Ok((first, last))
+}
+
+/// Which video segments to copy and which to re-encode for `start..end`.
+fn plan(segments: &[Segment], start: f64, end: f64) -> Result<Vec<Piece>> {
+    let (first, last) = covering(segments, start, end)?;
+    let cut_head = start > segments[first].start_secs + EPSILON;
+    let cut_tail = end < segment_end(&segments[last]) - EPSILON;
+    if first == last && (cut_head || cut_tail) {
+        return Ok(vec![Piece::Reencode(first)]);
+    }
+
+    let mut pieces = Vec::new();
+    let mut copy = first..last + 1;
+    if cut_head {
+        pieces.push(Piece::Reencode(first));
+        copy.start += 1;
+    }
+    if cut_tail {
+        copy.end -= 1;
+    }
+    if !copy.is_empty() {
+        pieces.push(Piece::Copy(copy));
+    }
+    if cut_tail {
+        pieces.push(Piece::Reencode(last));
+    }
+    Ok(pieces)
+}
+
+/// Write the initialization segment and then `range` of the media segments
+/// to `path`, giving a file qtdemux can read on its own.
+pub(crate) fn join(dir: &Path, list: &SegmentList, range: Range<usize>, path: &Path) -> Result<()> {
+    let mut file =
+        std::fs::File::create(path).context(format!("Failed to create {}", path.display()))?;
+    let media = list.segments[range].iter().map(|segment| &segment.media);
+    for name in list.initialization.iter().chain(media) {
+        let mut segment = std::fs::File::open(dir.join(name))
+            .context(format!("Failed to open segment {}", name))?;
+        std::io::copy(&mut segment, &mut file)?;
+    }
+    Ok(())
+}
+
+/// Times of the clip within the title.
+#[derive(Debug, Clone, Copy)]
+struct Clip {
+    start: gst::ClockTime,
+    end: gst::ClockTime,
+}
+
+impl Clip {
+    fn contains(&self, pts: gst::ClockTime) -> bool {
+        pts >= self.start && pts < self.end
+    }
+}
+
+/// Drop buffers outside the clip before they reach the encoder, so the
+/// re-encoded piece starts on a keyframe at the first frame of the clip.
+fn add_trim_probe(pad: &gst::Pad, clip: Clip) {
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        match info.buffer().and_then(|buffer| buffer.pts()) {
+            Some(pts) if !clip.contains(pts) => gst::PadProbeReturn::Drop,
+            _ => gst::PadProbeReturn::Ok,
+        }
+    });
+}
+
+/// Move the stream on `pad` to start at zero: buffers outside the clip are
+/// dropped, the rest shifted, and only the first segment and caps events
+/// are let through so the muxer sees the pieces as one stream.
+fn add_retime_probe(pad: &gst::Pad, clip: Clip) {
+    let segment_sent = AtomicBool::new(false);
+    let caps_sent = AtomicBool::new(false);
+    pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+        move |_, info| match &mut info.data {
+            Some(gst::PadProbeData::Buffer(buffer)) => {
+                let Some(pts) = buffer.pts() else {
+                    return gst::PadProbeReturn::Ok;
+                };
+                if !clip.contains(pts) {
+                    return gst::PadProbeReturn::Drop;
+                }
+                let buffer = buffer.make_mut();
+                buffer.set_pts(pts - clip.start);
+                if let Some(dts) = buffer.dts() {
+                    buffer.set_dts(dts.saturating_sub(clip.start));
+                }
+                gst::PadProbeReturn::Ok
+            }
+            Some(gst::PadProbeData::Event(event)) => match event.type_() {
+                gst::EventType::Segment => {
+                    if segment_sent.swap(true, Ordering::Relaxed) {
+                        return gst::PadProbeReturn::Drop;
+                    }
+                    *event =
+                        gst::event::Segment::new(&gst::FormattedSegment::<gst::ClockTime>::new());
+                    gst::PadProbeReturn::Ok
+                }
+                gst::EventType::Caps if caps_sent.swap(true, Ordering::Relaxed) => {
+                    gst::PadProbeReturn::Drop
+                }
+                _ => gst::PadProbeReturn::Ok,
+            },
+            _ => gst::PadProbeReturn::Ok,
+        },
+    );
+}
+
+/// Link the first `media` pad `element` adds to `sink`.
+pub(crate) fn link_when_added(element: &gst::Element, media: &'static str, sink: &gst::Pad) {
+    let sink_weak = sink.downgrade();
+    element.connect_pad_added(move |_, src_pad| {
+        let Some(sink) = sink_weak.upgrade() else {
+            return;
+        };
+        let is_media = src_pad
+            .current_caps()
+            .and_then(|caps| caps.structure(0).map(|s| s.name().starts_with(media)))
+            .unwrap_or(false);
+        if is_media && !sink.is_linked() {
+            src_pad
+                .link(&sink)
+                .expect("Failed to link demuxed stream into the clip");
+        }
+    });
+}
+
+/// Cut `start..end` seconds out of the title prepared in `dir` into an MP4
+/// at `output`, using its best video representation. Intermediate files
+/// go under `scratch`.
+pub fn extract(dir: &Path, start: f64, end: f64, output: &Path, scratch: &Path) -> Result<()> {
+    let xml = std::fs::read_to_string(dir.join(MANIFEST))
+        .context(format!("No prepared title in {}", dir.display()))?;
+    let manifest = mpd::parse(&xml)?;
+    let video = manifest
+        .representations
+        .iter()
+        .filter(|representation| representation.content_type == "video")
+        .max_by_key(|representation| representation.bandwidth)
+        .context("The title has no video")?;
+    let audio = manifest
+        .representations
+        .iter()
+        .find(|representation| representation.content_type == "audio");
+
+    // The seek index saves working the segments out again, where there is one
+    let index = SeekIndex::read(dir)?;
+    let segment_list = |id: &str| match index.as_ref().and_then(|index| index.representation(id)) {
+        Some(representation) => Ok(representation.segment_list()),
+        None => mpd::segment_list(&xml, id),
+    };
+    let video_list = segment_list(&video.id)?;
+    let pieces = plan(&video_list.segments, start, end)?;
+    let audio_list = match audio {
+        Some(audio) => Some(segment_list(&audio.id)?),
+        None => None,
+    };
+
+    let scratch = scratch.join(format!("movieshare-clip-{}", std::process::id()));
+    std::fs::create_dir_all(&scratch)?;
+    let clip = Clip {
+        start: gst::ClockTime::from_nseconds((start * 1e9) as u64),
+        end: gst::ClockTime::from_nseconds((end * 1e9) as u64),
+    };
+    let result = (|| {
+        let mut video_files = Vec::new();
+        for (i, piece) in pieces.iter().enumerate() {
+            let range = match piece {
+                Piece::Copy(range) => range.clone(),
+                Piece::Reencode(index) => *index..index + 1,
+            };
+            let path = scratch.join(format!("video-{}.mp4", i));
+            join(dir, &video_list, range, &path)?;
+            video_files.push(path);
+        }
+        let audio_file = match &audio_list {
+            Some(list) => {
+                let (first, last) = covering(&list.segments, start, end)?;
+                let path = scratch.join("audio.mp4");
+                join(dir, list, first..last + 1, &path)?;
+                Some(path)
+            }
+            None => None,
+        };
+        let bitrate_kbps = (video.bandwidth / 1000) as u32;
+        encode(
+            &pieces,
+            &video_files,
+            audio_file.as_deref(),
+            bitrate_kbps,
+            clip,
+            output,
+        )
+    })();
+    let _ = std::fs::remove_dir_all(&scratch);
+    result
+}
+
+/// Run the pipeline muxing the pieces into `output`.
+fn encode(
+    pieces: &[Piece],
+    video_files: &[std::path::PathBuf],
+    audio_file: Option<&Path>,
+    bitrate_kbps: u32,
+    clip: Clip,
+    output: &Path,
+) -> Result<()> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let concat = gst::ElementFactory::make("concat").build()?;
+    let video_queue = gst::ElementFactory::make("queue").build()?;
+    let mux = gst::ElementFactory::make("mp4mux")
+        .property("faststart", true)
+        .build()?;
+    let filesink = gst::ElementFactory::make("filesink")
+        .property("location", &*output.to_string_lossy())
+        .build()?;
+    pipeline.add_many([&concat, &video_queue, &mux, &filesink])?;
+    gst::Element::link_many([&concat, &video_queue, &mux, &filesink])?;
+    add_retime_probe(
+        &concat
+            .static_pad("src")
+            .context("Failed to get src pad from concat")?,
+        clip,
+    );
+
+    for (piece, path) in pieces.iter().zip(video_files) {
+        let filesrc = gst::ElementFactory::make("filesrc")
+            .property("location", &*path.to_string_lossy())
+            .build()?;
+        // Request in order: concat plays its inputs in the order they were added
+        let concat_pad = concat
+            .request_pad_simple("sink_%u")
+            .context("Failed to get a sink pad from concat")?;
+        match piece {
+            Piece::Copy(_) => {
+                let demux = gst::ElementFactory::make("qtdemux").build()?;
+                let queue = gst::ElementFactory::make("queue").build()?;
+                pipeline.add_many([&filesrc, &demux, &queue])?;
+                filesrc.link(&demux)?;
+                queue
+                    .static_pad("src")
+                    .context("Failed to get src pad from queue")?
+                    .link(&concat_pad)?;
+                link_when_added(
+                    &demux,
+                    "video/",
+                    &queue
+                        .static_pad("sink")
+                        .context("Failed to get sink pad from queue")?,
+                );
+            }
+            Piece::Reencode(_) => {
+                let decodebin = gst::ElementFactory::make("decodebin").build()?;
+                let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+                let encoder = gst::ElementFactory::make("svtav1enc")
+                    .property("preset", ENCODER_PRESET)
+                    .property("target-bitrate", bitrate_kbps)
+                    .build()?;
+                let parser = gst::ElementFactory::make("av1parse").build()?;
+                pipeline.add_many([&filesrc, &decodebin, &videoconvert, &encoder, &parser])?;
+                filesrc.link(&decodebin)?;
+                gst::Element::link_many([&videoconvert, &encoder, &parser])?;
+                parser
+                    .static_pad("src")
+                    .context("Failed to get src pad from av1parse")?
+                    .link(&concat_pad)?;
+                let convert_sink = videoconvert
+                    .static_pad("sink")
+                    .context("Failed to get sink pad from videoconvert")?;
+                add_trim_probe(&convert_sink, clip);
+                link_when_added(&decodebin, "video/", &convert_sink);
+            }
+        }
+    }
+
+    if let Some(path) = audio_file {
+        let filesrc = gst::ElementFactory::make("filesrc")
+            .property("location", &*path.to_string_lossy())
+            .build()?;
+        let demux = gst::ElementFactory::make("qtdemux").build()?;
+        let queue = gst::ElementFactory::make("queue").build()?;
+        pipeline.add_many([&filesrc, &demux, &queue])?;
+        filesrc.link(&demux)?;
+        queue.link(&mux)?;
+        add_retime_probe(
+            &queue
+                .static_pad("src")
+                .context("Failed to get src pad from queue")?,
+            clip,
+        );
+        link_when_added(
+            &demux,
+            "audio/",
+            &queue
+                .static_pad("sink")
+                .context("Failed to get sink pad from queue")?,
+        );
+    }
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline.bus().unwrap();
+    let result = loop {
+        use gst::MessageView;
+
+        let Some(message) = bus.timed_pop(gst::ClockTime::NONE) else {
+            break Ok(());
+        };
+        match message.view() {
+            MessageView::Eos(..) => break Ok(()),
+            MessageView::Error(err) => {
+                break Err(anyhow!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ));
+            }
+            _ => (),
+        }
+    };
+    pipeline.set_state(gst::State::Null)?;
+    result.context(format!("Failed to write {}", output.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reencodes_only_the_boundary_segments() {
+        let segments: Vec<Segment> = (0..5)
+            .map(|i| Segment {
+                media: format!("video_{}.m4s", i),
+                start_secs: i as f64 * 4.0,
+                duration_secs: 4.0,
+            })
+            .collect();
+
+        assert_eq!(
+            plan(&segments, 5.0, 15.0).unwrap(),
+            [Piece::Reencode(1), Piece::Copy(2..3), Piece::Reencode(3)]
+        );
+        assert_eq!(plan(&segments, 4.0, 12.0).unwrap(), [Piece::Copy(1..3)]);
+        assert_eq!(plan(&segments, 5.0, 8.0).unwrap(), [Piece::Reencode(1)]);
+        assert_eq!(plan(&segments, 5.0, 6.0).unwrap(), [Piece::Reencode(1)]);
+        assert_eq!(
+            plan(&segments, 4.0, 9.0).unwrap(),
+            [Piece::Copy(1..2), Piece::Reencode(2)]
+        );
+        assert!(plan(&segments, 10.0, 25.0).is_err());
+        assert!(plan(&segments, 10.0, 10.0).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Named groups of titles, like "Kids" or "Film club", kept in the library
+//! catalog.
+//!
+//! A collection is granted to users as a whole, so titles added to it later
+//! are theirs to watch too, and can be shared as a whole with one link.
+
+use crate::library::Catalog;
+use anyhow::{Context, Result, bail};
+use rusqlite::{OptionalExtension, params};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Collection {
+    pub name: String,
+    /// Names of the titles' directories in the library, sorted
+    pub titles: Vec<String>,
+}
+
+impl Catalog {
+    pub fn create_collection(&mut self, name: &str) -> Result<()> {
+        self.conn
+            .execute("INSERT INTO collections (name) VALUES (?1)", params![name])
+            .context(format!("Failed to create collection {}", name))?;
+        Ok(())
+    }
+
+    /// Delete a collection, taking back any grants of it.
+    pub fn delete_collection(&mut self, name: &str) -> Result<bool> {
+        let tx = self.conn.transaction()?;
+        let removed = tx.execute("DELETE FROM collections WHERE name = ?1", params![name])?;
+        tx.execute(
+            "DELETE FROM grants WHERE kind = 'collection' AND value = ?1",
+            params![name],
+        )?;
+        tx.commit()?;
+        Ok(removed > 0)
+    }
+
+    fn collection_id(&self, name: &str) -> Result<i64> {
+        self.conn
+            .query_row(
+                "SELECT id FROM collections WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?
+            .context(format!("No such collection: {}", name))
+    }
+
+    pub fn add_to_collection(&mut self, name: &str, titles: &[String]) -> Result<()> {
+        let id = self.collection_id(name)?;
+        let tx = self.conn.transaction()?;
+        for title in titles {
+            tx.execute(
+                "INSERT OR IGNORE INTO collection_titles (collection_id, title) VALUES (?1, ?2)",
+                params![id, title],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_from_collection(&mut self, name: &str, titles: &[String]) -> Result<()> {
+        let id = self.collection_id(name)?;
+        let tx = self.conn.transaction()?;
+        for title in titles {
+            let removed = tx.execute(
+                "DELETE FROM collection_titles WHERE collection_id = ?1 AND title = ?2",
+                params![id, title],
+            )?;
+            if removed == 0 {
+                bail!("{} isn't in {}", title, name);
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn collection(&self, name: &str) -> Result<Option<Collection>> {
+        let Ok(id) = self.collection_id(name) else {
+            return Ok(None);
+        };
+        let mut statement = self.conn.prepare(
+            "SELECT title FROM collection_titles WHERE collection_id = ?1 ORDER BY title",
+        )?;
+        let titles = statement
+            .query_map(params![id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(Some(Collection {
+            name: name.to_string(),
+            titles,
+        }))
+    }
+
+    pub fn collections(&self) -> Result<Vec<Collection>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT name FROM collections ORDER BY name")?;
+        let names = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        names
+            .iter()
+            .filter_map(|name| self.collection(name).transpose())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::GrantKind;
+
+    #[test]
+    fn grants_follow_the_collection() {
+        let mut catalog = Catalog::open(std::path::Path::new(":memory:")).unwrap();
+        catalog.create_collection("Kids").unwrap();
+        assert!(catalog.create_collection("Kids").is_err());
+        catalog
+            .add_to_collection("Kids", &[String::from("Cartoon"), String::from("Another")])
+            .unwrap();
+        assert!(catalog.add_to_collection("Nothing", &[]).is_err());
+        catalog.add_user("kid", None, true).unwrap();
+        catalog.grant("kid", GrantKind::Collection, "Kids").unwrap();
+
+        let kid = catalog.user("kid").unwrap().unwrap();
+        assert_eq!(kid.collections, ["Kids"]);
+        assert!(kid.can_watch("Cartoon", &[], None));
+        assert!(!kid.can_watch("Film", &[], None));
+
+        // Titles come and go with the collection
+        catalog
+            .remove_from_collection("Kids", &[String::from("Cartoon")])
+            .unwrap();
+        assert!(
+            !catalog
+                .user("kid")
+                .unwrap()
+                .unwrap()
+                .can_watch("Cartoon", &[], None)
+        );
+        assert_eq!(
+            catalog.collection("Kids").unwrap().unwrap().titles,
+            ["Another"]
+        );
+
+        assert!(catalog.delete_collection("Kids").unwrap());
+        let kid = catalog.user("kid").unwrap().unwrap();
+        assert!(kid.collections.is_empty());
+        assert!(!kid.can_watch("Another", &[], None));
+        assert!(catalog.collections().unwrap().is_empty());
+    }
+}
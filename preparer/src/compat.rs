@@ -0,0 +1,399 @@
+//! `preparer checkcompat`: which players a prepared title will play on.
+//!
+//! Each representation's codecs, and any DRM the manifest signals, are
+//! checked against what browsers and TV platforms are known to decode. A
+//! player adapts among the representations it can play, so a target plays
+//! the title when at least one video and one audio representation suit it.
+//! The matrix is built in and errs on the side of caution: where support
+//! depends on the model or release, that's what it says.
+
+use crate::mpd::{self, Representation};
+use anyhow::{Context, Result, bail};
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use std::path::Path;
+
+/// Whether a target can play something.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    Yes,
+    No,
+    /// Only on some models or releases, as described
+    Depends(&'static str),
+}
+
+impl std::fmt::Display for Support {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Support::Yes => write!(f, "yes"),
+            Support::No => write!(f, "no"),
+            Support::Depends(when) => write!(f, "only {}", when),
+        }
+    }
+}
+
+/// Codecs told apart by the matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Av1,
+    H264,
+    Hevc,
+    Vp9,
+    Opus,
+    Aac,
+    Ac3,
+    Eac3,
+    Flac,
+    /// Subtitles and thumbnails, which players skip if they can't show them
+    Other,
+}
+
+/// The codec of an RFC 6381 `codecs` string, if it's one the matrix knows.
+fn codec(codecs: &str) -> Option<Codec> {
+    let name = codecs.split('.').next()?;
+    Some(match name {
+        "av01" => Codec::Av1,
+        "avc1" | "avc3" => Codec::H264,
+        "hev1" | "hvc1" => Codec::Hevc,
+        "vp09" => Codec::Vp9,
+        "opus" | "Opus" => Codec::Opus,
+        "mp4a" => Codec::Aac,
+        "ac-3" => Codec::Ac3,
+        "ec-3" => Codec::Eac3,
+        "fLaC" | "flac" => Codec::Flac,
+        "wvtt" | "stpp" | "jpeg" => Codec::Other,
+        _ => return None,
+    })
+}
+
+const WIDEVINE: &str = "urn:uuid:edef8ba9-79d6-4ace-a3c8-27dcd51d21ed";
+const PLAYREADY: &str = "urn:uuid:9a04f079-9840-4286-ab92-e65be0885f95";
+const FAIRPLAY: &str = "urn:uuid:94ce86fb-07ff-4f43-adb8-93d2fa968ca2";
+const CLEARKEY: &str = "urn:uuid:e2719d58-a985-b3c9-781a-b030af78d30e";
+
+/// A player to check for.
+#[derive(Debug)]
+pub struct Target {
+    pub name: &'static str,
+    pub description: &'static str,
+    codecs: &'static [(Codec, Support)],
+    /// DRM systems it has, by `ContentProtection` scheme
+    key_systems: &'static [&'static str],
+}
+
+const AV1_SAFARI: Support = Support::Depends("on Apple M3, A17 Pro or later");
+const TV_2020: Support = Support::Depends("on 2020 models or later");
+
+pub const TARGETS: &[Target] = &[
+    Target {
+        name: "chrome",
+        description: "Chrome and other Chromium browsers",
+        codecs: &[
+            (Codec::Av1, Support::Yes),
+            (Codec::H264, Support::Yes),
+            (Codec::Hevc, Support::Depends("with hardware decoding")),
+            (Codec::Vp9, Support::Yes),
+            (Codec::Opus, Support::Yes),
+            (Codec::Aac, Support::Yes),
+            (Codec::Flac, Support::Yes),
+        ],
+        key_systems: &[WIDEVINE, CLEARKEY],
+    },
+    Target {
+        name: "firefox",
+        description: "Firefox",
+        codecs: &[
+            (Codec::Av1, Support::Yes),
+            (Codec::H264, Support::Yes),
+            (Codec::Vp9, Support::Yes),
+            (Codec::Opus, Support::Yes),
+            (Codec::Aac, Support::Yes),
+            (Codec::Flac, Support::Yes),
+        ],
+        key_systems: &[WIDEVINE, CLEARKEY],
+    },
+    Target {
+        name: "edge",
+        description: "Edge on Windows",
+        codecs: &[
+            (Codec::Av1, Support::Depends("with the AV1 Video Extension")),
+            (Codec::H264, Support::Yes),
+            (
+                Codec::Hevc,
+                Support::Depends("with the HEVC Video Extension"),
+            ),
+            (Codec::Vp9, Support::Yes),
+            (Codec::Opus, Support::Yes),
+            (Codec::Aac, Support::Yes),
+            (Codec::Ac3, Support::Yes),
+            (Codec::Eac3, Support::Yes),
+            (Codec::Flac, Support::Yes),
+        ],
+        key_systems: &[WIDEVINE, PLAYREADY, CLEARKEY],
+    },
+    Target {
+        name: "safari",
+        description: "Safari on macOS, iOS and iPadOS",
+        codecs: &[
+            (Codec::Av1, AV1_SAFARI),
+            (Codec::H264, Support::Yes),
+            (Codec::Hevc, Support::Yes),
+            (Codec::Vp9, Support::Depends("on macOS")),
+            (Codec::Opus, Support::Depends("from Safari 17")),
+            (Codec::Aac, Support::Yes),
+            (Codec::Ac3, Support::Yes),
+            (Codec::Eac3, Support::Yes),
+            (Codec::Flac, Support::Yes),
+        ],
+        key_systems: &[FAIRPLAY],
+    },
+    Target {
+        name: "shield",
+        description: "NVIDIA Shield TV and other Android TV boxes",
+        codecs: &[
+            (
+                Codec::Av1,
+                Support::Depends("on boxes with AV1 decoding, not the Shield"),
+            ),
+            (Codec::H264, Support::Yes),
+            (Codec::Hevc, Support::Yes),
+            (Codec::Vp9, Support::Yes),
+            (Codec::Opus, Support::Yes),
+            (Codec::Aac, Support::Yes),
+            (Codec::Ac3, Support::Yes),
+            (Codec::Eac3, Support::Yes),
+            (Codec::Flac, Support::Yes),
+        ],
+        key_systems: &[WIDEVINE, PLAYREADY, CLEARKEY],
+    },
+    Target {
+        name: "chromecast",
+        description: "Chromecast and Google TV",
+        codecs: &[
+            (Codec::Av1, Support::Depends("on the Google TV Streamer")),
+            (Codec::H264, Support::Yes),
+            (
+                Codec::Hevc,
+                Support::Depends("on Chromecast with Google TV"),
+            ),
+            (Codec::Vp9, Support::Yes),
+            (Codec::Opus, Support::Yes),
+            (Codec::Aac, Support::Yes),
+            (
+                Codec::Eac3,
+                Support::Depends("passed through to a receiver"),
+            ),
+            (Codec::Flac, Support::Yes),
+        ],
+        key_systems: &[WIDEVINE, PLAYREADY, CLEARKEY],
+    },
+    Target {
+        name: "lg-webos",
+        description: "LG TVs' webOS browser",
+        codecs: &[
+            (Codec::Av1, TV_2020),
+            (Codec::H264, Support::Yes),
+            (Codec::Hevc, Support::Yes),
+            (Codec::Vp9, Support::Yes),
+            (Codec::Opus, TV_2020),
+            (Codec::Aac, Support::Yes),
+            (Codec::Ac3, Support::Yes),
+            (Codec::Eac3, Support::Yes),
+        ],
+        key_systems: &[WIDEVINE, PLAYREADY],
+    },
+    Target {
+        name: "tizen",
+        description: "Samsung TVs' Tizen browser",
+        codecs: &[
+            (Codec::Av1, TV_2020),
+            (Codec::H264, Support::Yes),
+            (Codec::Hevc, Support::Yes),
+            (Codec::Vp9, Support::Yes),
+            (Codec::Opus, TV_2020),
+            (Codec::Aac, Support::Yes),
+            (Codec::Ac3, Support::Yes),
+            (Codec::Eac3, Support::Yes),
+        ],
+        key_systems: &[WIDEVINE, PLAYREADY],
+    },
+];
+
+/// The target named `name`.
+pub fn target(name: &str) -> Result<&'static Target> {
+    match TARGETS.iter().find(|target| target.name == name) {
+        Some(target) => Ok(target),
+        None => {
+            let names: Vec<&str> = TARGETS.iter().map(|target| target.name).collect();
+            bail!("Unknown target {:?}; expected {}", name, names.join(", "))
+        }
+    }
+}
+
+impl Target {
+    /// Whether this plays `representation`.
+    pub fn plays(&self, representation: &Representation) -> Support {
+        let Some(codec) = representation.codecs.as_deref().and_then(codec) else {
+            return Support::Depends("if it knows the codec");
+        };
+        if codec == Codec::Other {
+            return Support::Yes;
+        }
+        self.codecs
+            .iter()
+            .find(|(known, _)| *known == codec)
+            .map_or(Support::No, |(_, support)| *support)
+    }
+
+    /// Whether this has one of the DRM systems `schemes` signals, if any.
+    fn decrypts(&self, schemes: &[String]) -> bool {
+        let systems: Vec<&String> = schemes
+            .iter()
+            .filter(|scheme| scheme.starts_with("urn:uuid:"))
+            .collect();
+        systems.is_empty()
+            || systems
+                .iter()
+                .any(|scheme| self.key_systems.contains(&scheme.to_lowercase().as_str()))
+    }
+}
+
+/// How a target fares with a whole title.
+#[derive(Debug)]
+pub struct Verdict {
+    pub target: &'static Target,
+    pub overall: Support,
+    /// Each representation's id, codecs and support
+    pub representations: Vec<(String, String, Support)>,
+    /// Whether it's stopped by DRM it lacks
+    pub missing_drm: bool,
+}
+
+/// The `schemeIdUri` of each `ContentProtection` in the manifest `xml`.
+fn protection_schemes(xml: &str) -> Result<Vec<String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut schemes = Vec::new();
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == "ContentProtection" =>
+            {
+                if let Some(scheme) = element.try_get_attribute("schemeIdUri")? {
+                    schemes.push(scheme.value.into_owned());
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    Ok(schemes)
+}
+
+/// The best support among `supports` for one kind of stream, or `Yes` if
+/// there's none of that kind.
+fn best(supports: impl Iterator<Item = Support>) -> Support {
+    let supports: Vec<Support> = supports.collect();
+    if supports.is_empty() || supports.contains(&Support::Yes) {
+        return Support::Yes;
+    }
+    supports
+        .into_iter()
+        .find(|support| *support != Support::No)
+        .unwrap_or(Support::No)
+}
+
+/// How each of `targets` fares with the manifest `xml`.
+pub fn check(xml: &str, targets: &[&'static Target]) -> Result<Vec<Verdict>> {
+    let manifest = mpd::parse(xml)?;
+    let schemes = protection_schemes(xml)?;
+    let mut verdicts = Vec::new();
+    for &target in targets {
+        let representations: Vec<(String, String, Support)> = manifest
+            .representations
+            .iter()
+            .map(|representation| {
+                (
+                    format!("{} {}", representation.content_type, representation.id),
+                    representation.codecs.clone().unwrap_or_default(),
+                    target.plays(representation),
+                )
+            })
+            .collect();
+        let kind = |wanted: &str| {
+            best(
+                manifest
+                    .representations
+                    .iter()
+                    .filter(|representation| representation.content_type == wanted)
+                    .map(|representation| target.plays(representation)),
+            )
+        };
+        let missing_drm = !target.decrypts(&schemes);
+        let overall = match (kind("video"), kind("audio")) {
+            _ if missing_drm => Support::No,
+            (Support::No, _) | (_, Support::No) => Support::No,
+            (Support::Depends(when), _) | (_, Support::Depends(when)) => Support::Depends(when),
+            (Support::Yes, Support::Yes) => Support::Yes,
+        };
+        verdicts.push(Verdict {
+            target,
+            overall,
+            representations,
+            missing_drm,
+        });
+    }
+    Ok(verdicts)
+}
+
+/// Check the title prepared into `dir` against `targets`.
+pub fn check_dir(dir: &Path, targets: &[&'static Target]) -> Result<Vec<Verdict>> {
+    let path = dir.join(crate::library::MANIFEST);
+    let xml =
+        std::fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+    check(&xml, targets).context(format!("Failed to check {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"<MPD><Period>
+        <AdaptationSet contentType="video" mimeType="video/mp4">
+            <Representation id="0" codecs="av01.0.08M.08" bandwidth="4000000"/>
+            <Representation id="1" codecs="avc1.640028" bandwidth="2000000"/>
+        </AdaptationSet>
+        <AdaptationSet contentType="audio" mimeType="audio/mp4">
+            <Representation id="2" codecs="opus" bandwidth="128000"/>
+        </AdaptationSet>
+    </Period></MPD>"#;
+
+    #[test]
+    fn judges_each_target() {
+        let targets = [target("chrome").unwrap(), target("safari").unwrap()];
+        let verdicts = check(MANIFEST, &targets).unwrap();
+        assert_eq!(verdicts[0].overall, Support::Yes);
+        // H.264 carries the video, but the Opus audio needs a recent Safari
+        assert_eq!(verdicts[1].overall, Support::Depends("from Safari 17"));
+        assert_eq!(verdicts[1].representations[0].2, AV1_SAFARI);
+        assert_eq!(verdicts[1].representations[1].2, Support::Yes);
+
+        // Without H.264, the Shield has nothing to show
+        let av1_only = MANIFEST.replace("avc1.640028", "av01.0.04M.08");
+        let verdicts = check(&av1_only, &[target("shield").unwrap()]).unwrap();
+        assert!(matches!(verdicts[0].overall, Support::Depends(_)));
+
+        let protected = MANIFEST.replace(
+            "<AdaptationSet contentType=\"video\" mimeType=\"video/mp4\">",
+            &format!(
+                "<AdaptationSet contentType=\"video\" mimeType=\"video/mp4\"><ContentProtection schemeIdUri=\"{}\"/>",
+                WIDEVINE
+            ),
+        );
+        let verdicts = check(&protected, &targets).unwrap();
+        assert_eq!(verdicts[0].overall, Support::Yes);
+        assert!(verdicts[1].missing_drm);
+        assert_eq!(verdicts[1].overall, Support::No);
+
+        assert!(target("roku").is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! Local control socket for a long-running `preparer daemon`.
+//!
+//! Clients send one JSON request per line over a Unix socket and read one
+//! JSON response line back, so jobs keep running after the client exits.
+
+use crate::rest::JobView;
+use anyhow::{Context, Result, bail};
+use movieshare_core::JobSpec;
+use movieshare_core::queue::{JobId, JobQueue};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    Submit {
+        spec: Box<JobSpec>,
+        priority: i32,
+    },
+    /// One job, or every job when `id` is unset
+    Status {
+        id: Option<JobId>,
+    },
+    Cancel {
+        id: JobId,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+pub enum Response {
+    Submitted { id: JobId },
+    Jobs { jobs: Vec<JobView> },
+    Cancelled,
+    Error { message: String },
+}
+
+/// `$XDG_RUNTIME_DIR/movieshare.sock`, or a per-user path in the temp directory.
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("movieshare.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_else(|_| String::from("default"));
+            std::env::temp_dir().join(format!("movieshare-{}.sock", user))
+        }
+    }
+}
+
+fn respond(queue: &JobQueue, request: Request) -> Result<Response> {
+    Ok(match request {
+        Request::Submit { spec, priority } => Response::Submitted {
+            id: queue.submit(*spec, priority)?,
+        },
+        Request::Status { id: Some(id) } => Response::Jobs {
+            jobs: vec![
+                queue
+                    .get(id)
+                    .context(format!("No such job: {}", id))?
+                    .into(),
+            ],
+        },
+        Request::Status { id: None } => Response::Jobs {
+            jobs: queue.jobs().into_iter().map(JobView::from).collect(),
+        },
+        Request::Cancel { id } => {
+            queue.cancel(id)?;
+            Response::Cancelled
+        }
+    })
+}
+
+async fn handle(queue: JobQueue, stream: UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = serde_json::from_str(&line)
+            .context("Invalid request")
+            .and_then(|request| respond(&queue, request))
+            .unwrap_or_else(|err| Response::Error {
+                message: format!("{:#}", err),
+            });
+
+        let mut json = serde_json::to_string(&response)?;
+        json.push('\n');
+        writer.write_all(json.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Listen for control connections on `path`.
+pub async fn listen(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        // A socket nobody answers on is left over from a daemon that died
+        if UnixStream::connect(path).await.is_ok() {
+            bail!("A daemon is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path)
+            .context(format!("Failed to remove stale socket: {}", path.display()))?;
+    }
+
+    let listener =
+        UnixListener::bind(path).context(format!("Failed to listen on {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accept control connections until the process is stopped.
+pub async fn serve(queue: JobQueue, listener: UnixListener) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(queue, stream).await {
+                eprintln!("Control connection failed: {:#}", err);
+            }
+        });
+    }
+}
+
+/// Send one request to the daemon listening on `path` and wait for its answer.
+pub fn request(path: &Path, request: &Request) -> Result<Response> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path).context(format!(
+        "Failed to connect to {}; is `preparer daemon` running?",
+        path.display()
+    ))?;
+    let mut json = serde_json::to_string(request)?;
+    json.push('\n');
+    stream.write_all(json.as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    match serde_json::from_str(&line).context("Invalid response from the daemon")? {
+        Response::Error { message } => bail!(message),
+        response => Ok(response),
+    }
+}
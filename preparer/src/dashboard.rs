@@ -0,0 +1,271 @@
+//! What the daemon shows of its jobs as they run.
+//!
+//! On a terminal it keeps a dashboard under its other output: a line of
+//! totals and a progress bar for each running job, redrawn in place. Sent
+//! anywhere else, like a log file or the journal, it writes a line when a
+//! job starts, finishes or warns, and at each tenth of a job done.
+
+use crate::progress_line;
+use anyhow::Result;
+use futures::StreamExt;
+use movieshare_core::Progress;
+use movieshare_core::queue::{JobId, JobQueue, JobState, QueueEvent, QueuedJob};
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 24;
+/// Longest input name shown, so lines don't wrap and throw off redrawing
+const NAME_WIDTH: usize = 32;
+/// Least time between redraws for progress alone
+const REDRAW: Duration = Duration::from_millis(250);
+
+struct Row {
+    name: String,
+    state: JobState,
+    progress: Option<Progress>,
+    /// Tenths of the job done, as last logged
+    logged_tenths: u32,
+}
+
+#[derive(Default)]
+struct Dashboard {
+    jobs: BTreeMap<JobId, Row>,
+}
+
+/// The input's file name, shortened to fit the dashboard.
+fn job_name(job: &QueuedJob) -> String {
+    let name = job.spec.input.file_name().map_or_else(
+        || job.spec.input.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    match name.chars().count() > NAME_WIDTH {
+        true => {
+            let short: String = name.chars().take(NAME_WIDTH - 1).collect();
+            format!("{}…", short)
+        }
+        false => name,
+    }
+}
+
+/// A progress bar `BAR_WIDTH` wide, `fraction` of it filled.
+fn bar(fraction: f64) -> String {
+    let filled = (fraction.clamp(0.0, 1.0) * BAR_WIDTH as f64).round() as usize;
+    format!("[{}{}]", "#".repeat(filled), " ".repeat(BAR_WIDTH - filled))
+}
+
+impl Dashboard {
+    fn new(jobs: &[QueuedJob]) -> Self {
+        let mut dashboard = Dashboard::default();
+        for job in jobs {
+            dashboard.add(job);
+        }
+        dashboard
+    }
+
+    fn add(&mut self, job: &QueuedJob) {
+        self.jobs.insert(
+            job.id,
+            Row {
+                name: job_name(job),
+                state: job.state.clone(),
+                progress: job.progress.clone(),
+                logged_tenths: 0,
+            },
+        );
+    }
+
+    /// Take in `event`, returning the line it's worth logging, if any.
+    /// With `milestones`, that includes each tenth of a job done.
+    fn update(&mut self, event: &QueueEvent, milestones: bool) -> Option<String> {
+        match event {
+            QueueEvent::StateChanged { id, state } => {
+                let row = self.jobs.get_mut(id)?;
+                row.state = state.clone();
+                let what = match state {
+                    JobState::Queued => String::from("queued"),
+                    JobState::Running => String::from("started"),
+                    JobState::Completed => String::from("completed"),
+                    JobState::Failed { error } => format!("failed: {}", error),
+                    JobState::Cancelled => String::from("cancelled"),
+                };
+                Some(format!("Job {} {} ({})", id, what, row.name))
+            }
+            QueueEvent::Progress { id, progress } => {
+                let row = self.jobs.get_mut(id)?;
+                row.progress = Some(progress.clone());
+                let tenths = (progress.fraction.clamp(0.0, 1.0) * 10.0) as u32;
+                if !milestones || tenths <= row.logged_tenths {
+                    return None;
+                }
+                row.logged_tenths = tenths;
+                Some(format!(
+                    "Job {} ({}): {}",
+                    id,
+                    row.name,
+                    progress_line(progress)
+                ))
+            }
+            QueueEvent::Warning { id, message } => {
+                Some(format!("Job {}: warning: {}", id, message))
+            }
+        }
+    }
+
+    /// The dashboard's lines: totals, then a bar for each running job.
+    fn render(&self) -> Vec<String> {
+        let count = |wanted: fn(&JobState) -> bool| {
+            self.jobs.values().filter(|row| wanted(&row.state)).count()
+        };
+        let running: Vec<(&JobId, &Row)> = self
+            .jobs
+            .iter()
+            .filter(|(_, row)| row.state == JobState::Running)
+            .collect();
+        let fps: f64 = running
+            .iter()
+            .filter_map(|(_, row)| row.progress.as_ref())
+            .map(|progress| progress.fps)
+            .sum();
+        let mut lines = vec![format!(
+            "{} running at {:.1} fps, {} queued, {} done, {} failed",
+            running.len(),
+            fps,
+            count(|state| *state == JobState::Queued),
+            count(|state| *state == JobState::Completed),
+            count(|state| matches!(state, JobState::Failed { .. })),
+        )];
+        for (id, row) in running {
+            let progress = row.progress.clone().unwrap_or_default();
+            lines.push(format!(
+                "{:>4} {:<width$} {} {}",
+                id,
+                row.name,
+                bar(progress.fraction),
+                progress_line(&progress),
+                width = NAME_WIDTH
+            ));
+        }
+        lines
+    }
+}
+
+/// Replace the `drawn` lines of the dashboard at the bottom of `out` with
+/// `lines`, writing `above` first, where it stays.
+fn redraw(
+    out: &mut impl Write,
+    lines: &[String],
+    drawn: &mut usize,
+    above: Option<&str>,
+) -> Result<()> {
+    if *drawn > 0 {
+        write!(out, "\x1b[{}A", drawn)?;
+    }
+    write!(out, "\r\x1b[J")?;
+    if let Some(above) = above {
+        writeln!(out, "{}", above)?;
+    }
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    out.flush()?;
+    *drawn = lines.len();
+    Ok(())
+}
+
+/// Show the jobs of `queue` as they run, until it shuts down.
+pub async fn run(queue: JobQueue) -> Result<()> {
+    let terminal = std::io::stderr().is_terminal();
+    let mut dashboard = Dashboard::new(&queue.jobs());
+    let mut events = queue.subscribe();
+    let mut drawn = 0;
+    let mut last_drawn = Instant::now();
+    if terminal {
+        redraw(
+            &mut std::io::stderr(),
+            &dashboard.render(),
+            &mut drawn,
+            None,
+        )?;
+    }
+    while let Some(event) = events.next().await {
+        if let QueueEvent::StateChanged { id, .. } = &event
+            && !dashboard.jobs.contains_key(id)
+            && let Some(job) = queue.get(*id)
+        {
+            dashboard.add(&job);
+        }
+        let line = dashboard.update(&event, !terminal);
+        if !terminal {
+            if let Some(line) = line {
+                println!("{}", line);
+            }
+            continue;
+        }
+        if line.is_none() && last_drawn.elapsed() < REDRAW {
+            continue;
+        }
+        redraw(
+            &mut std::io::stderr(),
+            &dashboard.render(),
+            &mut drawn,
+            line.as_deref(),
+        )?;
+        last_drawn = Instant::now();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use movieshare_core::JobSpec;
+    use std::path::PathBuf;
+
+    #[test]
+    fn shows_running_jobs() {
+        let job = |id, state, input: &str| QueuedJob {
+            id,
+            priority: 0,
+            spec: JobSpec::new(PathBuf::from(input), PathBuf::from("out")),
+            state,
+            progress: None,
+        };
+        let mut dashboard = Dashboard::new(&[
+            job(1, JobState::Running, "/media/film.mkv"),
+            job(2, JobState::Queued, &format!("{}.mkv", "x".repeat(40))),
+        ]);
+        let progress = |fraction| QueueEvent::Progress {
+            id: 1,
+            progress: Progress {
+                fraction,
+                fps: 30.0,
+                ..Default::default()
+            },
+        };
+        assert_eq!(dashboard.update(&progress(0.05), true), None);
+        assert!(dashboard.update(&progress(0.25), true).is_some());
+        assert_eq!(dashboard.update(&progress(0.26), true), None);
+        assert_eq!(dashboard.update(&progress(0.5), false), None);
+
+        let lines = dashboard.render();
+        assert_eq!(
+            lines[0],
+            "1 running at 30.0 fps, 1 queued, 0 done, 0 failed"
+        );
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains("film.mkv"));
+        assert!(lines[1].contains(&format!("[{}{}]", "#".repeat(12), " ".repeat(12))));
+        assert_eq!(dashboard.jobs[&2].name.chars().count(), NAME_WIDTH);
+
+        let done = QueueEvent::StateChanged {
+            id: 1,
+            state: JobState::Completed,
+        };
+        assert_eq!(
+            dashboard.update(&done, false).as_deref(),
+            Some("Job 1 completed (film.mkv)")
+        );
+        assert_eq!(dashboard.render().len(), 1);
+    }
+}
@@ -0,0 +1,544 @@
+//! Spotting titles that are the same movie ripped from different sources.
+//!
+//! While preparing, the audio also runs through GStreamer's `chromaprint`
+//! element, and the AcoustID-style fingerprint it computes is kept next to
+//! the manifest. A frame every ten seconds is also shrunk to 9x8 grey
+//! pixels and reduced to a 64-bit difference hash, which survives scaling,
+//! grading and compression. `library scan` copies both into the catalog, and
+//! `preparer dedupe` compares every pair of titles: encodes of the same
+//! movie line up with few differing bits even when their sources were cut,
+//! mixed or compressed differently.
+
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use gstreamer_video as gst_video;
+use gstreamer_video::prelude::*;
+use movieshare_core::gst;
+use movieshare_core::{MediaType, PipelineBranch};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Seconds of audio to fingerprint from the start of each title.
+const FINGERPRINT_SECS: u32 = 180;
+/// Fingerprints must line up over at least this many items (about 8 a second).
+const MIN_OVERLAP: usize = 80;
+/// Time between the frames hashed for the video hash.
+const FRAME_INTERVAL: Duration = Duration::from_secs(10);
+/// Video hashes must line up over at least this many frames.
+const MIN_FRAME_OVERLAP: usize = 12;
+/// Values that don't fit the 3-bit normal section of a compressed fingerprint.
+const MAX_NORMAL_VALUE: u32 = 7;
+
+/// Fingerprints the decoded audio alongside the encoding branches.
+///
+/// Clones share the same elements, so a clone kept by the caller can read
+/// the fingerprint once the preparation has finished.
+#[derive(Clone)]
+pub struct FingerprintBranch {
+    queue: gst::Element,
+    audioconvert: gst::Element,
+    audioresample: gst::Element,
+    chromaprint: gst::Element,
+    sink: gst::Element,
+}
+
+impl FingerprintBranch {
+    /// Whether the `chromaprint` element is installed.
+    pub fn available() -> Result<bool> {
+        gst::init()?;
+        Ok(gst::ElementFactory::find("chromaprint").is_some())
+    }
+
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            queue: gst::ElementFactory::make("queue").build()?,
+            audioconvert: gst::ElementFactory::make("audioconvert").build()?,
+            audioresample: gst::ElementFactory::make("audioresample").build()?,
+            chromaprint: gst::ElementFactory::make("chromaprint")
+                .property("duration", FINGERPRINT_SECS)
+                .build()?,
+            sink: gst::ElementFactory::make("fakesink").build()?,
+        })
+    }
+
+    /// The compressed, base64-encoded fingerprint, once the audio has ended.
+    pub fn value(&self) -> Option<String> {
+        self.chromaprint
+            .property::<Option<String>>("fingerprint")
+            .filter(|fingerprint| !fingerprint.is_empty())
+    }
+
+    fn elements(&self) -> [&gst::Element; 5] {
+        [
+            &self.queue,
+            &self.audioconvert,
+            &self.audioresample,
+            &self.chromaprint,
+            &self.sink,
+        ]
+    }
+}
+
+impl PipelineBranch for FingerprintBranch {
+    fn name(&self) -> String {
+        String::from("chromaprint")
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::Audio
+    }
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
+        pipeline.add_many(self.elements())?;
+        Ok(())
+    }
+
+    fn link(&self, tee: &gst::Element, _dashsink: &gst::Element) -> Result<()> {
+        tee.link(&self.queue)?;
+        gst::Element::link_many(self.elements())?;
+        Ok(())
+    }
+}
+
+/// Hashes a frame every [`FRAME_INTERVAL`] alongside the encoding branches.
+///
+/// Clones share the same hashes, like [`FingerprintBranch`].
+#[derive(Clone)]
+pub struct VideoHashBranch {
+    queue: gst::Element,
+    videoconvert: gst::Element,
+    videoscale: gst::Element,
+    capsfilter: gst::Element,
+    sink: gst::Element,
+    hashes: Arc<Mutex<Vec<u64>>>,
+}
+
+impl VideoHashBranch {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            queue: gst::ElementFactory::make("queue").build()?,
+            videoconvert: gst::ElementFactory::make("videoconvert").build()?,
+            videoscale: gst::ElementFactory::make("videoscale").build()?,
+            capsfilter: gst::ElementFactory::make("capsfilter")
+                .property(
+                    "caps",
+                    gst::Caps::builder("video/x-raw")
+                        .field("format", "GRAY8")
+                        .field("width", 9)
+                        .field("height", 8)
+                        .field("pixel-aspect-ratio", gst::Fraction::new(1, 1))
+                        .build(),
+                )
+                .build()?,
+            sink: gst::ElementFactory::make("fakesink").build()?,
+            hashes: Arc::default(),
+        })
+    }
+
+    /// The hashes of the frames sampled so far.
+    pub fn hashes(&self) -> Vec<u64> {
+        self.hashes.lock().unwrap().clone()
+    }
+
+    fn elements(&self) -> [&gst::Element; 5] {
+        [
+            &self.queue,
+            &self.videoconvert,
+            &self.videoscale,
+            &self.capsfilter,
+            &self.sink,
+        ]
+    }
+}
+
+impl PipelineBranch for VideoHashBranch {
+    fn name(&self) -> String {
+        String::from("videohash")
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::Video
+    }
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
+        pipeline.add_many(self.elements())?;
+        Ok(())
+    }
+
+    fn link(&self, tee: &gst::Element, _dashsink: &gst::Element) -> Result<()> {
+        tee.link(&self.queue)?;
+        gst::Element::link_many(self.elements())?;
+
+        // Only sampled frames go on to be converted and scaled
+        let next = Mutex::new(Duration::ZERO);
+        self.queue
+            .static_pad("src")
+            .context("Failed to get src pad from queue")?
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                let Some(pts) = info.buffer().and_then(|buffer| buffer.pts()) else {
+                    return gst::PadProbeReturn::Drop;
+                };
+                let pts = Duration::from_nanos(pts.nseconds());
+                let mut next = next.lock().unwrap();
+                if pts < *next {
+                    return gst::PadProbeReturn::Drop;
+                }
+                *next = pts + FRAME_INTERVAL;
+                gst::PadProbeReturn::Ok
+            });
+
+        let hashes = self.hashes.clone();
+        self.sink
+            .static_pad("sink")
+            .context("Failed to get sink pad from fakesink")?
+            .add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+                if let Some(buffer) = info.buffer()
+                    && let Some(caps) = pad.current_caps()
+                    && let Ok(video) = gst_video::VideoInfo::from_caps(&caps)
+                    && let Ok(frame) =
+                        gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, &video)
+                    && let Ok(pixels) = frame.plane_data(0)
+                {
+                    let stride = frame.plane_stride()[0] as usize;
+                    hashes.lock().unwrap().push(difference_hash(pixels, stride));
+                }
+                gst::PadProbeReturn::Ok
+            });
+        Ok(())
+    }
+}
+
+/// Hash a 9x8 grey frame: one bit per pixel, set where it is brighter than
+/// its right neighbour.
+fn difference_hash(pixels: &[u8], stride: usize) -> u64 {
+    let mut hash = 0;
+    for y in 0..8 {
+        let row = &pixels[y * stride..y * stride + 9];
+        for x in 0..8 {
+            hash = (hash << 1) | (row[x] > row[x + 1]) as u64;
+        }
+    }
+    hash
+}
+
+/// Video hashes as kept in their sidecar file, one hex hash per line.
+pub fn encode_video_hashes(hashes: &[u64]) -> String {
+    hashes
+        .iter()
+        .map(|hash| format!("{:016x}\n", hash))
+        .collect()
+}
+
+pub fn decode_video_hashes(text: &str) -> Result<Vec<u64>> {
+    text.lines()
+        .map(|line| {
+            u64::from_str_radix(line.trim(), 16).context(format!("Invalid video hash {:?}", line))
+        })
+        .collect()
+}
+
+/// Reads values of a few bits each, least significant bit first, the way
+/// chromaprint packs them.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value = 0;
+        for i in 0..bits {
+            let byte = self.bytes.get(self.position / 8)?;
+            value |= (((byte >> (self.position % 8)) & 1) as u32) << i;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+/// Decode a compressed, base64-encoded chromaprint fingerprint into its
+/// 32-bit items.
+pub fn decode(fingerprint: &str) -> Result<Vec<u32>> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(fingerprint.trim())
+        .context("Fingerprint is not valid base64")?;
+    let Some((header, body)) = bytes.split_first_chunk::<4>() else {
+        bail!("Fingerprint is too short");
+    };
+    let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+    // Each item is the positions of its set bits, as gaps ended by a zero
+    let truncated = || anyhow::anyhow!("Fingerprint is truncated");
+    let mut normal = BitReader {
+        bytes: body,
+        position: 0,
+    };
+    let mut gaps = Vec::new();
+    let mut items = 0;
+    while items < len {
+        let gap = normal.read(3).ok_or_else(truncated)?;
+        if gap == 0 {
+            items += 1;
+        }
+        gaps.push(gap);
+    }
+    let mut exceptional = BitReader {
+        bytes: &body[normal.position.div_ceil(8)..],
+        position: 0,
+    };
+
+    let mut hashes = Vec::with_capacity(len);
+    let (mut item, mut bit, mut previous) = (0u32, 0u32, 0u32);
+    for gap in gaps {
+        if gap == 0 {
+            previous ^= item;
+            hashes.push(previous);
+            (item, bit) = (0, 0);
+            continue;
+        }
+        let gap = match gap {
+            MAX_NORMAL_VALUE => gap + exceptional.read(5).ok_or_else(truncated)?,
+            _ => gap,
+        };
+        bit += gap;
+        if bit > 32 {
+            bail!("Fingerprint is corrupt");
+        }
+        item |= 1 << (bit - 1);
+    }
+    Ok(hashes)
+}
+
+/// The share of bits two hash sequences agree on at their best alignment,
+/// over at least `min_overlap` hashes: about 0.5 for unrelated titles, 1.0
+/// for the same.
+fn aligned_similarity<T: Copy>(
+    a: &[T],
+    b: &[T],
+    min_overlap: usize,
+    differing_bits: impl Fn(T, T) -> u32,
+) -> Option<f64> {
+    let bits = 8 * std::mem::size_of::<T>();
+    let mut best: Option<f64> = None;
+    for offset in -(b.len() as isize)..a.len() as isize {
+        let a_first = offset.max(0) as usize;
+        let b_first = (-offset).max(0) as usize;
+        let overlap = (a.len() - a_first).min(b.len() - b_first);
+        if overlap < min_overlap {
+            continue;
+        }
+        let errors: u32 = a[a_first..a_first + overlap]
+            .iter()
+            .zip(&b[b_first..b_first + overlap])
+            .map(|(a, b)| differing_bits(*a, *b))
+            .sum();
+        let score = 1.0 - errors as f64 / (bits * overlap) as f64;
+        if best.is_none_or(|best| score > best) {
+            best = Some(score);
+        }
+    }
+    best
+}
+
+/// How alike two audio fingerprints are.
+pub fn similarity(a: &[u32], b: &[u32]) -> Option<f64> {
+    aligned_similarity(a, b, MIN_OVERLAP, |a, b| (a ^ b).count_ones())
+}
+
+/// How alike two titles' video hashes are.
+pub fn video_similarity(a: &[u64], b: &[u64]) -> Option<f64> {
+    aligned_similarity(a, b, MIN_FRAME_OVERLAP, |a, b| (a ^ b).count_ones())
+}
+
+/// What is known about one title when looking for duplicates.
+#[derive(Debug, Clone, Default)]
+pub struct Candidate {
+    pub name: String,
+    pub audio: Option<Vec<u32>>,
+    pub video: Option<Vec<u64>>,
+    /// Height and bandwidth of the best video representation
+    pub quality: (u32, u64),
+}
+
+/// Two titles that look or sound like the same movie.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Duplicate {
+    pub a: String,
+    pub b: String,
+    pub audio: Option<f64>,
+    pub video: Option<f64>,
+    /// The better preparation of the two
+    pub keep: String,
+}
+
+impl Duplicate {
+    fn similarity(&self) -> f64 {
+        self.audio.unwrap_or(0.0).max(self.video.unwrap_or(0.0))
+    }
+}
+
+/// Every pair of titles whose audio is at least `audio_threshold` similar or
+/// whose video is at least `video_threshold` similar, most similar first.
+pub fn find_duplicates(
+    titles: &[Candidate],
+    audio_threshold: f64,
+    video_threshold: f64,
+) -> Vec<Duplicate> {
+    let mut duplicates = Vec::new();
+    for (i, a) in titles.iter().enumerate() {
+        for b in &titles[i + 1..] {
+            let audio = a
+                .audio
+                .as_ref()
+                .zip(b.audio.as_ref())
+                .and_then(|(x, y)| similarity(x, y));
+            let video = a
+                .video
+                .as_ref()
+                .zip(b.video.as_ref())
+                .and_then(|(x, y)| video_similarity(x, y));
+            if audio.is_some_and(|s| s >= audio_threshold)
+                || video.is_some_and(|s| s >= video_threshold)
+            {
+                let keep = match b.quality > a.quality {
+                    true => &b.name,
+                    false => &a.name,
+                };
+                duplicates.push(Duplicate {
+                    a: a.name.clone(),
+                    b: b.name.clone(),
+                    audio,
+                    video,
+                    keep: keep.clone(),
+                });
+            }
+        }
+    }
+    duplicates.sort_by(|x, y| y.similarity().total_cmp(&x.similarity()));
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compress the way chromaprint does.
+    fn compress(hashes: &[u32]) -> String {
+        let mut normal = Vec::new();
+        let mut exceptional = Vec::new();
+        let mut previous = 0;
+        for &hash in hashes {
+            let (mut x, mut bit, mut last_bit) = (hash ^ previous, 1, 0);
+            previous = hash;
+            while x != 0 {
+                if x & 1 != 0 {
+                    let gap = bit - last_bit;
+                    normal.push(gap.min(MAX_NORMAL_VALUE));
+                    if gap >= MAX_NORMAL_VALUE {
+                        exceptional.push(gap - MAX_NORMAL_VALUE);
+                    }
+                    last_bit = bit;
+                }
+                x >>= 1;
+                bit += 1;
+            }
+            normal.push(0);
+        }
+        let pack = |values: &[u32], bits: usize| {
+            let mut bytes = vec![0u8; (values.len() * bits).div_ceil(8)];
+            for (i, value) in values.iter().enumerate() {
+                for b in 0..bits {
+                    let position = i * bits + b;
+                    bytes[position / 8] |= (((value >> b) & 1) as u8) << (position % 8);
+                }
+            }
+            bytes
+        };
+        let len = hashes.len() as u32;
+        let mut bytes = vec![1, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        bytes.extend(pack(&normal, 3));
+        bytes.extend(pack(&exceptional, 5));
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// Deterministic noise standing in for a soundtrack.
+    fn soundtrack(seed: u32, len: usize) -> Vec<u32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_compressed_fingerprints() {
+        let hashes = [0, 1, 0x8000_0001, 0xffff_ffff, 0x1234_5678, 0x1234_5678];
+        assert_eq!(decode(&compress(&hashes)).unwrap(), hashes);
+        let movie = soundtrack(7, 500);
+        assert_eq!(decode(&compress(&movie)).unwrap(), movie);
+        assert!(decode("AQAAAw").is_err());
+    }
+
+    #[test]
+    fn pairs_titles_that_sound_alike() {
+        let movie = soundtrack(7, 1000);
+        // Another rip: a different logo up front and a few flipped bits
+        let mut rip = soundtrack(99, 40);
+        rip.extend(movie[..900].iter().map(|hash| hash ^ 0x0101));
+        let candidate = |name: &str, audio, quality| Candidate {
+            name: name.to_string(),
+            audio: Some(audio),
+            video: None,
+            quality,
+        };
+        let titles = vec![
+            candidate("Heat", movie, (720, 2_000_000)),
+            candidate("Heat (Blu-ray)", rip, (1080, 6_000_000)),
+            candidate("Ronin", soundtrack(3, 1000), (1080, 6_000_000)),
+        ];
+        let duplicates = find_duplicates(&titles, 0.8, 0.9);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].a, "Heat");
+        assert_eq!(duplicates[0].b, "Heat (Blu-ray)");
+        assert_eq!(duplicates[0].keep, "Heat (Blu-ray)");
+        assert!(duplicates[0].audio.unwrap() > 0.9);
+        assert_eq!(duplicates[0].video, None);
+        assert!(
+            similarity(
+                &titles[0].audio.clone().unwrap(),
+                &titles[2].audio.clone().unwrap()
+            )
+            .unwrap()
+                < 0.6
+        );
+    }
+
+    #[test]
+    fn hashes_frames_by_brightness_gradient() {
+        // Brighter to the left on every row but the last
+        let mut pixels = vec![0u8; 12 * 8];
+        for y in 0..7 {
+            for x in 0..9 {
+                pixels[y * 12 + x] = 200 - 20 * x as u8;
+            }
+        }
+        let hash = difference_hash(&pixels, 12);
+        assert_eq!(hash, 0xffff_ffff_ffff_ff00);
+        let text = encode_video_hashes(&[hash, 1]);
+        assert_eq!(text, "ffffffffffffff00\n0000000000000001\n");
+        assert_eq!(decode_video_hashes(&text).unwrap(), [hash, 1]);
+
+        // A re-encode starting 3 samples later, with a few bits flipped
+        let movie: Vec<u64> = soundtrack(5, 80)
+            .iter()
+            .map(|h| (*h as u64) << 32 | *h as u64)
+            .collect();
+        let reencode: Vec<u64> = movie[3..].iter().map(|h| h ^ 0b101).collect();
+        assert!(video_similarity(&movie, &reencode).unwrap() > 0.95);
+    }
+}
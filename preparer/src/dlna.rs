@@ -0,0 +1,520 @@
+//! DLNA/UPnP media server, for TVs that can browse UPnP but can't play DASH.
+//!
+//! Renderers find the server over SSDP and browse a flat list of titles
+//! through the ContentDirectory service. Only titles with a progressive
+//! [`FALLBACK`] file are offered, since that is what gets streamed.
+//!
+//! - `GET /dlna/description.xml` describes the device and its services
+//! - `POST /dlna/control/{service}` answers SOAP actions
+//! - `GET /dlna/media/{title}` streams a title's fallback file
+
+use crate::library::FALLBACK;
+use crate::serve::{AppState, encode_segment, escape_html, titles};
+use anyhow::{Context, Result};
+use axum::Router;
+use axum::extract::{Path as UrlPath, Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use quick_xml::events::Event;
+use quick_xml::{Reader, XmlVersion};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+const SSDP_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+/// How long advertisements stay valid; they're repeated well before then.
+const MAX_AGE_SECS: u64 = 1800;
+
+const MEDIA_SERVER: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+const PROTOCOL_INFO: &str = "http-get:*:video/mp4:DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+/// A UUID that stays the same for a library, so renderers remember the server.
+fn device_uuid(library: &Path) -> String {
+    let path = std::path::absolute(library).unwrap_or(library.to_path_buf());
+    let hash: String = Sha256::digest(path.as_os_str().as_encoded_bytes())[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hash[..8],
+        &hash[8..12],
+        &hash[12..16],
+        &hash[16..20],
+        &hash[20..]
+    )
+}
+
+fn xml(body: String) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/xml; charset=\"utf-8\"")],
+        body,
+    )
+        .into_response()
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/dlna/description.xml", get(description))
+        .route(
+            "/dlna/ContentDirectory.xml",
+            get(|| async { xml(String::from(CONTENT_DIRECTORY_SCPD)) }),
+        )
+        .route(
+            "/dlna/ConnectionManager.xml",
+            get(|| async { xml(String::from(CONNECTION_MANAGER_SCPD)) }),
+        )
+        .route("/dlna/control/{service}", post(control))
+        .route("/dlna/media/{title}", get(media))
+}
+
+async fn description(State(state): State<AppState>) -> Response {
+    let name = std::path::absolute(&state.library)
+        .ok()
+        .and_then(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .unwrap_or(String::from("library"));
+    xml(format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0" xmlns:dlna="urn:schemas-dlna-org:device-1-0">
+  <specVersion><major>1</major><minor>0</minor></specVersion>
+  <device>
+    <deviceType>{}</deviceType>
+    <friendlyName>movieshare: {}</friendlyName>
+    <manufacturer>movieshare</manufacturer>
+    <modelName>movieshare</modelName>
+    <UDN>uuid:{}</UDN>
+    <dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>
+    <serviceList>
+      <service>
+        <serviceType>{}</serviceType>
+        <serviceId>urn:upnp-org:serviceId:ContentDirectory</serviceId>
+        <SCPDURL>/dlna/ContentDirectory.xml</SCPDURL>
+        <controlURL>/dlna/control/ContentDirectory</controlURL>
+        <eventSubURL>/dlna/events/ContentDirectory</eventSubURL>
+      </service>
+      <service>
+        <serviceType>{}</serviceType>
+        <serviceId>urn:upnp-org:serviceId:ConnectionManager</serviceId>
+        <SCPDURL>/dlna/ConnectionManager.xml</SCPDURL>
+        <controlURL>/dlna/control/ConnectionManager</controlURL>
+        <eventSubURL>/dlna/events/ConnectionManager</eventSubURL>
+      </service>
+    </serviceList>
+  </device>
+</root>"#,
+        MEDIA_SERVER,
+        escape_html(&name),
+        device_uuid(&state.library),
+        CONTENT_DIRECTORY,
+        CONNECTION_MANAGER
+    ))
+}
+
+/// The arguments of a SOAP action, by element name.
+fn soap_arguments(body: &str) -> Result<HashMap<String, String>> {
+    let mut reader = Reader::from_str(body);
+    let mut arguments = HashMap::new();
+    let mut current = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                current = Some(element.local_name().into_inner().to_string());
+            }
+            Event::Text(text) => {
+                if let Some(name) = &current {
+                    arguments.insert(
+                        name.clone(),
+                        text.xml_content(XmlVersion::Implicit1_0).into_owned(),
+                    );
+                }
+            }
+            Event::End(_) => current = None,
+            Event::Eof => return Ok(arguments),
+            _ => (),
+        }
+    }
+}
+
+fn soap_response(service: &str, action: &str, arguments: &[(&str, String)]) -> Response {
+    let arguments: String = arguments
+        .iter()
+        .map(|(name, value)| format!("<{}>{}</{}>", name, escape_html(value), name))
+        .collect();
+    xml(format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><u:{}Response xmlns:u="{}">{}</u:{}Response></s:Body>
+</s:Envelope>"#,
+        action, service, arguments, action
+    ))
+}
+
+/// A title as a DIDL-Lite item.
+fn didl_item(title: &str, label: &str, size: u64, host: &str) -> String {
+    format!(
+        "<item id=\"{}\" parentID=\"0\" restricted=\"1\"><dc:title>{}</dc:title>\
+         <upnp:class>object.item.videoItem.movie</upnp:class>\
+         <res protocolInfo=\"{}\" size=\"{}\">http://{}/dlna/media/{}</res></item>",
+        escape_html(title),
+        escape_html(label),
+        PROTOCOL_INFO,
+        size,
+        escape_html(host),
+        encode_segment(title)
+    )
+}
+
+fn didl(objects: &str) -> String {
+    format!(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+         xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">{}</DIDL-Lite>",
+        objects
+    )
+}
+
+/// Titles that have a fallback file: name, label and file size.
+fn playable(library: &Path) -> Vec<(String, String, u64)> {
+    titles(library)
+        .into_iter()
+        .filter_map(|(name, metadata)| {
+            let size = std::fs::metadata(library.join(&name).join(FALLBACK))
+                .ok()?
+                .len();
+            let label = metadata.map_or(name.clone(), |metadata| metadata.label());
+            Some((name, label, size))
+        })
+        .collect()
+}
+
+async fn control(
+    State(state): State<AppState>,
+    UrlPath(service): UrlPath<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Response, StatusCode> {
+    // SOAPACTION: "urn:schemas-upnp-org:service:ContentDirectory:1#Browse"
+    let action = headers
+        .get("soapaction")
+        .and_then(|action| action.to_str().ok())
+        .and_then(|action| action.trim_matches('"').split_once('#'))
+        .map(|(_, action)| action.to_string())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let arguments = soap_arguments(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    match (service.as_str(), action.as_str()) {
+        ("ContentDirectory", "Browse") => {
+            let library = state.library.clone();
+            let titles = tokio::task::spawn_blocking(move || playable(&library))
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let object = arguments.get("ObjectID").map_or("0", String::as_str);
+            let start: usize = arguments
+                .get("StartingIndex")
+                .and_then(|start| start.parse().ok())
+                .unwrap_or(0);
+            let count: usize = match arguments.get("RequestedCount").and_then(|c| c.parse().ok()) {
+                Some(0) | None => usize::MAX,
+                Some(count) => count,
+            };
+
+            let (objects, returned, total) = match arguments.get("BrowseFlag").map(String::as_str) {
+                Some("BrowseMetadata") if object == "0" => (
+                    format!(
+                        "<container id=\"0\" parentID=\"-1\" restricted=\"1\" childCount=\"{}\">\
+                         <dc:title>Movies</dc:title><upnp:class>object.container</upnp:class></container>",
+                        titles.len()
+                    ),
+                    1,
+                    1,
+                ),
+                Some("BrowseMetadata") => {
+                    let (name, label, size) = titles
+                        .iter()
+                        .find(|(name, _, _)| name == object)
+                        .ok_or(StatusCode::NOT_FOUND)?;
+                    (didl_item(name, label, *size, &host), 1, 1)
+                }
+                _ if object != "0" => (String::new(), 0, 0),
+                _ => {
+                    let page: Vec<_> = titles.iter().skip(start).take(count).collect();
+                    let objects = page
+                        .iter()
+                        .map(|(name, label, size)| didl_item(name, label, *size, &host))
+                        .collect();
+                    (objects, page.len(), titles.len())
+                }
+            };
+            Ok(soap_response(
+                CONTENT_DIRECTORY,
+                "Browse",
+                &[
+                    ("Result", didl(&objects)),
+                    ("NumberReturned", returned.to_string()),
+                    ("TotalMatches", total.to_string()),
+                    ("UpdateID", String::from("1")),
+                ],
+            ))
+        }
+        ("ContentDirectory", "GetSystemUpdateID") => Ok(soap_response(
+            CONTENT_DIRECTORY,
+            &action,
+            &[("Id", String::from("1"))],
+        )),
+        ("ContentDirectory", "GetSearchCapabilities") => Ok(soap_response(
+            CONTENT_DIRECTORY,
+            &action,
+            &[("SearchCaps", String::new())],
+        )),
+        ("ContentDirectory", "GetSortCapabilities") => Ok(soap_response(
+            CONTENT_DIRECTORY,
+            &action,
+            &[("SortCaps", String::new())],
+        )),
+        ("ConnectionManager", "GetProtocolInfo") => Ok(soap_response(
+            CONNECTION_MANAGER,
+            &action,
+            &[
+                ("Source", String::from(PROTOCOL_INFO)),
+                ("Sink", String::new()),
+            ],
+        )),
+        ("ConnectionManager", "GetCurrentConnectionIDs") => Ok(soap_response(
+            CONNECTION_MANAGER,
+            &action,
+            &[("ConnectionIDs", String::from("0"))],
+        )),
+        _ => Err(StatusCode::NOT_IMPLEMENTED),
+    }
+}
+
+async fn media(
+    State(state): State<AppState>,
+    UrlPath(title): UrlPath<String>,
+    request: Request,
+) -> Result<Response, StatusCode> {
+    if title.contains('/') || !crate::serve::is_title(&state.library, &title) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let response = ServeFile::new(state.library.join(&title).join(FALLBACK))
+        .oneshot(request)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut response = response.into_response();
+    // Some TVs refuse to play without the DLNA headers
+    response.headers_mut().insert(
+        "transferMode.dlna.org",
+        header::HeaderValue::from_static("Streaming"),
+    );
+    response.headers_mut().insert(
+        "contentFeatures.dlna.org",
+        header::HeaderValue::from_static("DLNA.ORG_OP=01;DLNA.ORG_CI=0"),
+    );
+    Ok(response)
+}
+
+/// The address renderers on the LAN can reach us at.
+fn lan_address(bind: IpAddr) -> Result<IpAddr> {
+    if !bind.is_unspecified() {
+        return Ok(bind);
+    }
+    // Connecting a UDP socket sends nothing but picks the outgoing interface
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((SSDP_ADDR, SSDP_PORT))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The notification types we answer to, with the USN for each.
+fn targets(uuid: &str) -> Vec<(String, String)> {
+    let device = format!("uuid:{}", uuid);
+    let mut targets = vec![(device.clone(), device.clone())];
+    for target in [
+        "upnp:rootdevice",
+        MEDIA_SERVER,
+        CONTENT_DIRECTORY,
+        CONNECTION_MANAGER,
+    ] {
+        targets.push((target.to_string(), format!("{}::{}", device, target)));
+    }
+    targets
+}
+
+/// Announce the server over SSDP and answer renderers searching for it.
+pub async fn advertise(library: &Path, addr: SocketAddr) -> Result<()> {
+    let uuid = device_uuid(library);
+    let location = format!(
+        "http://{}/dlna/description.xml",
+        SocketAddr::new(lan_address(addr.ip())?, addr.port())
+    );
+    let targets = targets(&uuid);
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SSDP_PORT))
+        .await
+        .context(format!("Failed to listen for SSDP on port {}", SSDP_PORT))?;
+    socket.join_multicast_v4(SSDP_ADDR, Ipv4Addr::UNSPECIFIED)?;
+    println!("Announcing the library over DLNA at {}", location);
+
+    let mut notify = tokio::time::interval(Duration::from_secs(MAX_AGE_SECS / 3));
+    let mut buffer = [0; 2048];
+    loop {
+        tokio::select! {
+            _ = notify.tick() => {
+                for (target, usn) in &targets {
+                    let message = format!(
+                        "NOTIFY * HTTP/1.1\r\nHOST: {}:{}\r\nCACHE-CONTROL: max-age={}\r\n\
+                         LOCATION: {}\r\nNT: {}\r\nNTS: ssdp:alive\r\nSERVER: movieshare UPnP/1.0 DLNADOC/1.50\r\n\
+                         USN: {}\r\n\r\n",
+                        SSDP_ADDR, SSDP_PORT, MAX_AGE_SECS, location, target, usn
+                    );
+                    socket.send_to(message.as_bytes(), (SSDP_ADDR, SSDP_PORT)).await?;
+                }
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let (length, peer) = received?;
+                let request = String::from_utf8_lossy(&buffer[..length]);
+                let Some(search) = search_target(&request) else {
+                    continue;
+                };
+                for (target, usn) in &targets {
+                    if search == "ssdp:all" || search == *target {
+                        let message = format!(
+                            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nEXT:\r\nLOCATION: {}\r\n\
+                             SERVER: movieshare UPnP/1.0 DLNADOC/1.50\r\nST: {}\r\nUSN: {}\r\n\r\n",
+                            MAX_AGE_SECS, location, target, usn
+                        );
+                        socket.send_to(message.as_bytes(), peer).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The ST of an M-SEARCH request.
+fn search_target(request: &str) -> Option<String> {
+    let mut lines = request.lines();
+    if !lines.next()?.starts_with("M-SEARCH") {
+        return None;
+    }
+    lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("st")
+            .then(|| value.trim().to_string())
+    })
+}
+
+const CONTENT_DIRECTORY_SCPD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+  <specVersion><major>1</major><minor>0</minor></specVersion>
+  <actionList>
+    <action>
+      <name>Browse</name>
+      <argumentList>
+        <argument><name>ObjectID</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_ObjectID</relatedStateVariable></argument>
+        <argument><name>BrowseFlag</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_BrowseFlag</relatedStateVariable></argument>
+        <argument><name>Filter</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Filter</relatedStateVariable></argument>
+        <argument><name>StartingIndex</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Index</relatedStateVariable></argument>
+        <argument><name>RequestedCount</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+        <argument><name>SortCriteria</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_SortCriteria</relatedStateVariable></argument>
+        <argument><name>Result</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Result</relatedStateVariable></argument>
+        <argument><name>NumberReturned</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+        <argument><name>TotalMatches</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+        <argument><name>UpdateID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_UpdateID</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>GetSystemUpdateID</name>
+      <argumentList>
+        <argument><name>Id</name><direction>out</direction><relatedStateVariable>SystemUpdateID</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>GetSearchCapabilities</name>
+      <argumentList>
+        <argument><name>SearchCaps</name><direction>out</direction><relatedStateVariable>SearchCapabilities</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>GetSortCapabilities</name>
+      <argumentList>
+        <argument><name>SortCaps</name><direction>out</direction><relatedStateVariable>SortCapabilities</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+  </actionList>
+  <serviceStateTable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_ObjectID</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_BrowseFlag</name><dataType>string</dataType>
+      <allowedValueList><allowedValue>BrowseMetadata</allowedValue><allowedValue>BrowseDirectChildren</allowedValue></allowedValueList>
+    </stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_Filter</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_Index</name><dataType>ui4</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_Count</name><dataType>ui4</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_SortCriteria</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_Result</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_UpdateID</name><dataType>ui4</dataType></stateVariable>
+    <stateVariable sendEvents="yes"><name>SystemUpdateID</name><dataType>ui4</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>SearchCapabilities</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>SortCapabilities</name><dataType>string</dataType></stateVariable>
+  </serviceStateTable>
+</scpd>"#;
+
+const CONNECTION_MANAGER_SCPD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+  <specVersion><major>1</major><minor>0</minor></specVersion>
+  <actionList>
+    <action>
+      <name>GetProtocolInfo</name>
+      <argumentList>
+        <argument><name>Source</name><direction>out</direction><relatedStateVariable>SourceProtocolInfo</relatedStateVariable></argument>
+        <argument><name>Sink</name><direction>out</direction><relatedStateVariable>SinkProtocolInfo</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>GetCurrentConnectionIDs</name>
+      <argumentList>
+        <argument><name>ConnectionIDs</name><direction>out</direction><relatedStateVariable>CurrentConnectionIDs</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+  </actionList>
+  <serviceStateTable>
+    <stateVariable sendEvents="yes"><name>SourceProtocolInfo</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="yes"><name>SinkProtocolInfo</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="yes"><name>CurrentConnectionIDs</name><dataType>string</dataType></stateVariable>
+  </serviceStateTable>
+</scpd>"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ssdp_and_soap() {
+        let search = "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nst: upnp:rootdevice\r\n\r\n";
+        assert_eq!(search_target(search).as_deref(), Some("upnp:rootdevice"));
+        assert_eq!(search_target("NOTIFY * HTTP/1.1\r\nNT: x\r\n"), None);
+
+        let arguments = soap_arguments(
+            r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:Browse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1"><ObjectID>0</ObjectID><BrowseFlag>BrowseDirectChildren</BrowseFlag><Filter>*</Filter><StartingIndex>0</StartingIndex><RequestedCount>50</RequestedCount><SortCriteria></SortCriteria></u:Browse></s:Body></s:Envelope>"#,
+        )
+        .unwrap();
+        assert_eq!(arguments["BrowseFlag"], "BrowseDirectChildren");
+        assert_eq!(arguments["RequestedCount"], "50");
+        assert_eq!(device_uuid(Path::new("/lib")).len(), 36);
+    }
+}
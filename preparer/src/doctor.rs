@@ -0,0 +1,218 @@
+//! `preparer doctor`: what this machine's GStreamer can do, and what the
+//! preparer can't do here as a result, for the start of a support request.
+//!
+//! Unlike `preparer selftest`, nothing is encoded; the registry is only
+//! asked which elements each feature needs are installed.
+
+use anyhow::Result;
+use movieshare_core::gst;
+use serde::Serialize;
+use std::fmt;
+
+/// Something the preparer can do given any of `elements`.
+struct Feature {
+    name: &'static str,
+    elements: &'static [&'static str],
+    /// What stops working without it
+    without: &'static str,
+}
+
+const FEATURES: &[Feature] = &[
+    Feature {
+        name: "decoding",
+        elements: &["decodebin"],
+        without: "nothing can be prepared",
+    },
+    Feature {
+        name: "DASH packaging (dashsink)",
+        elements: &["dashsink"],
+        without: "nothing can be prepared",
+    },
+    Feature {
+        name: "fragmented MP4 segments (dashmp4mux)",
+        elements: &["dashmp4mux"],
+        without: "dashsink can't write the segments the manifest names",
+    },
+    Feature {
+        name: "plain MP4 segments (mp4mux)",
+        elements: &["mp4mux"],
+        without: "`preparer clip` fails",
+    },
+    Feature {
+        name: "AV1 encoding in software (SVT-AV1)",
+        elements: &["svtav1enc"],
+        without: "--encoder software fails, and so does auto without a GPU encoder",
+    },
+    Feature {
+        name: "AV1 encoding on VA-API",
+        elements: &["vaav1enc"],
+        without: "--encoder vaapi fails",
+    },
+    Feature {
+        name: "AV1 encoding on NVENC",
+        elements: &["nvav1enc"],
+        without: "--encoder nvenc fails",
+    },
+    Feature {
+        name: "AV1 encoding on Quick Sync",
+        elements: &["qsvav1enc"],
+        without: "--encoder qsv fails",
+    },
+    Feature {
+        name: "AV1 stream copy",
+        elements: &["av1parse"],
+        without: "AV1 sources are re-encoded rather than repackaged",
+    },
+    Feature {
+        name: "H.264 encoding",
+        elements: &["x264enc", "vah264enc", "nvh264enc", "qsvh264enc"],
+        without: "--h264 rungs fail",
+    },
+    Feature {
+        name: "hardware decoding on VA-API",
+        elements: &["vah264dec", "vah265dec", "vaav1dec", "vavp9dec"],
+        without: "--decoder vaapi fails",
+    },
+    Feature {
+        name: "hardware decoding on NVDEC",
+        elements: &["nvh264dec", "nvh265dec", "nvav1dec", "nvvp9dec"],
+        without: "--decoder nvdec fails",
+    },
+    Feature {
+        name: "Opus audio",
+        elements: &["opusenc"],
+        without: "sources with audio can't be encoded",
+    },
+    Feature {
+        name: "text overlays",
+        elements: &["textoverlay"],
+        without: "--watermark-text, --burn-subtitles and `preparer subtitle-preview` fail",
+    },
+    Feature {
+        name: "burned-in timecode",
+        elements: &["timeoverlay"],
+        without: "--burn-timecode fails",
+    },
+    Feature {
+        name: "subtitle files",
+        elements: &["subparse"],
+        without: "--burn-subtitles fails",
+    },
+    Feature {
+        name: "image watermarks",
+        elements: &["gdkpixbufoverlay"],
+        without: "--watermark fails",
+    },
+    Feature {
+        name: "inverse telecine",
+        elements: &["ivtc"],
+        without: "telecined sources keep their pulldown",
+    },
+    Feature {
+        name: "audio fingerprints (chromaprint)",
+        elements: &["chromaprint"],
+        without: "`preparer dedupe` can't compare audio",
+    },
+    Feature {
+        name: "screen capture",
+        elements: &["pipewiresrc", "ximagesrc"],
+        without: "`preparer live screen` fails",
+    },
+];
+
+/// How one feature fares on this machine.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub feature: &'static str,
+    pub usable: bool,
+    /// Installed elements providing it
+    pub elements: Vec<&'static str>,
+    /// What's disabled, if it isn't usable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disabled: Option<&'static str>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    pub gstreamer: String,
+    pub findings: Vec<Finding>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.gstreamer)?;
+        for finding in &self.findings {
+            match finding.disabled {
+                None => writeln!(
+                    f,
+                    "ok      {} ({})",
+                    finding.feature,
+                    finding.elements.join(", ")
+                )?,
+                Some(disabled) => writeln!(f, "missing {}: {}", finding.feature, disabled)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How each feature fares, given which elements are `installed`.
+fn findings(installed: impl Fn(&str) -> bool) -> Vec<Finding> {
+    FEATURES
+        .iter()
+        .map(|feature| {
+            let elements: Vec<&'static str> = feature
+                .elements
+                .iter()
+                .copied()
+                .filter(|element| installed(element))
+                .collect();
+            let usable = !elements.is_empty();
+            Finding {
+                feature: feature.name,
+                usable,
+                elements,
+                disabled: (!usable).then_some(feature.without),
+            }
+        })
+        .collect()
+}
+
+/// Ask the registry.
+pub fn report() -> Result<Report> {
+    gst::init()?;
+    Ok(Report {
+        gstreamer: gst::version_string().to_string(),
+        findings: findings(|element| gst::ElementFactory::find(element).is_some()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn says_what_missing_elements_disable() {
+        let report = Report {
+            gstreamer: String::from("GStreamer 1.24.2"),
+            findings: findings(|element| element != "vaav1enc" && element != "x264enc"),
+        };
+        let disabled: Vec<&str> = report
+            .findings
+            .iter()
+            .filter_map(|finding| finding.disabled)
+            .collect();
+        assert_eq!(disabled, ["--encoder vaapi fails"]);
+        let h264 = report
+            .findings
+            .iter()
+            .find(|finding| finding.feature == "H.264 encoding")
+            .unwrap();
+        assert_eq!(h264.elements, ["vah264enc", "nvh264enc", "qsvh264enc"]);
+        assert!(
+            report
+                .to_string()
+                .contains("missing AV1 encoding on VA-API: --encoder vaapi fails")
+        );
+    }
+}
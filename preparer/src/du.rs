@@ -0,0 +1,182 @@
+//! Where a library's storage goes, for `preparer library du`: each title's
+//! files as the catalog recorded them, split by the representation of the
+//! manifest they belong to, without touching the files themselves.
+//!
+//! Titles barely watched, going by the playback sessions recorded, are
+//! suggested for losing their top video rung. Viewers rarely need it and
+//! it's usually the largest, so that's where space is cheapest to reclaim.
+
+use crate::analytics::TitleStats;
+use crate::mpd::{self, Representation};
+use crate::verify::{RecordedFile, representation_files};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Watched time, in run times of the title, below which it counts as rarely
+/// watched.
+const RARELY_WATCHED: f64 = 1.0;
+
+/// Storage taken by one representation of a title.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RepresentationUsage {
+    #[serde(flatten)]
+    pub representation: Representation,
+    pub size_bytes: u64,
+}
+
+impl RepresentationUsage {
+    /// Like `video 1080p 6.2 MB/s`.
+    pub fn label(&self) -> String {
+        let representation = &self.representation;
+        let mut label = representation.content_type.clone();
+        if let Some(height) = representation.height {
+            label.push_str(&format!(" {}p", height));
+        }
+        if let Some(lang) = &representation.lang {
+            label.push_str(&format!(" {}", lang));
+        }
+        if representation.content_type != "image" {
+            label.push_str(&format!(
+                " {:.1} MB/s",
+                representation.bandwidth as f64 / 1_000_000.0
+            ));
+        }
+        label
+    }
+}
+
+/// Storage taken by a title.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TitleUsage {
+    pub title: String,
+    pub size_bytes: u64,
+    /// Largest first
+    pub representations: Vec<RepresentationUsage>,
+    /// Files no representation names, like artwork, metadata and logs
+    pub other_bytes: u64,
+}
+
+/// A way to reclaim space.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub title: String,
+    pub representation: String,
+    pub size_bytes: u64,
+    pub reason: String,
+}
+
+/// How the `recorded` files of `title` split between the representations of
+/// its manifest, `xml`.
+pub fn title_usage(title: &str, xml: &str, recorded: &[RecordedFile]) -> Result<TitleUsage> {
+    let sizes: HashMap<&str, u64> = recorded
+        .iter()
+        .map(|file| (file.path.as_str(), file.size_bytes))
+        .collect();
+    let manifest = mpd::parse(xml)?;
+    let mut representations = Vec::new();
+    let mut claimed = 0;
+    for representation in &manifest.representations {
+        let size_bytes = representation_files(xml, &manifest, representation)?
+            .iter()
+            .filter_map(|file| sizes.get(file.as_str()))
+            .sum();
+        claimed += size_bytes;
+        representations.push(RepresentationUsage {
+            representation: representation.clone(),
+            size_bytes,
+        });
+    }
+    representations.sort_by_key(|usage| std::cmp::Reverse(usage.size_bytes));
+    let size_bytes = recorded.iter().map(|file| file.size_bytes).sum();
+    Ok(TitleUsage {
+        title: title.to_string(),
+        size_bytes,
+        representations,
+        other_bytes: size_bytes.saturating_sub(claimed),
+    })
+}
+
+/// Suggest dropping the top video rung of `usage` if the title, `duration_secs`
+/// long, has been watched for less than a run time in all, and there's a
+/// rung left to fall back on.
+pub fn suggest(
+    usage: &TitleUsage,
+    duration_secs: Option<f64>,
+    stats: Option<&TitleStats>,
+) -> Option<Suggestion> {
+    let duration_secs = duration_secs.filter(|secs| *secs > 0.0)?;
+    let watched_secs = stats.map_or(0.0, |stats| stats.watched_secs);
+    if watched_secs >= duration_secs * RARELY_WATCHED {
+        return None;
+    }
+    let video: Vec<&RepresentationUsage> = usage
+        .representations
+        .iter()
+        .filter(|usage| usage.representation.content_type == "video")
+        .collect();
+    if video.len() < 2 {
+        return None;
+    }
+    let top = video
+        .into_iter()
+        .max_by_key(|usage| usage.representation.bandwidth)?;
+    let reason = match stats {
+        Some(stats) => format!(
+            "watched for {:.0} minutes in {} sessions",
+            watched_secs / 60.0,
+            stats.sessions
+        ),
+        None => String::from("never watched"),
+    };
+    Some(Suggestion {
+        title: usage.title.clone(),
+        representation: top.label(),
+        size_bytes: top.size_bytes,
+        reason,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_storage_by_representation() {
+        let xml = r#"<?xml version="1.0"?><MPD type="static" mediaPresentationDuration="PT8S"><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="6000000" height="1080"><SegmentList timescale="1000" duration="4000"><Initialization sourceURL="video_0_init.mp4"/><SegmentURL media="video_0_00001.m4s"/><SegmentURL media="video_0_00002.m4s"/></SegmentList></Representation><Representation id="1" bandwidth="1000000" height="480"><SegmentList timescale="1000" duration="4000"><SegmentURL media="video_1_00001.m4s"/></SegmentList></Representation></AdaptationSet></Period></MPD>"#;
+        let file = |path: &str, size_bytes| RecordedFile {
+            path: path.to_string(),
+            size_bytes,
+            sha256: None,
+        };
+        let recorded = [
+            file("manifest.mpd", 5),
+            file("video_0_init.mp4", 10),
+            file("video_0_00001.m4s", 400),
+            file("video_0_00002.m4s", 600),
+            file("video_1_00001.m4s", 100),
+        ];
+        let usage = title_usage("Film", xml, &recorded).unwrap();
+        assert_eq!(usage.size_bytes, 1115);
+        assert_eq!(usage.other_bytes, 5);
+        let sizes: Vec<u64> = usage.representations.iter().map(|r| r.size_bytes).collect();
+        assert_eq!(sizes, [1010, 100]);
+        assert_eq!(usage.representations[0].label(), "video 1080p 6.0 MB/s");
+
+        let suggestion = suggest(&usage, Some(8.0), None).unwrap();
+        assert_eq!(suggestion.size_bytes, 1010);
+        assert_eq!(suggestion.reason, "never watched");
+        let stats = TitleStats {
+            title: String::from("Film"),
+            sessions: 3,
+            watched_secs: 20.0,
+            startup_secs: None,
+            rebuffer_count: 0,
+            rebuffer_secs: 0.0,
+            rung_switches: 0,
+            rungs: Default::default(),
+        };
+        assert_eq!(suggest(&usage, Some(8.0), Some(&stats)), None);
+        assert_eq!(suggest(&usage, None, None), None);
+    }
+}
@@ -0,0 +1,122 @@
+//! The log each title keeps of the run that prepared it.
+//!
+//! `encode-log.jsonl.gz` holds the plan as it was shown, every event the
+//! run raised, as `--json` prints them, and last the run itself, with the
+//! profile, GStreamer version and encoder it used and how it ended. The
+//! title's `metadata.json` names it, so how a title came to look the way it
+//! does can be looked into long after it was prepared.
+
+use crate::history::EncodeRun;
+use anyhow::{Context, Result};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use movieshare_core::events::Event;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Name of the log in a title's directory.
+pub const ENCODE_LOG: &str = "encode-log.jsonl.gz";
+
+/// A run's log, kept as JSON lines until it's written next to the output.
+#[derive(Default)]
+pub struct EncodeLog {
+    lines: String,
+}
+
+impl EncodeLog {
+    fn push(&mut self, line: &str) {
+        self.lines.push_str(line);
+        self.lines.push('\n');
+    }
+
+    /// Record the plan, as [`movieshare_core::plan::Overview`] shows it,
+    /// and what probing the source found.
+    pub fn plan(&mut self, overview: &str, source: &impl std::fmt::Debug) {
+        let line = json!({ "plan": overview, "source": format!("{:?}", source) });
+        self.push(&line.to_string());
+    }
+
+    pub fn event(&mut self, event: &Event) -> Result<()> {
+        self.push(&event.to_json()?);
+        Ok(())
+    }
+
+    /// Finish with `run` and write the log, compressed, into `dir`.
+    pub fn write(mut self, run: &EncodeRun, dir: &Path) -> Result<()> {
+        self.push(&json!({ "run": run }).to_string());
+        let path = dir.join(ENCODE_LOG);
+        let file =
+            std::fs::File::create(&path).context(format!("Failed to create {}", path.display()))?;
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(self.lines.as_bytes())?;
+        encoder.finish()?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// The log `file` in `dir`, decompressed, if it's there.
+pub fn read(dir: &Path, file: &str) -> Result<Option<String>> {
+    let path = dir.join(file);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file = std::fs::File::open(&path).context(format!("Failed to open {}", path.display()))?;
+    let mut lines = String::new();
+    GzDecoder::new(file)
+        .read_to_string(&mut lines)
+        .context(format!("{} is damaged", path.display()))?;
+    Ok(Some(lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use movieshare_core::events::EventBody;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn writes_and_reads_back() {
+        let dir = std::env::temp_dir().join(format!("encode-log-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(read(&dir, ENCODE_LOG).unwrap(), None);
+
+        let mut log = EncodeLog::default();
+        log.plan("Video: encode 2 rungs", &"source");
+        log.event(&Event::new(EventBody::Warning {
+            message: String::from("Dropped a frame"),
+        }))
+        .unwrap();
+        let run = EncodeRun {
+            finished_at: 0,
+            title: String::from("Film"),
+            input: String::from("film.mkv"),
+            source_hash: None,
+            settings: String::from("{}"),
+            gstreamer: String::from("GStreamer 1.24.0"),
+            encoder: String::from("x264"),
+            preset: 6,
+            encoded_rungs: 2,
+            source_secs: 60.0,
+            wall_secs: 30.0,
+            estimated_bytes: None,
+            output_bytes: 1000,
+            bitrates: BTreeMap::new(),
+            outcome: String::from("prepared"),
+            error: None,
+            usage: None,
+        };
+        log.write(&run, &dir).unwrap();
+
+        let lines = read(&dir, ENCODE_LOG).unwrap().unwrap();
+        let lines: Vec<serde_json::Value> = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["plan"], "Video: encode 2 rungs");
+        assert_eq!(lines[2]["run"]["encoder"], "x264");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
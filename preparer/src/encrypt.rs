@@ -0,0 +1,360 @@
+//! Encryption of prepared titles at rest, for libraries kept on storage that
+//! isn't trusted with the content, like a cloud bucket.
+//!
+//! Every file of a title is sealed with AES-256-GCM under a library key kept
+//! outside the library, and `preparer serve --library-key` decrypts files
+//! as they're requested. Files are sealed in chunks, each nonce carrying the
+//! chunk's index and whether it's the last, so a range request only needs
+//! the chunks it covers, and chunks can't be reordered or a file cut short
+//! unnoticed. A file's path within the title is authenticated too, so
+//! files can't be swapped for each other.
+//!
+//! The journal stays in the clear, so `--resume` still works.
+
+use anyhow::{Context, Result, anyhow, bail};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use movieshare_core::journal::JOURNAL_FILENAME;
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, Nonce, UnboundKey};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Start of every encrypted file.
+const MAGIC: &[u8] = b"movieshare-enc1\n";
+/// Random part of each chunk's nonce, stored after the magic.
+const PREFIX_LEN: usize = 7;
+const HEADER_LEN: u64 = (MAGIC.len() + PREFIX_LEN) as u64;
+const TAG_LEN: u64 = 16;
+/// Plaintext bytes per chunk.
+const CHUNK: u64 = 64 * 1024;
+const SEALED_CHUNK: u64 = CHUNK + TAG_LEN;
+
+#[derive(Clone)]
+pub struct LibraryKey {
+    key: [u8; 32],
+}
+
+impl LibraryKey {
+    /// Read a library key.
+    pub fn load(path: &Path) -> Result<Self> {
+        let key = std::fs::read(path)
+            .context(format!("Failed to read library key: {}", path.display()))?;
+        let key = key
+            .try_into()
+            .map_err(|_| anyhow!("Library key must be 32 bytes: {}", path.display()))?;
+        Ok(Self { key })
+    }
+
+    /// Read a library key, creating one if `path` doesn't exist yet.
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if path.exists() {
+            return Self::load(path);
+        }
+        let key: [u8; 32] = rand::random();
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .context(format!("Failed to create library key: {}", path.display()))?;
+        file.write_all(&key)?;
+        Ok(Self { key })
+    }
+
+    fn aead(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.key).unwrap())
+    }
+
+    /// Encrypt everything read from `reader` into `writer`. `name` is the
+    /// file's path within its title, which decrypting must give again.
+    pub fn seal(&self, mut reader: impl Read, mut writer: impl Write, name: &str) -> Result<()> {
+        let prefix: [u8; PREFIX_LEN] = rand::random();
+        writer.write_all(MAGIC)?;
+        writer.write_all(&prefix)?;
+        let key = self.aead();
+        let mut chunk = read_chunk(&mut reader)?;
+        let mut index = 0;
+        loop {
+            let next = match chunk.len() as u64 == CHUNK {
+                true => read_chunk(&mut reader)?,
+                false => Vec::new(),
+            };
+            let last = next.is_empty();
+            key.seal_in_place_append_tag(
+                nonce(&prefix, index, last),
+                Aad::from(name.as_bytes()),
+                &mut chunk,
+            )
+            .map_err(|_| anyhow!("Failed to encrypt {}", name))?;
+            writer.write_all(&chunk)?;
+            if last {
+                writer.flush()?;
+                return Ok(());
+            }
+            chunk = next;
+            index += 1;
+        }
+    }
+
+    /// Decrypt the bytes in `range` of an encrypted file.
+    pub fn open_range(
+        &self,
+        mut sealed: impl Read + Seek,
+        name: &str,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>> {
+        let sealed_len = sealed.seek(SeekFrom::End(0))?;
+        let len = plain_len(sealed_len)?;
+        if range.start > range.end || range.end > len {
+            bail!("Range {:?} is outside {} ({} bytes)", range, name, len);
+        }
+        let mut header = [0; HEADER_LEN as usize];
+        sealed.seek(SeekFrom::Start(0))?;
+        sealed.read_exact(&mut header)?;
+        if !header.starts_with(MAGIC) {
+            bail!("{} is not encrypted", name);
+        }
+        let prefix: [u8; PREFIX_LEN] = header[MAGIC.len()..].try_into().unwrap();
+
+        let body = sealed_len - HEADER_LEN;
+        let chunks = body.div_ceil(SEALED_CHUNK).max(1);
+        let first = range.start / CHUNK;
+        let last = match range.is_empty() {
+            true => first,
+            false => (range.end - 1) / CHUNK,
+        }
+        .min(chunks - 1);
+
+        let key = self.aead();
+        let mut plain = Vec::new();
+        sealed.seek(SeekFrom::Start(HEADER_LEN + first * SEALED_CHUNK))?;
+        for index in first..=last {
+            let mut chunk = vec![0; (body - index * SEALED_CHUNK).min(SEALED_CHUNK) as usize];
+            sealed.read_exact(&mut chunk)?;
+            let opened = key
+                .open_in_place(
+                    nonce(&prefix, index as u32, index + 1 == chunks),
+                    Aad::from(name.as_bytes()),
+                    &mut chunk,
+                )
+                .map_err(|_| anyhow!("{} is corrupt or was encrypted with another key", name))?;
+            plain.extend_from_slice(opened);
+        }
+        let offset = first * CHUNK;
+        Ok(plain[(range.start - offset) as usize..(range.end - offset) as usize].to_vec())
+    }
+
+    /// Decrypt the whole of the encrypted file at `path`, stored as `name`.
+    pub fn open_file(&self, path: &Path, name: &str) -> Result<Vec<u8>> {
+        let file = File::open(path).context(format!("Failed to open {}", path.display()))?;
+        let len = plain_len(file.metadata()?.len())?;
+        self.open_range(file, name, 0..len)
+    }
+
+    /// Encrypt every file of a prepared title in place, returning how many
+    /// were encrypted. Files that already are, and the journal, are left alone.
+    pub fn encrypt_dir(&self, dir: &Path) -> Result<usize> {
+        let mut encrypted = 0;
+        for path in files(dir)? {
+            let name = path
+                .strip_prefix(dir)?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if name == JOURNAL_FILENAME || is_encrypted(&path) {
+                continue;
+            }
+
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            let temp = path.with_file_name(format!(".{}.encrypting", file_name));
+            let reader = File::open(&path).context(format!("Failed to read {}", path.display()))?;
+            let writer =
+                File::create(&temp).context(format!("Failed to write {}", temp.display()))?;
+            self.seal(
+                std::io::BufReader::new(reader),
+                std::io::BufWriter::new(writer),
+                &name,
+            )
+            .context(format!("Failed to encrypt {}", path.display()))?;
+            std::fs::rename(&temp, &path)?;
+            encrypted += 1;
+        }
+        Ok(encrypted)
+    }
+}
+
+fn nonce(prefix: &[u8; PREFIX_LEN], index: u32, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    nonce[PREFIX_LEN..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Up to one chunk of plaintext; shorter only at the end of the input.
+fn read_chunk(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(CHUNK as usize);
+    reader.take(CHUNK).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Regular files under `dir`, in any subdirectory.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).context(format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files.extend(self::files(&entry.path())?);
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Length of the plaintext of an encrypted file `sealed_len` bytes long.
+pub fn plain_len(sealed_len: u64) -> Result<u64> {
+    let body = sealed_len
+        .checked_sub(HEADER_LEN)
+        .filter(|body| *body >= TAG_LEN && !(1..TAG_LEN).contains(&(body % SEALED_CHUNK)))
+        .context("Encrypted file is truncated")?;
+    Ok(body - body.div_ceil(SEALED_CHUNK) * TAG_LEN)
+}
+
+/// Whether the file at `path` was encrypted by [`LibraryKey::encrypt_dir`].
+pub fn is_encrypted(path: &Path) -> bool {
+    let mut magic = [0; MAGIC.len()];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|()| magic == MAGIC)
+}
+
+/// The single byte range asked for by a `Range` header, or `None` if it
+/// can't be satisfied.
+fn byte_range(header: &str, len: u64) -> Option<Range<u64>> {
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => len.saturating_sub(suffix.parse().ok()?)..len,
+        (start, "") => start.parse().ok()?..len,
+        (start, end) => start.parse().ok()?..end.parse::<u64>().ok()?.saturating_add(1).min(len),
+    };
+    (range.start < range.end).then_some(range)
+}
+
+/// Respond with the decrypted contents of the encrypted file at `path`,
+/// or the part of it a `Range` header asks for.
+pub(crate) async fn respond(
+    key: LibraryKey,
+    path: PathBuf,
+    name: String,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map(String::from);
+    tokio::task::spawn_blocking(move || {
+        let file = File::open(&path).map_err(|_| StatusCode::NOT_FOUND)?;
+        let len = file
+            .metadata()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+            .and_then(|metadata| {
+                plain_len(metadata.len()).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+            })?;
+        let (status, range) = match range {
+            None => (StatusCode::OK, 0..len),
+            Some(range) => match byte_range(&range, len) {
+                Some(range) => (StatusCode::PARTIAL_CONTENT, range),
+                None => {
+                    let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+                    response.headers_mut().insert(
+                        header::CONTENT_RANGE,
+                        HeaderValue::from_str(&format!("bytes */{}", len)).unwrap(),
+                    );
+                    return Ok(response);
+                }
+            },
+        };
+
+        let body = key
+            .open_range(std::io::BufReader::new(file), &name, range.clone())
+            .map_err(|err| {
+                eprintln!("Failed to decrypt {}: {:#}", path.display(), err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        let mut response = (status, body).into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        if status == StatusCode::PARTIAL_CONTENT {
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", range.start, range.end - 1, len))
+                    .unwrap(),
+            );
+        }
+        Ok(response)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn seal(key: &LibraryKey, plain: &[u8], name: &str) -> Vec<u8> {
+        let mut sealed = Vec::new();
+        key.seal(plain, &mut sealed, name).unwrap();
+        sealed
+    }
+
+    #[test]
+    fn round_trips_ranges_and_detects_tampering() {
+        let key = LibraryKey { key: [7; 32] };
+        let plain: Vec<u8> = (0..CHUNK * 2 + 100).map(|i| i as u8).collect();
+        let sealed = seal(&key, &plain, "chunk-0-1.m4s");
+        assert_eq!(plain_len(sealed.len() as u64).unwrap(), plain.len() as u64);
+        let open = |sealed: &[u8], name: &str, range: Range<u64>| {
+            key.open_range(Cursor::new(sealed), name, range)
+        };
+
+        let len = plain.len() as u64;
+        assert_eq!(open(&sealed, "chunk-0-1.m4s", 0..len).unwrap(), plain);
+        let across = CHUNK - 10..CHUNK * 2 + 5;
+        assert_eq!(
+            open(&sealed, "chunk-0-1.m4s", across.clone()).unwrap(),
+            &plain[across.start as usize..across.end as usize]
+        );
+        for plain in [&b""[..], &[1; CHUNK as usize][..]] {
+            let sealed = seal(&key, plain, "a");
+            assert_eq!(plain_len(sealed.len() as u64).unwrap(), plain.len() as u64);
+            assert_eq!(open(&sealed, "a", 0..plain.len() as u64).unwrap(), plain);
+        }
+
+        // Renamed, altered and truncated files all fail
+        assert!(open(&sealed, "chunk-0-2.m4s", 0..10).is_err());
+        let mut altered = sealed.clone();
+        altered[HEADER_LEN as usize + 5] ^= 1;
+        assert!(open(&altered, "chunk-0-1.m4s", 0..10).is_err());
+        let truncated = &sealed[..(HEADER_LEN + SEALED_CHUNK * 2) as usize];
+        assert!(open(truncated, "chunk-0-1.m4s", CHUNK..CHUNK + 10).is_err());
+        let other = LibraryKey { key: [8; 32] };
+        assert!(
+            other
+                .open_range(Cursor::new(&sealed), "chunk-0-1.m4s", 0..10)
+                .is_err()
+        );
+
+        assert_eq!(byte_range("bytes=2-5", 10), Some(2..6));
+        assert_eq!(byte_range("bytes=7-", 10), Some(7..10));
+        assert_eq!(byte_range("bytes=-3", 10), Some(7..10));
+        assert_eq!(byte_range("bytes=4-100", 10), Some(4..10));
+        assert_eq!(byte_range("bytes=10-", 10), None);
+    }
+}
@@ -0,0 +1,332 @@
+//! Portable copies of a library's catalog, for moving it to another server
+//! or merging two libraries.
+//!
+//! `preparer library export` writes a directory holding `catalog.json`,
+//! with the users, their grants and settings, collections and where each
+//! viewer left off, and under `titles/` each title's metadata and artwork.
+//! Media isn't included; it's moved separately. `preparer library import`
+//! merges such a bundle into a library, leaving what's already there as it
+//! is: users, collections and metadata are only added, and positions only
+//! replaced by ones saved more recently.
+
+use crate::library::{Catalog, Metadata};
+use crate::upload::valid_title;
+use anyhow::{Context, Result, bail};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Name of the bundle's catalog description.
+pub const CATALOG_JSON: &str = "catalog.json";
+/// Version of the bundle's layout, bumped when importers can't read older ones.
+const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Bundle {
+    pub version: u32,
+    pub titles: Vec<TitleEntry>,
+    pub users: Vec<UserEntry>,
+    pub collections: Vec<CollectionEntry>,
+    pub progress: Vec<ProgressEntry>,
+}
+
+/// A title's descriptive files, kept under `titles/<name>/` in the bundle.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TitleEntry {
+    pub name: String,
+    pub metadata: Option<Metadata>,
+    /// Artwork file names, as in the title's directory
+    pub poster: Option<String>,
+    pub backdrop: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserEntry {
+    pub name: String,
+    /// Argon2 hash, so they sign in with the same password
+    pub password_hash: Option<String>,
+    pub restricted: bool,
+    pub max_rating: Option<String>,
+    pub titles: Vec<String>,
+    pub genres: Vec<String>,
+    pub collections: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CollectionEntry {
+    pub name: String,
+    pub titles: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProgressEntry {
+    pub user: String,
+    pub title: String,
+    pub position_secs: f64,
+    pub duration_secs: f64,
+    /// Seconds since the Unix epoch
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub titles: usize,
+    /// Titles in the bundle whose media isn't in the library
+    pub missing_titles: Vec<String>,
+    pub users: usize,
+    pub collections: usize,
+    pub progress: usize,
+}
+
+/// Whether `name` is a plain file name, safe to join to a directory.
+fn plain_file_name(name: &str) -> bool {
+    Path::new(name).file_name().is_some_and(|file| file == name) && !name.starts_with('.')
+}
+
+impl Catalog {
+    /// Everything about the catalog a bundle carries, but the title files.
+    fn bundle(&self) -> Result<Bundle> {
+        let titles = self
+            .titles()?
+            .into_iter()
+            .map(|title| TitleEntry {
+                name: title.name,
+                metadata: title.metadata,
+                poster: title.poster,
+                backdrop: title.backdrop,
+            })
+            .collect();
+        let mut users = Vec::new();
+        for user in self.users()? {
+            let password_hash = self.conn.query_row(
+                "SELECT password_hash FROM users WHERE name = ?1",
+                params![user.name],
+                |row| row.get(0),
+            )?;
+            users.push(UserEntry {
+                name: user.name,
+                password_hash,
+                restricted: user.restricted,
+                max_rating: user.max_rating,
+                titles: user.titles,
+                genres: user.genres,
+                collections: user.collections,
+            });
+        }
+        let collections = self
+            .collections()?
+            .into_iter()
+            .map(|collection| CollectionEntry {
+                name: collection.name,
+                titles: collection.titles,
+            })
+            .collect();
+        let mut statement = self.conn.prepare(
+            "SELECT user, title, position_secs, duration_secs, updated_at FROM progress
+             ORDER BY user, title",
+        )?;
+        let progress = statement
+            .query_map([], |row| {
+                Ok(ProgressEntry {
+                    user: row.get(0)?,
+                    title: row.get(1)?,
+                    position_secs: row.get(2)?,
+                    duration_secs: row.get(3)?,
+                    updated_at: row.get::<_, i64>(4)? as u64,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Bundle {
+            version: BUNDLE_VERSION,
+            titles,
+            users,
+            collections,
+            progress,
+        })
+    }
+
+    /// Merge the catalog parts of `bundle`, keeping what's already here.
+    fn merge(&mut self, bundle: &Bundle, summary: &mut ImportSummary) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for user in &bundle.users {
+            summary.users += tx.execute(
+                "INSERT INTO users (name, password_hash, restricted, max_rating)
+                 VALUES (?1, ?2, ?3, ?4) ON CONFLICT (name) DO NOTHING",
+                params![
+                    user.name,
+                    user.password_hash,
+                    user.restricted,
+                    user.max_rating
+                ],
+            )?;
+        }
+        for collection in &bundle.collections {
+            summary.collections += tx.execute(
+                "INSERT INTO collections (name) VALUES (?1) ON CONFLICT (name) DO NOTHING",
+                params![collection.name],
+            )?;
+            for title in &collection.titles {
+                tx.execute(
+                    "INSERT OR IGNORE INTO collection_titles (collection_id, title)
+                     SELECT id, ?2 FROM collections WHERE name = ?1",
+                    params![collection.name, title],
+                )?;
+            }
+        }
+        // Grants go in once the collections they name exist
+        for user in &bundle.users {
+            let grants = [
+                ("title", &user.titles),
+                ("genre", &user.genres),
+                ("collection", &user.collections),
+            ];
+            for (kind, values) in grants {
+                for value in values {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO grants (user_id, kind, value)
+                         SELECT id, ?2, ?3 FROM users WHERE name = ?1",
+                        params![user.name, kind, value],
+                    )?;
+                }
+            }
+        }
+        for progress in &bundle.progress {
+            summary.progress += tx.execute(
+                "INSERT INTO progress (user, title, position_secs, duration_secs, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (user, title) DO UPDATE SET position_secs = ?3,
+                    duration_secs = ?4, updated_at = ?5
+                 WHERE updated_at < ?5",
+                params![
+                    progress.user,
+                    progress.title,
+                    progress.position_secs,
+                    progress.duration_secs,
+                    progress.updated_at as i64
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Write the catalog of `library` and its titles' metadata and artwork to
+/// a new bundle directory at `out`.
+pub fn export(library: &Path, out: &Path) -> Result<Bundle> {
+    let catalog = Catalog::open_existing(library)?.context(format!(
+        "No catalog in {}; run `preparer library scan` first",
+        library.display()
+    ))?;
+    if out.exists() {
+        bail!("{} already exists", out.display());
+    }
+    let bundle = catalog.bundle()?;
+    for title in &bundle.titles {
+        let dir = out.join("titles").join(&title.name);
+        std::fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+        if let Some(metadata) = &title.metadata {
+            metadata.write(&dir)?;
+        }
+        for artwork in title.poster.iter().chain(&title.backdrop) {
+            let from = library.join(&title.name).join(artwork);
+            std::fs::copy(&from, dir.join(artwork))
+                .context(format!("Failed to copy {}", from.display()))?;
+        }
+    }
+    let path = out.join(CATALOG_JSON);
+    std::fs::write(&path, serde_json::to_vec_pretty(&bundle)?)
+        .context(format!("Failed to write {}", path.display()))?;
+    Ok(bundle)
+}
+
+/// Merge the bundle at `bundle_dir` into `library`, then scan it so the
+/// catalog picks up the metadata and artwork.
+pub fn import(bundle_dir: &Path, library: &Path) -> Result<ImportSummary> {
+    let path = bundle_dir.join(CATALOG_JSON);
+    let bundle: Bundle = serde_json::from_slice(
+        &std::fs::read(&path).context(format!("Failed to read {}", path.display()))?,
+    )
+    .context(format!("{} isn't a catalog bundle", path.display()))?;
+    if bundle.version > BUNDLE_VERSION {
+        bail!(
+            "The bundle is version {}, newer than this release reads ({})",
+            bundle.version,
+            BUNDLE_VERSION
+        );
+    }
+
+    let mut summary = ImportSummary::default();
+    for title in &bundle.titles {
+        if !valid_title(&title.name) {
+            bail!("Invalid title in the bundle: {:?}", title.name);
+        }
+        let dir = library.join(&title.name);
+        if !dir.is_dir() {
+            summary.missing_titles.push(title.name.clone());
+            continue;
+        }
+        let from = bundle_dir.join("titles").join(&title.name);
+        let mut added = false;
+        if Metadata::read(&dir)?.is_none()
+            && let Some(metadata) = &title.metadata
+        {
+            metadata.write(&dir)?;
+            added = true;
+        }
+        for artwork in title.poster.iter().chain(&title.backdrop) {
+            if !plain_file_name(artwork) {
+                bail!("Invalid artwork in the bundle: {:?}", artwork);
+            }
+            if !dir.join(artwork).exists() && from.join(artwork).is_file() {
+                std::fs::copy(from.join(artwork), dir.join(artwork))
+                    .context(format!("Failed to copy {} artwork", title.name))?;
+                added = true;
+            }
+        }
+        summary.titles += usize::from(added);
+    }
+
+    let mut catalog = Catalog::open(&Catalog::default_path(library))?;
+    catalog.merge(&bundle, &mut summary)?;
+    catalog.scan(library, false)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::GrantKind;
+
+    #[test]
+    fn merges_without_overwriting() {
+        let mut old = Catalog::open(Path::new(":memory:")).unwrap();
+        old.add_user("ana", Some("secret"), true).unwrap();
+        old.create_collection("Kids").unwrap();
+        old.add_to_collection("Kids", &[String::from("Cartoon")])
+            .unwrap();
+        old.grant("ana", GrantKind::Collection, "Kids").unwrap();
+        old.save_progress("ana", "Cartoon", 120.0, 600.0).unwrap();
+        let bundle = old.bundle().unwrap();
+        assert_eq!(bundle.users[0].collections, ["Kids"]);
+
+        let mut new = Catalog::open(Path::new(":memory:")).unwrap();
+        new.add_user("bo", None, false).unwrap();
+        new.save_progress("ana", "Cartoon", 300.0, 600.0).unwrap();
+        let mut summary = ImportSummary::default();
+        new.merge(&bundle, &mut summary).unwrap();
+        assert_eq!((summary.users, summary.collections), (1, 1));
+
+        // The password hash came along
+        let ana = new.authenticate("ana", "secret").unwrap().unwrap();
+        assert!(ana.restricted);
+        assert!(ana.can_watch("Cartoon", &[], None));
+        assert!(new.user("bo").unwrap().is_some());
+        // The newer position on this side wins
+        assert_eq!(summary.progress, 0);
+        assert_eq!(new.progress("ana").unwrap()[0].position_secs, 300.0);
+
+        assert!(plain_file_name("poster.jpg"));
+        assert!(!plain_file_name("../poster.jpg"));
+    }
+}
@@ -0,0 +1,214 @@
+//! Feeds of recently prepared titles, for subscribing to what's new.
+//!
+//! `GET /feed.xml` is RSS 2.0 and `GET /feed.json` is JSON Feed 1.1; both list
+//! the newest titles the viewer may watch, by when their manifest was written,
+//! with links to their player pages.
+
+use crate::artwork::{self, POSTER};
+use crate::library::MANIFEST;
+use crate::serve::{AppState, encode_segment, escape_html, titles};
+use crate::users::User;
+use axum::Extension;
+use axum::extract::State;
+use axum::http::{HeaderMap, header};
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many titles a feed lists.
+const LIMIT: usize = 50;
+
+struct Entry {
+    name: String,
+    label: String,
+    overview: Option<String>,
+    prepared: SystemTime,
+    poster: Option<String>,
+}
+
+/// Where the server was reached at, as seen by the client.
+pub(crate) fn base_url(headers: &HeaderMap) -> String {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost");
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+        .unwrap_or("http");
+    format!("{}://{}", scheme, host)
+}
+
+fn entries(state: &AppState, user: Option<&User>) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = titles(&state.library)
+        .into_iter()
+        .filter(|(name, metadata)| {
+            let genres = metadata.as_ref().map_or(&[][..], |m| &m.genres);
+            let rating = metadata.as_ref().and_then(|m| m.rating.as_deref());
+            user.is_none_or(|user| user.can_watch(name, genres, rating))
+        })
+        .filter_map(|(name, metadata)| {
+            let dir = state.library.join(&name);
+            let prepared = std::fs::metadata(dir.join(MANIFEST))
+                .ok()?
+                .modified()
+                .ok()?;
+            let poster = [artwork::sized_name(POSTER, 342), String::from(POSTER)]
+                .into_iter()
+                .find(|poster| dir.join(poster).is_file());
+            Some(Entry {
+                label: metadata
+                    .as_ref()
+                    .map_or(name.clone(), |metadata| metadata.label()),
+                overview: metadata.and_then(|metadata| metadata.overview),
+                name,
+                prepared,
+                poster,
+            })
+        })
+        .collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.prepared));
+    entries.truncate(LIMIT);
+    entries
+}
+
+/// RFC 2822 dates, as RSS wants them: `Tue, 10 Jun 2003 04:00:00 GMT`.
+fn rfc2822(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = (secs / 86400) as i64;
+    let seconds = secs % 86400;
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+pub async fn rss(
+    State(state): State<AppState>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
+) -> Response {
+    let base = base_url(&headers);
+    let entries = entries(&state, user.as_deref());
+    let items: String = entries
+        .iter()
+        .map(|entry| {
+            let link = format!("{}/watch/{}", base, encode_segment(&entry.name));
+            let description = entry
+                .overview
+                .as_ref()
+                .map_or(String::new(), |overview| {
+                    format!("      <description>{}</description>\n", escape_html(overview))
+                });
+            let enclosure = entry.poster.as_ref().map_or(String::new(), |poster| {
+                format!(
+                    "      <enclosure url=\"{}/media/{}/{}\" length=\"0\" type=\"image/jpeg\"/>\n",
+                    base,
+                    encode_segment(&entry.name),
+                    poster
+                )
+            });
+            format!(
+                "    <item>\n      <title>{}</title>\n      <link>{}</link>\n      <guid>{}</guid>\n      <pubDate>{}</pubDate>\n{}{}    </item>\n",
+                escape_html(&entry.label),
+                escape_html(&link),
+                escape_html(&link),
+                rfc2822(entry.prepared),
+                description,
+                enclosure
+            )
+        })
+        .collect();
+    let rss = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<rss version=\"2.0\">\n  <channel>\n    <title>movieshare</title>\n    <link>{}/</link>\n    <description>Recently prepared titles</description>\n{}  </channel>\n</rss>\n",
+        escape_html(&base),
+        items
+    );
+    ([(header::CONTENT_TYPE, "application/rss+xml")], rss).into_response()
+}
+
+pub async fn json_feed(
+    State(state): State<AppState>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
+) -> Response {
+    let base = base_url(&headers);
+    let items: Vec<_> = entries(&state, user.as_deref())
+        .into_iter()
+        .map(|entry| {
+            let link = format!("{}/watch/{}", base, encode_segment(&entry.name));
+            let mut item = json!({
+                "id": link,
+                "url": link,
+                "title": entry.label,
+                "content_text": entry.overview.unwrap_or_default(),
+                "date_published": humantime::format_rfc3339_seconds(entry.prepared).to_string(),
+            });
+            if let Some(poster) = entry.poster {
+                item["image"] = json!(format!(
+                    "{}/media/{}/{}",
+                    base,
+                    encode_segment(&entry.name),
+                    poster
+                ));
+            }
+            item
+        })
+        .collect();
+    let feed = json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": "movieshare",
+        "home_page_url": format!("{}/", base),
+        "feed_url": format!("{}/feed.json", base),
+        "items": items,
+    });
+    (
+        [(header::CONTENT_TYPE, "application/feed+json")],
+        feed.to_string(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_rss_dates() {
+        assert_eq!(rfc2822(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            rfc2822(UNIX_EPOCH + Duration::from_secs(1055217600)),
+            "Tue, 10 Jun 2003 04:00:00 GMT"
+        );
+        assert_eq!(
+            rfc2822(UNIX_EPOCH + Duration::from_secs(1709164799)),
+            "Wed, 28 Feb 2024 23:59:59 GMT"
+        );
+    }
+}
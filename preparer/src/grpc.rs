@@ -0,0 +1,145 @@
+//! gRPC front end to the job queue, for driving preparations from other programs.
+//!
+//! Clients don't sign in, so the service only listens on loopback addresses.
+
+use anyhow::{Context, Result};
+use futures::{Stream, StreamExt, future};
+use movieshare_core::queue::{JobQueue, JobState, QueueEvent, QueuedJob};
+use movieshare_core::{EncodingProfile, JobSpec, Progress};
+use proto::preparer_server::{Preparer, PreparerServer};
+use std::pin::Pin;
+use tokio::net::TcpListener;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("movieshare.v1");
+}
+
+pub struct PreparerService {
+    queue: JobQueue,
+}
+
+fn job_status(job: &QueuedJob) -> proto::JobStatus {
+    let (state, error) = match &job.state {
+        JobState::Queued => (proto::JobState::Queued, String::new()),
+        JobState::Running => (proto::JobState::Running, String::new()),
+        JobState::Completed => (proto::JobState::Completed, String::new()),
+        JobState::Failed { error } => (proto::JobState::Failed, error.clone()),
+        JobState::Cancelled => (proto::JobState::Cancelled, String::new()),
+    };
+    proto::JobStatus {
+        job_id: job.id,
+        priority: job.priority,
+        input: job.spec.input.display().to_string(),
+        output: job.spec.output.display().to_string(),
+        state: state.into(),
+        error,
+        progress: job.progress.as_ref().map(progress),
+    }
+}
+
+fn progress(progress: &Progress) -> proto::Progress {
+    proto::Progress {
+        fraction: progress.fraction,
+        frames: progress.frames,
+        fps: progress.fps,
+    }
+}
+
+impl PreparerService {
+    fn job(&self, id: u64) -> Result<QueuedJob, Status> {
+        self.queue
+            .get(id)
+            .ok_or_else(|| Status::not_found(format!("No such job: {}", id)))
+    }
+}
+
+#[tonic::async_trait]
+impl Preparer for PreparerService {
+    async fn submit_job(
+        &self,
+        request: Request<proto::SubmitJobRequest>,
+    ) -> Result<Response<proto::SubmitJobResponse>, Status> {
+        let request = request.into_inner();
+        let mut spec = JobSpec::new(request.input, request.output);
+        if !request.profile_json.is_empty() {
+            spec.profile = EncodingProfile::from_json(&request.profile_json)
+                .map_err(|err| Status::invalid_argument(format!("Invalid profile: {:#}", err)))?;
+        }
+
+        let job_id = self
+            .queue
+            .submit(spec, request.priority)
+            .map_err(|err| Status::invalid_argument(format!("{:#}", err)))?;
+        Ok(Response::new(proto::SubmitJobResponse { job_id }))
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<proto::GetStatusRequest>,
+    ) -> Result<Response<proto::JobStatus>, Status> {
+        let job = self.job(request.into_inner().job_id)?;
+        Ok(Response::new(job_status(&job)))
+    }
+
+    async fn cancel_job(
+        &self,
+        request: Request<proto::CancelJobRequest>,
+    ) -> Result<Response<proto::CancelJobResponse>, Status> {
+        let id = request.into_inner().job_id;
+        self.job(id)?;
+        self.queue
+            .cancel(id)
+            .map_err(|err| Status::failed_precondition(format!("{:#}", err)))?;
+        Ok(Response::new(proto::CancelJobResponse {}))
+    }
+
+    type StreamEventsStream = Pin<Box<dyn Stream<Item = Result<proto::JobEvent, Status>> + Send>>;
+
+    async fn stream_events(
+        &self,
+        request: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let filter = request.into_inner().job_id;
+        if let Some(id) = filter {
+            self.job(id)?;
+        }
+
+        let queue = self.queue.clone();
+        let events = self.queue.subscribe().filter_map(move |event| {
+            let (job_id, event) = match event {
+                QueueEvent::StateChanged { id, .. } => (
+                    id,
+                    queue
+                        .get(id)
+                        .map(|job| proto::job_event::Event::StateChanged(job_status(&job))),
+                ),
+                QueueEvent::Progress { id, progress: p } => {
+                    (id, Some(proto::job_event::Event::Progress(progress(&p))))
+                }
+                QueueEvent::Warning { id, message } => {
+                    (id, Some(proto::job_event::Event::Warning(message)))
+                }
+            };
+            let wanted = filter.is_none_or(|id| id == job_id);
+            future::ready(event.filter(|_| wanted).map(|event| {
+                Ok(proto::JobEvent {
+                    job_id,
+                    event: Some(event),
+                })
+            }))
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+/// Serve the job queue over gRPC until the process is stopped.
+pub async fn serve(queue: JobQueue, listener: TcpListener) -> Result<()> {
+    let addr = listener.local_addr()?;
+    tonic::transport::Server::builder()
+        .add_service(PreparerServer::new(PreparerService { queue }))
+        .serve_with_incoming(TcpIncoming::from(listener))
+        .await
+        .context(format!("gRPC server on {} failed", addr))
+}
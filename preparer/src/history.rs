@@ -0,0 +1,313 @@
+//! Every encode run, kept in the catalog: to predict how long the next one
+//! will take and how big it will come out, and for `preparer history` to
+//! show when a GStreamer upgrade or a preset change made things slower,
+//! bigger or broken.
+
+use crate::library::Catalog;
+use crate::usage::ResourceUsage;
+use anyhow::{Context, Result};
+use rusqlite::params;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many of the latest runs predictions go by, so they follow upgrades
+/// and new hardware.
+const RECENT_RUNS: u32 = 20;
+
+/// How much of the source's start, middle and end [`source_hash`] reads.
+const HASHED_SPAN: u64 = 1024 * 1024;
+
+/// One encode, finished or not.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EncodeRun {
+    /// Seconds since the Unix epoch
+    pub finished_at: u64,
+    /// Name of the title's directory
+    pub title: String,
+    pub input: String,
+    /// From [`source_hash`], to tell whether two runs had the same source
+    pub source_hash: Option<String>,
+    /// The encoding profile, as JSON
+    pub settings: String,
+    pub gstreamer: String,
+    /// Encoder implementation, from [`movieshare_core::EncodingProfile::encoder_backend`]
+    pub encoder: String,
+    pub preset: u32,
+    /// Rungs encoded rather than copied
+    pub encoded_rungs: u32,
+    /// Length of the source
+    pub source_secs: f64,
+    /// How long the encode took
+    pub wall_secs: f64,
+    /// What the plan estimated the output would weigh
+    pub estimated_bytes: Option<u64>,
+    pub output_bytes: u64,
+    /// Average bitrate each rung came out at, in kb/s, by its target in MB/s
+    pub bitrates: BTreeMap<u32, f64>,
+    /// `prepared`, `cancelled` or `failed`
+    pub outcome: String,
+    pub error: Option<String>,
+    /// What the run cost the machine; unknown for runs from before it was measured
+    pub usage: Option<ResourceUsage>,
+}
+
+impl EncodeRun {
+    /// Seconds of source encoded per second, for each rung.
+    pub fn speed(&self) -> Option<f64> {
+        (self.wall_secs > 0.0 && self.encoded_rungs > 0)
+            .then(|| self.source_secs * self.encoded_rungs as f64 / self.wall_secs)
+    }
+}
+
+/// What past runs say to expect of the next one.
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectation {
+    /// Seconds of source encoded per second, for each rung
+    pub speed: Option<f64>,
+    /// How the output's size came out against the estimate from bitrates
+    pub size_ratio: Option<f64>,
+}
+
+/// Which runs `preparer history` lists.
+#[derive(Debug, Default)]
+pub struct RunFilter {
+    pub title: Option<String>,
+    pub since: Option<SystemTime>,
+    pub failed_only: bool,
+    pub limit: u32,
+}
+
+impl Catalog {
+    pub fn record_run(&mut self, run: &EncodeRun) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO encode_runs (finished_at, encoder, preset, encoded_rungs, source_secs,
+                wall_secs, estimated_bytes, output_bytes, title, input, source_hash, settings,
+                gstreamer, bitrates, outcome, error, cpu_secs, peak_rss_bytes, gpu_percent,
+                bytes_written)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                ?18, ?19, ?20)",
+            params![
+                run.finished_at as i64,
+                run.encoder,
+                run.preset,
+                run.encoded_rungs,
+                run.source_secs,
+                run.wall_secs,
+                run.estimated_bytes.map(|bytes| bytes as i64),
+                run.output_bytes as i64,
+                run.title,
+                run.input,
+                run.source_hash,
+                run.settings,
+                run.gstreamer,
+                serde_json::to_string(&run.bitrates)?,
+                run.outcome,
+                run.error,
+                run.usage.as_ref().map(|usage| usage.cpu_secs),
+                run.usage.as_ref().map(|usage| usage.peak_rss_bytes as i64),
+                run.usage.as_ref().and_then(|usage| usage.gpu_percent),
+                run.usage
+                    .as_ref()
+                    .and_then(|usage| usage.bytes_written)
+                    .map(|bytes| bytes as i64),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// What recent runs with the same encoder and preset say to expect.
+    pub fn expectation(&self, encoder: &str, preset: u32) -> Result<Expectation> {
+        let speed = self.conn.query_row(
+            "SELECT SUM(source_secs * encoded_rungs) / SUM(wall_secs) FROM (
+                SELECT source_secs, encoded_rungs, wall_secs FROM encode_runs
+                WHERE encoder = ?1 AND preset = ?2 AND encoded_rungs > 0 AND source_secs > 0
+                    AND wall_secs > 0
+                    AND outcome = 'prepared'
+                ORDER BY finished_at DESC, id DESC LIMIT ?3
+             )",
+            params![encoder, preset, RECENT_RUNS],
+            |row| row.get(0),
+        )?;
+        let size_ratio = self.conn.query_row(
+            "SELECT AVG(CAST(output_bytes AS REAL) / estimated_bytes) FROM (
+                SELECT output_bytes, estimated_bytes FROM encode_runs
+                WHERE estimated_bytes > 0 AND outcome = 'prepared'
+                ORDER BY finished_at DESC, id DESC LIMIT ?1
+             )",
+            params![RECENT_RUNS],
+            |row| row.get(0),
+        )?;
+        Ok(Expectation { speed, size_ratio })
+    }
+
+    /// Runs matching `filter`, latest first.
+    pub fn runs(&self, filter: &RunFilter) -> Result<Vec<EncodeRun>> {
+        let since = filter.since.map_or(0, |since| {
+            since
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs() as i64)
+        });
+        let mut statement = self.conn.prepare(
+            "SELECT finished_at, title, input, source_hash, settings, gstreamer, encoder, preset,
+                encoded_rungs, source_secs, wall_secs, estimated_bytes, output_bytes, bitrates,
+                outcome, error, cpu_secs, peak_rss_bytes, gpu_percent, bytes_written
+             FROM encode_runs
+             WHERE finished_at >= ?1 AND (?2 IS NULL OR title = ?2)
+                AND (NOT ?3 OR outcome = 'failed')
+             ORDER BY finished_at DESC, id DESC LIMIT ?4",
+        )?;
+        let runs = statement
+            .query_map(
+                params![since, filter.title, filter.failed_only, filter.limit],
+                |row| {
+                    let bitrates: Option<String> = row.get(13)?;
+                    Ok(EncodeRun {
+                        finished_at: row.get::<_, i64>(0)? as u64,
+                        title: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                        input: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                        source_hash: row.get(3)?,
+                        settings: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                        gstreamer: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                        encoder: row.get(6)?,
+                        preset: row.get(7)?,
+                        encoded_rungs: row.get(8)?,
+                        source_secs: row.get(9)?,
+                        wall_secs: row.get(10)?,
+                        estimated_bytes: row.get::<_, Option<i64>>(11)?.map(|bytes| bytes as u64),
+                        output_bytes: row.get::<_, i64>(12)? as u64,
+                        bitrates: bitrates
+                            .and_then(|json| serde_json::from_str(&json).ok())
+                            .unwrap_or_default(),
+                        outcome: row.get(14)?,
+                        error: row.get(15)?,
+                        usage: match row.get::<_, Option<f64>>(16)? {
+                            Some(cpu_secs) => Some(ResourceUsage {
+                                cpu_secs,
+                                peak_rss_bytes: row.get::<_, Option<i64>>(17)?.unwrap_or(0) as u64,
+                                gpu_percent: row.get(18)?,
+                                bytes_written: row.get::<_, Option<i64>>(19)?.map(|b| b as u64),
+                            }),
+                            None => None,
+                        },
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(runs)
+    }
+}
+
+/// SHA-256 of a file's size and its first, middle and last megabyte: enough
+/// to tell sources apart without reading all of a feature film.
+pub fn source_hash(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let size = file.metadata()?.len();
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+    let mut buffer = vec![0; HASHED_SPAN as usize];
+    for start in [0, size / 2, size.saturating_sub(HASHED_SPAN)] {
+        file.seek(SeekFrom::Start(start))?;
+        let read = file.by_ref().take(HASHED_SPAN).read_to_end(&mut buffer)?;
+        hasher.update(&buffer[buffer.len() - read..]);
+        buffer.clear();
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expects_what_past_runs_did() {
+        let mut catalog = Catalog::open(std::path::Path::new(":memory:")).unwrap();
+        assert_eq!(
+            catalog.expectation("svtav1", 8).unwrap(),
+            Expectation::default()
+        );
+
+        let run = EncodeRun {
+            finished_at: 1_000,
+            title: String::from("Heat"),
+            input: String::from("heat.mkv"),
+            source_hash: None,
+            settings: String::from("{}"),
+            gstreamer: String::from("GStreamer 1.26.0"),
+            encoder: String::from("svtav1"),
+            preset: 8,
+            encoded_rungs: 2,
+            source_secs: 3600.0,
+            wall_secs: 1800.0,
+            estimated_bytes: Some(1_000_000),
+            output_bytes: 900_000,
+            bitrates: BTreeMap::from([(6, 5800.0), (2, 1950.0)]),
+            outcome: String::from("prepared"),
+            error: None,
+            usage: Some(ResourceUsage {
+                cpu_secs: 14_400.0,
+                peak_rss_bytes: 3_000_000_000,
+                gpu_percent: None,
+                bytes_written: Some(950_000),
+            }),
+        };
+        catalog.record_run(&run).unwrap();
+        catalog
+            .record_run(&EncodeRun {
+                finished_at: 2_000,
+                encoded_rungs: 1,
+                wall_secs: 2400.0,
+                output_bytes: 700_000,
+                ..run.clone()
+            })
+            .unwrap();
+        // Neither a slower preset nor a failure counts towards speed
+        catalog
+            .record_run(&EncodeRun {
+                finished_at: 3_000,
+                preset: 4,
+                estimated_bytes: None,
+                ..run.clone()
+            })
+            .unwrap();
+        let failed = EncodeRun {
+            finished_at: 4_000,
+            title: String::from("Ronin"),
+            wall_secs: 10.0,
+            output_bytes: 0,
+            outcome: String::from("failed"),
+            error: Some(String::from("Encoder crashed")),
+            usage: None,
+            ..run.clone()
+        };
+        catalog.record_run(&failed).unwrap();
+
+        let expectation = catalog.expectation("svtav1", 8).unwrap();
+        assert_eq!(expectation.speed, Some(3600.0 * 3.0 / 4200.0));
+        assert_eq!(expectation.size_ratio, Some(0.8));
+
+        let all = RunFilter {
+            limit: 10,
+            ..RunFilter::default()
+        };
+        let runs = catalog.runs(&all).unwrap();
+        assert_eq!(runs.len(), 4);
+        assert_eq!(runs[0], failed);
+        assert_eq!(runs[3], run);
+        let heat = RunFilter {
+            title: Some(String::from("Heat")),
+            limit: 2,
+            ..RunFilter::default()
+        };
+        assert_eq!(catalog.runs(&heat).unwrap()[0].preset, 4);
+        let failures = RunFilter {
+            failed_only: true,
+            ..all
+        };
+        assert_eq!(catalog.runs(&failures).unwrap(), [failed]);
+    }
+}
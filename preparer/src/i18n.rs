@@ -0,0 +1,208 @@
+//! Translated messages for the command line and the player page.
+//!
+//! Messages are Fluent resources under `locales/`, one per language and
+//! built into the binary. The command line speaks the language of the
+//! locale it runs in, and the player page the one its viewer's browser asks
+//! for; anything a translation leaves out is shown in English.
+
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource, FluentValue};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use unic_langid::LanguageIdentifier;
+
+/// Translations by language, English first.
+const LOCALES: [(&str, &str); 4] = [
+    ("en", include_str!("locales/en.ftl")),
+    ("de", include_str!("locales/de.ftl")),
+    ("es", include_str!("locales/es.ftl")),
+    ("fr", include_str!("locales/fr.ftl")),
+];
+
+/// Messages the player page shows, handed to its script.
+const PLAYER_MESSAGES: [&str; 14] = [
+    "player-watch-together",
+    "player-watching-together",
+    "player-viewers",
+    "player-copy-link",
+    "player-handoff",
+    "player-handoff-code",
+    "player-enter-code",
+    "player-from-peers",
+    "player-disconnected",
+    "player-skip-intro",
+    "player-skip-credits",
+    "player-skip-recap",
+    "player-listen",
+    "player-watch-video",
+];
+
+fn bundles() -> &'static [FluentBundle<FluentResource>] {
+    static BUNDLES: OnceLock<Vec<FluentBundle<FluentResource>>> = OnceLock::new();
+    BUNDLES.get_or_init(|| {
+        LOCALES
+            .iter()
+            .map(|(code, source)| {
+                let language: LanguageIdentifier = code.parse().unwrap();
+                let mut bundle = FluentBundle::new_concurrent(vec![language]);
+                // Isolation marks show up as junk in terminals
+                bundle.set_use_isolating(false);
+                let resource = FluentResource::try_new(source.to_string())
+                    .unwrap_or_else(|_| panic!("Invalid messages for {}", code));
+                bundle
+                    .add_resource(resource)
+                    .unwrap_or_else(|_| panic!("Duplicate messages for {}", code));
+                bundle
+            })
+            .collect()
+    })
+}
+
+/// One of the languages messages are translated into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale(usize);
+
+impl Locale {
+    pub const ENGLISH: Locale = Locale(0);
+
+    /// The first of `requested`, like `fr-CA` or `de`, with a translation,
+    /// by its language alone; English if none has one.
+    pub fn negotiate<'a>(requested: impl IntoIterator<Item = &'a str>) -> Self {
+        requested
+            .into_iter()
+            .filter_map(|tag| {
+                let language = tag.split(['-', '_', '.', '@']).next()?.to_ascii_lowercase();
+                LOCALES.iter().position(|(code, _)| *code == language)
+            })
+            .next()
+            .map_or(Self::ENGLISH, Locale)
+    }
+
+    /// The language an `Accept-Language` header prefers, by its weights.
+    pub fn from_accept_language(header: &str) -> Self {
+        let mut ranges: Vec<(&str, f32)> = header
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let weight = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse().ok())?;
+                Some((tag, weight))
+            })
+            .filter(|(tag, weight)| !tag.is_empty() && *weight > 0.0)
+            .collect();
+        // Stable, so ranges of equal weight keep the order they came in
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Self::negotiate(ranges.into_iter().map(|(tag, _)| tag))
+    }
+
+    /// The language the command line runs in, from `LC_ALL`, `LC_MESSAGES`
+    /// or `LANG`, like POSIX tools.
+    pub fn cli() -> Self {
+        static CLI: OnceLock<Locale> = OnceLock::new();
+        *CLI.get_or_init(|| {
+            let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+                .into_iter()
+                .filter_map(|name| std::env::var(name).ok())
+                .find(|value| !value.is_empty())
+                .unwrap_or_default();
+            Self::negotiate([locale.as_str()])
+        })
+    }
+
+    /// Language code, like `fr`, for `<html lang>`.
+    pub fn code(self) -> &'static str {
+        LOCALES[self.0].0
+    }
+
+    /// The message `id`, filled in with `args`.
+    pub fn format(self, id: &str, args: &[(&str, FluentValue)]) -> String {
+        let mut fluent_args = FluentArgs::new();
+        for (name, value) in args {
+            fluent_args.set(*name, value.clone());
+        }
+        // Fall back on English for messages not translated yet
+        let bundles = bundles();
+        let (bundle, message) = [&bundles[self.0], &bundles[0]]
+            .into_iter()
+            .find_map(|bundle| Some((bundle, bundle.get_message(id)?)))
+            .unwrap_or_else(|| panic!("No message {}", id));
+        let mut errors = Vec::new();
+        bundle
+            .format_pattern(
+                message.value().expect("Messages have values"),
+                Some(&fluent_args),
+                &mut errors,
+            )
+            .into_owned()
+    }
+
+    /// The message `id`, which takes no arguments.
+    pub fn message(self, id: &str) -> String {
+        self.format(id, &[])
+    }
+
+    /// The player page's messages by id. Their arguments are left as
+    /// `{$name}` for its script to fill in.
+    pub fn player_messages(self) -> BTreeMap<&'static str, String> {
+        PLAYER_MESSAGES
+            .iter()
+            .map(|id| (*id, self.message(id)))
+            .collect()
+    }
+}
+
+/// The command line's message `id`, filled in with `args`.
+pub fn tr(id: &str, args: &[(&str, FluentValue)]) -> String {
+    Locale::cli().format(id, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_and_fills_in_translations() {
+        let french = Locale::negotiate(["fr_FR.UTF-8"]);
+        assert_eq!(french.code(), "fr");
+        assert_eq!(Locale::negotiate(["C"]), Locale::ENGLISH);
+        assert_eq!(
+            Locale::from_accept_language("ja, de-AT;q=0.8, es;q=0.9").code(),
+            "es"
+        );
+        assert_eq!(Locale::from_accept_language("fr;q=0, *"), Locale::ENGLISH);
+
+        let added =
+            |locale: Locale, count: u32| locale.format("added-tracks", &[("count", count.into())]);
+        assert_eq!(
+            added(Locale::ENGLISH, 1),
+            "Added 1 more track from the source"
+        );
+        assert_eq!(
+            added(Locale::ENGLISH, 3),
+            "Added 3 more tracks from the source"
+        );
+        assert_eq!(
+            french.format("input", &[("path", "film.mkv".into())]),
+            "Entrée : film.mkv"
+        );
+        assert_eq!(
+            Locale::ENGLISH.player_messages()["player-from-peers"],
+            ", {$megabytes} MB from peers"
+        );
+
+        // Every language has every message, so none falls back by accident
+        let english = LOCALES[0].1;
+        let ids = english
+            .lines()
+            .filter_map(|line| line.split_once(" ="))
+            .map(|(id, _)| id)
+            .filter(|id| !id.is_empty() && !id.starts_with([' ', '#']));
+        for id in ids {
+            for (bundle, (code, _)) in bundles().iter().zip(LOCALES) {
+                assert!(bundle.has_message(id), "{} has no {}", code, id);
+            }
+        }
+    }
+}
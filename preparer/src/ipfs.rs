@@ -0,0 +1,102 @@
+//! Publishing a finished title to IPFS through a local daemon's HTTP API.
+
+use crate::library::{self, Metadata};
+use crate::s3::uri_encode;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::path::Path;
+
+/// Kubo's default API address.
+pub const DEFAULT_API: &str = "http://127.0.0.1:5001";
+
+const BOUNDARY: &str = "movieshare-ipfs-boundary";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Added {
+    name: String,
+    hash: String,
+}
+
+/// A multipart body adding every file under `dir` inside a directory named `root`.
+///
+/// Hidden files like the journal are left out.
+fn form(dir: &Path, root: &str) -> Result<Vec<u8>> {
+    let mut files = Vec::new();
+    library::collect_files(dir, dir, &mut files)?;
+    files.retain(|(name, _)| !name.split('/').any(|part| part.starts_with('.')));
+    files.sort();
+
+    let mut body = Vec::new();
+    for (name, _) in files {
+        body.extend(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                BOUNDARY,
+                uri_encode(&format!("{}/{}", root, name), true)
+            )
+            .as_bytes(),
+        );
+        body.extend(std::fs::read(dir.join(&name)).context(format!("Failed to read {}", name))?);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    Ok(body)
+}
+
+/// Add `dir` to IPFS and return the CID of the directory.
+pub fn add(api: &str, dir: &Path) -> Result<String> {
+    let root = dir.file_name().map_or(String::from("title"), |name| {
+        name.to_string_lossy().into_owned()
+    });
+    let body = ureq::post(format!("{}/api/v0/add", api.trim_end_matches('/')))
+        .query("pin", "true")
+        .query("cid-version", "1")
+        .header(
+            "Content-Type",
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        )
+        .send(&form(dir, &root)?)
+        .context(format!("Failed to add {} to IPFS", dir.display()))?
+        .body_mut()
+        .read_to_string()?;
+    root_cid(&body, &root)
+}
+
+/// The daemon answers with one JSON object per added entry; pick the root's.
+fn root_cid(response: &str, root: &str) -> Result<String> {
+    for line in response.lines().filter(|line| !line.trim().is_empty()) {
+        let added: Added = serde_json::from_str(line)?;
+        if added.name == root {
+            return Ok(added.hash);
+        }
+    }
+    bail!("IPFS didn't report a CID for {}", root)
+}
+
+/// Publish `dir` and record the CID in its `metadata.json`.
+pub fn publish(api: &str, dir: &Path, name: &str) -> Result<String> {
+    let cid = add(api, dir)?;
+    let mut metadata = Metadata::read(dir)?.unwrap_or_else(|| Metadata {
+        title: name.to_string(),
+        ..Default::default()
+    });
+    metadata.ipfs_cid = Some(cid.clone());
+    metadata.write(dir)?;
+    Ok(cid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_root_cid() {
+        let response = concat!(
+            "{\"Name\":\"Heat/manifest.mpd\",\"Hash\":\"bafyfile\",\"Size\":\"12\"}\n",
+            "{\"Name\":\"Heat\",\"Hash\":\"bafyroot\",\"Size\":\"40\"}\n",
+        );
+        assert_eq!(root_cid(response, "Heat").unwrap(), "bafyroot");
+        assert!(root_cid(response, "Other").is_err());
+    }
+}
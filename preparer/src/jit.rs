@@ -0,0 +1,193 @@
+//! Preparing titles the first time someone asks for them.
+//!
+//! Each media file in the sources directory is a title named after the
+//! file's stem. Watching one that isn't prepared yet starts preparing it
+//! into the library with a dynamic manifest, and the player starts as soon
+//! as the first segments exist. The finished output stays in the library,
+//! so the title is prepared only once.
+
+use crate::library::MANIFEST;
+use crate::mpd;
+use anyhow::{Context, Result};
+use movieshare_core::journal::{self, Journal, JournalEvent};
+use movieshare_core::{Outcome, Preparer};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a request waits for the first segments before giving up.
+const MANIFEST_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+enum State {
+    Preparing,
+    Failed(String),
+}
+
+#[derive(Clone)]
+pub struct Jit {
+    sources: PathBuf,
+    library: PathBuf,
+    states: Arc<Mutex<HashMap<String, State>>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JitError {
+    NoSource,
+    Failed(String),
+    /// Still preparing, but nothing playable yet
+    NotReady,
+}
+
+impl Jit {
+    pub fn new(sources: PathBuf, library: PathBuf) -> Self {
+        Self {
+            sources,
+            library,
+            states: Arc::default(),
+        }
+    }
+
+    /// The source file `title` would be prepared from.
+    fn source(&self, title: &str) -> Option<PathBuf> {
+        if title.starts_with('.') || title.contains('/') {
+            return None;
+        }
+        std::fs::read_dir(&self.sources)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .find(|path| path.is_file() && path.file_stem().is_some_and(|stem| stem == title))
+    }
+
+    /// Names of every title with a source, prepared or not.
+    pub fn titles(&self) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(&self.sources) else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| Some(entry.path().file_stem()?.to_str()?.to_string()))
+            .filter(|title| !title.starts_with('.'))
+            .collect()
+    }
+
+    /// Whether the title's output is a finished run rather than a partial one.
+    fn is_complete(dir: &Path) -> Result<bool> {
+        if !dir.join(MANIFEST).is_file() {
+            return Ok(false);
+        }
+        let entries = Journal::read(dir)?;
+        // Output with no journal was prepared some other way
+        Ok(entries.is_empty()
+            || journal::last_run(&entries)
+                .iter()
+                .any(|entry| entry.event == JournalEvent::Finalized))
+    }
+
+    /// Make sure `title` is prepared or being prepared, waiting until its
+    /// manifest can be played.
+    pub async fn ensure(&self, title: &str) -> Result<(), JitError> {
+        let output = self.library.join(title);
+        if Self::is_complete(&output).unwrap_or(false) {
+            return Ok(());
+        }
+
+        {
+            let mut states = self.states.lock().unwrap();
+            match states.get(title) {
+                Some(State::Preparing) => (),
+                // Try a failed title again on the next request
+                Some(State::Failed(_)) | None => {
+                    let source = self.source(title).ok_or(JitError::NoSource)?;
+                    states.insert(title.to_string(), State::Preparing);
+                    self.spawn(title.to_string(), source, output.clone());
+                }
+            }
+        }
+
+        let deadline = tokio::time::Instant::now() + MANIFEST_WAIT;
+        loop {
+            match self.states.lock().unwrap().get(title) {
+                Some(State::Failed(error)) => return Err(JitError::Failed(error.clone())),
+                // Finished already
+                None => return Ok(()),
+                Some(State::Preparing) => (),
+            }
+            if output.join(MANIFEST).is_file() {
+                return Ok(());
+            }
+            if tokio::time::Instant::now() > deadline {
+                return Err(JitError::NotReady);
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    fn spawn(&self, title: String, source: PathBuf, output: PathBuf) {
+        let states = self.states.clone();
+        std::thread::spawn(move || {
+            println!("Preparing {} on demand", title);
+            let result = Preparer::new(&source)
+                .output(&output)
+                .dynamic_manifest(true)
+                .run()
+                .and_then(|outcome| match outcome {
+                    Outcome::Prepared(_) => finalize_manifest(&output),
+                    _ => Ok(()),
+                });
+
+            let mut states = states.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    println!("Finished preparing {}", title);
+                    states.remove(&title);
+                }
+                Err(err) => {
+                    eprintln!("Failed to prepare {}: {:#}", title, err);
+                    states.insert(title, State::Failed(format!("{:#}", err)));
+                }
+            }
+        });
+    }
+}
+
+/// Rewrite a finished dynamic manifest as a static one.
+pub(crate) fn finalize_manifest(output: &Path) -> Result<()> {
+    let path = output.join(MANIFEST);
+    let xml =
+        std::fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, mpd::make_static(&xml)?)?;
+    std::fs::rename(&tmp, &path).context(format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn finds_sources_by_stem() {
+        let dir = std::env::temp_dir().join(format!("movieshare-jit-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sources")).unwrap();
+        std::fs::create_dir_all(dir.join("library/Done")).unwrap();
+        std::fs::write(dir.join("sources/Film.mkv"), b"").unwrap();
+        std::fs::write(dir.join("sources/Done.mkv"), b"").unwrap();
+        std::fs::write(dir.join("library/Done").join(MANIFEST), "<MPD/>").unwrap();
+
+        let jit = Jit::new(dir.join("sources"), dir.join("library"));
+        let mut titles = jit.titles();
+        titles.sort();
+        assert_eq!(titles, ["Done", "Film"]);
+        assert_eq!(jit.source("Film"), Some(dir.join("sources/Film.mkv")));
+        assert_eq!(jit.source("../sources/Film"), None);
+
+        // Already prepared output is used as-is
+        assert_eq!(jit.ensure("Done").await, Ok(()));
+        assert_eq!(jit.ensure("Missing").await, Err(JitError::NoSource));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
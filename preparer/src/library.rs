@@ -0,0 +1,855 @@
+//! SQLite catalog of the prepared titles in a library directory.
+//!
+//! `preparer library scan` indexes each title's manifest, files and side
+//! metadata so the server can list the library without walking it, and
+//! its subtitles so they can be searched; see [`crate::search`].
+
+use crate::artwork;
+use crate::mpd::{self, Representation};
+use crate::search;
+use anyhow::{Context, Result, bail};
+use movieshare_core::cover;
+use movieshare_core::journal::{self, Journal, JournalEvent};
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DATABASE: &str = ".movieshare.db";
+pub const MANIFEST: &str = "manifest.mpd";
+pub(crate) const POSTERS: &[&str] = &[
+    artwork::POSTER,
+    "poster.png",
+    "folder.jpg",
+    cover::FILENAMES[0],
+    cover::FILENAMES[1],
+];
+pub const METADATA: &str = "metadata.json";
+/// Single-file MP4 of a title, for players that can't do DASH.
+pub const FALLBACK: &str = "fallback.mp4";
+/// Chromaprint fingerprint of a title's audio, written while preparing it.
+pub const FINGERPRINT: &str = "chromaprint.txt";
+/// Perceptual hashes of sampled frames of a title, written while preparing it.
+pub const VIDEO_HASH: &str = "videohash.txt";
+/// Segment and keyframe times of each representation, for seeking.
+pub const SEEK_INDEX: &str = "seek-index.json";
+
+/// Schema changes, applied in order; `PRAGMA user_version` counts those applied.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE titles (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        path TEXT NOT NULL,
+        duration_secs REAL,
+        ladder TEXT NOT NULL,
+        metadata TEXT,
+        poster TEXT,
+        size_bytes INTEGER NOT NULL,
+        manifest_sha256 TEXT NOT NULL,
+        scanned_at INTEGER NOT NULL
+    );
+    CREATE TABLE files (
+        title_id INTEGER NOT NULL REFERENCES titles(id) ON DELETE CASCADE,
+        path TEXT NOT NULL,
+        size_bytes INTEGER NOT NULL,
+        sha256 TEXT,
+        PRIMARY KEY (title_id, path)
+    );
+",
+    "ALTER TABLE titles ADD COLUMN backdrop TEXT;",
+    "
+    CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        password_hash TEXT,
+        restricted INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE grants (
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        kind TEXT NOT NULL CHECK (kind IN ('title', 'genre')),
+        value TEXT NOT NULL,
+        PRIMARY KEY (user_id, kind, value)
+    );
+",
+    "
+    CREATE TABLE progress (
+        user TEXT NOT NULL,
+        title TEXT NOT NULL,
+        position_secs REAL NOT NULL,
+        duration_secs REAL NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (user, title)
+    );
+",
+    "
+    CREATE TABLE playback_sessions (
+        id TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        startup_secs REAL,
+        watched_secs REAL NOT NULL,
+        rebuffer_count INTEGER NOT NULL,
+        rebuffer_secs REAL NOT NULL,
+        rung_switches INTEGER NOT NULL
+    );
+    CREATE TABLE playback_rungs (
+        session_id TEXT NOT NULL REFERENCES playback_sessions(id) ON DELETE CASCADE,
+        height INTEGER NOT NULL,
+        bandwidth INTEGER NOT NULL,
+        secs REAL NOT NULL,
+        PRIMARY KEY (session_id, height, bandwidth)
+    );
+",
+    "ALTER TABLE titles ADD COLUMN fingerprint TEXT;",
+    "ALTER TABLE titles ADD COLUMN video_hash TEXT;",
+    "ALTER TABLE users ADD COLUMN max_rating TEXT;",
+    "
+    CREATE TABLE encode_runs (
+        id INTEGER PRIMARY KEY,
+        finished_at INTEGER NOT NULL,
+        encoder TEXT NOT NULL,
+        preset INTEGER NOT NULL,
+        encoded_rungs INTEGER NOT NULL,
+        source_secs REAL NOT NULL,
+        wall_secs REAL NOT NULL,
+        estimated_bytes INTEGER,
+        output_bytes INTEGER NOT NULL
+    );
+",
+    "
+    ALTER TABLE encode_runs ADD COLUMN title TEXT;
+    ALTER TABLE encode_runs ADD COLUMN input TEXT;
+    ALTER TABLE encode_runs ADD COLUMN source_hash TEXT;
+    ALTER TABLE encode_runs ADD COLUMN settings TEXT;
+    ALTER TABLE encode_runs ADD COLUMN gstreamer TEXT;
+    ALTER TABLE encode_runs ADD COLUMN bitrates TEXT;
+    ALTER TABLE encode_runs ADD COLUMN outcome TEXT NOT NULL DEFAULT 'prepared';
+    ALTER TABLE encode_runs ADD COLUMN error TEXT;
+    CREATE INDEX encode_runs_finished_at ON encode_runs (finished_at);
+",
+    "
+    CREATE TABLE collections (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE collection_titles (
+        collection_id INTEGER NOT NULL REFERENCES collections(id) ON DELETE CASCADE,
+        title TEXT NOT NULL,
+        PRIMARY KEY (collection_id, title)
+    );
+    CREATE TABLE grants_with_collections (
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        kind TEXT NOT NULL CHECK (kind IN ('title', 'genre', 'collection')),
+        value TEXT NOT NULL,
+        PRIMARY KEY (user_id, kind, value)
+    );
+    INSERT INTO grants_with_collections SELECT user_id, kind, value FROM grants;
+    DROP TABLE grants;
+    ALTER TABLE grants_with_collections RENAME TO grants;
+",
+    "
+    ALTER TABLE encode_runs ADD COLUMN cpu_secs REAL;
+    ALTER TABLE encode_runs ADD COLUMN peak_rss_bytes INTEGER;
+    ALTER TABLE encode_runs ADD COLUMN gpu_percent REAL;
+    ALTER TABLE encode_runs ADD COLUMN bytes_written INTEGER;
+",
+    "
+    CREATE VIRTUAL TABLE subtitle_cues USING fts5(
+        text,
+        title UNINDEXED,
+        lang UNINDEXED,
+        start_secs UNINDEXED,
+        end_secs UNINDEXED
+    );
+    -- So the next scan indexes the subtitles of titles that haven't changed
+    UPDATE titles SET manifest_sha256 = '';
+",
+    "ALTER TABLE titles ADD COLUMN damaged TEXT;",
+    "ALTER TABLE titles ADD COLUMN attention TEXT;",
+];
+
+/// Descriptive metadata kept in a title's `metadata.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Metadata {
+    pub title: String,
+    pub year: Option<u32>,
+    pub overview: Option<String>,
+    pub genres: Vec<String>,
+    /// Content rating, like `PG-13`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rating: Option<String>,
+    pub poster_url: Option<String>,
+    pub backdrop_url: Option<String>,
+    /// Movie ID on TMDB, if the metadata came from there
+    pub tmdb_id: Option<u64>,
+    /// Content ID of the title's directory, if it was published to IPFS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipfs_cid: Option<String>,
+    /// Parts viewers may want to skip, found by `preparer markers`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub markers: Vec<Marker>,
+    /// The source's chapters, with stills for chapter menus
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chapters: Vec<Chapter>,
+    /// The compressed log of the run that prepared the title, from
+    /// [`crate::encodelog`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encode_log: Option<String>,
+    /// Packaging conventions the title follows, from
+    /// [`crate::migrate::FORMAT_VERSION`]; 0 from before versioning
+    pub format_version: u32,
+    /// Warnings the run that prepared the title raised, until someone
+    /// reviews it; see [`crate::attention`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attention: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkerKind {
+    Intro,
+    Credits,
+    /// "Previously on" at the start of an episode
+    Recap,
+}
+
+/// A stretch of a title that viewers can skip.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub kind: MarkerKind,
+    pub start_secs: f64,
+    pub end_secs: f64,
+}
+
+/// A chapter of a title.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Chapter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub start_secs: f64,
+    /// Still from the chapter, next to the manifest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<String>,
+}
+
+impl Metadata {
+    /// Read `metadata.json` from a title's directory, if it has one.
+    pub fn read(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(METADATA);
+        match std::fs::read_to_string(&path) {
+            Ok(json) => Ok(Some(
+                serde_json::from_str(&json).context(format!("Invalid {}", path.display()))?,
+            )),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(METADATA);
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .context(format!("Failed to write {}", path.display()))
+    }
+
+    /// Whether anything describes the title beyond its name, as a lookup
+    /// would fill in.
+    pub fn is_described(&self) -> bool {
+        self.tmdb_id.is_some()
+            || self.year.is_some()
+            || self.overview.is_some()
+            || !self.genres.is_empty()
+    }
+
+    /// `Title (Year)`, or just the title.
+    pub fn label(&self) -> String {
+        match self.year {
+            Some(year) => format!("{} ({})", self.title, year),
+            None => self.title.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Title {
+    pub name: String,
+    pub path: PathBuf,
+    pub duration_secs: Option<f64>,
+    pub ladder: Vec<Representation>,
+    pub metadata: Option<Metadata>,
+    /// Poster image file name within the title's directory
+    pub poster: Option<String>,
+    /// Backdrop image file name within the title's directory
+    pub backdrop: Option<String>,
+    pub size_bytes: u64,
+    pub manifest_sha256: String,
+    /// Seconds since the Unix epoch
+    pub scanned_at: u64,
+}
+
+/// Files next to the manifest that describe a title.
+#[derive(Debug, Clone, PartialEq)]
+struct Sidecars {
+    metadata: Option<Metadata>,
+    poster: Option<String>,
+    backdrop: Option<String>,
+    fingerprint: Option<String>,
+    video_hash: Option<String>,
+}
+
+impl Sidecars {
+    fn read(dir: &Path) -> Result<Self> {
+        let find = |names: &[&str]| {
+            names
+                .iter()
+                .find(|name| dir.join(name).is_file())
+                .map(|name| name.to_string())
+        };
+        Ok(Self {
+            metadata: Metadata::read(dir)?,
+            poster: find(POSTERS),
+            backdrop: find(&[artwork::BACKDROP]),
+            fingerprint: std::fs::read_to_string(dir.join(FINGERPRINT))
+                .ok()
+                .map(|fingerprint| fingerprint.trim().to_string()),
+            video_hash: std::fs::read_to_string(dir.join(VIDEO_HASH)).ok(),
+        })
+    }
+}
+
+/// A title's sidecars for telling duplicates apart, as the catalog has them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprints {
+    pub name: String,
+    /// Compressed chromaprint fingerprint
+    pub audio: Option<String>,
+    /// Contents of the video hash sidecar
+    pub video: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ScanSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+pub struct Catalog {
+    pub(crate) conn: Connection,
+}
+
+impl Catalog {
+    pub fn default_path(library: &Path) -> PathBuf {
+        library.join(DATABASE)
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .context(format!("Failed to open catalog: {}", path.display()))?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let mut catalog = Self { conn };
+        catalog.migrate()?;
+        Ok(catalog)
+    }
+
+    /// Open the catalog in `library`, or `None` if it was never scanned.
+    pub fn open_existing(library: &Path) -> Result<Option<Self>> {
+        let path = Self::default_path(library);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Self::open(&path).map(Some)
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let version: u32 = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        let tx = self.conn.transaction()?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            tx.execute_batch(migration)
+                .context(format!("Failed to migrate catalog to version {}", i + 1))?;
+            tx.pragma_update(None, "user_version", i as u32 + 1)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Index every title in `library`, dropping entries whose directory is gone.
+    ///
+    /// Titles whose manifest, metadata and artwork are unchanged are skipped
+    /// unless `checksums` asks for every file to be hashed again.
+    pub fn scan(&mut self, library: &Path, checksums: bool) -> Result<ScanSummary> {
+        let mut summary = ScanSummary::default();
+        let mut found = Vec::new();
+
+        let entries = std::fs::read_dir(library)
+            .context(format!("Failed to read library: {}", library.display()))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.starts_with('.') || !path.join(MANIFEST).is_file() {
+                continue;
+            }
+
+            let manifest_sha256 = sha256_file(&path.join(MANIFEST))?;
+            let sidecars = Sidecars::read(&path)?;
+            let existing: Option<(String, Sidecars)> = self
+                .conn
+                .query_row(
+                    "SELECT manifest_sha256, metadata, poster, backdrop, fingerprint, video_hash
+                     FROM titles WHERE name = ?1",
+                    params![name],
+                    |row| {
+                        let metadata: Option<String> = row.get(1)?;
+                        Ok((
+                            row.get(0)?,
+                            Sidecars {
+                                metadata: metadata.and_then(|m| serde_json::from_str(&m).ok()),
+                                poster: row.get(2)?,
+                                backdrop: row.get(3)?,
+                                fingerprint: row.get(4)?,
+                                video_hash: row.get(5)?,
+                            },
+                        ))
+                    },
+                )
+                .optional()?;
+            found.push(name.clone());
+
+            if existing.as_ref() == Some(&(manifest_sha256.clone(), sidecars.clone())) && !checksums
+            {
+                summary.unchanged += 1;
+                continue;
+            }
+            self.index(&name, &path, manifest_sha256, sidecars, checksums)
+                .context(format!("Failed to index {}", path.display()))?;
+            match existing {
+                Some(_) => summary.updated += 1,
+                None => summary.added += 1,
+            }
+        }
+
+        for name in self.names()? {
+            if !found.contains(&name) {
+                self.remove(&name)?;
+                summary.removed += 1;
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Index the one title `name` again, after changing it in place.
+    pub fn refresh(&mut self, name: &str, dir: &Path) -> Result<()> {
+        let manifest_sha256 = sha256_file(&dir.join(MANIFEST))?;
+        let sidecars = Sidecars::read(dir)?;
+        self.index(name, dir, manifest_sha256, sidecars, false)
+            .context(format!("Failed to index {}", dir.display()))
+    }
+
+    /// Index the titles `titles`, as names and directories, and group them
+    /// into `collection`, creating it if need be, all in one transaction, so
+    /// a batch like a season shows up whole or not at all.
+    pub fn import(&mut self, titles: &[(String, PathBuf)], collection: Option<&str>) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for (name, dir) in titles {
+            let manifest_sha256 = sha256_file(&dir.join(MANIFEST))?;
+            let sidecars = Sidecars::read(dir)?;
+            index_title(&tx, name, dir, manifest_sha256, sidecars, false)
+                .context(format!("Failed to index {}", dir.display()))?;
+        }
+        if let Some(collection) = collection {
+            tx.execute(
+                "INSERT OR IGNORE INTO collections (name) VALUES (?1)",
+                params![collection],
+            )?;
+            for (name, _) in titles {
+                tx.execute(
+                    "INSERT OR IGNORE INTO collection_titles (collection_id, title)
+                     SELECT id, ?2 FROM collections WHERE name = ?1",
+                    params![collection, name],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn index(
+        &mut self,
+        name: &str,
+        dir: &Path,
+        manifest_sha256: String,
+        sidecars: Sidecars,
+        checksums: bool,
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        index_title(&tx, name, dir, manifest_sha256, sidecars, checksums)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        let mut statement = self.conn.prepare("SELECT name FROM titles ORDER BY name")?;
+        let names = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    pub fn titles(&self) -> Result<Vec<Title>> {
+        let mut statement = self.conn.prepare(
+            "SELECT name, path, duration_secs, ladder, metadata, poster, backdrop, size_bytes,
+                manifest_sha256, scanned_at
+             FROM titles ORDER BY name",
+        )?;
+        // The JSON columns are parsed afterwards, outside of rusqlite's error type
+        let rows = statement.query_map([], |row| {
+            let title = Title {
+                name: row.get(0)?,
+                path: PathBuf::from(row.get::<_, String>(1)?),
+                duration_secs: row.get(2)?,
+                ladder: Vec::new(),
+                metadata: None,
+                poster: row.get(5)?,
+                backdrop: row.get(6)?,
+                size_bytes: row.get::<_, i64>(7)? as u64,
+                manifest_sha256: row.get(8)?,
+                scanned_at: row.get::<_, i64>(9)? as u64,
+            };
+            let ladder: String = row.get(3)?;
+            let metadata: Option<String> = row.get(4)?;
+            Ok((title, ladder, metadata))
+        })?;
+
+        let mut titles = Vec::new();
+        for row in rows {
+            let (mut title, ladder, metadata) = row?;
+            title.ladder = serde_json::from_str(&ladder)?;
+            title.metadata = metadata.map(|m| serde_json::from_str(&m)).transpose()?;
+            titles.push(title);
+        }
+        Ok(titles)
+    }
+
+    pub fn title(&self, name: &str) -> Result<Option<Title>> {
+        Ok(self.titles()?.into_iter().find(|title| title.name == name))
+    }
+
+    /// The audio fingerprint and video hashes of every title, by name.
+    pub fn fingerprints(&self) -> Result<Vec<Fingerprints>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT name, fingerprint, video_hash FROM titles ORDER BY name")?;
+        let fingerprints = statement
+            .query_map([], |row| {
+                Ok(Fingerprints {
+                    name: row.get(0)?,
+                    audio: row.get(1)?,
+                    video: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(fingerprints)
+    }
+
+    /// Forget a title. Its files on disk are left alone.
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        let removed = self
+            .conn
+            .execute("DELETE FROM titles WHERE name = ?1", params![name])?;
+        self.conn
+            .execute("DELETE FROM subtitle_cues WHERE title = ?1", params![name])?;
+        Ok(removed > 0)
+    }
+}
+
+/// Index the title `name` in `dir` as part of `tx`.
+fn index_title(
+    tx: &Transaction,
+    name: &str,
+    dir: &Path,
+    manifest_sha256: String,
+    sidecars: Sidecars,
+    checksums: bool,
+) -> Result<()> {
+    let xml = std::fs::read_to_string(dir.join(MANIFEST))?;
+    let manifest = mpd::parse(&xml)?;
+
+    let mut files = Vec::new();
+    collect_files(dir, dir, &mut files)?;
+    let size_bytes: u64 = files.iter().map(|(_, size)| size).sum();
+    let scanned_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let attention = match &sidecars.metadata {
+        Some(metadata) if !metadata.attention.is_empty() => {
+            Some(serde_json::to_string(&metadata.attention)?)
+        }
+        _ => None,
+    };
+
+    tx.execute("DELETE FROM titles WHERE name = ?1", params![name])?;
+    tx.execute(
+        "INSERT INTO titles (name, path, duration_secs, ladder, metadata, poster,
+            backdrop, size_bytes, manifest_sha256, scanned_at, fingerprint, video_hash,
+            attention)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        params![
+            name,
+            dir.to_string_lossy(),
+            manifest.duration_secs,
+            serde_json::to_string(&manifest.representations)?,
+            sidecars
+                .metadata
+                .map(|m| serde_json::to_string(&m))
+                .transpose()?,
+            sidecars.poster,
+            sidecars.backdrop,
+            size_bytes as i64,
+            manifest_sha256,
+            scanned_at as i64,
+            sidecars.fingerprint,
+            sidecars.video_hash,
+            attention,
+        ],
+    )?;
+    let title_id = tx.last_insert_rowid();
+    for (relative, size) in &files {
+        let sha256 = match checksums {
+            true => Some(sha256_file(&dir.join(relative))?),
+            false => None,
+        };
+        tx.execute(
+            "INSERT INTO files (title_id, path, size_bytes, sha256) VALUES (?1, ?2, ?3, ?4)",
+            params![title_id, relative, *size as i64, sha256],
+        )?;
+    }
+    search::index_cues(tx, name, dir, &xml)?;
+    Ok(())
+}
+
+/// The input a title was last prepared from, as its journal recorded it,
+/// if that file is still there, following it if the source policy moved it.
+pub(crate) fn recorded_source(dir: &Path) -> Result<PathBuf> {
+    let entries = Journal::read(dir)?;
+    let run = journal::last_run(&entries);
+    let Some(JournalEvent::Started { input }) = run.first().map(|entry| &entry.event) else {
+        bail!("No journal in {}", dir.display());
+    };
+    let input = match run.iter().rev().find_map(|entry| match &entry.event {
+        JournalEvent::SourceHandled { location, .. } => Some(location),
+        _ => None,
+    }) {
+        Some(Some(location)) => PathBuf::from(location),
+        Some(None) => bail!("The source {} was deleted once prepared", input),
+        None => PathBuf::from(input),
+    };
+    if !input.is_file() {
+        bail!("The source {} is gone", input.display());
+    }
+    Ok(input)
+}
+
+/// Paths relative to `root` and sizes of every file under `dir`.
+pub(crate) fn collect_files(root: &Path, dir: &Path, files: &mut Vec<(String, u64)>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_files(root, &entry.path(), files)?;
+        } else {
+            let relative = entry
+                .path()
+                .strip_prefix(root)?
+                .to_string_lossy()
+                .into_owned();
+            files.push((relative, metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+/// A file of a title and its SHA-256, for comparing two copies of it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileChecksum {
+    /// Path relative to the title's directory
+    pub path: String,
+    pub size_bytes: u64,
+    pub sha256: String,
+}
+
+/// Checksums of every file of the title `name` in `library`. Ones the
+/// catalog has for a file of the same size are reused, and the rest saved
+/// to it once worked out.
+pub fn checksums(library: &Path, name: &str) -> Result<Vec<FileChecksum>> {
+    let dir = library.join(name);
+    let mut files = Vec::new();
+    collect_files(&dir, &dir, &mut files)?;
+    files.sort();
+    let catalog = Catalog::open_existing(library)?;
+    let mut checksums = Vec::new();
+    for (path, size_bytes) in files {
+        let cached = match &catalog {
+            Some(catalog) => catalog
+                .conn
+                .query_row(
+                    "SELECT files.sha256 FROM files JOIN titles ON titles.id = files.title_id
+                     WHERE titles.name = ?1 AND files.path = ?2 AND files.size_bytes = ?3",
+                    params![name, path, size_bytes as i64],
+                    |row| row.get::<_, Option<String>>(0),
+                )
+                .optional()?
+                .flatten(),
+            None => None,
+        };
+        let sha256 = match cached {
+            Some(sha256) => sha256,
+            None => {
+                let sha256 = sha256_file(&dir.join(&path))?;
+                if let Some(catalog) = &catalog {
+                    catalog.conn.execute(
+                        "UPDATE files SET sha256 = ?3 WHERE path = ?2 AND size_bytes = ?4
+                         AND title_id = (SELECT id FROM titles WHERE name = ?1)",
+                        params![name, path, sha256, size_bytes as i64],
+                    )?;
+                }
+                sha256
+            }
+        };
+        checksums.push(FileChecksum {
+            path,
+            size_bytes,
+            sha256,
+        });
+    }
+    Ok(checksums)
+}
+
+pub(crate) fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MPD: &str = r#"<MPD mediaPresentationDuration="PT10S"><Period>
+        <AdaptationSet contentType="video">
+            <Representation id="0" bandwidth="2000000" width="1280" height="720"/>
+        </AdaptationSet>
+    </Period></MPD>"#;
+
+    fn library() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "movieshare-catalog-test-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        std::fs::create_dir_all(dir.join("Movie")).unwrap();
+        std::fs::write(dir.join("Movie").join(MANIFEST), MPD).unwrap();
+        std::fs::write(dir.join("Movie").join("init-0.m4s"), b"init").unwrap();
+        std::fs::write(dir.join("Movie").join("poster.png"), b"png").unwrap();
+        std::fs::create_dir_all(dir.join("Not a title")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn scans_incrementally() {
+        let library = library();
+        let mut catalog = Catalog::open(&Catalog::default_path(&library)).unwrap();
+
+        let summary = catalog.scan(&library, true).unwrap();
+        assert_eq!(summary.added, 1);
+        let title = catalog.title("Movie").unwrap().unwrap();
+        assert_eq!(title.duration_secs, Some(10.0));
+        assert_eq!(title.ladder[0].height, Some(720));
+        assert_eq!(title.poster.as_deref(), Some("poster.png"));
+        assert_eq!(title.size_bytes, (MPD.len() + 4 + 3) as u64);
+
+        assert_eq!(catalog.scan(&library, false).unwrap().unchanged, 1);
+
+        let metadata = Metadata {
+            title: String::from("A Movie"),
+            year: Some(2001),
+            ..Metadata::default()
+        };
+        metadata.write(&library.join("Movie")).unwrap();
+        assert_eq!(catalog.scan(&library, false).unwrap().updated, 1);
+        let title = catalog.title("Movie").unwrap().unwrap();
+        assert_eq!(title.metadata, Some(metadata));
+
+        std::fs::write(library.join("Movie").join(FINGERPRINT), "AQAAAA\n").unwrap();
+        std::fs::write(library.join("Movie").join(VIDEO_HASH), "00ff\n").unwrap();
+        assert_eq!(catalog.scan(&library, false).unwrap().updated, 1);
+        assert_eq!(
+            catalog.fingerprints().unwrap(),
+            [Fingerprints {
+                name: String::from("Movie"),
+                audio: Some(String::from("AQAAAA")),
+                video: Some(String::from("00ff\n")),
+            }]
+        );
+
+        std::fs::remove_dir_all(library.join("Movie")).unwrap();
+        assert_eq!(catalog.scan(&library, false).unwrap().removed, 1);
+        assert!(catalog.titles().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+
+    #[test]
+    fn imports_a_batch_all_or_nothing() {
+        let library = library();
+        let mut catalog = Catalog::open(&Catalog::default_path(&library)).unwrap();
+        let episode = |name: &str| (name.to_string(), library.join(name));
+        std::fs::create_dir_all(library.join("Episode 01")).unwrap();
+        std::fs::write(library.join("Episode 01").join(MANIFEST), MPD).unwrap();
+
+        // The second episode isn't there, so neither goes in
+        let season = [episode("Episode 01"), episode("Episode 02")];
+        assert!(catalog.import(&season, Some("Season 1")).is_err());
+        assert!(catalog.titles().unwrap().is_empty());
+        assert!(catalog.collection("Season 1").unwrap().is_none());
+
+        std::fs::create_dir_all(library.join("Episode 02")).unwrap();
+        std::fs::write(library.join("Episode 02").join(MANIFEST), MPD).unwrap();
+        catalog.import(&season, Some("Season 1")).unwrap();
+        assert_eq!(catalog.titles().unwrap().len(), 2);
+        assert_eq!(
+            catalog.collection("Season 1").unwrap().unwrap().titles,
+            ["Episode 01", "Episode 02"]
+        );
+
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+
+    #[test]
+    fn reopening_keeps_the_schema() {
+        let library = library();
+        let path = Catalog::default_path(&library);
+        Catalog::open(&path).unwrap().scan(&library, false).unwrap();
+
+        let catalog = Catalog::open_existing(&library).unwrap().unwrap();
+        assert_eq!(catalog.titles().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! `preparer live`: sharing a live SRT or RTMP stream, like a match or a
+//! school play, or a capture of the desktop, as a dynamic DASH presentation
+//! the household can join.
+//!
+//! Unless recording, only the last few minutes are kept: older segments are
+//! deleted as new ones arrive, so an all-day stream doesn't fill the disk.
+//! Players start at the live edge and can seek back only as far as the
+//! window reaches.
+
+use anyhow::{Result, bail};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often to look for segments that fell out of the window.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Input meaning a capture of the desktop rather than a stream.
+pub const SCREEN: &str = "screen";
+
+/// Check a live input is `screen` or a stream we know how to receive.
+pub fn parse_input(uri: &str) -> Result<String> {
+    if uri == SCREEN {
+        return Ok(uri.to_string());
+    }
+    match uri.split_once("://") {
+        Some(("srt" | "rtmp" | "rtmps", rest)) if !rest.is_empty() => Ok(uri.to_string()),
+        _ => bail!(
+            "Expected screen or an srt:// or rtmp:// URL, like srt://:9000 to listen for a sender, got {:?}",
+            uri
+        ),
+    }
+}
+
+/// Delete media segments in `dir` last written more than `window` ago,
+/// leaving the manifest and initialization segments. Returns how many were
+/// deleted.
+pub fn prune(dir: &Path, window: Duration) -> Result<usize> {
+    let Some(cutoff) = SystemTime::now().checked_sub(window) else {
+        return Ok(0);
+    };
+    let mut pruned = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "m4s")
+            && entry.metadata()?.modified()? < cutoff
+        {
+            std::fs::remove_file(&path)?;
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
+
+/// Keep pruning `dir` to `window` in the background until the process exits.
+pub fn spawn_pruner(dir: PathBuf, window: Duration) {
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(PRUNE_INTERVAL);
+            if let Err(err) = prune(&dir, window) {
+                eprintln!("Warning: failed to prune old segments: {:#}", err);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prunes_segments_outside_the_window() {
+        assert!(parse_input("srt://:9000").is_ok());
+        assert!(parse_input("screen").is_ok());
+        assert!(parse_input("rtmp://host/live/stream").is_ok());
+        assert!(parse_input("http://host/stream").is_err());
+        assert!(parse_input("srt://").is_err());
+
+        let dir = std::env::temp_dir().join(format!("movieshare-live-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let old = SystemTime::now() - Duration::from_secs(600);
+        for name in ["video_0_init.mp4", "video_0_00001.m4s", "video_0_00002.m4s"] {
+            let file = std::fs::File::create(dir.join(name)).unwrap();
+            file.set_modified(old).unwrap();
+        }
+        std::fs::write(dir.join("video_0_00003.m4s"), b"").unwrap();
+
+        assert_eq!(prune(&dir, Duration::from_secs(120)).unwrap(), 2);
+        let mut left: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["video_0_00003.m4s", "video_0_init.mp4"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+## Preparing a title
+
+starting = Transkodierung startet...
+input = Eingabe: { $path }
+output = Ausgabe: { $path }
+plan = Plan:
+warning = Warnung: { $message }
+progress = { $percent } % mit { $fps } fps
+progress-left = { $percent } % mit { $fps } fps, noch { $left }
+representation-stats = Repräsentation mit { $mbps } MB/s: { $frames } Bilder, { $bytes } Bytes
+complete = Transkodierung abgeschlossen!
+cancelled = Transkodierung abgebrochen
+already-prepared = { $dir } ist bereits vorbereitet, nichts zu tun
+used = Verbraucht: { $usage }
+added-tracks =
+    { $count ->
+        [one] { $count } weitere Spur aus der Quelle hinzugefügt
+       *[other] { $count } weitere Spuren aus der Quelle hinzugefügt
+    }
+
+## The player page
+
+player-watch-together = Gemeinsam ansehen
+player-watching-together = Gemeinsam ansehen:
+player-viewers = Zuschauer
+player-copy-link = Link kopieren
+player-handoff = Auf einem anderen Gerät weitersehen
+player-handoff-code = Auf dem anderen Gerät { $code } eingeben
+player-enter-code = Code eingeben
+player-from-peers = , { $megabytes } MB von anderen Zuschauern
+player-disconnected = Verbindung zum Raum getrennt
+player-skip-intro = Intro überspringen
+player-skip-credits = Abspann überspringen
+player-skip-recap = Rückblick überspringen
+player-listen = Nur Ton
+player-watch-video = Zurück zum Video
@@ -0,0 +1,40 @@
+# Messages of the command line and of the player page. A translation is a
+# copy of this file named for its language, with the values translated;
+# messages it leaves out are shown in English.
+
+## Preparing a title
+
+starting = Starting transcoding...
+input = Input: { $path }
+output = Output: { $path }
+plan = Plan:
+warning = Warning: { $message }
+progress = { $percent }% at { $fps } fps
+progress-left = { $percent }% at { $fps } fps, { $left } left
+representation-stats = { $mbps } MB/s representation: { $frames } frames, { $bytes } bytes
+complete = Transcoding complete!
+cancelled = Transcoding cancelled
+already-prepared = { $dir } is already prepared, nothing to do
+used = Used { $usage }
+added-tracks =
+    { $count ->
+        [one] Added { $count } more track from the source
+       *[other] Added { $count } more tracks from the source
+    }
+
+## The player page
+
+player-watch-together = Watch together
+player-watching-together = Watching together:
+player-viewers = viewer(s)
+player-copy-link = Copy link
+player-handoff = Continue on another device
+player-handoff-code = On the other device, enter { $code }
+player-enter-code = Enter a code
+player-from-peers = , { $megabytes } MB from peers
+player-disconnected = Disconnected from the room
+player-skip-intro = Skip intro
+player-skip-credits = Skip credits
+player-skip-recap = Skip recap
+player-listen = Listen only
+player-watch-video = Back to the video
@@ -0,0 +1,36 @@
+## Preparing a title
+
+starting = Iniciando la transcodificación...
+input = Entrada: { $path }
+output = Salida: { $path }
+plan = Plan:
+warning = Aviso: { $message }
+progress = { $percent } % a { $fps } fps
+progress-left = { $percent } % a { $fps } fps, quedan { $left }
+representation-stats = Representación de { $mbps } MB/s: { $frames } fotogramas, { $bytes } bytes
+complete = ¡Transcodificación completada!
+cancelled = Transcodificación cancelada
+already-prepared = { $dir } ya está preparado, no hay nada que hacer
+used = Usado: { $usage }
+added-tracks =
+    { $count ->
+        [one] Se añadió { $count } pista más de la fuente
+       *[other] Se añadieron { $count } pistas más de la fuente
+    }
+
+## The player page
+
+player-watch-together = Ver juntos
+player-watching-together = Viendo juntos:
+player-viewers = espectador(es)
+player-copy-link = Copiar enlace
+player-handoff = Seguir en otro dispositivo
+player-handoff-code = En el otro dispositivo, introduce { $code }
+player-enter-code = Introducir un código
+player-from-peers = , { $megabytes } MB de otros espectadores
+player-disconnected = Desconectado de la sala
+player-skip-intro = Saltar intro
+player-skip-credits = Saltar créditos
+player-skip-recap = Saltar resumen
+player-listen = Solo audio
+player-watch-video = Volver al vídeo
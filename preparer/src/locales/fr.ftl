@@ -0,0 +1,36 @@
+## Preparing a title
+
+starting = Début du transcodage...
+input = Entrée : { $path }
+output = Sortie : { $path }
+plan = Plan :
+warning = Attention : { $message }
+progress = { $percent } % à { $fps } i/s
+progress-left = { $percent } % à { $fps } i/s, { $left } restantes
+representation-stats = Représentation à { $mbps } Mo/s : { $frames } images, { $bytes } octets
+complete = Transcodage terminé !
+cancelled = Transcodage annulé
+already-prepared = { $dir } est déjà prêt, rien à faire
+used = Utilisé : { $usage }
+added-tracks =
+    { $count ->
+        [one] { $count } piste de plus ajoutée depuis la source
+       *[other] { $count } pistes de plus ajoutées depuis la source
+    }
+
+## The player page
+
+player-watch-together = Regarder ensemble
+player-watching-together = Visionnage commun :
+player-viewers = spectateur(s)
+player-copy-link = Copier le lien
+player-handoff = Continuer sur un autre appareil
+player-handoff-code = Sur l’autre appareil, saisissez { $code }
+player-enter-code = Saisir un code
+player-from-peers = , { $megabytes } Mo reçus des pairs
+player-disconnected = Déconnecté du salon
+player-skip-intro = Passer l’intro
+player-skip-credits = Passer le générique
+player-skip-recap = Passer le résumé
+player-listen = Son seul
+player-watch-video = Revenir à la vidéo
@@ -1,287 +1,3962 @@
-use anyhow::{Context, Result};
-use gstreamer as gst;
-use gstreamer::prelude::*;
-use std::env;
-
-struct EncodingBranch {
-    queue1: gst::Element,
-    videoscale: gst::Element,
-    capsfilter: gst::Element,
-    videoconvert: gst::Element,
-    queue2: gst::Element,
-    encoder: gst::Element,
-    queue3: gst::Element,
-    parser: gst::Element,
-    queue4: gst::Element,
-}
-
-impl EncodingBranch {
-    fn new(bitrate_mbps: u32, preset: u32, keyframe_interval: u32) -> Result<Self> {
-        let bitrate_kbps = bitrate_mbps * 1000; // Convert MB/s to kbps
-
-        // Capsfilter to limit resolution to 1080p
-        let caps = gst::Caps::builder("video/x-raw")
-            .field("width", gst::IntRange::new(1, 1920))
-            .field("height", gst::IntRange::new(1, 1080))
-            .build();
-
-        Ok(Self {
-            queue1: gst::ElementFactory::make("queue").build()?,
-            videoscale: gst::ElementFactory::make("videoscale")
-                .property_from_str("method", "lanczos")
-                .build()?,
-            capsfilter: gst::ElementFactory::make("capsfilter")
-                .property("caps", &caps)
-                .build()?,
-            videoconvert: gst::ElementFactory::make("videoconvert")
-                .property_from_str("dither", "bayer")
-                .property_from_str("chroma-mode", "full")
-                .build()?,
-            queue2: gst::ElementFactory::make("queue").build()?,
-            encoder: gst::ElementFactory::make("svtav1enc")
-                .property("preset", preset)
-                .property("target-bitrate", bitrate_kbps)
-                .property("intra-period-length", keyframe_interval as i32)
-                .build()?,
-            queue3: gst::ElementFactory::make("queue").build()?,
-            parser: gst::ElementFactory::make("av1parse").build()?,
-            queue4: gst::ElementFactory::make("queue").build()?,
-        })
-    }
-
-    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
-        pipeline.add_many(&[
-            &self.queue1,
-            &self.videoscale,
-            &self.capsfilter,
-            &self.videoconvert,
-            &self.queue2,
-            &self.encoder,
-            &self.queue3,
-            &self.parser,
-            &self.queue4,
-        ])?;
+mod addtrack;
+mod admin;
+mod analytics;
+mod api;
+mod artwork;
+mod attention;
+mod auth;
+mod bencode;
+mod bugreport;
+mod chapters;
+mod clip;
+mod collections;
+mod compat;
+mod daemon;
+mod dashboard;
+mod dedupe;
+mod dlna;
+mod doctor;
+mod du;
+mod encodelog;
+mod encrypt;
+mod export;
+mod feed;
+mod grpc;
+mod history;
+mod i18n;
+mod ipfs;
+mod jit;
+mod library;
+mod live;
+mod markers;
+mod metrics;
+mod migrate;
+mod mirror;
+mod mpd;
+mod notify;
+mod once;
+mod origin;
+mod priority;
+mod progress;
+mod push;
+mod rating;
+mod rest;
+mod resume;
+mod rung;
+mod s3;
+mod scrub;
+mod search;
+mod seed;
+mod seekindex;
+mod selftest;
+mod serve;
+mod share;
+mod slideshow;
+mod sourcecache;
+mod split;
+mod storage;
+mod subpreview;
+mod swarm;
+mod sync;
+mod systemd;
+mod testmedia;
+mod throttle;
+mod tmdb;
+mod torrent;
+mod trash;
+mod trickplay;
+mod upgrade;
+mod upload;
+mod usage;
+mod users;
+mod verify;
+mod watchfolders;
+mod waveform;
+
+use addtrack::ExtraTracks;
+use anyhow::{Context, Result, anyhow, bail};
+use chapters::ChapterThumbnailBranch;
+use clap::{Parser, Subcommand};
+use dedupe::{FingerprintBranch, VideoHashBranch};
+use encodelog::EncodeLog;
+use encrypt::LibraryKey;
+use futures::StreamExt;
+use history::{EncodeRun, Expectation, RunFilter};
+use library::Catalog;
+use metrics::Metrics;
+use movieshare_core::analysis;
+use movieshare_core::conform::{self, FrameRate};
+use movieshare_core::cuts;
+use movieshare_core::damage::DecodeErrors;
+use movieshare_core::decoder::Decoder;
+use movieshare_core::encoder::Encoder;
+use movieshare_core::events::{Event, EventBody};
+use movieshare_core::grade::Grade;
+use movieshare_core::isolate::{self, Worker};
+use movieshare_core::journal::{Journal, JournalEvent};
+use movieshare_core::language;
+use movieshare_core::levels::{self, LevelPolicy};
+use movieshare_core::plan::{self, Action, Overview, Plan, SourceInfo};
+use movieshare_core::queue::{JobId, JobQueue, JobState, QueueConfig};
+use movieshare_core::retention::SourcePolicy;
+use movieshare_core::telecine::{self, InverseTelecine};
+use movieshare_core::watermark::{Mark, Position, Watermark};
+use movieshare_core::window::{self, EncodeWindow};
+use movieshare_core::{
+    CancelPolicy, CancellationToken, EncodingProfile, JobEvent, JobSpec, MissingAudio, Outcome,
+    PrepareJob, Preparer, Progress,
+};
+use notify::{JobReport, JobStats, JobStatus};
+use origin::Uploader;
+use sha2::{Digest, Sha256};
+use share::ShareKey;
+use sourcecache::SourceCache;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use storage::Storage;
+use usage::UsageMeter;
+use waveform::WaveformBranch;
+
+/// How much video to encode before asking to go ahead.
+const LONG_JOB: Duration = Duration::from_secs(30 * 60);
+
+/// Transcode a video file into a DASH presentation
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    prepare: Option<PrepareArgs>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run queued jobs in the background, controlled through a local socket
+    Daemon(DaemonArgs),
+    /// Accept and run jobs submitted over gRPC
+    ServeGrpc(ServeGrpcArgs),
+    /// Queue a job on the running daemon
+    Submit(SubmitArgs),
+    /// Show jobs on the running daemon
+    Status(StatusArgs),
+    /// Cancel a job on the running daemon
+    Cancel(CancelArgs),
+    /// Print the JSON Schema of the events written by --json and the daemon
+    EventSchema,
+    /// Print the JSON Schema of the job specs the daemon queues and reports
+    SpecSchema,
+    /// Serve a library of prepared titles over HTTP
+    Serve(ServeArgs),
+    /// Print an expiring link to one title
+    Share(ShareArgs),
+    /// Manage the catalog of prepared titles
+    #[command(subcommand)]
+    Library(LibraryCommand),
+    /// Manage who can sign in to the server and what they may watch
+    User(UserArgs),
+    /// Group titles into collections to grant and share together
+    Collection(CollectionArgs),
+    /// Write a BitTorrent v2 .torrent for a prepared title
+    Torrent(TorrentArgs),
+    /// Sum up the playback sessions the server has recorded
+    Stats(StatsArgs),
+    /// List past encode runs, to spot ones that got slower, bigger or failed
+    History(HistoryArgs),
+    /// List the titles whose run raised warnings, to review only those after
+    /// a batch, and clear them once checked
+    Qc(QcArgs),
+    /// Find the titles and times a line is spoken at, in the subtitles
+    /// `library scan` indexed
+    Search(SearchArgs),
+    /// Find the intro and credits episodes of a season share, or set markers
+    /// from a file, for skipping
+    Markers(MarkersArgs),
+    /// List titles that look or sound like the same movie, and which to keep
+    Dedupe(DedupeArgs),
+    /// Prepare several inputs, like a short film and a feature, as one
+    /// multi-period presentation
+    Playlist(PlaylistArgs),
+    /// Cut a frame-accurate MP4 clip out of a prepared title, to share a scene
+    Clip(ClipArgs),
+    /// Draw a subtitle cue over its video frame into a PNG, to check it
+    SubtitlePreview(SubtitlePreviewArgs),
+    /// Add a subtitle or audio track to a prepared title, encoding only the
+    /// new track
+    AddTrack(AddTrackArgs),
+    /// Encode one rung of a prepared title again from its source, leaving
+    /// the others as they are
+    ReencodeRung(ReencodeRungArgs),
+    /// Upgrade titles prepared by older releases to the current packaging
+    Migrate(MigrateArgs),
+    /// Share a live SRT or RTMP stream, or the desktop, as a DASH presentation
+    /// updated as it arrives
+    Live(LiveArgs),
+    /// Prepare a slideshow of a directory of photos over a music track
+    Slideshow(SlideshowArgs),
+    /// Prepare a generated test pattern to check this machine's GStreamer install
+    Selftest(SelftestArgs),
+    /// Report the GStreamer version and which optional features are usable
+    /// here, to start a support request with
+    Doctor(DoctorArgs),
+    /// Write a small test file with two audio languages, subtitles, chapters and a variable frame rate
+    Testmedia(TestmediaArgs),
+    /// Bundle a probe, a small sample of the input and the logs and pipeline
+    /// graph from preparing it into an archive to attach to a bug report
+    ReportBug(ReportBugArgs),
+    /// Copy the titles another movieshare server has that this library is
+    /// missing or has different, checking each file against its checksum
+    Sync(SyncArgs),
+    /// Say which browsers and TVs will play a prepared title, from its
+    /// codecs and any DRM its manifest signals
+    Checkcompat(CheckcompatArgs),
+    /// Run one job sent by `daemon --isolate` on stdin
+    #[command(hide = true)]
+    Worker,
+}
+
+#[derive(clap::Args)]
+struct StatsArgs {
+    /// Directory holding one prepared title per subdirectory
+    #[arg(long, default_value = ".")]
+    library: PathBuf,
+
+    /// Only count sessions from this long ago, e.g. 7d
+    #[arg(long, value_parser = humantime::parse_duration)]
+    since: Option<Duration>,
+
+    /// Print the totals as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Suggest a bitrate ladder from the rungs viewers sustain, instead of
+    /// the totals
+    #[arg(long)]
+    recommend_ladder: bool,
+
+    /// JSON or TOML encoding profile whose ladder to fit, instead of the
+    /// built-in defaults
+    #[arg(long, requires = "recommend_ladder")]
+    profile: Option<PathBuf>,
+
+    /// Write the profile with the suggested ladder to this JSON file, to
+    /// prepare future titles with through --profile
+    #[arg(long, value_name = "FILE", requires = "recommend_ladder")]
+    write_profile: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct SearchArgs {
+    /// Words to look for, in order
+    query: String,
+
+    /// Directory holding one prepared title per subdirectory
+    #[arg(long, default_value = ".")]
+    library: PathBuf,
+
+    /// Show at most this many cues
+    #[arg(long, default_value_t = 20)]
+    limit: usize,
+
+    /// Print the cues as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct HistoryArgs {
+    /// Directory holding one prepared title per subdirectory
+    #[arg(long, default_value = ".")]
+    library: PathBuf,
+
+    /// Only runs of this title
+    #[arg(long)]
+    title: Option<String>,
+
+    /// Only runs from this long ago, e.g. 30d
+    #[arg(long, value_parser = humantime::parse_duration)]
+    since: Option<Duration>,
+
+    /// Only runs that failed
+    #[arg(long)]
+    failed: bool,
+
+    /// How many runs to list, latest first
+    #[arg(long, default_value_t = 20)]
+    limit: u32,
+
+    /// Print the runs, with their settings, as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct TorrentArgs {
+    /// Directory holding the prepared title
+    output_dir: PathBuf,
+
+    /// Announce URL of a tracker; may be given more than once
+    #[arg(long = "tracker", value_name = "URL")]
+    trackers: Vec<String>,
+
+    /// Where to write the torrent, instead of next to the directory
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct DoctorArgs {
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct SelftestArgs {
+    /// Length of the test pattern
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    duration: Duration,
+
+    /// Write the test presentation here and keep it, instead of a temporary directory
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Directory for the temporary test presentation instead of the system's temporary directory
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    scratch_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct PlaylistArgs {
+    /// Directory to write the presentation into
+    output_dir: PathBuf,
+
+    /// Input media files, in playback order
+    #[arg(required = true, num_args = 2..)]
+    inputs: Vec<PathBuf>,
+
+    /// Title of each input's period, in the same order; defaults to the file name
+    #[arg(long = "title", value_name = "TITLE")]
+    titles: Vec<String>,
+
+    /// JSON or TOML encoding profile to use instead of the built-in defaults
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    /// Skip inputs the journal shows were already prepared
+    #[arg(long)]
+    resume: bool,
+}
+
+#[derive(clap::Args)]
+#[command(group(clap::ArgGroup::new("track").required(true).args(["subtitle_file", "audio_file"])))]
+struct AddTrackArgs {
+    /// Directory holding the prepared title
+    output_dir: PathBuf,
+
+    /// SubRip or WebVTT subtitles to add
+    #[arg(long)]
+    subtitle_file: Option<PathBuf>,
+
+    /// File whose audio to encode and add, like a dub
+    #[arg(long)]
+    audio_file: Option<PathBuf>,
+
+    /// Language of the new track, e.g. fr or fre
+    #[arg(long, value_name = "LANG", value_parser = language::parse)]
+    lang: Option<String>,
+
+    /// JSON or TOML encoding profile for the new audio, instead of the built-in defaults
+    #[arg(long)]
+    profile: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct ReencodeRungArgs {
+    /// Directory holding the prepared title
+    output_dir: PathBuf,
+
+    /// Rung to replace, by height like 1080p or by representation id
+    #[arg(long)]
+    rung: String,
+
+    /// Bitrate to encode it at, in MB/s
+    #[arg(long)]
+    bitrate: u32,
+
+    /// JSON or TOML encoding profile to take the preset and segment duration from,
+    /// instead of the built-in defaults
+    #[arg(long)]
+    profile: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct MigrateArgs {
+    /// Directory holding one prepared title per subdirectory
+    library: PathBuf,
+
+    /// Only list what each title needs
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(clap::Args)]
+struct LiveArgs {
+    /// Stream to receive, like srt://:9000 to listen for a sender or
+    /// rtmp://host/app/stream to pull one, or screen to capture the desktop
+    /// and the default audio input
+    #[arg(long, value_parser = live::parse_input)]
+    input: String,
+
+    /// Directory to write the manifest and segments into
+    output_dir: PathBuf,
+
+    /// JSON or TOML encoding profile to use instead of the built-in defaults
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    /// How much of the stream to keep for seeking back, e.g. 2m
+    #[arg(long, default_value = "2m", value_parser = humantime::parse_duration)]
+    window: Duration,
+
+    /// Keep all of it, and finish it as a regular title once it ends or
+    /// Ctrl-C stops it
+    #[arg(long)]
+    record: bool,
+}
+
+#[derive(clap::Args)]
+struct SlideshowArgs {
+    /// Directory of JPEG and PNG images, shown in the order of their names
+    #[arg(long = "slideshow")]
+    images: PathBuf,
+
+    /// Directory to write the manifest and segments into
+    output_dir: PathBuf,
+
+    /// Audio to play under the images; cut at the last image, and silence
+    /// if left out
+    #[arg(long)]
+    audio: Option<PathBuf>,
+
+    /// How long to show each image, e.g. 5s
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    per_image: Duration,
+
+    /// JSON or TOML encoding profile to use instead of the built-in defaults
+    #[arg(long)]
+    profile: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct ClipArgs {
+    /// Directory holding the prepared title
+    output_dir: PathBuf,
+
+    /// Where the clip starts, in seconds or [HH:]MM:SS[.sss]
+    #[arg(long, value_parser = parse_timestamp)]
+    start: f64,
+
+    /// Where the clip ends, in seconds or [HH:]MM:SS[.sss]
+    #[arg(long, value_parser = parse_timestamp)]
+    end: f64,
+
+    /// MP4 file to write
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Directory for intermediate files instead of the system's temporary directory
+    #[arg(long, value_name = "DIR")]
+    scratch_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct SubtitlePreviewArgs {
+    /// Directory holding the prepared title
+    output_dir: PathBuf,
+
+    /// Which subtitle track, counting from 0 in manifest order
+    #[arg(long, default_value_t = 0)]
+    track: usize,
+
+    /// When the cue shows, in seconds or [HH:]MM:SS[.sss]
+    #[arg(long, value_parser = parse_timestamp)]
+    at: f64,
+
+    /// PNG file to write
+    #[arg(long, default_value = "subtitle-preview.png")]
+    out: PathBuf,
+
+    /// Directory for intermediate files instead of the system's temporary directory
+    #[arg(long, value_name = "DIR")]
+    scratch_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct TestmediaArgs {
+    /// Matroska file to write
+    output: PathBuf,
+
+    /// Length of the file
+    #[arg(long, default_value = "20s", value_parser = humantime::parse_duration)]
+    duration: Duration,
+
+    /// Keep a constant frame rate instead of dropping frames
+    #[arg(long)]
+    cfr: bool,
+}
+
+#[derive(clap::Args)]
+struct ReportBugArgs {
+    /// File that failed to prepare
+    input: PathBuf,
+
+    /// Where in the input things go wrong, in seconds or [HH:]MM:SS[.sss]
+    #[arg(long, default_value = "0", value_parser = parse_timestamp)]
+    at: f64,
+
+    /// Seconds of the input to put in the sample
+    #[arg(long, default_value_t = 10.0)]
+    length: f64,
+
+    /// JSON or TOML encoding profile the failed run used
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    /// Where the failed run wrote, to include its journal
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Archive to write
+    #[arg(long, default_value = "movieshare-bug.tar")]
+    out: PathBuf,
+
+    /// Directory for the sample and its run instead of the system's temporary directory
+    #[arg(long, value_name = "DIR")]
+    scratch_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct SyncArgs {
+    /// Base URL of the server to copy from, like http://other-host:8080
+    remote: String,
+
+    /// Library directory to bring up to date
+    #[arg(long, default_value = ".")]
+    library: PathBuf,
+
+    /// User to sign in to the remote as, with the password read from stdin
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Only say what would be copied
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(clap::Args)]
+struct CheckcompatArgs {
+    /// Directory of the prepared title
+    output_dir: PathBuf,
+
+    /// Players to check for, separated by commas: chrome, firefox, edge,
+    /// safari, shield, chromecast, lg-webos or tizen; all of them if left out
+    #[arg(long, value_delimiter = ',')]
+    target: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct MarkersArgs {
+    /// Directories of the prepared episodes, in order; their sources must
+    /// still be where they were prepared from
+    #[arg(required = true, num_args = 1..)]
+    episodes: Vec<PathBuf>,
+
+    /// Set the markers in this file on the episodes instead of finding them:
+    /// one a line, as intro, recap or credits, then the start and end
+    #[arg(long)]
+    file: Option<PathBuf>,
+
+    /// How far into an episode the intro may end
+    #[arg(long, default_value = "10m", value_parser = humantime::parse_duration)]
+    intro_window: Duration,
+
+    /// How far from the end the credits may start
+    #[arg(long, default_value = "10m", value_parser = humantime::parse_duration)]
+    credits_window: Duration,
+
+    /// Shortest intro or credits worth marking
+    #[arg(long, default_value = "15s", value_parser = humantime::parse_duration)]
+    min_length: Duration,
+}
+
+#[derive(clap::Args)]
+struct DedupeArgs {
+    /// Directory holding one prepared title per subdirectory
+    #[arg(long, default_value = ".")]
+    library: PathBuf,
+
+    /// How alike two titles must sound, from 0.5 (unrelated) to 1 (identical)
+    #[arg(long, default_value_t = 0.8)]
+    threshold: f64,
+
+    /// How alike two titles must look, from 0.5 (unrelated) to 1 (identical)
+    #[arg(long, default_value_t = 0.9)]
+    video_threshold: f64,
+}
+
+#[derive(clap::Args)]
+struct UserArgs {
+    /// Directory holding one prepared title per subdirectory
+    #[arg(long, default_value = ".", global = true)]
+    library: PathBuf,
+
+    #[command(subcommand)]
+    command: UserCommand,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Add a user, reading their password from stdin
+    Add {
+        name: String,
+
+        /// Only let them watch titles and genres granted to them
+        #[arg(long)]
+        restricted: bool,
+
+        /// Don't set a password; they can only sign in through --auth-header
+        #[arg(long)]
+        no_password: bool,
+    },
+    /// Change a user's password, reading it from stdin
+    Passwd {
+        name: String,
+    },
+    Remove {
+        name: String,
+    },
+    List,
+    /// Let a restricted user watch a title or genre
+    Grant {
+        name: String,
+        #[command(flatten)]
+        grant: GrantArgs,
+    },
+    /// Take back a grant
+    Revoke {
+        name: String,
+        #[command(flatten)]
+        grant: GrantArgs,
+    },
+    /// Hide titles rated above RATING, or unrated, from a user; omit it to
+    /// lift the limit
+    Rating {
+        name: String,
+        #[arg(value_parser = rating::parse)]
+        rating: Option<String>,
+    },
+}
+
+#[derive(clap::Args)]
+#[group(required = true, multiple = false)]
+struct GrantArgs {
+    /// Name of a title's directory in the library
+    #[arg(long)]
+    title: Option<String>,
+
+    /// A TMDB genre, such as Family
+    #[arg(long)]
+    genre: Option<String>,
+
+    /// A collection, with whatever titles it holds now or later
+    #[arg(long)]
+    collection: Option<String>,
+}
+
+impl GrantArgs {
+    fn kind_and_value(&self) -> (users::GrantKind, &str) {
+        match (&self.title, &self.genre, &self.collection) {
+            (Some(title), _, _) => (users::GrantKind::Title, title),
+            (None, Some(genre), _) => (users::GrantKind::Genre, genre),
+            (None, None, Some(collection)) => (users::GrantKind::Collection, collection),
+            // clap requires exactly one of them
+            (None, None, None) => unreachable!(),
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct CollectionArgs {
+    /// Directory holding one prepared title per subdirectory
+    #[arg(long, default_value = ".", global = true)]
+    library: PathBuf,
+
+    #[command(subcommand)]
+    command: CollectionCommand,
+}
+
+#[derive(Subcommand)]
+enum CollectionCommand {
+    Create {
+        name: String,
+    },
+    /// Delete a collection, taking back any grants of it; its titles stay
+    Delete {
+        name: String,
+    },
+    /// Add titles to a collection, by the names of their directories
+    Add {
+        name: String,
+        #[arg(required = true)]
+        titles: Vec<String>,
+    },
+    Remove {
+        name: String,
+        #[arg(required = true)]
+        titles: Vec<String>,
+    },
+    List,
+    /// Print an expiring link to every title in a collection
+    Share {
+        name: String,
+
+        /// How long the link works for, e.g. 48h or 7d
+        #[arg(long, default_value = "48h", value_parser = humantime::parse_duration)]
+        expires: Duration,
+
+        /// Address the server is reachable at from the recipient's side
+        #[arg(long, default_value = "http://localhost:8080")]
+        base_url: String,
+    },
+}
+
+#[derive(clap::Args)]
+struct QcArgs {
+    /// Directory holding one prepared title per subdirectory
+    #[arg(long, default_value = ".", global = true)]
+    library: PathBuf,
+
+    #[command(subcommand)]
+    command: QcCommand,
+}
+
+#[derive(Subcommand)]
+enum QcCommand {
+    /// List the titles flagged for review, with what their runs warned about
+    List {
+        /// Print the flagged titles as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Drop the flag on a title once it's been checked
+    Clear {
+        /// Name of the title's directory in the library
+        title: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum LibraryCommand {
+    /// Index the titles in a library directory
+    Scan {
+        /// Directory holding one prepared title per subdirectory
+        library_dir: PathBuf,
+
+        /// Hash every file, not just the manifests
+        #[arg(long)]
+        checksums: bool,
+
+        /// Look up missing metadata on TMDB and cache artwork (needs TMDB_API_KEY)
+        #[arg(long)]
+        fetch_metadata: bool,
+    },
+    /// List the titles in the catalog
+    List {
+        /// Directory holding one prepared title per subdirectory
+        #[arg(long, default_value = ".")]
+        library: PathBuf,
+
+        /// Print the full catalog entries as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Report the storage each title and each of its representations takes,
+    /// from the catalog, with suggestions for reclaiming space
+    Du {
+        /// Directory holding one prepared title per subdirectory
+        #[arg(long, default_value = ".")]
+        library: PathBuf,
+
+        /// Print the usage and suggestions as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Drop a title from the catalog
+    Remove {
+        /// Name of the title's directory in the library
+        title: String,
+
+        /// Directory holding one prepared title per subdirectory
+        #[arg(long, default_value = ".")]
+        library: PathBuf,
+
+        /// Also move the title's directory to the library's trash, to be
+        /// deleted once --keep-for is up
+        #[arg(long)]
+        delete_files: bool,
+
+        /// How long a deleted title stays in the trash, e.g. 30d
+        #[arg(long, default_value = "30d", value_parser = humantime::parse_duration)]
+        keep_for: Duration,
+    },
+    /// Move a title deleted with `remove --delete-files` back from the
+    /// trash and add it to the catalog again
+    Restore {
+        /// Name of the title's directory in the library
+        title: String,
+
+        /// Directory holding one prepared title per subdirectory
+        #[arg(long, default_value = ".")]
+        library: PathBuf,
+    },
+    /// List the titles in the trash
+    Trash {
+        /// Directory holding one prepared title per subdirectory
+        #[arg(long, default_value = ".")]
+        library: PathBuf,
+    },
+    /// Print the log kept from the run that prepared a title, with the
+    /// settings it used and the warnings it raised
+    Log {
+        /// Name of the title's directory in the library
+        title: String,
+
+        /// Directory holding one prepared title per subdirectory
+        #[arg(long, default_value = ".")]
+        library: PathBuf,
+    },
+    /// Write the catalog's users, collections, watch positions and each
+    /// title's metadata and artwork, but no media, to a bundle directory
+    Export {
+        /// Directory to create the bundle in
+        out: PathBuf,
+
+        /// Directory holding one prepared title per subdirectory
+        #[arg(long, default_value = ".")]
+        library: PathBuf,
+    },
+    /// Merge a bundle from `library export` into this library, keeping
+    /// what's already here
+    Import {
+        /// Directory `library export` wrote
+        bundle: PathBuf,
+
+        /// Directory holding one prepared title per subdirectory
+        #[arg(long, default_value = ".")]
+        library: PathBuf,
+    },
+    /// Queue titles prepared with settings that differ materially from a
+    /// new profile, like a missing rung or another codec, or found damaged
+    /// by scrubbing, to be prepared again
+    Upgrade {
+        /// Directory holding one prepared title per subdirectory
+        #[arg(long, default_value = ".")]
+        library: PathBuf,
+
+        /// JSON or TOML encoding profile to upgrade to instead of the built-in defaults
+        #[arg(long)]
+        profile: Option<PathBuf>,
+
+        /// Only list the titles that would be prepared again, and why
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Higher runs first
+        #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+        priority: i32,
+
+        #[command(flatten)]
+        socket: SocketArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Directory holding one prepared title per subdirectory
+    library_dir: PathBuf,
+
+    /// Port to listen on
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0")]
+    bind: std::net::IpAddr,
+
+    /// Only serve titles through share links
+    #[arg(long)]
+    shared_only: bool,
+
+    /// PEM certificate chain; serves HTTPS instead of HTTP
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Require users from `preparer user` to sign in with their password
+    #[arg(long)]
+    auth: bool,
+
+    /// Trust this header to name the signed-in user, as set by an authenticating proxy
+    #[arg(long, conflicts_with = "auth")]
+    auth_header: Option<axum::http::HeaderName>,
+
+    /// Prepare media files in this directory into the library the first time they're watched
+    #[arg(long)]
+    sources: Option<PathBuf>,
+
+    /// Limit each remote viewer to this many bytes per second, e.g. 2M
+    #[arg(long, value_name = "RATE", value_parser = throttle::parse_rate)]
+    rate_limit: Option<u64>,
+
+    /// Limit all remote viewers together to this many bytes per second
+    #[arg(long, value_name = "RATE", value_parser = throttle::parse_rate)]
+    total_rate_limit: Option<u64>,
+
+    /// Answer 503 once remote viewers have this many requests in flight
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Apply the limits to viewers on the local network too
+    #[arg(long)]
+    limit_lan: bool,
+
+    /// Also offer titles that have a fallback.mp4 to DLNA/UPnP renderers on the LAN
+    #[arg(long, conflicts_with_all = ["auth", "auth_header", "shared_only", "tls_cert"])]
+    dlna: bool,
+
+    /// Let viewers watching together fetch segments from each other over
+    /// WebRTC, easing the load on this server's uplink
+    #[arg(long)]
+    p2p: bool,
+
+    /// Decrypt titles encrypted with `--encrypt-key FILE` as they're served
+    #[arg(long, value_name = "FILE", conflicts_with = "dlna")]
+    library_key: Option<PathBuf>,
+
+    /// Check every title's manifest and files before serving, listing broken
+    /// titles as unavailable instead of failing mid-playback
+    #[arg(long, conflicts_with = "library_key")]
+    verify_on_start: bool,
+}
+
+#[derive(clap::Args)]
+struct ShareArgs {
+    /// Name of the title's directory in the library
+    title: String,
+
+    /// Directory holding one prepared title per subdirectory
+    #[arg(long, default_value = ".")]
+    library: PathBuf,
+
+    /// How long the link works for, e.g. 48h or 7d
+    #[arg(long, default_value = "48h", value_parser = humantime::parse_duration)]
+    expires: Duration,
+
+    /// Address the server is reachable at from the recipient's side
+    #[arg(long, default_value = "http://localhost:8080")]
+    base_url: String,
+
+    /// Make the link good for one viewing, in one browser, fetched no faster
+    /// than it plays
+    #[arg(long)]
+    once: bool,
+
+    /// Tallest video rung the link may fetch, e.g. 720p, for viewers on
+    /// metered connections
+    #[arg(long, value_name = "RUNG", value_parser = share::parse_rung, conflicts_with = "once")]
+    max_rung: Option<u32>,
+}
+
+#[derive(clap::Args)]
+struct QueueArgs {
+    /// Persist the job queue to this file so jobs survive restarts
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Number of jobs to run at once
+    #[arg(long, default_value_t = 1)]
+    max_concurrent: usize,
+
+    /// Jobs to run at once on one encoder backend, like nvenc=1 for a GPU
+    /// with few encode sessions; may be given more than once
+    #[arg(long = "backend-limit", value_name = "BACKEND=JOBS", value_parser = parse_backend_limit)]
+    backend_limits: Vec<(String, usize)>,
+
+    /// Expose Prometheus metrics for the queue on this address (e.g. 0.0.0.0:9100)
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Run encodes at this nice value, from -20 to 19
+    #[arg(long, allow_hyphen_values = true, value_parser = priority::parse_nice)]
+    nice: Option<i32>,
+
+    /// Run encodes at this I/O priority: idle, best-effort or best-effort:N
+    #[arg(long, value_parser = priority::parse_ionice)]
+    ionice: Option<priority::IoPriority>,
+
+    /// Set the CPU weight of the daemon's cgroup, from 1 to 10000 (default 100);
+    /// needs a delegated cgroup, like a systemd service with Delegate=yes
+    #[arg(long)]
+    cpu_weight: Option<u32>,
+
+    /// Only encode during this daily window in local time, like 23:00-07:00;
+    /// running jobs are paused outside it
+    #[arg(long, value_parser = window::parse)]
+    encode_window: Option<EncodeWindow>,
+
+    /// Run each job in a child process, so a plugin crashing on a bad file
+    /// fails only that job instead of the whole daemon
+    #[arg(long)]
+    isolate: bool,
+}
+
+#[derive(clap::Args)]
+struct SocketArgs {
+    /// Control socket of the daemon [default: $XDG_RUNTIME_DIR/movieshare.sock]
+    #[arg(long)]
+    socket: Option<PathBuf>,
+}
+
+impl SocketArgs {
+    fn path(&self) -> PathBuf {
+        self.socket
+            .clone()
+            .unwrap_or_else(daemon::default_socket_path)
+    }
+}
+
+#[derive(clap::Args)]
+struct DaemonArgs {
+    #[command(flatten)]
+    socket: SocketArgs,
+
+    /// Also accept jobs over gRPC on this address, which must be a loopback
+    /// one as gRPC clients don't sign in
+    #[arg(long)]
+    grpc_addr: Option<SocketAddr>,
+
+    /// Also serve an HTTP JSON job API on this address; only a loopback one
+    /// unless --upload-library or --admin-library has clients sign in
+    #[arg(long)]
+    http_addr: Option<SocketAddr>,
+
+    /// Accept uploads on the HTTP API and prepare them into this library;
+    /// the job routes then need an unrestricted user of its catalog
+    #[arg(long, requires = "http_addr")]
+    upload_library: Option<PathBuf>,
+
+    /// Serve a web UI at /admin/ on the HTTP API for the job queue and this
+    /// library; sign in as an unrestricted user of its catalog
+    #[arg(long, requires = "http_addr")]
+    admin_library: Option<PathBuf>,
+
+    /// Seed a prepared title over BitTorrent, using the torrent made by
+    /// `preparer torrent`; may be given more than once
+    #[arg(long = "seed", value_name = "DIR")]
+    seeds: Vec<PathBuf>,
+
+    /// Port to accept BitTorrent peers on
+    #[arg(long, default_value_t = 6881)]
+    seed_port: u16,
+
+    /// Queue media files dropped into the folders set up in this TOML file,
+    /// each into its own library with its own profile
+    #[arg(long, value_name = "FILE")]
+    watch_folders: Option<PathBuf>,
+
+    /// Now and then read back every file of this library that has a
+    /// checksum, flagging titles whose files have rotted so `library
+    /// upgrade` prepares them again
+    #[arg(long, value_name = "DIR")]
+    scrub_library: Option<PathBuf>,
+
+    /// Bytes per second to read while scrubbing, like 20M
+    #[arg(long, value_name = "RATE", default_value = "20M", value_parser = throttle::parse_rate)]
+    scrub_rate: u64,
+
+    /// Time between scrubbing passes
+    #[arg(long, default_value = "7d", value_parser = humantime::parse_duration)]
+    scrub_every: Duration,
+
+    #[command(flatten)]
+    queue: QueueArgs,
+}
+
+#[derive(clap::Args)]
+struct ServeGrpcArgs {
+    /// Address to listen on, which must be a loopback one as clients don't
+    /// sign in
+    #[arg(long, default_value = "127.0.0.1:50051")]
+    addr: SocketAddr,
+
+    /// Also serve an HTTP JSON job API on this loopback address
+    #[arg(long)]
+    http_addr: Option<SocketAddr>,
+
+    #[command(flatten)]
+    queue: QueueArgs,
+}
+
+#[derive(clap::Args, Clone)]
+struct EncodingArgs {
+    /// EDL file of ranges to leave out, like commercials or recaps
+    #[arg(long, value_name = "FILE")]
+    cuts: Option<PathBuf>,
+
+    /// Composite this image over every video representation
+    #[arg(long, value_name = "IMAGE", conflicts_with = "watermark_text")]
+    watermark: Option<PathBuf>,
+
+    /// Composite this text over every video representation, e.g. "screener for Alice"
+    #[arg(long, value_name = "TEXT")]
+    watermark_text: Option<String>,
+
+    /// Corner of the picture to put the watermark in
+    #[arg(long, default_value = "top-right")]
+    position: Position,
+
+    /// Watermark opacity, from 0 (invisible) to 1 (opaque)
+    #[arg(long, default_value_t = 0.4)]
+    opacity: f64,
+
+    /// Brighten or darken the picture, from -1 to 1
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    brightness: f64,
+
+    /// Picture contrast, from 0 to 2
+    #[arg(long, default_value_t = 1.0)]
+    contrast: f64,
+
+    /// Colour saturation, from 0 (greyscale) to 2
+    #[arg(long, default_value_t = 1.0)]
+    saturation: f64,
+
+    /// Apply this .cube 3D LUT to the picture, after any other adjustments
+    #[arg(long, value_name = "FILE")]
+    lut: Option<PathBuf>,
+
+    /// Burn the running timecode into a review representation: the one at
+    /// this ladder bitrate, or the lowest
+    #[arg(long, value_name = "MBPS", num_args = 0..=1)]
+    burn_timecode: Option<Option<u32>>,
+
+    /// Also encode a rung as H.264, for players without AV1: the one at this
+    /// ladder bitrate, or the lowest
+    #[arg(long, value_name = "MBPS", num_args = 0..=1)]
+    h264: Option<Option<u32>>,
+
+    /// Language to signal for audio the source leaves untagged, e.g. en or eng
+    #[arg(long, value_name = "LANG", value_parser = language::parse)]
+    default_lang: Option<String>,
+
+    /// Highest AV1 level the household's decoders handle, like 4.0 or
+    /// 5.1-high; rungs beyond it are brought down to fit
+    #[arg(long, value_name = "LEVEL", value_parser = parse_decoder_level)]
+    decoder_level: Option<String>,
+
+    /// Only warn about rungs beyond --decoder-level instead of bringing them down
+    #[arg(long, requires = "decoder_level")]
+    warn_level: bool,
+
+    /// AV1 level and tier to signal in the manifest for every rung, like 4.0
+    /// or 5.1-high, instead of the lowest each one fits
+    #[arg(long, value_name = "LEVEL", value_parser = parse_decoder_level)]
+    codec_level: Option<String>,
+
+    /// Conform the video to this frame rate, like 24000/1001, 23.976 or 25,
+    /// so episodes of a series play back to back at one rate
+    #[arg(long, value_name = "RATE")]
+    frame_rate: Option<FrameRate>,
+
+    /// Also encode the audio with its dynamic range compressed, offered to
+    /// viewers as the dialogue-enhanced track
+    #[arg(long)]
+    drc: bool,
+
+    /// Resample the audio to this rate before encoding, in Hz
+    #[arg(long, value_name = "HZ")]
+    audio_rate: Option<u32>,
+
+    /// Audio resampling quality, from 0 (fastest) to 10 (best)
+    #[arg(long, value_name = "0-10")]
+    resample_quality: Option<u32>,
+}
+
+#[derive(clap::Args)]
+struct SubmitArgs {
+    /// Input media file
+    input_file: PathBuf,
+
+    /// Directory to write the manifest and segments into
+    output_dir: PathBuf,
+
+    /// JSON or TOML encoding profile to use instead of the built-in defaults
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    #[command(flatten)]
+    encoding: EncodingArgs,
+
+    /// Higher runs first
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    priority: i32,
+
+    #[command(flatten)]
+    socket: SocketArgs,
+}
+
+#[derive(clap::Args)]
+struct StatusArgs {
+    /// Only show this job
+    id: Option<JobId>,
+
+    #[command(flatten)]
+    socket: SocketArgs,
+}
+
+#[derive(clap::Args)]
+struct CancelArgs {
+    id: JobId,
+
+    #[command(flatten)]
+    socket: SocketArgs,
+}
+
+#[derive(clap::Args, Clone)]
+struct PrepareArgs {
+    /// Input media file, an http(s):// or s3:// URL to fetch it from, or -
+    /// to read it from standard input
+    input_file: String,
+
+    /// Directory to write the manifest and segments into, or an
+    /// s3://bucket/prefix, http(s):// or webdav(s):// URL or
+    /// sftp://user@host/path to upload them to as they are written
+    output_dir: String,
+
+    /// JSON or TOML encoding profile to use instead of the built-in defaults
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    /// Directory for temporary files, like S3 staging and segments waiting
+    /// on a flaky output, instead of the system's; point it at a fast local
+    /// disk
+    #[arg(long, value_name = "DIR")]
+    scratch_dir: Option<PathBuf>,
+
+    /// Keep copies of remote sources here, so runs tried again don't
+    /// download them again
+    #[arg(long, value_name = "DIR")]
+    source_cache: Option<PathBuf>,
+
+    /// Most the source cache holds before dropping the copies used least
+    /// recently, e.g. 200G
+    #[arg(long, default_value = "100G", value_parser = sourcecache::parse_size)]
+    source_cache_size: u64,
+
+    /// Encode at this nice value, from -20 to 19
+    #[arg(long, allow_hyphen_values = true, value_parser = priority::parse_nice)]
+    nice: Option<i32>,
+
+    /// Encode at this I/O priority: idle, best-effort or best-effort:N
+    #[arg(long, value_parser = priority::parse_ionice)]
+    ionice: Option<priority::IoPriority>,
+
+    #[command(flatten)]
+    encoding: EncodingArgs,
+
+    /// Prepare each chapter as its own episode, in numbered directories
+    /// under the output
+    #[arg(long, conflicts_with = "split_at")]
+    split_by_chapters: bool,
+
+    /// Split into episodes at these times, e.g. 22:05,44:10
+    #[arg(long, value_name = "TIMES", value_delimiter = ',', value_parser = parse_timestamp)]
+    split_at: Vec<f64>,
+
+    /// Group the split episodes into this collection of the library's
+    /// catalog, which is in the output
+    #[arg(long, value_name = "NAME")]
+    collection: Option<String>,
+
+    /// The one episode this run prepares, when splitting
+    #[arg(skip)]
+    episode: Option<split::Episode>,
+
+    /// Burn this subtitle file, like SRT, into every video representation,
+    /// styled by the profile's [subtitles.burn_in]
+    #[arg(long, value_name = "FILE")]
+    burn_subtitles: Option<PathBuf>,
+
+    /// Expose Prometheus metrics on this address (e.g. 0.0.0.0:9100)
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// POST a JSON job report to this URL when the job finishes or fails
+    #[arg(long)]
+    notify_url: Option<String>,
+
+    /// Show a desktop notification when the job finishes or fails
+    #[arg(long)]
+    notify_desktop: bool,
+
+    /// Skip the job if the journal shows it already completed for this
+    /// input, or encode only the rungs the ladder has gained since. A run
+    /// that was interrupted starts over, as the segments it wrote can't be
+    /// appended to
+    #[arg(long)]
+    resume: bool,
+
+    /// Segment AV1 or H.264 video as it is instead of re-encoding it, when
+    /// it's already within the top of the ladder
+    #[arg(long, conflicts_with_all = ["cuts", "watermark", "watermark_text"])]
+    repackage: bool,
+
+    /// Copy the streams that already fit instead of encoding them, like AV1
+    /// video as the top rung or Opus audio, and say which before starting
+    #[arg(long, conflicts_with_all = ["cuts", "repackage"])]
+    copy_streams: bool,
+
+    /// Encode the audio even when it's Opus already at or below the target
+    /// bitrate, which is otherwise copied to spare it a generation of loss
+    #[arg(long)]
+    force_audio_reencode: bool,
+
+    /// What to do when the source has no audio: skip leaves it out of the
+    /// presentation, silence gives it a silent track
+    #[arg(long, default_value = "skip")]
+    missing_audio: MissingAudio,
+
+    /// Prepare just the audio when the source has no video, rather than failing
+    #[arg(long)]
+    allow_audio_only: bool,
+
+    /// What to do where the source is too damaged to decode: abort the run,
+    /// skip the damaged stretch, or freeze-frame over it
+    #[arg(long, value_name = "HOW", default_value = "abort")]
+    on_decode_error: DecodeErrors,
+
+    /// Undo 3:2 pulldown to recover 23.976 fps film: auto does when the
+    /// source looks telecined, always or never regardless
+    #[arg(long, default_value = "auto")]
+    ivtc: InverseTelecine,
+
+    /// Decode the source once beforehand to find its scene cuts and how hard
+    /// it is to encode, and say what was found before starting. Every rung
+    /// then starts a keyframe at those cuts instead of looking for them
+    /// again; how hard it is to encode is only reported, not passed on to
+    /// the encoders
+    #[arg(long)]
+    analyze: bool,
+
+    /// Start long jobs without asking first
+    #[arg(long, short)]
+    yes: bool,
+
+    /// Also carry the source's other audio streams, each converted in a
+    /// pipeline of its own alongside the video as its text subtitles are
+    #[arg(long, conflicts_with = "cuts")]
+    all_tracks: bool,
+
+    /// Leave out the source's text subtitles, which are otherwise carried as
+    /// WebVTT whenever its whole timeline is
+    #[arg(long)]
+    no_subtitles: bool,
+
+    /// Lay a thumbnail every 10 seconds into tiles for scrub previews, given
+    /// in the manifest as a DASH-IF image track
+    #[arg(long)]
+    trick_play: bool,
+
+    /// Strip wall-clock times from the segments and manifest, so mirrors can
+    /// check the output against each other by checksum
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Warn when the audio starts or ends further than this from the video,
+    /// e.g. 100ms, as sources with broken timestamps do
+    #[arg(long, value_name = "THRESHOLD", value_parser = humantime::parse_duration)]
+    check_sync: Option<Duration>,
+
+    /// Which decoders to use: auto, software, vaapi or nvdec. Hardware
+    /// decoding keeps 4K HEVC sources from taking every core before encoding
+    #[arg(long, default_value = "auto")]
+    decoder: Decoder,
+
+    /// Sample how full the pipeline's queues run and say at the end whether
+    /// decoding, converting, encoding or writing held the encode back
+    #[arg(long)]
+    report_bottleneck: bool,
+
+    /// Look for long stretches of black or frozen frames, the usual signs
+    /// of a source that failed to decode in places, and say where they are
+    #[arg(long)]
+    qc: bool,
+
+    /// Which encoders to use: auto, software, vaapi, nvenc or qsv. Auto
+    /// takes a GPU's if one is installed, and SVT-AV1 and x264 otherwise
+    #[arg(long, default_value = "auto")]
+    encoder: Encoder,
+
+    /// Print machine-readable events to stdout, one JSON object per line
+    #[arg(long)]
+    json: bool,
+
+    /// Look the title up on TMDB, writing metadata.json and artwork (needs TMDB_API_KEY)
+    #[arg(long)]
+    fetch_metadata: bool,
+
+    /// Content rating to record in metadata.json, like PG-13, overriding TMDB's
+    #[arg(long, value_parser = rating::parse)]
+    rating: Option<String>,
+
+    /// Encrypt the output at rest with the library key in FILE, creating the
+    /// key if it doesn't exist; `preparer serve --library-key` decrypts it
+    #[arg(long, value_name = "FILE")]
+    encrypt_key: Option<PathBuf>,
+
+    /// Copy the finished output to user@host:/path over SSH with rsync
+    #[arg(long, value_name = "DEST")]
+    push: Option<String>,
+
+    /// Limit --push to this many KiB/s
+    #[arg(long, requires = "push")]
+    push_bwlimit: Option<u32>,
+
+    /// Also upload segments to s3://bucket/prefix, an HTTP origin taking
+    /// PUTs, a webdav(s):// URL or sftp://user@host/path as they're written,
+    /// with a live manifest, so the title can be watched there before it's
+    /// finished
+    #[arg(long, value_name = "URL", conflicts_with = "encrypt_key")]
+    origin: Option<String>,
+
+    /// What to do with the source once the output checks out: keep,
+    /// move:<dir>, link:<dir> to hardlink it there, or delete
+    #[arg(long, value_name = "POLICY", default_value = "keep")]
+    source_policy: SourcePolicy,
+
+    /// Add the finished output to IPFS and record its CID in metadata.json
+    #[arg(long)]
+    ipfs: bool,
+
+    /// HTTP API of the IPFS daemon used by --ipfs
+    #[arg(long, default_value = ipfs::DEFAULT_API, requires = "ipfs")]
+    ipfs_api: String,
+}
+
+fn main() -> Result<()> {
+    // Parse command line arguments
+    let cli = Cli::parse();
+    match (cli.command, cli.prepare) {
+        (Some(Command::Daemon(args)), _) => serve(
+            &args.queue,
+            Some(args.socket.path()),
+            args.grpc_addr,
+            args.http_addr,
+            rest::Libraries {
+                uploads: args.upload_library,
+                admin: args.admin_library,
+            },
+            Extras {
+                seeds: args.seeds,
+                seed_port: args.seed_port,
+                watch_folders: args.watch_folders,
+                scrub: args.scrub_library.map(|library| scrub::Scrub {
+                    library,
+                    rate: args.scrub_rate,
+                    every: args.scrub_every,
+                }),
+            },
+        ),
+        (Some(Command::ServeGrpc(args)), _) => serve(
+            &args.queue,
+            None,
+            Some(args.addr),
+            args.http_addr,
+            rest::Libraries::default(),
+            Extras::default(),
+        ),
+        (Some(Command::Submit(args)), _) => submit(args),
+        (Some(Command::Status(args)), _) => status(args),
+        (Some(Command::Cancel(args)), _) => {
+            daemon::request(
+                &args.socket.path(),
+                &daemon::Request::Cancel { id: args.id },
+            )?;
+            println!("Cancelled job {}", args.id);
+            Ok(())
+        }
+        (Some(Command::Worker), _) => isolate::run_worker(
+            std::io::BufReader::new(std::io::stdin()),
+            std::io::stdout().lock(),
+        ),
+        (Some(Command::EventSchema), _) => {
+            println!("{}", Event::json_schema()?);
+            Ok(())
+        }
+        (Some(Command::SpecSchema), _) => {
+            println!("{}", JobSpec::json_schema()?);
+            Ok(())
+        }
+        (Some(Command::Serve(args)), _) => {
+            if !args.library_dir.is_dir() {
+                bail!(
+                    "Library directory not found: {}",
+                    args.library_dir.display()
+                );
+            }
+            if let Some(sources) = &args.sources
+                && !sources.is_dir()
+            {
+                bail!("Sources directory not found: {}", sources.display());
+            }
+            let auth = match (args.auth, args.auth_header) {
+                (_, Some(header)) => Some(auth::AuthMode::Header(header)),
+                (true, None) => Some(auth::AuthMode::Password),
+                (false, None) => None,
+            };
+            if auth.is_some() {
+                let users = Catalog::open_existing(&args.library_dir)?
+                    .map(|catalog| catalog.users())
+                    .transpose()?
+                    .unwrap_or_default();
+                if users.is_empty() {
+                    bail!("Signing in needs a user; add one with `preparer user add`");
+                }
+            }
+            let config = serve::ServeConfig {
+                key: ShareKey::load_or_create(&args.library_dir)?,
+                library: args.library_dir,
+                shared_only: args.shared_only,
+                auth,
+                sources: args.sources,
+                dlna: args.dlna,
+                limits: throttle::Limits {
+                    per_client: args.rate_limit,
+                    total: args.total_rate_limit,
+                    max_connections: args.max_connections,
+                    include_lan: args.limit_lan,
+                },
+                p2p: args.p2p,
+                library_key: args
+                    .library_key
+                    .as_deref()
+                    .map(LibraryKey::load)
+                    .transpose()?,
+                verify_on_start: args.verify_on_start,
+            };
+            let tls = args
+                .tls_cert
+                .zip(args.tls_key)
+                .map(|(cert, key)| serve::TlsFiles { cert, key });
+            serve::serve(config, SocketAddr::new(args.bind, args.port), tls)
+        }
+        (Some(Command::Share(args)), _) => share(args),
+        (Some(Command::Library(command)), _) => library(command),
+        (Some(Command::User(args)), _) => user(args),
+        (Some(Command::Collection(args)), _) => collection(args),
+        (Some(Command::Torrent(args)), _) => make_torrent(args),
+        (Some(Command::Stats(args)), _) => stats(args),
+        (Some(Command::History(args)), _) => history(args),
+        (Some(Command::Qc(args)), _) => qc(args),
+        (Some(Command::Search(args)), _) => search(args),
+        (Some(Command::Markers(args)), _) => find_markers(args),
+        (Some(Command::Dedupe(args)), _) => dedupe(args),
+        (Some(Command::Playlist(args)), _) => prepare_playlist(args),
+        (Some(Command::Clip(args)), _) => make_clip(args),
+        (Some(Command::SubtitlePreview(args)), _) => preview_subtitle(args),
+        (Some(Command::AddTrack(args)), _) => add_track(args),
+        (Some(Command::ReencodeRung(args)), _) => reencode_rung(args),
+        (Some(Command::Migrate(args)), _) => migrate_library(args),
+        (Some(Command::Live(args)), _) => prepare_live(args),
+        (Some(Command::Slideshow(args)), _) => prepare_slideshow(args),
+        (Some(Command::Selftest(args)), _) => run_selftest(args),
+        (Some(Command::Doctor(args)), _) => doctor(args),
+        (Some(Command::Testmedia(args)), _) => write_testmedia(args),
+        (Some(Command::ReportBug(args)), _) => report_bug(args),
+        (Some(Command::Sync(args)), _) => sync_library(args),
+        (Some(Command::Checkcompat(args)), _) => check_compat(args),
+        (None, Some(args)) => prepare(args),
+        // clap requires the prepare arguments when there is no subcommand
+        (None, None) => unreachable!(),
+    }
+}
+
+fn parse_decoder_level(level: &str) -> Result<String> {
+    levels::parse(level)?;
+    Ok(level.to_string())
+}
+
+/// Apply `--decoder-level` and `--warn-level` to `profile`.
+/// Where to put temporary files: `--scratch-dir`, or the system's default.
+fn scratch_dir(dir: &Option<PathBuf>) -> Result<PathBuf> {
+    match dir {
+        Some(dir) if !dir.is_dir() => bail!("Scratch directory not found: {}", dir.display()),
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(std::env::temp_dir()),
+    }
+}
+
+/// Read a JSON profile, or a TOML one if its name ends in `.toml`.
+fn load_profile(path: &Option<PathBuf>) -> Result<EncodingProfile> {
+    let Some(path) = path else {
+        return Ok(EncodingProfile::default());
+    };
+    let text = std::fs::read_to_string(path)
+        .context(format!("Failed to read profile: {}", path.display()))?;
+    match path.extension().is_some_and(|ext| ext == "toml") {
+        true => EncodingProfile::from_toml(&text),
+        false => EncodingProfile::from_json(&text),
+    }
+    .context(format!("Invalid profile: {}", path.display()))
+}
+
+impl EncodingArgs {
+    /// Override the parts of `profile` set on the command line.
+    fn apply_to(&self, profile: &mut EncodingProfile) -> Result<()> {
+        burn_timecode(profile, self.burn_timecode)?;
+        h264_rung(profile, self.h264)?;
+        if self.default_lang.is_some() {
+            profile.default_language = self.default_lang.clone();
+        }
+        if self.decoder_level.is_some() {
+            profile.decoder_level = self.decoder_level.clone();
+            profile.level_policy = match self.warn_level {
+                true => LevelPolicy::Warn,
+                false => LevelPolicy::Adjust,
+            };
+        }
+        if self.codec_level.is_some() {
+            profile.codec_level = self.codec_level.clone();
+        }
+        if self.frame_rate.is_some() {
+            profile.frame_rate = self.frame_rate;
+        }
+        profile.audio.drc |= self.drc;
+        if let Some(rate) = self.audio_rate {
+            profile.audio.sample_rate = rate;
+        }
+        if let Some(quality) = self.resample_quality {
+            profile.audio.resample_quality = quality;
+        }
         Ok(())
     }
 
-    fn link(&self, tee: &gst::Element, dashsink: &gst::Element) -> Result<()> {
-        // Link from tee
-        tee.link(&self.queue1)?;
-
-        // Link the encoding chain with scaling and conversion
-        self.queue1.link(&self.videoscale)?;
-        self.videoscale.link(&self.capsfilter)?;
-        self.capsfilter.link(&self.videoconvert)?;
-        self.videoconvert.link(&self.queue2)?;
-        self.queue2.link(&self.encoder)?;
-        self.encoder.link(&self.queue3)?;
-        self.queue3.link(&self.parser)?;
-
-        // Link with caps filter
-        let caps = gst::Caps::builder("video/x-av1")
-            .field("stream-format", "obu-stream")
-            .field("alignment", "tu")
-            .build();
-        self.parser.link_filtered(&self.queue4, &caps)?;
-
-        // Link to dashsink
-        let video_sink_pad = dashsink
-            .request_pad_simple("video_%u")
-            .context("Failed to get video pad from dashsink")?;
-        let video_src_pad = self
-            .queue4
-            .static_pad("src")
-            .context("Failed to get src pad from queue4")?;
-        video_src_pad.link(&video_sink_pad)?;
+    /// The ranges `--cuts` leaves out.
+    fn cuts(&self) -> Result<Vec<cuts::Cut>> {
+        match &self.cuts {
+            Some(path) => cuts::read_edl(path),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The watermark asked for with `--watermark` or `--watermark-text`.
+    fn watermark(&self) -> Result<Option<Watermark>> {
+        let mark = match (&self.watermark, &self.watermark_text) {
+            (Some(image), _) => Mark::Image(std::path::absolute(image)?),
+            (None, Some(text)) => Mark::Text(text.clone()),
+            (None, None) => return Ok(None),
+        };
+        let watermark = Watermark {
+            mark,
+            position: self.position,
+            opacity: self.opacity,
+        };
+        watermark.validate()?;
+        Ok(Some(watermark))
+    }
+
+    /// The grade asked for, if it changes anything.
+    fn grade(&self) -> Result<Option<Grade>> {
+        let grade = Grade {
+            brightness: self.brightness,
+            contrast: self.contrast,
+            saturation: self.saturation,
+            lut: self.lut.as_deref().map(std::path::absolute).transpose()?,
+        };
+        grade.validate()?;
+        Ok((!grade.is_neutral()).then_some(grade))
+    }
+}
+
+/// Apply `--burn-timecode`, defaulting to the lowest rung of the ladder.
+fn burn_timecode(profile: &mut EncodingProfile, rung: Option<Option<u32>>) -> Result<()> {
+    if let Some(rung) = rung {
+        profile.timecode_rung = rung.or_else(|| profile.ladder.iter().min().copied());
+        profile.validate()?;
+    }
+    Ok(())
+}
+
+/// A progress sample as people read it, like `42.0% at 48.5 fps, 12m 3s left`.
+fn progress_line(progress: &Progress) -> String {
+    let percent = format!("{:.1}", progress.fraction * 100.0);
+    let fps = format!("{:.1}", progress.fps);
+    match progress.eta_secs {
+        Some(eta) => {
+            let left = humantime::format_duration(Duration::from_secs(eta as u64)).to_string();
+            i18n::tr(
+                "progress-left",
+                &[
+                    ("percent", percent.into()),
+                    ("fps", fps.into()),
+                    ("left", left.into()),
+                ],
+            )
+        }
+        None => i18n::tr(
+            "progress",
+            &[("percent", percent.into()), ("fps", fps.into())],
+        ),
+    }
+}
+
+/// Apply `--h264`, defaulting to the lowest rung of the ladder.
+fn h264_rung(profile: &mut EncodingProfile, rung: Option<Option<u32>>) -> Result<()> {
+    if let Some(rung) = rung {
+        profile.h264_rung = rung.or_else(|| profile.ladder.iter().min().copied());
+        profile.validate()?;
+    }
+    Ok(())
+}
+
+/// What the daemon runs next to its job queue, besides front ends.
+#[derive(Default)]
+struct Extras {
+    seeds: Vec<PathBuf>,
+    seed_port: u16,
+    watch_folders: Option<PathBuf>,
+    scrub: Option<scrub::Scrub>,
+}
+
+/// Run a job queue behind whichever front ends were asked for.
+fn serve(
+    args: &QueueArgs,
+    socket: Option<PathBuf>,
+    grpc_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    libraries: rest::Libraries,
+    extras: Extras,
+) -> Result<()> {
+    let Extras {
+        seeds,
+        seed_port,
+        watch_folders,
+        scrub,
+    } = extras;
+    let scrubbed = scrub.as_ref().map(|scrub| &scrub.library);
+    let libraries_used = [
+        libraries.uploads.as_ref(),
+        libraries.admin.as_ref(),
+        scrubbed,
+    ];
+    for library in libraries_used.into_iter().flatten() {
+        if !library.is_dir() {
+            bail!("Library directory not found: {}", library.display());
+        }
+    }
+
+    let watch_folders = watch_folders
+        .as_deref()
+        .map(watchfolders::load)
+        .transpose()?
+        .unwrap_or_default();
+
+    // Before the queue starts any threads, so they all inherit it
+    priority::apply(args.nice, args.ionice)?;
+    if let Some(weight) = args.cpu_weight {
+        priority::set_cpu_weight(weight)?;
+    }
+    let queue = JobQueue::open(QueueConfig {
+        max_concurrent: args.max_concurrent,
+        backend_limits: args.backend_limits.iter().cloned().collect(),
+        state_file: args.state_file.clone(),
+        window: args.encode_window,
+        worker: match args.isolate {
+            true => Some(Worker {
+                program: std::env::current_exe()?,
+                args: vec![String::from("worker")],
+            }),
+            false => None,
+        },
+    })?;
+    if let Some(window) = args.encode_window {
+        println!("Encoding only between {}", window);
+    }
+
+    // Sockets from systemd socket activation replace the ones we'd bind
+    let mut activated = systemd::Listeners::from_env()?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let mut servers: Vec<Pin<Box<dyn Future<Output = Result<()>>>>> = Vec::new();
+        let control = match (activated.take("control"), socket) {
+            (Some(fd), _) => Some((
+                systemd::unix_listener(fd)?,
+                String::from("socket from systemd"),
+            )),
+            (None, Some(path)) => Some((daemon::listen(&path).await?, path.display().to_string())),
+            (None, None) => None,
+        };
+        if let Some((listener, name)) = control {
+            println!("Accepting jobs on {}", name);
+            servers.push(Box::pin(daemon::serve(queue.clone(), listener)));
+        }
+        let grpc = match (activated.take("grpc"), grpc_addr) {
+            (Some(fd), _) => Some(systemd::tcp_listener(fd)?),
+            (None, Some(addr)) => Some(listen(addr).await?),
+            (None, None) => None,
+        };
+        if let Some(listener) = grpc {
+            // gRPC has no signing in, so it only takes jobs from this machine
+            rest::check_listener(listener.local_addr()?, false)?;
+            println!("Accepting jobs over gRPC on {}", listener.local_addr()?);
+            servers.push(Box::pin(grpc::serve(queue.clone(), listener)));
+        }
+        let http = match (activated.take("http"), http_addr) {
+            (Some(fd), _) => Some(systemd::tcp_listener(fd)?),
+            (None, Some(addr)) => Some(listen(addr).await?),
+            (None, None) => None,
+        };
+        if let Some(listener) = http {
+            let addr = listener.local_addr()?;
+            rest::check_listener(addr, libraries.signs_in())?;
+            println!("Accepting jobs over HTTP on http://{}/jobs", addr);
+            if let Some(library) = &libraries.uploads {
+                println!("Accepting uploads into {}", library.display());
+            }
+            if libraries.admin.is_some() {
+                println!("Admin UI on http://{}/admin/", addr);
+            }
+            servers.push(Box::pin(rest::serve(queue.clone(), listener, libraries)));
+        }
+        activated.finish()?;
+        if !seeds.is_empty() {
+            println!("Seeding {} title(s) on port {}", seeds.len(), seed_port);
+            servers.push(Box::pin(seed::serve(seeds, seed_port)));
+        }
+        for folder in &watch_folders {
+            println!(
+                "Watching {} for titles to prepare into {}",
+                folder.path.display(),
+                folder.library.display()
+            );
+        }
+        if !watch_folders.is_empty() {
+            servers.push(Box::pin(watchfolders::run(queue.clone(), watch_folders)));
+        }
+        if let Some(scrub) = scrub {
+            println!(
+                "Scrubbing {} every {} at {} bytes a second",
+                scrub.library.display(),
+                humantime::format_duration(scrub.every),
+                scrub.rate
+            );
+            servers.push(Box::pin(scrub::run(scrub)));
+        }
+        if let Some(interval) = systemd::watchdog_interval() {
+            servers.push(Box::pin(systemd::watchdog(interval)));
+        }
+        if let Some(addr) = args.metrics_addr {
+            let metrics = Arc::new(Metrics::default());
+            metrics::serve(metrics.clone(), addr)?;
+            println!("Serving metrics on http://{}/metrics", addr);
+            servers.push(Box::pin(metrics::follow(metrics, queue.clone())));
+        }
+        systemd::notify("READY=1")?;
+        servers.push(Box::pin(dashboard::run(queue.clone())));
+        futures::future::try_join_all(servers).await?;
+        Ok(())
+    })
+}
+
+async fn listen(addr: SocketAddr) -> Result<tokio::net::TcpListener> {
+    tokio::net::TcpListener::bind(addr)
+        .await
+        .context(format!("Failed to listen on {}", addr))
+}
+
+fn share(args: ShareArgs) -> Result<()> {
+    if !args
+        .library
+        .join(&args.title)
+        .join("manifest.mpd")
+        .is_file()
+    {
+        bail!(
+            "No prepared title named {} in {}",
+            args.title,
+            args.library.display()
+        );
+    }
+
+    if let Some(max_height) = args.max_rung {
+        let path = args.library.join(&args.title).join(library::MANIFEST);
+        let xml =
+            std::fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+        if !mpd::parse(&xml)?
+            .representations
+            .iter()
+            .any(|representation| {
+                representation.content_type == "video"
+                    && representation
+                        .height
+                        .is_none_or(|height| height <= max_height)
+            })
+        {
+            bail!("{} has no rung of {}p or less", args.title, max_height);
+        }
+    }
+
+    let key = ShareKey::load_or_create(&args.library)?;
+    let expires = SystemTime::now() + args.expires;
+    let link = match (args.once, args.max_rung) {
+        (_, Some(max_height)) => format!(
+            "{}/s/{}/",
+            args.base_url.trim_end_matches('/'),
+            key.sign_capped(&args.title, max_height, expires)
+        ),
+        (true, None) => format!(
+            "{}/o/{}/",
+            args.base_url.trim_end_matches('/'),
+            key.sign_once(&args.title, expires)
+        ),
+        (false, None) => format!(
+            "{}/s/{}/",
+            args.base_url.trim_end_matches('/'),
+            key.sign(&args.title, expires)
+        ),
+    };
+    println!("{}", link);
+
+    let qr = args.library.join(&args.title).join(share::QR_CODE);
+    share::save_qr(&link, &qr)?;
+    // Keep stdout to the link alone when it's being captured
+    if std::io::stdout().is_terminal() {
+        println!("\n{}", share::qr_text(&link)?);
+        println!("Saved the QR code to {}", qr.display());
+    }
+    Ok(())
+}
+
+fn library(command: LibraryCommand) -> Result<()> {
+    let open = |library: &Path| {
+        Catalog::open_existing(library)?.context(format!(
+            "No catalog in {}; run `preparer library scan` first",
+            library.display()
+        ))
+    };
+
+    match command {
+        LibraryCommand::Scan {
+            library_dir,
+            checksums,
+            fetch_metadata,
+        } => {
+            if fetch_metadata {
+                let client = tmdb::Client::from_env()?;
+                for entry in std::fs::read_dir(&library_dir)?.flatten() {
+                    let dir = entry.path();
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if name.starts_with('.') || !dir.join(library::MANIFEST).is_file() {
+                        continue;
+                    }
+                    if let Err(err) = fetch_metadata_into(&client, &name, &dir) {
+                        eprintln!("Warning: {:#}", err);
+                    }
+                }
+            }
+            let mut catalog = Catalog::open(&Catalog::default_path(&library_dir))?;
+            let summary = catalog.scan(&library_dir, checksums)?;
+            println!(
+                "{} added, {} updated, {} unchanged, {} removed",
+                summary.added, summary.updated, summary.unchanged, summary.removed
+            );
+        }
+        LibraryCommand::List { library, json } => {
+            let titles = open(&library)?.titles()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&titles)?);
+                return Ok(());
+            }
+            println!("{:<9} {:>9} {:>6}  TITLE", "DURATION", "SIZE", "RUNGS");
+            for title in titles {
+                let duration = title.duration_secs.map_or(String::new(), |secs| {
+                    let secs = secs as u64;
+                    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+                });
+                let rungs = title
+                    .ladder
+                    .iter()
+                    .filter(|r| r.content_type == "video")
+                    .count();
+                println!(
+                    "{:<9} {:>8}M {:>6}  {}",
+                    duration,
+                    title.size_bytes / 1_000_000,
+                    rungs,
+                    title.name
+                );
+            }
+        }
+        LibraryCommand::Du { library, json } => {
+            let catalog = open(&library)?;
+            let stats = catalog.playback_stats(None)?;
+            let mut usages = Vec::new();
+            let mut suggestions = Vec::new();
+            for title in catalog.titles()? {
+                let manifest = title.path.join(library::MANIFEST);
+                let xml = std::fs::read_to_string(&manifest)
+                    .context(format!("Failed to read {}", manifest.display()))?;
+                let usage = du::title_usage(&title.name, &xml, &catalog.files(&title.name)?)?;
+                let watched = stats.iter().find(|stats| stats.title == title.name);
+                suggestions.extend(du::suggest(&usage, title.duration_secs, watched));
+                usages.push(usage);
+            }
+            usages.sort_by_key(|usage| std::cmp::Reverse(usage.size_bytes));
+            suggestions.sort_by_key(|suggestion| std::cmp::Reverse(suggestion.size_bytes));
+            if json {
+                let report = serde_json::json!({ "titles": usages, "suggestions": suggestions });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(());
+            }
+            for usage in &usages {
+                println!("{:>8}M  {}", usage.size_bytes / 1_000_000, usage.title);
+                for representation in &usage.representations {
+                    println!(
+                        "{:>8}M    {}",
+                        representation.size_bytes / 1_000_000,
+                        representation.label()
+                    );
+                }
+                if usage.other_bytes > 0 {
+                    println!("{:>8}M    other files", usage.other_bytes / 1_000_000);
+                }
+            }
+            let total: u64 = usages.iter().map(|usage| usage.size_bytes).sum();
+            println!("{:>8}M  in all", total / 1_000_000);
+            if !suggestions.is_empty() {
+                println!();
+                println!("To reclaim space, drop:");
+            }
+            for suggestion in suggestions {
+                println!(
+                    "{:>8}M  the {} rung of {}, {}",
+                    suggestion.size_bytes / 1_000_000,
+                    suggestion.representation,
+                    suggestion.title,
+                    suggestion.reason
+                );
+            }
+        }
+        LibraryCommand::Remove {
+            title,
+            library,
+            delete_files,
+            keep_for,
+        } => {
+            let mut catalog = open(&library)?;
+            let Some(entry) = catalog.title(&title)? else {
+                bail!("No title named {} in the catalog", title);
+            };
+            purge_trash(&library)?;
+            if delete_files {
+                trash::trash(&library, &entry.path, keep_for)?;
+            }
+            catalog.remove(&title)?;
+            match delete_files {
+                true => println!(
+                    "Removed {}; `preparer library restore` brings it back for {}",
+                    title,
+                    humantime::format_duration(keep_for)
+                ),
+                false => println!("Removed {}", title),
+            }
+        }
+        LibraryCommand::Restore { title, library } => {
+            purge_trash(&library)?;
+            let path = trash::restore(&library, &title)?;
+            Catalog::open(&Catalog::default_path(&library))?.scan(&library, false)?;
+            println!("Restored {} to {}", title, path.display());
+        }
+        LibraryCommand::Trash { library } => {
+            purge_trash(&library)?;
+            for entry in trash::entries(&library)? {
+                let left = UNIX_EPOCH + Duration::from_secs(entry.expires_at);
+                let left = left.duration_since(SystemTime::now()).unwrap_or_default();
+                println!(
+                    "{:<32} deleted for good in {}",
+                    entry.name,
+                    humantime::format_duration(Duration::from_secs(left.as_secs() / 3600 * 3600))
+                );
+            }
+        }
+        LibraryCommand::Log { title, library } => {
+            let dir = library.join(&title);
+            // Titles prepared before logs were kept have none
+            let file = library::Metadata::read(&dir)?
+                .and_then(|metadata| metadata.encode_log)
+                .unwrap_or_else(|| encodelog::ENCODE_LOG.to_string());
+            match encodelog::read(&dir, &file)? {
+                Some(lines) => print!("{}", lines),
+                None => bail!("{} has no encode log", title),
+            }
+        }
+        LibraryCommand::Export { out, library } => {
+            let bundle = export::export(&library, &out)?;
+            println!(
+                "Exported {} titles, {} users, {} collections and {} watch positions to {}",
+                bundle.titles.len(),
+                bundle.users.len(),
+                bundle.collections.len(),
+                bundle.progress.len(),
+                out.display()
+            );
+        }
+        LibraryCommand::Import { bundle, library } => {
+            let summary = export::import(&bundle, &library)?;
+            println!(
+                "Added metadata or artwork to {} titles, {} users, {} collections and {} watch positions",
+                summary.titles, summary.users, summary.collections, summary.progress
+            );
+            if !summary.missing_titles.is_empty() {
+                eprintln!(
+                    "Warning: not in the library, so skipped until their media is: {}",
+                    summary.missing_titles.join(", ")
+                );
+            }
+        }
+        LibraryCommand::Upgrade {
+            library,
+            profile,
+            dry_run,
+            priority,
+            socket,
+        } => {
+            let profile = load_profile(&profile)?;
+            let catalog = open(&library)?;
+            let mut queued = 0;
+            for title in catalog.titles()? {
+                let recorded = catalog.recorded_settings(&title.name)?;
+                let mut differences =
+                    upgrade::differences(&title.ladder, recorded.as_ref(), &profile);
+                if let Some(damage) = catalog.damage(&title.name)? {
+                    differences.push(format!("damaged: {}", damage));
+                }
+                if differences.is_empty() {
+                    continue;
+                }
+                println!("{}: {}", title.name, differences.join("; "));
+                if dry_run {
+                    continue;
+                }
+                let input = match library::recorded_source(&title.path) {
+                    Ok(input) => input,
+                    Err(err) => {
+                        eprintln!("Skipping {}: {:#}", title.name, err);
+                        continue;
+                    }
+                };
+                // The daemon runs elsewhere, so relative paths would resolve against its directory
+                let mut spec = JobSpec::new(
+                    std::path::absolute(&input)?,
+                    std::path::absolute(&title.path)?,
+                );
+                spec.profile = profile.clone();
+                let request = daemon::Request::Submit {
+                    spec: Box::new(spec),
+                    priority,
+                };
+                if let daemon::Response::Submitted { id } =
+                    daemon::request(&socket.path(), &request)?
+                {
+                    println!("  submitted job {}", id);
+                    queued += 1;
+                }
+            }
+            if !dry_run {
+                println!("Queued {} titles to be prepared again", queued);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Delete titles from the library's trash whose time is up.
+fn purge_trash(library: &Path) -> Result<()> {
+    for entry in trash::purge(library, SystemTime::now())? {
+        println!("Deleted {} from the trash for good", entry.name);
+    }
+    Ok(())
+}
+
+/// Ask a yes or no question on the terminal, refusing to guess when there's
+/// nobody there to answer.
+fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        bail!("This is a long job; pass --yes to start it without asking");
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn read_password() -> Result<String> {
+    eprint!("Password: ");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        bail!("The password can't be empty");
+    }
+    Ok(password)
+}
+
+fn user(args: UserArgs) -> Result<()> {
+    let mut catalog = Catalog::open(&Catalog::default_path(&args.library))?;
+    match args.command {
+        UserCommand::Add {
+            name,
+            restricted,
+            no_password,
+        } => {
+            let password = match no_password {
+                true => None,
+                false => Some(read_password()?),
+            };
+            catalog.add_user(&name, password.as_deref(), restricted)?;
+            println!("Added {}", name);
+        }
+        UserCommand::Passwd { name } => catalog.set_password(&name, &read_password()?)?,
+        UserCommand::Remove { name } => {
+            if !catalog.remove_user(&name)? {
+                bail!("No such user: {}", name);
+            }
+            println!("Removed {}", name);
+        }
+        UserCommand::List => {
+            for user in catalog.users()? {
+                let mut access = match user.restricted {
+                    true => format!(
+                        "titles: {}; genres: {}; collections: {}",
+                        user.titles.join(", "),
+                        user.genres.join(", "),
+                        user.collections.join(", ")
+                    ),
+                    false => String::from("everything"),
+                };
+                if let Some(max_rating) = &user.max_rating {
+                    access.push_str(&format!("; rated up to {}", max_rating));
+                }
+                println!("{:<16} {}", user.name, access);
+            }
+        }
+        UserCommand::Grant { name, grant } => {
+            let (kind, value) = grant.kind_and_value();
+            catalog.grant(&name, kind, value)?;
+        }
+        UserCommand::Revoke { name, grant } => {
+            let (kind, value) = grant.kind_and_value();
+            catalog.revoke(&name, kind, value)?;
+        }
+        UserCommand::Rating { name, rating } => catalog.set_max_rating(&name, rating.as_deref())?,
+    }
+    Ok(())
+}
+
+fn collection(args: CollectionArgs) -> Result<()> {
+    let mut catalog = Catalog::open(&Catalog::default_path(&args.library))?;
+    match args.command {
+        CollectionCommand::Create { name } => {
+            catalog.create_collection(&name)?;
+            println!("Created {}", name);
+        }
+        CollectionCommand::Delete { name } => {
+            if !catalog.delete_collection(&name)? {
+                bail!("No such collection: {}", name);
+            }
+            println!("Deleted {}", name);
+        }
+        CollectionCommand::Add { name, titles } => {
+            if let Some(missing) = titles
+                .iter()
+                .find(|title| !args.library.join(title).join(library::MANIFEST).is_file())
+            {
+                bail!(
+                    "No prepared title named {} in {}",
+                    missing,
+                    args.library.display()
+                );
+            }
+            catalog.add_to_collection(&name, &titles)?;
+        }
+        CollectionCommand::Remove { name, titles } => {
+            catalog.remove_from_collection(&name, &titles)?;
+        }
+        CollectionCommand::List => {
+            for collection in catalog.collections()? {
+                println!("{:<16} {}", collection.name, collection.titles.join(", "));
+            }
+        }
+        CollectionCommand::Share {
+            name,
+            expires,
+            base_url,
+        } => {
+            if catalog.collection(&name)?.is_none() {
+                bail!("No such collection: {}", name);
+            }
+            let key = ShareKey::load_or_create(&args.library)?;
+            let token = key.sign_collection(&name, SystemTime::now() + expires);
+            println!("{}/c/{}/", base_url.trim_end_matches('/'), token);
+        }
+    }
+    Ok(())
+}
+
+fn qc(args: QcArgs) -> Result<()> {
+    let Some(mut catalog) = Catalog::open_existing(&args.library)? else {
+        bail!(
+            "No catalog in {}; run `preparer library scan` first",
+            args.library.display()
+        );
+    };
+    match args.command {
+        QcCommand::List { json } => {
+            let flagged = catalog.needing_attention()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&flagged)?);
+                return Ok(());
+            }
+            for title in flagged {
+                println!("{}", title.name);
+                for reason in title.reasons {
+                    println!("  {}", reason);
+                }
+            }
+        }
+        QcCommand::Clear { title } => {
+            attention::clear(&mut catalog, &args.library, &title)?;
+            println!("Cleared {}", title);
+        }
+    }
+    Ok(())
+}
+
+/// Make sure `dir` has metadata and artwork, looking `name` up on TMDB if needed.
+fn fetch_metadata_into(client: &tmdb::Client, name: &str, dir: &Path) -> Result<()> {
+    let metadata = match library::Metadata::read(dir)? {
+        Some(metadata) if metadata.is_described() => metadata,
+        // Preparing stamps a bare metadata.json that a lookup fills in
+        existing => match client.lookup(name)? {
+            Some(mut metadata) => {
+                if let Some(existing) = existing {
+                    metadata.rating = existing.rating.or(metadata.rating);
+                    metadata.ipfs_cid = existing.ipfs_cid;
+                    metadata.markers = existing.markers;
+                    metadata.chapters = existing.chapters;
+                    metadata.format_version = existing.format_version;
+                    metadata.encode_log = existing.encode_log;
+                    metadata.attention = existing.attention;
+                }
+                metadata.write(dir)?;
+                println!("{}: {}", name, metadata.label());
+                metadata
+            }
+            None => {
+                eprintln!("No TMDB match for {}", name);
+                return Ok(());
+            }
+        },
+    };
+    artwork::cache(&metadata, dir)
+}
 
-        Ok(())
+fn recommend_ladder(catalog: &Catalog, since: Option<SystemTime>, args: &StatsArgs) -> Result<()> {
+    let mut profile = load_profile(&args.profile)?;
+    let pinned: Vec<u32> = [profile.timecode_rung, profile.h264_rung]
+        .into_iter()
+        .flatten()
+        .collect();
+    let advice =
+        analytics::recommend_ladder(&profile.ladder, &pinned, &catalog.bandwidth_watched(since)?)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&advice)?);
+    } else {
+        for (rung, share) in advice.shares.iter().rev() {
+            println!("{:>4} MB/s {:>5.1}%", rung, share * 100.0);
+        }
+        for change in &advice.changes {
+            println!("{}", change);
+        }
+        if advice.changes.is_empty() {
+            println!("The ladder fits what viewers sustain");
+        }
+        let ladder: Vec<String> = advice.ladder.iter().map(u32::to_string).collect();
+        println!("Suggested ladder: {} MB/s", ladder.join(", "));
+    }
+    if let Some(path) = &args.write_profile {
+        profile
+            .rungs
+            .retain(|rung| advice.ladder.contains(&rung.bitrate));
+        profile.ladder = advice.ladder;
+        profile.validate()?;
+        std::fs::write(path, serde_json::to_string_pretty(&profile)?)
+            .context(format!("Failed to write {}", path.display()))?;
+        eprintln!("Wrote {}", path.display());
     }
+    Ok(())
 }
 
-fn main() -> Result<()> {
-    // Initialize GStreamer
-    gst::init()?;
+fn stats(args: StatsArgs) -> Result<()> {
+    let Some(catalog) = Catalog::open_existing(&args.library)? else {
+        bail!("No catalog in {}", args.library.display());
+    };
+    let since = args.since.map(|since| SystemTime::now() - since);
+    if args.recommend_ladder {
+        return recommend_ladder(&catalog, since, &args);
+    }
+    let stats = catalog.playback_stats(since)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
 
-    // Parse command line arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage: {} <input-file> <output-directory>", args[0]);
-        eprintln!("Example: {} test.webm ./output", args[0]);
-        std::process::exit(1);
-    }
-
-    let input_file = &args[1];
-    let output_dir = &args[2];
-
-    // Ensure output directory exists
-    std::fs::create_dir_all(output_dir)
-        .context(format!("Failed to create output directory: {}", output_dir))?;
-
-    // Define bitrates in MB/s
-    let bitrates = vec![6, 2]; // Can easily add more: vec![8, 6, 4, 2, 1]
-    let encoder_preset = 8u32;
-    let target_duration = 4u32; // seconds
-
-    // Calculate keyframe interval (assuming 30fps, adjust if needed)
-    // For variable framerate, this will be approximate
-    let fps = 30u32;
-    let keyframe_interval = fps * target_duration; // 120 frames for 4 seconds at 30fps
-
-    // Create the pipeline
-    let pipeline = gst::Pipeline::new();
-
-    // Create source and decoder elements
-    let filesrc = gst::ElementFactory::make("filesrc")
-        .name("filesrc")
-        .property("location", input_file)
-        .build()?;
-
-    let decodebin = gst::ElementFactory::make("decodebin").name("d").build()?;
-
-    let tee = gst::ElementFactory::make("tee").name("t").build()?;
-
-    // Audio processing elements
-    let audio_queue1 = gst::ElementFactory::make("queue").build()?;
-    let audioconvert = gst::ElementFactory::make("audioconvert").build()?;
-    let audioresample = gst::ElementFactory::make("audioresample").build()?;
-    let audio_queue2 = gst::ElementFactory::make("queue").build()?;
-
-    let opusenc = gst::ElementFactory::make("opusenc")
-        .property("bitrate", 192000i32)
-        .build()?;
-
-    let audio_queue3 = gst::ElementFactory::make("queue").build()?;
-
-    // DASH sink with output directory
-    let dashsink = gst::ElementFactory::make("dashsink")
-        .property("mpd-filename", "manifest.mpd")
-        .property("mpd-root-path", output_dir)
-        .property("target-duration", target_duration)
-        .property_from_str("muxer", "dashmp4")
-        .build()?;
-
-    // Add base elements to pipeline
-    pipeline.add_many(&[
-        &filesrc,
-        &decodebin,
-        &tee,
-        &audio_queue1,
-        &audioconvert,
-        &audioresample,
-        &audio_queue2,
-        &opusenc,
-        &audio_queue3,
-        &dashsink,
-    ])?;
-
-    // Link static elements
-    filesrc.link(&decodebin)?;
-
-    // Link audio processing chain
-    audio_queue1.link(&audioconvert)?;
-    audioconvert.link(&audioresample)?;
-    audioresample.link(&audio_queue2)?;
-
-    // Link audio with caps filter to ensure stereo
-    let audio_caps = gst::Caps::builder("audio/x-raw")
-        .field("channels", 2i32)
-        .build();
-    audio_queue2.link_filtered(&opusenc, &audio_caps)?;
-    opusenc.link(&audio_queue3)?;
-
-    let audio_sink_pad = dashsink
-        .request_pad_simple("audio_%u")
-        .context("Failed to get audio pad from dashsink")?;
-    let audio_src_pad = audio_queue3
-        .static_pad("src")
-        .context("Failed to get src pad from audio_queue3")?;
-    audio_src_pad.link(&audio_sink_pad)?;
-
-    // Create and link encoding branches
-    let mut branches = Vec::new();
-    for bitrate in bitrates {
-        let branch = EncodingBranch::new(bitrate, encoder_preset, keyframe_interval)?;
-        branch.add_to_pipeline(&pipeline)?;
-        branch.link(&tee, &dashsink)?;
-        branches.push(branch);
-    }
-
-    // Handle dynamic pads from decodebin
-    let tee_weak = tee.downgrade();
-    let audio_queue1_weak = audio_queue1.downgrade();
-
-    decodebin.connect_pad_added(move |_dbin, src_pad| {
-        let tee = match tee_weak.upgrade() {
-            Some(t) => t,
-            None => return,
+    println!(
+        "{:>8} {:>8} {:>8} {:>8} {:>8}  {:<24} TITLE",
+        "SESSIONS", "HOURS", "STARTUP", "REBUF/H", "SWITCHES", "RUNGS"
+    );
+    for title in stats {
+        let startup = title
+            .startup_secs
+            .map_or(String::new(), |secs| format!("{:.1}s", secs));
+        // Share of watching time at each height, highest first
+        let total: f64 = title.rungs.values().sum();
+        let rungs = title
+            .rungs
+            .iter()
+            .rev()
+            .filter(|_| total > 0.0)
+            .map(|(height, secs)| format!("{}p {:.0}%", height, secs * 100.0 / total))
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!(
+            "{:>8} {:>8.1} {:>8} {:>8.1} {:>8}  {:<24} {}",
+            title.sessions,
+            title.watched_secs / 3600.0,
+            startup,
+            title.rebuffers_per_hour(),
+            title.rung_switches,
+            rungs,
+            title.title
+        );
+    }
+    Ok(())
+}
+
+fn search(args: SearchArgs) -> Result<()> {
+    let Some(catalog) = Catalog::open_existing(&args.library)? else {
+        bail!("No catalog in {}", args.library.display());
+    };
+    let hits = catalog.search(&args.query, args.limit)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&hits)?);
+        return Ok(());
+    }
+    if hits.is_empty() {
+        println!("No subtitles say {:?}", args.query);
+    }
+    for hit in hits {
+        let secs = hit.start_secs as u64;
+        println!(
+            "{:<30} {:>8}  {}",
+            hit.title,
+            format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+            hit.text
+        );
+    }
+    Ok(())
+}
+
+fn history(args: HistoryArgs) -> Result<()> {
+    let Some(catalog) = Catalog::open_existing(&args.library)? else {
+        bail!("No catalog in {}", args.library.display());
+    };
+    let runs = catalog.runs(&RunFilter {
+        title: args.title,
+        since: args.since.map(|since| SystemTime::now() - since),
+        failed_only: args.failed,
+        limit: args.limit,
+    })?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&runs)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<20} {:<9} {:>6} {:>7} {:>8} {:>6} {:>7}  {:<20} {:<18} TITLE",
+        "FINISHED", "OUTCOME", "PRESET", "SPEED", "SIZE", "CORES", "RSS", "KB/S", "GSTREAMER"
+    );
+    for run in runs {
+        let finished =
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(run.finished_at));
+        let speed = run
+            .speed()
+            .map_or(String::new(), |speed| format!("{:.2}x", speed));
+        // Achieved bitrates, highest rung first
+        let bitrates = run
+            .bitrates
+            .iter()
+            .rev()
+            .map(|(_, kbps)| format!("{:.0}", kbps))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let (cores, rss) = match &run.usage {
+            Some(usage) => (
+                usage
+                    .cores(run.wall_secs)
+                    .map_or(String::new(), |cores| format!("{:.1}", cores)),
+                format!("{:.1}G", usage.peak_rss_bytes as f64 / 1e9),
+            ),
+            None => (String::new(), String::new()),
+        };
+        println!(
+            "{:<20} {:<9} {:>6} {:>7} {:>7.2}G {:>6} {:>7}  {:<20} {:<18} {}",
+            finished.to_string(),
+            run.outcome,
+            run.preset,
+            speed,
+            run.output_bytes as f64 / 1e9,
+            cores,
+            rss,
+            bitrates,
+            run.gstreamer.trim_start_matches("GStreamer "),
+            run.title
+        );
+        if let Some(error) = &run.error {
+            println!("    {}", error);
+        }
+    }
+    Ok(())
+}
+
+fn make_torrent(args: TorrentArgs) -> Result<()> {
+    if !args.output_dir.join(library::MANIFEST).is_file() {
+        bail!("No prepared title in {}", args.output_dir.display());
+    }
+    let torrent = torrent::Torrent::create(&args.output_dir, args.trackers)?;
+    let path = args
+        .output
+        .unwrap_or_else(|| torrent::default_path(&args.output_dir));
+    std::fs::write(&path, torrent.encode())
+        .context(format!("Failed to write {}", path.display()))?;
+    println!("Wrote {}", path.display());
+    println!("{}", torrent.magnet());
+    Ok(())
+}
+
+fn parse_backend_limit(text: &str) -> Result<(String, usize)> {
+    let invalid = || format!("Invalid backend limit {:?}; expected like nvenc=1", text);
+    let (backend, jobs) = text.split_once('=').with_context(invalid)?;
+    let backend = match backend {
+        "svtav1" | "vaapi" | "nvenc" | "qsv" => backend.to_string(),
+        _ => bail!(
+            "Unknown encoder backend {:?}; expected svtav1, vaapi, nvenc or qsv",
+            backend
+        ),
+    };
+    Ok((backend, jobs.parse().with_context(invalid)?))
+}
+
+fn parse_timestamp(text: &str) -> Result<f64> {
+    cuts::parse_time(text).context(format!(
+        "Invalid time {:?}; expected seconds or [HH:]MM:SS[.sss]",
+        text
+    ))
+}
+
+fn prepare_playlist(args: PlaylistArgs) -> Result<()> {
+    if args.titles.len() > args.inputs.len() {
+        bail!("More titles than inputs");
+    }
+    let profile = load_profile(&args.profile)?;
+    let mut parts = Vec::new();
+    for (i, input) in args.inputs.iter().enumerate() {
+        let dir = format!("period-{}", i + 1);
+        let title = args.titles.get(i).cloned().unwrap_or_else(|| {
+            input
+                .file_stem()
+                .map_or(String::new(), |stem| stem.to_string_lossy().into_owned())
+        });
+        println!("Preparing {} as period {}", input.display(), i + 1);
+        let output = args.output_dir.join(&dir);
+        let outcome = Preparer::new(input)
+            .output(&output)
+            .profile(profile.clone())
+            .resume(args.resume)
+            .run()?;
+        if let Outcome::Cancelled(_) = outcome {
+            bail!("Preparing {} was cancelled", input.display());
+        }
+        let manifest = output.join(library::MANIFEST);
+        parts.push(mpd::PeriodPart {
+            dir,
+            title,
+            xml: std::fs::read_to_string(&manifest)
+                .context(format!("Failed to read {}", manifest.display()))?,
+        });
+    }
+
+    let manifest = args.output_dir.join(library::MANIFEST);
+    std::fs::write(&manifest, mpd::join_periods(&parts)?)
+        .context(format!("Failed to write {}", manifest.display()))?;
+    println!("Wrote {} with {} periods", manifest.display(), parts.len());
+    Ok(())
+}
+
+fn prepare_live(args: LiveArgs) -> Result<()> {
+    let profile = load_profile(&args.profile)?;
+    let preparer = match args.input.as_str() {
+        live::SCREEN => Preparer::screen(),
+        uri => Preparer::live(uri),
+    };
+    let token = CancellationToken::new();
+    let preparer = preparer
+        .output(&args.output_dir)
+        .profile(profile)
+        .cancellation_token(token.clone());
+    match args.record {
+        true => println!(
+            "Recording {} into {}; press Ctrl-C to stop",
+            args.input,
+            args.output_dir.display()
+        ),
+        false => {
+            println!(
+                "Sharing {} into {}, keeping the last {}",
+                args.input,
+                args.output_dir.display(),
+                humantime::format_duration(args.window)
+            );
+            live::spawn_pruner(args.output_dir.clone(), args.window);
+        }
+    }
+    // Stop cleanly on Ctrl-C so the segments so far make a playable title
+    std::thread::spawn(move || {
+        if let Ok(runtime) = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            && runtime.block_on(tokio::signal::ctrl_c()).is_ok()
+        {
+            token.cancel(CancelPolicy::Finalize);
+        }
+    });
+    let mut job = PrepareJob::spawn(preparer);
+    let result = futures::executor::block_on(async {
+        while let Some(event) = job.next().await {
+            match event {
+                JobEvent::Warning(warning) => {
+                    eprintln!("{}", i18n::tr("warning", &[("message", warning.into())]))
+                }
+                JobEvent::Finished(result) => return result,
+                _ => (),
+            }
+        }
+        Err(anyhow!("Preparation ended without finishing"))
+    });
+    result?;
+    if args.record {
+        jit::finalize_manifest(&args.output_dir)?;
+    }
+    println!("{} ended", args.input);
+    Ok(())
+}
+
+fn prepare_slideshow(args: SlideshowArgs) -> Result<()> {
+    if args.per_image.is_zero() {
+        bail!("--per-image must be longer than zero");
+    }
+    let images = slideshow::list_images(&args.images)?;
+    let profile = load_profile(&args.profile)?;
+    println!(
+        "Preparing {} images from {}, {} each",
+        images.len(),
+        args.images.display(),
+        humantime::format_duration(args.per_image)
+    );
+    let count = images.len();
+    let outcome = Preparer::slideshow(images, args.audio.clone(), args.per_image)
+        .output(&args.output_dir)
+        .profile(profile)
+        .run()?;
+    if let Outcome::Cancelled(_) = outcome {
+        bail!("Preparing the slideshow was cancelled");
+    }
+    migrate::stamp(&args.output_dir, migrate::FORMAT_VERSION)?;
+    if let Err(err) = seekindex::SeekIndex::build(&args.output_dir)
+        .and_then(|index| index.write(&args.output_dir))
+    {
+        eprintln!("Warning: failed to write the seek index: {:#}", err);
+    }
+    println!(
+        "Wrote a slideshow of {} images to {}",
+        count,
+        args.output_dir.display()
+    );
+    Ok(())
+}
+
+fn make_clip(args: ClipArgs) -> Result<()> {
+    let scratch = scratch_dir(&args.scratch_dir)?;
+    clip::extract(&args.output_dir, args.start, args.end, &args.out, &scratch)?;
+    println!("Wrote {}", args.out.display());
+    Ok(())
+}
+
+fn preview_subtitle(args: SubtitlePreviewArgs) -> Result<()> {
+    let scratch = scratch_dir(&args.scratch_dir)?;
+    subpreview::render(&args.output_dir, args.track, args.at, &args.out, &scratch)?;
+    println!("Wrote {}", args.out.display());
+    Ok(())
+}
+
+fn add_track(args: AddTrackArgs) -> Result<()> {
+    let dir = &args.output_dir;
+    let lang = args.lang.as_deref();
+    let id = match (&args.subtitle_file, &args.audio_file) {
+        (Some(file), _) => addtrack::add_subtitles(dir, file, lang)?,
+        (None, Some(file)) => {
+            println!("Encoding the audio of {}", file.display());
+            addtrack::add_audio(dir, file, lang, &load_profile(&args.profile)?)?
+        }
+        // clap requires one of them
+        (None, None) => unreachable!(),
+    };
+    seekindex::refresh(dir)?;
+    refresh_catalog(dir)?;
+    println!("Added representation {} to {}", id, dir.display());
+    Ok(())
+}
+
+fn reencode_rung(args: ReencodeRungArgs) -> Result<()> {
+    let dir = &args.output_dir;
+    let profile = load_profile(&args.profile)?;
+    let source = library::recorded_source(dir)?;
+    println!(
+        "Encoding {} again at {} MB/s from {}",
+        args.rung,
+        args.bitrate,
+        source.display()
+    );
+    rung::reencode(dir, &source, &args.rung, args.bitrate, &profile)?;
+    seekindex::refresh(dir)?;
+    refresh_catalog(dir)?;
+    println!("Replaced {} in {}", args.rung, dir.display());
+    Ok(())
+}
+
+fn migrate_library(args: MigrateArgs) -> Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(&args.library)
+        .context(format!(
+            "Failed to read library: {}",
+            args.library.display()
+        ))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.join(library::MANIFEST).is_file())
+        .collect();
+    entries.sort();
+
+    let mut failed = 0;
+    let mut migrated = 0;
+    for dir in &entries {
+        match migrate::migrate(dir, args.dry_run) {
+            Ok(migration) if migration.upgrades.is_empty() => (),
+            Ok(migration) => {
+                println!(
+                    "{}: from version {}: {}",
+                    dir.display(),
+                    migration.from,
+                    migration.upgrades.join(", ")
+                );
+                migrated += 1;
+            }
+            Err(err) => {
+                eprintln!("{}: {:#}", dir.display(), err);
+                failed += 1;
+            }
+        }
+    }
+    if migrated > 0 && !args.dry_run {
+        refresh_library(&args.library)?;
+    }
+    println!(
+        "{} of {} titles {} version {}",
+        migrated,
+        entries.len(),
+        match args.dry_run {
+            true => "need upgrading to",
+            false => "upgraded to",
+        },
+        migrate::FORMAT_VERSION
+    );
+    if failed > 0 {
+        bail!("{} titles failed to migrate", failed);
+    }
+    Ok(())
+}
+
+/// Rescan the library's catalog, if it has one.
+fn refresh_library(library: &Path) -> Result<()> {
+    if let Some(mut catalog) = Catalog::open_existing(library)? {
+        catalog.scan(library, false)?;
+    }
+    Ok(())
+}
+
+/// Keep the catalog's ladder in step with a title changed in place, if the
+/// title is in one.
+fn refresh_catalog(dir: &Path) -> Result<()> {
+    if let Some(library) = dir.parent()
+        && let Some(name) = dir.file_name().and_then(|name| name.to_str())
+        && let Some(mut catalog) = Catalog::open_existing(library)?
+        && catalog.title(name)?.is_some()
+    {
+        catalog.refresh(name, dir)?;
+    }
+    Ok(())
+}
+
+fn write_testmedia(args: TestmediaArgs) -> Result<()> {
+    let options = testmedia::Options {
+        duration: args.duration,
+        vfr: !args.cfr,
+    };
+    testmedia::generate(&args.output, &options)?;
+    println!("Wrote {}", args.output.display());
+    Ok(())
+}
+
+fn report_bug(args: ReportBugArgs) -> Result<()> {
+    let profile = load_profile(&args.profile)?;
+    let failed = bugreport::report(
+        &args.input,
+        args.at,
+        args.length,
+        &profile,
+        args.output_dir.as_deref(),
+        &args.out,
+        &scratch_dir(&args.scratch_dir)?,
+    )?;
+    if !failed {
+        eprintln!(
+            "Warning: the sample prepared without errors, so it may not show the bug; try another --at"
+        );
+    }
+    println!("Wrote {}", args.out.display());
+    Ok(())
+}
+
+fn sync_library(args: SyncArgs) -> Result<()> {
+    let password = args.user.as_ref().map(|_| read_password()).transpose()?;
+    let credentials = args.user.as_deref().zip(password.as_deref());
+    let summary = mirror::sync(&args.remote, credentials, &args.library, args.dry_run)?;
+    let verb = match args.dry_run {
+        true => "Would copy",
+        false => "Copied",
+    };
+    println!(
+        "{} {:.1} MB: {} titles added, {} updated, {} unchanged",
+        verb,
+        summary.bytes as f64 / 1e6,
+        summary.added,
+        summary.updated,
+        summary.unchanged
+    );
+    Ok(())
+}
+
+fn check_compat(args: CheckcompatArgs) -> Result<()> {
+    let targets = match args.target.is_empty() {
+        true => compat::TARGETS.iter().collect(),
+        false => args
+            .target
+            .iter()
+            .map(|name| compat::target(name.trim()))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    let verdicts = compat::check_dir(&args.output_dir, &targets)?;
+    for verdict in &verdicts {
+        let overall = match verdict.overall {
+            compat::Support::Yes => String::from("plays"),
+            compat::Support::No => String::from("won't play"),
+            compat::Support::Depends(when) => format!("plays {}", when),
+        };
+        println!(
+            "{} ({}): {}",
+            verdict.target.name, verdict.target.description, overall
+        );
+        if verdict.missing_drm {
+            println!("  has none of the DRM systems the manifest signals");
+        }
+        for (representation, codecs, support) in &verdict.representations {
+            println!("  {} {}: {}", representation, codecs, support);
+        }
+    }
+    let unplayable = verdicts
+        .iter()
+        .filter(|verdict| verdict.overall == compat::Support::No)
+        .count();
+    if unplayable > 0 {
+        bail!("{} of {} targets won't play it", unplayable, verdicts.len());
+    }
+    Ok(())
+}
+
+fn doctor(args: DoctorArgs) -> Result<()> {
+    let report = doctor::report()?;
+    match args.json {
+        true => println!("{}", serde_json::to_string_pretty(&report)?),
+        false => print!("{}", report),
+    }
+    Ok(())
+}
+
+fn run_selftest(args: SelftestArgs) -> Result<()> {
+    let report = |check: &selftest::Check| {
+        let status = match check.passed {
+            true => "PASS",
+            false => "FAIL",
         };
+        println!("{} {}: {}", status, check.name, check.detail);
+        check.passed
+    };
+
+    let elements = selftest::check_elements()?;
+    if !report(&elements) {
+        bail!("Self-test failed");
+    }
+
+    let dir = match &args.output {
+        Some(output) => output.clone(),
+        None => scratch_dir(&args.scratch_dir)?
+            .join(format!("movieshare-selftest-{}", std::process::id())),
+    };
+    let profile = EncodingProfile::default();
+    println!(
+        "Preparing a {} test pattern into {}",
+        humantime::format_duration(args.duration),
+        dir.display()
+    );
+    let started = Instant::now();
+    let result = Preparer::test_pattern(args.duration)
+        .output(&dir)
+        .profile(profile.clone())
+        .run();
+    let passed = match result {
+        Ok(_) => {
+            report(&selftest::Check {
+                name: "pipeline",
+                passed: true,
+                detail: format!("finished in {:.1}s", started.elapsed().as_secs_f64()),
+            });
+            // Report every check, not just up to the first failure
+            let checks = selftest::check_output(&dir, &profile, args.duration);
+            checks.iter().map(report).filter(|passed| !passed).count() == 0
+        }
+        Err(err) => report(&selftest::Check {
+            name: "pipeline",
+            passed: false,
+            detail: format!("{:#}", err),
+        }),
+    };
+
+    if args.output.is_none() {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    match passed {
+        true => {
+            println!("Self-test passed");
+            Ok(())
+        }
+        false => bail!("Self-test failed"),
+    }
+}
+
+fn dedupe(args: DedupeArgs) -> Result<()> {
+    let Some(catalog) = Catalog::open_existing(&args.library)? else {
+        bail!("No catalog in {}", args.library.display());
+    };
+    let fingerprints = catalog.fingerprints()?;
+    let mut titles = Vec::new();
+    for title in catalog.titles()? {
+        let (audio, video) = fingerprints
+            .iter()
+            .find(|fingerprints| fingerprints.name == title.name)
+            .map(|fingerprints| (fingerprints.audio.clone(), fingerprints.video.clone()))
+            .unwrap_or_default();
+        // A damaged fingerprint just leaves that title out of the comparison
+        let audio = audio.and_then(|audio| {
+            dedupe::decode(&audio)
+                .inspect_err(|err| eprintln!("Skipping audio of {}: {:#}", title.name, err))
+                .ok()
+        });
+        let video = video.and_then(|video| {
+            dedupe::decode_video_hashes(&video)
+                .inspect_err(|err| eprintln!("Skipping video of {}: {:#}", title.name, err))
+                .ok()
+        });
+        let quality = title
+            .ladder
+            .iter()
+            .filter(|representation| representation.content_type == "video")
+            .map(|representation| (representation.height.unwrap_or(0), representation.bandwidth))
+            .max()
+            .unwrap_or_default();
+        titles.push(dedupe::Candidate {
+            name: title.name,
+            audio,
+            video,
+            quality,
+        });
+    }
+    let missing = titles
+        .iter()
+        .filter(|title| title.audio.is_none() && title.video.is_none())
+        .count();
+    if missing > 0 {
+        println!(
+            "{} titles have no fingerprint; prepare them again to compare them",
+            missing
+        );
+    }
+
+    let duplicates = dedupe::find_duplicates(&titles, args.threshold, args.video_threshold);
+    if duplicates.is_empty() {
+        println!("No likely duplicates among {} titles", titles.len());
+        return Ok(());
+    }
+    let percent = |similarity: Option<f64>| {
+        similarity.map_or(String::from("-"), |s| format!("{:.0}%", s * 100.0))
+    };
+    println!("{:>5} {:>5}  TITLES", "AUDIO", "VIDEO");
+    for duplicate in duplicates {
+        println!(
+            "{:>5} {:>5}  {}  =  {}  (keep {})",
+            percent(duplicate.audio),
+            percent(duplicate.video),
+            duplicate.a,
+            duplicate.b,
+            duplicate.keep
+        );
+    }
+    Ok(())
+}
+
+fn find_markers(args: MarkersArgs) -> Result<()> {
+    if let Some(file) = &args.file {
+        let markers = markers::read_marker_file(file)?;
+        for dir in &args.episodes {
+            if !dir.join(library::MANIFEST).is_file() {
+                bail!("No prepared title in {}", dir.display());
+            }
+            markers::write_markers(dir, markers.clone())?;
+            println!("{}: set {} markers", dir.display(), markers.len());
+        }
+        return Ok(());
+    }
+    if args.episodes.len() < 2 {
+        bail!("Finding markers takes at least two episodes to compare");
+    }
+    let mut fingerprints = Vec::new();
+    for dir in &args.episodes {
+        if !dir.join(library::MANIFEST).is_file() {
+            bail!("No prepared title in {}", dir.display());
+        }
+        let source = library::recorded_source(dir)?;
+        println!("Listening to {}", source.display());
+        fingerprints.push(markers::fingerprint(&source)?);
+    }
 
-        let audio_queue1 = match audio_queue1_weak.upgrade() {
-            Some(q) => q,
-            None => return,
+    for (i, dir) in args.episodes.iter().enumerate() {
+        // Compare with the next episode, and the last with the one before
+        let other = match i + 1 < fingerprints.len() {
+            true => &fingerprints[i + 1],
+            false => &fingerprints[i - 1],
         };
+        let found = markers::find_markers(
+            &fingerprints[i],
+            other,
+            args.intro_window.as_secs_f64(),
+            args.credits_window.as_secs_f64(),
+            args.min_length.as_secs_f64(),
+        );
+        let summary: Vec<String> = found
+            .iter()
+            .map(|marker| {
+                format!(
+                    "{:?} {}-{}",
+                    marker.kind,
+                    humantime::format_duration(Duration::from_secs(marker.start_secs as u64)),
+                    humantime::format_duration(Duration::from_secs(marker.end_secs as u64))
+                )
+            })
+            .collect();
+        println!(
+            "{}: {}",
+            dir.display(),
+            match summary.is_empty() {
+                true => String::from("nothing shared"),
+                false => summary.join(", "),
+            }
+        );
+        markers::write_markers(dir, found)?;
+    }
+    Ok(())
+}
+
+fn submit(args: SubmitArgs) -> Result<()> {
+    // The daemon runs elsewhere, so relative paths would resolve against its directory
+    let mut spec = JobSpec::new(
+        std::path::absolute(&args.input_file)?,
+        std::path::absolute(&args.output_dir)?,
+    );
+    spec.profile = load_profile(&args.profile)?;
+    args.encoding.apply_to(&mut spec.profile)?;
+    spec.cuts = args.encoding.cuts()?;
+    spec.watermark = args.encoding.watermark()?;
+    spec.grade = args.encoding.grade()?;
+
+    let request = daemon::Request::Submit {
+        spec: Box::new(spec),
+        priority: args.priority,
+    };
+    if let daemon::Response::Submitted { id } = daemon::request(&args.socket.path(), &request)? {
+        println!("Submitted job {}", id);
+    }
+    Ok(())
+}
 
-        // Get pad caps
-        let caps = src_pad.current_caps().unwrap();
-        let structure = caps.structure(0).unwrap();
-        let name = structure.name();
+fn status(args: StatusArgs) -> Result<()> {
+    let request = daemon::Request::Status { id: args.id };
+    let daemon::Response::Jobs { jobs } = daemon::request(&args.socket.path(), &request)? else {
+        return Ok(());
+    };
 
-        if name.starts_with("video/") {
-            let sink_pad = tee.static_pad("sink").unwrap();
-            if !sink_pad.is_linked() {
-                src_pad
-                    .link(&sink_pad)
-                    .expect("Failed to link decodebin video to tee");
+    println!("{:<5} {:<10} {:>8}  INPUT", "ID", "STATE", "PROGRESS");
+    for view in jobs {
+        let job = view.job;
+        let state = match &job.state {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed { .. } => "failed",
+            JobState::Cancelled => "cancelled",
+        };
+        let progress = match &view.progress {
+            Some(progress) if job.state == JobState::Running => {
+                format!("{:.0}%", progress.fraction * 100.0)
             }
-        } else if name.starts_with("audio/") {
-            let sink_pad = audio_queue1.static_pad("sink").unwrap();
-            if !sink_pad.is_linked() {
-                src_pad
-                    .link(&sink_pad)
-                    .expect("Failed to link decodebin audio to queue");
+            _ => String::new(),
+        };
+        println!(
+            "{:<5} {:<10} {:>8}  {}",
+            job.id,
+            state,
+            progress,
+            job.spec.input.display()
+        );
+        if let JobState::Failed { error } = &job.state {
+            println!("      {}", error);
+        }
+    }
+    Ok(())
+}
+
+/// Prepare from a local copy of a remote source, fetched once for every
+/// pass and episode, and kept in the source cache if there is one.
+fn prepare_remote(mut args: PrepareArgs) -> Result<()> {
+    if args.source_policy != SourcePolicy::Keep {
+        bail!("A remote source can't be moved or deleted");
+    }
+    let (cache, temporary) = match &args.source_cache {
+        Some(dir) => (SourceCache::new(dir.clone(), args.source_cache_size), false),
+        None => (
+            SourceCache::new(
+                scratch_dir(&args.scratch_dir)?
+                    .join(format!("movieshare-source-{}", std::process::id())),
+                u64::MAX,
+            ),
+            true,
+        ),
+    };
+    eprintln!("Fetching {}", args.input_file);
+    let local = cache.fetch(&args.input_file)?;
+    args.input_file = local.to_string_lossy().into_owned();
+    let result = prepare(args);
+    if temporary {
+        let _ = std::fs::remove_dir_all(cache.dir());
+    }
+    result
+}
+
+/// Prepare every episode of a season-in-one-file rip into its own
+/// directory under the output.
+fn prepare_episodes(mut args: PrepareArgs) -> Result<()> {
+    let starts = match args.split_by_chapters {
+        true => split::read_chapters(Path::new(&args.input_file))?,
+        false => args.split_at.clone(),
+    };
+    let episodes = split::episodes(&starts);
+    // Episodes go into the catalog together once they're all done, so the
+    // library never shows part of the season
+    let mut catalog = Catalog::open_existing(Path::new(&args.output_dir))?;
+    if catalog.is_none() && args.collection.is_some() {
+        bail!(
+            "No catalog in {} to add the collection to; run `preparer library scan` first",
+            args.output_dir
+        );
+    }
+    eprintln!(
+        "Splitting {} into {} episodes",
+        args.input_file,
+        episodes.len()
+    );
+    for episode in &episodes {
+        let mut episode_args = args.clone();
+        episode_args.output_dir =
+            format!("{}/{}", args.output_dir.trim_end_matches('/'), episode.name);
+        episode_args.episode = Some(episode.clone());
+        prepare(episode_args)?;
+        // The first episode's plan stands for the rest
+        args.yes = true;
+    }
+    let dirs: Vec<PathBuf> = episodes
+        .iter()
+        .map(|episode| Path::new(&args.output_dir).join(&episode.name))
+        .collect();
+    if let Some(catalog) = &mut catalog {
+        let titles: Vec<(String, PathBuf)> = episodes
+            .iter()
+            .map(|episode| episode.name.clone())
+            .zip(dirs.iter().cloned())
+            .collect();
+        catalog.import(&titles, args.collection.as_deref())?;
+        eprintln!("Added {} episodes to the catalog", titles.len());
+    }
+    handle_source(&args.source_policy, Path::new(&args.input_file), &dirs)
+}
+
+/// Apply `policy` to the source of the titles in `dirs` now that they've
+/// checked out, and record that in their journals.
+fn handle_source(policy: &SourcePolicy, source: &Path, dirs: &[PathBuf]) -> Result<()> {
+    if *policy == SourcePolicy::Keep {
+        return Ok(());
+    }
+    let location = policy.apply(source)?;
+    match &location {
+        Some(location) => println!("Source now at {}", location.display()),
+        None => println!("Deleted the source {}", source.display()),
+    }
+    // Output uploaded elsewhere leaves no journal behind to record it in
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        Journal::open(dir)?.record(JournalEvent::SourceHandled {
+            policy: policy.clone(),
+            location: location
+                .as_deref()
+                .map(std::path::absolute)
+                .transpose()?
+                .map(|location| location.to_string_lossy().into_owned()),
+        })?;
+    }
+    Ok(())
+}
+
+fn prepare(args: PrepareArgs) -> Result<()> {
+    if sourcecache::is_remote(&args.input_file) {
+        return prepare_remote(args);
+    }
+    let stdin = args.input_file == "-";
+    if args.episode.is_none() && (args.split_by_chapters || !args.split_at.is_empty()) {
+        if stdin {
+            bail!("Can't split standard input into episodes; save it to a file first");
+        }
+        return prepare_episodes(args);
+    }
+    if args.collection.is_some() && args.episode.is_none() {
+        bail!("--collection groups the episodes of --split-by-chapters or --split-at");
+    }
+    if stdin && args.source_policy != SourcePolicy::Keep {
+        bail!("Standard input has no source file to keep, move or delete");
+    }
+    priority::apply(args.nice, args.ionice)?;
+    let input_file = &args.input_file;
+    let output_dir = &args.output_dir;
+    // Remote output is staged locally and uploaded as it's written
+    let remote = match storage::is_remote(output_dir) {
+        true => Some(storage::open(output_dir)?),
+        false => None,
+    };
+    // What to call the output in messages, without any password in its URL
+    let output_name = storage::redact(output_dir);
+    let local_dir = match &remote {
+        Some(_) => scratch_dir(&args.scratch_dir)?
+            .join(format!(
+                "movieshare-remote-{:x}",
+                Sha256::digest(output_dir)
+            ))
+            .to_string_lossy()
+            .into_owned(),
+        None => output_dir.clone(),
+    };
+
+    // Keep stdout for events when they were asked for
+    let say = |message: String| {
+        if args.json {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    };
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = args.metrics_addr {
+        metrics::serve(metrics.clone(), addr)?;
+        say(format!("Serving metrics on http://{}/metrics", addr));
+    }
+    let mut profile = load_profile(&args.profile)?;
+    args.encoding.apply_to(&mut profile)?;
+    let mut cuts = args.encoding.cuts()?;
+    if let Some(episode) = &args.episode {
+        cuts.extend(episode.cuts());
+        cuts = cuts::normalize(cuts);
+    }
+    let watermark = args.encoding.watermark()?;
+    let grade = args.encoding.grade()?;
+    // Check for the API key now rather than after a long encode
+    let tmdb = match args.fetch_metadata {
+        true => Some(tmdb::Client::from_env()?),
+        false => None,
+    };
+
+    // Work out and show the whole job before starting any of it
+    let source = match (stdin, args.copy_streams) {
+        (true, true) => bail!("Can't plan copying standard input; save it to a file first"),
+        (true, false) => SourceInfo::default(),
+        (false, true) => plan::probe(Path::new(input_file))?,
+        (false, false) => plan::probe(Path::new(input_file)).unwrap_or_else(|err| {
+            eprintln!("Warning: {:#}", err);
+            SourceInfo::default()
+        }),
+    };
+    let mut plan = match (args.repackage, args.copy_streams) {
+        (true, _) => Plan::repackage(&profile),
+        (false, true) => Plan::decide(&source, &profile),
+        (false, false) => Plan::encode_all(&profile),
+    };
+    let skipped = plan.skip_taller(&source, &profile);
+    if !skipped.is_empty() {
+        let skipped: Vec<String> = skipped.iter().map(|b| format!("{} MB/s", b)).collect();
+        say(format!(
+            "Skipping rungs taller than the source: {}",
+            skipped.join(", ")
+        ));
+    }
+    if args.copy_streams && args.force_audio_reencode {
+        plan.audio = Action::Encode;
+    } else if !args.force_audio_reencode
+        && cuts.is_empty()
+        && plan.pass_audio_through(&source, &profile)
+    {
+        say(String::from(
+            "Source audio is Opus within the target bitrate, so it will be copied",
+        ));
+    }
+    if args.analyze {
+        if stdin {
+            bail!("Can't analyze standard input; save it to a file first");
+        }
+        plan.findings = analysis::analyze(Path::new(input_file), analysis::all_passes())?;
+    }
+    // Only decoded video can have its pulldown undone
+    let inverse_telecine = match args.ivtc {
+        _ if plan.copies_video() || plan.encoded_rungs().next().is_none() => false,
+        InverseTelecine::Always => true,
+        InverseTelecine::Never => false,
+        InverseTelecine::Auto if stdin => false,
+        InverseTelecine::Auto => telecine::detect(Path::new(input_file)).unwrap_or_else(|err| {
+            eprintln!("Warning: {:#}", err);
+            false
+        }),
+    };
+    if inverse_telecine && args.ivtc == InverseTelecine::Auto {
+        say(String::from(
+            "Source looks like telecined film, so its pulldown will be undone",
+        ));
+    }
+    // A title finished before needs only the rungs the plan has added since,
+    // unless they'd need cutting or filtering as the rest were, or keyframes
+    // at its chapters or scene cuts to line up with them. Those are prepared
+    // on their own beside it, then added to it
+    let mut adding = None;
+    if args.resume
+        && !stdin
+        && remote.is_none()
+        && args.origin.is_none()
+        && cuts.is_empty()
+        && watermark.is_none()
+        && args.burn_subtitles.is_none()
+        && grade.is_none()
+        && source.video.is_some()
+        && plan.findings.scene_cuts.is_empty()
+        && split::read_toc(Path::new(input_file)).is_ok_and(|toc| toc.is_empty())
+    {
+        let decoder_level = profile
+            .decoder_level
+            .as_deref()
+            .map(levels::parse)
+            .transpose()?;
+        let fps = conform::encoded_fps(source.framerate, profile.frame_rate, inverse_telecine)
+            .round() as u32;
+        let fitted = |bitrate| match decoder_level {
+            Some(level) => levels::fit(bitrate, fps, level, profile.level_policy).bitrate_mbps,
+            None => bitrate,
+        };
+        let missing = resume::missing_rungs(
+            Path::new(&local_dir),
+            input_file,
+            plan.encoded_rungs(),
+            fitted,
+        )?;
+        if !missing.is_empty() {
+            let rungs: Vec<String> = missing.iter().map(|b| format!("{} MB/s", b)).collect();
+            say(format!(
+                "Adding the {} rungs to the prepared title",
+                rungs.join(", ")
+            ));
+            plan.rungs.retain(|(bitrate, _)| missing.contains(bitrate));
+            // Left by a run that didn't get to add its rungs
+            let dir = Path::new(&local_dir).join(resume::ADDING_DIR);
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir)
+                    .context(format!("Failed to remove {}", dir.display()))?;
             }
+            adding = Some(dir.to_string_lossy().into_owned());
         }
-    });
+    }
+    let mut filters = Vec::new();
+    if !cuts.is_empty() {
+        filters.push(format!("{} ranges cut", cuts.len()));
+    }
+    if watermark.is_some() {
+        filters.push(String::from("watermark"));
+    }
+    if args.burn_subtitles.is_some() {
+        filters.push(String::from("burned-in subtitles"));
+    }
+    if grade.is_some() {
+        filters.push(String::from("colour grade"));
+    }
+    if inverse_telecine {
+        filters.push(String::from("inverse telecine to 23.976 fps"));
+    }
+    if let Some(rate) = profile.frame_rate {
+        filters.push(format!("frame rate conformed to {} fps", rate));
+    }
+    if let Some(rung) = profile.timecode_rung {
+        filters.push(format!("timecode burned into the {} MB/s rung", rung));
+    }
+    if let Some(level) = &profile.decoder_level {
+        filters.push(format!("rungs fit to level {}", level));
+    }
+    // The library's past runs tell how long this one should take
+    let library = Path::new(&local_dir).parent().unwrap_or(Path::new("."));
+    let mut catalog = match remote {
+        Some(_) => None,
+        None => Catalog::open_existing(library)?,
+    };
+    let expectation = match &catalog {
+        Some(catalog) => catalog.expectation(profile.encoder_backend(), profile.encoder_preset)?,
+        None => Expectation::default(),
+    };
+    let overview = Overview {
+        source: &source,
+        plan: &plan,
+        profile: &profile,
+        filters,
+        speed: expectation.speed,
+        size_ratio: expectation.size_ratio,
+    };
+    say(format!("{}\n{}", i18n::tr("plan", &[]), overview));
+    let mut encode_log = EncodeLog::default();
+    encode_log.plan(&overview.to_string(), &source);
+    if args.json {
+        let estimate = Event::new(EventBody::Estimate {
+            size_bytes: overview.estimated_bytes(),
+            duration_secs: overview.estimated_time().map(|time| time.as_secs_f64()),
+        });
+        println!("{}", estimate.to_json()?);
+    }
+    let run = EncodeRun {
+        finished_at: 0,
+        title: Path::new(&local_dir)
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+        input: input_file.clone(),
+        source_hash: match catalog.is_some() && !stdin {
+            true => history::source_hash(Path::new(input_file)).ok(),
+            false => None,
+        },
+        settings: serde_json::to_string(&profile)?,
+        gstreamer: movieshare_core::gst::version_string().to_string(),
+        encoder: profile.encoder_backend().to_string(),
+        preset: profile.encoder_preset,
+        encoded_rungs: plan.encoded_rungs().count() as u32,
+        source_secs: overview.encoded_duration().as_secs_f64(),
+        wall_secs: 0.0,
+        estimated_bytes: overview.estimated_bytes(),
+        output_bytes: 0,
+        bitrates: BTreeMap::new(),
+        outcome: String::new(),
+        error: None,
+        usage: None,
+    };
+    if !args.yes && overview.encoded_duration() >= LONG_JOB && !confirm("Start?")? {
+        bail!("Cancelled before starting");
+    }
 
-    // Start playing
-    println!("Starting transcoding...");
-    println!("Input: {}", input_file);
-    println!("Output: {}", output_dir);
+    metrics.job_queued();
 
-    pipeline.set_state(gst::State::Playing)?;
+    say(i18n::tr("starting", &[]));
+    say(i18n::tr("input", &[("path", input_file.as_str().into())]));
+    say(i18n::tr("output", &[("path", output_name.as_str().into())]));
 
-    // Wait until error or EOS
-    let bus = pipeline.bus().unwrap();
-    for msg in bus.iter_timed(gst::ClockTime::NONE) {
-        use gst::MessageView;
+    metrics.job_started(input_file);
+    let started = Instant::now();
+    let meter = UsageMeter::start();
+    let mut frames = 0;
 
-        match msg.view() {
-            MessageView::Eos(..) => {
-                println!("Transcoding complete!");
-                break;
+    let library_key = args
+        .encrypt_key
+        .as_deref()
+        .map(LibraryKey::load_or_create)
+        .transpose()?;
+    let spawn_uploader =
+        |storage: Box<dyn Storage>| Uploader::spawn(storage, PathBuf::from(&local_dir), false);
+    if let Some(storage) = &remote {
+        storage::check(storage.as_ref(), &scratch_dir(&args.scratch_dir)?)?;
+    }
+    // The local copy stays, with a live copy kept up to date on the origin
+    let live = match &args.origin {
+        Some(_) if remote.is_some() => {
+            bail!("Remote output is already uploaded; --origin adds nothing")
+        }
+        Some(url) if !storage::is_remote(url) => {
+            bail!(
+                "--origin takes an s3://, http(s)://, webdav(s):// or sftp:// URL, not {}",
+                url
+            )
+        }
+        Some(url) => {
+            let storage = storage::open(url)?;
+            storage::check(storage.as_ref(), &scratch_dir(&args.scratch_dir)?)?;
+            Some(Uploader::spawn(storage, PathBuf::from(&local_dir), true))
+        }
+        None => None,
+    };
+    // Encrypted output can only be uploaded once it's been encrypted
+    let mut remote = remote;
+    let uploader = match library_key {
+        Some(_) => None,
+        None => remote.take().map(spawn_uploader),
+    };
+    let copies_audio = plan.audio == Action::Copy;
+    let decodes_video = plan.encoded_rungs().next().is_some();
+    // Fingerprint the audio for `preparer dedupe` when chromaprint is
+    // installed and the audio is decoded
+    // Rungs being added to a title need nothing the title has already
+    let extras = adding.is_none();
+    let fingerprint = match extras && !copies_audio && FingerprintBranch::available()? {
+        true => Some(FingerprintBranch::new()?),
+        false => None,
+    };
+    // Peaks for the player's seek bar, from the decoded audio
+    let waveform = match copies_audio || !extras {
+        true => None,
+        false => Some(WaveformBranch::new()?),
+    };
+    // Hashing frames needs them decoded, which copying video alone skips
+    let video_hash = match extras && decodes_video {
+        true => Some(VideoHashBranch::new()?),
+        false => None,
+    };
+    // Cut timelines would leave the cues out of step
+    let carry_subtitles = extras && !args.no_subtitles && !stdin && cuts.is_empty();
+    // Stills for the chapter menu; their times would be off once cut
+    let toc = match stdin || !cuts.is_empty() || !decodes_video {
+        true => Vec::new(),
+        false => split::read_toc(Path::new(input_file)).unwrap_or_else(|err| {
+            eprintln!("Warning: {:#}", err);
+            Vec::new()
+        }),
+    };
+    let chapter_thumbnails = match toc.is_empty() {
+        true => None,
+        false => {
+            let starts: Vec<f64> = toc.iter().map(|chapter| chapter.start_secs).collect();
+            Some(ChapterThumbnailBranch::new(Path::new(&local_dir), &starts)?)
+        }
+    };
+    let trick_play = match args.trick_play {
+        true if !decodes_video => bail!("Trick-play thumbnails need the video decoded"),
+        true if !extras => None,
+        true => Some(trickplay::TrickPlayBranch::new(Path::new(&local_dir))?),
+        false => None,
+    };
+    let preparer = match stdin {
+        true => Preparer::stdin(),
+        false => Preparer::new(input_file),
+    };
+    let mut preparer = preparer
+        .output(adding.as_deref().unwrap_or(&local_dir))
+        .profile(profile.clone())
+        .cuts(cuts)
+        .watermark(watermark)
+        .burn_subtitles(args.burn_subtitles.clone())
+        .grade(grade)
+        .inverse_telecine(inverse_telecine)
+        .chapter_starts(
+            toc.iter()
+                .filter_map(|chapter| Duration::try_from_secs_f64(chapter.start_secs).ok())
+                .collect::<Vec<_>>(),
+        )
+        .resume(args.resume)
+        .repackage(args.repackage)
+        .deterministic(args.deterministic)
+        .check_sync(args.check_sync)
+        .decoder(args.decoder)
+        .encoder(args.encoder)
+        .report_bottleneck(args.report_bottleneck)
+        .qc(args.qc)
+        .dynamic_manifest(live.is_some())
+        .scratch_dir(scratch_dir(&args.scratch_dir)?)
+        .missing_audio(args.missing_audio)
+        .decode_errors(args.on_decode_error)
+        .allow_audio_only(args.allow_audio_only);
+    preparer = preparer.plan(plan).probed(source.clone());
+    if let Some(branch) = &video_hash {
+        preparer = preparer.branch(branch.clone());
+    }
+    if let Some(branch) = &fingerprint {
+        preparer = preparer.branch(branch.clone());
+    }
+    if let Some(branch) = &waveform {
+        preparer = preparer.branch(branch.clone());
+    }
+    if let Some(branch) = &chapter_thumbnails {
+        preparer = preparer.branch(branch.clone());
+    }
+    if let Some(branch) = &trick_play {
+        preparer = preparer.branch(branch.clone());
+    }
+    let extra_tracks = match args.all_tracks {
+        true if stdin => bail!("Can't carry the other tracks of standard input"),
+        true if args.episode.is_some() => {
+            bail!("Can't carry the other tracks of episodes split out of the source")
+        }
+        true if !extras => None,
+        true => Some(ExtraTracks::start(
+            Path::new(input_file),
+            Path::new(&local_dir),
+            &profile,
+            source.audio_streams,
+            source.subtitle_streams * usize::from(!args.no_subtitles),
+        )),
+        false if carry_subtitles && source.subtitle_streams > 0 => Some(ExtraTracks::start(
+            Path::new(input_file),
+            Path::new(&local_dir),
+            &profile,
+            0,
+            source.subtitle_streams,
+        )),
+        false => None,
+    };
+    let mut job = PrepareJob::spawn(preparer);
+    let mut bitrates = BTreeMap::new();
+    let mut attention = Vec::new();
+    // Progress goes on one line rewritten in place, where there's a terminal to see it
+    let show_progress = !args.json && std::io::stderr().is_terminal();
+    let result = futures::executor::block_on(async {
+        while let Some(event) = job.next().await {
+            let record = Event::from_job_event(&event);
+            if args.json {
+                println!("{}", record.to_json()?);
             }
-            MessageView::Error(err) => {
-                eprintln!(
-                    "Error from {:?}: {} ({:?})",
-                    err.src().map(|s| s.path_string()),
-                    err.error(),
-                    err.debug()
-                );
-                break;
+            encode_log.event(&record)?;
+            attention.extend(attention::reason(&event));
+            if show_progress && !matches!(event, JobEvent::Progress(_)) {
+                eprint!("\r\x1b[K");
             }
-            MessageView::StateChanged(state) => {
-                if msg.src().map(|s| s == &pipeline).unwrap_or(false) {
-                    if state.current() == gst::State::Playing {
-                        println!("Pipeline is now playing...");
+            match event {
+                JobEvent::Progress(progress) => {
+                    frames = progress.frames;
+                    metrics.job_progress(input_file, progress.fraction, progress.fps);
+                    if show_progress {
+                        eprint!("\r\x1b[K{}", progress_line(&progress));
+                    }
+                }
+                JobEvent::Warning(warning) => {
+                    eprintln!("{}", i18n::tr("warning", &[("message", warning.into())]))
+                }
+                JobEvent::BranchStats(stats) => {
+                    if let Some(kbps) = stats.average_bitrate_kbps {
+                        bitrates.insert(stats.target_bitrate_mbps, kbps);
                     }
+                    say(i18n::tr(
+                        "representation-stats",
+                        &[
+                            ("mbps", stats.target_bitrate_mbps.into()),
+                            ("frames", stats.frames.into()),
+                            ("bytes", stats.bytes.into()),
+                        ],
+                    ))
                 }
+                JobEvent::Bottleneck(report) => say(report.to_string()),
+                JobEvent::Qc(report) => say(report.to_string()),
+                JobEvent::Finished(result) => return result,
+            }
+        }
+        Err(anyhow!("Preparation ended without finishing"))
+    });
+
+    // Only rungs prepared in full are added to the title
+    let result = match (result, &adding) {
+        (Ok(Outcome::Prepared(summary)), Some(_)) => {
+            resume::add_rungs(Path::new(&local_dir), library_key.as_ref())
+                .map(|()| Outcome::Prepared(summary))
+        }
+        (result, _) => result,
+    };
+    let usage = meter.finish();
+    let state = match &result {
+        Ok(Outcome::Cancelled(_)) => JobState::Cancelled,
+        Ok(_) => JobState::Completed,
+        Err(err) => JobState::Failed {
+            error: format!("{:#}", err),
+        },
+    };
+    metrics.job_finished(input_file, &state);
+    // Keep the run, failed or not, for estimates and `preparer history`,
+    // and with the output for looking into how it came out
+    if !matches!(result, Ok(Outcome::AlreadyPrepared)) {
+        let (outcome, error) = match &result {
+            Ok(Outcome::Cancelled(_)) => ("cancelled", None),
+            Ok(_) => ("prepared", None),
+            Err(err) => ("failed", Some(format!("{:#}", err))),
+        };
+        let run = EncodeRun {
+            finished_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            wall_secs: started.elapsed().as_secs_f64(),
+            output_bytes: notify::directory_size(Path::new(&local_dir)),
+            bitrates,
+            outcome: outcome.to_string(),
+            error,
+            usage: Some(usage.clone()),
+            ..run
+        };
+        if let Some(catalog) = &mut catalog
+            && let Err(err) = catalog.record_run(&run)
+        {
+            eprintln!("Warning: failed to record the run: {:#}", err);
+        }
+        if Path::new(&local_dir).is_dir()
+            && let Err(err) = encode_log.write(&run, Path::new(&local_dir))
+        {
+            eprintln!("Warning: failed to write the encode log: {:#}", err);
+        }
+    }
+    match &result {
+        Ok(Outcome::Prepared(_)) => {
+            say(i18n::tr("complete", &[]));
+            if live.is_some() {
+                jit::finalize_manifest(Path::new(&local_dir))?;
+            }
+            let cores = usage
+                .cores(started.elapsed().as_secs_f64())
+                .map_or(String::new(), |cores| format!(" ({:.1} cores busy)", cores));
+            say(format!(
+                "{}{}",
+                i18n::tr("used", &[("usage", usage.to_string().into())]),
+                cores
+            ));
+            if let Some(extra_tracks) = extra_tracks {
+                let added = extra_tracks.finish(Path::new(&local_dir))?;
+                say(i18n::tr("added-tracks", &[("count", added.into())]));
+            }
+            if let Some(fingerprint) = fingerprint.as_ref().and_then(FingerprintBranch::value) {
+                std::fs::write(
+                    Path::new(&local_dir).join(library::FINGERPRINT),
+                    fingerprint,
+                )?;
+            }
+            if let Some(video_hash) = &video_hash {
+                std::fs::write(
+                    Path::new(&local_dir).join(library::VIDEO_HASH),
+                    dedupe::encode_video_hashes(&video_hash.hashes()),
+                )?;
+            }
+            if let Some(branch) = &trick_play {
+                branch.finish(Path::new(&local_dir))?;
+            }
+            if let Some(branch) = &waveform {
+                branch.finish(Path::new(&local_dir))?;
+            }
+            if let Err(err) = seekindex::SeekIndex::build(Path::new(&local_dir))
+                .and_then(|index| index.write(Path::new(&local_dir)))
+            {
+                eprintln!("Warning: failed to write the seek index: {:#}", err);
+            }
+            let name = match stdin {
+                true => String::new(),
+                false => Path::new(input_file)
+                    .file_stem()
+                    .map_or(String::new(), |stem| stem.to_string_lossy().into_owned()),
+            };
+            if let Some(branch) = &chapter_thumbnails {
+                let dir = Path::new(&local_dir);
+                let mut metadata =
+                    library::Metadata::read(dir)?.unwrap_or_else(|| library::Metadata {
+                        title: name.clone(),
+                        ..Default::default()
+                    });
+                metadata.chapters = branch.chapters(&toc);
+                metadata.write(dir)?;
+            }
+            if let Some(client) = &tmdb
+                && let Err(err) = fetch_metadata_into(client, &name, Path::new(&local_dir))
+            {
+                eprintln!("Warning: {:#}", err);
             }
-            _ => (),
+            if let Some(rating) = &args.rating {
+                let dir = Path::new(&local_dir);
+                let mut metadata =
+                    library::Metadata::read(dir)?.unwrap_or_else(|| library::Metadata {
+                        title: name.clone(),
+                        ..Default::default()
+                    });
+                metadata.rating = Some(rating.clone());
+                metadata.write(dir)?;
+            }
+            if Path::new(&local_dir).join(encodelog::ENCODE_LOG).exists() {
+                let dir = Path::new(&local_dir);
+                let mut metadata =
+                    library::Metadata::read(dir)?.unwrap_or_else(|| library::Metadata {
+                        title: name.clone(),
+                        ..Default::default()
+                    });
+                metadata.encode_log = Some(encodelog::ENCODE_LOG.to_string());
+                metadata.attention = attention;
+                metadata.write(dir)?;
+            }
+            migrate::stamp(Path::new(&local_dir), migrate::FORMAT_VERSION)?;
+            // Check before encrypting, which leaves the manifest unreadable
+            if args.source_policy != SourcePolicy::Keep {
+                push::validate(Path::new(&local_dir))
+                    .context("The output didn't check out, so the source was left alone")?;
+            }
+            if let Some(key) = &library_key {
+                let encrypted = key.encrypt_dir(Path::new(&local_dir))?;
+                say(format!("Encrypted {} files", encrypted));
+            }
+            if args.ipfs {
+                let cid = ipfs::publish(&args.ipfs_api, Path::new(&local_dir), &name)?;
+                say(format!("Published to IPFS: ipfs://{}", cid));
+            }
+            if let Some(destination) = &args.push {
+                say(format!("Pushing to {}", destination));
+                push::push(Path::new(&local_dir), destination, args.push_bwlimit)?;
+            }
+            if let Some(uploader) = uploader.or_else(|| remote.map(spawn_uploader)) {
+                say(format!("Finishing upload to {}", output_name));
+                uploader.finish()?;
+                std::fs::remove_dir_all(&local_dir)?;
+            }
+            if let (Some(live), Some(url)) = (live, &args.origin) {
+                say(format!("Finishing upload to {}", storage::redact(url)));
+                live.finish()?;
+            }
+            // Episodes share the source, so it waits for the last of them
+            if args.episode.is_none() {
+                handle_source(
+                    &args.source_policy,
+                    Path::new(input_file),
+                    &[PathBuf::from(&local_dir)],
+                )?;
+            }
+        }
+        Ok(Outcome::AlreadyPrepared) => {
+            say(i18n::tr(
+                "already-prepared",
+                &[("dir", output_name.as_str().into())],
+            ));
+            return Ok(());
         }
+        Ok(Outcome::Cancelled(_)) => say(i18n::tr("cancelled", &[])),
+        Err(_) => (),
     }
 
-    // Clean up
-    pipeline.set_state(gst::State::Null)?;
+    if args.notify_url.is_some() || args.notify_desktop {
+        let elapsed = started.elapsed().as_secs_f64();
+        let frames = match &result {
+            Ok(Outcome::Prepared(summary)) => summary.frames,
+            _ => frames,
+        };
+        let report = JobReport {
+            input: input_file.clone(),
+            output: output_name.clone(),
+            status: match &result {
+                Ok(Outcome::Cancelled(_)) => JobStatus::Cancelled,
+                Ok(_) => JobStatus::Completed,
+                Err(_) => JobStatus::Failed,
+            },
+            error: result.as_ref().err().map(|err| format!("{:#}", err)),
+            duration_secs: elapsed,
+            stats: JobStats {
+                frames,
+                average_fps: frames as f64 / elapsed,
+                bytes_written: notify::directory_size(Path::new(&local_dir)),
+            },
+        };
+        if let Some(url) = &args.notify_url
+            && let Err(err) = notify::send_webhook(url, &report)
+        {
+            eprintln!("{:#}", err);
+        }
+        if args.notify_desktop
+            && let Err(err) = notify::send_desktop(&report)
+        {
+            eprintln!("{:#}", err);
+        }
+    }
 
-    Ok(())
+    result.map(|_| ())
 }
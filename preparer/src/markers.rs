@@ -0,0 +1,455 @@
+//! Finding the intro and credits of TV episodes so viewers can skip them.
+//!
+//! Episodes of a season share their intro and credits music, so each
+//! episode's audio is fingerprinted and compared with the next one's: the
+//! longest stretch they share near the start is the intro, and near the end
+//! the credits. Boundaries are then nudged onto nearby silence, where cuts
+//! usually are. The markers go into each episode's `metadata.json` and into
+//! its manifest as an event stream the player offers "Skip intro" from.
+//! Markers can also be given by hand in a marker file, such as for recaps,
+//! which no two episodes share.
+
+use crate::library::{MANIFEST, Marker, MarkerKind, Metadata};
+use crate::mpd;
+use anyhow::{Context, Result, anyhow, bail};
+use movieshare_core::cuts;
+use movieshare_core::gst;
+use movieshare_core::gst::prelude::*;
+use std::path::Path;
+
+/// Fingerprint frames per second of audio.
+const FRAMES_PER_SEC: f64 = 8.0;
+const SAMPLE_RATE: i32 = 11025;
+const SPECTRUM_BANDS: u32 = 128;
+/// Frames match when their hashes differ in at most this many of 32 bits.
+const MAX_BIT_ERRORS: u32 = 10;
+/// A shared stretch survives this many mismatched frames in a row.
+const MAX_GAP_FRAMES: usize = 8;
+/// Frames whose loudest band is below this are silence.
+const SILENCE_DB: f32 = -60.0;
+/// How far a boundary may move to land on silence.
+const SNAP_FRAMES: usize = 16;
+
+/// What an episode's audio sounded like, frame by frame.
+#[derive(Debug, Clone, Default)]
+pub struct Fingerprint {
+    pub hashes: Vec<u32>,
+    pub silent: Vec<bool>,
+}
+
+impl Fingerprint {
+    pub fn duration_secs(&self) -> f64 {
+        self.hashes.len() as f64 / FRAMES_PER_SEC
+    }
+
+    /// Add a frame of spectrum magnitudes in dB.
+    fn push(&mut self, magnitudes: &[f32], previous: &mut [f32; 33]) {
+        let energies = band_energies(magnitudes);
+        let mut hash = 0;
+        for band in 0..32 {
+            let difference = energies[band] - energies[band + 1];
+            let previous_difference = previous[band] - previous[band + 1];
+            if difference - previous_difference > 0.0 {
+                hash |= 1 << band;
+            }
+        }
+        *previous = energies;
+        self.hashes.push(hash);
+        self.silent
+            .push(magnitudes.iter().all(|magnitude| *magnitude < SILENCE_DB));
+    }
+}
+
+/// Energy in 33 bands spaced logarithmically between 300 Hz and 3 kHz,
+/// where music is most recognisable.
+fn band_energies(magnitudes: &[f32]) -> [f32; 33] {
+    let hz_per_band = SAMPLE_RATE as f32 / 2.0 / magnitudes.len().max(1) as f32;
+    let mut energies = [0.0; 33];
+    for (band, energy) in energies.iter_mut().enumerate() {
+        let low = 300.0 * 10f32.powf(band as f32 / 33.0);
+        let high = 300.0 * 10f32.powf((band + 1) as f32 / 33.0);
+        let bins = (low / hz_per_band) as usize..((high / hz_per_band) as usize + 1);
+        let bins = &magnitudes[bins.start.min(magnitudes.len())..bins.end.min(magnitudes.len())];
+        let power: f32 = bins.iter().map(|db| 10f32.powf(db / 10.0)).sum();
+        *energy = (power / bins.len().max(1) as f32).max(1e-12).log10();
+    }
+    energies
+}
+
+/// Decode a file's audio and fingerprint all of it.
+pub fn fingerprint(input: &Path) -> Result<Fingerprint> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*input.to_string_lossy())
+        .build()?;
+    // Only audio needs decoding; other streams are dropped still encoded
+    let decodebin = gst::ElementFactory::make("decodebin")
+        .property("caps", gst::Caps::builder("audio/x-raw").build())
+        .build()?;
+    let audioconvert = gst::ElementFactory::make("audioconvert").build()?;
+    let audioresample = gst::ElementFactory::make("audioresample").build()?;
+    let capsfilter = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("audio/x-raw")
+                .field("rate", SAMPLE_RATE)
+                .field("channels", 1i32)
+                .build(),
+        )
+        .build()?;
+    let spectrum = gst::ElementFactory::make("spectrum")
+        .property("bands", SPECTRUM_BANDS)
+        .property("interval", (1e9 / FRAMES_PER_SEC) as u64)
+        .property("threshold", -80i32)
+        .property("post-messages", true)
+        .build()?;
+    let fakesink = gst::ElementFactory::make("fakesink")
+        .property("sync", false)
+        .build()?;
+    pipeline.add_many([
+        &filesrc,
+        &decodebin,
+        &audioconvert,
+        &audioresample,
+        &capsfilter,
+        &spectrum,
+        &fakesink,
+    ])?;
+    filesrc.link(&decodebin)?;
+    gst::Element::link_many([
+        &audioconvert,
+        &audioresample,
+        &capsfilter,
+        &spectrum,
+        &fakesink,
+    ])?;
+
+    let pipeline_weak = pipeline.downgrade();
+    let audioconvert_weak = audioconvert.downgrade();
+    decodebin.connect_pad_added(move |_, src_pad| {
+        let (Some(pipeline), Some(audioconvert)) =
+            (pipeline_weak.upgrade(), audioconvert_weak.upgrade())
+        else {
+            return;
+        };
+        let is_audio = src_pad
+            .current_caps()
+            .and_then(|caps| caps.structure(0).map(|s| s.name() == "audio/x-raw"))
+            .unwrap_or(false);
+        let sink_pad = audioconvert.static_pad("sink").unwrap();
+        if is_audio && !sink_pad.is_linked() {
+            let _ = src_pad.link(&sink_pad);
+            return;
+        }
+        // Everything else, including further audio tracks, goes nowhere
+        if let Ok(sink) = gst::ElementFactory::make("fakesink")
+            .property("sync", false)
+            .build()
+            && pipeline.add(&sink).is_ok()
+        {
+            let _ = sink.sync_state_with_parent();
+            let _ = src_pad.link(&sink.static_pad("sink").unwrap());
+        }
+    });
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline.bus().unwrap();
+    let mut fingerprint = Fingerprint::default();
+    let mut previous = [0.0; 33];
+    let result = loop {
+        use gst::MessageView;
+
+        let Some(message) = bus.timed_pop(gst::ClockTime::NONE) else {
+            break Ok(());
+        };
+        match message.view() {
+            MessageView::Eos(..) => break Ok(()),
+            MessageView::Error(err) => {
+                break Err(anyhow!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ));
+            }
+            MessageView::Element(element) => {
+                if let Some(s) = element.structure()
+                    && s.name() == "spectrum"
+                    && let Ok(magnitudes) = s.get::<gst::List>("magnitude")
+                {
+                    let magnitudes: Vec<f32> = magnitudes
+                        .iter()
+                        .filter_map(|value| value.get::<f32>().ok())
+                        .collect();
+                    fingerprint.push(&magnitudes, &mut previous);
+                }
+            }
+            _ => (),
+        }
+    };
+    pipeline.set_state(gst::State::Null)?;
+    result.context(format!("Failed to analyse {}", input.display()))?;
+    if fingerprint.hashes.is_empty() {
+        bail!("No audio in {}", input.display());
+    }
+    Ok(fingerprint)
+}
+
+/// A stretch two fingerprints share: where it starts in each, and its length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SharedSpan {
+    pub a_start: usize,
+    pub b_start: usize,
+    pub len: usize,
+}
+
+/// The longest stretch of `a` that also appears somewhere in `b`.
+pub fn longest_shared(a: &[u32], b: &[u32]) -> Option<SharedSpan> {
+    let mut best: Option<SharedSpan> = None;
+    // Line `a` up against `b` at every offset, following matching frames
+    for offset in -(b.len() as isize)..a.len() as isize {
+        let a_first = offset.max(0) as usize;
+        let b_first = (-offset).max(0) as usize;
+        let overlap = (a.len() - a_first).min(b.len() - b_first);
+        if best.is_some_and(|best| best.len >= overlap) {
+            continue;
+        }
+        let mut run_start = None;
+        let mut last_match = 0;
+        for i in 0..overlap {
+            let matches = (a[a_first + i] ^ b[b_first + i]).count_ones() <= MAX_BIT_ERRORS;
+            if matches {
+                if run_start.is_none() {
+                    run_start = Some(i);
+                }
+                last_match = i;
+            }
+            let ended = !matches && run_start.is_some() && i - last_match > MAX_GAP_FRAMES;
+            if let Some(start) = run_start
+                && (ended || i + 1 == overlap)
+            {
+                let len = last_match + 1 - start;
+                if best.is_none_or(|best| len > best.len) {
+                    best = Some(SharedSpan {
+                        a_start: a_first + start,
+                        b_start: b_first + start,
+                        len,
+                    });
+                }
+                if ended {
+                    run_start = None;
+                }
+            }
+        }
+    }
+    best
+}
+
+/// Move a boundary onto the nearest silent frame, if one is close.
+fn snap_to_silence(frame: usize, silent: &[bool]) -> usize {
+    (0..=SNAP_FRAMES)
+        .flat_map(|distance| [frame.checked_sub(distance), Some(frame + distance)])
+        .flatten()
+        .find(|&candidate| silent.get(candidate) == Some(&true))
+        .unwrap_or(frame)
+}
+
+/// Markers for an episode from what it shares with another.
+///
+/// Intros are looked for in the first `intro_window` seconds and credits in
+/// the last `credits_window`; either has to be `min_secs` long to count.
+pub fn find_markers(
+    episode: &Fingerprint,
+    other: &Fingerprint,
+    intro_window: f64,
+    credits_window: f64,
+    min_secs: f64,
+) -> Vec<Marker> {
+    let frames = |secs: f64| (secs * FRAMES_PER_SEC) as usize;
+    let min_frames = frames(min_secs);
+    let mut markers = Vec::new();
+
+    let head = |fingerprint: &Fingerprint| {
+        fingerprint.hashes[..frames(intro_window).min(fingerprint.hashes.len())].to_vec()
+    };
+    if let Some(span) = longest_shared(&head(episode), &head(other))
+        && span.len >= min_frames
+    {
+        let start = snap_to_silence(span.a_start, &episode.silent);
+        let end = snap_to_silence(span.a_start + span.len, &episode.silent);
+        markers.push(Marker {
+            kind: MarkerKind::Intro,
+            start_secs: start as f64 / FRAMES_PER_SEC,
+            end_secs: end as f64 / FRAMES_PER_SEC,
+        });
+    }
+
+    let tail_start = |fingerprint: &Fingerprint| {
+        fingerprint
+            .hashes
+            .len()
+            .saturating_sub(frames(credits_window))
+    };
+    let offset = tail_start(episode);
+    if let Some(span) = longest_shared(
+        &episode.hashes[offset..],
+        &other.hashes[tail_start(other)..],
+    ) && span.len >= min_frames
+    {
+        let start = snap_to_silence(offset + span.a_start, &episode.silent);
+        // Whatever follows the credits is rarely worth staying for
+        markers.push(Marker {
+            kind: MarkerKind::Credits,
+            start_secs: start as f64 / FRAMES_PER_SEC,
+            end_secs: episode.duration_secs(),
+        });
+    }
+    markers
+}
+
+/// Parse a marker file: one marker a line, as its kind (`intro`, `recap` or
+/// `credits`), start and end, in seconds or `[HH:]MM:SS[.sss]`. Blank lines
+/// and lines starting with `#` are skipped.
+pub fn parse_marker_file(text: &str) -> Result<Vec<Marker>> {
+    let mut markers = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [kind, start, end] = fields[..] else {
+            bail!("Line {}: expected a kind, start and end", i + 1);
+        };
+        let kind: MarkerKind = serde_json::from_value(serde_json::Value::from(kind))
+            .map_err(|_| anyhow!("Line {}: unknown marker kind {:?}", i + 1, kind))?;
+        let time = |text: &str| {
+            cuts::parse_time(text).context(format!("Line {}: invalid time {:?}", i + 1, text))
+        };
+        let (start_secs, end_secs) = (time(start)?, time(end)?);
+        if end_secs <= start_secs {
+            bail!("Line {}: the marker ends before it starts", i + 1);
+        }
+        markers.push(Marker {
+            kind,
+            start_secs,
+            end_secs,
+        });
+    }
+    markers.sort_by(|a, b| a.start_secs.total_cmp(&b.start_secs));
+    Ok(markers)
+}
+
+/// Read and parse a marker file.
+pub fn read_marker_file(path: &Path) -> Result<Vec<Marker>> {
+    let text = std::fs::read_to_string(path)
+        .context(format!("Failed to read markers {}", path.display()))?;
+    parse_marker_file(&text).context(format!("Invalid markers {}", path.display()))
+}
+
+/// Save an episode's markers into its `metadata.json` and manifest.
+pub fn write_markers(dir: &Path, markers: Vec<Marker>) -> Result<()> {
+    let mut metadata = Metadata::read(dir)?.unwrap_or_else(|| Metadata {
+        title: dir
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+        ..Metadata::default()
+    });
+    let manifest_path = dir.join(MANIFEST);
+    let manifest = std::fs::read_to_string(&manifest_path)
+        .context(format!("Failed to read {}", manifest_path.display()))?;
+    let manifest = mpd::set_skip_markers(&manifest, &markers)?;
+    metadata.markers = markers;
+    metadata.write(dir)?;
+    std::fs::write(&manifest_path, manifest)
+        .context(format!("Failed to write {}", manifest_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hashes that differ a lot from each other, as real audio does.
+    fn noise(seed: u32, len: usize) -> Vec<u32> {
+        let mut state = seed.wrapping_mul(2654435761).max(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    fn episode(intro_at: usize, theme: &[u32], credits: &[u32], seed: u32) -> Fingerprint {
+        let mut hashes = noise(seed, intro_at);
+        hashes.extend_from_slice(theme);
+        hashes.extend(noise(seed + 1, 2000));
+        hashes.extend_from_slice(credits);
+        let mut silent = vec![false; hashes.len()];
+        // A quiet moment just after the theme ends
+        silent[intro_at + theme.len() + 3] = true;
+        Fingerprint { hashes, silent }
+    }
+
+    #[test]
+    fn finds_shared_intro_and_credits() {
+        let theme = noise(7, 240);
+        let credits = noise(9, 400);
+        let mut first = episode(100, &theme, &credits, 1);
+        let second = episode(300, &theme, &credits, 3);
+        // A few flipped bits and a short glitch don't break the match
+        first.hashes[150] ^= 0b111;
+        first.hashes[160..164].copy_from_slice(&noise(11, 4));
+
+        let markers = find_markers(&first, &second, 120.0, 120.0, 15.0);
+        assert_eq!(
+            markers,
+            [
+                Marker {
+                    kind: MarkerKind::Intro,
+                    start_secs: 100.0 / FRAMES_PER_SEC,
+                    end_secs: 343.0 / FRAMES_PER_SEC,
+                },
+                Marker {
+                    kind: MarkerKind::Credits,
+                    start_secs: 2340.0 / FRAMES_PER_SEC,
+                    end_secs: 2740.0 / FRAMES_PER_SEC,
+                },
+            ]
+        );
+
+        // Nothing in common, nothing marked
+        let unrelated = Fingerprint {
+            hashes: noise(99, 2000),
+            silent: vec![false; 2000],
+        };
+        assert!(find_markers(&first, &unrelated, 120.0, 120.0, 15.0).is_empty());
+    }
+
+    #[test]
+    fn parses_marker_files() {
+        let markers =
+            parse_marker_file("# Episode 2\ncredits 41:10 43:00\n\nrecap 0 1:05.5\n").unwrap();
+        assert_eq!(
+            markers,
+            [
+                Marker {
+                    kind: MarkerKind::Recap,
+                    start_secs: 0.0,
+                    end_secs: 65.5,
+                },
+                Marker {
+                    kind: MarkerKind::Credits,
+                    start_secs: 2470.0,
+                    end_secs: 2580.0,
+                },
+            ]
+        );
+        assert!(parse_marker_file("outro 0 10").is_err());
+        assert!(parse_marker_file("intro 10 5").is_err());
+        assert!(parse_marker_file("intro 10").is_err());
+    }
+}
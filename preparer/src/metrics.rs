@@ -0,0 +1,273 @@
+//! Prometheus metrics for daemon mode and single runs: how many jobs are
+//! queued, running, completed and failed, and each running job's progress
+//! and encode speed, served as text at `/metrics`.
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use movieshare_core::queue::{JobId, JobQueue, JobState, QueueEvent};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+struct JobMetrics {
+    progress: f64,
+    fps: f64,
+}
+
+/// Job counters and per-job progress, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    jobs_queued: AtomicU64,
+    jobs_running: AtomicU64,
+    jobs_completed: AtomicU64,
+    jobs_failed: AtomicU64,
+    jobs: Mutex<BTreeMap<String, JobMetrics>>,
+}
+
+impl Metrics {
+    pub fn job_queued(&self) {
+        self.jobs_queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn job_started(&self, job: &str) {
+        self.jobs_queued.fetch_sub(1, Ordering::Relaxed);
+        self.jobs_running.fetch_add(1, Ordering::Relaxed);
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(job.to_string(), JobMetrics::default());
+    }
+
+    pub fn job_progress(&self, job: &str, progress: f64, fps: f64) {
+        if let Some(metrics) = self.jobs.lock().unwrap().get_mut(job) {
+            metrics.progress = progress;
+            metrics.fps = fps;
+        }
+    }
+
+    /// Count a running job as finished in `state`; cancelled ones are
+    /// neither completed nor failed.
+    pub fn job_finished(&self, job: &str, state: &JobState) {
+        self.job_moved(job, Some(&JobState::Running), state);
+    }
+
+    /// Count a queued job as no longer in `from`, and as in `to` instead.
+    fn job_moved(&self, job: &str, from: Option<&JobState>, to: &JobState) {
+        match from {
+            Some(JobState::Queued) => {
+                self.jobs_queued.fetch_sub(1, Ordering::Relaxed);
+            }
+            Some(JobState::Running) => {
+                self.jobs_running.fetch_sub(1, Ordering::Relaxed);
+                self.jobs.lock().unwrap().remove(job);
+            }
+            _ => (),
+        }
+        let counter = match to {
+            JobState::Queued => &self.jobs_queued,
+            JobState::Running => {
+                self.jobs
+                    .lock()
+                    .unwrap()
+                    .insert(job.to_string(), JobMetrics::default());
+                &self.jobs_running
+            }
+            JobState::Completed => &self.jobs_completed,
+            JobState::Failed { .. } => &self.jobs_failed,
+            JobState::Cancelled => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "movieshare_jobs_queued",
+                "gauge",
+                "Jobs waiting to start",
+                &self.jobs_queued,
+            ),
+            (
+                "movieshare_jobs_running",
+                "gauge",
+                "Jobs currently encoding",
+                &self.jobs_running,
+            ),
+            (
+                "movieshare_jobs_completed_total",
+                "counter",
+                "Jobs that finished successfully",
+                &self.jobs_completed,
+            ),
+            (
+                "movieshare_jobs_failed_total",
+                "counter",
+                "Jobs that finished with an error",
+                &self.jobs_failed,
+            ),
+        ];
+        for (name, kind, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let jobs = self.jobs.lock().unwrap();
+        let _ = writeln!(
+            out,
+            "# HELP movieshare_job_progress_ratio Fraction of the input processed"
+        );
+        let _ = writeln!(out, "# TYPE movieshare_job_progress_ratio gauge");
+        for (job, metrics) in jobs.iter() {
+            let _ = writeln!(
+                out,
+                "movieshare_job_progress_ratio{{job=\"{}\"}} {}",
+                escape_label(job),
+                metrics.progress
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP movieshare_encode_fps Decoded frames per second fed to the encoders"
+        );
+        let _ = writeln!(out, "# TYPE movieshare_encode_fps gauge");
+        for (job, metrics) in jobs.iter() {
+            let _ = writeln!(
+                out,
+                "movieshare_encode_fps{{job=\"{}\"}} {}",
+                escape_label(job),
+                metrics.fps
+            );
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Keep `metrics` up to date with the jobs of `queue`, labelled by job id,
+/// for as long as the queue runs.
+pub async fn follow(metrics: Arc<Metrics>, queue: JobQueue) -> Result<()> {
+    let mut events = queue.subscribe();
+    // Jobs already there count from where they are, but not ones that
+    // finished before this process started
+    let mut states: HashMap<JobId, JobState> = HashMap::new();
+    for job in queue.jobs() {
+        if !job.state.is_finished() {
+            metrics.job_moved(&job.id.to_string(), None, &job.state);
+        }
+        states.insert(job.id, job.state);
+    }
+    while let Some(event) = events.next().await {
+        match event {
+            QueueEvent::StateChanged { id, state } => {
+                let previous = states.insert(id, state.clone());
+                if previous.as_ref() != Some(&state) {
+                    metrics.job_moved(&id.to_string(), previous.as_ref(), &state);
+                }
+            }
+            QueueEvent::Progress { id, progress } => {
+                metrics.job_progress(&id.to_string(), progress.fraction, progress.fps);
+            }
+            QueueEvent::Warning { .. } => (),
+        }
+    }
+    Ok(())
+}
+
+/// How long a scrape may take to send its request or read the answer, so a
+/// client that connects and goes quiet can't hold up the next.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve `GET /metrics` on `addr` from a background thread.
+pub fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> Result<()> {
+    let listener =
+        TcpListener::bind(addr).context(format!("Failed to bind metrics address: {}", addr))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = handle_request(&metrics, stream) {
+                eprintln!("Metrics request failed: {}", err);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_request(metrics: &Metrics, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::from("Not found\n")),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_queue_jobs_as_they_move() {
+        let metrics = Metrics::default();
+        metrics.job_moved("1", None, &JobState::Queued);
+        metrics.job_moved("2", None, &JobState::Queued);
+        metrics.job_moved("1", Some(&JobState::Queued), &JobState::Running);
+        metrics.job_progress("1", 0.5, 24.0);
+        metrics.job_moved("2", Some(&JobState::Queued), &JobState::Cancelled);
+        let rendered = metrics.render();
+        assert!(rendered.contains("movieshare_jobs_queued 0\n"));
+        assert!(rendered.contains("movieshare_jobs_running 1\n"));
+        assert!(rendered.contains("movieshare_job_progress_ratio{job=\"1\"} 0.5\n"));
+
+        let failed = JobState::Failed {
+            error: String::from("No decoder"),
+        };
+        metrics.job_moved("1", Some(&JobState::Running), &failed);
+        let rendered = metrics.render();
+        assert!(rendered.contains("movieshare_jobs_running 0\n"));
+        assert!(rendered.contains("movieshare_jobs_failed_total 1\n"));
+        assert!(!rendered.contains("job=\"1\""));
+    }
+
+    #[test]
+    fn counts_a_cancelled_run_as_neither_completed_nor_failed() {
+        let metrics = Metrics::default();
+        metrics.job_queued();
+        metrics.job_started("movie.mkv");
+        metrics.job_finished("movie.mkv", &JobState::Cancelled);
+        let rendered = metrics.render();
+        assert!(rendered.contains("movieshare_jobs_running 0\n"));
+        assert!(rendered.contains("movieshare_jobs_completed_total 0\n"));
+        assert!(rendered.contains("movieshare_jobs_failed_total 0\n"));
+        assert!(!rendered.contains("movie.mkv"));
+    }
+}
@@ -0,0 +1,119 @@
+//! `preparer migrate`: bring titles prepared by older releases up to the
+//! current packaging conventions.
+//!
+//! Each title's `metadata.json` records the format version it follows; titles
+//! from before versioning count as version 0. Upgrades run in order, and the
+//! version is stamped after each one, so an interrupted migration picks up
+//! where it stopped.
+
+use crate::artwork;
+use crate::library::{MANIFEST, Metadata};
+use crate::mpd;
+use crate::seekindex::SeekIndex;
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/// Brings a title in a directory up one format version.
+type Upgrade = fn(&Path) -> Result<()>;
+
+/// Upgrades between format versions: the one at index `i` takes a title from
+/// version `i` to `i + 1`.
+const UPGRADES: &[(&str, Upgrade)] = &[
+    ("tag tracks with BCP 47 languages", normalize_languages),
+    ("name the poster poster.jpg", rename_poster),
+    ("write the seek index", write_seek_index),
+];
+
+/// Format version of titles prepared by this release.
+pub const FORMAT_VERSION: u32 = UPGRADES.len() as u32;
+
+/// Record in `dir`'s metadata that the title follows format `version`.
+pub fn stamp(dir: &Path, version: u32) -> Result<()> {
+    let mut metadata = Metadata::read(dir)?.unwrap_or_else(|| Metadata {
+        title: dir
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+        ..Metadata::default()
+    });
+    metadata.format_version = version;
+    metadata.write(dir)
+}
+
+/// What [`migrate`] did, or would do, to one title.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migration {
+    pub from: u32,
+    pub upgrades: Vec<&'static str>,
+}
+
+/// Upgrade the title in `dir` to [`FORMAT_VERSION`], or with `dry_run` only
+/// say what that would take.
+pub fn migrate(dir: &Path, dry_run: bool) -> Result<Migration> {
+    let from = Metadata::read(dir)?.map_or(0, |metadata| metadata.format_version);
+    let mut migration = Migration {
+        from,
+        upgrades: Vec::new(),
+    };
+    for (version, (description, upgrade)) in UPGRADES.iter().enumerate().skip(from as usize) {
+        if !dry_run {
+            upgrade(dir).context(format!("Failed to {}", description))?;
+            stamp(dir, version as u32 + 1)?;
+        }
+        migration.upgrades.push(*description);
+    }
+    Ok(migration)
+}
+
+fn normalize_languages(dir: &Path) -> Result<()> {
+    let path = dir.join(MANIFEST);
+    let xml =
+        std::fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+    std::fs::write(&path, mpd::normalize_languages(&xml)?)
+        .context(format!("Failed to write {}", path.display()))
+}
+
+/// Early releases cached the poster as `folder.jpg`, for Kodi.
+fn rename_poster(dir: &Path) -> Result<()> {
+    let old = dir.join("folder.jpg");
+    if old.is_file() && !dir.join(artwork::POSTER).exists() {
+        std::fs::rename(&old, dir.join(artwork::POSTER))?;
+    }
+    Ok(())
+}
+
+fn write_seek_index(dir: &Path) -> Result<()> {
+    SeekIndex::build(dir)?.write(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrades_from_the_stamped_version() {
+        let dir = std::env::temp_dir().join(format!("movieshare-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD><Period><AdaptationSet mimeType="audio/mp4" lang="fre"><Representation id="0" bandwidth="1"><SegmentList duration="1"></SegmentList></Representation></AdaptationSet></Period></MPD>"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("folder.jpg"), b"jpeg").unwrap();
+
+        let planned = migrate(&dir, true).unwrap();
+        assert_eq!(planned.from, 0);
+        assert_eq!(planned.upgrades.len(), FORMAT_VERSION as usize);
+        assert!(!dir.join(artwork::POSTER).exists());
+
+        assert_eq!(migrate(&dir, false).unwrap(), planned);
+        assert!(dir.join(artwork::POSTER).is_file());
+        let xml = std::fs::read_to_string(dir.join(MANIFEST)).unwrap();
+        assert!(xml.contains(r#"lang="fr""#), "{}", xml);
+        assert_eq!(
+            Metadata::read(&dir).unwrap().unwrap().format_version,
+            FORMAT_VERSION
+        );
+        assert!(migrate(&dir, false).unwrap().upgrades.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+//! `preparer sync`: mirror another movieshare server's titles into a
+//! library.
+//!
+//! The remote's `/api/titles/{id}/files` lists each title's files with
+//! their SHA-256, and what's missing or different here is downloaded from
+//! its `/media`, checked against those checksums, and only then moved into
+//! place. A title that's new here appears whole once all of it has arrived;
+//! for one that changed, the manifest goes in last so players never see it
+//! name segments that aren't there yet. Titles are only added or updated,
+//! never removed, so two servers syncing from each other keep the union of
+//! their libraries.
+
+use crate::library::{self, Catalog, FileChecksum, MANIFEST};
+use crate::serve::{encode_segment, is_title};
+use crate::upload::valid_title;
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Component, Path};
+use ureq::Body;
+use ureq::http::Response;
+
+/// Where titles are assembled before they're moved into the library.
+const STAGING_DIR: &str = ".sync";
+const SESSION_COOKIE: &str = "movieshare_session";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    /// Bytes downloaded, or that would be with a dry run
+    pub bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct RemoteTitle {
+    id: String,
+}
+
+/// A movieshare server to sync from.
+struct Remote {
+    base: String,
+    agent: ureq::Agent,
+    cookie: Option<String>,
+}
+
+impl Remote {
+    fn new(base: &str) -> Self {
+        // Redirects and errors are answers here, like the one to /login
+        let agent = ureq::Agent::config_builder()
+            .max_redirects(0)
+            .http_status_as_error(false)
+            .build()
+            .into();
+        Remote {
+            base: base.trim_end_matches('/').to_string(),
+            agent,
+            cookie: None,
+        }
+    }
+
+    /// Sign in as `name`, for servers with password auth.
+    fn sign_in(&mut self, name: &str, password: &str) -> Result<()> {
+        let url = format!("{}/login", self.base);
+        let response = self
+            .agent
+            .post(&url)
+            .send_form([("name", name), ("password", password)])
+            .context(format!("Failed to reach {}", url))?;
+        self.cookie = response
+            .headers()
+            .get_all("set-cookie")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| value.split(';').next())
+            .find(|cookie| cookie.starts_with(&format!("{}=", SESSION_COOKIE)))
+            .map(String::from);
+        if self.cookie.is_none() {
+            bail!("{} didn't accept the password for {}", self.base, name);
+        }
+        Ok(())
+    }
+
+    fn get(&self, path: &str) -> Result<Response<Body>> {
+        let url = format!("{}{}", self.base, path);
+        let mut request = self.agent.get(&url);
+        if let Some(cookie) = &self.cookie {
+            request = request.header("cookie", cookie);
+        }
+        let response = request.call().context(format!("Failed to reach {}", url))?;
+        match response.status().as_u16() {
+            200 => Ok(response),
+            303 | 401 => bail!("{} wants a signed-in user; pass --user", self.base),
+            501 => bail!(
+                "{} serves an encrypted library, which can't be mirrored yet",
+                self.base
+            ),
+            status => bail!("{} answered {}", url, status),
+        }
+    }
+
+    fn get_json<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let body = self.get(path)?.body_mut().read_to_vec()?;
+        serde_json::from_slice(&body).context(format!("Unexpected answer from {}", path))
+    }
+
+    fn titles(&self) -> Result<Vec<String>> {
+        let titles: Vec<RemoteTitle> = self.get_json("/api/titles")?;
+        Ok(titles.into_iter().map(|title| title.id).collect())
+    }
+
+    fn files(&self, title: &str) -> Result<Vec<FileChecksum>> {
+        self.get_json(&format!("/api/titles/{}/files", encode_segment(title)))
+    }
+
+    /// Download `file` of `title` to `to`, checking it's what was listed.
+    fn download(&self, title: &str, file: &FileChecksum, to: &Path) -> Result<()> {
+        let path: Vec<String> = file.path.split('/').map(encode_segment).collect();
+        let response = self.get(&format!(
+            "/media/{}/{}",
+            encode_segment(title),
+            path.join("/")
+        ))?;
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut out =
+            std::fs::File::create(to).context(format!("Failed to create {}", to.display()))?;
+        let mut reader = response.into_body().into_reader();
+        let mut hasher = Sha256::new();
+        let mut buffer = [0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            out.write_all(&buffer[..read])?;
+            size += read as u64;
+        }
+        out.sync_all()?;
+        if size != file.size_bytes || format!("{:x}", hasher.finalize()) != file.sha256 {
+            bail!(
+                "{}/{} didn't arrive intact; it may have changed on {}",
+                title,
+                file.path,
+                self.base
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` stays inside the directory it's relative to.
+fn contained(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Which of the `remote` files to fetch, as they're missing or differ
+/// here, and which `local` files the remote no longer has.
+fn changes<'a>(
+    remote: &'a [FileChecksum],
+    local: &[FileChecksum],
+) -> (Vec<&'a FileChecksum>, Vec<String>) {
+    let local_sums: HashMap<&str, &str> = local
+        .iter()
+        .map(|file| (file.path.as_str(), file.sha256.as_str()))
+        .collect();
+    let fetch = remote
+        .iter()
+        .filter(|file| local_sums.get(file.path.as_str()) != Some(&file.sha256.as_str()))
+        .collect();
+    let stale = local
+        .iter()
+        .filter(|file| !remote.iter().any(|other| other.path == file.path))
+        .map(|file| file.path.clone())
+        .collect();
+    (fetch, stale)
+}
+
+/// Bring `library` up to date with the titles on the server at `base`,
+/// signed in with `credentials` if it wants. With `dry_run`, only say what
+/// would change.
+pub fn sync(
+    base: &str,
+    credentials: Option<(&str, &str)>,
+    library: &Path,
+    dry_run: bool,
+) -> Result<SyncSummary> {
+    let mut remote = Remote::new(base);
+    if let Some((name, password)) = credentials {
+        remote.sign_in(name, password)?;
+    }
+    let staging = library.join(STAGING_DIR);
+    let mut summary = SyncSummary::default();
+    for title in remote.titles()? {
+        if !valid_title(&title) {
+            bail!("{} listed an invalid title: {:?}", base, title);
+        }
+        let files = remote.files(&title)?;
+        if let Some(file) = files.iter().find(|file| !contained(&file.path)) {
+            bail!(
+                "{} listed an invalid file of {}: {:?}",
+                base,
+                title,
+                file.path
+            );
+        }
+        let exists = is_title(library, &title);
+        let local = match exists {
+            true => library::checksums(library, &title)?,
+            false => Vec::new(),
+        };
+        let (fetch, stale) = changes(&files, &local);
+        if fetch.is_empty() && stale.is_empty() {
+            summary.unchanged += 1;
+            continue;
+        }
+        match exists {
+            true => summary.updated += 1,
+            false => summary.added += 1,
+        }
+        summary.bytes += fetch.iter().map(|file| file.size_bytes).sum::<u64>();
+        if dry_run {
+            continue;
+        }
+
+        let stage = staging.join(&title);
+        if stage.exists() {
+            std::fs::remove_dir_all(&stage)?;
+        }
+        std::fs::create_dir_all(&stage).context(format!("Failed to create {}", stage.display()))?;
+        for file in &fetch {
+            remote.download(&title, file, &stage.join(&file.path))?;
+        }
+        let dir = library.join(&title);
+        if !exists {
+            if dir.exists() {
+                bail!("{} is in the way of {}", dir.display(), title);
+            }
+            std::fs::rename(&stage, &dir)
+                .context(format!("Failed to move {} into place", title))?;
+            continue;
+        }
+        let (manifest, segments): (Vec<&FileChecksum>, Vec<&FileChecksum>) =
+            fetch.into_iter().partition(|file| file.path == MANIFEST);
+        for file in segments.into_iter().chain(manifest) {
+            let to = dir.join(&file.path);
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(stage.join(&file.path), &to)
+                .context(format!("Failed to move {} into place", to.display()))?;
+        }
+        for path in stale {
+            std::fs::remove_file(dir.join(&path))
+                .context(format!("Failed to remove {}/{}", title, path))?;
+        }
+        std::fs::remove_dir_all(&stage)?;
+    }
+    if staging.exists() {
+        let _ = std::fs::remove_dir_all(&staging);
+    }
+
+    if !dry_run
+        && summary.added + summary.updated > 0
+        && let Some(mut catalog) = Catalog::open_existing(library)?
+    {
+        catalog.scan(library, false)?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, sha256: &str) -> FileChecksum {
+        FileChecksum {
+            path: path.to_string(),
+            size_bytes: 10,
+            sha256: sha256.to_string(),
+        }
+    }
+
+    #[test]
+    fn fetches_what_differs() {
+        let remote = [
+            file(MANIFEST, "b"),
+            file("video/1.m4s", "c"),
+            file("video/2.m4s", "d"),
+        ];
+        let local = [
+            file(MANIFEST, "a"),
+            file("video/1.m4s", "c"),
+            file("video/old.m4s", "e"),
+        ];
+        let (fetch, stale) = changes(&remote, &local);
+        let fetch: Vec<&str> = fetch.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(fetch, [MANIFEST, "video/2.m4s"]);
+        assert_eq!(stale, ["video/old.m4s"]);
+        assert_eq!(changes(&remote, &[]).0.len(), 3);
+
+        assert!(contained("video/1.m4s"));
+        assert!(!contained("../other/manifest.mpd"));
+        assert!(!contained("/etc/passwd"));
+        assert!(!contained(""));
+    }
+}
@@ -0,0 +1,1195 @@
+//! Just enough MPD parsing to summarise a prepared title.
+
+use crate::library::Marker;
+use anyhow::{Context, Result, bail};
+use movieshare_core::language;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer, XmlVersion};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Representation {
+    pub id: String,
+    /// `video`, `audio`, `text` or `image`
+    pub content_type: String,
+    pub codecs: Option<String>,
+    /// Bits per second as declared in the manifest
+    pub bandwidth: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub lang: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    pub duration_secs: Option<f64>,
+    pub representations: Vec<Representation>,
+}
+
+/// Parse an ISO 8601 duration of the form MPDs use, like `PT1H2M3.5S`.
+pub fn parse_duration(duration: &str) -> Option<f64> {
+    let rest = duration.strip_prefix("P")?;
+    let (days, time) = match rest.split_once('T') {
+        Some((days, time)) => (days, time),
+        None => (rest, ""),
+    };
+
+    let mut seconds = 0.0;
+    if !days.is_empty() {
+        seconds += days.strip_suffix('D')?.parse::<f64>().ok()? * 86400.0;
+    }
+    let mut number = String::new();
+    for c in time.chars() {
+        let unit = match c {
+            'H' => 3600.0,
+            'M' => 60.0,
+            'S' => 1.0,
+            _ => {
+                number.push(c);
+                continue;
+            }
+        };
+        seconds += number.parse::<f64>().ok()? * unit;
+        number.clear();
+    }
+    number.is_empty().then_some(seconds)
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Result<Option<String>> {
+    Ok(match element.try_get_attribute(name)? {
+        Some(value) => Some(
+            value
+                .normalized_value(XmlVersion::Implicit1_0)?
+                .into_owned(),
+        ),
+        None => None,
+    })
+}
+
+/// `contentType`, or the type half of `mimeType`.
+fn content_type(element: &BytesStart) -> Result<Option<String>> {
+    if let Some(content_type) = attribute(element, "contentType")? {
+        return Ok(Some(content_type));
+    }
+    Ok(attribute(element, "mimeType")?.and_then(|mime| mime.split('/').next().map(str::to_string)))
+}
+
+pub fn parse(xml: &str) -> Result<Manifest> {
+    let mut reader = Reader::from_str(xml);
+    let mut manifest = Manifest::default();
+    // Representations inherit these from their adaptation set
+    let mut set_type = None;
+    let mut set_codecs = None;
+    let mut set_lang = None;
+
+    loop {
+        let (element, is_empty) = match reader.read_event().context("Invalid MPD")? {
+            Event::Start(element) => (element, false),
+            Event::Empty(element) => (element, true),
+            Event::Eof => break,
+            _ => continue,
+        };
+
+        match element.local_name().into_inner() {
+            "MPD" => {
+                manifest.duration_secs = attribute(&element, "mediaPresentationDuration")?
+                    .as_deref()
+                    .and_then(parse_duration);
+            }
+            "AdaptationSet" if !is_empty => {
+                set_type = content_type(&element)?;
+                set_codecs = attribute(&element, "codecs")?;
+                // Older titles were tagged however the source had it
+                set_lang = attribute(&element, "lang")?
+                    .map(|lang| language::normalize(&lang).unwrap_or(lang));
+            }
+            "Representation" => {
+                let number = |name: &str| -> Result<Option<u64>> {
+                    Ok(attribute(&element, name)?.and_then(|v| v.parse().ok()))
+                };
+                manifest.representations.push(Representation {
+                    id: attribute(&element, "id")?.unwrap_or_default(),
+                    content_type: content_type(&element)?
+                        .or_else(|| set_type.clone())
+                        .unwrap_or_default(),
+                    codecs: attribute(&element, "codecs")?.or_else(|| set_codecs.clone()),
+                    bandwidth: number("bandwidth")?.unwrap_or(0),
+                    width: number("width")?.map(|w| w as u32),
+                    height: number("height")?.map(|h| h as u32),
+                    lang: set_lang.clone(),
+                });
+            }
+            _ => (),
+        }
+    }
+    Ok(manifest)
+}
+
+/// One media segment of a representation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// File name, relative to the manifest
+    pub media: String,
+    pub start_secs: f64,
+    pub duration_secs: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SegmentList {
+    /// Initialization segment to put in front of any media segment
+    pub initialization: Option<String>,
+    pub segments: Vec<Segment>,
+}
+
+/// A `SegmentList` as read, before segment times are worked out.
+#[derive(Default, Clone)]
+struct PendingList {
+    timescale: u64,
+    duration: Option<u64>,
+    initialization: Option<String>,
+    media: Vec<String>,
+    /// `S` elements of a `SegmentTimeline`: start, duration and repeats
+    timeline: Vec<(Option<u64>, u64, u64)>,
+}
+
+impl PendingList {
+    fn finish(self) -> Result<SegmentList> {
+        let mut times = Vec::new();
+        let mut next = 0;
+        for (start, duration, repeats) in &self.timeline {
+            next = start.unwrap_or(next);
+            for _ in 0..=*repeats {
+                times.push((next, *duration));
+                next += duration;
+            }
+        }
+        if times.is_empty() {
+            let duration = self
+                .duration
+                .context("The segment list gives neither a duration nor a timeline")?;
+            times = (0..self.media.len() as u64)
+                .map(|i| (i * duration, duration))
+                .collect();
+        }
+        let timescale = self.timescale.max(1) as f64;
+        Ok(SegmentList {
+            initialization: self.initialization,
+            segments: self
+                .media
+                .into_iter()
+                .zip(times)
+                .map(|(media, (start, duration))| Segment {
+                    media,
+                    start_secs: start as f64 / timescale,
+                    duration_secs: duration as f64 / timescale,
+                })
+                .collect(),
+        })
+    }
+}
+
+/// The segments of the representation with id `representation`, from the
+/// `SegmentList` dashsink writes on it or its adaptation set.
+pub fn segment_list(xml: &str, representation: &str) -> Result<SegmentList> {
+    #[derive(PartialEq)]
+    enum Scope {
+        Outside,
+        Set,
+        Wanted,
+        Other,
+    }
+
+    let mut reader = Reader::from_str(xml);
+    let mut scope = Scope::Outside;
+    let mut set_list: Option<PendingList> = None;
+    let mut representation_list: Option<PendingList> = None;
+    let mut list: Option<PendingList> = None;
+    loop {
+        let (element, is_empty) = match reader.read_event().context("Invalid MPD")? {
+            Event::Start(element) => (element, false),
+            Event::Empty(element) => (element, true),
+            Event::End(element) => {
+                match element.local_name().into_inner() {
+                    "SegmentList" => match scope {
+                        Scope::Set => set_list = list.take(),
+                        Scope::Wanted => representation_list = list.take(),
+                        _ => list = None,
+                    },
+                    "Representation" if scope == Scope::Wanted => {
+                        return representation_list
+                            .or(set_list)
+                            .context("The representation has no segment list")?
+                            .finish();
+                    }
+                    "Representation" => scope = Scope::Set,
+                    "AdaptationSet" => scope = Scope::Outside,
+                    _ => (),
+                }
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+        let number = |name: &str| -> Result<Option<u64>> {
+            Ok(attribute(&element, name)?.and_then(|v| v.parse().ok()))
+        };
+        match element.local_name().into_inner() {
+            "AdaptationSet" => {
+                scope = Scope::Set;
+                set_list = None;
+            }
+            "Representation" => {
+                let wanted = attribute(&element, "id")?.as_deref() == Some(representation);
+                if wanted && is_empty {
+                    return set_list
+                        .context("The representation has no segment list")?
+                        .finish();
+                }
+                scope = match wanted {
+                    true => Scope::Wanted,
+                    false => Scope::Other,
+                };
+            }
+            "SegmentList" => {
+                list = Some(PendingList {
+                    timescale: number("timescale")?.unwrap_or(1),
+                    duration: number("duration")?,
+                    ..Default::default()
+                })
+            }
+            "Initialization" => {
+                if let Some(list) = &mut list {
+                    list.initialization = attribute(&element, "sourceURL")?;
+                }
+            }
+            "SegmentURL" => {
+                if let Some(list) = &mut list
+                    && let Some(media) = attribute(&element, "media")?
+                {
+                    list.media.push(media);
+                }
+            }
+            "S" => {
+                if let Some(list) = &mut list {
+                    list.timeline.push((
+                        number("t")?,
+                        number("d")?.context("A timeline entry has no duration")?,
+                        number("r")?.unwrap_or(0),
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+    bail!("No representation {:?} in the manifest", representation)
+}
+
+/// The `BaseURL` of the representation with id `representation`, which is
+/// how sidecar files like WebVTT subtitles are given.
+pub fn base_url(xml: &str, representation: &str) -> Result<Option<String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut inside = false;
+    let mut in_base_url = false;
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Start(element) => match element.local_name().into_inner() {
+                "Representation" => {
+                    inside = attribute(&element, "id")?.as_deref() == Some(representation)
+                }
+                "BaseURL" => in_base_url = inside,
+                _ => (),
+            },
+            Event::Text(text) if in_base_url => {
+                return Ok(Some(
+                    text.xml_content(XmlVersion::Implicit1_0).trim().to_owned(),
+                ));
+            }
+            Event::End(element) => match element.local_name().into_inner() {
+                "Representation" if inside => return Ok(None),
+                "BaseURL" => in_base_url = false,
+                _ => (),
+            },
+            Event::Eof => bail!("No representation {:?} in the manifest", representation),
+            _ => (),
+        }
+    }
+}
+
+/// Format seconds as an ISO 8601 duration, like `PT95.5S`.
+pub fn format_duration(secs: f64) -> String {
+    format!("PT{}S", (secs * 1000.0).round() / 1000.0)
+}
+
+/// Scheme of the property giving each period of a multi-title
+/// presentation its title.
+pub const PERIOD_TITLE_SCHEME: &str = "urn:movieshare:period-title:2024";
+
+/// One prepared input of a multi-period presentation.
+pub struct PeriodPart {
+    /// Directory holding the input's segments, relative to the joined manifest
+    pub dir: String,
+    pub title: String,
+    /// The input's own single-period manifest
+    pub xml: String,
+}
+
+/// Join the manifests of several prepared inputs into one presentation,
+/// each input a period played after the one before.
+pub fn join_periods(parts: &[PeriodPart]) -> Result<String> {
+    let mut writer = Writer::new(Vec::new());
+    let durations = parts
+        .iter()
+        .map(|part| {
+            parse(&part.xml)?
+                .duration_secs
+                .context(format!("{} has no duration", part.dir))
+        })
+        .collect::<Result<Vec<f64>>>()?;
+    let total: f64 = durations.iter().sum();
+
+    let mut start = 0.0;
+    for (i, (part, duration)) in parts.iter().zip(&durations).enumerate() {
+        let mut reader = Reader::from_str(&part.xml);
+        // Depth inside the period being copied
+        let mut depth = 0;
+        loop {
+            let event = reader.read_event().context("Invalid MPD")?;
+            if depth > 0 {
+                match &event {
+                    Event::Start(_) => depth += 1,
+                    Event::End(_) => depth -= 1,
+                    _ => (),
+                }
+                if depth == 0 {
+                    writer.write_event(Event::End(BytesEnd::new("Period")))?;
+                    continue;
+                }
+                writer.write_event(event)?;
+                continue;
+            }
+            match event {
+                Event::Eof => break,
+                Event::Start(element) if element.local_name().into_inner() == "Period" => {
+                    let id = format!("{}", i);
+                    let period = BytesStart::new("Period").with_attributes([
+                        ("id", id.as_str()),
+                        ("start", format_duration(start).as_str()),
+                        ("duration", format_duration(*duration).as_str()),
+                    ]);
+                    writer.write_event(Event::Start(period))?;
+                    let base_url = format!("{}/", part.dir);
+                    writer
+                        .create_element("BaseURL")
+                        .write_text_content(BytesText::new(&base_url))?;
+                    writer
+                        .create_element("SupplementalProperty")
+                        .with_attributes([
+                            ("schemeIdUri", PERIOD_TITLE_SCHEME),
+                            ("value", part.title.as_str()),
+                        ])
+                        .write_empty()?;
+                    depth = 1;
+                }
+                // The first manifest provides the document around the periods
+                Event::Start(element) if i == 0 && element.local_name().into_inner() == "MPD" => {
+                    let mut root = BytesStart::new(element.name().into_inner().to_string());
+                    for attribute in element.attributes() {
+                        let attribute = attribute?;
+                        if attribute.key.local_name().into_inner() != "mediaPresentationDuration" {
+                            root.push_attribute(attribute);
+                        }
+                    }
+                    root.push_attribute((
+                        "mediaPresentationDuration",
+                        format_duration(total).as_str(),
+                    ));
+                    writer.write_event(Event::Start(root))?;
+                }
+                // Closed once every period is in
+                Event::End(element) if element.local_name().into_inner() == "MPD" => (),
+                event if i == 0 => writer.write_event(event)?,
+                _ => (),
+            }
+        }
+        start += duration;
+    }
+    writer.write_event(Event::End(BytesEnd::new("MPD")))?;
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Turn the dynamic manifest of a finished run into a static one.
+pub fn make_static(xml: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            Event::Start(element) if element.local_name().into_inner() == "MPD" => {
+                let mut root = BytesStart::new(element.name().into_inner().to_string());
+                for attribute in element.attributes() {
+                    let attribute = attribute?;
+                    if !matches!(
+                        attribute.key.local_name().into_inner(),
+                        "type" | "minimumUpdatePeriod" | "availabilityStartTime" | "publishTime"
+                    ) {
+                        root.push_attribute(attribute);
+                    }
+                }
+                root.push_attribute(("type", "static"));
+                writer.write_event(Event::Start(root))?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Scheme of the event streams holding skip markers; each stream's `value`
+/// is the kind of marker.
+pub const SKIP_SCHEME: &str = "urn:movieshare:skip:2024";
+
+/// Replace the skip markers in a manifest's first period with `markers`.
+pub fn set_skip_markers(xml: &str, markers: &[Marker]) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let is_skip_stream = |element: &BytesStart| -> Result<bool> {
+        Ok(element.local_name().into_inner() == "EventStream"
+            && attribute(element, "schemeIdUri")?.as_deref() == Some(SKIP_SCHEME))
+    };
+    // Depth inside an old skip stream being dropped
+    let mut skipping = 0;
+    let mut written = false;
+    loop {
+        let event = reader.read_event().context("Invalid MPD")?;
+        if skipping > 0 {
+            match event {
+                Event::Start(_) => skipping += 1,
+                Event::End(_) => skipping -= 1,
+                Event::Eof => break,
+                _ => (),
+            }
+            continue;
+        }
+        match event {
+            Event::Eof => break,
+            Event::Start(element) if is_skip_stream(&element)? => skipping = 1,
+            Event::Empty(element) if is_skip_stream(&element)? => (),
+            Event::Start(element) if element.local_name().into_inner() == "Period" && !written => {
+                writer.write_event(Event::Start(element))?;
+                for (id, marker) in markers.iter().enumerate() {
+                    let kind = serde_json::to_value(marker.kind)?;
+                    let stream = BytesStart::new("EventStream").with_attributes([
+                        ("schemeIdUri", SKIP_SCHEME),
+                        ("value", kind.as_str().unwrap_or_default()),
+                        ("timescale", "1000"),
+                    ]);
+                    let start = format!("{}", (marker.start_secs * 1000.0).round() as u64);
+                    let duration = format!(
+                        "{}",
+                        ((marker.end_secs - marker.start_secs) * 1000.0).round() as u64
+                    );
+                    let id = format!("{}", id);
+                    writer.write_event(Event::Start(stream))?;
+                    writer.write_event(Event::Empty(BytesStart::new("Event").with_attributes(
+                        [
+                            ("id", id.as_str()),
+                            ("presentationTime", start.as_str()),
+                            ("duration", duration.as_str()),
+                        ],
+                    )))?;
+                    writer.write_event(Event::End(BytesEnd::new("EventStream")))?;
+                }
+                written = true;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Scheme of the `Role` marking a text track as subtitles.
+const ROLE_SCHEME: &str = "urn:mpeg:dash:role:2011";
+
+/// An id for a new representation, after the numbers dashsink gave the others.
+pub fn next_representation_id(manifest: &Manifest) -> String {
+    let next = manifest
+        .representations
+        .iter()
+        .filter_map(|representation| representation.id.parse::<u64>().ok())
+        .max()
+        .map_or(0, |id| id + 1);
+    format!("{}", next)
+}
+
+/// A copy of `element` with the attributes `replace` gives a value for changed.
+fn rewritten(
+    element: &BytesStart,
+    replace: impl Fn(&str, &str) -> Option<String>,
+) -> Result<BytesStart<'static>> {
+    let mut rewritten = BytesStart::new(element.name().into_inner().to_string());
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let value = attribute.normalized_value(XmlVersion::Implicit1_0)?;
+        match replace(attribute.key.local_name().into_inner(), &value) {
+            Some(value) => rewritten.push_attribute((attribute.key.into_inner(), value.as_str())),
+            None => rewritten.push_attribute(attribute),
+        }
+    }
+    Ok(rewritten)
+}
+
+/// The first element in `xml` that `is_wanted`, with the segments under it
+/// found under `base` and its representation given the id `id`, ready to go
+/// into another manifest.
+fn extract(
+    xml: &str,
+    is_wanted: impl Fn(&BytesStart) -> Result<bool>,
+    base: &str,
+    id: &str,
+) -> Result<Option<String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let moved = |element: &BytesStart| {
+        let name = element.local_name().into_inner().to_string();
+        rewritten(element, |key, value| match (name.as_str(), key) {
+            ("Representation", "id") => Some(id.to_string()),
+            ("Initialization", "sourceURL") | ("SegmentURL", "media") => {
+                Some(format!("{}{}", base, value))
+            }
+            _ => None,
+        })
+    };
+    // Depth inside the element being copied
+    let mut depth = 0;
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            Event::Start(element) if depth == 0 && is_wanted(&element)? => {
+                writer.write_event(Event::Start(moved(&element)?))?;
+                depth = 1;
+            }
+            Event::Start(element) if depth > 0 => {
+                writer.write_event(Event::Start(moved(&element)?))?;
+                depth += 1;
+            }
+            Event::Empty(element) if depth > 0 => {
+                writer.write_event(Event::Empty(moved(&element)?))?
+            }
+            Event::End(element) if depth > 0 => {
+                writer.write_event(Event::End(element))?;
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(String::from_utf8(writer.into_inner())?));
+                }
+            }
+            event if depth > 0 => writer.write_event(event)?,
+            _ => (),
+        }
+    }
+    Ok(None)
+}
+
+/// The first adaptation set of type `wanted` in `xml`, with its segments
+/// found under `base` and its representation given the id `id`, ready to
+/// go into another manifest.
+pub fn extract_adaptation_set(xml: &str, wanted: &str, base: &str, id: &str) -> Result<String> {
+    let is_wanted = |element: &BytesStart| -> Result<bool> {
+        Ok(element.local_name().into_inner() == "AdaptationSet"
+            && content_type(element)?.as_deref() == Some(wanted))
+    };
+    extract(xml, is_wanted, base, id)?
+        .context(format!("No {} adaptation set in the manifest", wanted))
+}
+
+/// The first representation in `xml`, moved like [`extract_adaptation_set`]
+/// moves a set.
+pub fn extract_representation(xml: &str, base: &str, id: &str) -> Result<String> {
+    let is_wanted =
+        |element: &BytesStart| Ok(element.local_name().into_inner() == "Representation");
+    extract(xml, is_wanted, base, id)?.context("No representation in the manifest")
+}
+
+/// The representation with id `from` in `xml`, moved like
+/// [`extract_adaptation_set`] moves a set.
+pub fn extract_representation_with_id(
+    xml: &str,
+    from: &str,
+    base: &str,
+    id: &str,
+) -> Result<String> {
+    let is_wanted = |element: &BytesStart| -> Result<bool> {
+        Ok(element.local_name().into_inner() == "Representation"
+            && attribute(element, "id")?.as_deref() == Some(from))
+    };
+    extract(xml, is_wanted, base, id)?
+        .context(format!("No representation {} in the manifest", from))
+}
+
+/// Put `replacement` in place of the representation with id `id`.
+pub fn replace_representation(xml: &str, id: &str, replacement: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let is_replaced = |element: &BytesStart| -> Result<bool> {
+        Ok(element.local_name().into_inner() == "Representation"
+            && attribute(element, "id")?.as_deref() == Some(id))
+    };
+    // Depth inside the representation being dropped
+    let mut skipping = 0;
+    let mut replaced = false;
+    loop {
+        let event = reader.read_event().context("Invalid MPD")?;
+        if skipping > 0 {
+            match event {
+                Event::Start(_) => skipping += 1,
+                Event::End(_) => skipping -= 1,
+                Event::Eof => break,
+                _ => (),
+            }
+            continue;
+        }
+        match event {
+            Event::Eof => break,
+            Event::Start(element) if is_replaced(&element)? => {
+                writer.get_mut().extend_from_slice(replacement.as_bytes());
+                skipping = 1;
+                replaced = true;
+            }
+            Event::Empty(element) if is_replaced(&element)? => {
+                writer.get_mut().extend_from_slice(replacement.as_bytes());
+                replaced = true;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    if !replaced {
+        bail!("No representation {:?} in the manifest", id);
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Leave the representations with the ids in `ids` out of a manifest, and
+/// any adaptation set left without representations with them.
+pub fn drop_representations(xml: &str, ids: &[String]) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let is_dropped = |element: &BytesStart| -> Result<bool> {
+        Ok(element.local_name().into_inner() == "Representation"
+            && attribute(element, "id")?.is_some_and(|id| ids.contains(&id)))
+    };
+    // The adaptation set being copied, held back until it's known whether
+    // any of its representations are kept, and how many are
+    let mut set: Option<(Writer<Vec<u8>>, usize)> = None;
+    // Depth inside the representation being dropped
+    let mut skipping = 0;
+    loop {
+        let event = reader.read_event().context("Invalid MPD")?;
+        if skipping > 0 {
+            match event {
+                Event::Start(_) => skipping += 1,
+                Event::End(_) => skipping -= 1,
+                Event::Eof => break,
+                _ => (),
+            }
+            continue;
+        }
+        match event {
+            Event::Eof => break,
+            Event::Start(element) if element.local_name().into_inner() == "AdaptationSet" => {
+                let mut held = Writer::new(Vec::new());
+                held.write_event(Event::Start(element))?;
+                set = Some((held, 0));
+            }
+            Event::End(element) if element.local_name().into_inner() == "AdaptationSet" => {
+                if let Some((mut held, kept)) = set.take()
+                    && kept > 0
+                {
+                    held.write_event(Event::End(element))?;
+                    writer.get_mut().extend_from_slice(&held.into_inner());
+                }
+            }
+            Event::Start(element) if is_dropped(&element)? => skipping = 1,
+            Event::Empty(element) if is_dropped(&element)? => (),
+            event => {
+                let counts = matches!(
+                    &event,
+                    Event::Start(element) | Event::Empty(element)
+                        if element.local_name().into_inner() == "Representation"
+                );
+                match &mut set {
+                    Some((held, kept)) => {
+                        *kept += counts as usize;
+                        held.write_event(event)?;
+                    }
+                    None => writer.write_event(event)?,
+                }
+            }
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// An adaptation set for a WebVTT subtitle file served whole.
+pub fn subtitle_adaptation_set(
+    file: &str,
+    lang: Option<&str>,
+    id: &str,
+    bandwidth: u64,
+) -> Result<String> {
+    let mut writer = Writer::new(Vec::new());
+    let mut set = BytesStart::new("AdaptationSet")
+        .with_attributes([("contentType", "text"), ("mimeType", "text/vtt")]);
+    if let Some(lang) = lang {
+        set.push_attribute(("lang", lang));
+    }
+    writer.write_event(Event::Start(set))?;
+    writer
+        .create_element("Role")
+        .with_attributes([("schemeIdUri", ROLE_SCHEME), ("value", "subtitle")])
+        .write_empty()?;
+    let bandwidth = format!("{}", bandwidth);
+    writer.write_event(Event::Start(
+        BytesStart::new("Representation")
+            .with_attributes([("id", id), ("bandwidth", bandwidth.as_str())]),
+    ))?;
+    writer
+        .create_element("BaseURL")
+        .write_text_content(BytesText::new(file))?;
+    writer.write_event(Event::End(BytesEnd::new("Representation")))?;
+    writer.write_event(Event::End(BytesEnd::new("AdaptationSet")))?;
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// DASH-IF scheme marking an image adaptation set as tiles of thumbnails
+pub const THUMBNAIL_TILE_SCHEME: &str = "http://dashif.org/thumbnail_tile";
+
+/// Trick-play thumbnails as an image adaptation set gives them: JPEG tiles
+/// of `columns` by `rows` thumbnails, each tile covering `tile_secs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThumbnailTrack {
+    pub id: String,
+    /// Template of the tiles' URLs, with `$Number$` in it
+    pub media: String,
+    pub start_number: u64,
+    pub tile_secs: f64,
+    pub columns: u32,
+    pub rows: u32,
+    /// Size of a whole tile
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The first tiled image adaptation set in a manifest, if there is one.
+pub fn thumbnail_track(xml: &str) -> Result<Option<ThumbnailTrack>> {
+    #[derive(Default)]
+    struct Pending {
+        id: Option<String>,
+        media: Option<String>,
+        start_number: Option<u64>,
+        duration: Option<u64>,
+        timescale: Option<u64>,
+        grid: Option<(u32, u32)>,
+        size: Option<(u32, u32)>,
+    }
+
+    let mut reader = Reader::from_str(xml);
+    let mut pending: Option<Pending> = None;
+    loop {
+        let element = match reader.read_event().context("Invalid MPD")? {
+            Event::Start(element) | Event::Empty(element) => element,
+            Event::End(element) if element.local_name().into_inner() == "AdaptationSet" => {
+                if let Some(Pending {
+                    id: Some(id),
+                    media: Some(media),
+                    duration: Some(duration),
+                    grid: Some((columns, rows)),
+                    size: Some((width, height)),
+                    start_number,
+                    timescale,
+                }) = pending.take()
+                {
+                    return Ok(Some(ThumbnailTrack {
+                        id,
+                        media,
+                        start_number: start_number.unwrap_or(1),
+                        tile_secs: duration as f64 / timescale.unwrap_or(1).max(1) as f64,
+                        columns,
+                        rows,
+                        width,
+                        height,
+                    }));
+                }
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+        let number = |name: &str| -> Result<Option<u64>> {
+            Ok(attribute(&element, name)?.and_then(|v| v.parse().ok()))
+        };
+        match element.local_name().into_inner() {
+            "AdaptationSet" => {
+                pending =
+                    (content_type(&element)?.as_deref() == Some("image")).then(Pending::default);
+            }
+            _ if pending.is_none() => (),
+            "SegmentTemplate" => {
+                let pending = pending.as_mut().unwrap();
+                pending.media = attribute(&element, "media")?;
+                pending.start_number = number("startNumber")?;
+                pending.duration = number("duration")?;
+                pending.timescale = number("timescale")?;
+            }
+            "Representation" => {
+                let pending = pending.as_mut().unwrap();
+                pending.id = attribute(&element, "id")?;
+                if let (Some(width), Some(height)) = (number("width")?, number("height")?) {
+                    pending.size = Some((width as u32, height as u32));
+                }
+            }
+            "EssentialProperty" | "SupplementalProperty"
+                if attribute(&element, "schemeIdUri")?.as_deref()
+                    == Some(THUMBNAIL_TILE_SCHEME) =>
+            {
+                let grid = attribute(&element, "value")?;
+                pending.as_mut().unwrap().grid = grid.as_deref().and_then(|grid| {
+                    let (columns, rows) = grid.split_once('x')?;
+                    Some((columns.parse().ok()?, rows.parse().ok()?))
+                });
+            }
+            _ => (),
+        }
+    }
+    Ok(None)
+}
+
+/// An image adaptation set for `track`, as DASH-IF players expect
+/// trick-play thumbnails.
+pub fn thumbnail_adaptation_set(track: &ThumbnailTrack, bandwidth: u64) -> Result<String> {
+    let mut writer = Writer::new(Vec::new());
+    writer.write_event(Event::Start(
+        BytesStart::new("AdaptationSet")
+            .with_attributes([("contentType", "image"), ("mimeType", "image/jpeg")]),
+    ))?;
+    let duration = format!("{}", (track.tile_secs * 1000.0).round() as u64);
+    let start_number = format!("{}", track.start_number);
+    writer
+        .create_element("SegmentTemplate")
+        .with_attributes([
+            ("media", track.media.as_str()),
+            ("timescale", "1000"),
+            ("duration", duration.as_str()),
+            ("startNumber", start_number.as_str()),
+        ])
+        .write_empty()?;
+    let (bandwidth, width, height) = (
+        format!("{}", bandwidth),
+        format!("{}", track.width),
+        format!("{}", track.height),
+    );
+    writer.write_event(Event::Start(
+        BytesStart::new("Representation").with_attributes([
+            ("id", track.id.as_str()),
+            ("bandwidth", bandwidth.as_str()),
+            ("width", width.as_str()),
+            ("height", height.as_str()),
+        ]),
+    ))?;
+    let grid = format!("{}x{}", track.columns, track.rows);
+    writer
+        .create_element("EssentialProperty")
+        .with_attributes([
+            ("schemeIdUri", THUMBNAIL_TILE_SCHEME),
+            ("value", grid.as_str()),
+        ])
+        .write_empty()?;
+    writer.write_event(Event::End(BytesEnd::new("Representation")))?;
+    writer.write_event(Event::End(BytesEnd::new("AdaptationSet")))?;
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Rewrite every `lang` in a manifest as its BCP 47 tag, like `fr` for `fre`.
+pub fn normalize_languages(xml: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let normalized = |element: &BytesStart| {
+        rewritten(element, |key, value| match key {
+            "lang" => language::normalize(value),
+            _ => None,
+        })
+    };
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            Event::Start(element) => writer.write_event(Event::Start(normalized(&element)?))?,
+            Event::Empty(element) => writer.write_event(Event::Empty(normalized(&element)?))?,
+            event => writer.write_event(event)?,
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Add `representation` to the end of the first adaptation set of type
+/// `wanted`, such as another rung of the video ladder.
+pub fn add_representation(xml: &str, wanted: &str, representation: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    // Whether the set being copied is the one added to, and whether it's done
+    let (mut inside, mut added) = (false, false);
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            Event::Start(element) if element.local_name().into_inner() == "AdaptationSet" => {
+                inside = !added && content_type(&element)?.as_deref() == Some(wanted);
+                writer.write_event(Event::Start(element))?;
+            }
+            Event::End(element)
+                if inside && element.local_name().into_inner() == "AdaptationSet" =>
+            {
+                writer
+                    .get_mut()
+                    .extend_from_slice(representation.as_bytes());
+                writer.write_event(Event::End(element))?;
+                (inside, added) = (false, true);
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    if !added {
+        bail!("No {} adaptation set in the manifest", wanted);
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Add `set` to the end of a manifest's first period.
+pub fn add_adaptation_set(xml: &str, set: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let mut added = false;
+    loop {
+        match reader.read_event().context("Invalid MPD")? {
+            Event::Eof => break,
+            Event::End(element) if element.local_name().into_inner() == "Period" && !added => {
+                writer.get_mut().extend_from_slice(set.as_bytes());
+                writer.write_event(Event::End(element))?;
+                added = true;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+    if !added {
+        bail!("The manifest has no period to add the track to");
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+        assert_eq!(parse_duration("PT0.48S"), Some(0.48));
+        assert_eq!(parse_duration("P1DT1S"), Some(86401.0));
+        assert_eq!(parse_duration("1H"), None);
+        assert_eq!(parse_duration("PT5"), None);
+    }
+
+    #[test]
+    fn summarises_representations() {
+        let manifest = parse(
+            r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" mediaPresentationDuration="PT1M30S">
+  <Period>
+    <AdaptationSet contentType="video" mimeType="video/mp4">
+      <Representation id="0" codecs="av01.0.08M.08" bandwidth="6000000" width="1920" height="1080"/>
+      <Representation id="1" codecs="av01.0.05M.08" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4" codecs="opus" lang="eng">
+      <Representation id="2" bandwidth="192000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#,
+        )
+        .unwrap();
+
+        assert_eq!(manifest.duration_secs, Some(90.0));
+        assert_eq!(manifest.representations.len(), 3);
+        assert_eq!(manifest.representations[1].height, Some(720));
+        assert_eq!(
+            manifest.representations[2],
+            Representation {
+                id: String::from("2"),
+                content_type: String::from("audio"),
+                codecs: Some(String::from("opus")),
+                bandwidth: 192000,
+                width: None,
+                height: None,
+                lang: Some(String::from("en")),
+            }
+        );
+    }
+
+    #[test]
+    fn makes_manifests_static() {
+        let xml = make_static(
+            r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="dynamic" minimumUpdatePeriod="PT4S" availabilityStartTime="2026-01-01T00:00:00Z"><Period id="0"/></MPD>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static"><Period id="0"/></MPD>"#
+        );
+    }
+
+    #[test]
+    fn replaces_skip_markers() {
+        use crate::library::MarkerKind;
+
+        let markers = [Marker {
+            kind: MarkerKind::Intro,
+            start_secs: 30.0,
+            end_secs: 75.5,
+        }];
+        let xml = set_skip_markers(
+            r#"<MPD><Period id="0"><AdaptationSet/></Period></MPD>"#,
+            &markers,
+        )
+        .unwrap();
+        assert_eq!(
+            xml,
+            r#"<MPD><Period id="0"><EventStream schemeIdUri="urn:movieshare:skip:2024" value="intro" timescale="1000"><Event id="0" presentationTime="30000" duration="45500"/></EventStream><AdaptationSet/></Period></MPD>"#
+        );
+        // Running again replaces rather than adds
+        assert_eq!(set_skip_markers(&xml, &markers).unwrap(), xml);
+        assert_eq!(
+            set_skip_markers(&xml, &[]).unwrap(),
+            r#"<MPD><Period id="0"><AdaptationSet/></Period></MPD>"#
+        );
+    }
+
+    #[test]
+    fn lists_segments() {
+        let xml = r#"<MPD><Period>
+            <AdaptationSet mimeType="video/mp4">
+                <Representation id="0" bandwidth="6000000">
+                    <SegmentList timescale="1000" duration="4000">
+                        <Initialization sourceURL="video_0_init.mp4"/>
+                        <SegmentURL media="video_0_00001.m4s"/>
+                        <SegmentURL media="video_0_00002.m4s"/>
+                    </SegmentList>
+                </Representation>
+            </AdaptationSet>
+            <AdaptationSet mimeType="audio/mp4">
+                <SegmentList timescale="48000">
+                    <SegmentTimeline><S t="0" d="192000" r="1"/><S d="96000"/></SegmentTimeline>
+                    <SegmentURL media="audio_00001.m4s"/>
+                    <SegmentURL media="audio_00002.m4s"/>
+                    <SegmentURL media="audio_00003.m4s"/>
+                </SegmentList>
+                <Representation id="1" bandwidth="192000"/>
+            </AdaptationSet>
+        </Period></MPD>"#;
+
+        let video = segment_list(xml, "0").unwrap();
+        assert_eq!(video.initialization.as_deref(), Some("video_0_init.mp4"));
+        assert_eq!(
+            video.segments[1],
+            Segment {
+                media: String::from("video_0_00002.m4s"),
+                start_secs: 4.0,
+                duration_secs: 4.0,
+            }
+        );
+
+        let audio = segment_list(xml, "1").unwrap();
+        assert_eq!(audio.initialization, None);
+        let times: Vec<(f64, f64)> = audio
+            .segments
+            .iter()
+            .map(|segment| (segment.start_secs, segment.duration_secs))
+            .collect();
+        assert_eq!(times, [(0.0, 4.0), (4.0, 4.0), (8.0, 2.0)]);
+        assert!(segment_list(xml, "2").is_err());
+    }
+
+    #[test]
+    fn joins_inputs_into_periods() {
+        let part = |dir: &str, title: &str, duration: &str| PeriodPart {
+            dir: String::from(dir),
+            title: String::from(title),
+            xml: format!(
+                r#"<?xml version="1.0"?><MPD type="static" mediaPresentationDuration="{}"><Period id="0"><AdaptationSet contentType="video"><Representation id="0" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+                duration
+            ),
+        };
+        let xml = join_periods(&[
+            part("0", "Short film", "PT10.5S"),
+            part("1", "Feature", "PT1M"),
+        ])
+        .unwrap();
+        assert_eq!(
+            xml,
+            concat!(
+                r#"<?xml version="1.0"?><MPD type="static" mediaPresentationDuration="PT70.5S">"#,
+                r#"<Period id="0" start="PT0S" duration="PT10.5S"><BaseURL>0/</BaseURL>"#,
+                r#"<SupplementalProperty schemeIdUri="urn:movieshare:period-title:2024" value="Short film"/>"#,
+                r#"<AdaptationSet contentType="video"><Representation id="0" bandwidth="1"/></AdaptationSet></Period>"#,
+                r#"<Period id="1" start="PT10.5S" duration="PT60S"><BaseURL>1/</BaseURL>"#,
+                r#"<SupplementalProperty schemeIdUri="urn:movieshare:period-title:2024" value="Feature"/>"#,
+                r#"<AdaptationSet contentType="video"><Representation id="0" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+            )
+        );
+        assert_eq!(parse(&xml).unwrap().duration_secs, Some(70.5));
+    }
+
+    #[test]
+    fn adds_tracks_to_a_manifest() {
+        let title = r#"<MPD><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="1"/></AdaptationSet><AdaptationSet contentType="audio"><Representation id="1" bandwidth="1"/></AdaptationSet></Period></MPD>"#;
+        let id = next_representation_id(&parse(title).unwrap());
+        assert_eq!(id, "2");
+
+        let track = r#"<MPD><Period><AdaptationSet mimeType="audio/mp4" codecs="opus"><Representation id="0" bandwidth="192000"><SegmentList timescale="1000" duration="4000"><Initialization sourceURL="audio_0_init.mp4"/><SegmentURL media="audio_0_00001.m4s"/></SegmentList></Representation></AdaptationSet></Period></MPD>"#;
+        let set = extract_adaptation_set(track, "audio", "audio_2/", &id).unwrap();
+        assert_eq!(
+            set,
+            r#"<AdaptationSet mimeType="audio/mp4" codecs="opus"><Representation id="2" bandwidth="192000"><SegmentList timescale="1000" duration="4000"><Initialization sourceURL="audio_2/audio_0_init.mp4"/><SegmentURL media="audio_2/audio_0_00001.m4s"/></SegmentList></Representation></AdaptationSet>"#
+        );
+        assert!(extract_adaptation_set(track, "video", "", "3").is_err());
+
+        let xml = add_adaptation_set(title, &set).unwrap();
+        let subtitles = subtitle_adaptation_set("subtitles_3.vtt", Some("fr"), "3", 80).unwrap();
+        let xml = add_adaptation_set(&xml, &subtitles).unwrap();
+        let manifest = parse(&xml).unwrap();
+        assert_eq!(manifest.representations.len(), 4);
+        assert_eq!(manifest.representations[3].content_type, "text");
+        assert_eq!(manifest.representations[3].lang.as_deref(), Some("fr"));
+        assert_eq!(
+            base_url(&xml, "3").unwrap().as_deref(),
+            Some("subtitles_3.vtt")
+        );
+        assert_eq!(base_url(&xml, "2").unwrap(), None);
+        assert_eq!(
+            segment_list(&xml, "2").unwrap().segments[0].media,
+            "audio_2/audio_0_00001.m4s"
+        );
+
+        let rung = extract_representation(track, "video_0_1/", "0").unwrap();
+        let xml = replace_representation(&xml, "0", &rung).unwrap();
+        assert_eq!(parse(&xml).unwrap().representations[0].bandwidth, 192000);
+        assert_eq!(
+            segment_list(&xml, "0").unwrap().initialization.as_deref(),
+            Some("video_0_1/audio_0_init.mp4")
+        );
+        assert!(replace_representation(&xml, "9", &rung).is_err());
+
+        let another = extract_representation(track, "video_4/", "4").unwrap();
+        let xml = add_representation(&xml, "video", &another).unwrap();
+        let manifest = parse(&xml).unwrap();
+        assert_eq!(manifest.representations[1].id, "4");
+        assert_eq!(manifest.representations[1].content_type, "video");
+        assert!(add_representation(title, "image", &another).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! Telling someone a job has finished, whether it completed, failed or was
+//! cancelled: a JSON report POSTed to a webhook, or a desktop notification.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize)]
+pub struct JobStats {
+    pub frames: u64,
+    pub average_fps: f64,
+    pub bytes_written: u64,
+}
+
+/// Summary of a finished job, used for completion notifications.
+#[derive(Serialize)]
+pub struct JobReport {
+    pub input: String,
+    pub output: String,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_secs: f64,
+    pub stats: JobStats,
+}
+
+/// POST the report as JSON to a webhook URL.
+pub fn send_webhook(url: &str, report: &JobReport) -> Result<()> {
+    ureq::post(url)
+        .send_json(report)
+        .context(format!("Failed to notify webhook: {}", url))?;
+    Ok(())
+}
+
+/// Show a freedesktop notification summarizing the report.
+pub fn send_desktop(report: &JobReport) -> Result<()> {
+    let (summary, body) = match report.status {
+        JobStatus::Completed => (
+            "Preparation complete",
+            format!(
+                "{} finished in {}",
+                report.input,
+                format_duration(report.duration_secs)
+            ),
+        ),
+        JobStatus::Failed => (
+            "Preparation failed",
+            format!(
+                "{}: {}",
+                report.input,
+                report.error.as_deref().unwrap_or("unknown error")
+            ),
+        ),
+        JobStatus::Cancelled => (
+            "Preparation cancelled",
+            format!(
+                "{} cancelled after {}",
+                report.input,
+                format_duration(report.duration_secs)
+            ),
+        ),
+    };
+
+    notify_rust::Notification::new()
+        .appname("movieshare")
+        .summary(summary)
+        .body(&body)
+        .show()
+        .context("Failed to show desktop notification")?;
+    Ok(())
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+    format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Total size of all files below `dir`.
+pub fn directory_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
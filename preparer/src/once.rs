@@ -0,0 +1,359 @@
+//! Share links good for a single viewing.
+//!
+//! The first browser to open a link from `preparer share --once` claims it
+//! with a cookie, and every other browser is turned away. The link stops
+//! working once that viewing is over, either because it reached the end of
+//! the title or because the viewer went quiet. Segments are handed out no
+//! faster than the title plays, with a little lead for buffering, so the
+//! link can't be used to pull down the whole title in one go. Nothing the
+//! manifest doesn't list is handed out at all, like the fallback MP4.
+//!
+//! Links are listed in the library as soon as they're claimed, so a restart
+//! ends a viewing under way rather than letting another browser claim it.
+
+use crate::mpd;
+use anyhow::{Context, Result};
+use axum::http::{HeaderMap, StatusCode, header};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const SPENT_FILENAME: &str = ".movieshare-spent-shares";
+const COOKIE: &str = "movieshare_once";
+/// How far past the playing position a viewer may fetch
+const LEAD: Duration = Duration::from_secs(60);
+/// A viewing is over once its viewer has fetched nothing for this long
+const IDLE_LIMIT: Duration = Duration::from_secs(30 * 60);
+/// How long the last segments stay available once the first is fetched
+const END_GRACE: Duration = Duration::from_secs(60);
+
+/// Start and end of each media segment of a title, by file name
+type Segments = HashMap<String, (f64, f64)>;
+
+struct Viewing {
+    session: String,
+    started: Instant,
+    last_seen: Instant,
+    ending: Option<Instant>,
+    segments: Segments,
+    /// The manifest, init segments and sidecars, which aren't paced
+    unpaced: HashSet<String>,
+    duration_secs: f64,
+}
+
+impl Viewing {
+    fn is_over(&self, now: Instant) -> bool {
+        now.duration_since(self.last_seen) > IDLE_LIMIT
+            || self
+                .ending
+                .is_some_and(|ending| now.duration_since(ending) > END_GRACE)
+    }
+}
+
+#[derive(Default)]
+struct Shares {
+    viewings: HashMap<String, Viewing>,
+    spent: HashSet<String>,
+}
+
+impl Shares {
+    /// Forget the viewing of link `id`, which `claim` already recorded.
+    fn spend(&mut self, id: &str) {
+        self.viewings.remove(id);
+        self.spent.insert(id.to_string());
+    }
+}
+
+/// Single-viewing share links being watched, and those already spent or
+/// claimed before the last restart.
+#[derive(Clone)]
+pub(crate) struct OnceShares {
+    path: PathBuf,
+    shares: Arc<Mutex<Shares>>,
+}
+
+impl OnceShares {
+    /// Load the links already claimed in `library`, all of them now spent.
+    pub(crate) fn load(library: &Path) -> Result<Self> {
+        let path = library.join(SPENT_FILENAME);
+        let spent = match std::fs::read_to_string(&path) {
+            Ok(text) => text.lines().map(String::from).collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => return Err(err).context(format!("Failed to read {}", path.display())),
+        };
+        Ok(Self {
+            path,
+            shares: Arc::new(Mutex::new(Shares {
+                viewings: HashMap::new(),
+                spent,
+            })),
+        })
+    }
+
+    /// Let the browser opening link `id` watch its title, returning the
+    /// cookie to claim it with if this is the first to. `manifest` reads the
+    /// title's manifest, decrypted if need be, and is only called then.
+    pub(crate) fn claim(
+        &self,
+        id: &str,
+        headers: &HeaderMap,
+        manifest: impl FnOnce() -> Result<String>,
+    ) -> Result<Option<String>, StatusCode> {
+        self.claim_at(id, manifest, cookie(headers), Instant::now())
+    }
+
+    fn claim_at(
+        &self,
+        id: &str,
+        manifest: impl FnOnce() -> Result<String>,
+        cookie: Option<&str>,
+        now: Instant,
+    ) -> Result<Option<String>, StatusCode> {
+        let mut shares = self.shares.lock().unwrap();
+        if shares.spent.contains(id) {
+            return Err(StatusCode::GONE);
+        }
+        if let Some(viewing) = shares.viewings.get(id) {
+            if viewing.is_over(now) {
+                shares.spend(id);
+                return Err(StatusCode::GONE);
+            }
+            return match cookie == Some(viewing.session.as_str()) {
+                true => Ok(None),
+                false => Err(StatusCode::FORBIDDEN),
+            };
+        }
+
+        let (segments, unpaced, duration_secs) =
+            manifest().and_then(|xml| segments(&xml)).map_err(|err| {
+                eprintln!("Failed to read the segments of a shared title: {:#}", err);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        // Recorded before it's handed out, so a restart can't let a second
+        // browser in
+        self.record(id).map_err(|err| {
+            eprintln!("{:#}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let session = format!("{:032x}", rand::random::<u128>());
+        shares.viewings.insert(
+            id.to_string(),
+            Viewing {
+                session: session.clone(),
+                started: now,
+                last_seen: now,
+                ending: None,
+                segments,
+                unpaced,
+                duration_secs,
+            },
+        );
+        Ok(Some(session))
+    }
+
+    /// Whether the viewer of link `id` may fetch `file` yet.
+    pub(crate) fn check(
+        &self,
+        id: &str,
+        file: &str,
+        headers: &HeaderMap,
+    ) -> Result<(), StatusCode> {
+        self.check_at(id, file, cookie(headers), Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        id: &str,
+        file: &str,
+        cookie: Option<&str>,
+        now: Instant,
+    ) -> Result<(), StatusCode> {
+        let mut shares = self.shares.lock().unwrap();
+        if shares.spent.contains(id) {
+            return Err(StatusCode::GONE);
+        }
+        let Some(viewing) = shares.viewings.get_mut(id) else {
+            // The player page has to be opened first
+            return Err(StatusCode::FORBIDDEN);
+        };
+        if cookie != Some(viewing.session.as_str()) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        if viewing.is_over(now) {
+            shares.spend(id);
+            return Err(StatusCode::GONE);
+        }
+
+        viewing.last_seen = now;
+        let Some(&(start, end)) = viewing.segments.get(file) else {
+            return match viewing.unpaced.contains(file) {
+                true => Ok(()),
+                false => Err(StatusCode::NOT_FOUND),
+            };
+        };
+        let playing = now.duration_since(viewing.started) + LEAD;
+        if start > playing.as_secs_f64() {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        if end >= viewing.duration_secs {
+            viewing.ending.get_or_insert(now);
+        }
+        Ok(())
+    }
+
+    /// List link `id` in the library as claimed.
+    fn record(&self, id: &str) -> Result<()> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", id))
+            .context(format!(
+                "Failed to record a claimed share link in {}",
+                self.path.display()
+            ))
+    }
+}
+
+/// The cookie value `claim` set, if the request carries one.
+fn cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(COOKIE)?.strip_prefix('='))
+}
+
+/// A `Set-Cookie` value claiming the link whose files are under `path`.
+pub(crate) fn set_cookie(session: &str, path: &str) -> String {
+    format!(
+        "{}={}; Path={}; HttpOnly; SameSite=Strict",
+        COOKIE, session, path
+    )
+}
+
+/// Times of every media segment of the title with manifest `xml`, the other
+/// files it lists, and its length.
+fn segments(xml: &str) -> Result<(Segments, HashSet<String>, f64)> {
+    let manifest = mpd::parse(xml)?;
+    let mut segments = HashMap::new();
+    let mut unpaced = HashSet::from([crate::library::MANIFEST.to_string()]);
+    for representation in &manifest.representations {
+        let Ok(list) = mpd::segment_list(xml, &representation.id) else {
+            // Subtitles come whole, but a video or audio file given whole
+            // would be the title in one go
+            if representation.content_type == "text"
+                && let Ok(Some(base_url)) = mpd::base_url(xml, &representation.id)
+            {
+                unpaced.insert(base_url);
+            }
+            continue;
+        };
+        unpaced.extend(list.initialization);
+        // Trick-play thumbnails aren't part of the viewing
+        if representation.content_type == "image" {
+            unpaced.extend(list.segments.into_iter().map(|segment| segment.media));
+            continue;
+        }
+        for segment in list.segments {
+            let end = segment.start_secs + segment.duration_secs;
+            segments.insert(segment.media, (segment.start_secs, end));
+        }
+    }
+    let duration_secs = manifest
+        .duration_secs
+        .unwrap_or_else(|| segments.values().map(|&(_, end)| end).fold(0.0, f64::max));
+    Ok((segments, unpaced, duration_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_viewer_at_the_pace_of_playback() {
+        let dir = std::env::temp_dir().join(format!("movieshare-once-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let segments: String = (1..=60)
+            .map(|i| format!("<SegmentURL media=\"video_{:05}.m4s\"/>", i))
+            .collect();
+        let xml = format!(
+            r#"<MPD mediaPresentationDuration="PT4M"><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="1000000"><SegmentList timescale="1000" duration="4000">{}</SegmentList></Representation></AdaptationSet></Period></MPD>"#,
+            segments
+        );
+        let manifest = || anyhow::Ok(xml.clone());
+
+        let shares = OnceShares::load(&dir).unwrap();
+        let start = Instant::now();
+        let session = shares
+            .claim_at("a", manifest, None, start)
+            .unwrap()
+            .unwrap();
+        let cookie = Some(session.as_str());
+        // Nobody else gets in, and the viewer doesn't need a new cookie
+        assert_eq!(
+            shares.claim_at("a", manifest, None, start),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(shares.claim_at("a", manifest, cookie, start), Ok(None));
+        assert_eq!(
+            shares.check_at("a", "video_00001.m4s", None, start),
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        // Only what the manifest lists, and not the title whole
+        assert_eq!(
+            shares.check_at("a", crate::library::MANIFEST, cookie, start),
+            Ok(())
+        );
+        assert_eq!(
+            shares.check_at("a", crate::library::FALLBACK, cookie, start),
+            Err(StatusCode::NOT_FOUND)
+        );
+
+        // A minute ahead of playback, but no further
+        assert_eq!(
+            shares.check_at("a", "video_00015.m4s", cookie, start),
+            Ok(())
+        );
+        assert_eq!(
+            shares.check_at("a", "video_00020.m4s", cookie, start),
+            Err(StatusCode::TOO_MANY_REQUESTS)
+        );
+        let later = start + Duration::from_secs(200);
+        assert_eq!(
+            shares.check_at("a", "video_00060.m4s", cookie, later),
+            Ok(())
+        );
+
+        // Spent once the end has gone by
+        let after = later + END_GRACE + Duration::from_secs(1);
+        assert_eq!(
+            shares.check_at("a", "video_00060.m4s", cookie, after),
+            Err(StatusCode::GONE)
+        );
+        assert_eq!(
+            shares.claim_at("a", manifest, cookie, after),
+            Err(StatusCode::GONE)
+        );
+
+        // A restart mid-viewing ends it rather than freeing the link
+        let session = shares
+            .claim_at("b", manifest, None, start)
+            .unwrap()
+            .unwrap();
+        let reloaded = OnceShares::load(&dir).unwrap();
+        assert_eq!(
+            reloaded.claim_at("b", manifest, None, start),
+            Err(StatusCode::GONE)
+        );
+        assert_eq!(
+            reloaded.check_at("b", "video_00001.m4s", Some(session.as_str()), start),
+            Err(StatusCode::GONE)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
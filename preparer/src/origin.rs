@@ -0,0 +1,133 @@
+//! Uploading a run's output as it is written, to any [`Storage`]: S3, an
+//! HTTP origin taking PUTs, WebDAV or SFTP.
+//!
+//! Segments go up as the journal announces them. For a live push, where a
+//! copy is also kept locally, the init segments and the dynamic manifest
+//! follow each round of segments, so the title can be watched from the
+//! origin while it's still being prepared. Whatever is left goes up once
+//! the run finishes, with the manifest last so it never names missing
+//! files.
+
+use crate::storage::Storage;
+use anyhow::{Context, Result, anyhow};
+use movieshare_core::journal::{self, Journal, JournalEvent};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const MANIFEST_EXTENSION: &str = "mpd";
+/// Suffix dashsink gives each representation's init segment
+const INIT_SUFFIX: &str = "_init.mp4";
+
+/// Uploads a run's output as it is written, following its journal.
+pub struct Uploader {
+    done: Arc<AtomicBool>,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl Uploader {
+    /// Upload `output` to `storage` as it's written. With `live`, keep the
+    /// manifest there up to date as well.
+    pub fn spawn(storage: Box<dyn Storage>, output: PathBuf, live: bool) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        let finished = done.clone();
+        let thread = std::thread::spawn(move || {
+            let upload = |path: &Path| {
+                let relative = path.strip_prefix(&output).unwrap_or(path);
+                let name = relative.to_string_lossy();
+                storage.put(&name, path).context(format!(
+                    "Failed to upload {} to {}",
+                    name,
+                    storage.url()
+                ))
+            };
+            let mut uploaded = HashSet::new();
+            while !finished.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_secs(1));
+                let mut written = false;
+                let entries = Journal::read(&output)?;
+                for entry in journal::last_run(&entries) {
+                    if let JournalEvent::SegmentWritten { location: segment } = &entry.event {
+                        let path = PathBuf::from(segment);
+                        if uploaded.insert(path.clone()) {
+                            upload(&path)?;
+                            written = true;
+                        }
+                    }
+                }
+                if !live || !written {
+                    continue;
+                }
+                // Init segments are written before any segment that needs them
+                for path in remaining(&output, &uploaded)? {
+                    if is_init_segment(&path) {
+                        upload(&path)?;
+                        uploaded.insert(path);
+                    }
+                }
+                let manifests = remaining(&output, &uploaded)?;
+                for path in manifests.iter().filter(|path| is_manifest(path)) {
+                    upload(path)?;
+                }
+            }
+
+            // Whatever wasn't announced as a segment (init segments and the
+            // like), with the manifest last so it never points at missing files
+            let mut rest = remaining(&output, &uploaded)?;
+            rest.sort_by_key(|path| is_manifest(path));
+            for path in rest {
+                upload(&path)?;
+            }
+            Ok(())
+        });
+        Self { done, thread }
+    }
+
+    /// Upload what's left once the run has finished.
+    pub fn finish(self) -> Result<()> {
+        self.done.store(true, Ordering::Relaxed);
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("Upload thread panicked"))?
+    }
+}
+
+fn is_manifest(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == MANIFEST_EXTENSION)
+}
+
+fn is_init_segment(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(INIT_SUFFIX))
+}
+
+/// Files in `output` not yet `uploaded`, leaving out hidden ones like the
+/// journal.
+fn remaining(output: &Path, uploaded: &HashSet<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut rest = Vec::new();
+    for entry in
+        std::fs::read_dir(output).context(format!("Failed to read {}", output.display()))?
+    {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_file() && !name.starts_with('.') && !uploaded.contains(&path) {
+            rest.push(path);
+        }
+    }
+    Ok(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_manifests_and_init_segments_apart() {
+        assert!(is_init_segment(Path::new("out/video_0_init.mp4")));
+        assert!(!is_init_segment(Path::new("out/video_0_00001.m4s")));
+        assert!(is_manifest(Path::new("out/manifest.mpd")));
+    }
+}
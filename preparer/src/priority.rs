@@ -0,0 +1,147 @@
+//! CPU and I/O priority for encoding, so preparing in the background doesn't
+//! make playback on the same machine stutter.
+//!
+//! Linux keeps nice values and I/O priorities per thread, so they're set on
+//! every thread of the process; threads started later, like GStreamer's
+//! streaming threads, inherit them.
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::path::PathBuf;
+
+/// `ioprio_set` target meaning a single thread.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+/// An I/O scheduling class, as `ionice` names them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IoPriority {
+    /// Only when nobody else wants the disk
+    Idle,
+    /// Shared with everyone else, at a level from 0 (highest) to 7
+    BestEffort(u8),
+}
+
+impl IoPriority {
+    fn value(self) -> libc::c_int {
+        let (class, level) = match self {
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        (class << IOPRIO_CLASS_SHIFT | level as u32) as libc::c_int
+    }
+}
+
+/// Parse `idle`, `best-effort` or `best-effort:N`.
+pub fn parse_ionice(priority: &str) -> Result<IoPriority> {
+    let (class, level) = match priority.split_once(':') {
+        Some((class, level)) => (class, Some(level)),
+        None => (priority, None),
+    };
+    match (class, level) {
+        ("idle", None) => Ok(IoPriority::Idle),
+        ("best-effort", None) => Ok(IoPriority::BestEffort(7)),
+        ("best-effort", Some(level)) => match level.parse() {
+            Ok(level) if level <= 7 => Ok(IoPriority::BestEffort(level)),
+            _ => bail!("Best-effort I/O priority must be from 0 to 7"),
+        },
+        _ => bail!(
+            "Unknown I/O priority {:?}; expected idle, best-effort or best-effort:N",
+            priority
+        ),
+    }
+}
+
+/// Check a nice value given on the command line.
+pub fn parse_nice(nice: &str) -> Result<i32> {
+    match nice.parse() {
+        Ok(nice) if (-20..=19).contains(&nice) => Ok(nice),
+        _ => bail!("Nice value must be from -20 to 19"),
+    }
+}
+
+/// IDs of the process's threads.
+fn threads() -> Result<Vec<libc::id_t>> {
+    let mut threads = Vec::new();
+    for entry in std::fs::read_dir("/proc/self/task").context("Failed to list threads")? {
+        if let Ok(id) = entry?.file_name().to_string_lossy().parse() {
+            threads.push(id);
+        }
+    }
+    Ok(threads)
+}
+
+/// Give every thread of the process `nice` and `io` priority.
+pub fn apply(nice: Option<i32>, io: Option<IoPriority>) -> Result<()> {
+    if nice.is_none() && io.is_none() {
+        return Ok(());
+    }
+    for thread in threads()? {
+        if let Some(nice) = nice
+            && unsafe { libc::setpriority(libc::PRIO_PROCESS, thread, nice) } != 0
+        {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("Failed to set nice value {}", nice));
+        }
+        if let Some(io) = io
+            && unsafe {
+                libc::syscall(
+                    libc::SYS_ioprio_set,
+                    IOPRIO_WHO_PROCESS,
+                    thread as libc::c_int,
+                    io.value(),
+                )
+            } != 0
+        {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("Failed to set I/O priority {:?}", io));
+        }
+    }
+    Ok(())
+}
+
+/// The cgroup v2 directory the process runs in.
+fn own_cgroup() -> Result<PathBuf> {
+    let cgroups =
+        std::fs::read_to_string("/proc/self/cgroup").context("Failed to read /proc/self/cgroup")?;
+    let path = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| anyhow!("Not running under cgroup v2"))?;
+    Ok(PathBuf::from("/sys/fs/cgroup").join(path.trim_start_matches('/')))
+}
+
+/// Set the CPU weight of the process's cgroup, from 1 to 10000 with 100 as
+/// the default. Needs the cgroup delegated to us, as systemd does for
+/// services with `Delegate=yes`.
+pub fn set_cpu_weight(weight: u32) -> Result<()> {
+    if !(1..=10000).contains(&weight) {
+        bail!("CPU weight must be from 1 to 10000");
+    }
+    let path = own_cgroup()?.join("cpu.weight");
+    std::fs::write(&path, weight.to_string()).context(format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_priorities() {
+        assert_eq!(parse_ionice("idle").unwrap(), IoPriority::Idle);
+        assert_eq!(
+            parse_ionice("best-effort").unwrap(),
+            IoPriority::BestEffort(7)
+        );
+        assert_eq!(
+            parse_ionice("best-effort:3").unwrap(),
+            IoPriority::BestEffort(3)
+        );
+        assert!(parse_ionice("best-effort:8").is_err());
+        assert!(parse_ionice("realtime").is_err());
+        assert_eq!(IoPriority::Idle.value(), 3 << 13);
+        assert_eq!(IoPriority::BestEffort(4).value(), 2 << 13 | 4);
+
+        assert_eq!(parse_nice("10").unwrap(), 10);
+        assert!(parse_nice("20").is_err());
+    }
+}
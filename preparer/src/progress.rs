@@ -0,0 +1,187 @@
+//! Where each viewer left off in each title, so they can resume elsewhere.
+//!
+//! The player page saves its position to `PUT /progress/<title>` as it
+//! plays and asks `GET /progress/<title>` where to start. `GET /progress`
+//! lists everything the viewer has started, most recent first. Without
+//! sign-in every viewer shares one set of positions.
+
+use crate::auth;
+use crate::library::Catalog;
+use crate::serve::{AppState, is_title};
+use crate::users::User;
+use anyhow::Result;
+use axum::Extension;
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use rusqlite::{OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub title: String,
+    pub position_secs: f64,
+    pub duration_secs: f64,
+    /// Seconds since the Unix epoch
+    pub updated_at: u64,
+}
+
+impl Catalog {
+    pub fn save_progress(
+        &mut self,
+        user: &str,
+        title: &str,
+        position_secs: f64,
+        duration_secs: f64,
+    ) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.conn.execute(
+            "INSERT INTO progress (user, title, position_secs, duration_secs, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (user, title) DO UPDATE SET position_secs = ?3,
+                duration_secs = ?4, updated_at = ?5",
+            params![user, title, position_secs, duration_secs, now as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Everything `user` has started watching, most recent first.
+    pub fn progress(&self, user: &str) -> Result<Vec<Progress>> {
+        let mut statement = self.conn.prepare(
+            "SELECT title, position_secs, duration_secs, updated_at FROM progress
+             WHERE user = ?1 ORDER BY updated_at DESC, title",
+        )?;
+        let progress = statement
+            .query_map(params![user], |row| {
+                Ok(Progress {
+                    title: row.get(0)?,
+                    position_secs: row.get(1)?,
+                    duration_secs: row.get(2)?,
+                    updated_at: row.get::<_, i64>(3)? as u64,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(progress)
+    }
+
+    pub fn title_progress(&self, user: &str, title: &str) -> Result<Option<Progress>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT position_secs, duration_secs, updated_at FROM progress
+                 WHERE user = ?1 AND title = ?2",
+                params![user, title],
+                |row| {
+                    Ok(Progress {
+                        title: title.to_string(),
+                        position_secs: row.get(0)?,
+                        duration_secs: row.get(1)?,
+                        updated_at: row.get::<_, i64>(2)? as u64,
+                    })
+                },
+            )
+            .optional()?)
+    }
+}
+
+fn user_name(user: &Option<Extension<User>>) -> &str {
+    user.as_ref().map_or("", |user| user.name.as_str())
+}
+
+fn catalog(state: &AppState) -> Result<Catalog, StatusCode> {
+    Catalog::open(&Catalog::default_path(&state.library)).map_err(|err| {
+        eprintln!("{:#}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+pub async fn list(
+    State(state): State<AppState>,
+    user: Option<Extension<User>>,
+) -> Result<Json<Vec<Progress>>, StatusCode> {
+    let progress = catalog(&state)?
+        .progress(user_name(&user))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Grants may have been taken away since
+    let progress = progress
+        .into_iter()
+        .filter(|p| auth::may_watch(&state, user.as_deref(), &p.title))
+        .collect();
+    Ok(Json(progress))
+}
+
+pub async fn get(
+    State(state): State<AppState>,
+    user: Option<Extension<User>>,
+    Path(title): Path<String>,
+) -> Result<Json<Progress>, StatusCode> {
+    if !auth::may_watch(&state, user.as_deref(), &title) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    catalog(&state)?
+        .title_progress(user_name(&user), &title)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize)]
+pub struct SaveProgress {
+    position_secs: f64,
+    duration_secs: f64,
+}
+
+pub async fn save(
+    State(state): State<AppState>,
+    user: Option<Extension<User>>,
+    Path(title): Path<String>,
+    Json(request): Json<SaveProgress>,
+) -> StatusCode {
+    let valid = |secs: f64| secs.is_finite() && secs >= 0.0;
+    if !valid(request.position_secs) || !valid(request.duration_secs) {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    if !is_title(&state.library, &title) || !auth::may_watch(&state, user.as_deref(), &title) {
+        return StatusCode::NOT_FOUND;
+    }
+
+    let saved = catalog(&state).map(|mut catalog| {
+        catalog.save_progress(
+            user_name(&user),
+            &title,
+            request.position_secs,
+            request.duration_secs,
+        )
+    });
+    match saved {
+        Ok(Ok(())) => StatusCode::NO_CONTENT,
+        Ok(Err(err)) => {
+            eprintln!("Failed to save progress: {:#}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        Err(status) => status,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_latest_position_per_user() {
+        let mut catalog = Catalog::open(std::path::Path::new(":memory:")).unwrap();
+        catalog
+            .save_progress("alice", "Heat", 60.0, 6000.0)
+            .unwrap();
+        catalog
+            .save_progress("alice", "Heat", 120.0, 6000.0)
+            .unwrap();
+        catalog.save_progress("bob", "Heat", 5.0, 6000.0).unwrap();
+
+        let progress = catalog.title_progress("alice", "Heat").unwrap().unwrap();
+        assert_eq!(progress.position_secs, 120.0);
+        assert_eq!(catalog.progress("alice").unwrap().len(), 1);
+        assert!(catalog.title_progress("carol", "Heat").unwrap().is_none());
+    }
+}
@@ -0,0 +1,98 @@
+//! Pushing a finished title to a remote server with rsync over SSH.
+
+use crate::library::MANIFEST;
+use crate::mpd;
+use anyhow::{Context, Result, bail};
+use std::path::Path;
+use std::process::Command;
+
+/// Check that a title is complete enough to publish.
+pub fn validate(dir: &Path) -> Result<()> {
+    let path = dir.join(MANIFEST);
+    let xml =
+        std::fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+    let manifest = mpd::parse(&xml).context(format!("Invalid manifest: {}", path.display()))?;
+    if !manifest
+        .representations
+        .iter()
+        .any(|representation| representation.content_type == "video")
+    {
+        bail!("{} has no video representations", path.display());
+    }
+    if manifest
+        .duration_secs
+        .is_none_or(|duration| duration <= 0.0)
+    {
+        bail!("{} has no duration", path.display());
+    }
+    Ok(())
+}
+
+/// rsync arguments for copying `dir` into `destination` (`user@host:/path`).
+///
+/// Partial files are kept so an interrupted push picks up where it left off.
+fn rsync_args(dir: &Path, destination: &str, bwlimit_kbps: Option<u32>) -> Vec<String> {
+    let mut args = vec![
+        String::from("--archive"),
+        String::from("--compress"),
+        String::from("--partial"),
+        String::from("--human-readable"),
+        String::from("--info=progress2"),
+        String::from("--exclude=.*"),
+        String::from("--rsh=ssh"),
+    ];
+    if let Some(limit) = bwlimit_kbps {
+        args.push(format!("--bwlimit={}", limit));
+    }
+    // The trailing slash copies the directory's contents rather than the directory
+    args.push(format!("{}/", dir.display()));
+    args.push(destination.to_string());
+    args
+}
+
+/// Validate `dir` and copy it to `destination`.
+pub fn push(dir: &Path, destination: &str, bwlimit_kbps: Option<u32>) -> Result<()> {
+    if !destination.contains(':') {
+        bail!("Push destination must look like user@host:/path");
+    }
+    validate(dir)?;
+    let status = Command::new("rsync")
+        .args(rsync_args(dir, destination, bwlimit_kbps))
+        .status()
+        .context("Failed to run rsync")?;
+    if !status.success() {
+        bail!("rsync to {} failed: {}", destination, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_before_pushing() {
+        let dir = std::env::temp_dir().join(format!("movieshare-push-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(validate(&dir).is_err());
+
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD mediaPresentationDuration="PT10S"><Period><AdaptationSet contentType="audio"><Representation id="0" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+        )
+        .unwrap();
+        assert!(validate(&dir).is_err());
+
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD mediaPresentationDuration="PT10S"><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+        )
+        .unwrap();
+        validate(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let args = rsync_args(Path::new("/out"), "me@vps:/srv/Heat", Some(500));
+        assert!(args.contains(&String::from("--bwlimit=500")));
+        assert_eq!(&args[args.len() - 2..], ["/out/", "me@vps:/srv/Heat"]);
+    }
+}
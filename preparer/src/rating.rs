@@ -0,0 +1,82 @@
+//! Content ratings, for keeping titles from viewers too young for them.
+//!
+//! Ratings are kept as the certification string the title came with, like
+//! `PG-13` or `TV-MA`, and compared by the minimum age each implies.
+
+use anyhow::{Result, bail};
+
+/// Certifications and the youngest age each is meant for. US film and TV
+/// ratings, plus the UK's letter ratings; ages like `12` or `FSK 16` are
+/// read as numbers.
+const AGES: &[(&str, u32)] = &[
+    ("G", 0),
+    ("PG", 8),
+    ("PG-13", 13),
+    ("R", 17),
+    ("NC-17", 18),
+    ("TV-Y", 0),
+    ("TV-Y7", 7),
+    ("TV-G", 0),
+    ("TV-PG", 8),
+    ("TV-14", 14),
+    ("TV-MA", 17),
+    ("U", 0),
+    ("12A", 12),
+];
+
+/// The youngest age `rating` is meant for, if it's one we understand.
+pub fn min_age(rating: &str) -> Option<u32> {
+    let rating = rating.trim();
+    if let Some((_, age)) = AGES
+        .iter()
+        .find(|(certification, _)| certification.eq_ignore_ascii_case(rating))
+    {
+        return Some(*age);
+    }
+    // Plain ages, with or without a board's name in front
+    let digits = rating.trim_start_matches(|c: char| !c.is_ascii_digit());
+    match digits.is_empty() {
+        true => None,
+        false => digits.parse().ok(),
+    }
+}
+
+/// Check a rating given on the command line.
+pub fn parse(rating: &str) -> Result<String> {
+    if min_age(rating).is_none() {
+        bail!(
+            "Unknown content rating {:?}; expected one like PG-13, TV-14 or an age",
+            rating
+        );
+    }
+    Ok(rating.trim().to_string())
+}
+
+/// Whether a title rated `rating` is within the limit `max`. Titles without
+/// a rating we understand are not, since nothing says they're suitable.
+pub fn allows(max: &str, rating: Option<&str>) -> bool {
+    match (min_age(max), rating.and_then(min_age)) {
+        (Some(max), Some(age)) => age <= max,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_ratings_by_age() {
+        assert_eq!(min_age("pg-13"), Some(13));
+        assert_eq!(min_age("FSK 16"), Some(16));
+        assert_eq!(min_age("15"), Some(15));
+        assert_eq!(min_age("Unrated"), None);
+
+        assert!(allows("PG", Some("G")));
+        assert!(allows("PG-13", Some("TV-PG")));
+        assert!(!allows("PG", Some("R")));
+        assert!(!allows("PG", None));
+        assert!(!allows("PG", Some("NR")));
+        assert!(parse("X-rated").is_err());
+    }
+}
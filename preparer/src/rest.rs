@@ -0,0 +1,267 @@
+//! HTTP JSON front end to the job queue, for web frontends that can't speak gRPC.
+//!
+//! - `POST /jobs` submits a job and answers with its id
+//! - `GET /jobs/{id}` reports a job's state and progress
+//! - `DELETE /jobs/{id}` cancels a job
+//! - `GET /jobs/{id}/events` streams the job's events as server-sent events,
+//!   in the format of [`movieshare_core::events`]
+//! - `POST /uploads` accepts media to prepare into a library, when one is
+//!   given, and `/uploads/sessions` the same in resumable pieces; see
+//!   [`crate::upload`]
+//! - `/admin/` is a web UI for the queue and a library, when one is given;
+//!   see [`crate::admin`]
+//!
+//! Once uploads or the admin UI are on, which sign in as the library's
+//! users, the job routes need HTTP Basic auth as an unrestricted user too,
+//! as jobs read and write anywhere the daemon can. Until then, the API only
+//! listens on loopback addresses; see [`check_listener`].
+
+use crate::{admin, upload};
+use anyhow::{Context, Result, bail};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::{Stream, StreamExt, future};
+use movieshare_core::events;
+use movieshare_core::queue::{JobId, JobQueue, QueuedJob};
+use movieshare_core::{EncodingProfile, JobSpec, Progress};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubmitRequest {
+    input: PathBuf,
+    output: PathBuf,
+    #[serde(default)]
+    profile: EncodingProfile,
+    /// Higher runs first
+    #[serde(default)]
+    priority: i32,
+}
+
+#[derive(Serialize)]
+struct SubmitResponse {
+    id: JobId,
+}
+
+/// A job as reported to clients, including its live progress.
+#[derive(Serialize, Deserialize)]
+pub struct JobView {
+    #[serde(flatten)]
+    pub job: QueuedJob,
+    pub progress: Option<Progress>,
+}
+
+impl From<QueuedJob> for JobView {
+    fn from(job: QueuedJob) -> Self {
+        Self {
+            progress: job.progress.clone(),
+            job,
+        }
+    }
+}
+
+/// An error answered as `{"error": "..."}` with the given status.
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+fn find_job(queue: &JobQueue, id: JobId) -> Result<QueuedJob, ApiError> {
+    queue
+        .get(id)
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("No such job: {}", id)))
+}
+
+async fn submit_job(
+    State(queue): State<JobQueue>,
+    Json(request): Json<SubmitRequest>,
+) -> Result<(StatusCode, Json<SubmitResponse>), ApiError> {
+    let mut spec = JobSpec::new(request.input, request.output);
+    spec.profile = request.profile;
+    let id = queue
+        .submit(spec, request.priority)
+        .map_err(|err| ApiError(StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", err)))?;
+    Ok((StatusCode::CREATED, Json(SubmitResponse { id })))
+}
+
+async fn get_job(
+    State(queue): State<JobQueue>,
+    Path(id): Path<JobId>,
+) -> Result<Json<JobView>, ApiError> {
+    Ok(Json(find_job(&queue, id)?.into()))
+}
+
+async fn cancel_job(
+    State(queue): State<JobQueue>,
+    Path(id): Path<JobId>,
+) -> Result<StatusCode, ApiError> {
+    find_job(&queue, id)?;
+    queue
+        .cancel(id)
+        .map_err(|err| ApiError(StatusCode::CONFLICT, format!("{:#}", err)))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn job_events(
+    State(queue): State<JobQueue>,
+    Path(id): Path<JobId>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    find_job(&queue, id)?;
+
+    // Each SSE event is named after the event type and carries the full event as data
+    let events = queue.subscribe().filter_map(move |event| {
+        let event = events::Event::from_queue_event(&event);
+        let sse = if event.job_id == Some(id) {
+            Event::default()
+                .event(event.body.kind())
+                .json_data(&event)
+                .ok()
+        } else {
+            None
+        };
+        future::ready(sse.map(Ok))
+    });
+    Ok(Sse::new(events).keep_alive(Default::default()))
+}
+
+/// Libraries the HTTP API works with, besides the queue itself.
+#[derive(Default)]
+pub struct Libraries {
+    /// Take uploads into this one
+    pub uploads: Option<PathBuf>,
+    /// Serve the admin UI for this one
+    pub admin: Option<PathBuf>,
+}
+
+impl Libraries {
+    /// Whether the job routes need signing in.
+    pub fn signs_in(&self) -> bool {
+        self.uploads.is_some() || self.admin.is_some()
+    }
+}
+
+/// Refuse to take jobs on `addr` from other machines unless they have to
+/// sign in, as a job reads and writes anywhere the daemon can.
+pub fn check_listener(addr: SocketAddr, signs_in: bool) -> Result<()> {
+    if !signs_in && !addr.ip().is_loopback() {
+        bail!(
+            "Refusing to accept jobs on {} from other machines without signing in; \
+             listen on a loopback address instead",
+            addr
+        );
+    }
+    Ok(())
+}
+
+fn router(queue: JobQueue, libraries: Libraries) -> Router {
+    let mut app = Router::new()
+        .route("/jobs", axum::routing::post(submit_job))
+        .route("/jobs/{id}", get(get_job).delete(cancel_job))
+        .route("/jobs/{id}/events", get(job_events))
+        .with_state(queue.clone());
+    if let Some(library) = libraries.admin.as_ref().or(libraries.uploads.as_ref()) {
+        app = admin::admins_only(app, library.clone());
+    }
+    if let Some(library) = libraries.uploads {
+        app = app.merge(upload::router(queue.clone(), library));
+    }
+    if let Some(library) = libraries.admin {
+        app = app.merge(admin::router(queue, library));
+    }
+    app
+}
+
+/// Serve the job queue over HTTP until the process is stopped.
+pub async fn serve(queue: JobQueue, listener: TcpListener, libraries: Libraries) -> Result<()> {
+    let app = router(queue, libraries);
+    let addr = listener.local_addr()?;
+    axum::serve(listener, app)
+        .await
+        .context(format!("HTTP server on {} failed", addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::Catalog;
+    use axum::body::Body;
+    use axum::http::{Request, header};
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use movieshare_core::queue::QueueConfig;
+    use tower::ServiceExt;
+
+    #[test]
+    fn takes_jobs_from_other_machines_only_with_signing_in() {
+        let local: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let open: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+        assert!(check_listener(local, false).is_ok());
+        assert!(check_listener("[::1]:8080".parse().unwrap(), false).is_ok());
+        assert!(check_listener(open, false).is_err());
+        assert!(check_listener(open, true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn jobs_need_an_admin_once_a_library_is_served() {
+        let library = std::env::temp_dir().join(format!("movieshare-rest-{}", std::process::id()));
+        std::fs::create_dir_all(&library).unwrap();
+        {
+            let mut catalog = Catalog::open(&Catalog::default_path(&library)).unwrap();
+            catalog.add_user("alice", Some("secret"), false).unwrap();
+            catalog.add_user("kid", Some("secret"), true).unwrap();
+        }
+        let queue = JobQueue::open(QueueConfig::default()).unwrap();
+        let status = |libraries: Libraries, credentials: Option<&str>| {
+            let mut request = Request::get("/jobs/1");
+            if let Some(credentials) = credentials {
+                request = request.header(
+                    header::AUTHORIZATION,
+                    format!("Basic {}", STANDARD.encode(credentials)),
+                );
+            }
+            let app = router(queue.clone(), libraries);
+            async move {
+                app.oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+        let admin = || Libraries {
+            admin: Some(library.clone()),
+            ..Default::default()
+        };
+
+        // Without a library, the queue is open as before
+        assert_eq!(
+            status(Libraries::default(), None).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status(admin(), None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(admin(), Some("kid:secret")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(admin(), Some("alice:secret")).await,
+            StatusCode::NOT_FOUND
+        );
+        let uploads = Libraries {
+            uploads: Some(library.clone()),
+            ..Default::default()
+        };
+        assert_eq!(status(uploads, None).await, StatusCode::UNAUTHORIZED);
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
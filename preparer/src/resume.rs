@@ -0,0 +1,276 @@
+//! Catching a prepared title up with a longer ladder, for `--resume`.
+//!
+//! The journal in the output directory records the branches each run
+//! configured. When the last run finished preparing the same input, the
+//! rungs the plan encodes now that it didn't are prepared as any run would,
+//! but into [`ADDING_DIR`] under the title. Their video is then added to the
+//! title's manifest, rather than the whole title being prepared again.
+
+use crate::encrypt::{self, LibraryKey};
+use crate::library::MANIFEST;
+use crate::mpd;
+use anyhow::{Context, Result, bail};
+use movieshare_core::journal::{self, Journal, JournalEvent};
+use std::path::Path;
+
+/// Where, under a title, the rungs being added to it are prepared.
+pub const ADDING_DIR: &str = ".movieshare-adding";
+
+/// Name the preparer gives the branch encoding the rung of `bitrate`.
+fn branch_name(bitrate: u32) -> String {
+    format!("av1-{}mbps", bitrate)
+}
+
+/// Of the `rungs` planned, those missing from the title in `dir`, if its
+/// last run finished preparing `input`; empty if it didn't, as it's prepared
+/// again from the start then. `fitted` gives the bitrate a rung was encoded
+/// and journaled at, which fitting it to a decoder level may have lowered.
+pub fn missing_rungs(
+    dir: &Path,
+    input: &str,
+    rungs: impl IntoIterator<Item = u32>,
+    fitted: impl Fn(u32) -> u32,
+) -> Result<Vec<u32>> {
+    let entries = Journal::read(dir)?;
+    let run = journal::last_run(&entries);
+    let finished = matches!(
+        run.first().map(|entry| &entry.event),
+        Some(JournalEvent::Started { input: previous }) if previous == input
+    ) && run
+        .iter()
+        .any(|entry| entry.event == JournalEvent::Finalized);
+    if !finished {
+        return Ok(Vec::new());
+    }
+    let configured: Vec<&str> = run
+        .iter()
+        .filter_map(|entry| match &entry.event {
+            JournalEvent::BranchConfigured { name } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    Ok(rungs
+        .into_iter()
+        .filter(|&bitrate| {
+            !configured.contains(&branch_name(fitted(bitrate)).as_str())
+                && !configured.contains(&format!("copy-{}mbps", bitrate).as_str())
+        })
+        .collect())
+}
+
+/// Add the video of the title prepared in `dir`'s [`ADDING_DIR`] to the
+/// title in `dir`, with its files in a directory of their own, then remove
+/// what's left. `key` decrypts the title's manifest if it was encrypted;
+/// it's written back in the clear, to be encrypted again with the rest.
+pub fn add_rungs(dir: &Path, key: Option<&LibraryKey>) -> Result<()> {
+    let prepared = dir.join(ADDING_DIR);
+    let added_path = prepared.join(MANIFEST);
+    let added_xml = std::fs::read_to_string(&added_path)
+        .context(format!("Failed to read {}", added_path.display()))?;
+    let manifest_path = dir.join(MANIFEST);
+    let mut xml = match key {
+        _ if !encrypt::is_encrypted(&manifest_path) => std::fs::read_to_string(&manifest_path)
+            .context(format!("No prepared title in {}", dir.display()))?,
+        Some(key) => String::from_utf8(key.open_file(&manifest_path, MANIFEST)?)?,
+        None => bail!(
+            "{} is encrypted; pass --encrypt-key to add rungs to it",
+            dir.display()
+        ),
+    };
+
+    let subdir = (1..)
+        .map(|n| format!("added_{}", n))
+        .find(|name| !dir.join(name).exists())
+        .unwrap();
+    for representation in mpd::parse(&added_xml)?.representations {
+        if representation.content_type != "video" {
+            continue;
+        }
+        let list = mpd::segment_list(&added_xml, &representation.id)?;
+        let files = list
+            .initialization
+            .into_iter()
+            .chain(list.segments.into_iter().map(|segment| segment.media));
+        for file in files {
+            let to = dir.join(&subdir).join(&file);
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)
+                    .context(format!("Failed to create {}", parent.display()))?;
+            }
+            std::fs::rename(prepared.join(&file), &to).context(format!(
+                "Failed to move {} into {}",
+                file,
+                dir.display()
+            ))?;
+        }
+        let id = mpd::next_representation_id(&mpd::parse(&xml)?);
+        let rung = mpd::extract_representation_with_id(
+            &added_xml,
+            &representation.id,
+            &format!("{}/", subdir),
+            &id,
+        )?;
+        xml = mpd::add_representation(&xml, "video", &rung)?;
+    }
+    std::fs::write(&manifest_path, xml)
+        .context(format!("Failed to write {}", manifest_path.display()))?;
+
+    // So the next run finds them there
+    let entries = Journal::read(&prepared)?;
+    let mut journal = Journal::open(dir)?;
+    for entry in journal::last_run(&entries) {
+        if let JournalEvent::BranchConfigured { name } = &entry.event
+            && (name.starts_with("av1-") || name.starts_with("h264-"))
+        {
+            journal.record(entry.event.clone())?;
+        }
+    }
+    std::fs::remove_dir_all(&prepared).context(format!("Failed to remove {}", prepared.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use movieshare_core::EncodingProfile;
+    use movieshare_core::plan::{Plan, SourceInfo};
+    use movieshare_core::spec::RungSpec;
+
+    #[test]
+    fn finds_rungs_the_last_run_left_out() {
+        let dir = std::env::temp_dir().join(format!("resume-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let rung = |bitrate, height| RungSpec {
+            bitrate,
+            height: Some(height),
+            preset: None,
+            keyframe_secs: None,
+            caps: None,
+        };
+        let profile = EncodingProfile {
+            ladder: vec![12, 6, 4, 2],
+            rungs: vec![rung(12, 2160), rung(6, 1080), rung(4, 720), rung(2, 480)],
+            ..EncodingProfile::default()
+        };
+        // The 12 MB/s rung is taller than the source, so was never prepared
+        let mut plan = Plan::encode_all(&profile);
+        let source = SourceInfo {
+            height: Some(1080),
+            ..SourceInfo::default()
+        };
+        assert_eq!(plan.skip_taller(&source, &profile), vec![12]);
+        // And the 6 MB/s rung was fit to a decoder level at 5 MB/s
+        let fitted = |bitrate| match bitrate {
+            6 => 5,
+            bitrate => bitrate,
+        };
+        let mut journal = Journal::open(&dir).unwrap();
+        for event in [
+            JournalEvent::Started {
+                input: String::from("movie.mkv"),
+            },
+            JournalEvent::BranchConfigured {
+                name: String::from("av1-5mbps"),
+            },
+            JournalEvent::BranchConfigured {
+                name: String::from("copy-2mbps"),
+            },
+        ] {
+            journal.record(event).unwrap();
+        }
+        // An unfinished run is started again from scratch
+        assert!(
+            missing_rungs(&dir, "movie.mkv", plan.encoded_rungs(), fitted)
+                .unwrap()
+                .is_empty()
+        );
+
+        journal.record(JournalEvent::Finalized).unwrap();
+        assert_eq!(
+            missing_rungs(&dir, "movie.mkv", plan.encoded_rungs(), fitted).unwrap(),
+            vec![4]
+        );
+        assert!(
+            missing_rungs(&dir, "other.mkv", plan.encoded_rungs(), fitted)
+                .unwrap()
+                .is_empty()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn adds_the_video_of_the_rungs_prepared() {
+        let dir = std::env::temp_dir().join(format!("resume-add-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let prepared = dir.join(ADDING_DIR);
+        std::fs::create_dir_all(&prepared).unwrap();
+        let list = |name: &str| {
+            format!(
+                r#"<SegmentList timescale="1000" duration="4000"><Initialization sourceURL="{0}_init.mp4"/><SegmentURL media="{0}_00001.m4s"/></SegmentList>"#,
+                name
+            )
+        };
+        std::fs::write(
+            dir.join(MANIFEST),
+            format!(
+                r#"<MPD><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="6000000">{}</Representation></AdaptationSet></Period></MPD>"#,
+                list("video_0")
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            prepared.join(MANIFEST),
+            format!(
+                r#"<MPD><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="4000000">{}</Representation></AdaptationSet><AdaptationSet contentType="audio"><Representation id="1" bandwidth="128000">{}</Representation></AdaptationSet></Period></MPD>"#,
+                list("video_0"),
+                list("audio_1")
+            ),
+        )
+        .unwrap();
+        for file in [
+            "video_0_init.mp4",
+            "video_0_00001.m4s",
+            "audio_1_init.mp4",
+            "audio_1_00001.m4s",
+        ] {
+            std::fs::write(prepared.join(file), file).unwrap();
+        }
+        let mut journal = Journal::open(&prepared).unwrap();
+        for name in ["opus-128kbps", "av1-4mbps"] {
+            journal
+                .record(JournalEvent::BranchConfigured {
+                    name: String::from(name),
+                })
+                .unwrap();
+        }
+
+        add_rungs(&dir, None).unwrap();
+        let xml = std::fs::read_to_string(dir.join(MANIFEST)).unwrap();
+        let manifest = mpd::parse(&xml).unwrap();
+        assert_eq!(manifest.representations.len(), 2);
+        assert!(
+            manifest
+                .representations
+                .iter()
+                .all(|representation| representation.content_type == "video")
+        );
+        let added = mpd::segment_list(&xml, "1").unwrap();
+        assert_eq!(
+            added.initialization.as_deref(),
+            Some("added_1/video_0_init.mp4")
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("added_1/video_0_00001.m4s")).unwrap(),
+            "video_0_00001.m4s"
+        );
+        assert!(!prepared.exists());
+        // The run's audio is left out, and the rung is journaled with the title
+        assert!(!dir.join("added_1/audio_1_00001.m4s").exists());
+        let entries = Journal::read(&dir).unwrap();
+        assert!(entries.iter().any(|entry| entry.event
+            == JournalEvent::BranchConfigured {
+                name: String::from("av1-4mbps")
+            }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
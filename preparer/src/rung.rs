@@ -0,0 +1,112 @@
+//! `preparer reencode-rung`: encode one representation of a prepared title
+//! again from its source, such as when a rung turns out too soft, and swap
+//! it into the manifest. Every other representation's segments are left as
+//! they were.
+
+use crate::library::MANIFEST;
+use crate::mpd::{self, Manifest, Representation};
+use anyhow::{Context, Result};
+use movieshare_core::EncodingProfile;
+use movieshare_core::track;
+use std::path::Path;
+
+/// The video representation `rung` names: by height, like `1080p`, or by id.
+pub fn find<'a>(manifest: &'a Manifest, rung: &str) -> Result<&'a Representation> {
+    let height = rung
+        .strip_suffix('p')
+        .and_then(|height| height.parse::<u32>().ok());
+    manifest
+        .representations
+        .iter()
+        .filter(|representation| representation.content_type == "video")
+        .find(|representation| match height {
+            Some(height) => representation.height == Some(height),
+            None => representation.id == rung,
+        })
+        .context(format!("The title has no {} rung", rung))
+}
+
+/// Encode the rung of the title in `dir` that `rung` names again from
+/// `source` at `bitrate_mbps`, at the same size, and put it in place of the
+/// old one.
+pub fn reencode(
+    dir: &Path,
+    source: &Path,
+    rung: &str,
+    bitrate_mbps: u32,
+    profile: &EncodingProfile,
+) -> Result<()> {
+    let manifest_path = dir.join(MANIFEST);
+    let xml = std::fs::read_to_string(&manifest_path)
+        .context(format!("No prepared title in {}", dir.display()))?;
+    let manifest = mpd::parse(&xml)?;
+    let old = find(&manifest, rung)?;
+    let size = old
+        .width
+        .zip(old.height)
+        .context(format!("The manifest gives no size for the {} rung", rung))?;
+    let old_segments = mpd::segment_list(&xml, &old.id)?;
+
+    // Segments go in a directory of their own, so their names can't clash
+    // with the old ones before those are gone
+    let subdir = (1..)
+        .map(|n| format!("video_{}_{}", old.id, n))
+        .find(|name| !dir.join(name).exists())
+        .unwrap();
+    track::encode_video(source, &dir.join(&subdir), profile, bitrate_mbps, size)?;
+
+    let track_manifest = dir.join(&subdir).join(MANIFEST);
+    let track_xml = std::fs::read_to_string(&track_manifest)
+        .context(format!("Failed to read {}", track_manifest.display()))?;
+    let replacement = mpd::extract_representation(&track_xml, &format!("{}/", subdir), &old.id)?;
+    std::fs::write(
+        &manifest_path,
+        mpd::replace_representation(&xml, &old.id, &replacement)?,
+    )
+    .context(format!("Failed to write {}", manifest_path.display()))?;
+    std::fs::remove_file(&track_manifest)?;
+
+    // Only now that nothing points at them
+    let old_files = old_segments
+        .initialization
+        .iter()
+        .chain(old_segments.segments.iter().map(|segment| &segment.media));
+    for name in old_files {
+        match std::fs::remove_file(dir.join(name)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).context(format!("Failed to remove {}", name));
+            }
+            _ => (),
+        }
+        // A rung encoded again before leaves its directory behind
+        if let Some(parent) = Path::new(name).parent()
+            && !parent.as_os_str().is_empty()
+        {
+            let _ = std::fs::remove_dir(dir.join(parent));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_rungs_by_height_or_id() {
+        let manifest = mpd::parse(
+            r#"<MPD><Period>
+<AdaptationSet contentType="video">
+  <Representation id="0" bandwidth="6000000" width="1920" height="1080"/>
+  <Representation id="1" bandwidth="2000000" width="1280" height="720"/>
+</AdaptationSet>
+<AdaptationSet contentType="audio"><Representation id="2" bandwidth="192000"/></AdaptationSet>
+</Period></MPD>"#,
+        )
+        .unwrap();
+        assert_eq!(find(&manifest, "720p").unwrap().id, "1");
+        assert_eq!(find(&manifest, "0").unwrap().height, Some(1080));
+        assert!(find(&manifest, "2").is_err());
+        assert!(find(&manifest, "480p").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Scrubbing a library for bit rot, for `preparer daemon --scrub-library`.
+//!
+//! Every so often the daemon reads back each file the catalog took a
+//! checksum of (`preparer library scan --checksums`) and compares it,
+//! slowly enough not to get in the way of viewers or encodes. A title with
+//! a file that no longer matches is flagged damaged in the catalog, and
+//! `preparer library upgrade` queues it to be prepared again from its
+//! source.
+//!
+//! Titles whose manifest changed since the last scan are passed over, as
+//! their recorded checksums are out of date rather than their files bad.
+
+use crate::library::{Catalog, MANIFEST, sha256_file};
+use anyhow::{Context, Result};
+use rusqlite::{OptionalExtension, params};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// What to scrub, and how hard.
+pub struct Scrub {
+    pub library: PathBuf,
+    /// Bytes read per second
+    pub rate: u64,
+    /// Time between the end of one pass and the start of the next
+    pub every: Duration,
+}
+
+impl Catalog {
+    /// Flag the title `name` as damaged, saying how.
+    pub fn mark_damaged(&self, name: &str, damage: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE titles SET damaged = ?2 WHERE name = ?1",
+            params![name, damage],
+        )?;
+        Ok(())
+    }
+
+    /// How the title `name` was found damaged, if it was. Preparing it
+    /// again and scanning clears this.
+    pub fn damage(&self, name: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT damaged FROM titles WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+}
+
+/// Keeps reading to a number of bytes a second.
+struct Pace {
+    rate: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Pace {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// How long to wait after reading `bytes` more, `elapsed` since starting.
+    fn delay(&mut self, bytes: usize, elapsed: Duration) -> Duration {
+        self.bytes += bytes as u64;
+        Duration::from_secs_f64(self.bytes as f64 / self.rate as f64).saturating_sub(elapsed)
+    }
+
+    fn read(&mut self, bytes: usize) {
+        std::thread::sleep(self.delay(bytes, self.started.elapsed()));
+    }
+}
+
+/// The SHA-256 of the file at `path`, read no faster than `pace` allows.
+fn paced_sha256(path: &Path, pace: &mut Pace) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        pace.read(read);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Check every title in `library` once, reading `rate` bytes a second and
+/// flagging those found damaged; returns their names.
+fn pass(library: &Path, rate: u64) -> Result<Vec<String>> {
+    let Some(catalog) = Catalog::open_existing(library)? else {
+        return Ok(Vec::new());
+    };
+    let mut pace = Pace::new(rate);
+    let mut damaged = Vec::new();
+    for title in catalog.titles()? {
+        if catalog.damage(&title.name)?.is_some() {
+            continue;
+        }
+        let manifest = title.path.join(MANIFEST);
+        if sha256_file(&manifest).ok() != Some(title.manifest_sha256.clone()) {
+            continue;
+        }
+        for file in catalog.files(&title.name)? {
+            let Some(sha256) = &file.sha256 else {
+                continue;
+            };
+            let damage = match paced_sha256(&title.path.join(&file.path), &mut pace) {
+                Ok(found) if found == *sha256 => continue,
+                Ok(_) => format!("{} doesn't match its checksum", file.path),
+                Err(_) => format!("{} can't be read", file.path),
+            };
+            eprintln!("Warning: {} is damaged: {}", title.name, damage);
+            catalog.mark_damaged(&title.name, &damage)?;
+            damaged.push(title.name.clone());
+            break;
+        }
+    }
+    Ok(damaged)
+}
+
+/// Scrub the library now and then, until the daemon stops.
+pub async fn run(scrub: Scrub) -> Result<()> {
+    loop {
+        let library = scrub.library.clone();
+        let rate = scrub.rate;
+        match tokio::task::spawn_blocking(move || pass(&library, rate)).await? {
+            Ok(damaged) if damaged.is_empty() => {}
+            Ok(damaged) => println!(
+                "Found {} damaged title(s) in {}; `preparer library upgrade` prepares them again",
+                damaged.len(),
+                scrub.library.display()
+            ),
+            Err(err) => eprintln!("Warning: failed to scrub the library: {:#}", err),
+        }
+        tokio::time::sleep(scrub.every).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_titles_that_rotted() {
+        let mut pace = Pace::new(1000);
+        assert_eq!(
+            pace.delay(500, Duration::from_millis(100)),
+            Duration::from_millis(400)
+        );
+        assert_eq!(pace.delay(500, Duration::from_secs(2)), Duration::ZERO);
+
+        let library = std::env::temp_dir().join(format!("movieshare-scrub-{}", std::process::id()));
+        let dir = library.join("Movie");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD mediaPresentationDuration="PT4S"><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("video_0_00001.m4s"), b"segment").unwrap();
+        let mut catalog = Catalog::open(&Catalog::default_path(&library)).unwrap();
+        catalog.scan(&library, true).unwrap();
+
+        assert!(pass(&library, u64::MAX).unwrap().is_empty());
+        std::fs::write(dir.join("video_0_00001.m4s"), b"segmenT").unwrap();
+        assert_eq!(pass(&library, u64::MAX).unwrap(), ["Movie"]);
+        assert_eq!(
+            catalog.damage("Movie").unwrap().as_deref(),
+            Some("video_0_00001.m4s doesn't match its checksum")
+        );
+        // Flagged titles aren't read again
+        assert!(pass(&library, u64::MAX).unwrap().is_empty());
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! Searching the subtitles of the whole library, for `preparer search` and
+//! `GET /api/search`, to find the scene a line is from.
+//!
+//! `preparer library scan` indexes the cues of each title's WebVTT tracks
+//! in a full-text table of the catalog; a search answers with the titles
+//! and times the words are spoken at.
+
+use crate::library::Catalog;
+use crate::mpd;
+use crate::subpreview;
+use anyhow::Result;
+use rusqlite::{Transaction, params};
+use serde::Serialize;
+use std::path::Path;
+
+/// A cue that matched a search.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub title: String,
+    /// Language of the subtitle track the cue is from
+    pub lang: Option<String>,
+    pub start_secs: f64,
+    pub end_secs: f64,
+    pub text: String,
+}
+
+/// Cue text without its WebVTT tags, like `<i>`, or line breaks.
+fn plain_text(text: &str) -> String {
+    let mut plain = String::new();
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            '\n' if !in_tag => plain.push(' '),
+            _ if !in_tag => plain.push(c),
+            _ => (),
+        }
+    }
+    plain
+        .replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+}
+
+/// Index the cues of every subtitle track the manifest `xml` of the title
+/// `name` in `dir` names, replacing those indexed before.
+pub(crate) fn index_cues(tx: &Transaction, name: &str, dir: &Path, xml: &str) -> Result<()> {
+    tx.execute("DELETE FROM subtitle_cues WHERE title = ?1", params![name])?;
+    for representation in mpd::parse(xml)?.representations {
+        if representation.content_type != "text" {
+            continue;
+        }
+        let Some(file) = mpd::base_url(xml, &representation.id)? else {
+            continue;
+        };
+        // A missing track is for `serve --verify-on-start` to point out
+        let Ok(vtt) = std::fs::read_to_string(dir.join(&file)) else {
+            continue;
+        };
+        for cue in subpreview::parse_vtt(&vtt) {
+            tx.execute(
+                "INSERT INTO subtitle_cues (text, title, lang, start_secs, end_secs)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    plain_text(&cue.text),
+                    name,
+                    representation.lang,
+                    cue.start_secs,
+                    cue.end_secs
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+impl Catalog {
+    /// The cues whose text has the words of `query` in that order, best
+    /// matches first.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<Hit>> {
+        // As one phrase, so quotes and operators in it are just words
+        let phrase = format!("\"{}\"", query.replace('"', "\"\""));
+        let mut statement = self.conn.prepare(
+            "SELECT title, lang, start_secs, end_secs, text FROM subtitle_cues
+             WHERE subtitle_cues MATCH ?1 ORDER BY rank LIMIT ?2",
+        )?;
+        let hits = statement
+            .query_map(params![phrase, limit as i64], |row| {
+                Ok(Hit {
+                    title: row.get(0)?,
+                    lang: row.get(1)?,
+                    start_secs: row.get(2)?,
+                    end_secs: row.get(3)?,
+                    text: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::MANIFEST;
+
+    #[test]
+    fn finds_the_line_across_the_library() {
+        let library =
+            std::env::temp_dir().join(format!("movieshare-search-{}", std::process::id()));
+        let dir = library.join("Casablanca");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD><Period><AdaptationSet contentType="text" lang="en"><Representation id="1" bandwidth="1"><BaseURL>subtitles_1.vtt</BaseURL></Representation></AdaptationSet></Period></MPD>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("subtitles_1.vtt"),
+            "WEBVTT\n\n00:01:00.000 --> 00:01:02.000\nOf all the gin joints\n\n01:42:10.500 --> 01:42:13.000\n<i>Here's looking\nat you, kid.</i>\n",
+        )
+        .unwrap();
+        let mut catalog = Catalog::open(&Catalog::default_path(&library)).unwrap();
+        catalog.scan(&library, false).unwrap();
+
+        let hits = catalog.search("looking at you", 10).unwrap();
+        assert_eq!(
+            hits,
+            [Hit {
+                title: String::from("Casablanca"),
+                lang: Some(String::from("en")),
+                start_secs: 6130.5,
+                end_secs: 6133.0,
+                text: String::from("Here's looking at you, kid."),
+            }]
+        );
+        // Words out of order aren't the line, and quotes are just words
+        assert!(catalog.search("you looking", 10).unwrap().is_empty());
+        assert!(catalog.search("\"gin OR", 10).unwrap().is_empty());
+
+        // A title taken out of the catalog takes its cues with it
+        catalog.remove("Casablanca").unwrap();
+        assert!(catalog.search("gin joints", 10).unwrap().is_empty());
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
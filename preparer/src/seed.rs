@@ -0,0 +1,219 @@
+//! A minimal seed-only BitTorrent peer for titles with a v2 torrent.
+//!
+//! It announces to the torrent's HTTP trackers and serves pieces to whoever
+//! connects; it never downloads anything.
+
+use crate::torrent::{self, Torrent};
+use anyhow::{Context, Result, bail};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+/// Largest block a peer may ask for at once.
+const MAX_REQUEST: u32 = 128 * 1024;
+
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const HASH_REQUEST: u8 = 21;
+const HASH_REJECT: u8 = 23;
+
+struct Seed {
+    torrent: Torrent,
+    dir: PathBuf,
+}
+
+/// The handshake and trackers identify v2 torrents by a truncated info hash.
+fn short_hash(torrent: &Torrent) -> [u8; 20] {
+    torrent.info_hash()[..20].try_into().unwrap()
+}
+
+fn peer_id() -> [u8; 20] {
+    let mut id = *b"-MS0001-000000000000";
+    for byte in &mut id[8..] {
+        *byte = b'0' + rand::random_range(0..10);
+    }
+    id
+}
+
+/// Seed each directory's torrent (made with `preparer torrent`) on `port`.
+pub async fn serve(dirs: Vec<PathBuf>, port: u16) -> Result<()> {
+    let peer_id = peer_id();
+    let mut seeds = HashMap::new();
+    for dir in dirs {
+        let torrent = Torrent::read(&torrent::default_path(&dir))?;
+        let hash = short_hash(&torrent);
+        let seed = Arc::new(Seed { torrent, dir });
+        tokio::spawn(announce(seed.clone(), peer_id, port));
+        seeds.insert(hash, seed);
+    }
+    let seeds = Arc::new(seeds);
+
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .context(format!("Failed to listen for peers on port {}", port))?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let seeds = seeds.clone();
+        tokio::spawn(async move {
+            // Peers come and go; one misbehaving doesn't matter
+            let _ = handle_peer(stream, &seeds, peer_id).await;
+        });
+    }
+}
+
+/// Tell the torrent's trackers about us, again whenever they ask to hear back.
+async fn announce(seed: Arc<Seed>, peer_id: [u8; 20], port: u16) {
+    let mut event = "&event=started";
+    loop {
+        let mut interval = 1800;
+        for tracker in &seed.torrent.trackers {
+            if !tracker.starts_with("http") {
+                continue;
+            }
+            let url = format!(
+                "{}{}info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left=0&compact=1{}",
+                tracker,
+                if tracker.contains('?') { '&' } else { '?' },
+                uri_encode_bytes(&short_hash(&seed.torrent)),
+                uri_encode_bytes(&peer_id),
+                port,
+                event
+            );
+            let response = tokio::task::spawn_blocking(move || -> Result<u64> {
+                let body = ureq::get(&url).call()?.body_mut().read_to_vec()?;
+                let response = crate::bencode::Value::decode(&body)?;
+                if let Some(reason) = response.get("failure reason").and_then(|r| r.as_str()) {
+                    bail!("{}", reason);
+                }
+                Ok(response
+                    .get("interval")
+                    .and_then(|interval| interval.as_int())
+                    .unwrap_or(1800) as u64)
+            })
+            .await;
+            match response {
+                Ok(Ok(seconds)) => interval = interval.min(seconds.max(60)),
+                Ok(Err(err)) => eprintln!("Tracker {} failed: {:#}", tracker, err),
+                Err(_) => (),
+            }
+        }
+        event = "";
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+fn uri_encode_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+async fn send(stream: &mut TcpStream, id: u8, payload: &[u8]) -> Result<()> {
+    let mut message = Vec::with_capacity(5 + payload.len());
+    message.extend(((payload.len() + 1) as u32).to_be_bytes());
+    message.push(id);
+    message.extend(payload);
+    stream.write_all(&message).await?;
+    Ok(())
+}
+
+/// Every piece, with the spare bits of the last byte left clear.
+fn bitfield(pieces: u64) -> Vec<u8> {
+    let mut bits = vec![0xff; pieces.div_ceil(8) as usize];
+    if !pieces.is_multiple_of(8)
+        && let Some(last) = bits.last_mut()
+    {
+        *last = 0xff << (8 - pieces % 8);
+    }
+    bits
+}
+
+async fn handle_peer(
+    mut stream: TcpStream,
+    seeds: &HashMap<[u8; 20], Arc<Seed>>,
+    peer_id: [u8; 20],
+) -> Result<()> {
+    let mut handshake = [0; 68];
+    stream.read_exact(&mut handshake).await?;
+    if handshake[0] as usize != PROTOCOL.len() || &handshake[1..20] != PROTOCOL {
+        bail!("Not a BitTorrent peer");
+    }
+    let hash: [u8; 20] = handshake[28..48].try_into()?;
+    let seed = seeds.get(&hash).context("Unknown torrent")?;
+
+    let mut reply = Vec::with_capacity(68);
+    reply.push(PROTOCOL.len() as u8);
+    reply.extend(PROTOCOL);
+    // Advertise v2 support
+    reply.extend([0, 0, 0, 0, 0, 0, 0, 0x10]);
+    reply.extend(hash);
+    reply.extend(peer_id);
+    stream.write_all(&reply).await?;
+    send(&mut stream, BITFIELD, &bitfield(seed.torrent.piece_count())).await?;
+
+    loop {
+        let length = stream.read_u32().await?;
+        if length == 0 {
+            continue;
+        }
+        if length > MAX_REQUEST + 64 {
+            bail!("Message too long");
+        }
+        let mut message = vec![0; length as usize];
+        stream.read_exact(&mut message).await?;
+        let (id, payload) = (message[0], &message[1..]);
+        match id {
+            INTERESTED => send(&mut stream, UNCHOKE, &[]).await?,
+            REQUEST if payload.len() == 12 => {
+                let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+                let (index, begin, length) = (field(0), field(4), field(8));
+                let (file, start, piece_length) = seed
+                    .torrent
+                    .locate(index as u64)
+                    .context("Request for a piece that doesn't exist")?;
+                if length > MAX_REQUEST || begin as u64 + length as u64 > piece_length {
+                    bail!("Request outside the piece");
+                }
+                let mut data = vec![0; length as usize];
+                let mut source = tokio::fs::File::open(seed.dir.join(&file.path)).await?;
+                source.seek(SeekFrom::Start(start + begin as u64)).await?;
+                source.read_exact(&mut data).await?;
+
+                let mut reply = Vec::with_capacity(8 + data.len());
+                reply.extend(index.to_be_bytes());
+                reply.extend(begin.to_be_bytes());
+                reply.extend(data);
+                send(&mut stream, PIECE, &reply).await?;
+            }
+            // Peers with the .torrent already have the piece layers
+            HASH_REQUEST => send(&mut stream, HASH_REJECT, payload).await?,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_every_piece() {
+        assert_eq!(bitfield(8), [0xff]);
+        assert_eq!(bitfield(11), [0xff, 0b1110_0000]);
+        assert_eq!(uri_encode_bytes(&[0x12, b'a', 0xff]), "%12a%FF");
+    }
+}
@@ -0,0 +1,360 @@
+//! A sidecar listing, for each representation of a prepared title, when
+//! each segment starts and where its keyframes fall, so custom players and
+//! `preparer clip` can seek to the frame without reading every segment.
+//!
+//! Keyframes are found from the sample flags in each video segment's
+//! `moof`, with the timescale and defaults from the initialization segment.
+
+use crate::library::{MANIFEST, SEEK_INDEX};
+use crate::mpd::{self, Segment, SegmentList};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// `sample_is_non_sync_sample` in ISO BMFF sample flags.
+const NON_SYNC: u32 = 0x10000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SeekIndex {
+    pub representations: Vec<IndexedRepresentation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexedRepresentation {
+    pub id: String,
+    /// `video`, `audio` or `text`
+    pub content_type: String,
+    pub bandwidth: u64,
+    pub initialization: Option<String>,
+    pub segments: Vec<IndexedSegment>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexedSegment {
+    /// File name, relative to the manifest
+    pub media: String,
+    pub start_secs: f64,
+    pub duration_secs: f64,
+    /// Presentation times of the segment's keyframes, in seconds from the
+    /// start of the title; empty for audio and text, where every sample is
+    /// one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keyframes: Vec<f64>,
+}
+
+impl IndexedRepresentation {
+    /// The segments, as the manifest would give them.
+    pub fn segment_list(&self) -> SegmentList {
+        SegmentList {
+            initialization: self.initialization.clone(),
+            segments: self
+                .segments
+                .iter()
+                .map(|segment| Segment {
+                    media: segment.media.clone(),
+                    start_secs: segment.start_secs,
+                    duration_secs: segment.duration_secs,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl SeekIndex {
+    /// Index the prepared title in `dir`.
+    pub fn build(dir: &Path) -> Result<Self> {
+        let xml = std::fs::read_to_string(dir.join(MANIFEST))
+            .context(format!("No prepared title in {}", dir.display()))?;
+        let manifest = mpd::parse(&xml)?;
+        let mut index = Self::default();
+        for representation in manifest.representations {
+            // Subtitles added later are one file, not segments, and
+            // thumbnails aren't media
+            if matches!(representation.content_type.as_str(), "text" | "image") {
+                continue;
+            }
+            let list = mpd::segment_list(&xml, &representation.id)
+                .context(format!("Failed to list segments of {}", representation.id))?;
+            let defaults = match (representation.content_type.as_str(), &list.initialization) {
+                ("video", Some(init)) => {
+                    let data = std::fs::read(dir.join(init))
+                        .context(format!("Failed to read {}", init))?;
+                    Some(
+                        TrackDefaults::parse(&data)
+                            .context(format!("Invalid initialization segment {}", init))?,
+                    )
+                }
+                _ => None,
+            };
+            let mut segments = Vec::with_capacity(list.segments.len());
+            for segment in list.segments {
+                let keyframes = match &defaults {
+                    Some(defaults) => {
+                        let data = std::fs::read(dir.join(&segment.media))
+                            .context(format!("Failed to read {}", segment.media))?;
+                        keyframe_offsets(&data, defaults)
+                            .context(format!("Invalid media segment {}", segment.media))?
+                            .into_iter()
+                            .map(|offset| segment.start_secs + offset)
+                            .collect()
+                    }
+                    None => Vec::new(),
+                };
+                segments.push(IndexedSegment {
+                    media: segment.media,
+                    start_secs: segment.start_secs,
+                    duration_secs: segment.duration_secs,
+                    keyframes,
+                });
+            }
+            index.representations.push(IndexedRepresentation {
+                id: representation.id,
+                content_type: representation.content_type,
+                bandwidth: representation.bandwidth,
+                initialization: list.initialization,
+                segments,
+            });
+        }
+        Ok(index)
+    }
+
+    /// Read the index from a title's directory, if it has one.
+    pub fn read(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(SEEK_INDEX);
+        match std::fs::read_to_string(&path) {
+            Ok(json) => Ok(Some(
+                serde_json::from_str(&json).context(format!("Invalid {}", path.display()))?,
+            )),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(SEEK_INDEX);
+        std::fs::write(&path, serde_json::to_string(self)?)
+            .context(format!("Failed to write {}", path.display()))
+    }
+
+    pub fn representation(&self, id: &str) -> Option<&IndexedRepresentation> {
+        self.representations.iter().find(|r| r.id == id)
+    }
+}
+
+/// Bring the seek index of the title in `dir` up to date with its manifest,
+/// if it has one.
+pub fn refresh(dir: &Path) -> Result<()> {
+    if SeekIndex::read(dir)?.is_some() {
+        SeekIndex::build(dir)?.write(dir)?;
+    }
+    Ok(())
+}
+
+/// Boxes directly inside `data`, as type and payload.
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let kind = data.get(4..8)?;
+        let (header, size) = match size {
+            0 => (8, data.len()),
+            1 => (
+                16,
+                u64::from_be_bytes(data.get(8..16)?.try_into().ok()?) as usize,
+            ),
+            size => (8, size),
+        };
+        let payload = data.get(header..size)?;
+        data = &data[size..];
+        Some((kind, payload))
+    })
+}
+
+/// Payload of the first box of type `path[0]`, inside the first `path[1]`,
+/// and so on.
+fn find<'a>(data: &'a [u8], path: &[&str]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, name| {
+        boxes(data)
+            .find(|(kind, _)| *kind == name.as_bytes())
+            .map(|(_, payload)| payload)
+    })
+}
+
+/// Reads big-endian fields one after another.
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn u32(&mut self) -> Option<u32> {
+        let value = u32::from_be_bytes(self.0.get(..4)?.try_into().ok()?);
+        self.0 = &self.0[4..];
+        Some(value)
+    }
+
+    fn skip(&mut self, bytes: usize) -> Option<()> {
+        self.0 = self.0.get(bytes..)?;
+        Some(())
+    }
+}
+
+/// What the initialization segment says about the track.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TrackDefaults {
+    timescale: u32,
+    sample_duration: u32,
+    sample_flags: u32,
+}
+
+impl TrackDefaults {
+    fn parse(init: &[u8]) -> Option<Self> {
+        let mdhd = find(init, &["moov", "trak", "mdia", "mdhd"])?;
+        let mut fields = Fields(mdhd);
+        let version = fields.u32()? >> 24;
+        fields.skip(if version == 1 { 16 } else { 8 })?;
+        let timescale = fields.u32()?;
+
+        let (sample_duration, sample_flags) = match find(init, &["moov", "mvex", "trex"]) {
+            Some(trex) => {
+                let mut fields = Fields(trex);
+                fields.skip(12)?;
+                let duration = fields.u32()?;
+                fields.skip(4)?;
+                (duration, fields.u32()?)
+            }
+            None => (0, 0),
+        };
+        Some(Self {
+            timescale: timescale.max(1),
+            sample_duration,
+            sample_flags,
+        })
+    }
+}
+
+/// Presentation times of the keyframes in a media segment, in seconds from
+/// its first sample.
+fn keyframe_offsets(segment: &[u8], defaults: &TrackDefaults) -> Option<Vec<f64>> {
+    let traf = find(segment, &["moof", "traf"])?;
+    let mut fields = Fields(find(traf, &["tfhd"])?);
+    let flags = fields.u32()? & 0xffffff;
+    fields.skip(4)?;
+    if flags & 0x01 != 0 {
+        fields.skip(8)?;
+    }
+    if flags & 0x02 != 0 {
+        fields.skip(4)?;
+    }
+    let default_duration = match flags & 0x08 != 0 {
+        true => fields.u32()?,
+        false => defaults.sample_duration,
+    };
+    if flags & 0x10 != 0 {
+        fields.skip(4)?;
+    }
+    let default_flags = match flags & 0x20 != 0 {
+        true => fields.u32()?,
+        false => defaults.sample_flags,
+    };
+
+    let mut keyframes = Vec::new();
+    let mut decode_time: i64 = 0;
+    let mut first_pts = None;
+    for (_, trun) in boxes(traf).filter(|(kind, _)| *kind == b"trun") {
+        let mut fields = Fields(trun);
+        let header = fields.u32()?;
+        let (version, flags) = (header >> 24, header & 0xffffff);
+        let count = fields.u32()?;
+        if flags & 0x01 != 0 {
+            fields.skip(4)?;
+        }
+        let first_flags = match flags & 0x04 != 0 {
+            true => Some(fields.u32()?),
+            false => None,
+        };
+        for i in 0..count {
+            let duration = match flags & 0x100 != 0 {
+                true => fields.u32()?,
+                false => default_duration,
+            };
+            if flags & 0x200 != 0 {
+                fields.skip(4)?;
+            }
+            let sample_flags = match (flags & 0x400 != 0, first_flags) {
+                (true, _) => fields.u32()?,
+                (false, Some(first)) if i == 0 => first,
+                (false, _) => default_flags,
+            };
+            let offset = match flags & 0x800 != 0 {
+                // Signed from version 1 on
+                true if version == 0 => fields.u32()? as i64,
+                true => fields.u32()? as i32 as i64,
+                false => 0,
+            };
+            let pts = decode_time + offset;
+            let first_pts = *first_pts.get_or_insert(pts);
+            if sample_flags & NON_SYNC == 0 {
+                keyframes.push((pts - first_pts) as f64 / defaults.timescale as f64);
+            }
+            decode_time += duration as i64;
+        }
+    }
+    Some(keyframes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &str, payload: &[&[u8]]) -> Vec<u8> {
+        let payload = payload.concat();
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend(kind.as_bytes());
+        data.extend(payload);
+        data
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn finds_keyframes_from_sample_flags() {
+        let mdhd = mp4_box("mdhd", &[&words(&[0, 0, 0, 90000, 0, 0])]);
+        let trex = mp4_box("trex", &[&words(&[0, 1, 1, 3000, 0, NON_SYNC])]);
+        let init = mp4_box(
+            "moov",
+            &[
+                &mp4_box("trak", &[&mp4_box("mdia", &[&mdhd])]),
+                &mp4_box("mvex", &[&trex]),
+            ],
+        );
+        let defaults = TrackDefaults::parse(&init).unwrap();
+        assert_eq!(
+            defaults,
+            TrackDefaults {
+                timescale: 90000,
+                sample_duration: 3000,
+                sample_flags: NON_SYNC,
+            }
+        );
+
+        // Six samples: the first a keyframe by first-sample-flags, the sixth
+        // by its own flags, the rest not
+        let tfhd = mp4_box("tfhd", &[&words(&[0, 1])]);
+        let first = mp4_box("trun", &[&words(&[0x04, 4, 0])]);
+        let flags = [NON_SYNC, 0];
+        let second = mp4_box("trun", &[&words(&[0x400, 2]), &words(&flags)]);
+        let segment = mp4_box(
+            "moof",
+            &[
+                &mp4_box("mfhd", &[&words(&[0, 1])]),
+                &mp4_box("traf", &[&tfhd, &first, &second]),
+            ],
+        );
+        let keyframes = keyframe_offsets(&segment, &defaults).unwrap();
+        assert_eq!(keyframes, [0.0, 5.0 * 3000.0 / 90000.0]);
+
+        assert!(keyframe_offsets(&segment[..20], &defaults).is_none());
+    }
+}
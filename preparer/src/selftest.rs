@@ -0,0 +1,173 @@
+//! `preparer selftest`: prepare a generated test pattern end to end and
+//! check what comes out, to tell whether a machine's GStreamer install has
+//! everything the pipeline needs.
+
+use crate::library::MANIFEST;
+use crate::mpd;
+use anyhow::Result;
+use movieshare_core::EncodingProfile;
+use movieshare_core::gst;
+use std::path::Path;
+use std::time::Duration;
+
+/// Elements the pipeline creates by name, besides dashsink's muxer.
+const REQUIRED_ELEMENTS: &[&str] = &[
+    "videotestsrc",
+    "audiotestsrc",
+    "filesrc",
+    "fdsrc",
+    "decodebin",
+    "parsebin",
+    "tee",
+    "queue",
+    "capsfilter",
+    "videoscale",
+    "videoconvert",
+    "svtav1enc",
+    "av1parse",
+    "audioconvert",
+    "audioresample",
+    "opusenc",
+    "dashsink",
+];
+
+/// One thing the self-test looked at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, passed: bool, detail: String) -> Self {
+        Self {
+            name,
+            passed,
+            detail,
+        }
+    }
+}
+
+/// Whether every element the pipeline needs is installed.
+pub fn check_elements() -> Result<Check> {
+    gst::init()?;
+    let missing: Vec<&str> = REQUIRED_ELEMENTS
+        .iter()
+        .copied()
+        .filter(|name| gst::ElementFactory::find(name).is_none())
+        .collect();
+    Ok(match missing.is_empty() {
+        true => Check::new(
+            "elements",
+            true,
+            format!("all {} installed", REQUIRED_ELEMENTS.len()),
+        ),
+        false => Check::new("elements", false, format!("missing {}", missing.join(", "))),
+    })
+}
+
+/// Check the presentation written to `dir` against what was asked for.
+pub fn check_output(dir: &Path, profile: &EncodingProfile, duration: Duration) -> Vec<Check> {
+    let xml = match std::fs::read_to_string(dir.join(MANIFEST)) {
+        Ok(xml) => xml,
+        Err(err) => return vec![Check::new("manifest", false, err.to_string())],
+    };
+    let manifest = match mpd::parse(&xml) {
+        Ok(manifest) => manifest,
+        Err(err) => return vec![Check::new("manifest", false, format!("{:#}", err))],
+    };
+    let mut checks = vec![Check::new("manifest", true, String::from("parses"))];
+
+    let expected = duration.as_secs_f64();
+    let tolerance = profile.segment_duration as f64;
+    checks.push(match manifest.duration_secs {
+        Some(secs) => Check::new(
+            "duration",
+            (secs - expected).abs() <= tolerance,
+            format!("{:.1}s, expected {:.0}s", secs, expected),
+        ),
+        None => Check::new("duration", false, String::from("not declared")),
+    });
+
+    let count = |content_type: &str| {
+        manifest
+            .representations
+            .iter()
+            .filter(|representation| representation.content_type == content_type)
+            .count()
+    };
+    let (video, audio) = (count("video"), count("audio"));
+    checks.push(Check::new(
+        "representations",
+        video == profile.ladder.len() && audio == 1,
+        format!(
+            "{} video and {} audio, expected {} and 1",
+            video,
+            audio,
+            profile.ladder.len()
+        ),
+    ));
+
+    let segments = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| {
+            let path = entry.path();
+            matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("m4s" | "mp4")
+            ) && entry.metadata().is_ok_and(|metadata| metadata.len() > 0)
+        })
+        .count();
+    checks.push(Check::new(
+        "segments",
+        segments > 0,
+        format!("{} files written", segments),
+    ));
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_written_presentation() {
+        let dir = std::env::temp_dir().join(format!("movieshare-selftest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let profile = EncodingProfile::default();
+        let duration = Duration::from_secs(30);
+
+        assert!(!check_output(&dir, &profile, duration)[0].passed);
+
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD mediaPresentationDuration="PT30.0S"><Period>
+                <AdaptationSet contentType="video">
+                    <Representation id="0" bandwidth="6000000"/>
+                    <Representation id="1" bandwidth="2000000"/>
+                </AdaptationSet>
+                <AdaptationSet contentType="audio"><Representation id="2" bandwidth="192000"/></AdaptationSet>
+            </Period></MPD>"#,
+        )
+        .unwrap();
+        let checks = check_output(&dir, &profile, duration);
+        let failed: Vec<&str> = checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| check.name)
+            .collect();
+        assert_eq!(failed, ["segments"]);
+
+        std::fs::write(dir.join("video_0-1.m4s"), b"moof").unwrap();
+        assert!(
+            check_output(&dir, &profile, duration)
+                .iter()
+                .all(|check| check.passed)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
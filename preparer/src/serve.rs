@@ -0,0 +1,1605 @@
+//! HTTP server for a library of prepared titles.
+//!
+//! Each subdirectory of the library is one title as written by the preparer;
+//! its files are served under `/media/<title>/`, so a title's manifest is
+//! at `/media/<title>/manifest.mpd`. `/watch/<title>` is a player page for
+//! the title and `/` lists every title, from the library's catalog if
+//! `preparer library scan` has built one.
+//!
+//! `POST /rooms` opens a watch-together room for a title; viewers join it
+//! with `/watch/<title>?room=<id>`, which syncs over `/sync/<id>`. A viewer
+//! moving to another device gets a code there that `POST /handoff/<code>`
+//! turns back into the room and position to join at. With `p2p` set,
+//! viewers in a room also fetch segments from each other; see
+//! [`crate::swarm`].
+//!
+//! `/s/<token>/` is the player page of a share link and `/s/<token>/<file>`
+//! the title's files, available only while the token is valid. With
+//! `shared_only`, share links are the only way in. A capped link's manifest
+//! leaves out the video rungs taller than it allows, whose segments it
+//! can't fetch either. `/s/<token>/listen.mpd` leaves out the video
+//! altogether, for the player's listen mode, which shows the poster
+//! instead so a viewer on a poor connection can keep up with a room by
+//! ear. Links good for a single viewing are under `/o/<token>/` instead;
+//! see [`crate::once`]. A link to a collection lists its titles at
+//! `/c/<token>/`, each of which plays at `/c/<token>/<title>/`.
+//!
+//! With `sources` set, media files there are prepared into the library the
+//! first time they're watched; see [`crate::jit`].
+//!
+//! `/feed.xml` and `/feed.json` list the newest titles; see [`crate::feed`].
+//!
+//! `/api/titles` describes the library as JSON for other frontends; see
+//! [`crate::api`].
+//!
+//! The player reports anonymous playback sessions to `/analytics`; see
+//! [`crate::analytics`].
+//!
+//! `/progress` saves and restores where each viewer left off; see
+//! [`crate::progress`].
+//!
+//! With `auth` set, everything but share links needs a signed-in user from
+//! the catalog, who only sees the titles they're allowed; see [`crate::auth`].
+//!
+//! `limits` throttle viewers outside the LAN; see [`crate::throttle`].
+//!
+//! With `dlna` set, the library is also announced to UPnP renderers on the
+//! LAN; see [`crate::dlna`].
+//!
+//! With `library_key` set, titles encrypted with it are decrypted as their
+//! files are requested; see [`crate::encrypt`].
+//!
+//! With `verify_on_start` set, every title is checked before serving begins,
+//! and broken ones are listed as unavailable, with their player pages
+//! answering 503 and what's wrong; see [`crate::verify`].
+
+use crate::analytics;
+use crate::api;
+use crate::auth::{self, AuthMode};
+use crate::dlna;
+use crate::encrypt::{self, LibraryKey};
+use crate::feed;
+use crate::i18n::Locale;
+use crate::jit::{Jit, JitError};
+use crate::library::{Catalog, FALLBACK, MANIFEST, Metadata, POSTERS};
+use crate::mpd;
+use crate::once::{self, OnceShares};
+use crate::progress;
+use crate::share::ShareKey;
+use crate::swarm::{self, Swarms};
+use crate::sync::{self, Rooms};
+use crate::throttle::{self, Limits, Throttle};
+use crate::users::User;
+use crate::verify;
+use anyhow::{Context, Result, bail};
+use axum::Extension;
+use axum::Json;
+use axum::Router;
+use axum::extract::{ConnectInfo, FromRef, Path as UrlPath, Request, State};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum_server::tls_rustls::RustlsConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tower::ServiceExt;
+use tower_http::cors::{Any, CorsLayer};
+use tower_http::services::ServeDir;
+
+/// A share link's manifest without video, for viewers with too little
+/// bandwidth to watch who'd rather keep listening.
+const LISTEN_MANIFEST: &str = "listen.mpd";
+
+/// MIME types players are picky about; anything else is guessed from the extension.
+pub(crate) fn content_type(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path).extension()?.to_str()?;
+    Some(match extension {
+        "mpd" => "application/dash+xml",
+        "m4s" => "video/iso.segment",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "vtt" => "text/vtt",
+        _ => return None,
+    })
+}
+
+async fn set_content_type(request: Request, next: Next) -> Response {
+    let content_type = content_type(request.uri().path());
+    let mut response = next.run(request).await;
+    if let Some(content_type) = content_type
+        && response.status().is_success()
+    {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+    response
+}
+
+#[derive(Clone)]
+pub struct ServeConfig {
+    pub library: PathBuf,
+    pub key: ShareKey,
+    /// Only serve titles through share links
+    pub shared_only: bool,
+    /// How users sign in; `None` lets anyone watch anything
+    pub auth: Option<AuthMode>,
+    /// Media files to prepare into the library when first watched
+    pub sources: Option<PathBuf>,
+    /// Offer titles with a fallback MP4 to DLNA renderers
+    pub dlna: bool,
+    /// Bandwidth and connection limits for remote viewers
+    pub limits: Limits,
+    /// Let viewers in a room share segments with each other over WebRTC
+    pub p2p: bool,
+    /// Key to decrypt titles encrypted at rest
+    pub library_key: Option<LibraryKey>,
+    /// Check every title before serving, marking broken ones unavailable
+    pub verify_on_start: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub(crate) library: PathBuf,
+    pub(crate) key: ShareKey,
+    pub(crate) rooms: Rooms,
+    pub(crate) auth: Option<AuthMode>,
+    pub(crate) jit: Option<Jit>,
+    /// Peers sharing segments in each room, when that's enabled
+    pub(crate) swarms: Option<Swarms>,
+    pub(crate) library_key: Option<LibraryKey>,
+    pub(crate) once: OnceShares,
+    /// What's wrong with each title found broken on start
+    pub(crate) unavailable: Arc<HashMap<String, String>>,
+}
+
+impl FromRef<AppState> for PathBuf {
+    fn from_ref(state: &AppState) -> Self {
+        state.library.clone()
+    }
+}
+
+impl FromRef<AppState> for Rooms {
+    fn from_ref(state: &AppState) -> Self {
+        state.rooms.clone()
+    }
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// A string literal safe to embed in a `<script>` block.
+fn js_string(text: &str) -> String {
+    // JSON strings are valid JS; escaping `<` keeps `</script>` out of them
+    serde_json::to_string(text).unwrap().replace('<', "\\u003c")
+}
+
+/// Percent-encode one URL path segment.
+pub(crate) fn encode_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Names and metadata of the prepared titles in the library, sorted by name.
+///
+/// Comes from the library's catalog, or from its directories if it was never scanned.
+pub(crate) fn titles(library: &Path) -> Vec<(String, Option<Metadata>)> {
+    match Catalog::open_existing(library) {
+        Ok(Some(catalog)) => match catalog.titles() {
+            // The catalog may only hold users so far
+            Ok(titles) if !titles.is_empty() => {
+                return titles
+                    .into_iter()
+                    .map(|title| (title.name, title.metadata))
+                    .collect();
+            }
+            Ok(_) => (),
+            Err(err) => eprintln!("Failed to read the library catalog: {:#}", err),
+        },
+        Ok(None) => (),
+        Err(err) => eprintln!("Failed to open the library catalog: {:#}", err),
+    }
+
+    let Ok(entries) = std::fs::read_dir(library) else {
+        return Vec::new();
+    };
+    let mut titles: Vec<_> = entries
+        .flatten()
+        .filter(|entry| entry.path().join(MANIFEST).is_file())
+        .filter_map(|entry| {
+            let metadata = Metadata::read(&entry.path()).ok().flatten();
+            Some((entry.file_name().into_string().ok()?, metadata))
+        })
+        .collect();
+    titles.sort_by(|a, b| a.0.cmp(&b.0));
+    titles
+}
+
+async fn index(State(state): State<AppState>, user: Option<Extension<User>>) -> Html<String> {
+    let mut titles = titles(&state.library);
+    // Titles that would be prepared on demand
+    if let Some(jit) = &state.jit {
+        for title in jit.titles() {
+            if !titles.iter().any(|(name, _)| *name == title) {
+                titles.push((title, None));
+            }
+        }
+        titles.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    let items: String = titles
+        .into_iter()
+        .filter(|(title, metadata)| {
+            let genres = metadata.as_ref().map_or(&[][..], |m| &m.genres);
+            let rating = metadata.as_ref().and_then(|m| m.rating.as_deref());
+            user.as_ref()
+                .is_none_or(|user| user.can_watch(title, genres, rating))
+        })
+        .map(|(title, metadata)| {
+            let label = metadata.as_ref().map_or(title.clone(), Metadata::label);
+            if let Some(problem) = state.unavailable.get(&title) {
+                return format!(
+                    "            <li>{} <em>(unavailable: {})</em></li>\n",
+                    escape_html(&label),
+                    escape_html(problem)
+                );
+            }
+            let overview = metadata
+                .and_then(|metadata| metadata.overview)
+                .map_or(String::new(), |overview| {
+                    format!("<p>{}</p>", escape_html(&overview))
+                });
+            format!(
+                "            <li><a href=\"/watch/{}\">{}</a>{}</li>\n",
+                encode_segment(&title),
+                escape_html(&label),
+                overview
+            )
+        })
+        .collect();
+    Html(format!(
+        r#"<!doctype html>
+<html lang="en">
+    <head>
+        <meta charset="UTF-8" />
+        <title>Library</title>
+        <link rel="alternate" type="application/rss+xml" title="New titles" href="/feed.xml" />
+        <link rel="alternate" type="application/feed+json" title="New titles" href="/feed.json" />
+    </head>
+    <body>
+        <h1>Library</h1>
+{}        <ul>
+{}        </ul>
+    </body>
+</html>
+"#,
+        user.map_or(String::new(), |user| format!(
+            "        <form method=\"post\" action=\"/logout\">{} <button>Sign out</button></form>\n",
+            escape_html(&user.name)
+        )),
+        items
+    ))
+}
+
+pub(crate) fn is_title(library: &Path, title: &str) -> bool {
+    !title.starts_with('.') && library.join(title).join(MANIFEST).is_file()
+}
+
+/// A 503 page saying what's wrong with `title`, if it was found broken.
+fn unavailable_page(state: &AppState, title: &str) -> Option<Response> {
+    let problem = state.unavailable.get(title)?;
+    let page = format!(
+        r#"<!doctype html>
+<html lang="en">
+    <head>
+        <meta charset="UTF-8" />
+        <title>{}</title>
+    </head>
+    <body>
+        <h1>{} is unavailable</h1>
+        <p>{}</p>
+    </body>
+</html>
+"#,
+        escape_html(title),
+        escape_html(title),
+        escape_html(problem)
+    );
+    Some((StatusCode::SERVICE_UNAVAILABLE, Html(page)).into_response())
+}
+
+/// The language a viewer's browser asks for.
+fn viewer_locale(headers: &HeaderMap) -> Locale {
+    headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(Locale::ENGLISH, Locale::from_accept_language)
+}
+
+fn player_page(
+    title: &str,
+    manifest: &str,
+    share_token: Option<&str>,
+    progress_url: Option<&str>,
+    p2p: bool,
+    poster: Option<&str>,
+    locale: Locale,
+) -> Html<String> {
+    let share_token = share_token.map_or(String::from("null"), js_string);
+    let progress_url = progress_url.map_or(String::from("null"), js_string);
+    let poster = poster.map_or(String::from("null"), js_string);
+    let messages = serde_json::to_string(&locale.player_messages())
+        .unwrap()
+        .replace('<', "\\u003c");
+    Html(
+        include_str!("player.html")
+            .replace("{{lang}}", locale.code())
+            .replace("{{messages}}", &messages)
+            .replace("{{title}}", &escape_html(title))
+            .replace("{{title_json}}", &js_string(title))
+            .replace("{{share_token}}", &share_token)
+            .replace("{{progress_url}}", &progress_url)
+            .replace("{{p2p}}", if p2p { "true" } else { "false" })
+            .replace("{{poster}}", &poster)
+            .replace("{{manifest}}", &js_string(manifest)),
+    )
+}
+
+async fn watch(
+    State(state): State<AppState>,
+    UrlPath(title): UrlPath<String>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    if !auth::may_watch(&state, user.as_deref(), &title) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if let Some(jit) = &state.jit {
+        match jit.ensure(&title).await {
+            Ok(()) | Err(JitError::NoSource) => (),
+            Err(JitError::NotReady) => return Err(StatusCode::SERVICE_UNAVAILABLE),
+            Err(JitError::Failed(_)) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+    if !is_title(&state.library, &title) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if let Some(page) = unavailable_page(&state, &title) {
+        return Ok(page);
+    }
+
+    let manifest = format!("/media/{}/{}", encode_segment(&title), MANIFEST);
+    let progress = format!("/progress/{}", encode_segment(&title));
+    Ok(player_page(
+        &title,
+        &manifest,
+        None,
+        Some(&progress),
+        state.swarms.is_some(),
+        None,
+        viewer_locale(&headers),
+    )
+    .into_response())
+}
+
+/// The title a share token grants and, for capped links, the tallest rung
+/// it may fetch, if it's valid and the title still exists.
+fn shared_link(state: &AppState, token: &str) -> Result<(String, Option<u32>), StatusCode> {
+    let now = SystemTime::now();
+    let (title, max_height) = match state.key.verify(token, now) {
+        Ok(title) => (title, None),
+        Err(_) => state
+            .key
+            .verify_capped(token, now)
+            .map(|(title, max_height)| (title, Some(max_height)))
+            .map_err(|_| StatusCode::FORBIDDEN)?,
+    };
+    if !is_title(&state.library, &title) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok((title, max_height))
+}
+
+/// The title a share token grants, if it's valid and the title still exists.
+pub(crate) fn shared_title(state: &AppState, token: &str) -> Result<String, StatusCode> {
+    shared_link(state, token).map(|(title, _)| title)
+}
+
+async fn shared_watch(
+    State(state): State<AppState>,
+    UrlPath(token): UrlPath<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let title = shared_title(&state, &token)?;
+    if let Some(page) = unavailable_page(&state, &title) {
+        return Ok(page);
+    }
+    let manifest = format!("/s/{}/{}", token, MANIFEST);
+    // Shown while listening only
+    let poster = POSTERS
+        .iter()
+        .find(|poster| state.library.join(&title).join(poster).is_file())
+        .map(|poster| format!("/s/{}/{}", token, poster));
+    Ok(player_page(
+        &title,
+        &manifest,
+        Some(&token),
+        None,
+        state.swarms.is_some(),
+        poster.as_deref(),
+        viewer_locale(&headers),
+    )
+    .into_response())
+}
+
+/// The name of the collection a share token grants and the titles in it
+/// that exist, if the token is valid.
+fn shared_collection(state: &AppState, token: &str) -> Result<(String, Vec<String>), StatusCode> {
+    let name = state
+        .key
+        .verify_collection(token, SystemTime::now())
+        .map_err(|_| StatusCode::FORBIDDEN)?;
+    let collection = match Catalog::open_existing(&state.library) {
+        Ok(Some(catalog)) => catalog.collection(&name),
+        Ok(None) => Ok(None),
+        Err(err) => Err(err),
+    }
+    .map_err(|err| {
+        eprintln!("Failed to read collection {}: {:#}", name, err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    let titles = collection
+        .titles
+        .into_iter()
+        .filter(|title| is_title(&state.library, title))
+        .collect();
+    Ok((name, titles))
+}
+
+async fn collection_index(
+    State(state): State<AppState>,
+    UrlPath(token): UrlPath<String>,
+) -> Result<Html<String>, StatusCode> {
+    let (name, titles) = shared_collection(&state, &token)?;
+    let items: String = titles
+        .iter()
+        .map(|title| {
+            let label = Metadata::read(&state.library.join(title))
+                .ok()
+                .flatten()
+                .map_or(title.clone(), |metadata| metadata.label());
+            format!(
+                "            <li><a href=\"/c/{}/{}/\">{}</a></li>\n",
+                token,
+                encode_segment(title),
+                escape_html(&label)
+            )
+        })
+        .collect();
+    Ok(Html(format!(
+        r#"<!doctype html>
+<html lang="en">
+    <head>
+        <meta charset="UTF-8" />
+        <title>{0}</title>
+    </head>
+    <body>
+        <h1>{0}</h1>
+        <ul>
+{1}        </ul>
+    </body>
+</html>
+"#,
+        escape_html(&name),
+        items
+    )))
+}
+
+/// A title of a shared collection, if it's in the collection.
+fn collection_title(state: &AppState, token: &str, title: &str) -> Result<(), StatusCode> {
+    let (_, titles) = shared_collection(state, token)?;
+    match titles.iter().any(|t| t == title) {
+        true => Ok(()),
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn collection_watch(
+    State(state): State<AppState>,
+    UrlPath((token, title)): UrlPath<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    collection_title(&state, &token, &title)?;
+    if let Some(page) = unavailable_page(&state, &title) {
+        return Ok(page);
+    }
+    let manifest = format!("/c/{}/{}/{}", token, encode_segment(&title), MANIFEST);
+    Ok(player_page(
+        &title,
+        &manifest,
+        None,
+        None,
+        state.swarms.is_some(),
+        None,
+        viewer_locale(&headers),
+    )
+    .into_response())
+}
+
+async fn collection_file(
+    State(state): State<AppState>,
+    UrlPath((token, title, file)): UrlPath<(String, String, String)>,
+    request: Request,
+) -> Result<Response, StatusCode> {
+    collection_title(&state, &token, &title)?;
+    title_file(&state, &title, &file, request).await
+}
+
+/// The title and id of a single-viewing link, if it's valid and the title still exists.
+fn once_title(state: &AppState, token: &str) -> Result<(String, String), StatusCode> {
+    let (id, title) = state
+        .key
+        .verify_once(token, SystemTime::now())
+        .map_err(|_| StatusCode::FORBIDDEN)?;
+    if !is_title(&state.library, &title) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok((id, title))
+}
+
+async fn once_watch(
+    State(state): State<AppState>,
+    UrlPath(token): UrlPath<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let (id, title) = once_title(&state, &token)?;
+    // Before claiming, so the single viewing isn't spent on a broken title
+    if let Some(page) = unavailable_page(&state, &title) {
+        return Ok(page);
+    }
+    let claimed = state
+        .once
+        .claim(&id, &headers, || read_manifest(&state, &title))?;
+    let manifest = format!("/o/{}/{}", token, MANIFEST);
+    // Without a share token, the page can't open rooms for others to join
+    let page = player_page(
+        &title,
+        &manifest,
+        None,
+        None,
+        false,
+        None,
+        viewer_locale(&headers),
+    );
+    Ok(match claimed {
+        Some(session) => {
+            let cookie = once::set_cookie(&session, &format!("/o/{}/", token));
+            ([(header::SET_COOKIE, cookie)], page).into_response()
+        }
+        None => page.into_response(),
+    })
+}
+
+async fn once_file(
+    State(state): State<AppState>,
+    UrlPath((token, file)): UrlPath<(String, String)>,
+    request: Request,
+) -> Result<Response, StatusCode> {
+    let (id, title) = once_title(&state, &token)?;
+    state.once.check(&id, &file, request.headers())?;
+    title_file(&state, &title, &file, request).await
+}
+
+async fn shared_file(
+    State(state): State<AppState>,
+    UrlPath((token, file)): UrlPath<(String, String)>,
+    request: Request,
+) -> Result<Response, StatusCode> {
+    let (title, max_height) = shared_link(&state, &token)?;
+    if file == LISTEN_MANIFEST {
+        return listen_file(&state, &title).map(IntoResponse::into_response);
+    }
+    match max_height {
+        Some(max_height) => capped_file(&state, &title, max_height, &file, request).await,
+        None => title_file(&state, &title, &file, request).await,
+    }
+}
+
+/// The manifest of `title`, decrypted if it was encrypted at rest.
+fn read_manifest(state: &AppState, title: &str) -> Result<String> {
+    let path = state.library.join(title).join(MANIFEST);
+    if let Some(key) = &state.library_key
+        && encrypt::is_encrypted(&path)
+    {
+        return Ok(String::from_utf8(key.open_file(&path, MANIFEST)?)?);
+    }
+    std::fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))
+}
+
+/// The manifest of `title` without any video, for listening to it alone.
+fn listen_manifest(xml: &str) -> Result<String> {
+    let manifest = mpd::parse(xml)?;
+    if !manifest
+        .representations
+        .iter()
+        .any(|representation| representation.content_type == "audio")
+    {
+        bail!("The title has no audio to listen to");
+    }
+    let video: Vec<String> = manifest
+        .representations
+        .into_iter()
+        .filter(|representation| representation.content_type == "video")
+        .map(|representation| representation.id)
+        .collect();
+    mpd::drop_representations(xml, &video)
+}
+
+fn listen_file(state: &AppState, title: &str) -> Result<String, StatusCode> {
+    read_manifest(state, title)
+        .and_then(|xml| listen_manifest(&xml))
+        .map_err(|err| {
+            eprintln!("Failed to leave the video out of {}: {:#}", title, err);
+            StatusCode::NOT_FOUND
+        })
+}
+
+/// A manifest without its video rungs taller than `max_height`, and the
+/// files of the rungs left out.
+fn capped_manifest(xml: &str, max_height: u32) -> Result<(String, HashSet<String>)> {
+    let manifest = mpd::parse(xml)?;
+    let (kept, dropped): (Vec<_>, Vec<_>) = manifest
+        .representations
+        .iter()
+        .filter(|representation| representation.content_type == "video")
+        .partition(|representation| representation.height.is_none_or(|h| h <= max_height));
+    if kept.is_empty() {
+        bail!("The title has no rung of {}p or less", max_height);
+    }
+    let mut withheld = HashSet::from([FALLBACK.to_string()]);
+    let mut ids = Vec::new();
+    for representation in dropped {
+        let list = mpd::segment_list(xml, &representation.id)?;
+        withheld.extend(list.initialization);
+        withheld.extend(list.segments.into_iter().map(|segment| segment.media));
+        ids.push(representation.id.clone());
+    }
+    Ok((mpd::drop_representations(xml, &ids)?, withheld))
+}
+
+/// One of a title's files as a link capped at `max_height` sees them: the
+/// manifest holds no taller rungs, and their segments can't be fetched.
+async fn capped_file(
+    state: &AppState,
+    title: &str,
+    max_height: u32,
+    file: &str,
+    request: Request,
+) -> Result<Response, StatusCode> {
+    let (manifest, withheld) = read_manifest(state, title)
+        .and_then(|xml| capped_manifest(&xml, max_height))
+        .map_err(|err| {
+            eprintln!("Failed to cap {} at {}p: {:#}", title, max_height, err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if file == MANIFEST {
+        return Ok(manifest.into_response());
+    }
+    // Checked before the lookup, or `./` in front of a withheld file would
+    // get it past
+    if !is_plain_file(file) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if withheld.contains(file) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    title_file(state, title, file, request).await
+}
+
+/// Files of an encrypted library, which are decrypted rather than served as is.
+async fn media_file(
+    State(state): State<AppState>,
+    UrlPath((title, file)): UrlPath<(String, String)>,
+    request: Request,
+) -> Result<Response, StatusCode> {
+    title_file(&state, &title, &file, request).await
+}
+
+/// Whether `file` names a file under a title by plain names alone, with no
+/// empty, `.` or `..` parts that could make it another file.
+fn is_plain_file(file: &str) -> bool {
+    file.split('/')
+        .all(|part| !matches!(part, "" | "." | "..") && !part.contains('\\'))
+}
+
+/// One of a title's files, decrypted if it was encrypted at rest.
+async fn title_file(
+    state: &AppState,
+    title: &str,
+    file: &str,
+    mut request: Request,
+) -> Result<Response, StatusCode> {
+    // Path parameters come percent-decoded, so a title can hold a `/`
+    if title.contains(['/', '\\']) || !is_title(&state.library, title) || !is_plain_file(file) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let dir = state.library.join(title);
+    if let Some(key) = &state.library_key {
+        let path = dir.join(file);
+        if encrypt::is_encrypted(&path) {
+            return encrypt::respond(key.clone(), path, file.to_string(), request.headers()).await;
+        }
+    }
+
+    // Serve the file as if the title directory were the root
+    let path: Vec<_> = file.split('/').map(encode_segment).collect();
+    *request.uri_mut() = format!("/{}", path.join("/"))
+        .parse()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let response = ServeDir::new(dir)
+        .oneshot(request)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(response.into_response())
+}
+
+#[derive(Deserialize)]
+struct CreateRoom {
+    title: Option<String>,
+    /// Open the room through a share link instead of by title
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RoomCreated {
+    id: String,
+    /// Path of the player page that joins the room
+    link: String,
+}
+
+async fn create_room(
+    State(state): State<AppState>,
+    user: Option<Extension<User>>,
+    Json(request): Json<CreateRoom>,
+) -> Result<(StatusCode, Json<RoomCreated>), StatusCode> {
+    let (title, page) = match (request.token, request.title) {
+        (Some(token), _) => (shared_title(&state, &token)?, format!("/s/{}/", token)),
+        (None, Some(title)) => {
+            if !is_title(&state.library, &title)
+                || !auth::may_watch(&state, user.as_deref(), &title)
+            {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            let page = format!("/watch/{}", encode_segment(&title));
+            (title, page)
+        }
+        (None, None) => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let id = state.rooms.create(&title);
+    let link = format!("{}?room={}", page, id);
+    Ok((StatusCode::CREATED, Json(RoomCreated { id, link })))
+}
+
+/// `POST /handoff/{code}`: where to pick up a session handed off from
+/// another device.
+async fn redeem_handoff(
+    State(state): State<AppState>,
+    UrlPath(code): UrlPath<String>,
+    request: Request,
+) -> Result<Json<sync::Redeemed>, StatusCode> {
+    // Without connection info (as in tests) every client counts as one
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip());
+    state.rooms.redeem(&code, client).map(Json)
+}
+
+/// Rooms may only be opened through share links.
+async fn create_shared_room(
+    state: State<AppState>,
+    Json(request): Json<CreateRoom>,
+) -> Result<(StatusCode, Json<RoomCreated>), StatusCode> {
+    if request.token.is_none() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    create_room(state, None, Json(request)).await
+}
+
+pub fn router(config: &ServeConfig) -> Result<Router> {
+    // Players on other origins (casting receivers, hosted players) fetch with Range
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods([Method::GET, Method::HEAD])
+        .allow_headers([header::RANGE])
+        .expose_headers([
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::ACCEPT_RANGES,
+        ]);
+
+    let state = AppState {
+        library: config.library.clone(),
+        key: config.key.clone(),
+        rooms: Rooms::default(),
+        auth: config.auth.clone(),
+        jit: config
+            .sources
+            .clone()
+            .map(|sources| Jit::new(sources, config.library.clone())),
+        swarms: config.p2p.then(Swarms::default),
+        library_key: config.library_key.clone(),
+        once: OnceShares::load(&config.library)?,
+        unavailable: Arc::new(match config.verify_on_start {
+            true => verify_library(&config.library)?,
+            false => HashMap::new(),
+        }),
+    };
+    let shared = Router::new()
+        .route("/s/{token}/", get(shared_watch))
+        .route("/c/{token}/", get(collection_index))
+        .route("/c/{token}/{title}/", get(collection_watch))
+        .route("/c/{token}/{title}/{*file}", get(collection_file))
+        .route("/o/{token}/", get(once_watch))
+        .route("/o/{token}/{*file}", get(once_file))
+        .route("/s/{token}/analytics", post(analytics::shared_report))
+        .route("/s/{token}/{*file}", get(shared_file))
+        .route("/sync/{room}", get(sync::connect))
+        .route("/handoff/{code}", post(redeem_handoff))
+        .route("/peers/{room}", get(swarm::connect));
+    let router = if config.shared_only {
+        shared.route("/rooms", post(create_shared_room))
+    } else {
+        let media = match config.library_key {
+            Some(_) => Router::new().route("/media/{title}/{*file}", get(media_file)),
+            None => Router::new().nest_service("/media", ServeDir::new(&config.library)),
+        };
+        let private = media
+            .route("/", get(index))
+            .route("/watch/{title}", get(watch))
+            .route("/rooms", post(create_room))
+            .route("/feed.xml", get(feed::rss))
+            .route("/feed.json", get(feed::json_feed))
+            .route("/api/titles", get(api::list))
+            .route("/api/titles/{id}", get(api::get))
+            .route("/api/titles/{id}/thumbnail", get(api::thumbnail))
+            .route("/api/titles/{id}/files", get(api::files))
+            .route("/api/search", get(api::search))
+            .route("/analytics", post(analytics::report))
+            .route("/progress", get(progress::list))
+            .route("/progress/{title}", get(progress::get).put(progress::save))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::authenticate,
+            ))
+            .route("/login", get(auth::login_page).post(auth::login))
+            .route("/logout", post(auth::logout));
+        shared.merge(private)
+    };
+
+    let router = match config.dlna {
+        true => router.merge(dlna::router()),
+        false => router,
+    };
+
+    let router = router.with_state(state);
+    let router = router
+        .layer(middleware::from_fn(set_content_type))
+        .layer(cors);
+    Ok(match config.limits.is_empty() {
+        true => router,
+        false => router.layer(middleware::from_fn_with_state(
+            Throttle::new(config.limits.clone()),
+            throttle::limit,
+        )),
+    })
+}
+
+/// Check every title of `library`, reporting the broken ones.
+fn verify_library(library: &Path) -> Result<HashMap<String, String>> {
+    let unavailable = verify::check_library(library)?;
+    let mut broken: Vec<_> = unavailable.iter().collect();
+    broken.sort();
+    for (title, problem) in broken {
+        eprintln!("Unavailable: {}: {}", title, problem);
+    }
+    println!(
+        "Verified the library; {} titles are unavailable",
+        unavailable.len()
+    );
+    Ok(unavailable)
+}
+
+/// PEM files for serving over HTTPS.
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Serve the library until the process is stopped.
+pub fn serve(config: ServeConfig, addr: SocketAddr, tls: Option<TlsFiles>) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        if config.dlna {
+            let library = config.library.clone();
+            tokio::spawn(async move {
+                if let Err(err) = dlna::advertise(&library, addr).await {
+                    eprintln!("DLNA announcements stopped: {:#}", err);
+                }
+            });
+        }
+        let Some(tls) = tls else {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .context(format!("Failed to listen on {}", addr))?;
+            println!("Serving {} on http://{}/", config.library.display(), addr);
+            // Limits tell clients apart by address
+            let service = router(&config)?.into_make_service_with_connect_info::<SocketAddr>();
+            return axum::serve(listener, service)
+                .await
+                .context(format!("HTTP server on {} failed", addr));
+        };
+
+        // Only one provider is compiled in, but rustls still wants it chosen explicitly
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+            .await
+            .context(format!(
+                "Failed to load TLS certificate {} and key {}",
+                tls.cert.display(),
+                tls.key.display()
+            ))?;
+        println!("Serving {} on https://{}/", config.library.display(), addr);
+        axum_server::bind_rustls(addr, rustls)
+            .serve(router(&config)?.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .context(format!("HTTPS server on {} failed", addr))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use http_body_util::BodyExt;
+    use std::time::Duration;
+
+    fn config(shared_only: bool) -> ServeConfig {
+        let dir = std::env::temp_dir().join(format!("movieshare-serve-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("movie")).unwrap();
+        std::fs::write(dir.join("movie/manifest.mpd"), "<MPD/>").unwrap();
+        std::fs::write(dir.join("movie/chunk-0-1.m4s"), "0123456789").unwrap();
+        ServeConfig {
+            key: ShareKey::load_or_create(&dir).unwrap(),
+            library: dir,
+            shared_only,
+            auth: None,
+            sources: None,
+            dlna: false,
+            limits: Limits::default(),
+            p2p: false,
+            library_key: None,
+            verify_on_start: false,
+        }
+    }
+
+    fn router_for_library() -> Router {
+        router(&config(false)).unwrap()
+    }
+
+    async fn get(router: Router, uri: &str) -> Response {
+        router
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_manifest_with_dash_mime_type_and_cors() {
+        let response = router_for_library()
+            .oneshot(
+                Request::get("/media/movie/manifest.mpd")
+                    .header(header::ORIGIN, "http://tv.local")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/dash+xml"
+        );
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[tokio::test]
+    async fn broken_titles_are_unavailable() {
+        let dir =
+            std::env::temp_dir().join(format!("movieshare-serve-verify-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("broken")).unwrap();
+        std::fs::write(
+            dir.join("broken/manifest.mpd"),
+            r#"<MPD><Period><AdaptationSet contentType="text"><Representation id="0" bandwidth="1"><BaseURL>subtitles_0.vtt</BaseURL></Representation></AdaptationSet></Period></MPD>"#,
+        )
+        .unwrap();
+        let config = ServeConfig {
+            library: dir.clone(),
+            verify_on_start: true,
+            ..config(false)
+        };
+        let router = router(&config).unwrap();
+
+        let response = get(router.clone(), "/watch/broken").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("subtitles_0.vtt is missing"));
+        let body = get(router, "/")
+            .await
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("broken <em>(unavailable"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_segment_ranges() {
+        let response = router_for_library()
+            .oneshot(
+                Request::get("/media/movie/chunk-0-1.m4s")
+                    .header(header::RANGE, "bytes=2-5")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "video/iso.segment"
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"2345");
+    }
+
+    #[tokio::test]
+    async fn decrypts_encrypted_titles() {
+        let dir =
+            std::env::temp_dir().join(format!("movieshare-serve-encrypted-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("library/movie")).unwrap();
+        std::fs::write(dir.join("library/movie/manifest.mpd"), "<MPD/>").unwrap();
+        std::fs::write(dir.join("library/movie/chunk-0-1.m4s"), "0123456789").unwrap();
+        let key = LibraryKey::load_or_create(&dir.join("key")).unwrap();
+        assert_eq!(key.encrypt_dir(&dir.join("library/movie")).unwrap(), 2);
+        let config = ServeConfig {
+            library_key: Some(key),
+            verify_on_start: false,
+            library: dir.join("library"),
+            ..config(false)
+        };
+
+        let response = router(&config)
+            .unwrap()
+            .oneshot(
+                Request::get("/media/movie/chunk-0-1.m4s")
+                    .header(header::RANGE, "bytes=2-5")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"2345");
+
+        let response = get(router(&config).unwrap(), "/media/movie/manifest.mpd").await;
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/dash+xml"
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"<MPD/>");
+        let response = get(
+            router(&config).unwrap(),
+            "/media/movie/../movie/manifest.mpd",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        // The title comes percent-decoded, and this one is outside the library
+        std::fs::write(dir.join("secret"), "key").unwrap();
+        let response = get(router(&config).unwrap(), "/media/movie%2F..%2F../secret").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn player_page_points_at_manifest() {
+        let response = router_for_library()
+            .oneshot(Request::get("/watch/movie").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let page = String::from_utf8(body.to_vec()).unwrap();
+        assert!(page.contains("player.load(\"/media/movie/manifest.mpd\", startTime)"));
+        assert!(page.contains("const PROGRESS_URL = \"/progress/movie\";"));
+        assert!(page.contains("<html lang=\"en\">"));
+
+        let response = router_for_library()
+            .oneshot(
+                Request::get("/watch/movie")
+                    .header(header::ACCEPT_LANGUAGE, "fr-CA, en;q=0.5")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let page = String::from_utf8(body.to_vec()).unwrap();
+        assert!(page.contains("<html lang=\"fr\">"));
+        assert!(page.contains("\"player-watch-together\":\"Regarder ensemble\""));
+
+        let response = router_for_library()
+            .oneshot(Request::get("/watch/missing").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn encodes_title_segments() {
+        assert_eq!(encode_segment("Alien (1979)"), "Alien%20%281979%29");
+        assert_eq!(escape_html("<b>&"), "&lt;b&gt;&amp;");
+    }
+
+    #[tokio::test]
+    async fn share_links_grant_one_title() {
+        let config = config(true);
+        let token = config
+            .key
+            .sign("movie", SystemTime::now() + Duration::from_secs(60));
+
+        let response = get(
+            router(&config).unwrap(),
+            &format!("/s/{}/manifest.mpd", token),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/dash+xml"
+        );
+
+        let response = get(router(&config).unwrap(), &format!("/s/{}/", token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let expired = config
+            .key
+            .sign("movie", SystemTime::now() - Duration::from_secs(1));
+        let response = get(
+            router(&config).unwrap(),
+            &format!("/s/{}/manifest.mpd", expired),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Nothing else is reachable without a token
+        let response = get(router(&config).unwrap(), "/media/movie/manifest.mpd").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get(router(&config).unwrap(), "/").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn capped_links_hold_back_taller_rungs() {
+        let library =
+            std::env::temp_dir().join(format!("movieshare-serve-capped-{}", std::process::id()));
+        let dir = library.join("ladder");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD mediaPresentationDuration="PT4S"><Period>
+                <AdaptationSet contentType="video">
+                    <Representation id="0" bandwidth="6000000" width="1920" height="1080">
+                        <SegmentList timescale="1000" duration="4000">
+                            <Initialization sourceURL="video_0_init.mp4"/>
+                            <SegmentURL media="video_0_00001.m4s"/>
+                        </SegmentList>
+                    </Representation>
+                    <Representation id="1" bandwidth="2000000" width="1280" height="720">
+                        <SegmentList timescale="1000" duration="4000">
+                            <Initialization sourceURL="video_1_init.mp4"/>
+                            <SegmentURL media="video_1_00001.m4s"/>
+                        </SegmentList>
+                    </Representation>
+                </AdaptationSet>
+            </Period></MPD>"#,
+        )
+        .unwrap();
+        for file in ["video_0_00001.m4s", "video_1_00001.m4s"] {
+            std::fs::write(dir.join(file), "data").unwrap();
+        }
+        let config = ServeConfig {
+            key: ShareKey::load_or_create(&library).unwrap(),
+            library: library.clone(),
+            ..config(true)
+        };
+        let token =
+            config
+                .key
+                .sign_capped("ladder", 720, SystemTime::now() + Duration::from_secs(60));
+
+        let response = get(
+            router(&config).unwrap(),
+            &format!("/s/{}/manifest.mpd", token),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let manifest = mpd::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+        let heights: Vec<_> = manifest.representations.iter().map(|r| r.height).collect();
+        assert_eq!(heights, [Some(720)]);
+
+        let fetch = |file: &str| {
+            let uri = format!("/s/{}/{}", token, file);
+            let router = router(&config).unwrap();
+            async move { get(router, &uri).await.status() }
+        };
+        assert_eq!(fetch("video_1_00001.m4s").await, StatusCode::OK);
+        assert_eq!(fetch("video_0_00001.m4s").await, StatusCode::FORBIDDEN);
+        assert_eq!(fetch("./video_0_00001.m4s").await, StatusCode::NOT_FOUND);
+        assert_eq!(fetch(".//video_0_00001.m4s").await, StatusCode::NOT_FOUND);
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+
+    #[tokio::test]
+    async fn listen_mode_leaves_out_the_video() {
+        let library =
+            std::env::temp_dir().join(format!("movieshare-serve-listen-{}", std::process::id()));
+        let dir = library.join("concert");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD mediaPresentationDuration="PT4S"><Period>
+                <AdaptationSet contentType="video">
+                    <Representation id="0" bandwidth="2000000" width="1280" height="720"/>
+                </AdaptationSet>
+                <AdaptationSet contentType="audio" lang="en">
+                    <Representation id="1" bandwidth="128000"/>
+                </AdaptationSet>
+            </Period></MPD>"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("poster.jpg"), "jpeg").unwrap();
+        let config = ServeConfig {
+            key: ShareKey::load_or_create(&library).unwrap(),
+            library: library.clone(),
+            ..config(true)
+        };
+        let token = config
+            .key
+            .sign("concert", SystemTime::now() + Duration::from_secs(60));
+
+        let response = get(
+            router(&config).unwrap(),
+            &format!("/s/{}/listen.mpd", token),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/dash+xml"
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let manifest = mpd::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+        let kinds: Vec<_> = manifest
+            .representations
+            .iter()
+            .map(|r| r.content_type.as_str())
+            .collect();
+        assert_eq!(kinds, ["audio"]);
+
+        let response = get(router(&config).unwrap(), &format!("/s/{}/", token)).await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let page = String::from_utf8(body.to_vec()).unwrap();
+        assert!(page.contains(&format!("const POSTER = \"/s/{}/poster.jpg\";", token)));
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+
+    #[tokio::test]
+    async fn once_links_serve_one_browser() {
+        let config = config(true);
+        let token = config
+            .key
+            .sign_once("movie", SystemTime::now() + Duration::from_secs(60));
+        let app = router(&config).unwrap();
+
+        // Files are only for the browser that opened the player page
+        let response = get(app.clone(), &format!("/o/{}/manifest.mpd", token)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = get(app.clone(), &format!("/o/{}/", token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get(format!("/o/{}/manifest.mpd", token))
+                    .header(header::COOKIE, &cookie)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Nor do they get the whole title at once
+        std::fs::write(config.library.join("movie").join(FALLBACK), "mp4").unwrap();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get(format!("/o/{}/{}", token, FALLBACK))
+                    .header(header::COOKIE, &cookie)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get(app, &format!("/o/{}/", token)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Nor is it an ordinary share link
+        let response = get(router(&config).unwrap(), &format!("/s/{}/", token)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn once_links_open_encrypted_titles() {
+        let dir = std::env::temp_dir().join(format!(
+            "movieshare-serve-once-sealed-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(dir.join("library/movie")).unwrap();
+        let xml = r#"<MPD mediaPresentationDuration="PT4S"><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="1000000"><SegmentList timescale="1000" duration="4000"><SegmentURL media="chunk-0-1.m4s"/></SegmentList></Representation></AdaptationSet></Period></MPD>"#;
+        std::fs::write(dir.join("library/movie/manifest.mpd"), xml).unwrap();
+        std::fs::write(dir.join("library/movie/chunk-0-1.m4s"), "0123456789").unwrap();
+        let key = LibraryKey::load_or_create(&dir.join("key")).unwrap();
+        assert_eq!(key.encrypt_dir(&dir.join("library/movie")).unwrap(), 2);
+        let config = ServeConfig {
+            library_key: Some(key),
+            library: dir.join("library"),
+            ..config(true)
+        };
+        let token = config
+            .key
+            .sign_once("movie", SystemTime::now() + Duration::from_secs(60));
+        let app = router(&config).unwrap();
+
+        let response = get(app.clone(), &format!("/o/{}/", token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        for (file, body) in [("manifest.mpd", xml), ("chunk-0-1.m4s", "0123456789")] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::get(format!("/o/{}/{}", token, file))
+                        .header(header::COOKIE, &cookie)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let bytes = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(&bytes[..], body.as_bytes());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn does_not_escape_the_library() {
+        let response = router_for_library()
+            .oneshot(
+                Request::get("/media/../Cargo.toml")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        // Nor through a title with an encoded slash in it
+        let response = get(
+            router_for_library(),
+            "/media/movie%2F..%2F..%2F..%2F..%2F..%2Fetc/passwd",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn feeds_link_to_player_pages() {
+        let response = router_for_library()
+            .oneshot(
+                Request::get("/feed.xml")
+                    .header(header::HOST, "media.local:8080")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let rss = String::from_utf8(body.to_vec()).unwrap();
+        assert!(rss.contains("<link>http://media.local:8080/watch/movie</link>"));
+
+        let response = get(router_for_library(), "/feed.json").await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let feed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(feed["items"][0]["title"], "movie");
+    }
+
+    #[tokio::test]
+    async fn api_describes_titles() {
+        let response = get(router_for_library(), "/api/titles").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let titles: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(titles[0]["id"], "movie");
+
+        let response = get(router_for_library(), "/api/titles/movie").await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let title: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            title["manifest_url"],
+            "http://localhost/media/movie/manifest.mpd"
+        );
+
+        let response = get(router_for_library(), "/api/titles/nothing").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn dlna_browses_fallback_files() {
+        let dir = std::env::temp_dir().join(format!("movieshare-dlna-{}", std::process::id()));
+        for title in ["movie", "dash-only"] {
+            std::fs::create_dir_all(dir.join(title)).unwrap();
+            std::fs::write(dir.join(title).join(MANIFEST), "<MPD/>").unwrap();
+        }
+        std::fs::write(dir.join("movie/fallback.mp4"), "0123456789").unwrap();
+        let config = ServeConfig {
+            key: ShareKey::load_or_create(&dir).unwrap(),
+            library: dir.clone(),
+            shared_only: false,
+            auth: None,
+            sources: None,
+            dlna: true,
+            limits: Limits::default(),
+            p2p: false,
+            library_key: None,
+            verify_on_start: false,
+        };
+
+        let browse = r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:Browse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1"><ObjectID>0</ObjectID><BrowseFlag>BrowseDirectChildren</BrowseFlag><StartingIndex>0</StartingIndex><RequestedCount>0</RequestedCount></u:Browse></s:Body></s:Envelope>"#;
+        let response = router(&config)
+            .unwrap()
+            .oneshot(
+                Request::post("/dlna/control/ContentDirectory")
+                    .header(header::HOST, "10.0.0.2:8080")
+                    .header(
+                        "SOAPACTION",
+                        "\"urn:schemas-upnp-org:service:ContentDirectory:1#Browse\"",
+                    )
+                    .body(Body::from(browse))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<NumberReturned>1</NumberReturned>"));
+        assert!(body.contains("http://10.0.0.2:8080/dlna/media/movie"));
+        assert!(!body.contains("dash-only"));
+
+        let response = get(router(&config).unwrap(), "/dlna/media/movie").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "video/mp4");
+        let response = get(router(&config).unwrap(), "/dlna/media/dash-only").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        // Off unless asked for
+        let response = get(router_for_library(), "/dlna/description.xml").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn auth_limits_titles_to_grants() {
+        let dir = std::env::temp_dir().join(format!("movieshare-auth-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("movie")).unwrap();
+        std::fs::write(dir.join("movie/manifest.mpd"), "<MPD/>").unwrap();
+        let mut catalog = Catalog::open(&Catalog::default_path(&dir)).unwrap();
+        catalog.add_user("adult", None, false).unwrap();
+        catalog.add_user("kid", None, true).unwrap();
+        let config = ServeConfig {
+            key: ShareKey::load_or_create(&dir).unwrap(),
+            library: dir.clone(),
+            shared_only: false,
+            auth: Some(AuthMode::Password),
+            sources: None,
+            dlna: false,
+            limits: Limits::default(),
+            p2p: false,
+            library_key: None,
+            verify_on_start: false,
+        };
+
+        let as_user = |user: &str, uri: &str| {
+            let session = config
+                .key
+                .sign_session(user, SystemTime::now() + Duration::from_secs(60));
+            router(&config).unwrap().oneshot(
+                Request::get(uri)
+                    .header(header::COOKIE, format!("movieshare_session={}", session))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get(router(&config).unwrap(), "/").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let response = get(router(&config).unwrap(), "/media/movie/manifest.mpd").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = as_user("adult", "/media/movie/manifest.mpd").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = as_user("kid", "/media/movie/manifest.mpd").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = as_user("kid", "/watch/movie").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        catalog
+            .grant("kid", crate::users::GrantKind::Title, "movie")
+            .unwrap();
+        let response = as_user("kid", "/watch/movie").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Share links still work without signing in
+        let token = config
+            .key
+            .sign("movie", SystemTime::now() + Duration::from_secs(60));
+        let response = get(router(&config).unwrap(), &format!("/s/{}/", token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+//! Signed, expiring links to a single title.
+//!
+//! A token names one title and an expiry time, signed with HMAC-SHA256
+//! under a key kept in the library. Tokens travel in the URL path
+//! (`/s/<token>/...`) so the relative segment URLs in a manifest carry the
+//! token along without any player support.
+//!
+//! Single-viewing links carry an id as well, to tell when each is spent;
+//! see [`crate::once`]. Collection links name a collection rather than a
+//! title, and grant whatever titles it holds when they're used.
+//!
+//! Capped links carry the tallest video rung they may fetch, for viewers on
+//! metered connections; the server leaves the taller rungs out of the
+//! manifest it gives them and refuses their segments.
+
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use image::Luma;
+use qrcode::QrCode;
+use qrcode::render::unicode::Dense1x2;
+use sha2::Sha256;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const KEY_FILENAME: &str = ".movieshare-share-key";
+/// Where `preparer share` saves a scannable copy of the link, in the title's directory.
+pub const QR_CODE: &str = "share-qr.png";
+
+#[derive(Clone)]
+pub struct ShareKey {
+    key: Vec<u8>,
+}
+
+impl ShareKey {
+    /// Read the library's signing key, creating one on first use.
+    pub fn load_or_create(library: &Path) -> Result<Self> {
+        let path = library.join(KEY_FILENAME);
+        if path.exists() {
+            let key = std::fs::read(&path)
+                .context(format!("Failed to read share key: {}", path.display()))?;
+            if key.len() < 32 {
+                bail!("Share key is too short: {}", path.display());
+            }
+            return Ok(Self { key });
+        }
+
+        let key: [u8; 32] = rand::random();
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .context(format!("Failed to create share key: {}", path.display()))?;
+        file.write_all(&key)?;
+        Ok(Self { key: key.to_vec() })
+    }
+
+    fn mac(&self, title: &str, expires: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(title.as_bytes());
+        mac.update(b"\n");
+        mac.update(expires.to_string().as_bytes());
+        mac
+    }
+
+    /// Like [`mac`](Self::mac), but for tokens that grant something other
+    /// than a title, like a signed-in user's session; the leading NUL can't
+    /// start a title, so tokens of different scopes never verify as each other.
+    fn scoped_mac(&self, scope: &str, payload: &str, expires: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(b"\0");
+        mac.update(scope.as_bytes());
+        mac.update(b"\n");
+        mac.update(payload.as_bytes());
+        mac.update(b"\n");
+        mac.update(expires.to_string().as_bytes());
+        mac
+    }
+
+    pub fn sign(&self, title: &str, expires: SystemTime) -> String {
+        encode(title, expires, |expires| self.mac(title, expires))
+    }
+
+    /// The title a token grants access to, if it is genuine and unexpired.
+    pub fn verify(&self, token: &str, now: SystemTime) -> Result<String> {
+        decode(token, now, |title, expires| self.mac(title, expires)).context("Invalid share link")
+    }
+
+    /// A link to `title` that works for one viewing, under a fresh id.
+    pub fn sign_once(&self, title: &str, expires: SystemTime) -> String {
+        let payload = format!("{:016x}/{}", rand::random::<u64>(), title);
+        encode(&payload, expires, |expires| {
+            self.scoped_mac("once", &payload, expires)
+        })
+    }
+
+    /// The id and title of a single-viewing link, if it is genuine and unexpired.
+    pub fn verify_once(&self, token: &str, now: SystemTime) -> Result<(String, String)> {
+        let payload = decode(token, now, |payload, expires| {
+            self.scoped_mac("once", payload, expires)
+        })
+        .context("Invalid share link")?;
+        let (id, title) = payload.split_once('/').context("Invalid share link")?;
+        Ok((id.to_string(), title.to_string()))
+    }
+
+    /// A link to `title` that only reaches video rungs up to `max_height`
+    /// lines tall.
+    pub fn sign_capped(&self, title: &str, max_height: u32, expires: SystemTime) -> String {
+        let payload = format!("{}/{}", max_height, title);
+        encode(&payload, expires, |expires| {
+            self.scoped_mac("capped", &payload, expires)
+        })
+    }
+
+    /// The title and tallest rung of a capped link, if it is genuine and
+    /// unexpired.
+    pub fn verify_capped(&self, token: &str, now: SystemTime) -> Result<(String, u32)> {
+        let payload = decode(token, now, |payload, expires| {
+            self.scoped_mac("capped", payload, expires)
+        })
+        .context("Invalid share link")?;
+        let (max_height, title) = payload.split_once('/').context("Invalid share link")?;
+        Ok((
+            title.to_string(),
+            max_height.parse().context("Invalid share link")?,
+        ))
+    }
+
+    /// A link to every title in `collection`, as it is when the link is used.
+    pub fn sign_collection(&self, collection: &str, expires: SystemTime) -> String {
+        encode(collection, expires, |expires| {
+            self.scoped_mac("collection", collection, expires)
+        })
+    }
+
+    /// The collection a token grants, if it is genuine and unexpired.
+    pub fn verify_collection(&self, token: &str, now: SystemTime) -> Result<String> {
+        decode(token, now, |collection, expires| {
+            self.scoped_mac("collection", collection, expires)
+        })
+        .context("Invalid share link")
+    }
+
+    /// A session cookie value for `user`.
+    pub fn sign_session(&self, user: &str, expires: SystemTime) -> String {
+        encode(user, expires, |expires| {
+            self.scoped_mac("session", user, expires)
+        })
+    }
+
+    /// The user a session cookie belongs to, if it is genuine and unexpired.
+    pub fn verify_session(&self, token: &str, now: SystemTime) -> Result<String> {
+        decode(token, now, |user, expires| {
+            self.scoped_mac("session", user, expires)
+        })
+        .context("Invalid session")
+    }
+}
+
+/// `base64(payload).expires.base64(signature)`
+fn encode(payload: &str, expires: SystemTime, mac: impl Fn(u64) -> Hmac<Sha256>) -> String {
+    let expires = expires
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let signature = mac(expires).finalize().into_bytes();
+    format!(
+        "{}.{}.{}",
+        URL_SAFE_NO_PAD.encode(payload),
+        expires,
+        URL_SAFE_NO_PAD.encode(signature)
+    )
+}
+
+fn decode(token: &str, now: SystemTime, mac: impl Fn(&str, u64) -> Hmac<Sha256>) -> Result<String> {
+    let mut parts = token.split('.');
+    let (Some(payload), Some(expires), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        bail!("Malformed token");
+    };
+
+    let payload = String::from_utf8(URL_SAFE_NO_PAD.decode(payload)?)?;
+    let expires: u64 = expires.parse()?;
+    mac(&payload, expires)
+        .verify_slice(&URL_SAFE_NO_PAD.decode(signature)?)
+        .context("Bad signature")?;
+
+    if now > UNIX_EPOCH + Duration::from_secs(expires) {
+        bail!("Token has expired");
+    }
+    Ok(payload)
+}
+
+/// The height a rung like `720p` names.
+pub fn parse_rung(rung: &str) -> Result<u32> {
+    rung.strip_suffix('p')
+        .unwrap_or(rung)
+        .parse()
+        .ok()
+        .filter(|&height| height > 0)
+        .context(format!(
+            "Invalid rung {:?}; expected a height like 720p",
+            rung
+        ))
+}
+
+/// A QR code of `link` drawn with block characters, for printing to a terminal.
+pub fn qr_text(link: &str) -> Result<String> {
+    let code = QrCode::new(link).context("Link is too long for a QR code")?;
+    // Light on dark terminals reads inverted, so draw the dark modules as spaces
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+/// Save a QR code of `link` as a PNG.
+pub fn save_qr(link: &str, path: &Path) -> Result<()> {
+    let code = QrCode::new(link).context("Link is too long for a QR code")?;
+    code.render::<Luma<u8>>()
+        .min_dimensions(512, 512)
+        .build()
+        .save(path)
+        .context(format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> ShareKey {
+        ShareKey { key: vec![7; 32] }
+    }
+
+    #[test]
+    fn round_trips_until_expiry() {
+        let now = SystemTime::now();
+        let token = key().sign("Alien (1979)", now + Duration::from_secs(60));
+
+        assert_eq!(key().verify(&token, now).unwrap(), "Alien (1979)");
+        assert!(
+            key()
+                .verify(&token, now + Duration::from_secs(120))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let now = SystemTime::now();
+        let token = key().sign("movie", now + Duration::from_secs(60));
+
+        // Pointing the token at another title invalidates it
+        let (_, rest) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode("other"), rest);
+        assert!(key().verify(&forged, now).is_err());
+
+        // Nor is a share link a session, or the other way around
+        let session = key().sign_session("movie", now + Duration::from_secs(60));
+        assert!(key().verify(&session, now).is_err());
+        assert!(key().verify_session(&token, now).is_err());
+        assert_eq!(key().verify_session(&session, now).unwrap(), "movie");
+        let once = key().sign_once("movie", now + Duration::from_secs(60));
+        assert!(key().verify(&once, now).is_err());
+        assert!(key().verify_once(&token, now).is_err());
+        assert_eq!(key().verify_once(&once, now).unwrap().1, "movie");
+        let capped = key().sign_capped("movie", 720, now + Duration::from_secs(60));
+        assert!(key().verify(&capped, now).is_err());
+        assert_eq!(
+            key().verify_capped(&capped, now).unwrap(),
+            (String::from("movie"), 720)
+        );
+        let collection = key().sign_collection("movie", now + Duration::from_secs(60));
+        assert!(key().verify(&collection, now).is_err());
+        assert_eq!(key().verify_collection(&collection, now).unwrap(), "movie");
+
+        let other_key = ShareKey { key: vec![8; 32] };
+        assert!(other_key.verify(&token, now).is_err());
+        assert!(key().verify("garbage", now).is_err());
+    }
+
+    #[test]
+    fn renders_qr_codes() {
+        let link = "http://media.local:8080/s/token/";
+        assert!(qr_text(link).unwrap().lines().count() > 10);
+
+        let path = std::env::temp_dir().join(format!("movieshare-qr-{}.png", std::process::id()));
+        save_qr(link, &path).unwrap();
+        let image = image::open(&path).unwrap();
+        assert!(image.width() >= 512 && image.width() == image.height());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
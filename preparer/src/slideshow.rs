@@ -0,0 +1,49 @@
+//! `preparer slideshow`: turn a directory of photos and a music track into a
+//! prepared title, so slideshows can be shared with the same player.
+
+use anyhow::{Context, Result, bail};
+use std::path::{Path, PathBuf};
+
+/// Image types the slideshow takes, by extension.
+const EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+
+/// The images in `dir`, in the order of their names.
+pub fn list_images(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut images: Vec<_> = std::fs::read_dir(dir)
+        .context(format!("Failed to read images: {}", dir.display()))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension().is_some_and(|ext| {
+                EXTENSIONS
+                    .iter()
+                    .any(|wanted| ext.eq_ignore_ascii_case(wanted))
+            })
+        })
+        .collect();
+    if images.is_empty() {
+        bail!("No JPEG or PNG images in {}", dir.display());
+    }
+    images.sort();
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_images_by_name() {
+        let dir = std::env::temp_dir().join(format!("movieshare-slideshow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(list_images(&dir).is_err());
+        for name in ["b.PNG", "a.jpg", "notes.txt", "c.jpeg"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        assert_eq!(
+            list_images(&dir).unwrap(),
+            ["a.jpg", "b.PNG", "c.jpeg"].map(|name| dir.join(name))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
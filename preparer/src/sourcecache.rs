@@ -0,0 +1,239 @@
+//! Local copies of remote sources, for `preparer prepare https://...` and
+//! `preparer prepare s3://...`.
+//!
+//! Probing, analysis and encoding each read the source from the start, so
+//! a remote one is fetched to disk once and every pass reads that. With
+//! `--source-cache` the copies are kept, up to a size, so a run tried
+//! again doesn't download the source again either. A copy is used as long
+//! as the remote still answers with the ETag, or length and modification
+//! time, it had when fetched; the copies used least recently go first
+//! once the cache is full.
+
+use crate::s3::{S3Client, S3Location};
+use crate::throttle;
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Whether `input` names a remote source rather than a file.
+pub fn is_remote(input: &str) -> bool {
+    ["http://", "https://", "s3://"]
+        .iter()
+        .any(|scheme| input.starts_with(scheme))
+}
+
+/// Parse a size like `500M` or `200G` in bytes.
+pub fn parse_size(size: &str) -> Result<u64> {
+    throttle::parse_rate(size).context(format!("Invalid size {:?}", size))
+}
+
+/// What's recorded next to a copy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Entry {
+    url: String,
+    /// Tells whether the remote changed since the copy was made
+    validator: Option<String>,
+}
+
+/// Somewhere remote to fetch a source from.
+enum Remote {
+    Http(String),
+    S3(S3Location, S3Client),
+}
+
+impl Remote {
+    fn parse(url: &str) -> Result<Self> {
+        if url.starts_with("s3://") {
+            let location =
+                S3Location::parse(url).context(format!("Invalid S3 location: {}", url))?;
+            if location.prefix.is_empty() {
+                bail!("{} names a bucket rather than a source in it", url);
+            }
+            return Ok(Remote::S3(location, S3Client::from_env()?));
+        }
+        Ok(Remote::Http(url.to_string()))
+    }
+
+    /// The ETag, or failing that the length and modification time, of the
+    /// source as it is now.
+    fn validator(&self) -> Result<Option<String>> {
+        match self {
+            Remote::Http(url) => {
+                let response = ureq::head(url)
+                    .call()
+                    .context(format!("Failed to reach {}", url))?;
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                };
+                Ok(match (header("etag"), header("content-length")) {
+                    (Some(etag), _) => Some(etag.to_string()),
+                    (None, Some(length)) => Some(format!(
+                        "{} {}",
+                        length,
+                        header("last-modified").unwrap_or_default()
+                    )),
+                    (None, None) => None,
+                })
+            }
+            Remote::S3(location, client) => client.etag(&location.bucket, &location.prefix),
+        }
+    }
+
+    fn download(&self, path: &Path) -> Result<()> {
+        match self {
+            Remote::Http(url) => {
+                let response = ureq::get(url)
+                    .call()
+                    .context(format!("Failed to fetch {}", url))?;
+                let mut file = std::fs::File::create(path)
+                    .context(format!("Failed to create {}", path.display()))?;
+                std::io::copy(&mut response.into_body().into_reader(), &mut file)
+                    .context(format!("Failed to download {}", url))?;
+                Ok(())
+            }
+            Remote::S3(location, client) => {
+                client.get_file(&location.bucket, &location.prefix, path)
+            }
+        }
+    }
+}
+
+/// A directory of local copies of remote sources.
+pub struct SourceCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl SourceCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self { dir, max_bytes }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Where the copy of `url` goes, keeping its extension for tools that
+    /// go by it.
+    fn path(&self, url: &str) -> PathBuf {
+        let key = format!("{:x}", Sha256::digest(url));
+        let name = url
+            .split(['?', '#'])
+            .next()
+            .and_then(|path| path.rsplit('/').next())
+            .and_then(|name| Path::new(name).extension())
+            .and_then(|ext| ext.to_str())
+            .map_or(key[..16].to_string(), |ext| {
+                format!("{}.{}", &key[..16], ext)
+            });
+        self.dir.join(name)
+    }
+
+    /// A local copy of the source at `url`, fetched unless the cache has
+    /// one that's still current.
+    pub fn fetch(&self, url: &str) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)
+            .context(format!("Failed to create {}", self.dir.display()))?;
+        let remote = Remote::parse(url)?;
+        let validator = remote.validator()?;
+        let path = self.path(url);
+        let entry_path = path.with_extension("json");
+        let current = Entry {
+            url: url.to_string(),
+            validator,
+        };
+        let cached = std::fs::read(&entry_path)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<Entry>(&json).ok());
+        if path.is_file() && current.validator.is_some() && cached.as_ref() == Some(&current) {
+            // Used just now, as far as eviction goes
+            std::fs::File::options()
+                .append(true)
+                .open(&path)?
+                .set_modified(SystemTime::now())?;
+            return Ok(path);
+        }
+
+        let partial = path.with_extension("part");
+        remote.download(&partial)?;
+        std::fs::rename(&partial, &path)?;
+        std::fs::write(&entry_path, serde_json::to_vec(&current)?)?;
+        self.evict(&path)?;
+        Ok(path)
+    }
+
+    /// Remove the copies used least recently, other than `keep`, until the
+    /// cache fits its size.
+    fn evict(&self, keep: &Path) -> Result<()> {
+        let mut copies = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)?.flatten() {
+            let path = entry.path();
+            if matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("json" | "part")
+            ) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            copies.push((metadata.modified()?, metadata.len(), path));
+        }
+        copies.sort();
+        let mut total: u64 = copies.iter().map(|(_, size, _)| size).sum();
+        for (_, size, path) in copies {
+            if total <= self.max_bytes {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            std::fs::remove_file(&path)?;
+            let _ = std::fs::remove_file(path.with_extension("json"));
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn evicts_the_copies_used_least_recently() {
+        let dir = std::env::temp_dir().join(format!("movieshare-sources-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = SourceCache::new(dir.clone(), 250);
+        assert!(is_remote("s3://media/film.mkv"));
+        assert!(!is_remote("/media/film.mkv"));
+
+        let old = cache.path("https://example.com/old.mkv?token=1");
+        let recent = cache.path("https://example.com/recent.mkv");
+        let new = cache.path("https://example.com/new");
+        assert_eq!(old.extension().unwrap(), "mkv");
+        assert_eq!(new.extension(), None);
+        let now = SystemTime::now();
+        for (path, age) in [(&old, 20), (&recent, 10), (&new, 30)] {
+            std::fs::write(path, [0; 100]).unwrap();
+            std::fs::write(path.with_extension("json"), b"{}").unwrap();
+            std::fs::File::options()
+                .append(true)
+                .open(path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age))
+                .unwrap();
+        }
+        // The new copy stays even though it's the oldest on disk
+        cache.evict(&new).unwrap();
+        assert!(!old.exists());
+        assert!(!old.with_extension("json").exists());
+        assert!(recent.exists());
+        assert!(new.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
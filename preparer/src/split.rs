@@ -0,0 +1,196 @@
+//! Splitting one source into several titles, for disc rips that hold a
+//! whole season in one file.
+//!
+//! Each episode is prepared on its own, with everything outside it cut, so
+//! it comes out as an independent title starting at zero.
+
+use anyhow::{Context, Result, anyhow, bail};
+use movieshare_core::cuts::Cut;
+use movieshare_core::gst;
+use movieshare_core::gst::prelude::*;
+use std::path::Path;
+
+/// Shortest episode worth splitting out, in seconds; shorter chapters are
+/// merged into the one before, since rips often end with a stub chapter.
+const MIN_EPISODE_SECS: f64 = 60.0;
+
+/// One part of the source, from `start_secs` to `end_secs`, or to the end
+/// of the source for the last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Episode {
+    pub name: String,
+    pub start_secs: f64,
+    pub end_secs: Option<f64>,
+}
+
+impl Episode {
+    /// Cuts leaving only this episode of the source.
+    pub fn cuts(&self) -> Vec<Cut> {
+        let mut cuts = Vec::new();
+        if self.start_secs > 0.0 {
+            cuts.push(Cut {
+                start_secs: 0.0,
+                end_secs: self.start_secs,
+            });
+        }
+        if let Some(end_secs) = self.end_secs {
+            cuts.push(Cut {
+                start_secs: end_secs,
+                end_secs: f64::MAX,
+            });
+        }
+        cuts
+    }
+}
+
+/// Episodes starting at each of `starts`, in seconds. The first episode
+/// always starts at zero, so nothing before the first split is lost.
+pub fn episodes(starts: &[f64]) -> Vec<Episode> {
+    let mut starts: Vec<f64> = starts.iter().copied().filter(|s| *s > 0.0).collect();
+    starts.sort_by(f64::total_cmp);
+    let mut kept = vec![0.0];
+    for start in starts {
+        if start - kept.last().unwrap() >= MIN_EPISODE_SECS {
+            kept.push(start);
+        }
+    }
+    (0..kept.len())
+        .map(|i| Episode {
+            name: format!("Episode {:02}", i + 1),
+            start_secs: kept[i],
+            end_secs: kept.get(i + 1).copied(),
+        })
+        .collect()
+}
+
+/// A chapter from a source's table of contents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TocChapter {
+    pub title: Option<String>,
+    pub start_secs: f64,
+}
+
+/// Start times of the chapters of `input`, in seconds.
+pub fn read_chapters(input: &Path) -> Result<Vec<f64>> {
+    let chapters = read_toc(input)?;
+    if chapters.is_empty() {
+        bail!("{} has no chapters", input.display());
+    }
+    Ok(chapters.iter().map(|chapter| chapter.start_secs).collect())
+}
+
+/// The chapters of `input`, if it has any.
+pub fn read_toc(input: &Path) -> Result<Vec<TocChapter>> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*input.to_string_lossy())
+        .build()?;
+    // Demuxing is enough to see the table of contents
+    let parsebin = gst::ElementFactory::make("parsebin").build()?;
+    pipeline.add_many([&filesrc, &parsebin])?;
+    filesrc.link(&parsebin)?;
+
+    let pipeline_weak = pipeline.downgrade();
+    parsebin.connect_pad_added(move |_, src_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        let fakesink = gst::ElementFactory::make("fakesink")
+            .build()
+            .expect("Failed to create fakesink");
+        pipeline.add(&fakesink).expect("Failed to add fakesink");
+        fakesink
+            .sync_state_with_parent()
+            .expect("Failed to start fakesink");
+        src_pad
+            .link(&fakesink.static_pad("sink").unwrap())
+            .expect("Failed to link parsebin to fakesink");
+    });
+
+    pipeline.set_state(gst::State::Paused)?;
+    let bus = pipeline.bus().unwrap();
+    let mut chapters = Vec::new();
+    let result = loop {
+        use gst::MessageView;
+
+        let Some(message) = bus.timed_pop(gst::ClockTime::NONE) else {
+            break Ok(());
+        };
+        match message.view() {
+            MessageView::AsyncDone(..) | MessageView::Eos(..) => break Ok(()),
+            MessageView::Toc(toc) => {
+                let (toc, _) = toc.toc();
+                chapters.clear();
+                collect_chapters(&toc.entries(), &mut chapters);
+            }
+            MessageView::Error(err) => {
+                break Err(anyhow!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ));
+            }
+            _ => (),
+        }
+    };
+    pipeline.set_state(gst::State::Null)?;
+    result.context(format!("Failed to read chapters of {}", input.display()))?;
+    Ok(chapters)
+}
+
+/// The top-level chapters under `entries`, looking inside editions.
+fn collect_chapters(entries: &[gst::TocEntry], chapters: &mut Vec<TocChapter>) {
+    for entry in entries {
+        match entry.entry_type() {
+            gst::TocEntryType::Edition => collect_chapters(&entry.sub_entries(), chapters),
+            gst::TocEntryType::Chapter => {
+                if let Some((start, _)) = entry.start_stop_times() {
+                    let title = entry.tags().and_then(|tags| {
+                        tags.get::<gst::tags::Title>().map(|t| t.get().to_owned())
+                    });
+                    chapters.push(TocChapter {
+                        title,
+                        start_secs: start as f64 / 1e9,
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_at_chapters() {
+        let episodes = episodes(&[1320.0, 0.0, 2650.0, 2680.0, 20.0]);
+        let bounds: Vec<(f64, Option<f64>)> = episodes
+            .iter()
+            .map(|episode| (episode.start_secs, episode.end_secs))
+            .collect();
+        assert_eq!(
+            bounds,
+            [(0.0, Some(1320.0)), (1320.0, Some(2650.0)), (2650.0, None)]
+        );
+        assert_eq!(episodes[2].name, "Episode 03");
+
+        assert_eq!(
+            episodes[1].cuts(),
+            [
+                Cut {
+                    start_secs: 0.0,
+                    end_secs: 1320.0
+                },
+                Cut {
+                    start_secs: 2650.0,
+                    end_secs: f64::MAX
+                }
+            ]
+        );
+        assert_eq!(episodes[0].cuts().len(), 1);
+    }
+}
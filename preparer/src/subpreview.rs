@@ -0,0 +1,240 @@
+//! `preparer subtitle-preview`: draw the subtitle cue showing at some time
+//! over the video frame it goes with, and save it as a PNG, to spot-check
+//! converted or OCR'd subtitles without setting up playback.
+
+use crate::clip;
+use crate::library::MANIFEST;
+use crate::mpd;
+use crate::seekindex::SeekIndex;
+use anyhow::{Context, Result, anyhow, bail};
+use movieshare_core::cuts;
+use movieshare_core::gst;
+use movieshare_core::gst::prelude::*;
+use std::path::Path;
+
+/// A WebVTT cue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub start_secs: f64,
+    pub end_secs: f64,
+    pub text: String,
+}
+
+/// The cues of a WebVTT file, in the order they appear.
+pub fn parse_vtt(vtt: &str) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    let mut lines = vtt.lines();
+    while let Some(line) = lines.next() {
+        let Some((start, rest)) = line.split_once("-->") else {
+            continue;
+        };
+        // Cue settings, like `line:0`, follow the end time
+        let end = rest.split_whitespace().next().unwrap_or_default();
+        let (Some(start_secs), Some(end_secs)) =
+            (cuts::parse_time(start.trim()), cuts::parse_time(end))
+        else {
+            continue;
+        };
+        let text: Vec<&str> = lines.by_ref().take_while(|line| !line.is_empty()).collect();
+        cues.push(Cue {
+            start_secs,
+            end_secs,
+            text: text.join("\n"),
+        });
+    }
+    cues
+}
+
+/// Turn a cue's text into the Pango markup `textoverlay` takes: italics,
+/// bold and underline are kept, voice and class spans dropped.
+pub fn cue_markup(text: &str) -> String {
+    let mut markup = String::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        markup.push_str(&escape(&rest[..open]));
+        let Some(close) = rest[open..].find('>') else {
+            rest = &rest[open..];
+            break;
+        };
+        let tag = &rest[open + 1..open + close];
+        let name = tag.trim_start_matches('/').split(['.', ' ']).next();
+        if let Some(name @ ("i" | "b" | "u")) = name {
+            match tag.starts_with('/') {
+                true => markup.push_str(&format!("</{}>", name)),
+                false => markup.push_str(&format!("<{}>", name)),
+            }
+        }
+        rest = &rest[open + close + 1..];
+    }
+    markup.push_str(&escape(rest));
+    markup
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        // WebVTT escapes these the same way, so they're markup already
+        .replace("&amp;lt;", "&lt;")
+        .replace("&amp;gt;", "&gt;")
+        .replace("&amp;amp;", "&amp;")
+        .replace("&amp;nbsp;", "\u{a0}")
+}
+
+/// Draw the cue of the `track`th subtitle track of the title in `dir`
+/// showing at `at` seconds over the frame then, into a PNG at `output`.
+/// Intermediate files go under `scratch`.
+pub fn render(dir: &Path, track: usize, at: f64, output: &Path, scratch: &Path) -> Result<()> {
+    let xml = std::fs::read_to_string(dir.join(MANIFEST))
+        .context(format!("No prepared title in {}", dir.display()))?;
+    let manifest = mpd::parse(&xml)?;
+    let subtitles: Vec<_> = manifest
+        .representations
+        .iter()
+        .filter(|representation| representation.content_type == "text")
+        .collect();
+    let subtitle = subtitles.get(track).context(format!(
+        "The title has {} subtitle tracks; there is no track {}",
+        subtitles.len(),
+        track
+    ))?;
+    let file = mpd::base_url(&xml, &subtitle.id)?
+        .context(format!("Subtitle track {} has no file", track))?;
+    let vtt = std::fs::read_to_string(dir.join(&file))
+        .context(format!("Failed to read subtitles: {}", file))?;
+    let cues = parse_vtt(&vtt);
+    let Some(cue) = cues
+        .iter()
+        .find(|cue| cue.start_secs <= at && at < cue.end_secs)
+    else {
+        return match cues.iter().find(|cue| cue.start_secs > at) {
+            Some(next) => Err(anyhow!(
+                "No cue showing then; the next one starts at {}",
+                mpd::format_duration(next.start_secs)
+            )),
+            None => Err(anyhow!("No cue showing then")),
+        };
+    };
+
+    let video = manifest
+        .representations
+        .iter()
+        .filter(|representation| representation.content_type == "video")
+        .max_by_key(|representation| representation.bandwidth)
+        .context("The title has no video")?;
+    let index = SeekIndex::read(dir)?;
+    let list = match index
+        .as_ref()
+        .and_then(|index| index.representation(&video.id))
+    {
+        Some(representation) => representation.segment_list(),
+        None => mpd::segment_list(&xml, &video.id)?,
+    };
+    let (segment, _) = clip::covering(&list.segments, at, at + 0.001)?;
+
+    let path = scratch.join(format!(
+        "movieshare-subtitle-preview-{}.mp4",
+        std::process::id()
+    ));
+    let result = clip::join(dir, &list, segment..segment + 1, &path)
+        .and_then(|()| draw(&path, &cue_markup(&cue.text), at, output));
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+/// Decode `video` up to `at` seconds and save that frame with `markup` drawn
+/// over it as a PNG at `output`.
+fn draw(video: &Path, markup: &str, at: f64, output: &Path) -> Result<()> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*video.to_string_lossy())
+        .build()?;
+    let decodebin = gst::ElementFactory::make("decodebin").build()?;
+    let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+    let textoverlay = gst::ElementFactory::make("textoverlay")
+        .property("text", markup)
+        .property_from_str("valignment", "bottom")
+        .property_from_str("halignment", "center")
+        .property("font-desc", "Sans 28")
+        .property("shaded-background", true)
+        .build()?;
+    let pngconvert = gst::ElementFactory::make("videoconvert").build()?;
+    // Only the first frame through is encoded, then it ends the stream
+    let pngenc = gst::ElementFactory::make("pngenc")
+        .property("snapshot", true)
+        .build()?;
+    let filesink = gst::ElementFactory::make("filesink")
+        .property("location", &*output.to_string_lossy())
+        .build()?;
+    pipeline.add_many([
+        &filesrc,
+        &decodebin,
+        &videoconvert,
+        &textoverlay,
+        &pngconvert,
+        &pngenc,
+        &filesink,
+    ])?;
+    filesrc.link(&decodebin)?;
+    gst::Element::link_many([&videoconvert, &textoverlay, &pngconvert, &pngenc, &filesink])?;
+    let convert_sink = videoconvert
+        .static_pad("sink")
+        .context("Failed to get sink pad from videoconvert")?;
+    let at = gst::ClockTime::from_nseconds((at * 1e9) as u64);
+    convert_sink.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        match info.buffer().and_then(|buffer| buffer.pts()) {
+            Some(pts) if pts < at => gst::PadProbeReturn::Drop,
+            _ => gst::PadProbeReturn::Ok,
+        }
+    });
+    clip::link_when_added(&decodebin, "video/", &convert_sink);
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline.bus().unwrap();
+    let result = loop {
+        use gst::MessageView;
+
+        let Some(message) = bus.timed_pop(gst::ClockTime::NONE) else {
+            break Ok(());
+        };
+        match message.view() {
+            MessageView::Eos(..) => break Ok(()),
+            MessageView::Error(err) => {
+                break Err(anyhow!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ));
+            }
+            _ => (),
+        }
+    };
+    pipeline.set_state(gst::State::Null)?;
+    result.context(format!("Failed to write {}", output.display()))?;
+    if !output.is_file() {
+        bail!("No frame at that time to draw on");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_cues_as_markup() {
+        let cues = parse_vtt(
+            "WEBVTT\n\n1\n00:01.000 --> 00:02.500 line:0\n<v Anne>Bonjour</v>\n<i>monde</i> &amp; <c.yellow>tous</c>\n\n00:01:00.000 --> 00:01:01.000\nAu revoir\n",
+        );
+        assert_eq!(cues.len(), 2);
+        assert_eq!((cues[0].start_secs, cues[0].end_secs), (1.0, 2.5));
+        assert_eq!(cues[1].start_secs, 60.0);
+        assert_eq!(
+            cue_markup(&cues[0].text),
+            "Bonjour\n<i>monde</i> &amp; tous"
+        );
+        assert_eq!(cue_markup("1 &lt; 2 & <b>3</b>"), "1 &lt; 2 &amp; <b>3</b>");
+    }
+}
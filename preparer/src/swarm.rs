@@ -0,0 +1,212 @@
+//! Signaling for viewers in a watch-together room to share segments over
+//! WebRTC, so the host's uplink carries each segment fewer times.
+//!
+//! The server only relays messages: `GET /peers/{room}` is a WebSocket on
+//! which the server sends `welcome` with the viewer's id and the peers
+//! already there, then `joined`, `left` and `signal` as others come and go.
+//! Viewers send `signal` with a peer's id and whatever WebRTC needs passed
+//! on (offers, answers and ICE candidates); the player page does the rest.
+
+use crate::serve::AppState;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Viewers ping every ten seconds; one silent this long is gone.
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// Every viewer connects to every other, so keep the mesh small.
+const MAX_PEERS: usize = 8;
+
+type PeerId = u64;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    Signal { to: PeerId, data: serde_json::Value },
+    Ping,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage {
+    Welcome {
+        id: PeerId,
+        peers: Vec<PeerId>,
+    },
+    Joined {
+        id: PeerId,
+    },
+    Left {
+        id: PeerId,
+    },
+    Signal {
+        from: PeerId,
+        data: serde_json::Value,
+    },
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<PeerId, mpsc::UnboundedSender<ServerMessage>>,
+    next_peer: PeerId,
+}
+
+impl Swarm {
+    fn join(&mut self, sender: mpsc::UnboundedSender<ServerMessage>) -> Option<PeerId> {
+        if self.peers.len() >= MAX_PEERS {
+            return None;
+        }
+        let id = self.next_peer;
+        self.next_peer += 1;
+        let mut peers: Vec<PeerId> = self.peers.keys().copied().collect();
+        peers.sort();
+        let _ = sender.send(ServerMessage::Welcome { id, peers });
+        for peer in self.peers.values() {
+            let _ = peer.send(ServerMessage::Joined { id });
+        }
+        self.peers.insert(id, sender);
+        Some(id)
+    }
+
+    fn leave(&mut self, id: PeerId) {
+        self.peers.remove(&id);
+        for peer in self.peers.values() {
+            let _ = peer.send(ServerMessage::Left { id });
+        }
+    }
+
+    fn handle(&mut self, from: PeerId, message: ClientMessage) {
+        match message {
+            ClientMessage::Signal { to, data } => {
+                if let Some(peer) = self.peers.get(&to) {
+                    let _ = peer.send(ServerMessage::Signal { from, data });
+                }
+            }
+            ClientMessage::Ping => (),
+        }
+    }
+}
+
+/// The swarm of each room that has viewers sharing segments.
+#[derive(Clone, Default)]
+pub struct Swarms {
+    swarms: Arc<Mutex<HashMap<String, Swarm>>>,
+}
+
+impl Swarms {
+    fn with_swarm<T>(&self, room: &str, f: impl FnOnce(&mut Swarm) -> T) -> T {
+        let mut swarms = self.swarms.lock().unwrap();
+        let result = f(swarms.entry(room.to_string()).or_default());
+        swarms.retain(|_, swarm| !swarm.peers.is_empty());
+        result
+    }
+}
+
+/// `GET /peers/{room}`: join a room's swarm over a WebSocket.
+pub async fn connect(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+) -> Response {
+    let Some(swarms) = state.swarms else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if state.rooms.title(&room).is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    ws.on_upgrade(move |socket| run_peer(swarms, room, socket))
+}
+
+async fn run_peer(swarms: Swarms, room: String, socket: WebSocket) {
+    let (mut sink, mut stream) = socket.split();
+    let (sender, mut outgoing) = mpsc::unbounded_channel();
+    // A full swarm just closes the socket; the viewer fetches from the server
+    let Some(id) = swarms.with_swarm(&room, |swarm| swarm.join(sender)) else {
+        return;
+    };
+
+    let forward = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let json = serde_json::to_string(&message).unwrap();
+            if sink.send(Message::Text(json.into())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        match tokio::time::timeout(STALE_AFTER, stream.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => match serde_json::from_str(&text) {
+                Ok(message) => swarms.with_swarm(&room, |swarm| swarm.handle(id, message)),
+                Err(err) => eprintln!("Ignoring bad peer message {:?}: {}", text.as_str(), err),
+            },
+            Ok(Some(Ok(Message::Close(_)) | Err(_)) | None) | Err(_) => break,
+            Ok(Some(Ok(_))) => (),
+        }
+    }
+
+    swarms.with_swarm(&room, |swarm| swarm.leave(id));
+    forward.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn relays_signals_between_peers() {
+        let mut swarm = Swarm::default();
+        let (sender, mut first) = mpsc::unbounded_channel();
+        let a = swarm.join(sender).unwrap();
+        let (sender, mut second) = mpsc::unbounded_channel();
+        let b = swarm.join(sender).unwrap();
+
+        assert_eq!(
+            first.try_recv().unwrap(),
+            ServerMessage::Welcome {
+                id: a,
+                peers: vec![]
+            }
+        );
+        assert_eq!(first.try_recv().unwrap(), ServerMessage::Joined { id: b });
+        assert_eq!(
+            second.try_recv().unwrap(),
+            ServerMessage::Welcome {
+                id: b,
+                peers: vec![a]
+            }
+        );
+
+        swarm.handle(
+            b,
+            ClientMessage::Signal {
+                to: a,
+                data: json!({ "sdp": "offer" }),
+            },
+        );
+        assert_eq!(
+            first.try_recv().unwrap(),
+            ServerMessage::Signal {
+                from: b,
+                data: json!({ "sdp": "offer" })
+            }
+        );
+
+        swarm.leave(b);
+        assert_eq!(first.try_recv().unwrap(), ServerMessage::Left { id: b });
+
+        for _ in 1..MAX_PEERS {
+            swarm.join(mpsc::unbounded_channel().0).unwrap();
+        }
+        assert!(swarm.join(mpsc::unbounded_channel().0).is_none());
+    }
+}
@@ -0,0 +1,544 @@
+//! Watch-together rooms: everyone watching a title in one room shares play,
+//! pause and seek.
+//!
+//! The wire protocol is the standalone webapp's. Clients send `play`,
+//! `pause`, `seek`, `bufferReady` and `ping`; the server sends `init`,
+//! `play`, `pause`, `seek` and `clientCount`. Playback only starts once
+//! every viewer has reported enough buffer, and any seek pauses the room
+//! until everyone has buffered again.
+//!
+//! A viewer can also send `handoff` to move to another device, like from a
+//! phone to the TV: only they get back `handoff` with a code, which the
+//! other device, with the title already open, redeems at
+//! `POST /handoff/<code>` for the room to join and the position it's at.
+//! The room keeps that position even if the phone leaves before the TV
+//! joins. Codes are long enough not to be guessed, and a client trying too
+//! many wrong ones is turned away for a while.
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Viewers ping every ten seconds; one silent this long is gone.
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// Empty rooms are forgotten after this long.
+const ROOM_IDLE_LIMIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Handoff codes are good for this long, and only once.
+const HANDOFF_LIMIT: Duration = Duration::from_secs(10 * 60);
+
+/// Letters and digits of handoff codes, leaving out ones easily misread.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 12;
+
+/// Wrong codes one client may try within `HANDOFF_LIMIT` before it's
+/// turned away.
+const MAX_FAILED_REDEMPTIONS: usize = 20;
+
+/// Wrong codes from every client together within `HANDOFF_LIMIT` before
+/// redeeming stops for everyone, against guessing from many addresses.
+const MAX_TOTAL_FAILED_REDEMPTIONS: usize = 1000;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    Play,
+    Pause,
+    Seek { time: f64 },
+    BufferReady,
+    Ping,
+    Handoff,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage {
+    Init { time: f64, playing: bool },
+    Play,
+    Pause,
+    Seek { time: f64 },
+    ClientCount { count: usize },
+    Handoff { code: String },
+}
+
+type ViewerId = u64;
+
+struct Viewer {
+    sender: mpsc::UnboundedSender<ServerMessage>,
+    buffered: bool,
+}
+
+pub struct Room {
+    pub title: String,
+    viewers: HashMap<ViewerId, Viewer>,
+    next_viewer: ViewerId,
+    time: f64,
+    playing: bool,
+    /// When playback last started from `time`
+    started_at: Option<Instant>,
+    /// Someone pressed play; start once everyone has buffered
+    wants_play: bool,
+    empty_since: Option<Instant>,
+}
+
+impl Room {
+    fn new(title: String) -> Self {
+        Self {
+            title,
+            viewers: HashMap::new(),
+            next_viewer: 0,
+            time: 0.0,
+            playing: false,
+            started_at: None,
+            wants_play: false,
+            empty_since: Some(Instant::now()),
+        }
+    }
+
+    /// Where playback is now.
+    fn position(&self) -> f64 {
+        let elapsed = self.started_at.map_or(0.0, |at| at.elapsed().as_secs_f64());
+        self.time + elapsed
+    }
+
+    fn join(&mut self, sender: mpsc::UnboundedSender<ServerMessage>) -> ViewerId {
+        // Hold everyone while the newcomer buffers
+        if !self.viewers.is_empty() {
+            self.pause();
+        }
+
+        let id = self.next_viewer;
+        self.next_viewer += 1;
+        let _ = sender.send(ServerMessage::Init {
+            time: self.time,
+            playing: false,
+        });
+        self.viewers.insert(
+            id,
+            Viewer {
+                sender,
+                buffered: false,
+            },
+        );
+        self.empty_since = None;
+        self.broadcast_count();
+        id
+    }
+
+    fn leave(&mut self, id: ViewerId) {
+        self.viewers.remove(&id);
+        if self.viewers.is_empty() {
+            self.time = 0.0;
+            self.playing = false;
+            self.started_at = None;
+            self.wants_play = false;
+            self.empty_since = Some(Instant::now());
+        } else {
+            self.broadcast_count();
+            // The viewer everyone was waiting on may have just left
+            self.start_if_ready();
+        }
+    }
+
+    fn handle(&mut self, id: ViewerId, message: ClientMessage) {
+        match message {
+            ClientMessage::Play => {
+                self.wants_play = true;
+                self.start_if_ready();
+            }
+            ClientMessage::Pause => {
+                self.wants_play = false;
+                self.pause();
+            }
+            ClientMessage::Seek { time } => {
+                self.pause();
+                self.time = time;
+                for viewer in self.viewers.values_mut() {
+                    viewer.buffered = false;
+                }
+                self.broadcast(ServerMessage::Seek { time });
+            }
+            ClientMessage::BufferReady => {
+                if let Some(viewer) = self.viewers.get_mut(&id) {
+                    viewer.buffered = true;
+                }
+                self.start_if_ready();
+            }
+            // Codes are handed out by `Rooms`, which knows every room
+            ClientMessage::Ping | ClientMessage::Handoff => (),
+        }
+    }
+
+    fn pause(&mut self) {
+        self.time = self.position();
+        self.started_at = None;
+        self.playing = false;
+        self.broadcast(ServerMessage::Pause);
+    }
+
+    fn start_if_ready(&mut self) {
+        let all_buffered = self.viewers.values().all(|viewer| viewer.buffered);
+        if self.wants_play && !self.playing && all_buffered && !self.viewers.is_empty() {
+            self.playing = true;
+            self.started_at = Some(Instant::now());
+            self.broadcast(ServerMessage::Play);
+        }
+    }
+
+    fn broadcast_count(&mut self) {
+        let count = self.viewers.len();
+        self.broadcast(ServerMessage::ClientCount { count });
+    }
+
+    fn broadcast(&mut self, message: ServerMessage) {
+        for viewer in self.viewers.values() {
+            let _ = viewer.sender.send(message.clone());
+        }
+    }
+}
+
+/// A session on its way to another device.
+struct Handoff {
+    room: String,
+    time: f64,
+    created: Instant,
+}
+
+#[derive(Default)]
+struct Handoffs {
+    by_code: HashMap<String, Handoff>,
+    /// When wrong codes were tried, oldest first, by the client trying them
+    failures: HashMap<Option<IpAddr>, VecDeque<Instant>>,
+}
+
+/// A redeemed handoff code: where the other device picks up.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Redeemed {
+    pub room: String,
+    pub time: f64,
+}
+
+/// Every open room, by id.
+#[derive(Clone, Default)]
+pub struct Rooms {
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
+    /// Sessions being handed off
+    handoffs: Arc<Mutex<Handoffs>>,
+}
+
+impl Rooms {
+    /// Open a room for `title` and return its id.
+    pub fn create(&self, title: &str) -> String {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.retain(|_, room| {
+            room.empty_since
+                .is_none_or(|since| since.elapsed() < ROOM_IDLE_LIMIT)
+        });
+
+        let id = format!("{:016x}", rand::random::<u64>());
+        rooms.insert(id.clone(), Room::new(title.to_string()));
+        id
+    }
+
+    pub fn title(&self, id: &str) -> Option<String> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(id).map(|room| room.title.clone())
+    }
+
+    /// A code handing the session in room `id` off to another device at
+    /// where it's playing now.
+    fn handoff(&self, id: &str) -> Option<String> {
+        let time = self.with_room(id, |room| room.position())?;
+        let mut handoffs = self.handoffs.lock().unwrap();
+        handoffs
+            .by_code
+            .retain(|_, handoff| handoff.created.elapsed() < HANDOFF_LIMIT);
+        let code: String = (0..CODE_LENGTH)
+            .map(|_| CODE_ALPHABET[rand::random_range(0..CODE_ALPHABET.len())] as char)
+            .collect();
+        handoffs.by_code.insert(
+            code.clone(),
+            Handoff {
+                room: id.to_string(),
+                time,
+                created: Instant::now(),
+            },
+        );
+        Some(code)
+    }
+
+    /// Pick up the session handed off with `code` for `client`, if its
+    /// address is known. The code can't be used again. Spaces, dashes and
+    /// case don't matter.
+    pub fn redeem(&self, code: &str, client: Option<IpAddr>) -> Result<Redeemed, StatusCode> {
+        let code: String = code
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let mut handoffs = self.handoffs.lock().unwrap();
+        handoffs.failures.retain(|_, failures| {
+            while failures
+                .front()
+                .is_some_and(|failed| failed.elapsed() >= HANDOFF_LIMIT)
+            {
+                failures.pop_front();
+            }
+            !failures.is_empty()
+        });
+        let total: usize = handoffs.failures.values().map(VecDeque::len).sum();
+        if total >= MAX_TOTAL_FAILED_REDEMPTIONS
+            || handoffs
+                .failures
+                .get(&client)
+                .is_some_and(|failures| failures.len() >= MAX_FAILED_REDEMPTIONS)
+        {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        let Some(handoff) = handoffs
+            .by_code
+            .remove(&code)
+            .filter(|handoff| handoff.created.elapsed() < HANDOFF_LIMIT)
+        else {
+            handoffs
+                .failures
+                .entry(client)
+                .or_default()
+                .push_back(Instant::now());
+            return Err(StatusCode::NOT_FOUND);
+        };
+        drop(handoffs);
+
+        self.with_room(&handoff.room, |room| {
+            // Nobody's left to say where the room was
+            if room.viewers.is_empty() {
+                room.time = handoff.time;
+            }
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+        Ok(Redeemed {
+            room: handoff.room,
+            time: handoff.time,
+        })
+    }
+
+    fn with_room<T>(&self, id: &str, f: impl FnOnce(&mut Room) -> T) -> Option<T> {
+        self.rooms.lock().unwrap().get_mut(id).map(f)
+    }
+}
+
+/// `GET /sync/{room}`: join a room over a WebSocket.
+pub async fn connect(
+    ws: WebSocketUpgrade,
+    State(rooms): State<Rooms>,
+    Path(room): Path<String>,
+) -> Response {
+    if rooms.title(&room).is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    ws.on_upgrade(move |socket| run_viewer(rooms, room, socket))
+}
+
+async fn run_viewer(rooms: Rooms, room: String, socket: WebSocket) {
+    let (mut sink, mut stream) = socket.split();
+    let (sender, mut outgoing) = mpsc::unbounded_channel();
+    let Some(id) = rooms.with_room(&room, |r| r.join(sender.clone())) else {
+        return;
+    };
+
+    let forward = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let json = serde_json::to_string(&message).unwrap();
+            if sink.send(Message::Text(json.into())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        match tokio::time::timeout(STALE_AFTER, stream.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => match serde_json::from_str(&text) {
+                Ok(ClientMessage::Handoff) => {
+                    if let Some(code) = rooms.handoff(&room) {
+                        let _ = sender.send(ServerMessage::Handoff { code });
+                    }
+                }
+                Ok(message) => {
+                    rooms.with_room(&room, |r| r.handle(id, message));
+                }
+                Err(err) => eprintln!("Ignoring bad sync message {:?}: {}", text.as_str(), err),
+            },
+            Ok(Some(Ok(Message::Close(_)) | Err(_)) | None) | Err(_) => break,
+            Ok(Some(Ok(_))) => (),
+        }
+    }
+
+    rooms.with_room(&room, |r| r.leave(id));
+    forward.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn viewer(room: &mut Room) -> (ViewerId, mpsc::UnboundedReceiver<ServerMessage>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (room.join(sender), receiver)
+    }
+
+    fn drain(receiver: &mut mpsc::UnboundedReceiver<ServerMessage>) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn joining_sends_init_and_count() {
+        let mut room = Room::new(String::from("movie"));
+        let (_, mut first) = viewer(&mut room);
+        assert_eq!(
+            drain(&mut first),
+            [
+                ServerMessage::Init {
+                    time: 0.0,
+                    playing: false
+                },
+                ServerMessage::ClientCount { count: 1 }
+            ]
+        );
+
+        let (_, _second) = viewer(&mut room);
+        assert_eq!(
+            drain(&mut first),
+            [
+                ServerMessage::Pause,
+                ServerMessage::ClientCount { count: 2 }
+            ]
+        );
+    }
+
+    #[test]
+    fn play_waits_for_everyone_to_buffer() {
+        let mut room = Room::new(String::from("movie"));
+        let (a, mut first) = viewer(&mut room);
+        let (b, _second) = viewer(&mut room);
+        drain(&mut first);
+
+        room.handle(a, ClientMessage::BufferReady);
+        room.handle(a, ClientMessage::Play);
+        assert_eq!(drain(&mut first), []);
+
+        room.handle(b, ClientMessage::BufferReady);
+        assert_eq!(drain(&mut first), [ServerMessage::Play]);
+    }
+
+    #[test]
+    fn seek_pauses_and_rebuffers() {
+        let mut room = Room::new(String::from("movie"));
+        let (a, mut first) = viewer(&mut room);
+        room.handle(a, ClientMessage::BufferReady);
+        room.handle(a, ClientMessage::Play);
+        drain(&mut first);
+
+        room.handle(a, ClientMessage::Seek { time: 42.0 });
+        assert_eq!(
+            drain(&mut first),
+            [ServerMessage::Pause, ServerMessage::Seek { time: 42.0 }]
+        );
+
+        // Still wants to play, so resumes once rebuffered
+        room.handle(a, ClientMessage::BufferReady);
+        assert_eq!(drain(&mut first), [ServerMessage::Play]);
+
+        // A latecomer starts where the room has played on to
+        let (_, mut late) = viewer(&mut room);
+        let ServerMessage::Init { time, playing } = drain(&mut late)[0] else {
+            panic!("Expected init first");
+        };
+        assert!((42.0..42.5).contains(&time));
+        assert!(!playing);
+    }
+
+    #[test]
+    fn leaving_viewer_unblocks_play() {
+        let mut room = Room::new(String::from("movie"));
+        let (a, mut first) = viewer(&mut room);
+        let (b, _second) = viewer(&mut room);
+        room.handle(a, ClientMessage::BufferReady);
+        room.handle(a, ClientMessage::Play);
+        drain(&mut first);
+
+        room.leave(b);
+        assert_eq!(
+            drain(&mut first),
+            [ServerMessage::ClientCount { count: 1 }, ServerMessage::Play]
+        );
+    }
+
+    #[test]
+    fn handoff_picks_up_where_the_room_was() {
+        let rooms = Rooms::default();
+        let id = rooms.create("movie");
+        let (phone, _) = rooms.with_room(&id, viewer).unwrap();
+        rooms.with_room(&id, |room| {
+            room.handle(phone, ClientMessage::Seek { time: 42.0 })
+        });
+        let code = rooms.handoff(&id).unwrap();
+        assert_eq!(code.len(), CODE_LENGTH);
+
+        // The phone hangs up before the TV joins
+        rooms.with_room(&id, |room| room.leave(phone));
+        let typed = format!("{}-{}", &code[..6], code[6..].to_lowercase());
+        let guesser = Some(IpAddr::from([203, 0, 113, 7]));
+        let tv_addr = Some(IpAddr::from([192, 168, 1, 20]));
+        assert_eq!(
+            rooms.redeem(&typed, tv_addr),
+            Ok(Redeemed {
+                room: id.clone(),
+                time: 42.0
+            })
+        );
+        assert_eq!(rooms.redeem(&code, guesser), Err(StatusCode::NOT_FOUND));
+
+        let (_, mut tv) = rooms.with_room(&id, viewer).unwrap();
+        assert_eq!(
+            drain(&mut tv)[0],
+            ServerMessage::Init {
+                time: 42.0,
+                playing: false
+            }
+        );
+
+        // Guessing gets cut off, even for a good code
+        let code = rooms.handoff(&id).unwrap();
+        for _ in 1..MAX_FAILED_REDEMPTIONS {
+            assert_eq!(
+                rooms.redeem("AAAAAAAAAAAA", guesser),
+                Err(StatusCode::NOT_FOUND)
+            );
+        }
+        assert_eq!(
+            rooms.redeem(&code, guesser),
+            Err(StatusCode::TOO_MANY_REQUESTS)
+        );
+        // But only for the client guessing
+        assert_eq!(
+            rooms.redeem(&code, tv_addr),
+            Ok(Redeemed {
+                room: id.clone(),
+                time: 42.0
+            })
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Running `preparer daemon` as a systemd service.
+//!
+//! With `Type=notify`, the daemon reports readiness once it's listening,
+//! and with `WatchdogSec=` it pings the watchdog while its runtime is
+//! responsive. With socket activation, listening sockets passed in by
+//! systemd are used instead of binding new ones; name them with
+//! `FileDescriptorName=` as `control` (the Unix control socket), `http` or
+//! `grpc`:
+//!
+//! ```ini
+//! # movieshare.socket
+//! [Socket]
+//! ListenStream=%t/movieshare.sock
+//! FileDescriptorName=control
+//! ```
+
+use anyhow::{Context, Result, bail};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// First file descriptor systemd passes sockets in.
+const LISTEN_FDS_START: i32 = 3;
+
+/// Send a state change like `READY=1` to systemd. Does nothing when not
+/// run by systemd.
+pub fn notify(state: &str) -> Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => send(&path.to_string_lossy(), state),
+        None => Ok(()),
+    }
+}
+
+/// Send `state` to the notification socket at `path`; a leading `@` means
+/// an abstract socket.
+fn send(path: &str, state: &str) -> Result<()> {
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to_addr(state.as_bytes(), &addr)
+        .context(format!("Failed to notify systemd on {}", path))?;
+    Ok(())
+}
+
+/// How often to ping the watchdog, if systemd expects it: half its timeout.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Ping the watchdog every `interval` for as long as the runtime runs it.
+pub async fn watchdog(interval: Duration) -> Result<()> {
+    loop {
+        tokio::time::sleep(interval).await;
+        notify("WATCHDOG=1")?;
+    }
+}
+
+/// Sockets systemd passed us, by name.
+pub struct Listeners {
+    sockets: Vec<(String, OwnedFd)>,
+}
+
+impl Listeners {
+    /// The sockets passed through socket activation, if any.
+    pub fn from_env() -> Result<Self> {
+        let mut sockets = Vec::new();
+        let pid = std::env::var("LISTEN_PID").ok();
+        if pid.as_deref().and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+            return Ok(Self { sockets });
+        }
+        let count: i32 = std::env::var("LISTEN_FDS")
+            .context("LISTEN_PID is set without LISTEN_FDS")?
+            .parse()
+            .context("Invalid LISTEN_FDS")?;
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        let mut names = names.split(':');
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            let name = names.next().unwrap_or("unknown").to_string();
+            // systemd hands these over for us to own
+            sockets.push((name, unsafe { OwnedFd::from_raw_fd(fd) }));
+        }
+        Ok(Self { sockets })
+    }
+
+    /// Take the socket named `name`.
+    pub fn take(&mut self, name: &str) -> Option<OwnedFd> {
+        let index = self.sockets.iter().position(|(n, _)| n == name)?;
+        Some(self.sockets.remove(index).1)
+    }
+
+    /// Fail if any socket went unused, since its unit would wait forever.
+    pub fn finish(self) -> Result<()> {
+        if !self.sockets.is_empty() {
+            let names: Vec<_> = self.sockets.iter().map(|(name, _)| name.as_str()).collect();
+            bail!(
+                "Don't know what to serve on the socket(s) named {}; name them control, http or grpc",
+                names.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A passed-in socket as a Tokio TCP listener.
+pub fn tcp_listener(fd: OwnedFd) -> Result<tokio::net::TcpListener> {
+    let listener = std::net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    Ok(tokio::net::TcpListener::from_std(listener)?)
+}
+
+/// A passed-in socket as a Tokio Unix listener.
+pub fn unix_listener(fd: OwnedFd) -> Result<tokio::net::UnixListener> {
+    let listener = std::os::unix::net::UnixListener::from(fd);
+    listener.set_nonblocking(true)?;
+    Ok(tokio::net::UnixListener::from_std(listener)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_notifications_to_the_notify_socket() {
+        let dir = std::env::temp_dir().join(format!("movieshare-systemd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send(&path.to_string_lossy(), "READY=1").unwrap();
+
+        let mut buffer = [0; 64];
+        let len = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1");
+        assert!(Listeners::from_env().unwrap().take("http").is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! Small synthetic media files with the awkward features real rips have:
+//! two audio languages, a subtitle track, chapters and a variable frame
+//! rate.
+//!
+//! `preparer testmedia out.mkv` writes one, so a bug report can come with a
+//! file that reproduces it instead of "it fails on my MKV", and tests that
+//! need GStreamer use them as input.
+
+use anyhow::{Context, Result, anyhow};
+use movieshare_core::gst;
+use movieshare_core::gst::prelude::*;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// Audio tracks: ISO 639-2 language and tone frequency.
+pub const AUDIO_TRACKS: [(&str, f64); 2] = [("eng", 440.0), ("fra", 660.0)];
+pub const SUBTITLE_LANGUAGE: &str = "eng";
+pub const CHAPTERS: u32 = 4;
+/// Rate of the test pattern before frames are dropped for a variable rate.
+const BASE_FPS: u64 = 60;
+/// Frames kept out of every five: gaps of one, two and two frames, so the
+/// rate moves between 60 and 30 frames a second.
+const KEPT_FRAMES: [u64; 3] = [0, 1, 3];
+const CUE_SECS: u64 = 4;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub duration: Duration,
+    /// Drop frames to make the frame rate vary
+    pub vfr: bool,
+}
+
+/// How long a frame shows, in base frames, or `None` if it is dropped.
+fn frame_span(index: u64) -> Option<u64> {
+    let position = index % 5;
+    let next = KEPT_FRAMES
+        .iter()
+        .find(|kept| **kept > position)
+        .copied()
+        .unwrap_or(5);
+    KEPT_FRAMES.contains(&position).then_some(next - position)
+}
+
+/// Chapters evenly splitting `duration`, as title, start and end.
+pub fn chapters(duration: Duration) -> Vec<(String, Duration, Duration)> {
+    let length = duration / CHAPTERS;
+    (0..CHAPTERS)
+        .map(|i| {
+            let end = match i + 1 == CHAPTERS {
+                true => duration,
+                false => length * (i + 1),
+            };
+            (format!("Chapter {}", i + 1), length * i, end)
+        })
+        .collect()
+}
+
+fn srt_time(time: Duration) -> String {
+    let millis = time.as_millis();
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// SubRip subtitles with a numbered cue every few seconds, each saying
+/// when it should appear so drift is easy to spot.
+pub fn subtitles(duration: Duration) -> String {
+    let mut srt = String::new();
+    let cues = duration.as_secs() / CUE_SECS;
+    for i in 0..cues {
+        let start = Duration::from_secs(i * CUE_SECS);
+        let end = start + Duration::from_secs(CUE_SECS - 1);
+        let _ = write!(
+            srt,
+            "{}\n{} --> {}\nCue {} at {}s\n\n",
+            i + 1,
+            srt_time(start),
+            srt_time(end),
+            i + 1,
+            start.as_secs()
+        );
+    }
+    srt
+}
+
+fn make(factory: &str) -> Result<gst::Element> {
+    gst::ElementFactory::make(factory)
+        .build()
+        .context(format!("Failed to create {} element", factory))
+}
+
+/// Write a test file to `output` as Matroska.
+pub fn generate(output: &Path, options: &Options) -> Result<()> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let mux = make("matroskamux")?;
+    let filesink = gst::ElementFactory::make("filesink")
+        .property("location", &*output.to_string_lossy())
+        .build()?;
+    pipeline.add_many([&mux, &filesink])?;
+    mux.link(&filesink)?;
+
+    // Video: a moving pattern with a clock, some frames dropped
+    let frames = options.duration.as_millis() as u64 * BASE_FPS / 1000;
+    let videotestsrc = gst::ElementFactory::make("videotestsrc")
+        .property("num-buffers", frames as i32)
+        .property_from_str("pattern", "ball")
+        .build()?;
+    let videocaps = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("width", 320)
+                .field("height", 180)
+                .field("framerate", gst::Fraction::new(BASE_FPS as i32, 1))
+                .build(),
+        )
+        .build()?;
+    let clock = gst::ElementFactory::make("timeoverlay")
+        .property_from_str("halignment", "center")
+        .build()?;
+    let videoconvert = make("videoconvert")?;
+    let encoder = gst::ElementFactory::make("svtav1enc")
+        .property("preset", 12u32)
+        .build()?;
+    let parser = make("av1parse")?;
+    let video = [
+        &videotestsrc,
+        &videocaps,
+        &clock,
+        &videoconvert,
+        &encoder,
+        &parser,
+    ];
+    pipeline.add_many(video)?;
+    gst::Element::link_many(video)?;
+    parser.link(&mux)?;
+    if options.vfr {
+        let frame = gst::ClockTime::SECOND / BASE_FPS;
+        videocaps
+            .static_pad("src")
+            .context("Failed to get src pad from capsfilter")?
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                let Some(gst::PadProbeData::Buffer(buffer)) = info.data.as_mut() else {
+                    return gst::PadProbeReturn::Ok;
+                };
+                let index = buffer
+                    .pts()
+                    .map_or(0, |pts| pts.nseconds() / frame.nseconds());
+                match frame_span(index) {
+                    Some(span) => {
+                        buffer.make_mut().set_duration(frame * span);
+                        gst::PadProbeReturn::Ok
+                    }
+                    None => gst::PadProbeReturn::Drop,
+                }
+            });
+    }
+
+    // Audio: a tone per language
+    let buffers = options.duration.as_millis() as u64 * 48 / 1024 + 1;
+    for (language, frequency) in AUDIO_TRACKS {
+        let audiotestsrc = gst::ElementFactory::make("audiotestsrc")
+            .property("num-buffers", buffers as i32)
+            .property("samplesperbuffer", 1024i32)
+            .property("freq", frequency)
+            .property("volume", 0.2)
+            .build()?;
+        let tags = gst::ElementFactory::make("taginject")
+            .property("tags", format!("language-code={}", language))
+            .property_from_str("scope", "stream")
+            .build()?;
+        let audio = [
+            &audiotestsrc,
+            &tags,
+            &make("audioconvert")?,
+            &make("opusenc")?,
+        ];
+        pipeline.add_many(audio)?;
+        gst::Element::link_many(audio)?;
+        audio[3].link(&mux)?;
+    }
+
+    // Subtitles: SubRip parsed from a temporary file
+    let srt = output.with_extension("srt.tmp");
+    std::fs::write(&srt, subtitles(options.duration))
+        .context(format!("Failed to write {}", srt.display()))?;
+    let subsrc = gst::ElementFactory::make("filesrc")
+        .property("location", &*srt.to_string_lossy())
+        .build()?;
+    let subparse = make("subparse")?;
+    // Cues are plain text, so they can go in as UTF-8 rather than Pango markup
+    let subcaps = gst::ElementFactory::make("capssetter")
+        .property(
+            "caps",
+            gst::Caps::builder("text/x-raw")
+                .field("format", "utf8")
+                .build(),
+        )
+        .build()?;
+    let subtags = gst::ElementFactory::make("taginject")
+        .property("tags", format!("language-code={}", SUBTITLE_LANGUAGE))
+        .property_from_str("scope", "stream")
+        .build()?;
+    let subtitles = [&subsrc, &subparse, &subcaps, &subtags];
+    pipeline.add_many(subtitles)?;
+    gst::Element::link_many(subtitles)?;
+    subtags.link(&mux)?;
+
+    // Chapters
+    let mut toc = gst::Toc::new(gst::TocScope::Global);
+    {
+        let mut edition = gst::TocEntry::new(gst::TocEntryType::Edition, "edition");
+        for (i, (title, start, end)) in chapters(options.duration).into_iter().enumerate() {
+            let mut chapter =
+                gst::TocEntry::new(gst::TocEntryType::Chapter, &format!("chapter{}", i));
+            let entry = chapter.get_mut().unwrap();
+            entry.set_start_stop_times(start.as_nanos() as i64, end.as_nanos() as i64);
+            let mut tags = gst::TagList::new();
+            tags.get_mut()
+                .unwrap()
+                .add::<gst::tags::Title>(&title.as_str(), gst::TagMergeMode::Replace);
+            entry.set_tags(tags);
+            edition.get_mut().unwrap().append_sub_entry(chapter);
+        }
+        toc.get_mut().unwrap().append_entry(edition);
+    }
+    mux.dynamic_cast_ref::<gst::TocSetter>()
+        .context("matroskamux can't take chapters")?
+        .set_toc(Some(&toc));
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline.bus().unwrap();
+    let result = loop {
+        use gst::MessageView;
+
+        let Some(message) = bus.timed_pop(gst::ClockTime::NONE) else {
+            break Ok(());
+        };
+        match message.view() {
+            MessageView::Eos(..) => break Ok(()),
+            MessageView::Error(err) => {
+                break Err(anyhow!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ));
+            }
+            _ => (),
+        }
+    };
+    pipeline.set_state(gst::State::Null)?;
+    let _ = std::fs::remove_file(&srt);
+    result.context(format!("Failed to write {}", output.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_tracks_chapters_and_frame_rate() {
+        let spans: Vec<Option<u64>> = (0..6).map(frame_span).collect();
+        assert_eq!(spans, [Some(1), Some(2), None, Some(2), None, Some(1)]);
+
+        let chapters = chapters(Duration::from_secs(10));
+        assert_eq!(chapters.len(), CHAPTERS as usize);
+        assert_eq!(chapters[1].1, Duration::from_millis(2500));
+        assert_eq!(chapters[3].2, Duration::from_secs(10));
+
+        let srt = subtitles(Duration::from_secs(10));
+        assert!(srt.starts_with("1\n00:00:00,000 --> 00:00:03,000\nCue 1 at 0s\n\n"));
+        assert!(srt.contains("2\n00:00:04,000 --> 00:00:07,000\nCue 2 at 4s\n"));
+        assert!(!srt.contains("\n3\n"));
+    }
+
+    /// Writes a real file and prepares it.
+    #[test]
+    #[ignore = "needs GStreamer with svtav1enc, matroskamux and dashsink installed"]
+    fn prepares_generated_media() {
+        let dir = std::env::temp_dir().join(format!("movieshare-testmedia-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("test.mkv");
+        let options = Options {
+            duration: Duration::from_secs(8),
+            vfr: true,
+        };
+        generate(&input, &options).unwrap();
+
+        movieshare_core::Preparer::new(&input)
+            .ladder([1])
+            .output(dir.join("out"))
+            .run()
+            .unwrap();
+        let manifest = crate::mpd::parse(
+            &std::fs::read_to_string(dir.join("out").join(crate::library::MANIFEST)).unwrap(),
+        )
+        .unwrap();
+        assert!(manifest.duration_secs.unwrap() >= 7.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+//! Bandwidth and connection limits for the server, so remote viewers can't
+//! saturate the uplink.
+//!
+//! Limits apply to clients outside the local network unless `include_lan`
+//! is set. Response bodies are paced through a token bucket per client and
+//! one shared by everyone; requests beyond the connection limit get a 503.
+
+use anyhow::{Result, bail};
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Bytes per second for each client
+    pub per_client: Option<u64>,
+    /// Bytes per second for all limited clients together
+    pub total: Option<u64>,
+    /// Requests limited clients may have in flight at once
+    pub max_connections: Option<usize>,
+    /// Also limit clients on loopback and private addresses
+    pub include_lan: bool,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.per_client.is_none() && self.total.is_none() && self.max_connections.is_none()
+    }
+}
+
+/// Parse a rate like `750K`, `6M` or `1.5G` in bytes per second.
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    let rate = rate
+        .trim()
+        .trim_end_matches("/s")
+        .trim_end_matches(['B', 'b']);
+    let (number, scale) = match rate.chars().last() {
+        Some('k' | 'K') => (&rate[..rate.len() - 1], 1e3),
+        Some('m' | 'M') => (&rate[..rate.len() - 1], 1e6),
+        Some('g' | 'G') => (&rate[..rate.len() - 1], 1e9),
+        _ => (rate, 1.0),
+    };
+    let bytes = number.parse::<f64>()? * scale;
+    if bytes < 1.0 {
+        bail!("Rate must be at least one byte per second");
+    }
+    Ok(bytes as u64)
+}
+
+/// Up to a second's worth of burst, then `rate` bytes per second.
+struct Bucket {
+    rate: f64,
+    /// Tokens available and when they were counted; goes negative while
+    /// callers wait for bytes they already reserved
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` and say how long to wait before sending them.
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, counted) = *state;
+        let tokens = (tokens + now.duration_since(counted).as_secs_f64() * self.rate)
+            .min(self.rate)
+            - bytes as f64;
+        *state = (tokens, now);
+        match tokens < 0.0 {
+            true => Duration::from_secs_f64(-tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Throttle {
+    limits: Limits,
+    total: Option<Arc<Bucket>>,
+    clients: Arc<Mutex<HashMap<IpAddr, Arc<Bucket>>>>,
+    connections: Option<Arc<Semaphore>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Self {
+            total: limits.total.map(|rate| Arc::new(Bucket::new(rate))),
+            connections: limits
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            clients: Arc::default(),
+            limits,
+        }
+    }
+
+    fn applies_to(&self, ip: IpAddr) -> bool {
+        if self.limits.include_lan {
+            return true;
+        }
+        let ip = ip.to_canonical();
+        let local = match ip {
+            IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+            IpAddr::V6(ip) => {
+                ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local()
+            }
+        };
+        !local
+    }
+
+    fn client_bucket(&self, ip: IpAddr) -> Option<Arc<Bucket>> {
+        let rate = self.limits.per_client?;
+        let mut clients = self.clients.lock().unwrap();
+        // Forget clients with nothing in flight
+        clients.retain(|_, bucket| Arc::strong_count(bucket) > 1);
+        Some(
+            clients
+                .entry(ip)
+                .or_insert_with(|| Arc::new(Bucket::new(rate)))
+                .clone(),
+        )
+    }
+}
+
+pub(crate) async fn limit(
+    State(throttle): State<Throttle>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Without connection info (as in tests) there's no client to limit
+    let Some(ip) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip())
+    else {
+        return next.run(request).await;
+    };
+    if !throttle.applies_to(ip) {
+        return next.run(request).await;
+    }
+
+    let permit = match &throttle.connections {
+        Some(connections) => match connections.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                return (StatusCode::SERVICE_UNAVAILABLE, "Too many connections").into_response();
+            }
+        },
+        None => None,
+    };
+    let client = throttle.client_bucket(ip);
+    let total = throttle.total.clone();
+
+    let (parts, body) = next.run(request).await.into_parts();
+    let body = body.into_data_stream().then(move |chunk| {
+        // Held until the response is done
+        let _permit = &permit;
+        let buckets = [client.clone(), total.clone()];
+        async move {
+            if let Ok(chunk) = &chunk {
+                let now = Instant::now();
+                let wait = buckets
+                    .iter()
+                    .flatten()
+                    .map(|bucket| bucket.reserve(chunk.len(), now))
+                    .max()
+                    .unwrap_or_default();
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+            }
+            chunk
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("750K").unwrap(), 750_000);
+        assert_eq!(parse_rate("1.5M").unwrap(), 1_500_000);
+        assert_eq!(parse_rate("2MB/s").unwrap(), 2_000_000);
+        assert_eq!(parse_rate("4096").unwrap(), 4096);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("0").is_err());
+    }
+
+    #[test]
+    fn paces_after_the_burst() {
+        let start = Instant::now();
+        let bucket = Bucket::new(1000);
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, start), Duration::from_millis(500));
+        // Waiting pays the debt back
+        assert_eq!(
+            bucket.reserve(0, start + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            bucket.reserve(100, start + Duration::from_millis(600)),
+            Duration::ZERO
+        );
+
+        let throttle = Throttle::new(Limits::default());
+        assert!(!throttle.applies_to("192.168.1.20".parse().unwrap()));
+        assert!(!throttle.applies_to("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(throttle.applies_to("203.0.113.7".parse().unwrap()));
+    }
+}
@@ -0,0 +1,202 @@
+//! Movie metadata lookups against The Movie Database.
+//!
+//! Lookups need a TMDB API key in `TMDB_API_KEY`. Titles are matched by
+//! searching for a name guessed from the file or directory name.
+
+use crate::library::Metadata;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+pub const API_KEY_VAR: &str = "TMDB_API_KEY";
+const API: &str = "https://api.themoviedb.org/3";
+const IMAGES: &str = "https://image.tmdb.org/t/p/original";
+/// Country whose certification is taken as the content rating.
+const RATING_COUNTRY: &str = "US";
+
+#[derive(Deserialize)]
+struct SearchResults {
+    results: Vec<SearchResult>,
+}
+
+#[derive(Deserialize)]
+struct SearchResult {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct Genre {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ReleaseDate {
+    certification: String,
+}
+
+#[derive(Deserialize)]
+struct CountryReleases {
+    iso_3166_1: String,
+    release_dates: Vec<ReleaseDate>,
+}
+
+#[derive(Deserialize, Default)]
+struct ReleaseDates {
+    results: Vec<CountryReleases>,
+}
+
+impl ReleaseDates {
+    fn certification(&self) -> Option<String> {
+        self.results
+            .iter()
+            .filter(|country| country.iso_3166_1 == RATING_COUNTRY)
+            .flat_map(|country| &country.release_dates)
+            .map(|release| release.certification.trim())
+            .find(|certification| !certification.is_empty())
+            .map(str::to_string)
+    }
+}
+
+#[derive(Deserialize)]
+struct Movie {
+    id: u64,
+    title: String,
+    release_date: Option<String>,
+    overview: Option<String>,
+    #[serde(default)]
+    genres: Vec<Genre>,
+    poster_path: Option<String>,
+    backdrop_path: Option<String>,
+    #[serde(default)]
+    release_dates: ReleaseDates,
+}
+
+impl From<Movie> for Metadata {
+    fn from(movie: Movie) -> Self {
+        Self {
+            title: movie.title,
+            year: movie
+                .release_date
+                .and_then(|date| date.get(..4).and_then(|year| year.parse().ok())),
+            overview: movie.overview.filter(|overview| !overview.is_empty()),
+            genres: movie.genres.into_iter().map(|genre| genre.name).collect(),
+            rating: movie.release_dates.certification(),
+            poster_url: movie.poster_path.map(|path| format!("{}{}", IMAGES, path)),
+            backdrop_url: movie
+                .backdrop_path
+                .map(|path| format!("{}{}", IMAGES, path)),
+            tmdb_id: Some(movie.id),
+            ipfs_cid: None,
+            markers: Vec::new(),
+            chapters: Vec::new(),
+            encode_log: None,
+            format_version: 0,
+            attention: Vec::new(),
+        }
+    }
+}
+
+/// Guess a search query and release year from a name like `The.Movie.2019.1080p`.
+pub fn guess_query(name: &str) -> (String, Option<u32>) {
+    let words: Vec<_> = name
+        .split(|c: char| c == '.' || c == '_' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    // The year is the last one that looks like it, so titles like "1917" survive
+    let year_at = words.iter().rposition(|word| {
+        let word = word.trim_matches(|c| matches!(c, '(' | ')' | '[' | ']'));
+        word.len() == 4 && matches!(word.parse::<u32>(), Ok(1900..=2099))
+    });
+    match year_at {
+        Some(i) if i > 0 => {
+            let year = words[i].trim_matches(|c| matches!(c, '(' | ')' | '[' | ']'));
+            (words[..i].join(" "), year.parse().ok())
+        }
+        _ => (words.join(" "), None),
+    }
+}
+
+pub struct Client {
+    api_key: String,
+}
+
+impl Client {
+    pub fn from_env() -> Result<Self> {
+        let api_key = std::env::var(API_KEY_VAR).context(format!(
+            "Fetching metadata needs a TMDB API key in {}",
+            API_KEY_VAR
+        ))?;
+        Ok(Self { api_key })
+    }
+
+    /// Look up the best match for a file or directory name.
+    pub fn lookup(&self, name: &str) -> Result<Option<Metadata>> {
+        let (query, year) = guess_query(name);
+        let mut request = ureq::get(format!("{}/search/movie", API))
+            .query("api_key", &self.api_key)
+            .query("query", &query);
+        if let Some(year) = year {
+            request = request.query("year", year.to_string());
+        }
+        let results: SearchResults = request
+            .call()
+            .context(format!("TMDB search for {:?} failed", query))?
+            .body_mut()
+            .read_json()?;
+        let Some(best) = results.results.first() else {
+            return Ok(None);
+        };
+
+        let movie: Movie = ureq::get(format!("{}/movie/{}", API, best.id))
+            .query("api_key", &self.api_key)
+            .query("append_to_response", "release_dates")
+            .call()
+            .context(format!("Failed to fetch TMDB movie {}", best.id))?
+            .body_mut()
+            .read_json()?;
+        Ok(Some(movie.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guesses_queries() {
+        assert_eq!(
+            guess_query("The.Movie.2019.1080p.BluRay"),
+            (String::from("The Movie"), Some(2019))
+        );
+        assert_eq!(
+            guess_query("Some Film (1984)"),
+            (String::from("Some Film"), Some(1984))
+        );
+        assert_eq!(guess_query("1917"), (String::from("1917"), None));
+        assert_eq!(guess_query("Heat"), (String::from("Heat"), None));
+    }
+
+    #[test]
+    fn converts_movies() {
+        let movie: Movie = serde_json::from_str(
+            r#"{"id": 949, "title": "Heat", "release_date": "1995-12-15",
+                "overview": "", "genres": [{"id": 28, "name": "Action"}],
+                "poster_path": "/heat.jpg", "backdrop_path": null,
+                "release_dates": {"results": [
+                    {"iso_3166_1": "DE", "release_dates": [{"certification": "16"}]},
+                    {"iso_3166_1": "US", "release_dates": [{"certification": ""}, {"certification": "R"}]}
+                ]}}"#,
+        )
+        .unwrap();
+        let metadata = Metadata::from(movie);
+        assert_eq!(metadata.year, Some(1995));
+        assert_eq!(metadata.overview, None);
+        assert_eq!(metadata.genres, vec![String::from("Action")]);
+        assert_eq!(metadata.rating.as_deref(), Some("R"));
+        assert_eq!(
+            metadata.poster_url.as_deref(),
+            Some("https://image.tmdb.org/t/p/original/heat.jpg")
+        );
+        assert_eq!(metadata.label(), "Heat (1995)");
+    }
+}
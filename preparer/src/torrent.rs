@@ -0,0 +1,391 @@
+//! BitTorrent v2 (BEP 52) metainfo for prepared titles.
+//!
+//! Version 2 torrents hash every file separately and start each one on a piece
+//! boundary, so a piece never spans two segments and peers can fetch and
+//! verify segments independently.
+
+use crate::bencode::Value;
+use crate::library;
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Size of the leaves of each file's merkle tree.
+const BLOCK: usize = 16 * 1024;
+const MIN_PIECE: u64 = BLOCK as u64;
+const MAX_PIECE: u64 = 4 * 1024 * 1024;
+
+type Hash = [u8; 32];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentFile {
+    /// Relative to the torrent's directory
+    pub path: PathBuf,
+    pub length: u64,
+    /// None for empty files
+    pub pieces_root: Option<Hash>,
+    /// Hashes of each piece, for files longer than one piece
+    pub piece_layer: Vec<Hash>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Torrent {
+    pub name: String,
+    pub piece_length: u64,
+    /// In file tree order, which is also the order of their pieces
+    pub files: Vec<TorrentFile>,
+    pub trackers: Vec<String>,
+}
+
+/// Where `preparer torrent` writes the torrent for a title's directory.
+pub fn default_path(dir: &Path) -> PathBuf {
+    let mut path = dir.as_os_str().to_owned();
+    path.push(".torrent");
+    PathBuf::from(path)
+}
+
+/// The largest power of two no bigger than the typical file, so most
+/// segments fit in a single piece.
+fn choose_piece_length(lengths: &[u64]) -> u64 {
+    let mut lengths: Vec<u64> = lengths.iter().copied().filter(|&len| len > 0).collect();
+    lengths.sort();
+    let median = lengths.get(lengths.len() / 2).copied().unwrap_or(MIN_PIECE);
+    let mut piece = MIN_PIECE;
+    while piece * 2 <= median && piece < MAX_PIECE {
+        piece *= 2;
+    }
+    piece
+}
+
+fn merkle_root(mut layer: Vec<Hash>) -> Hash {
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(pair[0]);
+                hasher.update(pair[1]);
+                hasher.finalize().into()
+            })
+            .collect();
+    }
+    layer[0]
+}
+
+/// The pieces root and piece layer of a file's blocks.
+fn hash_blocks(mut leaves: Vec<Hash>, piece_length: u64) -> (Option<Hash>, Vec<Hash>) {
+    if leaves.is_empty() {
+        return (None, Vec::new());
+    }
+    let blocks_per_piece = (piece_length / BLOCK as u64) as usize;
+    if leaves.len() <= blocks_per_piece {
+        leaves.resize(leaves.len().next_power_of_two(), [0; 32]);
+        return (Some(merkle_root(leaves)), Vec::new());
+    }
+
+    leaves.resize(
+        leaves.len().div_ceil(blocks_per_piece) * blocks_per_piece,
+        [0; 32],
+    );
+    let layer: Vec<Hash> = leaves
+        .chunks(blocks_per_piece)
+        .map(|piece| merkle_root(piece.to_vec()))
+        .collect();
+    let mut padded = layer.clone();
+    padded.resize(
+        layer.len().next_power_of_two(),
+        merkle_root(vec![[0; 32]; blocks_per_piece]),
+    );
+    (Some(merkle_root(padded)), layer)
+}
+
+fn hash_file(path: &Path, piece_length: u64) -> Result<(Option<Hash>, Vec<Hash>)> {
+    let mut file =
+        std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut leaves = Vec::new();
+    let mut block = vec![0; BLOCK];
+    loop {
+        let mut filled = 0;
+        while filled < BLOCK {
+            match file.read(&mut block[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        leaves.push(Sha256::digest(&block[..filled]).into());
+        if filled < BLOCK {
+            break;
+        }
+    }
+    Ok(hash_blocks(leaves, piece_length))
+}
+
+impl Torrent {
+    /// Hash every file in a title's directory, leaving out hidden files like the journal.
+    pub fn create(dir: &Path, trackers: Vec<String>) -> Result<Self> {
+        let mut found = Vec::new();
+        library::collect_files(dir, dir, &mut found)?;
+        found.retain(|(name, _)| !name.split('/').any(|part| part.starts_with('.')));
+        if found.is_empty() {
+            bail!("No files to share in {}", dir.display());
+        }
+        // The order of the file tree, which sorts each directory level separately
+        found.sort_by(|(a, _), (b, _)| a.split('/').cmp(b.split('/')));
+
+        let lengths: Vec<u64> = found.iter().map(|(_, length)| *length).collect();
+        let piece_length = choose_piece_length(&lengths);
+        let mut files = Vec::new();
+        for (name, length) in found {
+            let (pieces_root, piece_layer) = hash_file(&dir.join(&name), piece_length)?;
+            files.push(TorrentFile {
+                path: PathBuf::from(name),
+                length,
+                pieces_root,
+                piece_layer,
+            });
+        }
+
+        let name = std::path::absolute(dir)?
+            .file_name()
+            .map_or(String::from("title"), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        Ok(Self {
+            name,
+            piece_length,
+            files,
+            trackers,
+        })
+    }
+
+    fn info(&self) -> Value {
+        let mut tree = BTreeMap::new();
+        for file in &self.files {
+            let mut entry = vec![("length", Value::Int(file.length as i64))];
+            if let Some(root) = file.pieces_root {
+                entry.push(("pieces root", Value::Bytes(root.to_vec())));
+            }
+            let mut node = &mut tree;
+            for part in file.path.iter() {
+                let child = node
+                    .entry(part.as_encoded_bytes().to_vec())
+                    .or_insert_with(|| Value::Dict(BTreeMap::new()));
+                let Value::Dict(child) = child else {
+                    unreachable!()
+                };
+                node = child;
+            }
+            node.insert(Vec::new(), Value::dict(entry));
+        }
+        Value::dict([
+            ("file tree", Value::Dict(tree)),
+            ("meta version", Value::Int(2)),
+            ("name", Value::str(&self.name)),
+            ("piece length", Value::Int(self.piece_length as i64)),
+        ])
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut torrent = vec![("created by", Value::str("movieshare"))];
+        if let Some(first) = self.trackers.first() {
+            torrent.push(("announce", Value::str(first)));
+            torrent.push((
+                "announce-list",
+                Value::List(
+                    self.trackers
+                        .iter()
+                        .map(|tracker| Value::List(vec![Value::str(tracker)]))
+                        .collect(),
+                ),
+            ));
+        }
+        let layers = self
+            .files
+            .iter()
+            .filter(|file| !file.piece_layer.is_empty())
+            .filter_map(|file| {
+                Some((
+                    file.pieces_root?.to_vec(),
+                    Value::Bytes(file.piece_layer.concat()),
+                ))
+            })
+            .collect();
+        torrent.push(("info", self.info()));
+        torrent.push(("piece layers", Value::Dict(layers)));
+        Value::dict(torrent).encode()
+    }
+
+    /// Read the parts of a .torrent needed to seed it.
+    pub fn read(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).context(format!("Failed to read {}", path.display()))?;
+        let torrent =
+            Value::decode(&data).context(format!("Invalid torrent: {}", path.display()))?;
+        let info = torrent.get("info").context("Torrent has no info")?;
+        if info.get("meta version").and_then(Value::as_int) != Some(2) {
+            bail!("{} isn't a version 2 torrent", path.display());
+        }
+
+        let mut trackers = Vec::new();
+        if let Some(Value::List(tiers)) = torrent.get("announce-list") {
+            for tier in tiers {
+                if let Value::List(urls) = tier {
+                    trackers.extend(urls.iter().filter_map(Value::as_str).map(String::from));
+                }
+            }
+        } else if let Some(announce) = torrent.get("announce").and_then(Value::as_str) {
+            trackers.push(announce.to_string());
+        }
+
+        let mut files = Vec::new();
+        let tree = info.get("file tree").context("Torrent has no file tree")?;
+        read_tree(tree, PathBuf::new(), &mut files)?;
+        Ok(Self {
+            name: info
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            piece_length: info
+                .get("piece length")
+                .and_then(Value::as_int)
+                .context("Torrent has no piece length")? as u64,
+            files,
+            trackers,
+        })
+    }
+
+    /// SHA-256 of the info dictionary.
+    pub fn info_hash(&self) -> Hash {
+        Sha256::digest(self.info().encode()).into()
+    }
+
+    pub fn magnet(&self) -> String {
+        let hash: String = self
+            .info_hash()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let mut link = format!("magnet:?xt=urn:btmh:1220{}&dn={}", hash, self.name);
+        for tracker in &self.trackers {
+            link.push_str(&format!("&tr={}", crate::s3::uri_encode(tracker, false)));
+        }
+        link
+    }
+
+    fn file_pieces(&self, file: &TorrentFile) -> u64 {
+        file.length.div_ceil(self.piece_length)
+    }
+
+    pub fn piece_count(&self) -> u64 {
+        self.files.iter().map(|file| self.file_pieces(file)).sum()
+    }
+
+    /// The file holding a piece, where in it the piece starts, and its length.
+    pub fn locate(&self, piece: u64) -> Option<(&TorrentFile, u64, u64)> {
+        let mut first = 0;
+        for file in &self.files {
+            let count = self.file_pieces(file);
+            if piece < first + count {
+                let start = (piece - first) * self.piece_length;
+                return Some((file, start, (file.length - start).min(self.piece_length)));
+            }
+            first += count;
+        }
+        None
+    }
+}
+
+fn read_tree(node: &Value, path: PathBuf, files: &mut Vec<TorrentFile>) -> Result<()> {
+    let Value::Dict(entries) = node else {
+        bail!("Invalid file tree in torrent");
+    };
+    for (name, child) in entries {
+        if name.is_empty() {
+            files.push(TorrentFile {
+                path: path.clone(),
+                length: child
+                    .get("length")
+                    .and_then(Value::as_int)
+                    .context("File without a length in torrent")? as u64,
+                pieces_root: child
+                    .get("pieces root")
+                    .and_then(Value::as_bytes)
+                    .and_then(|root| root.try_into().ok()),
+                piece_layer: Vec::new(),
+            });
+        } else {
+            let name = std::str::from_utf8(name)?;
+            if name == ".." || name.contains('/') {
+                bail!("Unsafe path in torrent: {}", name);
+            }
+            read_tree(child, path.join(name), files)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(a: Hash, b: Hash) -> Hash {
+        merkle_root(vec![a, b])
+    }
+
+    #[test]
+    fn builds_merkle_trees() {
+        let [a, b, c] = [[1; 32], [2; 32], [3; 32]];
+        let zero = [0; 32];
+        // Smaller than a piece: a single tree over the blocks
+        assert_eq!(hash_blocks(vec![a], 32768), (Some(a), Vec::new()));
+        assert_eq!(
+            hash_blocks(vec![a, b, c], 65536),
+            (Some(pair(pair(a, b), pair(c, zero))), Vec::new())
+        );
+        // Larger: piece hashes, padded with the hash of an empty piece
+        let pieces = vec![pair(a, b), pair(c, zero)];
+        assert_eq!(
+            hash_blocks(vec![a, b, c], 32768),
+            (Some(pair(pieces[0], pieces[1])), pieces.clone())
+        );
+        assert_eq!(hash_blocks(Vec::new(), 32768), (None, Vec::new()));
+        assert_eq!(
+            choose_piece_length(&[100, 3_000_000, 5_000_000]),
+            2 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn round_trips_through_files() {
+        let dir = std::env::temp_dir().join(format!("movieshare-torrent-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("manifest.mpd"), "<MPD/>").unwrap();
+        std::fs::write(dir.join("sub/seg-1.m4s"), vec![7; 40000]).unwrap();
+        std::fs::write(dir.join(".journal"), "hidden").unwrap();
+
+        let torrent = Torrent::create(&dir, vec![String::from("http://t/announce")]).unwrap();
+        assert_eq!(torrent.files.len(), 2);
+        assert_eq!(torrent.piece_length, 32768);
+        assert_eq!(torrent.files[1].piece_layer.len(), 2);
+        assert_eq!(torrent.piece_count(), 3);
+        let (file, start, length) = torrent.locate(2).unwrap();
+        assert_eq!(
+            (file.path.as_path(), start, length),
+            (Path::new("sub/seg-1.m4s"), 32768, 7232)
+        );
+        assert!(torrent.locate(3).is_none());
+
+        let path = default_path(&dir);
+        std::fs::write(&path, torrent.encode()).unwrap();
+        let read = Torrent::read(&path).unwrap();
+        assert_eq!(read.info_hash(), torrent.info_hash());
+        assert_eq!(read.trackers, torrent.trackers);
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
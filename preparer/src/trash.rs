@@ -0,0 +1,152 @@
+//! Removed titles, kept for a while in case the removal was a mistake.
+//!
+//! `preparer library remove --delete-files` moves the title's directory
+//! into `.trash` in the library rather than deleting it, since preparing it
+//! again can take hours. `preparer library restore` moves it back. Entries
+//! are deleted for good once their retention period is up, the next time
+//! the trash is touched.
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TRASH_DIR: &str = ".trash";
+/// What each entry records about itself, next to the title's directory
+const ENTRY_FILENAME: &str = "entry.json";
+/// Where the title's directory goes within its entry
+const TITLE_DIR: &str = "title";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrashEntry {
+    /// Name of the title's directory in the library
+    pub name: String,
+    /// Unix time it was removed at
+    pub trashed_at: u64,
+    /// Unix time it'll be deleted for good after
+    pub expires_at: u64,
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Move the title in directory `path` of `library` into the trash, to be
+/// kept for `retention`.
+pub fn trash(library: &Path, path: &Path, retention: Duration) -> Result<TrashEntry> {
+    let name = path
+        .file_name()
+        .context(format!("{} isn't a title's directory", path.display()))?
+        .to_string_lossy()
+        .into_owned();
+    let now = SystemTime::now();
+    let trashed_at = unix_secs(now);
+    let dir = library
+        .join(TRASH_DIR)
+        .join(format!("{}-{}", trashed_at, name));
+    std::fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+    let entry = TrashEntry {
+        name,
+        trashed_at,
+        expires_at: unix_secs(now + retention),
+        dir,
+    };
+    std::fs::write(
+        entry.dir.join(ENTRY_FILENAME),
+        serde_json::to_string_pretty(&entry)?,
+    )?;
+    std::fs::rename(path, entry.dir.join(TITLE_DIR))
+        .context(format!("Failed to move {} to the trash", path.display()))?;
+    Ok(entry)
+}
+
+/// Everything in the trash, oldest first.
+pub fn entries(library: &Path) -> Result<Vec<TrashEntry>> {
+    let trash = library.join(TRASH_DIR);
+    let Ok(dirs) = std::fs::read_dir(&trash) else {
+        return Ok(Vec::new());
+    };
+    let mut entries = Vec::new();
+    for dir in dirs.flatten() {
+        let path = dir.path().join(ENTRY_FILENAME);
+        let Ok(json) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let mut entry: TrashEntry =
+            serde_json::from_str(&json).context(format!("Failed to parse {}", path.display()))?;
+        entry.dir = dir.path();
+        entries.push(entry);
+    }
+    entries.sort_by_key(|entry| entry.trashed_at);
+    Ok(entries)
+}
+
+/// Delete the entries whose retention is up, returning them.
+pub fn purge(library: &Path, now: SystemTime) -> Result<Vec<TrashEntry>> {
+    let now = unix_secs(now);
+    let mut purged = Vec::new();
+    for entry in entries(library)? {
+        if entry.expires_at < now {
+            std::fs::remove_dir_all(&entry.dir)
+                .context(format!("Failed to delete {}", entry.dir.display()))?;
+            purged.push(entry);
+        }
+    }
+    Ok(purged)
+}
+
+/// Move the latest trashed title named `name` back into `library`,
+/// returning its directory.
+pub fn restore(library: &Path, name: &str) -> Result<PathBuf> {
+    let Some(entry) = entries(library)?
+        .into_iter()
+        .rev()
+        .find(|entry| entry.name == name)
+    else {
+        bail!("Nothing named {} in the trash", name);
+    };
+    let path = library.join(&entry.name);
+    if path.exists() {
+        bail!("{} is already there; move it aside first", path.display());
+    }
+    std::fs::rename(entry.dir.join(TITLE_DIR), &path)
+        .context(format!("Failed to move {} back from the trash", entry.name))?;
+    std::fs::remove_dir_all(&entry.dir)
+        .context(format!("Failed to delete {}", entry.dir.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_until_purged() {
+        let library = std::env::temp_dir().join(format!("movieshare-trash-{}", std::process::id()));
+        let title = library.join("movie");
+        std::fs::create_dir_all(&title).unwrap();
+        std::fs::write(title.join("manifest.mpd"), "<MPD/>").unwrap();
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        trash(&library, &title, day).unwrap();
+        assert!(!title.exists());
+        assert_eq!(entries(&library).unwrap()[0].name, "movie");
+        assert!(purge(&library, SystemTime::now()).unwrap().is_empty());
+
+        assert_eq!(restore(&library, "movie").unwrap(), title);
+        assert!(title.join("manifest.mpd").is_file());
+        assert!(entries(&library).unwrap().is_empty());
+        assert!(restore(&library, "movie").is_err());
+
+        // Gone for good once its time is up
+        trash(&library, &title, day).unwrap();
+        let purged = purge(&library, SystemTime::now() + day * 2).unwrap();
+        assert_eq!(purged.len(), 1);
+        assert!(restore(&library, "movie").is_err());
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
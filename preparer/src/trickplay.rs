@@ -0,0 +1,387 @@
+//! Trick-play thumbnails, for previews while scrubbing.
+//!
+//! With `preparer prepare --trick-play`, a frame every [`INTERVAL`] is
+//! scaled down and laid into JPEG tiles of [`COLUMNS`] by [`ROWS`] in
+//! `thumbnails/`, which the manifest gives as a DASH-IF image adaptation
+//! set. Players that know the format use it as it is; for other frontends,
+//! `GET /api/titles/{id}/thumbnail?t=<secs>` answers with the tile and the
+//! part of it showing that moment.
+
+use crate::library::MANIFEST;
+use crate::mpd::{self, ThumbnailTrack};
+use anyhow::{Context, Result};
+use gstreamer_video as gst_video;
+use gstreamer_video::prelude::*;
+use image::{ImageFormat, RgbImage, imageops};
+use movieshare_core::gst;
+use movieshare_core::{MediaType, PipelineBranch};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Directory of the title the tiles are written into
+pub const DIR: &str = "thumbnails";
+/// How far apart the thumbnails are taken
+pub const INTERVAL: Duration = Duration::from_secs(10);
+/// Thumbnails across a tile
+pub const COLUMNS: u32 = 10;
+/// Thumbnails down a tile
+pub const ROWS: u32 = 10;
+/// Width of a thumbnail; the height follows the picture's aspect ratio.
+const WIDTH: i32 = 160;
+/// Template of the tiles' URLs in the manifest
+const MEDIA: &str = "thumbnails/tile-$Number%05d$.jpg";
+
+/// File name of the tile numbered `number`, from 1.
+fn tile_name(number: u64) -> String {
+    format!("tile-{:05}.jpg", number)
+}
+
+/// `template` with its `$Number$`, or `$Number%05d$` and the like, filled in.
+pub fn fill_number(template: &str, number: u64) -> String {
+    let Some(start) = template.find("$Number") else {
+        return template.to_string();
+    };
+    let rest = &template[start + "$Number".len()..];
+    let Some(end) = rest.find('$') else {
+        return template.to_string();
+    };
+    let width = rest[..end]
+        .strip_prefix("%0")
+        .and_then(|format| format.strip_suffix('d'))
+        .and_then(|width| width.parse().ok())
+        .unwrap_or(0);
+    format!(
+        "{}{:0width$}{}",
+        &template[..start],
+        number,
+        &rest[end + 1..],
+        width = width
+    )
+}
+
+/// The thumbnail of one moment: which tile it's in, and where.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    /// Relative to the manifest
+    pub url: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// The stretch of the title the thumbnail stands for
+    pub start_secs: f64,
+    pub end_secs: f64,
+}
+
+/// The thumbnail of `track` showing `secs` into a title lasting
+/// `duration_secs`, if it has one.
+pub fn thumbnail_at(
+    track: &ThumbnailTrack,
+    duration_secs: Option<f64>,
+    secs: f64,
+) -> Option<Thumbnail> {
+    let per_tile = (track.columns * track.rows) as u64;
+    let thumbnail_secs = track.tile_secs / per_tile as f64;
+    if !secs.is_finite()
+        || secs < 0.0
+        || thumbnail_secs <= 0.0
+        || duration_secs.is_some_and(|duration| secs >= duration)
+    {
+        return None;
+    }
+    let index = (secs / thumbnail_secs) as u64;
+    let position = (index % per_tile) as u32;
+    let (width, height) = (track.width / track.columns, track.height / track.rows);
+    let start_secs = index as f64 * thumbnail_secs;
+    Some(Thumbnail {
+        url: fill_number(&track.media, track.start_number + index / per_tile),
+        x: position % track.columns * width,
+        y: position / track.columns * height,
+        width,
+        height,
+        start_secs,
+        end_secs: duration_secs.map_or(start_secs + thumbnail_secs, |duration| {
+            duration.min(start_secs + thumbnail_secs)
+        }),
+    })
+}
+
+/// Every tile of `track` for a title lasting `duration_secs`.
+pub fn tiles(track: &ThumbnailTrack, duration_secs: f64) -> Vec<String> {
+    let count = match track.tile_secs > 0.0 {
+        true => (duration_secs / track.tile_secs).ceil() as u64,
+        false => 0,
+    };
+    (0..count)
+        .map(|tile| fill_number(&track.media, track.start_number + tile))
+        .collect()
+}
+
+/// Tiles as they're filled in.
+#[derive(Default)]
+struct Tiles {
+    /// The tile being filled, by index from 0
+    current: Option<(u64, RgbImage)>,
+    /// Size of a thumbnail, from the first frame
+    size: Option<(u32, u32)>,
+    /// How many tiles were written, and their bytes
+    written: u64,
+    bytes: u64,
+}
+
+impl Tiles {
+    /// Lay `still` into the tile it belongs in as the thumbnail at `index`,
+    /// writing the tile before when it moves on to another.
+    fn add(&mut self, dir: &Path, index: u64, still: &RgbImage) -> Result<()> {
+        let per_tile = (COLUMNS * ROWS) as u64;
+        let (width, height) = *self.size.get_or_insert(still.dimensions());
+        let tile = index / per_tile;
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(current, _)| *current != tile)
+        {
+            self.flush(dir)?;
+        }
+        let (_, image) = self
+            .current
+            .get_or_insert_with(|| (tile, RgbImage::new(width * COLUMNS, height * ROWS)));
+        let position = (index % per_tile) as u32;
+        imageops::replace(
+            image,
+            still,
+            (position % COLUMNS * width) as i64,
+            (position / COLUMNS * height) as i64,
+        );
+        Ok(())
+    }
+
+    /// Write the tile being filled.
+    fn flush(&mut self, dir: &Path) -> Result<()> {
+        let Some((tile, image)) = self.current.take() else {
+            return Ok(());
+        };
+        let path = dir.join(tile_name(tile + 1));
+        image
+            .save_with_format(&path, ImageFormat::Jpeg)
+            .context(format!("Failed to write {}", path.display()))?;
+        self.written = self.written.max(tile + 1);
+        self.bytes += std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        Ok(())
+    }
+}
+
+/// Lays a thumbnail every [`INTERVAL`] into tiles in the output directory.
+#[derive(Clone)]
+pub struct TrickPlayBranch {
+    queue: gst::Element,
+    videoconvert: gst::Element,
+    videoscale: gst::Element,
+    capsfilter: gst::Element,
+    sink: gst::Element,
+    /// Where the tiles go
+    dir: PathBuf,
+    tiles: Arc<Mutex<Tiles>>,
+}
+
+impl TrickPlayBranch {
+    /// Take thumbnails into [`DIR`] of the output directory `dir`.
+    pub fn new(dir: &Path) -> Result<Self> {
+        let dir = dir.join(DIR);
+        std::fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+        Ok(Self {
+            queue: gst::ElementFactory::make("queue").build()?,
+            videoconvert: gst::ElementFactory::make("videoconvert").build()?,
+            videoscale: gst::ElementFactory::make("videoscale").build()?,
+            capsfilter: gst::ElementFactory::make("capsfilter")
+                .property(
+                    "caps",
+                    gst::Caps::builder("video/x-raw")
+                        .field("format", "RGB")
+                        .field("width", WIDTH)
+                        .field("pixel-aspect-ratio", gst::Fraction::new(1, 1))
+                        .build(),
+                )
+                .build()?,
+            sink: gst::ElementFactory::make("fakesink").build()?,
+            dir,
+            tiles: Arc::default(),
+        })
+    }
+
+    /// Write the last tile and add the thumbnails to the manifest in
+    /// `output`, once the pipeline has finished.
+    pub fn finish(&self, output: &Path) -> Result<()> {
+        let mut tiles = self.tiles.lock().unwrap();
+        tiles.flush(&self.dir)?;
+        let Some((width, height)) = tiles.size.filter(|_| tiles.written > 0) else {
+            return Ok(());
+        };
+        let path = output.join(MANIFEST);
+        let xml =
+            std::fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+        let tile_secs = INTERVAL.as_secs_f64() * (COLUMNS * ROWS) as f64;
+        let track = ThumbnailTrack {
+            id: mpd::next_representation_id(&mpd::parse(&xml)?),
+            media: MEDIA.to_string(),
+            start_number: 1,
+            tile_secs,
+            columns: COLUMNS,
+            rows: ROWS,
+            width: width * COLUMNS,
+            height: height * ROWS,
+        };
+        let bandwidth = (tiles.bytes * 8) as f64 / (tiles.written as f64 * tile_secs);
+        let set = mpd::thumbnail_adaptation_set(&track, bandwidth.ceil() as u64)?;
+        std::fs::write(&path, mpd::add_adaptation_set(&xml, &set)?)
+            .context(format!("Failed to write {}", path.display()))
+    }
+
+    fn elements(&self) -> [&gst::Element; 5] {
+        [
+            &self.queue,
+            &self.videoconvert,
+            &self.videoscale,
+            &self.capsfilter,
+            &self.sink,
+        ]
+    }
+}
+
+impl PipelineBranch for TrickPlayBranch {
+    fn name(&self) -> String {
+        String::from("trickplay")
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::Video
+    }
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
+        pipeline.add_many(self.elements())?;
+        Ok(())
+    }
+
+    fn link(&self, tee: &gst::Element, _dashsink: &gst::Element) -> Result<()> {
+        tee.link(&self.queue)?;
+        gst::Element::link_many(self.elements())?;
+
+        // Only the first frame of each interval goes on to be scaled
+        let next = Mutex::new(Duration::ZERO);
+        self.queue
+            .static_pad("src")
+            .context("Failed to get src pad from queue")?
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                let Some(pts) = info.buffer().and_then(|buffer| buffer.pts()) else {
+                    return gst::PadProbeReturn::Drop;
+                };
+                let pts = Duration::from_nanos(pts.nseconds());
+                let mut next = next.lock().unwrap();
+                if pts < *next {
+                    return gst::PadProbeReturn::Drop;
+                }
+                *next = INTERVAL * (pts.as_nanos() / INTERVAL.as_nanos() + 1) as u32;
+                gst::PadProbeReturn::Ok
+            });
+
+        let tiles = self.tiles.clone();
+        let dir = self.dir.clone();
+        self.sink
+            .static_pad("sink")
+            .context("Failed to get sink pad from fakesink")?
+            .add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+                if let Some(buffer) = info.buffer()
+                    && let Some(pts) = buffer.pts()
+                    && let Some(caps) = pad.current_caps()
+                    && let Ok(video) = gst_video::VideoInfo::from_caps(&caps)
+                    && let Ok(frame) =
+                        gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, &video)
+                    && let Ok(pixels) = frame.plane_data(0)
+                {
+                    let stride = frame.plane_stride()[0] as usize;
+                    let row = frame.width() as usize * 3;
+                    let rgb = (0..frame.height() as usize)
+                        .flat_map(|y| &pixels[y * stride..y * stride + row])
+                        .copied()
+                        .collect();
+                    let Some(still) = RgbImage::from_raw(frame.width(), frame.height(), rgb) else {
+                        return gst::PadProbeReturn::Ok;
+                    };
+                    let index = pts.nseconds() / INTERVAL.as_nanos() as u64;
+                    if let Err(err) = tiles.lock().unwrap().add(&dir, index, &still) {
+                        eprintln!("Warning: {:#}", err);
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_thumbnail_of_a_moment() {
+        assert_eq!(fill_number(MEDIA, 7), "thumbnails/tile-00007.jpg");
+        assert_eq!(fill_number("tile_$Number$.jpg", 12), "tile_12.jpg");
+
+        let dir = std::env::temp_dir().join(format!("movieshare-trickplay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut filling = Tiles::default();
+        let still = RgbImage::new(16, 9);
+        filling.add(&dir, 0, &still).unwrap();
+        filling.add(&dir, 99, &still).unwrap();
+        // The next tile's first thumbnail writes out the one before
+        filling.add(&dir, 100, &still).unwrap();
+        assert_eq!(
+            image::image_dimensions(dir.join(tile_name(1))).unwrap(),
+            (160, 90)
+        );
+        assert!(!dir.join(tile_name(2)).exists());
+        filling.flush(&dir).unwrap();
+        assert_eq!(filling.written, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let track = ThumbnailTrack {
+            id: String::from("3"),
+            media: MEDIA.to_string(),
+            start_number: 1,
+            tile_secs: 1000.0,
+            columns: COLUMNS,
+            rows: ROWS,
+            width: 1600,
+            height: 900,
+        };
+        let set = mpd::thumbnail_adaptation_set(&track, 2000).unwrap();
+        let xml = mpd::add_adaptation_set(
+            r#"<MPD mediaPresentationDuration="PT25M"><Period><AdaptationSet contentType="video"><Representation id="0" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+            &set,
+        )
+        .unwrap();
+        assert_eq!(mpd::thumbnail_track(&xml).unwrap(), Some(track.clone()));
+        assert_eq!(
+            tiles(&track, 1500.0),
+            ["thumbnails/tile-00001.jpg", "thumbnails/tile-00002.jpg"]
+        );
+
+        // 1234s is the 23rd thumbnail of the second tile
+        assert_eq!(
+            thumbnail_at(&track, Some(1500.0), 1234.5),
+            Some(Thumbnail {
+                url: String::from("thumbnails/tile-00002.jpg"),
+                x: 480,
+                y: 180,
+                width: 160,
+                height: 90,
+                start_secs: 1230.0,
+                end_secs: 1240.0,
+            })
+        );
+        assert_eq!(thumbnail_at(&track, Some(1500.0), 1500.0), None);
+        assert_eq!(thumbnail_at(&track, Some(1500.0), -1.0), None);
+    }
+}
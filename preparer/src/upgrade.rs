@@ -0,0 +1,168 @@
+//! Finding the titles a new encoding profile would change enough to be
+//! worth preparing again, for `preparer library upgrade`.
+//!
+//! A title is compared against the settings its last encode run recorded
+//! in the catalog. Titles prepared before runs were recorded are compared
+//! against their manifest instead, which tells less: just how many video
+//! rungs there are and in what codec.
+//!
+//! Only differences a viewer would notice count: a missing rung, another
+//! codec, other audio, or a missing audio rendition. A new preset or
+//! segment length isn't worth hours of encoding.
+
+use crate::history::RunFilter;
+use crate::library::Catalog;
+use crate::mpd::Representation;
+use anyhow::Result;
+use movieshare_core::EncodingProfile;
+
+/// What a title was last prepared with, as its encode run recorded.
+pub struct Recorded {
+    pub encoder: String,
+    pub profile: EncodingProfile,
+}
+
+impl Catalog {
+    /// The settings `title` was last successfully prepared with, if they
+    /// were recorded.
+    pub fn recorded_settings(&self, title: &str) -> Result<Option<Recorded>> {
+        let runs = self.runs(&RunFilter {
+            title: Some(title.to_string()),
+            since: None,
+            failed_only: false,
+            limit: 20,
+        })?;
+        Ok(runs
+            .into_iter()
+            .filter(|run| run.outcome == "prepared")
+            .find_map(|run| {
+                Some(Recorded {
+                    profile: serde_json::from_str(&run.settings).ok()?,
+                    encoder: run.encoder,
+                })
+            }))
+    }
+}
+
+/// Codec string prefix of video `encoder` writes in the manifest.
+fn codec_prefix(encoder: &str) -> &'static str {
+    match encoder {
+        "svtav1" => "av01",
+        _ => "",
+    }
+}
+
+/// How a title would come out differently under `profile`, one line per
+/// difference; empty when preparing it again isn't worth it.
+pub fn differences(
+    ladder: &[Representation],
+    recorded: Option<&Recorded>,
+    profile: &EncodingProfile,
+) -> Vec<String> {
+    let mut differences = Vec::new();
+    let Some(recorded) = recorded else {
+        let video: Vec<_> = ladder
+            .iter()
+            .filter(|r| r.content_type == "video")
+            .collect();
+        if video.len() < profile.ladder.len() {
+            differences.push(format!(
+                "has {} video rungs of {}",
+                video.len(),
+                profile.ladder.len()
+            ));
+        }
+        let prefix = codec_prefix(profile.encoder_backend());
+        if let Some(codecs) = video
+            .iter()
+            .filter_map(|r| r.codecs.as_deref())
+            .find(|codecs| !codecs.starts_with(prefix))
+        {
+            differences.push(format!("video is {}, not {}", codecs, prefix));
+        }
+        return differences;
+    };
+
+    let old = &recorded.profile;
+    let missing: Vec<String> = profile
+        .ladder
+        .iter()
+        .filter(|rung| !old.ladder.contains(rung))
+        .map(|rung| format!("{} MB/s", rung))
+        .collect();
+    if !missing.is_empty() {
+        differences.push(format!("missing the {} video rungs", missing.join(", ")));
+    }
+    if recorded.encoder != profile.encoder_backend() {
+        differences.push(format!(
+            "encoded with {}, not {}",
+            recorded.encoder,
+            profile.encoder_backend()
+        ));
+    }
+    let (audio, old_audio) = (&profile.audio, &old.audio);
+    if (audio.bitrate_kbps, audio.channels) != (old_audio.bitrate_kbps, old_audio.channels) {
+        differences.push(format!(
+            "audio is {} kb/s in {} channels, not {} kb/s in {}",
+            old_audio.bitrate_kbps, old_audio.channels, audio.bitrate_kbps, audio.channels
+        ));
+    }
+    if audio.mono_kbps.is_some() && old_audio.mono_kbps.is_none() {
+        differences.push(String::from("missing the mono audio rendition"));
+    }
+    if audio.drc && !old_audio.drc {
+        differences.push(String::from("missing the compressed audio rendition"));
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(codecs: &str) -> Representation {
+        Representation {
+            id: String::from("0"),
+            content_type: String::from("video"),
+            codecs: Some(String::from(codecs)),
+            bandwidth: 6_000_000,
+            width: Some(1920),
+            height: Some(1080),
+            lang: None,
+        }
+    }
+
+    #[test]
+    fn only_material_differences_count() {
+        let old = EncodingProfile::default();
+        let recorded = Recorded {
+            encoder: String::from("svtav1"),
+            profile: old.clone(),
+        };
+        let mut profile = EncodingProfile {
+            encoder_preset: old.encoder_preset + 2,
+            segment_duration: old.segment_duration * 2,
+            ..old.clone()
+        };
+        assert!(differences(&[], Some(&recorded), &profile).is_empty());
+
+        profile.ladder.push(12);
+        profile.audio.drc = true;
+        assert_eq!(
+            differences(&[], Some(&recorded), &profile),
+            [
+                "missing the 12 MB/s video rungs",
+                "missing the compressed audio rendition"
+            ]
+        );
+
+        // With nothing recorded, the manifest is all there is to go on
+        let ladder = [video("avc1.640028")];
+        assert_eq!(
+            differences(&ladder, None, &old),
+            ["has 1 video rungs of 2", "video is avc1.640028, not av01"]
+        );
+        let ladder = [video("av01.0.08M.08"), video("av01.0.05M.08")];
+        assert!(differences(&ladder, None, &old).is_empty());
+    }
+}
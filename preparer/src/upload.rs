@@ -0,0 +1,638 @@
+//! Uploading media to the daemon to be prepared into a library.
+//!
+//! `POST /uploads` takes a multipart form with the media in a `file` field
+//! and, optionally, the title to prepare it as in a `title` field; without
+//! one the file name is used. Requests sign in with HTTP Basic auth as a
+//! user from the library's catalog (see `preparer user`). The upload is
+//! staged under `.uploads/` in the library, queued like any other job, and
+//! once prepared the staged copy is deleted and the catalog rescanned so the
+//! title shows up in the library.
+//!
+//! Uploads over flaky connections can instead go in pieces, picking up
+//! where a dropped one stopped:
+//!
+//! - `POST /uploads/sessions` with a JSON `file_name`, `size` and optional
+//!   `title` opens a session and answers with its id
+//! - `HEAD /uploads/sessions/{id}` answers with how much has arrived so far,
+//!   in an `Upload-Offset` header
+//! - `PATCH /uploads/sessions/{id}` appends its body to what arrived, and
+//!   must say where it starts in an `Upload-Offset` header. Once all `size`
+//!   bytes are in, the upload is queued as if it was posted whole.
+//!
+//! Sessions are kept under `.uploads/` too, so they outlive the daemon.
+
+use crate::library::Catalog;
+use crate::users::{GrantKind, User};
+use anyhow::{Context, Result};
+use axum::Json;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::multipart::{Field, Multipart};
+use axum::extract::{DefaultBodyLimit, Path as UrlPath, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{head, post};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures::StreamExt;
+use movieshare_core::JobSpec;
+use movieshare_core::queue::{JobId, JobQueue, JobState, QueueEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+
+const STAGING_DIR: &str = ".uploads";
+/// Header saying where in the file a piece of a session starts, or how much
+/// of it has arrived.
+const UPLOAD_OFFSET: &str = "upload-offset";
+
+#[derive(Clone)]
+struct UploadState {
+    queue: JobQueue,
+    library: PathBuf,
+    /// Sessions a piece is arriving for right now
+    receiving: Arc<Mutex<HashSet<String>>>,
+}
+
+#[derive(Serialize)]
+struct UploadResponse {
+    id: JobId,
+    title: String,
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(serde_json::json!({ "error": message.into() }))).into_response()
+}
+
+/// Check HTTP Basic credentials against the catalog's users.
+pub(crate) async fn authenticate(library: &Path, headers: &HeaderMap) -> Result<Option<User>> {
+    let credentials = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let Some((name, password)) = credentials
+        .as_deref()
+        .and_then(|credentials| credentials.split_once(':'))
+        .map(|(name, password)| (name.to_string(), password.to_string()))
+    else {
+        return Ok(None);
+    };
+
+    let library = library.to_path_buf();
+    tokio::task::spawn_blocking(move || match Catalog::open_existing(&library)? {
+        Some(catalog) => catalog.authenticate(&name, &password),
+        None => Ok(None),
+    })
+    .await?
+}
+
+/// A title name that is safe to use as a directory in the library.
+pub(crate) fn valid_title(title: &str) -> bool {
+    !title.is_empty() && !title.starts_with('.') && !title.contains(['/', '\0'])
+}
+
+/// The user `headers` sign in as, or the response turning them away.
+async fn signed_in(state: &UploadState, headers: &HeaderMap) -> Result<User, Response> {
+    match authenticate(&state.library, headers).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err((
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"movieshare\"")],
+        )
+            .into_response()),
+        Err(err) => Err(error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("{:#}", err),
+        )),
+    }
+}
+
+async fn save_field(mut field: Field<'_>, path: &Path) -> Result<()> {
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .context(format!("Failed to create {}", path.display()))?;
+    while let Some(chunk) = field.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+async fn upload(
+    State(state): State<UploadState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    let user = match signed_in(&state, &headers).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let staging = state.library.join(STAGING_DIR);
+    if let Err(err) = tokio::fs::create_dir_all(&staging).await {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    }
+
+    // The title may come before or after the file, so it's only settled at the end
+    let mut title = None;
+    let mut staged: Option<(PathBuf, String)> = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return error(StatusCode::BAD_REQUEST, err.body_text()),
+        };
+        match field.name() {
+            Some("title") => match field.text().await {
+                Ok(text) => title = Some(text.trim().to_string()),
+                Err(err) => return error(StatusCode::BAD_REQUEST, err.body_text()),
+            },
+            Some("file") if staged.is_none() => {
+                let file_name = field.file_name().unwrap_or("upload").to_string();
+                let file_name = Path::new(&file_name)
+                    .file_name()
+                    .map_or(String::from("upload"), |name| {
+                        name.to_string_lossy().into_owned()
+                    });
+                let path = staging.join(format!("{:016x}-{}", rand::random::<u64>(), file_name));
+                if let Err(err) = save_field(field, &path).await {
+                    let _ = tokio::fs::remove_file(&path).await;
+                    return error(StatusCode::BAD_REQUEST, format!("{:#}", err));
+                }
+                let stem = Path::new(&file_name)
+                    .file_stem()
+                    .map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+                staged = Some((path, stem));
+            }
+            _ => (),
+        }
+    }
+
+    let Some((path, stem)) = staged else {
+        return error(StatusCode::BAD_REQUEST, "No file field in the upload");
+    };
+    let title = title.filter(|title| !title.is_empty()).unwrap_or(stem);
+    queue_upload(&state, user, path, title).await
+}
+
+/// Why `title` can't be uploaded to the library, if it can't.
+fn title_conflict(library: &Path, title: &str) -> Option<String> {
+    if !valid_title(title) {
+        Some(format!("Invalid title: {:?}", title))
+    } else if library.join(title).exists() {
+        Some(format!("{} is already in the library", title))
+    } else {
+        None
+    }
+}
+
+/// Queue the upload staged at `path` to be prepared as `title`.
+async fn queue_upload(state: &UploadState, user: User, path: PathBuf, title: String) -> Response {
+    let output = state.library.join(&title);
+    if let Some(message) = title_conflict(&state.library, &title) {
+        let _ = tokio::fs::remove_file(&path).await;
+        return error(StatusCode::CONFLICT, message);
+    }
+
+    // Subscribe first so the job can't finish unnoticed
+    let events = state.queue.subscribe();
+    let id = match state.queue.submit(JobSpec::new(&path, &output), 0) {
+        Ok(id) => id,
+        Err(err) => {
+            let _ = tokio::fs::remove_file(&path).await;
+            return error(StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", err));
+        }
+    };
+    println!("{} uploaded {} as job {}", user.name, title, id);
+    tokio::spawn(finish(
+        events,
+        id,
+        path,
+        state.library.clone(),
+        user,
+        title.clone(),
+    ));
+
+    (StatusCode::CREATED, Json(UploadResponse { id, title })).into_response()
+}
+
+/// An upload arriving in pieces.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Session {
+    /// Who opened it, and only they may add to it
+    user: String,
+    file_name: String,
+    title: Option<String>,
+    /// Size of the whole file, in bytes
+    size: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OpenSession {
+    file_name: String,
+    size: u64,
+    title: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SessionOpened {
+    id: String,
+}
+
+/// Where a session's description and what arrived of its file are kept.
+fn session_paths(staging: &Path, id: &str) -> Option<(PathBuf, PathBuf)> {
+    // Ids are ours, so anything else can't name a session
+    if id.len() != 16 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((
+        staging.join(format!("{}.json", id)),
+        staging.join(format!("{}.part", id)),
+    ))
+}
+
+impl Session {
+    fn title(&self) -> String {
+        self.title
+            .clone()
+            .filter(|title| !title.is_empty())
+            .unwrap_or_else(|| {
+                Path::new(&self.file_name)
+                    .file_stem()
+                    .map_or(String::new(), |stem| stem.to_string_lossy().into_owned())
+            })
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec(self)?)
+            .context(format!("Failed to write {}", path.display()))
+    }
+
+    fn load(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(format!("Failed to read {}", path.display())),
+        }
+    }
+}
+
+/// The session `id` of `user` and its files, if they have one by that id.
+fn user_session(
+    state: &UploadState,
+    user: &User,
+    id: &str,
+) -> Result<Option<(Session, PathBuf, PathBuf)>> {
+    let Some((description, data)) = session_paths(&state.library.join(STAGING_DIR), id) else {
+        return Ok(None);
+    };
+    Ok(Session::load(&description)?
+        .filter(|session| session.user == user.name)
+        .map(|session| (session, description, data)))
+}
+
+fn no_session() -> Response {
+    error(StatusCode::NOT_FOUND, "No such upload session")
+}
+
+/// How much of a session's file has arrived.
+fn received(data: &Path) -> u64 {
+    std::fs::metadata(data).map_or(0, |metadata| metadata.len())
+}
+
+fn offset_response(status: StatusCode, offset: u64) -> Response {
+    (status, [(UPLOAD_OFFSET, offset.to_string())]).into_response()
+}
+
+async fn open_session(
+    State(state): State<UploadState>,
+    headers: HeaderMap,
+    Json(request): Json<OpenSession>,
+) -> Response {
+    let user = match signed_in(&state, &headers).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    let session = Session {
+        user: user.name,
+        file_name: request.file_name,
+        title: request.title.map(|title| title.trim().to_string()),
+        size: request.size,
+    };
+    // Turn a clashing title away before gigabytes arrive for it
+    if let Some(message) = title_conflict(&state.library, &session.title()) {
+        return error(StatusCode::CONFLICT, message);
+    }
+
+    let staging = state.library.join(STAGING_DIR);
+    let id = format!("{:016x}", rand::random::<u64>());
+    let (description, data) = session_paths(&staging, &id).unwrap();
+    let result = std::fs::create_dir_all(&staging)
+        .context(format!("Failed to create {}", staging.display()))
+        .and_then(|()| session.save(&description))
+        .and_then(|()| {
+            std::fs::File::create(&data).context(format!("Failed to create {}", data.display()))
+        });
+    if let Err(err) = result {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err));
+    }
+    (
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/uploads/sessions/{}", id))],
+        Json(SessionOpened { id }),
+    )
+        .into_response()
+}
+
+async fn session_offset(
+    State(state): State<UploadState>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    let user = match signed_in(&state, &headers).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    match user_session(&state, &user, &id) {
+        Ok(Some((_, _, data))) => offset_response(StatusCode::OK, received(&data)),
+        Ok(None) => no_session(),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)),
+    }
+}
+
+/// Marks a session as receiving until dropped.
+struct Receiving<'a> {
+    sessions: &'a Mutex<HashSet<String>>,
+    id: String,
+}
+
+impl Drop for Receiving<'_> {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+async fn append_to_session(
+    State(state): State<UploadState>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let user = match signed_in(&state, &headers).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    let (session, description, data) = match user_session(&state, &user, &id) {
+        Ok(Some(found)) => found,
+        Ok(None) => return no_session(),
+        Err(err) => return error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err)),
+    };
+    // Two pieces at once would interleave
+    if !state.receiving.lock().unwrap().insert(id.clone()) {
+        return error(
+            StatusCode::CONFLICT,
+            "A piece is already arriving for this upload",
+        );
+    }
+    let _receiving = Receiving {
+        sessions: &state.receiving,
+        id,
+    };
+
+    let offset = received(&data);
+    let claimed = headers
+        .get(UPLOAD_OFFSET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if claimed != Some(offset) {
+        return offset_response(StatusCode::CONFLICT, offset);
+    }
+
+    let mut file = match tokio::fs::OpenOptions::new().append(true).open(&data).await {
+        Ok(file) => file,
+        Err(err) => return error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    };
+    let mut stream = body.into_data_stream();
+    let mut offset = offset;
+    // Whatever arrived before the connection dropped is kept to resume from
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => break,
+        };
+        if offset + chunk.len() as u64 > session.size {
+            let _ = file.flush().await;
+            let _ = file.set_len(offset).await;
+            return error(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "The upload is bigger than the {} bytes announced",
+                    session.size
+                ),
+            );
+        }
+        if let Err(err) = file.write_all(&chunk).await {
+            return error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+        }
+        offset += chunk.len() as u64;
+    }
+    if let Err(err) = file.flush().await {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    }
+    if offset < session.size {
+        return offset_response(StatusCode::NO_CONTENT, offset);
+    }
+
+    // All of it is in: stage it like a whole upload and forget the session
+    let file_name = Path::new(&session.file_name)
+        .file_name()
+        .map_or(String::from("upload"), |name| {
+            name.to_string_lossy().into_owned()
+        });
+    let staged = data.with_file_name(format!("{:016x}-{}", rand::random::<u64>(), file_name));
+    if let Err(err) = tokio::fs::rename(&data, &staged).await {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    }
+    let _ = tokio::fs::remove_file(&description).await;
+    queue_upload(&state, user, staged, session.title()).await
+}
+
+/// Once the job is done, drop the staged upload and add the title to the catalog.
+async fn finish(
+    mut events: futures::channel::mpsc::UnboundedReceiver<QueueEvent>,
+    id: JobId,
+    staged: PathBuf,
+    library: PathBuf,
+    user: User,
+    title: String,
+) {
+    let mut finished = None;
+    while let Some(event) = events.next().await {
+        if let QueueEvent::StateChanged { id: job, state } = event
+            && job == id
+            && state.is_finished()
+        {
+            finished = Some(state);
+            break;
+        }
+    }
+    drop(events);
+    let _ = tokio::fs::remove_file(&staged).await;
+    if finished != Some(JobState::Completed) {
+        return;
+    }
+
+    let result = tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut catalog = Catalog::open(&Catalog::default_path(&library))?;
+        // Restricted users can still see what they uploaded themselves
+        if user.restricted {
+            catalog.grant(&user.name, GrantKind::Title, &title)?;
+        }
+        catalog.scan(&library, false)?;
+        Ok(())
+    })
+    .await;
+    if let Ok(Err(err)) = result {
+        eprintln!("Failed to add uploaded title to the catalog: {:#}", err);
+    }
+}
+
+/// Routes accepting uploads into `library`.
+pub fn router(queue: JobQueue, library: PathBuf) -> Router {
+    Router::new()
+        .route("/uploads", post(upload))
+        .route("/uploads/sessions", post(open_session))
+        .route(
+            "/uploads/sessions/{id}",
+            head(session_offset).patch(append_to_session),
+        )
+        // Uploads are whole movies; don't cap them at axum's default of a few megabytes
+        .layer(DefaultBodyLimit::disable())
+        .with_state(UploadState {
+            queue,
+            library,
+            receiving: Arc::default(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use movieshare_core::queue::QueueConfig;
+    use tower::ServiceExt;
+
+    #[test]
+    fn validates_titles() {
+        assert!(valid_title("Birthday 2024"));
+        assert!(!valid_title(""));
+        assert!(!valid_title(".uploads"));
+        assert!(!valid_title("../escape"));
+    }
+
+    #[test]
+    fn keeps_sessions_to_resume() {
+        let staging = std::env::temp_dir().join(format!("upload-test-{}", std::process::id()));
+        std::fs::create_dir_all(&staging).unwrap();
+        assert!(session_paths(&staging, "../../etc/passwd").is_none());
+        let (description, data) = session_paths(&staging, "00ff00ff00ff00ff").unwrap();
+        assert_eq!(Session::load(&description).unwrap(), None);
+
+        let session = Session {
+            user: String::from("ana"),
+            file_name: String::from("Birthday.mkv"),
+            title: None,
+            size: 10,
+        };
+        session.save(&description).unwrap();
+        assert_eq!(Session::load(&description).unwrap(), Some(session.clone()));
+        assert_eq!(session.title(), "Birthday");
+        assert_eq!(received(&data), 0);
+        std::fs::write(&data, b"1234").unwrap();
+        assert_eq!(received(&data), 4);
+        std::fs::remove_dir_all(&staging).unwrap();
+    }
+
+    #[tokio::test]
+    async fn appends_pieces_until_the_upload_is_queued() {
+        let library =
+            std::env::temp_dir().join(format!("movieshare-upload-{}", std::process::id()));
+        std::fs::create_dir_all(&library).unwrap();
+        Catalog::open(&Catalog::default_path(&library))
+            .unwrap()
+            .add_user("ana", Some("secret"), false)
+            .unwrap();
+        // Nothing may start, so the finished upload stays queued
+        let queue = JobQueue::open(QueueConfig {
+            max_concurrent: 0,
+            ..QueueConfig::default()
+        })
+        .unwrap();
+        let app = router(queue.clone(), library.clone());
+        let send = |method: &str, uri: &str, offset: Option<u64>, body: Body| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(
+                    header::AUTHORIZATION,
+                    format!("Basic {}", STANDARD.encode("ana:secret")),
+                )
+                .header(header::CONTENT_TYPE, "application/json");
+            if let Some(offset) = offset {
+                request = request.header(UPLOAD_OFFSET, offset.to_string());
+            }
+            let app = app.clone();
+            async move { app.oneshot(request.body(body).unwrap()).await.unwrap() }
+        };
+
+        let opened = send(
+            "POST",
+            "/uploads/sessions",
+            None,
+            Body::from(r#"{"file_name": "Birthday.mkv", "size": 10}"#),
+        )
+        .await;
+        assert_eq!(opened.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(opened.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let id = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let uri = format!("/uploads/sessions/{}", id);
+
+        let piece = send("PATCH", &uri, Some(0), Body::from("1234")).await;
+        assert_eq!(piece.status(), StatusCode::NO_CONTENT);
+        assert_eq!(piece.headers()[UPLOAD_OFFSET], "4");
+
+        // A piece starting anywhere but where the last one stopped is turned away
+        let mismatch = send("PATCH", &uri, Some(2), Body::from("3456")).await;
+        assert_eq!(mismatch.status(), StatusCode::CONFLICT);
+        assert_eq!(mismatch.headers()[UPLOAD_OFFSET], "4");
+
+        // So is one running past the announced size, without keeping any of it
+        let overlong = send("PATCH", &uri, Some(4), Body::from("567890ab")).await;
+        assert_eq!(overlong.status(), StatusCode::BAD_REQUEST);
+        let offset = send("HEAD", &uri, None, Body::empty()).await;
+        assert_eq!(offset.headers()[UPLOAD_OFFSET], "4");
+        assert!(queue.jobs().is_empty());
+
+        let last = send("PATCH", &uri, Some(4), Body::from("567890")).await;
+        assert_eq!(last.status(), StatusCode::CREATED);
+        let jobs = queue.jobs();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].state, JobState::Queued);
+        assert_eq!(std::fs::read(&jobs[0].spec.input).unwrap(), b"1234567890");
+        assert_eq!(
+            send("HEAD", &uri, None, Body::empty()).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+//! What a job cost the machine: CPU time, peak memory, GPU utilization and
+//! bytes written, to compare encoder backends by more than wall time.
+//!
+//! CPU time and memory come from `getrusage`, so they cover the whole
+//! process and the workers it waited on. Bytes written come from
+//! `/proc/self/io` and GPU utilization is sampled from the kernel's DRM
+//! drivers or `nvidia-smi`, where either is there.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often the GPU is sampled
+const GPU_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ResourceUsage {
+    /// User and system CPU time
+    pub cpu_secs: f64,
+    /// Most memory resident at once
+    pub peak_rss_bytes: u64,
+    /// Average utilization of the busiest GPU, in percent
+    pub gpu_percent: Option<f64>,
+    /// Bytes that went to storage
+    pub bytes_written: Option<u64>,
+}
+
+impl ResourceUsage {
+    /// How many cores were kept busy on average over `wall_secs`.
+    pub fn cores(&self, wall_secs: f64) -> Option<f64> {
+        (wall_secs > 0.0).then(|| self.cpu_secs / wall_secs)
+    }
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of CPU time, {:.2} GB peak memory",
+            humantime::format_duration(Duration::from_secs(self.cpu_secs as u64)),
+            self.peak_rss_bytes as f64 / 1e9
+        )?;
+        if let Some(gpu) = self.gpu_percent {
+            write!(f, ", {:.0}% GPU", gpu)?;
+        }
+        if let Some(bytes) = self.bytes_written {
+            write!(f, ", {:.2} GB written", bytes as f64 / 1e9)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters at the start of a job, to measure it from.
+struct Counters {
+    cpu_secs: f64,
+    bytes_written: Option<u64>,
+}
+
+impl Counters {
+    fn now() -> Self {
+        let cpu_secs = [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN]
+            .into_iter()
+            .filter_map(rusage)
+            .map(|usage| seconds(usage.ru_utime) + seconds(usage.ru_stime))
+            .sum();
+        Self {
+            cpu_secs,
+            bytes_written: bytes_written(),
+        }
+    }
+}
+
+fn rusage(who: libc::c_int) -> Option<libc::rusage> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage only writes to the struct it's given
+    match unsafe { libc::getrusage(who, usage.as_mut_ptr()) } {
+        0 => Some(unsafe { usage.assume_init() }),
+        _ => None,
+    }
+}
+
+fn seconds(time: libc::timeval) -> f64 {
+    time.tv_sec as f64 + time.tv_usec as f64 / 1e6
+}
+
+/// `write_bytes` from `/proc/self/io`.
+fn bytes_written() -> Option<u64> {
+    std::fs::read_to_string("/proc/self/io")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("write_bytes:"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Utilization of the busiest GPU right now, in percent.
+fn gpu_busy() -> Option<f64> {
+    // AMD and Intel drivers expose it in sysfs
+    let sysfs = std::fs::read_dir("/sys/class/drm")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|card| {
+            std::fs::read_to_string(card.path().join("device/gpu_busy_percent")).ok()
+        })
+        .filter_map(|busy| busy.trim().parse::<f64>().ok())
+        .reduce(f64::max);
+    if sysfs.is_some() {
+        return sysfs;
+    }
+    let output = Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=utilization.gpu",
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.trim().parse::<f64>().ok())
+        .reduce(f64::max)
+}
+
+/// Samples GPU utilization in the background.
+struct GpuSampler {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+    samples: Arc<Mutex<Vec<f64>>>,
+}
+
+impl GpuSampler {
+    /// Start sampling, if there's a GPU whose utilization can be read.
+    fn start() -> Option<Self> {
+        gpu_busy()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let handle = std::thread::spawn({
+            let (stop, samples) = (stop.clone(), samples.clone());
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if let Some(busy) = gpu_busy() {
+                        samples.lock().unwrap().push(busy);
+                    }
+                    std::thread::sleep(GPU_INTERVAL);
+                }
+            }
+        });
+        Some(Self {
+            stop,
+            handle,
+            samples,
+        })
+    }
+
+    /// Stop sampling, returning the average.
+    fn finish(self) -> Option<f64> {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+        let samples = self.samples.lock().unwrap();
+        (!samples.is_empty()).then(|| samples.iter().sum::<f64>() / samples.len() as f64)
+    }
+}
+
+/// Measures a job from when it's started until it's finished.
+pub struct UsageMeter {
+    start: Counters,
+    gpu: Option<GpuSampler>,
+}
+
+impl UsageMeter {
+    pub fn start() -> Self {
+        Self {
+            start: Counters::now(),
+            gpu: GpuSampler::start(),
+        }
+    }
+
+    pub fn finish(self) -> ResourceUsage {
+        let end = Counters::now();
+        let gpu_percent = self.gpu.and_then(GpuSampler::finish);
+        // The kernel keeps only the peak over the process's whole life
+        let peak_rss_kib = [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN]
+            .into_iter()
+            .filter_map(rusage)
+            .map(|usage| usage.ru_maxrss.max(0) as u64)
+            .max()
+            .unwrap_or(0);
+        ResourceUsage {
+            cpu_secs: end.cpu_secs - self.start.cpu_secs,
+            peak_rss_bytes: peak_rss_kib * 1024,
+            gpu_percent,
+            bytes_written: end
+                .bytes_written
+                .zip(self.start.bytes_written)
+                .map(|(end, start)| end.saturating_sub(start)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_the_work_in_between() {
+        let meter = UsageMeter::start();
+        let mut sum = 0u64;
+        for i in 0..20_000_000u64 {
+            sum = sum.wrapping_add(i * i);
+        }
+        std::hint::black_box(sum);
+        let usage = meter.finish();
+        assert!(usage.cpu_secs > 0.0);
+        assert!(usage.peak_rss_bytes > 0);
+        assert_eq!(usage.cores(0.0), None);
+        assert_eq!(usage.cores(usage.cpu_secs * 2.0), Some(0.5));
+    }
+}
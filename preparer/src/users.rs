@@ -0,0 +1,287 @@
+//! Viewer accounts and what each may watch, kept in the library catalog.
+//!
+//! Unrestricted users see the whole library. Restricted users only see
+//! titles they were granted by name, or through one of the TMDB genres in a
+//! title's metadata, so a kids' profile can be given "Family" and "Animation".
+//! Any user can also be held to a maximum content rating, which hides
+//! titles rated above it or not rated at all, unless granted by name.
+//! Titles in a granted collection count as granted by name, since someone
+//! picked each of them out.
+
+use crate::library::Catalog;
+use crate::rating;
+use anyhow::{Context, Result, anyhow, bail};
+use argon2::Argon2;
+use argon2::password_hash::phc::PasswordHash;
+use argon2::password_hash::{PasswordHasher, PasswordVerifier};
+use rusqlite::{OptionalExtension, params};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GrantKind {
+    Title,
+    Genre,
+    Collection,
+}
+
+impl GrantKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            GrantKind::Title => "title",
+            GrantKind::Genre => "genre",
+            GrantKind::Collection => "collection",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub restricted: bool,
+    /// Titles a restricted user may watch
+    pub titles: Vec<String>,
+    /// Genres a restricted user may watch every title of
+    pub genres: Vec<String>,
+    /// Collections a restricted user may watch every title of
+    pub collections: Vec<String>,
+    /// The titles of those collections
+    #[serde(skip)]
+    pub collection_titles: Vec<String>,
+    /// Highest content rating they may watch, like `PG`
+    pub max_rating: Option<String>,
+}
+
+impl User {
+    pub fn can_watch(&self, title: &str, genres: &[String], rating: Option<&str>) -> bool {
+        let granted = self
+            .titles
+            .iter()
+            .chain(&self.collection_titles)
+            .any(|t| t == title);
+        if let Some(max) = &self.max_rating
+            && !granted
+            && !rating::allows(max, rating)
+        {
+            return false;
+        }
+        !self.restricted || granted || genres.iter().any(|genre| self.genres.contains(genre))
+    }
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes())
+        .map_err(|err| anyhow!("Failed to hash password: {}", err))?
+        .to_string())
+}
+
+impl Catalog {
+    /// Add a user. Without a password they can only sign in through a proxy.
+    pub fn add_user(&mut self, name: &str, password: Option<&str>, restricted: bool) -> Result<()> {
+        let hash = password.map(hash_password).transpose()?;
+        self.conn
+            .execute(
+                "INSERT INTO users (name, password_hash, restricted) VALUES (?1, ?2, ?3)",
+                params![name, hash, restricted],
+            )
+            .context(format!("Failed to add user {}", name))?;
+        Ok(())
+    }
+
+    pub fn set_password(&mut self, name: &str, password: &str) -> Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE users SET password_hash = ?2 WHERE name = ?1",
+            params![name, hash_password(password)?],
+        )?;
+        if changed == 0 {
+            bail!("No such user: {}", name);
+        }
+        Ok(())
+    }
+
+    /// Hold a user to a maximum content rating, or lift the limit with `None`.
+    pub fn set_max_rating(&mut self, name: &str, rating: Option<&str>) -> Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE users SET max_rating = ?2 WHERE name = ?1",
+            params![name, rating],
+        )?;
+        if changed == 0 {
+            bail!("No such user: {}", name);
+        }
+        Ok(())
+    }
+
+    pub fn remove_user(&mut self, name: &str) -> Result<bool> {
+        let tx = self.conn.transaction()?;
+        let removed = tx.execute("DELETE FROM users WHERE name = ?1", params![name])?;
+        tx.execute("DELETE FROM progress WHERE user = ?1", params![name])?;
+        tx.commit()?;
+        Ok(removed > 0)
+    }
+
+    pub fn user(&self, name: &str) -> Result<Option<User>> {
+        let row: Option<(i64, bool, Option<String>)> = self
+            .conn
+            .query_row(
+                "SELECT id, restricted, max_rating FROM users WHERE name = ?1",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let Some((id, restricted, max_rating)) = row else {
+            return Ok(None);
+        };
+
+        let mut user = User {
+            name: name.to_string(),
+            restricted,
+            titles: Vec::new(),
+            genres: Vec::new(),
+            collections: Vec::new(),
+            collection_titles: Vec::new(),
+            max_rating,
+        };
+        let mut statement = self
+            .conn
+            .prepare("SELECT kind, value FROM grants WHERE user_id = ?1 ORDER BY value")?;
+        let grants = statement.query_map(params![id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for grant in grants {
+            match grant? {
+                (kind, value) if kind == "title" => user.titles.push(value),
+                (kind, value) if kind == "collection" => user.collections.push(value),
+                (_, value) => user.genres.push(value),
+            }
+        }
+        for name in &user.collections {
+            if let Some(collection) = self.collection(name)? {
+                user.collection_titles.extend(collection.titles);
+            }
+        }
+        Ok(Some(user))
+    }
+
+    pub fn users(&self) -> Result<Vec<User>> {
+        let mut statement = self.conn.prepare("SELECT name FROM users ORDER BY name")?;
+        let names = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        names
+            .iter()
+            .filter_map(|name| self.user(name).transpose())
+            .collect()
+    }
+
+    /// The user, if the password is theirs.
+    pub fn authenticate(&self, name: &str, password: &str) -> Result<Option<User>> {
+        let hash: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT password_hash FROM users WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(Some(hash)) = hash else {
+            return Ok(None);
+        };
+
+        let hash = PasswordHash::new(&hash).map_err(|err| anyhow!("Bad password hash: {}", err))?;
+        if Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_err()
+        {
+            return Ok(None);
+        }
+        self.user(name)
+    }
+
+    pub fn grant(&mut self, user: &str, kind: GrantKind, value: &str) -> Result<()> {
+        if kind == GrantKind::Collection && self.collection(value)?.is_none() {
+            bail!("No such collection: {}", value);
+        }
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO grants (user_id, kind, value)
+             SELECT id, ?2, ?3 FROM users WHERE name = ?1",
+            params![user, kind.as_str(), value],
+        )?;
+        if inserted == 0 && self.user(user)?.is_none() {
+            bail!("No such user: {}", user);
+        }
+        Ok(())
+    }
+
+    pub fn revoke(&mut self, user: &str, kind: GrantKind, value: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM grants WHERE kind = ?2 AND value = ?3
+                AND user_id = (SELECT id FROM users WHERE name = ?1)",
+            params![user, kind.as_str(), value],
+        )?;
+        Ok(())
+    }
+
+    /// Whether `user` may watch the title named `title`.
+    pub fn can_watch(&self, user: &User, title: &str) -> Result<bool> {
+        if !user.restricted && user.max_rating.is_none() {
+            return Ok(true);
+        }
+        let metadata = self
+            .title(title)?
+            .and_then(|title| title.metadata)
+            .unwrap_or_default();
+        Ok(user.can_watch(title, &metadata.genres, metadata.rating.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> Catalog {
+        Catalog::open(std::path::Path::new(":memory:")).unwrap()
+    }
+
+    #[test]
+    fn checks_passwords() {
+        let mut catalog = catalog();
+        catalog.add_user("alice", Some("hunter2"), false).unwrap();
+        catalog.add_user("proxy-only", None, false).unwrap();
+
+        assert!(catalog.authenticate("alice", "hunter2").unwrap().is_some());
+        assert!(catalog.authenticate("alice", "hunter3").unwrap().is_none());
+        assert!(catalog.authenticate("bob", "hunter2").unwrap().is_none());
+        assert!(catalog.authenticate("proxy-only", "").unwrap().is_none());
+        assert!(catalog.add_user("alice", None, false).is_err());
+    }
+
+    #[test]
+    fn restricts_to_grants() {
+        let mut catalog = catalog();
+        catalog.add_user("kid", None, true).unwrap();
+        catalog.grant("kid", GrantKind::Title, "Cartoon").unwrap();
+        catalog.grant("kid", GrantKind::Genre, "Family").unwrap();
+        assert!(catalog.grant("nobody", GrantKind::Genre, "Family").is_err());
+
+        let kid = catalog.user("kid").unwrap().unwrap();
+        assert!(kid.can_watch("Cartoon", &[], None));
+        assert!(kid.can_watch("Film", &[String::from("Family")], None));
+        assert!(!kid.can_watch("Film", &[String::from("Horror")], None));
+
+        catalog.revoke("kid", GrantKind::Title, "Cartoon").unwrap();
+        let kid = catalog.user("kid").unwrap().unwrap();
+        assert!(!kid.can_watch("Cartoon", &[], None));
+
+        // A rating limit holds for genre grants too, but not for titles granted by name
+        catalog.set_max_rating("kid", Some("PG")).unwrap();
+        catalog.grant("kid", GrantKind::Title, "Classic").unwrap();
+        let kid = catalog.user("kid").unwrap().unwrap();
+        let family = [String::from("Family")];
+        assert!(kid.can_watch("Film", &family, Some("G")));
+        assert!(!kid.can_watch("Film", &family, Some("PG-13")));
+        assert!(!kid.can_watch("Film", &family, None));
+        assert!(kid.can_watch("Classic", &[], Some("R")));
+        assert!(catalog.set_max_rating("nobody", Some("PG")).is_err());
+    }
+}
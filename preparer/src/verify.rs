@@ -0,0 +1,195 @@
+//! Checking the library before serving it, for `preparer serve
+//! --verify-on-start`: each title's manifest has to parse and every file it
+//! names has to be there, with the size and, where one was taken, the
+//! checksum the catalog recorded for it.
+//!
+//! Only the files players fetch are checked against the catalog; journals
+//! and metadata change after a scan without anything being wrong.
+//!
+//! Titles that fail are marked unavailable, so viewers are told so up front
+//! instead of the player stalling on a missing segment halfway through.
+
+use crate::library::{Catalog, MANIFEST, sha256_file};
+use crate::mpd;
+use crate::trickplay;
+use anyhow::{Context, Result};
+use rusqlite::params;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A file of a title as the catalog recorded it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedFile {
+    /// Relative to the title's directory
+    pub path: String,
+    pub size_bytes: u64,
+    /// Only taken by `preparer library scan --checksums`
+    pub sha256: Option<String>,
+}
+
+impl Catalog {
+    /// The files recorded for the title `name`.
+    pub fn files(&self, name: &str) -> Result<Vec<RecordedFile>> {
+        let mut statement = self.conn.prepare(
+            "SELECT files.path, files.size_bytes, files.sha256
+             FROM files JOIN titles ON titles.id = files.title_id
+             WHERE titles.name = ?1 ORDER BY files.path",
+        )?;
+        let files = statement
+            .query_map(params![name], |row| {
+                Ok(RecordedFile {
+                    path: row.get(0)?,
+                    size_bytes: row.get::<_, i64>(1)? as u64,
+                    sha256: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+}
+
+/// Every file the manifest `xml` names, relative to it.
+fn referenced_files(xml: &str) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let manifest = mpd::parse(xml)?;
+    for representation in &manifest.representations {
+        files.extend(representation_files(xml, &manifest, representation)?);
+    }
+    Ok(files)
+}
+
+/// The files of one `representation` of `manifest`, parsed from `xml`.
+pub(crate) fn representation_files(
+    xml: &str,
+    manifest: &mpd::Manifest,
+    representation: &mpd::Representation,
+) -> Result<Vec<String>> {
+    if representation.content_type == "image" {
+        let Some(track) = mpd::thumbnail_track(xml)? else {
+            return Ok(Vec::new());
+        };
+        let duration_secs = manifest.duration_secs.unwrap_or(0.0);
+        return Ok(trickplay::tiles(&track, duration_secs));
+    }
+    if let Some(url) = mpd::base_url(xml, &representation.id)? {
+        return Ok(vec![url]);
+    }
+    let list = mpd::segment_list(xml, &representation.id)?;
+    let mut files: Vec<String> = list.initialization.into_iter().collect();
+    files.extend(list.segments.into_iter().map(|segment| segment.media));
+    Ok(files)
+}
+
+/// What's wrong with the title in `dir`, if anything, checking the files its
+/// manifest names against those `recorded` for it.
+pub fn check_title(dir: &Path, recorded: &[RecordedFile]) -> Result<Option<String>> {
+    let Ok(xml) = std::fs::read_to_string(dir.join(MANIFEST)) else {
+        return Ok(Some(String::from("the manifest can't be read")));
+    };
+    let files = match referenced_files(&xml) {
+        Ok(files) => files,
+        Err(err) => return Ok(Some(format!("the manifest is invalid: {:#}", err))),
+    };
+    if let Some(missing) = files.iter().find(|file| !dir.join(file).is_file()) {
+        return Ok(Some(format!("{} is missing", missing)));
+    }
+    for file in recorded.iter().filter(|file| files.contains(&file.path)) {
+        let path = dir.join(&file.path);
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            return Ok(Some(format!("{} is missing", file.path)));
+        };
+        if metadata.len() != file.size_bytes {
+            return Ok(Some(format!(
+                "{} is {} bytes, not {}",
+                file.path,
+                metadata.len(),
+                file.size_bytes
+            )));
+        }
+        if let Some(sha256) = &file.sha256
+            && sha256_file(&path)? != *sha256
+        {
+            return Ok(Some(format!("{} doesn't match its checksum", file.path)));
+        }
+    }
+    Ok(None)
+}
+
+/// Check every title in `library`, returning what's wrong with each one
+/// that's broken.
+pub fn check_library(library: &Path) -> Result<HashMap<String, String>> {
+    let catalog = Catalog::open_existing(library)?;
+    let mut broken = HashMap::new();
+    for entry in std::fs::read_dir(library)
+        .context(format!("Failed to read library: {}", library.display()))?
+        .flatten()
+    {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') || !entry.path().join(MANIFEST).is_file() {
+            continue;
+        }
+        let recorded = match &catalog {
+            Some(catalog) => catalog.files(&name)?,
+            None => Vec::new(),
+        };
+        if let Some(problem) = check_title(&entry.path(), &recorded)? {
+            broken.insert(name, problem);
+        }
+    }
+    Ok(broken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_missing_and_damaged_files() {
+        let library =
+            std::env::temp_dir().join(format!("movieshare-verify-{}", std::process::id()));
+        let dir = library.join("Movie");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            r#"<MPD mediaPresentationDuration="PT8S"><Period>
+                <AdaptationSet contentType="video"><Representation id="0" bandwidth="1">
+                    <SegmentList timescale="1000" duration="4000">
+                        <Initialization sourceURL="video_0_init.mp4"/>
+                        <SegmentURL media="video_0_00001.m4s"/>
+                        <SegmentURL media="video_0_00002.m4s"/>
+                    </SegmentList>
+                </Representation></AdaptationSet>
+                <AdaptationSet contentType="text"><Representation id="1" bandwidth="1">
+                    <BaseURL>subtitles_1.vtt</BaseURL>
+                </Representation></AdaptationSet>
+            </Period></MPD>"#,
+        )
+        .unwrap();
+        for file in ["video_0_init.mp4", "video_0_00001.m4s", "subtitles_1.vtt"] {
+            std::fs::write(dir.join(file), "data").unwrap();
+        }
+        assert_eq!(
+            check_title(&dir, &[]).unwrap().as_deref(),
+            Some("video_0_00002.m4s is missing")
+        );
+
+        std::fs::write(dir.join("video_0_00002.m4s"), "data").unwrap();
+        std::fs::write(dir.join("journal.jsonl"), "{}\n").unwrap();
+        let mut catalog = Catalog::open(&Catalog::default_path(&library)).unwrap();
+        catalog.scan(&library, true).unwrap();
+        assert_eq!(catalog.files("Movie").unwrap().len(), 6);
+        // The journal grows after a scan without anything being wrong
+        std::fs::write(dir.join("journal.jsonl"), "{}\n{}\n").unwrap();
+        assert!(check_library(&library).unwrap().is_empty());
+
+        // Same size, other bytes
+        std::fs::write(dir.join("video_0_00001.m4s"), "dat4").unwrap();
+        assert_eq!(
+            check_library(&library).unwrap()["Movie"],
+            "video_0_00001.m4s doesn't match its checksum"
+        );
+        std::fs::remove_dir_all(&library).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+//! Watch folders for the daemon: media files dropped into one are queued
+//! to be prepared into its library, with its own profile, so sources that
+//! want different treatment, like 4K masters and phone clips, get it by
+//! where they're put.
+//!
+//! Folders are set up in a TOML file, with paths relative to it:
+//!
+//! ```toml
+//! [[folder]]
+//! path = "incoming/4k"
+//! library = "/srv/movies"
+//! profile = "profiles/archive.toml"
+//!
+//! [[folder]]
+//! path = "incoming/phone"
+//! library = "/srv/clips"
+//! profile = "profiles/quick.json"
+//! priority = -1
+//! ```
+//!
+//! A file is only queued once its size has held still between two looks,
+//! so one still being copied in isn't prepared half-written.
+
+use crate::load_profile;
+use crate::upload::valid_title;
+use anyhow::{Context, Result, bail};
+use movieshare_core::queue::JobQueue;
+use movieshare_core::{EncodingProfile, JobSpec};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Time between looks at the folders.
+const POLL: Duration = Duration::from_secs(10);
+/// Names of files still being downloaded or written.
+const PARTIAL: &[&str] = &["part", "partial", "tmp", "crdownload"];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    folder: Vec<FolderConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FolderConfig {
+    path: PathBuf,
+    library: PathBuf,
+    profile: Option<PathBuf>,
+    #[serde(default)]
+    priority: i32,
+}
+
+/// A folder to watch, and what to do with what turns up in it.
+pub struct Folder {
+    pub path: PathBuf,
+    pub library: PathBuf,
+    /// Profile file, for saying which was used
+    pub profile_path: Option<PathBuf>,
+    profile: EncodingProfile,
+    priority: i32,
+}
+
+/// Read the folders set up in the TOML file at `path`, with their profiles.
+pub fn load(path: &Path) -> Result<Vec<Folder>> {
+    let text =
+        std::fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    let config: Config =
+        toml::from_str(&text).context(format!("Invalid watch folders: {}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new("."));
+    let mut folders = Vec::new();
+    for folder in config.folder {
+        let watched = base.join(&folder.path);
+        let library = base.join(&folder.library);
+        for dir in [&watched, &library] {
+            if !dir.is_dir() {
+                bail!("Directory not found: {}", dir.display());
+            }
+        }
+        let profile_path = folder.profile.map(|profile| base.join(profile));
+        folders.push(Folder {
+            profile: load_profile(&profile_path)?,
+            path: watched,
+            library,
+            profile_path,
+            priority: folder.priority,
+        });
+    }
+    Ok(folders)
+}
+
+/// Files seen in the folders, by the size they had when last looked at.
+#[derive(Default)]
+struct Arrivals {
+    sizes: HashMap<PathBuf, u64>,
+}
+
+impl Arrivals {
+    /// Take in the files `found` in a look, returning those the same size
+    /// as the time before.
+    fn settled(&mut self, found: Vec<(PathBuf, u64)>) -> Vec<PathBuf> {
+        let previous = std::mem::take(&mut self.sizes);
+        let mut settled = Vec::new();
+        for (path, size) in found {
+            if previous.get(&path) == Some(&size) {
+                settled.push(path.clone());
+            }
+            self.sizes.insert(path, size);
+        }
+        settled
+    }
+}
+
+/// Files in `dir` that could be sources, with their sizes.
+fn candidates(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(dir).context(format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let partial = path
+            .extension()
+            .is_some_and(|extension| PARTIAL.iter().any(|partial| extension == *partial));
+        if name.starts_with('.') || partial {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            found.push((path, metadata.len()));
+        }
+    }
+    Ok(found)
+}
+
+/// Queue what's dropped into `folders` on `queue`, until the daemon stops.
+pub async fn run(queue: JobQueue, folders: Vec<Folder>) -> Result<()> {
+    let mut arrivals: Vec<Arrivals> = folders.iter().map(|_| Arrivals::default()).collect();
+    // Jobs from before a restart are still in the queue
+    let mut queued: HashSet<PathBuf> = queue.jobs().into_iter().map(|job| job.spec.input).collect();
+    loop {
+        for (folder, arrivals) in folders.iter().zip(&mut arrivals) {
+            let found = match candidates(&folder.path) {
+                Ok(found) => found,
+                Err(err) => {
+                    eprintln!("Warning: {:#}", err);
+                    continue;
+                }
+            };
+            for input in arrivals.settled(found) {
+                if queued.contains(&input) {
+                    continue;
+                }
+                queued.insert(input.clone());
+                let title = input
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let output = folder.library.join(&title);
+                if !valid_title(&title) || output.exists() {
+                    eprintln!(
+                        "Warning: leaving {} alone; {} is taken or not a valid title",
+                        input.display(),
+                        output.display()
+                    );
+                    continue;
+                }
+                let mut spec = JobSpec::new(&input, &output);
+                spec.profile = folder.profile.clone();
+                let profile = match &folder.profile_path {
+                    Some(path) => path.display().to_string(),
+                    None => String::from("the default profile"),
+                };
+                match queue.submit(spec, folder.priority) {
+                    Ok(id) => {
+                        println!("Queued {} as job {} with {}", input.display(), id, profile)
+                    }
+                    Err(err) => eprintln!("Failed to queue {}: {:#}", input.display(), err),
+                }
+            }
+        }
+        tokio::time::sleep(POLL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_files_once_copied() {
+        let dir = std::env::temp_dir().join(format!("watch-folders-{}", std::process::id()));
+        for sub in ["incoming/4k", "movies", "profiles"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        std::fs::write(dir.join("profiles/archive.toml"), "ladder = [20, 10]\n").unwrap();
+        std::fs::write(
+            dir.join("watch.toml"),
+            "[[folder]]\npath = \"incoming/4k\"\nlibrary = \"movies\"\nprofile = \"profiles/archive.toml\"\n",
+        )
+        .unwrap();
+        let folders = load(&dir.join("watch.toml")).unwrap();
+        assert_eq!(folders[0].path, dir.join("incoming/4k"));
+        assert_eq!(folders[0].profile.ladder, [20, 10]);
+
+        std::fs::write(dir.join("incoming/4k/Film.mkv"), b"1234").unwrap();
+        std::fs::write(dir.join("incoming/4k/Other.mkv.part"), b"12").unwrap();
+        std::fs::write(dir.join("incoming/4k/.hidden"), b"").unwrap();
+        let found = candidates(&dir.join("incoming/4k")).unwrap();
+        assert_eq!(found, [(dir.join("incoming/4k/Film.mkv"), 4)]);
+
+        let film = PathBuf::from("Film.mkv");
+        let mut arrivals = Arrivals::default();
+        assert!(arrivals.settled(vec![(film.clone(), 10)]).is_empty());
+        assert!(arrivals.settled(vec![(film.clone(), 20)]).is_empty());
+        assert_eq!(arrivals.settled(vec![(film.clone(), 20)]), [film]);
+
+        std::fs::write(
+            dir.join("watch.toml"),
+            "[[folder]]\npath = \"missing\"\nlibrary = \"movies\"\n",
+        )
+        .unwrap();
+        assert!(load(&dir.join("watch.toml")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+//! Waveform peaks of a title's audio, for the player's seek bar.
+//!
+//! While preparing, the decoded audio is also mixed down to mono and the
+//! lowest and highest sample of each stretch kept. They're written next to
+//! the manifest in audiowaveform's JSON format, 8 bits a peak, which keeps a
+//! feature film's waveform to a few hundred kilobytes.
+
+use anyhow::{Context, Result};
+use movieshare_core::gst;
+use movieshare_core::gst::prelude::*;
+use movieshare_core::{MediaType, PipelineBranch};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Name of the peaks file in a title's directory.
+pub const WAVEFORM: &str = "waveform.json";
+/// Rate the audio is resampled to before looking for peaks.
+const SAMPLE_RATE: u32 = 8000;
+/// Samples each pair of peaks covers: four a second.
+const SAMPLES_PER_PEAK: u32 = 2000;
+
+/// The lowest and highest samples of each stretch of audio so far.
+#[derive(Debug, Default)]
+struct Peaks {
+    peaks: Vec<i8>,
+    /// Samples in the stretch being looked at
+    count: u32,
+    min: f32,
+    max: f32,
+}
+
+/// A sample from -1 to 1 as one of 8 bits.
+fn quantize(sample: f32) -> i8 {
+    (sample.clamp(-1.0, 1.0) * 127.0).round() as i8
+}
+
+impl Peaks {
+    fn add(&mut self, samples: impl IntoIterator<Item = f32>) {
+        for sample in samples {
+            if self.count == 0 {
+                (self.min, self.max) = (sample, sample);
+            }
+            self.min = self.min.min(sample);
+            self.max = self.max.max(sample);
+            self.count += 1;
+            if self.count == SAMPLES_PER_PEAK {
+                self.flush();
+            }
+        }
+    }
+
+    /// Keep the stretch being looked at, even if it's short.
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.peaks.push(quantize(self.min));
+            self.peaks.push(quantize(self.max));
+            self.count = 0;
+        }
+    }
+}
+
+/// The peaks file, as audiowaveform writes it.
+#[derive(Serialize)]
+struct Waveform<'a> {
+    version: u32,
+    channels: u32,
+    sample_rate: u32,
+    samples_per_pixel: u32,
+    bits: u32,
+    /// Pairs of peaks
+    length: usize,
+    /// Lowest then highest sample of each stretch
+    data: &'a [i8],
+}
+
+/// Finds the waveform peaks of the decoded audio alongside the encoding
+/// branches.
+///
+/// Clones share the same peaks, like
+/// [`FingerprintBranch`](crate::dedupe::FingerprintBranch).
+#[derive(Clone)]
+pub struct WaveformBranch {
+    queue: gst::Element,
+    audioconvert: gst::Element,
+    audioresample: gst::Element,
+    capsfilter: gst::Element,
+    sink: gst::Element,
+    peaks: Arc<Mutex<Peaks>>,
+}
+
+impl WaveformBranch {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            queue: gst::ElementFactory::make("queue").build()?,
+            audioconvert: gst::ElementFactory::make("audioconvert").build()?,
+            audioresample: gst::ElementFactory::make("audioresample").build()?,
+            capsfilter: gst::ElementFactory::make("capsfilter")
+                .property(
+                    "caps",
+                    gst::Caps::builder("audio/x-raw")
+                        .field("format", "F32LE")
+                        .field("layout", "interleaved")
+                        .field("channels", 1)
+                        .field("rate", SAMPLE_RATE as i32)
+                        .build(),
+                )
+                .build()?,
+            sink: gst::ElementFactory::make("fakesink").build()?,
+            peaks: Arc::default(),
+        })
+    }
+
+    /// Write the peaks found into `dir`, once the audio has ended.
+    pub fn finish(&self, dir: &Path) -> Result<()> {
+        let mut peaks = self.peaks.lock().unwrap();
+        peaks.flush();
+        let waveform = Waveform {
+            version: 2,
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            samples_per_pixel: SAMPLES_PER_PEAK,
+            bits: 8,
+            length: peaks.peaks.len() / 2,
+            data: &peaks.peaks,
+        };
+        let path = dir.join(WAVEFORM);
+        std::fs::write(&path, serde_json::to_vec(&waveform)?)
+            .context(format!("Failed to write {}", path.display()))
+    }
+
+    fn elements(&self) -> [&gst::Element; 5] {
+        [
+            &self.queue,
+            &self.audioconvert,
+            &self.audioresample,
+            &self.capsfilter,
+            &self.sink,
+        ]
+    }
+}
+
+impl PipelineBranch for WaveformBranch {
+    fn name(&self) -> String {
+        String::from("waveform")
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::Audio
+    }
+
+    fn add_to_pipeline(&self, pipeline: &gst::Pipeline) -> Result<()> {
+        pipeline.add_many(self.elements())?;
+        Ok(())
+    }
+
+    fn link(&self, tee: &gst::Element, _dashsink: &gst::Element) -> Result<()> {
+        tee.link(&self.queue)?;
+        gst::Element::link_many(self.elements())?;
+
+        let peaks = self.peaks.clone();
+        self.sink
+            .static_pad("sink")
+            .context("Failed to get sink pad from fakesink")?
+            .add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if let Some(buffer) = info.buffer()
+                    && let Ok(map) = buffer.map_readable()
+                {
+                    let samples = map
+                        .chunks_exact(4)
+                        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()));
+                    peaks.lock().unwrap().add(samples);
+                }
+                gst::PadProbeReturn::Ok
+            });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_extremes_of_each_stretch() {
+        let mut peaks = Peaks::default();
+        let stretch = SAMPLES_PER_PEAK as usize;
+        let mut samples = vec![0.1; stretch];
+        samples[10] = -0.5;
+        samples[20] = 2.0;
+        peaks.add(samples);
+        peaks.add(vec![0.25; stretch / 2]);
+        peaks.flush();
+        assert_eq!(peaks.peaks, [-64, 127, 32, 32]);
+    }
+}